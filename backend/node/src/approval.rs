@@ -0,0 +1,209 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::node::NodeIdentity;
+use crate::policy::{ SigningPolicy, SigningRequest };
+use crate::session_manager;
+use crate::App;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Result };
+use chrono::{ DateTime, Utc };
+use nkeys::KeyPair;
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+use tracing::info;
+
+/// Approvers subscribe to `network.gridlock.approvals.<node id>` for the requests of a node
+const APPROVAL_SUBJECT: &str = "network.gridlock.approvals";
+
+/// Requests waiting for their approval, by approval id
+static PENDING: Mutex<BTreeMap<String, PendingApproval>> = Mutex::new(BTreeMap::new());
+
+struct PendingApproval {
+    request: ApprovalRequest,
+    approver_public_key: String,
+    decision: oneshot::Sender<bool>,
+}
+
+/// Signing request waiting for the approval of the owner, published to the approver
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ApprovalRequest {
+    /// Id of the signing session
+    pub approval_id: String,
+    pub node_id: String,
+    pub key_id: String,
+    /// Hex messages to sign
+    pub messages: Vec<String>,
+    pub is_transfer: bool,
+    /// Hex SHA-256 over the messages, covered by the signature of the approver
+    pub request_hash: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl ApprovalRequest {
+    /// What the approver signs to approve or reject the request
+    pub fn decision_message(&self, approved: bool) -> String {
+        let decision = if approved { "approve" } else { "reject" };
+        format!("{}:{}:{}", self.approval_id, self.request_hash, decision)
+    }
+}
+
+/// Waits for the decision on a queued request
+pub struct ApprovalTicket {
+    approval_id: String,
+    expires_at: DateTime<Utc>,
+    decision: oneshot::Receiver<bool>,
+}
+
+impl ApprovalTicket {
+    /// Fails unless the request is approved before it expires
+    pub async fn wait(self) -> Result<()> {
+        let remaining = (self.expires_at - Utc::now()).to_std().unwrap_or_default();
+        let decision = tokio::time::timeout(remaining, self.decision).await;
+        PENDING.lock().unwrap().remove(&self.approval_id);
+        match decision {
+            Ok(Ok(true)) => {
+                info!("Signing request {} was approved", self.approval_id);
+                Ok(())
+            }
+            Ok(Ok(false)) => bail!("Signing request {} was rejected", self.approval_id),
+            Ok(Err(_)) => bail!("Signing request {} was withdrawn", self.approval_id),
+            Err(_) => bail!("Signing request {} was not approved in time", self.approval_id),
+        }
+    }
+
+    /// `wait` for sessions running on their own thread
+    pub fn wait_blocking(self) -> Result<()> {
+        session_manager::runtime().block_on(self.wait())
+    }
+}
+
+/// Queues the request if the key's policy requires approval for it and notifies the approver.
+/// Returns `None` when the request can be signed right away.
+pub fn request_approval(
+    app: &App,
+    key_id: &str,
+    email: &str,
+    session_id: &str,
+    request: &SigningRequest
+) -> Result<Option<ApprovalTicket>> {
+    let approval = match SigningPolicy::get(key_id, email)?.and_then(|policy| policy.approval) {
+        Some(approval) if approval.applies_to(request) => approval,
+        _ => {
+            return Ok(None);
+        }
+    };
+    let node = NodeIdentity::cached()?;
+    let mut hasher = Sha256::new();
+    for message in &request.messages {
+        hasher.update(message);
+    }
+    let approval_request = ApprovalRequest {
+        approval_id: session_id.to_string(),
+        node_id: node.node_id.to_string(),
+        key_id: key_id.to_string(),
+        messages: request.messages.iter().map(hex::encode).collect(),
+        is_transfer: request.is_transfer,
+        request_hash: hex::encode(hasher.finalize()),
+        expires_at: Utc::now() + approval.ttl(),
+    };
+    let notification = serde_json::to_string(&approval_request)?;
+    let ticket = queue(approval_request, &approval.approver_public_key)?;
+    app.nc.publish(&format!("{}.{}", APPROVAL_SUBJECT, node.node_id), notification)?;
+    info!("Signing request {} is waiting for approval", session_id);
+    Ok(Some(ticket))
+}
+
+fn queue(request: ApprovalRequest, approver_public_key: &str) -> Result<ApprovalTicket> {
+    let mut pending = PENDING.lock().unwrap();
+    if pending.contains_key(&request.approval_id) {
+        bail!("Signing request {} is already waiting for approval", request.approval_id);
+    }
+    let (sender, receiver) = oneshot::channel();
+    let ticket = ApprovalTicket {
+        approval_id: request.approval_id.clone(),
+        expires_at: request.expires_at,
+        decision: receiver,
+    };
+    pending.insert(request.approval_id.clone(), PendingApproval {
+        request,
+        approver_public_key: approver_public_key.to_string(),
+        decision: sender,
+    });
+    Ok(ticket)
+}
+
+/// Approves or rejects a signing request waiting for approval
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ApproveSigningCommand {
+    pub approval_id: String,
+    pub approved: bool,
+    /// Base64 signature of the approver key over `ApprovalRequest::decision_message`
+    pub signature: String,
+}
+
+impl JsonCommand for ApproveSigningCommand {
+    type Response = ();
+
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let mut pending = PENDING.lock().unwrap();
+        let waiting = pending
+            .get(&self.approval_id)
+            .ok_or_else(|| anyhow!("No signing request {} is waiting", self.approval_id))?;
+        if waiting.request.expires_at < Utc::now() {
+            bail!("Signing request {} has expired", self.approval_id);
+        }
+        let message = waiting.request.decision_message(self.approved);
+        KeyPair::from_public_key(&waiting.approver_public_key)?
+            .verify(message.as_bytes(), &base64::decode(&self.signature)?)
+            .map_err(|_| anyhow!("Invalid approver signature"))?;
+
+        let waiting = pending.remove(&self.approval_id).unwrap();
+        // The session may have ended in the meantime, then nobody waits for the decision
+        let _ = waiting.decision.send(self.approved);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn releases_requests_only_with_the_approver_signature() {
+        let approver = KeyPair::new_user();
+        let request = ApprovalRequest {
+            approval_id: "session-approval-1".to_string(),
+            node_id: "node-1".to_string(),
+            key_id: "key-1".to_string(),
+            messages: vec![hex::encode(b"hello")],
+            is_transfer: false,
+            request_hash: hex::encode(Sha256::digest(b"hello")),
+            expires_at: Utc::now() + Duration::minutes(5),
+        };
+        let ticket = queue(request.clone(), &approver.public_key()).unwrap();
+        assert!(queue(request.clone(), &approver.public_key()).is_err());
+
+        let sign = |key: &KeyPair, approved: bool| {
+            base64::encode(key.sign(request.decision_message(approved).as_bytes()).unwrap())
+        };
+        let command = |approved: bool, signature: String| ApproveSigningCommand {
+            approval_id: request.approval_id.clone(),
+            approved,
+            signature,
+        };
+        let other = KeyPair::new_user();
+        assert!(command(true, sign(&other, true)).execute_message(MsgContext::FFI).is_err());
+        assert!(command(true, sign(&approver, false)).execute_message(MsgContext::FFI).is_err());
+
+        assert!(command(true, sign(&approver, true)).execute_message(MsgContext::FFI).is_ok());
+        assert!(ticket.wait_blocking().is_ok());
+    }
+}
@@ -0,0 +1,293 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::node::NodeIdentity;
+use crate::operator::OperatorInfo;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Context, Result };
+use chrono::{ DateTime, Duration, Utc };
+use nkeys::KeyPair;
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256, Sha512 };
+use std::env;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::sync::OnceLock;
+use tracing::{ info, warn };
+
+/// configfs-tsm directory, the kernel interface to SEV-SNP and TDX guest reports (Linux 6.7+)
+const TSM_REPORT_DIR_VAR: &str = "ATTESTATION_TSM_DIR";
+const DEFAULT_TSM_REPORT_DIR: &str = "/sys/kernel/config/tsm/report";
+/// Owners choose the nonce so evidence can't be replayed, short nonces would make that guessable
+const MIN_NONCE_BYTES: usize = 16;
+/// Evidence older than this is refused by `verify`
+const MAX_EVIDENCE_AGE_MINUTES: i64 = 5;
+
+/// Asks the guardian for evidence of the build and environment it runs in, owners check it before
+/// they share keys with the guardian
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AttestCommand {
+    /// Hex nonce chosen by the owner, bound into the signature and the TEE report
+    pub nonce: String,
+}
+
+/// Build the guardian runs, `binary_sha256` is compared with the hash of a reproducible build
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct SoftwareManifest {
+    pub version: String,
+    pub binary_sha256: String,
+    pub os: String,
+    pub arch: String,
+    /// Optional features the node was built with
+    pub features: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator: Option<OperatorInfo>,
+}
+
+/// Report of the confidential VM the guardian runs in. The report is verified by the owner
+/// against the certificate chain of the CPU vendor, its report data is `report_data`.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct TeeReport {
+    /// Kernel TSM provider, "sev_guest" for AMD SEV-SNP or "tdx_guest" for Intel TDX
+    pub provider: String,
+    /// Base64 raw report or quote
+    pub report: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AttestationEvidence {
+    pub node_id: String,
+    pub networking_public_key: String,
+    pub nonce: String,
+    pub timestamp: DateTime<Utc>,
+    pub manifest: SoftwareManifest,
+    /// Absent for guardians outside a confidential VM, the manifest is then only as trustworthy
+    /// as the operator
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tee: Option<TeeReport>,
+    /// Base64 ed25519 signature of the networking key over `signed_message`
+    pub signature: String,
+}
+
+impl JsonCommand for AttestCommand {
+    type Response = AttestationEvidence;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let nonce = hex::decode(&self.nonce).context("Nonce is not hex")?;
+        if nonce.len() < MIN_NONCE_BYTES {
+            bail!("Nonce must be at least {} bytes", MIN_NONCE_BYTES);
+        }
+        let node = NodeIdentity::cached()?;
+        let evidence = AttestationEvidence::produce(&node, &self.nonce, Utc::now())?;
+        info!(
+            "Attested the guardian environment{}",
+            if evidence.tee.is_some() { " with a TEE report" } else { "" }
+        );
+        Ok(evidence)
+    }
+}
+
+impl AttestationEvidence {
+    fn produce(node: &NodeIdentity, nonce: &str, timestamp: DateTime<Utc>) -> Result<Self> {
+        let node_id = node.node_id.to_string();
+        let manifest = SoftwareManifest::current()?;
+        let data = report_data(&node_id, &node.networking_public_key, nonce, &manifest)?;
+        let tee = match tee_report(&data) {
+            Ok(tee) => tee,
+            Err(err) => {
+                warn!("No TEE report in the attestation: {}", err);
+                None
+            }
+        };
+        let message = signed_message(&node_id, nonce, &timestamp, &manifest, tee.as_ref())?;
+        let signature = KeyPair::from_seed(&node.networking_private_key)?.sign(&message)?;
+        Ok(Self {
+            node_id,
+            networking_public_key: node.networking_public_key.clone(),
+            nonce: nonce.to_string(),
+            timestamp,
+            manifest,
+            tee,
+            signature: base64::encode(signature),
+        })
+    }
+
+    /// Checks the signature against the networking key the owner knows the guardian by, the nonce
+    /// the owner sent and, when given, the hash of the build the owner expects. The TEE report is
+    /// left to the vendor's verification tools, with `report_data` as the expected report data.
+    pub fn verify(
+        &self,
+        networking_public_key: &str,
+        nonce: &str,
+        expected_binary_sha256: Option<&str>,
+        now: DateTime<Utc>
+    ) -> Result<()> {
+        if self.networking_public_key != networking_public_key {
+            bail!("Attestation of node {} is signed by another key", self.node_id);
+        }
+        if self.nonce != nonce {
+            bail!("Attestation of node {} answers another nonce", self.node_id);
+        }
+        let message = signed_message(
+            &self.node_id,
+            &self.nonce,
+            &self.timestamp,
+            &self.manifest,
+            self.tee.as_ref()
+        )?;
+        KeyPair::from_public_key(networking_public_key)?
+            .verify(&message, &base64::decode(&self.signature)?)
+            .map_err(|_| anyhow!("Attestation signature of node {} is invalid", self.node_id))?;
+        if (now - self.timestamp).abs() > Duration::minutes(MAX_EVIDENCE_AGE_MINUTES) {
+            bail!("Attestation of node {} from {} is stale", self.node_id, self.timestamp);
+        }
+        match expected_binary_sha256 {
+            Some(expected) if !expected.eq_ignore_ascii_case(&self.manifest.binary_sha256) => {
+                bail!(
+                    "Node {} runs build {}, not the expected one",
+                    self.node_id,
+                    self.manifest.binary_sha256
+                );
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Report data the TEE report has to carry, binding it to the nonce, key and manifest
+    pub fn report_data(&self) -> Result<Vec<u8>> {
+        report_data(&self.node_id, &self.networking_public_key, &self.nonce, &self.manifest)
+    }
+}
+
+impl SoftwareManifest {
+    fn current() -> Result<Self> {
+        let features = [
+            ("sqlite-storage", cfg!(feature = "sqlite-storage")),
+            ("s3-storage", cfg!(feature = "s3-storage")),
+            ("tpm-identity", cfg!(feature = "tpm-identity")),
+            ("keychain-identity", cfg!(feature = "keychain-identity")),
+            ("pkcs11-identity", cfg!(feature = "pkcs11-identity")),
+            ("deterministic-seeds", cfg!(feature = "deterministic-seeds")),
+            ("testing", cfg!(feature = "testing")),
+        ];
+        Ok(Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            binary_sha256: binary_sha256()?.clone(),
+            os: env::consts::OS.to_string(),
+            arch: env::consts::ARCH.to_string(),
+            features: features
+                .into_iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(feature, _)| feature.to_string())
+                .collect(),
+            operator: OperatorInfo::configured().cloned(),
+        })
+    }
+}
+
+/// Hash of the running executable, computed once
+fn binary_sha256() -> Result<&'static String> {
+    static HASH: OnceLock<String> = OnceLock::new();
+    if let Some(hash) = HASH.get() {
+        return Ok(hash);
+    }
+    let path = env::current_exe()?;
+    let binary = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(HASH.get_or_init(|| hex::encode(Sha256::digest(&binary))))
+}
+
+fn report_data(
+    node_id: &str,
+    networking_public_key: &str,
+    nonce: &str,
+    manifest: &SoftwareManifest
+) -> Result<Vec<u8>> {
+    let mut hasher = Sha512::new();
+    hasher.update(b"gridlock-attestation");
+    hasher.update(serde_json::to_vec(&(node_id, networking_public_key, nonce, manifest))?);
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Bytes covered by the signature: everything in the evidence but the signature itself
+fn signed_message(
+    node_id: &str,
+    nonce: &str,
+    timestamp: &DateTime<Utc>,
+    manifest: &SoftwareManifest,
+    tee: Option<&TeeReport>
+) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&(node_id, nonce, timestamp.to_rfc3339(), manifest, tee))?)
+}
+
+/// Report of the confidential VM through configfs-tsm, `None` outside of one
+fn tee_report(report_data: &[u8]) -> Result<Option<TeeReport>> {
+    let dir = PathBuf::from(
+        env::var(TSM_REPORT_DIR_VAR).unwrap_or_else(|_| DEFAULT_TSM_REPORT_DIR.to_string())
+    );
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    // Every report gets its own entry, concurrent requests would overwrite each other's inblob
+    let entry = dir.join(format!("guardian-{}", uuid::Uuid::new_v4()));
+    fs::create_dir(&entry).with_context(|| format!("Failed to create {}", entry.display()))?;
+    let report = read_tsm_report(&entry, report_data);
+    if let Err(err) = fs::remove_dir(&entry) {
+        warn!("Failed to remove {}: {}", entry.display(), err);
+    }
+    report.map(Some)
+}
+
+fn read_tsm_report(entry: &Path, report_data: &[u8]) -> Result<TeeReport> {
+    fs::write(entry.join("inblob"), report_data)?;
+    let report = fs::read(entry.join("outblob"))?;
+    let provider = fs::read_to_string(entry.join("provider"))?;
+    Ok(TeeReport {
+        provider: provider.trim().to_string(),
+        report: base64::encode(report),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evidence_is_bound_to_key_nonce_and_build() {
+        let node = NodeIdentity::new();
+        let nonce = hex::encode([7u8; 16]);
+        let now = Utc::now();
+        let manifest = SoftwareManifest {
+            version: "1.0.0".to_string(),
+            binary_sha256: "ab".repeat(32),
+            os: "linux".to_string(),
+            arch: "x86_64".to_string(),
+            features: Vec::new(),
+            operator: None,
+        };
+        let message = signed_message(&node.node_id.to_string(), &nonce, &now, &manifest, None);
+        let signature = KeyPair::from_seed(&node.networking_private_key)
+            .unwrap()
+            .sign(&message.unwrap())
+            .unwrap();
+        let evidence = AttestationEvidence {
+            node_id: node.node_id.to_string(),
+            networking_public_key: node.networking_public_key.clone(),
+            nonce: nonce.clone(),
+            timestamp: now,
+            manifest,
+            tee: None,
+            signature: base64::encode(signature),
+        };
+        let key = &node.networking_public_key;
+        assert!(evidence.verify(key, &nonce, Some(&"AB".repeat(32)), now).is_ok());
+        assert!(evidence.verify(key, &hex::encode([8u8; 16]), None, now).is_err());
+        assert!(evidence.verify(key, &nonce, Some(&"cd".repeat(32)), now).is_err());
+        assert!(evidence.verify(key, &nonce, None, now + Duration::minutes(10)).is_err());
+        let other = NodeIdentity::new();
+        assert!(evidence.verify(&other.networking_public_key, &nonce, None, now).is_err());
+    }
+}
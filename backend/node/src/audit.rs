@@ -0,0 +1,377 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::config::{ Config, ConfigProvider };
+use crate::node::NodeIdentity;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Context, Result };
+use chrono::{ DateTime, Utc };
+use nkeys::KeyPair;
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use std::fmt::Display;
+use std::fs::{ self, OpenOptions };
+use std::io::{ ErrorKind, Write };
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::warn;
+
+const AUDIT_DIRECTORY: &str = "audit";
+const AUDIT_LOG_FILE: &str = "audit.jsonl";
+/// Most records one `GetAuditLogCommand` returns, larger ranges are exported page by page
+const MAX_EXPORTED_RECORDS: usize = 1000;
+
+/// Sequence and hash the next record chains to, loaded from the log on first use. Holding the
+/// lock while appending keeps the chain linear across session threads.
+static CHAIN_HEAD: Mutex<Option<ChainHead>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum AuditAction {
+    KeyGen,
+    Signing,
+    Recovery,
+    ShareRefresh,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Succeeded,
+    Failed,
+}
+
+/// What is recorded about one handled request, the hash of a record covers all of it
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub sequence: u64,
+    pub timestamp: DateTime<Utc>,
+    pub action: AuditAction,
+    pub scheme: String,
+    pub key_id: String,
+    pub session_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    pub outcome: AuditOutcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What the request was about, e.g. the decoded transaction of a signing request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+    /// Hash of the record before, so no record can be dropped or reordered unnoticed
+    pub previous_hash: String,
+}
+
+impl AuditEntry {
+    fn hash(&self) -> Result<String> {
+        Ok(hex::encode(Sha256::digest(serde_json::to_vec(self)?)))
+    }
+}
+
+/// Entry of the audit log with its hash, signed with the networking key of the node
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AuditRecord {
+    #[serde(flatten)]
+    pub entry: AuditEntry,
+    pub hash: String,
+    /// Base64 ed25519 signature over `hash`
+    pub signature: String,
+}
+
+impl AuditRecord {
+    fn seal(entry: AuditEntry, node: &NodeIdentity) -> Result<Self> {
+        let hash = entry.hash()?;
+        let signature = KeyPair::from_seed(&node.networking_private_key)?.sign(hash.as_bytes())?;
+        Ok(AuditRecord {
+            entry,
+            hash,
+            signature: base64::encode(signature),
+        })
+    }
+}
+
+struct ChainHead {
+    next_sequence: u64,
+    hash: String,
+}
+
+impl ChainHead {
+    fn load() -> Result<Self> {
+        let last = read_records()?.pop();
+        Ok(match last {
+            Some(record) =>
+                ChainHead {
+                    next_sequence: record.entry.sequence + 1,
+                    hash: record.hash,
+                },
+            None =>
+                ChainHead {
+                    next_sequence: 0,
+                    hash: genesis_hash(),
+                },
+        })
+    }
+}
+
+/// Hash the first record of a log chains to
+pub fn genesis_hash() -> String {
+    hex::encode([0u8; 32])
+}
+
+/// Keygen, signing or recovery request handled by this node, recorded once its outcome is known
+pub struct AuditedRequest {
+    action: AuditAction,
+    scheme: String,
+    key_id: String,
+    session_id: String,
+    email: Option<String>,
+    details: Option<String>,
+}
+
+impl AuditedRequest {
+    pub fn new(
+        action: AuditAction,
+        scheme: &str,
+        key_id: &str,
+        session_id: &str,
+        email: Option<&str>
+    ) -> Self {
+        AuditedRequest {
+            action,
+            scheme: scheme.to_string(),
+            key_id: key_id.to_string(),
+            session_id: session_id.to_string(),
+            email: email.map(str::to_string),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: Option<String>) -> Self {
+        self.details = details;
+        self
+    }
+
+    /// Appends the outcome to the audit log. A failure to write is logged, the request itself
+    /// already ran.
+    pub fn record<T, E: Display>(&self, result: &Result<T, E>) {
+        let (outcome, error) = match result {
+            Ok(_) => (AuditOutcome::Succeeded, None),
+            Err(err) => (AuditOutcome::Failed, Some(err.to_string())),
+        };
+        if let Err(err) = self.append(outcome, error) {
+            warn!("Failed to write the audit record of session {}: {}", self.session_id, err);
+        }
+    }
+
+    /// Records the request as failed unless `PendingAudit::complete` is called, for sessions
+    /// with many early returns
+    pub fn pending(self) -> PendingAudit {
+        PendingAudit {
+            request: self,
+            completed: false,
+        }
+    }
+
+    fn append(&self, outcome: AuditOutcome, error: Option<String>) -> Result<()> {
+        let node = NodeIdentity::cached()?;
+        let mut head = CHAIN_HEAD.lock().unwrap();
+        if head.is_none() {
+            *head = Some(ChainHead::load()?);
+        }
+        let current = head.as_ref().unwrap();
+        let entry = AuditEntry {
+            sequence: current.next_sequence,
+            timestamp: Utc::now(),
+            action: self.action,
+            scheme: self.scheme.clone(),
+            key_id: self.key_id.clone(),
+            session_id: self.session_id.clone(),
+            email: self.email.clone(),
+            outcome,
+            error,
+            details: self.details.clone(),
+            previous_hash: current.hash.clone(),
+        };
+        let record = AuditRecord::seal(entry, &node)?;
+
+        fs::create_dir_all(audit_directory())?;
+        let mut file = OpenOptions::new().create(true).append(true).open(audit_log_path())?;
+        writeln!(file, "{}", serde_json::to_string(&record)?)?;
+        file.sync_data()?;
+
+        *head = Some(ChainHead {
+            next_sequence: record.entry.sequence + 1,
+            hash: record.hash,
+        });
+        Ok(())
+    }
+}
+
+pub struct PendingAudit {
+    request: AuditedRequest,
+    completed: bool,
+}
+
+impl PendingAudit {
+    pub fn complete(mut self) {
+        self.completed = true;
+        self.request.record::<(), String>(&Ok(()));
+    }
+}
+
+impl Drop for PendingAudit {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.request.record::<(), String>(&Err("Session did not complete".to_string()));
+        }
+    }
+}
+
+/// Checks that the records are correctly hashed, signed by the node and chained without gaps.
+/// A range that starts at sequence 0 is checked against the genesis hash as well.
+pub fn verify_records(records: &[AuditRecord], networking_public_key: &str) -> Result<()> {
+    let key = KeyPair::from_public_key(networking_public_key)?;
+    let mut previous: Option<&AuditRecord> = None;
+    for record in records {
+        let sequence = record.entry.sequence;
+        if record.entry.hash()? != record.hash {
+            bail!("Audit record {} does not match its hash", sequence);
+        }
+        key
+            .verify(record.hash.as_bytes(), &base64::decode(&record.signature)?)
+            .map_err(|_| anyhow!("Audit record {} has an invalid signature", sequence))?;
+        let expected_previous = match previous {
+            Some(previous) => {
+                if sequence != previous.entry.sequence + 1 {
+                    bail!("Audit record {} follows record {}", sequence, previous.entry.sequence);
+                }
+                Some(previous.hash.clone())
+            }
+            None if sequence == 0 => Some(genesis_hash()),
+            None => None,
+        };
+        if let Some(expected_previous) = expected_previous {
+            if record.entry.previous_hash != expected_previous {
+                bail!("Audit record {} is not chained to the record before it", sequence);
+            }
+        }
+        previous = Some(record);
+    }
+    Ok(())
+}
+
+fn read_records() -> Result<Vec<AuditRecord>> {
+    let contents = match fs::read_to_string(audit_log_path()) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Ok(Vec::new());
+        }
+        Err(err) => {
+            return Err(err.into());
+        }
+    };
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(line, record)| {
+            serde_json::from_str(record).with_context(|| format!("Audit log line {}", line + 1))
+        })
+        .collect()
+}
+
+fn audit_directory() -> PathBuf {
+    let mut path = Config::get_gridlock_directory();
+    path.push(AUDIT_DIRECTORY);
+    path
+}
+
+fn audit_log_path() -> PathBuf {
+    let mut path = audit_directory();
+    path.push(AUDIT_LOG_FILE);
+    path
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AuditLogResponse {
+    pub node_id: String,
+    /// Key the records are verified with, see `verify_records`
+    pub networking_public_key: String,
+    pub records: Vec<AuditRecord>,
+}
+
+/// Exports the audit records from `from_sequence` on, at most `limit` of them
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetAuditLogCommand {
+    #[serde(default)]
+    pub from_sequence: u64,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl JsonCommand for GetAuditLogCommand {
+    type Response = AuditLogResponse;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let node = NodeIdentity::cached()?;
+        let limit = self.limit.unwrap_or(MAX_EXPORTED_RECORDS).min(MAX_EXPORTED_RECORDS);
+        // Appends hold the lock, so the export never ends in a partially written line
+        let records = {
+            let _head = CHAIN_HEAD.lock().unwrap();
+            read_records()?
+        };
+        Ok(AuditLogResponse {
+            node_id: node.node_id.to_string(),
+            networking_public_key: node.networking_public_key,
+            records: records
+                .into_iter()
+                .filter(|record| record.entry.sequence >= self.from_sequence)
+                .take(limit)
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_tampered_dropped_and_foreign_records() {
+        let node = NodeIdentity::new();
+        let mut previous_hash = genesis_hash();
+        let mut records = Vec::new();
+        for sequence in 0..3 {
+            let entry = AuditEntry {
+                sequence,
+                timestamp: Utc::now(),
+                action: AuditAction::Signing,
+                scheme: "frost".to_string(),
+                key_id: "key-1".to_string(),
+                session_id: format!("session-{}", sequence),
+                email: Some("user@example.com".to_string()),
+                outcome: AuditOutcome::Succeeded,
+                error: None,
+                details: None,
+                previous_hash,
+            };
+            let record = AuditRecord::seal(entry, &node).unwrap();
+            previous_hash = record.hash.clone();
+            records.push(record);
+        }
+        let key = &node.networking_public_key;
+        assert!(verify_records(&records, key).is_ok());
+        assert!(verify_records(&records[1..], key).is_ok());
+
+        let mut tampered = records.clone();
+        tampered[1].entry.outcome = AuditOutcome::Failed;
+        assert!(verify_records(&tampered, key).is_err());
+
+        let dropped = vec![records[0].clone(), records[2].clone()];
+        assert!(verify_records(&dropped, key).is_err());
+
+        let other = NodeIdentity::new();
+        assert!(verify_records(&records, &other.networking_public_key).is_err());
+    }
+}
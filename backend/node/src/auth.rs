@@ -1,4 +1,5 @@
-use anyhow::Result;
+use crate::revocation::ensure_not_revoked;
+use anyhow::{ anyhow, Result };
 use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::{
     gen_nonce,
@@ -6,6 +7,7 @@ use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::{
     PublicKey,
     SecretKey,
 };
+use zeroize::{ Zeroize, Zeroizing };
 
 pub fn e2e_decrypt(
     encrypted_data: &str,
@@ -13,7 +15,7 @@ pub fn e2e_decrypt(
     e2e_sender_public: &str
 ) -> Result<Vec<u8>> {
     let encrypted_data = base64::decode(encrypted_data)?;
-    let e2e_local_private = base64::decode(e2e_private_key)?;
+    let e2e_local_private = Zeroizing::new(base64::decode(e2e_private_key)?);
     let e2e_sender_public = base64::decode(e2e_sender_public)?;
 
     let nonce_size = box_::curve25519xsalsa20poly1305::NONCEBYTES;
@@ -34,9 +36,36 @@ pub fn e2e_decrypt(
     Ok(decrypted_data)
 }
 
+/// Decrypts data sent by a client, refusing clients whose e2e key has been revoked
+pub fn client_e2e_decrypt(
+    encrypted_data: &str,
+    e2e_private_key: &str,
+    client_e2e_public_key: &str
+) -> Result<Vec<u8>> {
+    ensure_not_revoked(client_e2e_public_key)?;
+    e2e_decrypt(encrypted_data, e2e_private_key, client_e2e_public_key)
+}
+
+/// Decrypts a secret string sent by a client, such as its signing key, into a buffer that is
+/// wiped when dropped
+pub fn client_e2e_decrypt_secret(
+    encrypted_data: &str,
+    e2e_private_key: &str,
+    client_e2e_public_key: &str
+) -> Result<Zeroizing<String>> {
+    let decrypted = client_e2e_decrypt(encrypted_data, e2e_private_key, client_e2e_public_key)?;
+    match String::from_utf8(decrypted) {
+        Ok(secret) => Ok(Zeroizing::new(secret)),
+        Err(err) => {
+            err.into_bytes().zeroize();
+            Err(anyhow!("Decrypted secret is not valid UTF-8"))
+        }
+    }
+}
+
 pub fn e2e_encrypt(message: &[u8], target_public: &str, local_private: &str) -> Result<String> {
     let target_public = base64::decode(target_public)?;
-    let local_private = base64::decode(local_private)?;
+    let local_private = Zeroizing::new(base64::decode(local_private)?);
 
     let target_public = box_::curve25519xsalsa20poly1305::PublicKey
         ::from_slice(&target_public)
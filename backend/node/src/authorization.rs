@@ -1,6 +1,6 @@
 use anyhow::bail;
 use anyhow::Result;
-use nats::{ Connection as NatsConnection, Message };
+use crate::communication::blocking::{ Connection as NatsConnection, Message };
 use std::thread;
 use tracing::{ error, info };
 use uuid::Uuid;
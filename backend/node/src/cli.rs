@@ -0,0 +1,236 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::key_info::GetKeyInfoCommand;
+use crate::keygen::Key;
+use crate::operator::GetNodeInfoCommand;
+use crate::signing::hashing::HashMode;
+use crate::signing::SigningCommand;
+use crate::storage::backup;
+use crate::storage::key_listing::ListKeysCommand;
+use crate::start_offline;
+use anyhow::{ bail, Context, Result };
+use shared::key_info::{ self, NodeId };
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Passphrase of `backup`, read from stdin when unset
+const BACKUP_PASSPHRASE_VAR: &str = "GUARDIAN_BACKUP_PASSPHRASE";
+
+const USAGE: &str =
+    "Usage:
+  guardian-node keys list
+  guardian-node keys info <key id>
+  guardian-node identity show
+  guardian-node backup --email <account> [--key-id <key id>] [--out <file>]
+  guardian-node sign --key-id <key id> --message <hex>
+                     [--parties <node id>,...] [--hash-mode none|sha256|keccak256]";
+
+/// First arguments the administration CLI handles
+pub const COMMANDS: [&str; 4] = ["keys", "identity", "backup", "sign"];
+
+#[derive(Debug, PartialEq)]
+enum CliCommand {
+    ListKeys,
+    KeyInfo {
+        key_id: String,
+    },
+    ShowIdentity,
+    Backup {
+        email: String,
+        key_id: Option<String>,
+        out: Option<PathBuf>,
+    },
+    Sign {
+        key_id: String,
+        message: Vec<u8>,
+        /// The key's node pool when not given
+        parties: Option<Vec<NodeId>>,
+        hash_mode: HashMode,
+    },
+}
+
+/// Administers the node from its own machine, e.g. over SSH. The commands run the same
+/// `JsonCommand` implementations as requests over NATS, locally as FFI commands, and print their
+/// responses as JSON. Signing orchestrates the session with the key's guardians over NATS.
+pub fn run(args: &[String]) -> Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let command = parse(&args)?;
+    start_offline()?;
+    match command {
+        CliCommand::ListKeys => print(ListKeysCommand { authorization: None }),
+        CliCommand::KeyInfo { key_id } => {
+            print(GetKeyInfoCommand { key_id, authorization: None })
+        }
+        CliCommand::ShowIdentity => print(GetNodeInfoCommand {}),
+        CliCommand::Backup { email, key_id, out } => backup(email, key_id, out),
+        CliCommand::Sign { key_id, message, parties, hash_mode } => {
+            sign(key_id, message, parties, hash_mode)
+        }
+    }
+}
+
+fn parse(args: &[&str]) -> Result<CliCommand> {
+    let command = match args {
+        ["keys", "list"] => CliCommand::ListKeys,
+        ["keys", "info", key_id] => CliCommand::KeyInfo { key_id: key_id.to_string() },
+        ["identity", "show"] => CliCommand::ShowIdentity,
+        ["backup", options @ ..] => {
+            let mut options = parse_options(options)?;
+            let Some(email) = options.remove("--email") else {
+                bail!("backup needs --email\n{}", USAGE);
+            };
+            let command = CliCommand::Backup {
+                email: email.to_string(),
+                key_id: options.remove("--key-id").map(str::to_string),
+                out: options.remove("--out").map(PathBuf::from),
+            };
+            reject_unknown(options)?;
+            command
+        }
+        ["sign", options @ ..] => {
+            let mut options = parse_options(options)?;
+            let key_id = options.remove("--key-id");
+            let (key_id, message) = match (key_id, options.remove("--message")) {
+                (Some(key_id), Some(message)) => (key_id, message),
+                _ => bail!("sign needs --key-id and --message\n{}", USAGE),
+            };
+            let hash_mode = match options.remove("--hash-mode") {
+                Some(mode) => {
+                    serde_json
+                        ::from_value(serde_json::Value::String(mode.to_string()))
+                        .with_context(|| format!("Unknown hash mode {}", mode))?
+                }
+                None => HashMode::None,
+            };
+            let parties = options
+                .remove("--parties")
+                .map(|parties| parties.split(',').map(|id| NodeId::new(id.to_string())).collect());
+            reject_unknown(options)?;
+            CliCommand::Sign {
+                key_id: key_id.to_string(),
+                message: hex::decode(message).context("Message is not hex")?,
+                parties,
+                hash_mode,
+            }
+        }
+        _ => bail!("{}", USAGE),
+    };
+    Ok(command)
+}
+
+/// `--name value` pairs
+fn parse_options<'a>(args: &[&'a str]) -> Result<BTreeMap<&'a str, &'a str>> {
+    if args.len() % 2 != 0 || args.iter().step_by(2).any(|name| !name.starts_with("--")) {
+        bail!("Options have to be given as --name value\n{}", USAGE);
+    }
+    Ok(
+        args
+            .chunks(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect()
+    )
+}
+
+fn reject_unknown(options: BTreeMap<&str, &str>) -> Result<()> {
+    if let Some(name) = options.keys().next() {
+        bail!("Unknown option {}\n{}", name, USAGE);
+    }
+    Ok(())
+}
+
+fn print<C: JsonCommand>(command: C) -> Result<()> {
+    let response = command.execute_message(MsgContext::FFI)?;
+    println!("{}", serde_json::to_string_pretty(&response)?);
+    Ok(())
+}
+
+/// Backs up keys of an account without its proof, whoever runs the CLI can read the key storage
+fn backup(email: String, key_id: Option<String>, out: Option<PathBuf>) -> Result<()> {
+    let passphrase = match env::var(BACKUP_PASSPHRASE_VAR) {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            eprintln!("Backup passphrase:");
+            let mut passphrase = String::new();
+            io::stdin().read_line(&mut passphrase)?;
+            passphrase.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    let bundle = backup::create_backup(&email, key_id.as_deref(), &passphrase)?;
+    let bundle = serde_json::to_string_pretty(&bundle)?;
+    match out {
+        Some(out) => {
+            fs::write(&out, bundle).with_context(|| format!("Failed to write {}", out.display()))
+        }
+        None => {
+            println!("{}", bundle);
+            Ok(())
+        }
+    }
+}
+
+/// Signs with the key's node pool unless the parties are given
+fn sign(
+    key_id: String,
+    message: Vec<u8>,
+    parties: Option<Vec<NodeId>>,
+    hash_mode: HashMode
+) -> Result<()> {
+    let info = GetKeyInfoCommand {
+        key_id: key_id.clone(),
+        authorization: None,
+    }.execute_message(MsgContext::FFI)?;
+    let kind = match info.kind {
+        key_info::Key::ECDSA { .. } => Key::ECDSA,
+        key_info::Key::EDDSA { .. } => Key::EDDSA,
+        key_info::Key::Sr25519 { .. } => Key::Sr25519,
+        key_info::Key::Frost { .. } => Key::Frost,
+        key_info::Key::BLS { .. } => Key::BLS,
+    };
+    let party_nodes = parties.unwrap_or_else(|| {
+        info.node_pool
+            .iter()
+            .map(|node| node.node_id.clone())
+            .collect()
+    });
+    print(SigningCommand {
+        kind,
+        key_id,
+        session_id: uuid::Uuid::new_v4().to_string(),
+        party_nodes,
+        msg: message,
+        encoding: None,
+        taproot_merkle_root: None,
+        presignature_id: None,
+        hash_mode,
+        network_mode: Default::default(),
+        response_version: Default::default(),
+        allow_resign: false,
+        allow_quarantined_peers: false,
+        dry_run: false,
+        derivation_path: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands_and_options() {
+        assert_eq!(parse(&["keys", "list"]).unwrap(), CliCommand::ListKeys);
+        let sign = parse(&["sign", "--message", "0aff", "--key-id", "k1", "--hash-mode", "sha256"]);
+        assert_eq!(sign.unwrap(), CliCommand::Sign {
+            key_id: "k1".to_string(),
+            message: vec![0x0a, 0xff],
+            parties: None,
+            hash_mode: HashMode::Sha256,
+        });
+        assert!(parse(&["sign", "--key-id", "k1"]).is_err());
+        assert!(parse(&["backup", "--out"]).is_err());
+        assert!(parse(&["backup", "--key-id", "k1"]).is_err());
+        assert!(parse(&["backup", "--force", "yes"]).is_err());
+        assert!(parse(&["keys", "remove"]).is_err());
+    }
+}
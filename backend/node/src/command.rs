@@ -1,11 +1,60 @@
+use crate::approval::ApproveSigningCommand;
+use crate::attestation::AttestCommand;
+use crate::audit::GetAuditLogCommand;
+use crate::communication::incoming::IncomingMessage;
+use crate::communication::permissions::GetNatsPermissionsCommand;
+use crate::config::file::ReloadConfigCommand;
+use crate::conformance::ConformanceCheckCommand;
 use crate::eject::{ EjectKeysCommand, EjectSharesCommand };
+use crate::ghost_shares::GenerateGhostSharesCommand;
+use crate::health::{ self, GetGuardianHealthCommand, GetHealthHistoryCommand };
+use crate::key_info::{ GetKeyInfoCommand, GetKeyUsageCommand };
+use crate::key_info_repair::{ RepairKeyInfoCommand, RequestKeyInfoCommand };
+use crate::keygen::derivation::DeriveChildKeyCommand;
+use crate::reputation::GetPeerReputationCommand;
+use crate::user_recovery::verifier::EnrollRecoveryFactorCommand;
 use crate::keygen::key_import::{ KeyImportCommand, KeyImportShareCommand };
+use crate::keygen::key_import::party::{ PrepareKeyImportCommand, ReceiveImportedShareCommand };
+use crate::keygen::preflight::GetKeygenCapabilitiesCommand;
 use crate::keygen::sr25519::KeyGenCommand as Sr25519KeyGenCommand;
 use crate::keygen::KeyGenCommand;
-use crate::recovery::{ GetPaillierKeysCommand, RecoveryCommand };
+use crate::liveness::{ GetPoolHealthCommand, PingPeerCommand };
+use crate::log_tail::TailLogsCommand;
+use crate::observer::ObserverConsentCommand;
+use crate::operator::GetNodeInfoCommand;
+use crate::pairing::{ ConfirmPairingCommand, PairDeviceCommand };
+use crate::policy::SetPolicyCommand;
+use crate::recovery::{
+    CancelRecoveryCommand,
+    DirectRecoveryCommand,
+    GetPendingRecoveriesCommand,
+    GetPaillierKeysCommand,
+    RecoveryCommand,
+    RecordPendingRecoveryCommand,
+    ReplaceGuardianCommand,
+    RevokeRecoverySessionCommand,
+    SetRecoveryDelayCommand,
+};
+use crate::refresh::generations::RevertShareRefreshCommand;
+use crate::refresh::RefreshSharesCommand;
+use crate::revocation::UpdateRevocationListCommand;
+use crate::session_manager::{ self, CancelSessionCommand, ListSessionsCommand };
+use crate::signing::batch::BatchSigningCommand;
+use crate::signing::ecdsa::warmup::WarmupSessionCommand;
 use crate::signing::sr25519::KeySignCommand as Sr25519KeySignCommand;
+use crate::signing::presigning::PresignCommand;
+use crate::signing::preflight::PreflightSigningCommand;
 use crate::signing::SigningCommand;
+use crate::slo::GetSLOReportCommand;
 use crate::storage::keyshare_index_info::{ get_all_keyshare_indices, KeyshareIndex };
+use crate::storage::backup::{ BackupShareCommand, RestoreShareCommand };
+use crate::storage::deletion::{ ConfirmDeleteKeyCommand, DeleteKeyCommand };
+use crate::storage::key_listing::ListKeysCommand;
+use crate::storage::keyshare_check::VerifyKeysharesCommand;
+use crate::storage::reencryption::GetReencryptionStatusCommand;
+use crate::strict::{ self, ValidatePayloadCommand };
+use crate::node::NodeIdentity;
+use crate::tenant::{ self, Access, TenantScope };
 use crate::App;
 use anyhow::{ anyhow, bail, Result };
 use serde::{ Deserialize, Serialize };
@@ -16,60 +65,96 @@ use shared::recovery::{
     UpdateSinglePaillierKeyCommand,
 };
 use std::fmt::Debug;
-use std::thread;
 use tracing::{ error, info };
 
-pub enum MsgContext {
+enum Source {
     NATS(App),
     FFI,
 }
 
+/// Where a command came from and the account its caller proved
+pub struct MsgContext {
+    source: Source,
+    tenant: Option<String>,
+}
+
 impl MsgContext {
+    pub const FFI: MsgContext = MsgContext { source: Source::FFI, tenant: None };
+
+    pub fn nats(app: App) -> Self {
+        Self { source: Source::NATS(app), tenant: None }
+    }
+
     pub fn get_app(&self) -> Result<App> {
-        match self {
-            MsgContext::NATS(app) => Ok(app.clone()),
-            MsgContext::FFI => App::new(),
+        match &self.source {
+            Source::NATS(app) => Ok(app.clone()),
+            Source::FFI => App::new(),
         }
     }
 
     fn get_encoder(&self) -> Encoder {
-        match self {
-            MsgContext::NATS(_) => Encoder::PlaintextEncoder,
-            MsgContext::FFI => Encoder::B64Encoder,
+        match self.source {
+            Source::NATS(_) => Encoder::PlaintextEncoder,
+            Source::FFI => Encoder::B64Encoder,
+        }
+    }
+
+    /// Account the caller proved, `None` for commands about the node and on single user nodes
+    /// for commands without a proof
+    pub fn tenant(&self) -> Option<String> {
+        self.tenant.clone()
+    }
+
+    /// Refuses an account other than the proven one
+    pub fn ensure_account(&self, email: &str) -> Result<()> {
+        match &self.tenant {
+            Some(tenant) if tenant != email => {
+                bail!("Account {} can't access keys of another account", tenant)
+            }
+            _ => Ok(()),
         }
     }
+
+    /// Checks what the command has to prove. The returned scope confines storage reads on this
+    /// thread to the proven account while the command runs.
+    fn authorize(mut self, access: Access) -> Result<(Self, Option<TenantScope>)> {
+        let scope = match access {
+            Access::Node | Access::Checked | Access::Peer => None,
+            Access::Account { auth, key_ids } => {
+                tenant::authorize(auth, &key_ids, &NodeIdentity::cached()?)?
+            }
+        };
+        self.tenant = tenant::current();
+        Ok((self, scope))
+    }
 }
 
-pub fn handle_nats_command(app: &App, message: nats::Message) -> Result<()> {
+pub fn handle_nats_command(app: &App, message: IncomingMessage) -> Result<()> {
     let request = String::from_utf8(message.data.clone())?;
     let app = app.clone();
-    let subject = message.subject.clone();
-
-    match
-        thread::Builder
-            ::new()
-            .name(subject.clone())
-            .spawn(move || {
-                let response = handle_json_message(&request, MsgContext::NATS(app)).unwrap_or_else(
-                    |err| format!("ERROR: {}", err)
-                );
-
-                if message.reply.is_some() {
-                    match message.respond(response) {
-                        Ok(_) => {}
-                        Err(err) => error!("Unable to respond to nats message: {}", err),
-                    }
-                }
-            })
-    {
-        Ok(_) => Ok(()),
-        Err(_e) => bail!("Failed to spawn thread for keygen session for subject {}", subject),
-    }
+    let nc = app.nc.clone();
+
+    // Commands block on the node's storage and connection, they run on the runtime's pool of
+    // blocking threads, which caps how many run at once
+    session_manager::runtime().spawn_blocking(move || {
+        let result = handle_json_message(&request, MsgContext::nats(app));
+        health::record_command(result.is_ok());
+        let response = result.unwrap_or_else(|err| format!("ERROR: {}", err));
+
+        if message.reply.is_some() {
+            match message.respond(&nc, response) {
+                Ok(_) => {}
+                Err(err) => error!("Unable to respond to nats message: {}", err),
+            }
+        }
+    });
+    Ok(())
 }
 
 pub fn handle_json_message(request: &str, source: MsgContext) -> Result<String> {
     process_request(request, source).map_err(|err| {
-        let msg = format!("Could not process received message: {}, message was {}", err, request);
+        // Requests carry passphrases and key material, only the error is logged
+        let msg = format!("Could not process received message: {}", err);
         error!("{}", &msg);
         anyhow!("{}", &msg)
     })
@@ -78,15 +163,67 @@ pub fn handle_json_message(request: &str, source: MsgContext) -> Result<String>
 fn process_request<T>(request: T, ctx: MsgContext) -> Result<String> where T: AsRef<[u8]> {
     let encoder = ctx.get_encoder();
     let command = encoder.decode(request).map_err(|_| anyhow!("Could not decode message"))?;
-    let response = match serde_json::from_slice::<TaggedCommandType>(&command) {
+    let response = match strict::from_slice::<TaggedCommandType>(&command) {
         Ok(tagged_cmd) =>
             (match tagged_cmd {
                 TaggedCommandType::OrchestrateKeyGen(cmd) => cmd.execute(ctx),
                 TaggedCommandType::OrchestrateSigning(cmd) => cmd.execute(ctx),
+                TaggedCommandType::OrchestrateBatchSigning(cmd) => cmd.execute(ctx),
                 TaggedCommandType::OrchestrateRecovery(cmd) => cmd.execute(ctx),
+                TaggedCommandType::PreflightSigning(cmd) => cmd.execute(ctx),
+                TaggedCommandType::OrchestratePresign(cmd) => cmd.execute(ctx),
+                TaggedCommandType::CancelSession(cmd) => cmd.execute(ctx),
+                TaggedCommandType::ListSessions(cmd) => cmd.execute(ctx),
+                TaggedCommandType::OrchestrateDirectRecovery(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GetKeyInfo(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GetKeyUsage(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GetReencryptionStatus(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GetSLOReport(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GetGuardianHealth(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GetAuditLog(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GetNodeInfo(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GetNatsPermissions(cmd) => cmd.execute(ctx),
+                TaggedCommandType::BackupShare(cmd) => cmd.execute(ctx),
+                TaggedCommandType::RestoreShare(cmd) => cmd.execute(ctx),
+                TaggedCommandType::DeleteKey(cmd) => cmd.execute(ctx),
+                TaggedCommandType::ConfirmDeleteKey(cmd) => cmd.execute(ctx),
+                TaggedCommandType::SetPolicy(cmd) => cmd.execute(ctx),
+                TaggedCommandType::ApproveSigning(cmd) => cmd.execute(ctx),
+                TaggedCommandType::ReplaceGuardian(cmd) => cmd.execute(ctx),
+                TaggedCommandType::RevokeRecoverySession(cmd) => cmd.execute(ctx),
+                TaggedCommandType::TailLogs(cmd) => cmd.execute(ctx),
+                TaggedCommandType::ValidatePayload(cmd) => cmd.execute(ctx),
+                TaggedCommandType::WarmupSession(cmd) => cmd.execute(ctx),
+                TaggedCommandType::PairDevice(cmd) => cmd.execute(ctx),
+                TaggedCommandType::ConfirmPairing(cmd) => cmd.execute(ctx),
+                TaggedCommandType::ReloadConfig(cmd) => cmd.execute(ctx),
+                TaggedCommandType::ListKeys(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GenerateGhostShares(cmd) => cmd.execute(ctx),
+                TaggedCommandType::Attest(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GetKeygenCapabilities(cmd) => cmd.execute(ctx),
+                TaggedCommandType::RefreshShares(cmd) => cmd.execute(ctx),
+                TaggedCommandType::RevertShareRefresh(cmd) => cmd.execute(ctx),
+                TaggedCommandType::DeriveChildKey(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GetPeerReputation(cmd) => cmd.execute(ctx),
+                TaggedCommandType::PrepareKeyImport(cmd) => cmd.execute(ctx),
+                TaggedCommandType::ReceiveImportedShare(cmd) => cmd.execute(ctx),
+                TaggedCommandType::EnrollRecoveryFactor(cmd) => cmd.execute(ctx),
+                TaggedCommandType::SetRecoveryDelay(cmd) => cmd.execute(ctx),
+                TaggedCommandType::RecordPendingRecovery(cmd) => cmd.execute(ctx),
+                TaggedCommandType::CancelRecovery(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GetPendingRecoveries(cmd) => cmd.execute(ctx),
+                TaggedCommandType::PingPeer(cmd) => cmd.execute(ctx),
+                TaggedCommandType::GetPoolHealth(cmd) => cmd.execute(ctx),
+                TaggedCommandType::RequestKeyInfo(cmd) => cmd.execute(ctx),
+                TaggedCommandType::RepairKeyInfo(cmd) => cmd.execute(ctx),
+                TaggedCommandType::VerifyKeyshares(cmd) => cmd.execute(ctx),
             })?,
+        // Only legacy commands come without the `cmd` tag
+        Err(err) if has_command_tag(&command) => {
+            return Err(err);
+        }
         Err(_e) =>
-            (match serde_json::from_slice::<CommandType>(&command)? {
+            (match strict::from_slice::<CommandType>(&command)? {
                 CommandType::KeyImport(cmd) => cmd.execute(ctx),
                 CommandType::KeyImportShare(cmd) => cmd.execute(ctx),
                 CommandType::KeyshareRecovery(cmd) => cmd.execute(ctx),
@@ -99,12 +236,23 @@ fn process_request<T>(request: T, ctx: MsgContext) -> Result<String> where T: As
                 CommandType::Sr25519KeySign(cmd) => cmd.execute(ctx),
                 CommandType::UpdateKeyInfo(cmd) => cmd.execute(ctx),
                 CommandType::GetPaillierKeys(cmd) => cmd.execute(ctx),
+                CommandType::ConformanceCheck(cmd) => cmd.execute(ctx),
+                CommandType::UpdateRevocationList(cmd) => cmd.execute(ctx),
+                CommandType::ObserverConsent(cmd) => cmd.execute(ctx),
+                CommandType::GetHealthHistory(cmd) => cmd.execute(ctx),
             })?,
     };
 
     encoder.encode(&response)
 }
 
+fn has_command_tag(command: &[u8]) -> bool {
+    serde_json
+        ::from_slice::<serde_json::Value>(command)
+        .map(|value| value.get("cmd").is_some())
+        .unwrap_or(false)
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum CommandType {
@@ -120,6 +268,10 @@ pub enum CommandType {
     EjectKeys(EjectKeysCommand),
     UpdateKeyInfo(UpdateKeyInfoCommand),
     GetPaillierKeys(GetPaillierKeysCommand),
+    ConformanceCheck(ConformanceCheckCommand),
+    UpdateRevocationList(UpdateRevocationListCommand),
+    ObserverConsent(ObserverConsentCommand),
+    GetHealthHistory(GetHealthHistoryCommand),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -127,7 +279,55 @@ pub enum CommandType {
 pub enum TaggedCommandType {
     OrchestrateKeyGen(KeyGenCommand),
     OrchestrateSigning(SigningCommand),
+    OrchestrateBatchSigning(BatchSigningCommand),
     OrchestrateRecovery(RecoveryCommand),
+    PreflightSigning(PreflightSigningCommand),
+    OrchestratePresign(PresignCommand),
+    CancelSession(CancelSessionCommand),
+    ListSessions(ListSessionsCommand),
+    OrchestrateDirectRecovery(DirectRecoveryCommand),
+    GetKeyInfo(GetKeyInfoCommand),
+    GetKeyUsage(GetKeyUsageCommand),
+    GetReencryptionStatus(GetReencryptionStatusCommand),
+    GetSLOReport(GetSLOReportCommand),
+    GetGuardianHealth(GetGuardianHealthCommand),
+    GetAuditLog(GetAuditLogCommand),
+    GetNodeInfo(GetNodeInfoCommand),
+    GetNatsPermissions(GetNatsPermissionsCommand),
+    BackupShare(BackupShareCommand),
+    RestoreShare(RestoreShareCommand),
+    DeleteKey(DeleteKeyCommand),
+    ConfirmDeleteKey(ConfirmDeleteKeyCommand),
+    SetPolicy(SetPolicyCommand),
+    ApproveSigning(ApproveSigningCommand),
+    ReplaceGuardian(ReplaceGuardianCommand),
+    RevokeRecoverySession(RevokeRecoverySessionCommand),
+    TailLogs(TailLogsCommand),
+    ValidatePayload(ValidatePayloadCommand),
+    WarmupSession(WarmupSessionCommand),
+    PairDevice(PairDeviceCommand),
+    ConfirmPairing(ConfirmPairingCommand),
+    ReloadConfig(ReloadConfigCommand),
+    ListKeys(ListKeysCommand),
+    GenerateGhostShares(GenerateGhostSharesCommand),
+    Attest(AttestCommand),
+    GetKeygenCapabilities(GetKeygenCapabilitiesCommand),
+    RefreshShares(RefreshSharesCommand),
+    RevertShareRefresh(RevertShareRefreshCommand),
+    DeriveChildKey(DeriveChildKeyCommand),
+    GetPeerReputation(GetPeerReputationCommand),
+    PrepareKeyImport(PrepareKeyImportCommand),
+    ReceiveImportedShare(ReceiveImportedShareCommand),
+    EnrollRecoveryFactor(EnrollRecoveryFactorCommand),
+    SetRecoveryDelay(SetRecoveryDelayCommand),
+    RecordPendingRecovery(RecordPendingRecoveryCommand),
+    CancelRecovery(CancelRecoveryCommand),
+    GetPendingRecoveries(GetPendingRecoveriesCommand),
+    PingPeer(PingPeerCommand),
+    GetPoolHealth(GetPoolHealthCommand),
+    RequestKeyInfo(RequestKeyInfoCommand),
+    RepairKeyInfo(RepairKeyInfoCommand),
+    VerifyKeyshares(VerifyKeysharesCommand),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -149,6 +349,7 @@ pub trait JsonCommand: Debug {
     type Response: Serialize;
     fn execute(self, ctx: MsgContext) -> Result<String> where Self: Sized {
         self.log_message();
+        let (ctx, _scope) = ctx.authorize(self.access())?;
         let response = self.execute_message(ctx)?;
         info!("Message processed successfully");
         let res = serde_json::to_string(&response)?;
@@ -159,6 +360,11 @@ pub trait JsonCommand: Debug {
         info!("Received message: {:?}", &self)
     }
 
+    /// Commands reading keys of an account without a proof only run on single user nodes
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: None, key_ids: Vec::new() }
+    }
+
     fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized;
 }
 
@@ -0,0 +1,191 @@
+//! Blocking calls on the node's async NATS client, for the code that still runs on its own
+//! threads: the orchestrators, the GG20 signing and keygen sessions, key import, preflight,
+//! liveness, refresh, health and recovery. Those haven't been ported to tasks yet, they share the
+//! one connection of the node with the async sessions instead of opening a second one.
+use crate::session_manager;
+use async_nats::{ Client, Subscriber };
+use futures::StreamExt;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::{ Mutex, Notify };
+
+/// Waits for `future` on the session runtime. Called from a task it moves the other tasks of its
+/// worker to another one first, the runtime has a worker thread per core.
+fn block_on<F: Future>(future: F) -> F::Output {
+    match Handle::try_current() {
+        Ok(handle) => tokio::task::block_in_place(|| handle.block_on(future)),
+        Err(_) => session_manager::runtime().block_on(future),
+    }
+}
+
+fn io_error(err: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+#[derive(Clone, Debug)]
+pub struct Connection {
+    client: Client,
+}
+
+impl Connection {
+    pub fn new(client: Client) -> Self {
+        Connection { client }
+    }
+
+    /// The async client, for the code running as tasks
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn publish(&self, subject: &str, data: impl AsRef<[u8]>) -> io::Result<()> {
+        let payload = data.as_ref().to_vec().into();
+        block_on(self.client.publish(subject.to_string(), payload)).map_err(io_error)
+    }
+
+    pub fn publish_request(
+        &self,
+        subject: &str,
+        reply: &str,
+        data: impl AsRef<[u8]>
+    ) -> io::Result<()> {
+        let payload = data.as_ref().to_vec().into();
+        let publish = self.client.publish_with_reply(
+            subject.to_string(),
+            reply.to_string(),
+            payload
+        );
+        block_on(publish).map_err(io_error)
+    }
+
+    pub fn subscribe(&self, subject: &str) -> io::Result<Subscription> {
+        let subscriber = block_on(self.client.subscribe(subject.to_string())).map_err(io_error)?;
+        Ok(Subscription {
+            subject: subject.to_string(),
+            client: self.client.clone(),
+            subscriber: Arc::new(Mutex::new(subscriber)),
+            closed: Arc::new(AtomicBool::new(false)),
+            unsubscribed: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Waits for a response for as long as the client's request timeout
+    pub fn request(&self, subject: &str, data: impl AsRef<[u8]>) -> io::Result<Message> {
+        let payload = data.as_ref().to_vec().into();
+        let response = block_on(self.client.request(subject.to_string(), payload));
+        Ok(Message::received(response.map_err(io_error)?, &self.client))
+    }
+
+    pub fn request_timeout(
+        &self,
+        subject: &str,
+        data: impl AsRef<[u8]>,
+        timeout: Duration
+    ) -> io::Result<Message> {
+        let payload = data.as_ref().to_vec().into();
+        let request = self.client.request(subject.to_string(), payload);
+        match block_on(async { tokio::time::timeout(timeout, request).await }) {
+            Ok(response) => Ok(Message::received(response.map_err(io_error)?, &self.client)),
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "No response in time")),
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        block_on(self.client.flush()).map_err(io_error)
+    }
+}
+
+/// Subscription to one subject. Clones share it, unsubscribing one ends the wait of a thread
+/// blocked on another.
+#[derive(Clone)]
+pub struct Subscription {
+    subject: String,
+    client: Client,
+    subscriber: Arc<Mutex<Subscriber>>,
+    closed: Arc<AtomicBool>,
+    unsubscribed: Arc<Notify>,
+}
+
+impl Subscription {
+    /// Next message, `None` once the subscription is closed
+    pub fn next(&self) -> Option<Message> {
+        block_on(self.receive())
+    }
+
+    pub fn next_timeout(&self, timeout: Duration) -> io::Result<Message> {
+        match block_on(async { tokio::time::timeout(timeout, self.receive()).await }) {
+            Ok(Some(message)) => Ok(message),
+            Ok(None) => {
+                Err(io::Error::new(io::ErrorKind::NotConnected, "Subscription was closed"))
+            }
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "No message in time")),
+        }
+    }
+
+    pub fn unsubscribe(self) -> io::Result<()> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.unsubscribed.notify_waiters();
+        block_on(async { self.subscriber.lock().await.unsubscribe().await }).map_err(io_error)
+    }
+
+    async fn receive(&self) -> Option<Message> {
+        let unsubscribed = self.unsubscribed.notified();
+        tokio::pin!(unsubscribed);
+        // Registered before the flag is read, an unsubscribe in between still wakes it
+        unsubscribed.as_mut().enable();
+        if self.closed.load(Ordering::SeqCst) {
+            return None;
+        }
+        let mut subscriber = tokio::select! {
+            _ = &mut unsubscribed => {
+                return None;
+            }
+            subscriber = self.subscriber.lock() => subscriber,
+        };
+        tokio::select! {
+            _ = unsubscribed => None,
+            message = subscriber.next() => {
+                message.map(|message| Message::received(message, &self.client))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscription").field("subject", &self.subject).finish()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub subject: String,
+    pub reply: Option<String>,
+    pub data: Vec<u8>,
+    client: Client,
+}
+
+impl Message {
+    fn received(message: async_nats::Message, client: &Client) -> Self {
+        Message {
+            subject: message.subject,
+            reply: message.reply,
+            data: message.payload.to_vec(),
+            client: client.clone(),
+        }
+    }
+
+    /// Publishes `data` on the reply subject, fails for messages that don't expect a response
+    pub fn respond(&self, data: impl AsRef<[u8]>) -> io::Result<()> {
+        match &self.reply {
+            Some(reply) => Connection::new(self.client.clone()).publish(reply, data),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "No reply subject")),
+        }
+    }
+}
@@ -1,12 +1,23 @@
+use crate::communication::blocking;
+use crate::communication::envelope;
+use crate::communication::round_subscriptions::ReplayRequester;
 use crate::node::NodeIdentity;
+use crate::session_manager;
 use anyhow::{ anyhow, bail };
+use async_nats::Subscriber;
+use futures::StreamExt;
 use serde::de::DeserializeOwned;
 use serde::{ Deserialize, Serialize };
 use shared::key_info::NodeId;
 use std::any::type_name;
-use std::collections::BTreeMap;
-use std::time::Duration;
-use tracing::error;
+use std::collections::{ BTreeMap, BTreeSet };
+use std::time::{ Duration, Instant };
+use tracing::{ error, warn };
+
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Waiting this long without a message asks the other parties to resend the round
+const REPLAY_AFTER: Duration = Duration::from_secs(5);
 
 #[derive(Serialize, Deserialize)]
 pub struct JoinMessage {
@@ -17,7 +28,7 @@ pub struct JoinMessage {
 
 impl JoinMessage {
     pub fn new(session_id: String, index: usize) -> Self {
-        let node = match NodeIdentity::load() {
+        let node = match NodeIdentity::cached() {
             Ok(node) => node,
             Err(err) => {
                 let err_msg = format!("Node id is not retrievable: {}", err);
@@ -47,93 +58,273 @@ pub trait HasTargetId {
     fn get_target_id(&self) -> usize;
 }
 
-fn collect_messages<T>(
-    sub: &nats::Subscription,
-    party_count: usize,
-    receiver_id: Option<usize>
-) -> anyhow::Result<Vec<T>>
-    where T: DeserializeOwned + HasSenderId + Clone
-{
-    let message_count = party_count - (if receiver_id.is_some() { 1 } else { 0 });
+/// Messages of one round keyed by sender, together with the raw payload each was decoded from
+struct SenderMessages<T> {
+    expected_senders: BTreeSet<usize>,
+    received: BTreeMap<usize, (Vec<u8>, T)>,
+}
 
-    let mut map: BTreeMap<usize, T> = BTreeMap::new();
-    while map.len() < message_count {
-        let data = get_next_item::<T>(sub).map_err(|err|
-            anyhow!("{}, recieved responses from parties {:?}", err, map.keys())
-        )?;
+impl<T> SenderMessages<T> where T: HasSenderId {
+    fn new(expected_senders: BTreeSet<usize>) -> Self {
+        Self {
+            expected_senders,
+            received: BTreeMap::new(),
+        }
+    }
 
-        let sender_id = data.get_sender_id();
+    fn is_complete(&self) -> bool {
+        self.received.len() == self.expected_senders.len()
+    }
 
-        if let Some(id) = receiver_id {
-            if id == sender_id {
-                let err_msg = format!("Received a \"{}\" message from ourselves", type_name::<T>());
-                bail!("{}", err_msg);
-            }
-        }
+    fn missing_senders(&self) -> Vec<usize> {
+        self.expected_senders
+            .iter()
+            .filter(|sender| !self.received.contains_key(sender))
+            .copied()
+            .collect()
+    }
 
-        if map.contains_key(&sender_id) {
-            let err_msg = format!(
-                "Received more than one \"{}\" message from sender #{}",
+    /// A byte-identical copy of a message already received is a replay and is dropped, a different
+    /// message from the same sender or a message from outside the expected set fails the round
+    fn insert(&mut self, data: Vec<u8>, message: T) -> anyhow::Result<()> {
+        let sender_id = message.get_sender_id();
+        if !self.expected_senders.contains(&sender_id) {
+            bail!(
+                "Received a \"{}\" message from unexpected sender #{}, expected senders are {:?}",
                 type_name::<T>(),
-                sender_id
+                sender_id,
+                self.expected_senders
             );
-            bail!("{}", err_msg);
         }
-
-        map.insert(sender_id, data);
-    }
-
-    for i in 0..party_count {
-        if let Some(id) = receiver_id {
-            if i == id {
-                map.remove(&i);
+        match self.received.get(&sender_id) {
+            Some((previous, _)) if previous == &data => {
+                warn!(
+                    "Ignoring a replayed \"{}\" message from sender #{}",
+                    type_name::<T>(),
+                    sender_id
+                );
+            }
+            Some(_) => {
+                bail!(
+                    "Received conflicting \"{}\" messages from sender #{}",
+                    type_name::<T>(),
+                    sender_id
+                );
+            }
+            None => {
+                self.received.insert(sender_id, (data, message));
             }
         }
+        Ok(())
     }
-    let values = map.values().cloned().collect();
 
-    Ok(values)
+    fn into_ordered(self) -> Vec<T> {
+        self.received
+            .into_values()
+            .map(|(_, message)| message)
+            .collect()
+    }
+}
+
+/// Collects exactly one message from every sender in `expected_senders`, ordered by sender id
+pub fn collect_messages_from<T>(
+    sub: &blocking::Subscription,
+    expected_senders: BTreeSet<usize>
+) -> anyhow::Result<Vec<T>>
+    where T: DeserializeOwned + HasSenderId + Clone
+{
+    session_manager::check_party_count(expected_senders.len())?;
+    let mut messages = SenderMessages::<T>::new(expected_senders);
+    while !messages.is_complete() {
+        let (data, message) = get_next_raw_item::<T>(sub).map_err(|err|
+            anyhow!("{}, missing messages from senders {:?}", err, messages.missing_senders())
+        )?;
+        messages.insert(data, message)?;
+    }
+    Ok(messages.into_ordered())
 }
 
+/// Collects one message from each of the senders `0..expected_count`
 pub fn collect_messages_ordered<T>(
-    sub: &nats::Subscription,
+    sub: &blocking::Subscription,
     expected_count: usize
 ) -> anyhow::Result<Vec<T>>
     where T: DeserializeOwned + HasSenderId + Clone
 {
-    collect_messages(sub, expected_count, None)
+    session_manager::check_party_count(expected_count)?;
+    collect_messages_from(sub, (0..expected_count).collect())
 }
 
+/// Collects one message from each of the senders `0..party_count` except the receiver itself
 pub fn collect_messages_p2p<T>(
-    sub: &nats::Subscription,
+    sub: &blocking::Subscription,
     party_count: usize,
     receiver_id: usize
 ) -> anyhow::Result<Vec<T>>
     where T: DeserializeOwned + HasSenderId + Clone
 {
-    collect_messages(sub, party_count, Some(receiver_id))
+    session_manager::check_party_count(party_count)?;
+    collect_messages_from(
+        sub,
+        (0..party_count).filter(|sender| *sender != receiver_id).collect()
+    )
 }
 
-pub fn collect_message<T>(sub: &nats::Subscription) -> anyhow::Result<T>
+pub fn collect_message<T>(sub: &blocking::Subscription) -> anyhow::Result<T>
     where T: DeserializeOwned + Clone
 {
     get_next_item::<T>(sub)
 }
 
-fn get_next_item<T>(sub: &nats::Subscription) -> anyhow::Result<T> where T: DeserializeOwned + Clone {
-    let mesg = match sub.next_timeout(Duration::from_secs(30)) {
-        Ok(msg) => msg,
-        Err(_) => {
+fn get_next_item<T>(sub: &blocking::Subscription) -> anyhow::Result<T>
+    where T: DeserializeOwned + Clone
+{
+    get_next_raw_item(sub).map(|(_, item)| item)
+}
+
+fn get_next_raw_item<T>(sub: &blocking::Subscription) -> anyhow::Result<(Vec<u8>, T)>
+    where T: DeserializeOwned + Clone
+{
+    // Waits in short slices so a cancelled or expired session stops without waiting out the round
+    let started = Instant::now();
+    let mesg = loop {
+        session_manager::ensure_active()?;
+        if let Ok(msg) = sub.next_timeout(SESSION_CHECK_INTERVAL) {
+            break msg;
+        }
+        if started.elapsed() >= MESSAGE_TIMEOUT {
             let err_msg = format!("Timeout while waiting on {:?}", &sub);
             bail!("{}", err_msg);
         }
     };
-    serde_json::from_slice::<T>(&mesg.data).map_err(|_| {
+    decode_item(mesg.data)
+}
+
+/// Collects exactly one message from every sender in `expected_senders` from an async
+/// subscription, ordered by sender id. Senders are asked to resend the round when it stalls.
+pub async fn receive_messages_from<T>(
+    sub: &mut Subscriber,
+    expected_senders: BTreeSet<usize>,
+    replay: &ReplayRequester
+) -> anyhow::Result<Vec<T>>
+    where T: DeserializeOwned + HasSenderId + Clone
+{
+    session_manager::check_party_count(expected_senders.len())?;
+    let mut messages = SenderMessages::<T>::new(expected_senders);
+    while !messages.is_complete() {
+        let (data, message) = receive_next_raw_item::<T>(sub, replay).await.map_err(|err|
+            anyhow!("{}, missing messages from senders {:?}", err, messages.missing_senders())
+        )?;
+        messages.insert(data, message)?;
+    }
+    Ok(messages.into_ordered())
+}
+
+pub async fn receive_message<T>(
+    sub: &mut Subscriber,
+    replay: &ReplayRequester
+) -> anyhow::Result<T>
+    where T: DeserializeOwned + Clone
+{
+    receive_next_raw_item(sub, replay).await.map(|(_, item)| item)
+}
+
+async fn receive_next_raw_item<T>(
+    sub: &mut Subscriber,
+    replay: &ReplayRequester
+) -> anyhow::Result<(Vec<u8>, T)>
+    where T: DeserializeOwned + Clone
+{
+    // Same slices as the blocking version, the task is parked instead of a thread
+    let started = Instant::now();
+    let mut last_replay = Instant::now();
+    let mesg = loop {
+        session_manager::ensure_active()?;
+        match tokio::time::timeout(SESSION_CHECK_INTERVAL, sub.next()).await {
+            Ok(Some(msg)) => {
+                break msg;
+            }
+            Ok(None) => bail!("Subscription closed while waiting for a \"{}\"", type_name::<T>()),
+            Err(_) => {}
+        }
+        if started.elapsed() >= MESSAGE_TIMEOUT {
+            bail!("Timeout while waiting for a \"{}\" message", type_name::<T>());
+        }
+        // Messages published before this party subscribed never reach it otherwise
+        if last_replay.elapsed() >= REPLAY_AFTER {
+            replay.request().await?;
+            last_replay = Instant::now();
+        }
+    };
+    decode_item(mesg.payload.to_vec())
+}
+
+fn decode_item<T>(data: Vec<u8>) -> anyhow::Result<(Vec<u8>, T)> where T: DeserializeOwned + Clone {
+    session_manager::charge_received(data.len())?;
+    let item = envelope::decode::<T>(&data).map_err(|err| {
         let err_msg = format!(
-            "Failed to deserialize message into a \"{}\" struct, message was {:?}",
+            "Failed to deserialize message into a \"{}\" struct ({}), message was {:?}",
             type_name::<T>(),
-            String::from_utf8(mesg.data).unwrap()
+            err,
+            String::from_utf8_lossy(&data)
         );
         anyhow!("{}", err_msg)
-    })
+    })?;
+    Ok((data, item))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+    struct TestMessage {
+        sender_id: usize,
+        value: u8,
+    }
+
+    impl HasSenderId for TestMessage {
+        fn get_sender_id(&self) -> usize {
+            self.sender_id
+        }
+    }
+
+    fn insert(
+        messages: &mut SenderMessages<TestMessage>,
+        sender_id: usize,
+        value: u8
+    ) -> anyhow::Result<()> {
+        let message = TestMessage { sender_id, value };
+        messages.insert(serde_json::to_vec(&message).unwrap(), message)
+    }
+
+    #[test]
+    fn drops_replays_and_orders_by_sender() {
+        let mut messages = SenderMessages::new([1, 3, 4].into_iter().collect());
+        insert(&mut messages, 4, 40).unwrap();
+        insert(&mut messages, 1, 10).unwrap();
+        insert(&mut messages, 4, 40).unwrap();
+        assert!(!messages.is_complete());
+        assert_eq!(messages.missing_senders(), vec![3]);
+
+        insert(&mut messages, 3, 30).unwrap();
+        assert!(messages.is_complete());
+        let values: Vec<u8> = messages
+            .into_ordered()
+            .iter()
+            .map(|m| m.value)
+            .collect();
+        assert_eq!(values, vec![10, 30, 40]);
+    }
+
+    #[test]
+    fn rejects_unexpected_and_conflicting_senders() {
+        let mut messages = SenderMessages::new([1, 2].into_iter().collect());
+        let err = insert(&mut messages, 5, 50).unwrap_err();
+        assert!(err.to_string().contains("unexpected sender #5"));
+
+        insert(&mut messages, 2, 20).unwrap();
+        let err = insert(&mut messages, 2, 21).unwrap_err();
+        assert!(err.to_string().contains("conflicting"));
+    }
 }
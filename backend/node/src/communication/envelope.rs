@@ -0,0 +1,105 @@
+//! Versioned envelope of session round messages, `{"v": 2, "type": "<round>", "body": ...}`.
+//!
+//! Parties announce the highest version they speak in their join message and the join response
+//! carries the lowest one of the session, so a pool mixing old and new guardians keeps working:
+//! - v1 is the untyped JSON nodes sent before the envelope, the body without any wrapping. It is
+//!   sent whenever a party of the session didn't announce a version.
+//! - v2 wraps the body and names its round.
+//!
+//! Receivers accept every version up to their own whatever the session agreed on, a change of the
+//! message format only has to add a version here and a translation of the older bodies.
+
+use anyhow::{ anyhow, bail, Result };
+use serde::{ de::DeserializeOwned, Deserialize, Serialize };
+use serde_json::Value;
+
+/// Untyped JSON without an envelope
+pub const LEGACY_VERSION: u32 = 1;
+/// Highest version this node sends and understands
+pub const ENVELOPE_VERSION: u32 = 2;
+
+/// Version assumed for join messages and responses of nodes that predate the envelope
+pub fn legacy_version() -> u32 {
+    LEGACY_VERSION
+}
+
+/// Version all parties of a session speak
+pub fn negotiate(versions: impl IntoIterator<Item = u32>) -> u32 {
+    versions.into_iter().min().unwrap_or(LEGACY_VERSION).clamp(LEGACY_VERSION, ENVELOPE_VERSION)
+}
+
+#[derive(Serialize)]
+struct OutgoingEnvelope<'a, T> {
+    v: u32,
+    #[serde(rename = "type")]
+    kind: &'a str,
+    body: &'a T,
+}
+
+#[derive(Deserialize)]
+struct IncomingEnvelope {
+    v: u32,
+    #[serde(rename = "type")]
+    kind: String,
+    body: Value,
+}
+
+/// Serializes a round message in the version the session agreed on
+pub fn encode<T: Serialize>(version: u32, kind: &str, body: &T) -> Result<String> {
+    if version < ENVELOPE_VERSION {
+        return Ok(serde_json::to_string(body)?);
+    }
+    Ok(serde_json::to_string(&(OutgoingEnvelope { v: ENVELOPE_VERSION, kind, body }))?)
+}
+
+/// Deserializes a round message of any known version
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    let value = serde_json::from_slice::<Value>(data)?;
+    let is_enveloped = match &value {
+        Value::Object(fields) => fields.contains_key("v") && fields.contains_key("body"),
+        _ => false,
+    };
+    if !is_enveloped {
+        return Ok(serde_json::from_value(value)?);
+    }
+    let envelope = serde_json::from_value::<IncomingEnvelope>(value)?;
+    match envelope.v {
+        ENVELOPE_VERSION => {
+            serde_json
+                ::from_value(envelope.body)
+                .map_err(|err| anyhow!("Invalid body of a \"{}\" message: {}", envelope.kind, err))
+        }
+        v if v > ENVELOPE_VERSION => {
+            bail!(
+                "\"{}\" message is in format v{}, this node only speaks up to v{}",
+                envelope.kind,
+                v,
+                ENVELOPE_VERSION
+            )
+        }
+        v => bail!("\"{}\" message has the unknown format v{}", envelope.kind, v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::nats::BroadcastMessage;
+
+    #[test]
+    fn enveloped_and_legacy_messages_decode_alike() {
+        let message = BroadcastMessage { sender_id: 3, message: vec![1u8, 2] };
+        let legacy = encode(negotiate([ENVELOPE_VERSION, LEGACY_VERSION]), "Round1", &message);
+        let enveloped = encode(negotiate([ENVELOPE_VERSION, ENVELOPE_VERSION]), "Round1", &message);
+        let legacy = legacy.unwrap();
+        let enveloped = enveloped.unwrap();
+        assert_eq!(legacy, r#"{"sender_id":3,"message":[1,2]}"#);
+        assert!(enveloped.starts_with(r#"{"v":2,"type":"Round1","body":"#));
+        for data in [legacy, enveloped] {
+            let decoded = decode::<BroadcastMessage<Vec<u8>>>(data.as_bytes()).unwrap();
+            assert_eq!((decoded.sender_id, decoded.message), (3, vec![1, 2]));
+        }
+        let newer = r#"{"v":3,"type":"Round1","body":{}}"#;
+        assert!(decode::<BroadcastMessage<Vec<u8>>>(newer.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+use crate::communication::envelope::{ self, ENVELOPE_VERSION };
+use crate::communication::nats::{ BroadcastMessage, PeerMessenger };
+use crate::communication::protocol::{ AllRounds, Topic };
+use anyhow::{ bail, Context, Result };
+use serde::{ de::DeserializeOwned, Serialize };
+use sha2::{ Digest, Sha256 };
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::time::{ Duration, Instant };
+use tracing::info;
+
+/// Seconds an air-gapped party waits for the files of a round, carrying them takes a person
+const ROUND_TIMEOUT_VAR: &str = "AIRGAP_ROUND_TIMEOUT_SECS";
+const DEFAULT_ROUND_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const CHUNK_PREFIX: &str = "GLAG1";
+/// Base64 characters per chunk, a chunk line fits a QR code at medium error correction
+pub const DEFAULT_CHUNK_LEN: usize = 1000;
+
+/// Directories the round messages of an air-gapped party are exchanged through. Files written to
+/// the outbox are carried to the other parties' inboxes by removable media or as QR codes, a
+/// directory can be both the inbox and the outbox when the parties share one.
+#[derive(Clone)]
+pub struct FileTransport {
+    pub inbox: PathBuf,
+    pub outbox: PathBuf,
+}
+
+/// Messenger exchanging round messages as files instead of over NATS, for guardians that never
+/// touch the network. Each message is one file named after the session, topic, round, sender and
+/// recipient, holding the enveloped message. The session waits on every round until the files of
+/// all other parties have been placed in the inbox.
+pub struct FileMessenger<R> {
+    transport: FileTransport,
+    topic: String,
+    session_id: String,
+    party_index: usize,
+    all_party_indices: Vec<usize>,
+    round_timeout: Duration,
+    rounds: PhantomData<fn() -> R>,
+}
+
+impl<R> FileMessenger<R> where R: AllRounds {
+    pub fn new(
+        transport: FileTransport,
+        topic: Topic,
+        session_id: &str,
+        party_index: usize,
+        mut all_party_indices: Vec<usize>
+    ) -> Result<Self> {
+        if session_id.is_empty() || !session_id.chars().all(|c| c.is_alphanumeric() || c == '-') {
+            bail!("Session id {:?} can't be used in message file names", session_id);
+        }
+        all_party_indices.sort();
+        all_party_indices.dedup();
+        if !all_party_indices.contains(&party_index) {
+            bail!("Party {} is not one of the parties {:?}", party_index, all_party_indices);
+        }
+        fs::create_dir_all(&transport.outbox)?;
+        let round_timeout = match env::var(ROUND_TIMEOUT_VAR) {
+            Ok(secs) => Duration::from_secs(secs.parse().context(ROUND_TIMEOUT_VAR)?),
+            Err(_) => DEFAULT_ROUND_TIMEOUT,
+        };
+        Ok(Self {
+            transport,
+            topic: topic.to_string(),
+            session_id: session_id.to_string(),
+            party_index,
+            all_party_indices,
+            round_timeout,
+            rounds: PhantomData,
+        })
+    }
+
+    pub fn party_index(&self) -> usize {
+        self.party_index
+    }
+
+    fn other_party_indices(&self) -> Vec<usize> {
+        self.all_party_indices
+            .iter()
+            .filter(|&&index| index != self.party_index)
+            .copied()
+            .collect()
+    }
+
+    /// Broadcasts are addressed to "all", p2p messages to the index of their recipient
+    fn file_name(&self, round: &str, sender: usize, recipient: Option<usize>) -> String {
+        let recipient = recipient.map_or("all".to_string(), |recipient| recipient.to_string());
+        format!("{}.{}.{}.{}.{}.json", self.session_id, self.topic, round, sender, recipient)
+    }
+
+    fn write<T: Serialize>(&self, round: &str, recipient: Option<usize>, message: T) -> Result<()> {
+        let message = BroadcastMessage { sender_id: self.party_index, message };
+        let payload = envelope::encode(ENVELOPE_VERSION, round, &message)?;
+        let path = self.transport.outbox.join(self.file_name(round, self.party_index, recipient));
+        // Renamed once complete, a party sharing the directory never reads half a message
+        let partial = path.with_extension("part");
+        fs::write(&partial, payload)?;
+        fs::rename(&partial, &path)?;
+        info!("Wrote {}", path.display());
+        Ok(())
+    }
+
+    /// Own broadcasts are read back from the outbox
+    fn read<T: DeserializeOwned>(
+        &self,
+        round: &str,
+        sender: usize,
+        recipient: Option<usize>
+    ) -> Result<Option<T>> {
+        let dir = if sender == self.party_index {
+            &self.transport.outbox
+        } else {
+            &self.transport.inbox
+        };
+        let path = dir.join(self.file_name(round, sender, recipient));
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(None);
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        let message = envelope
+            ::decode::<BroadcastMessage<T>>(&data)
+            .with_context(|| format!("Invalid message file {}", path.display()))?;
+        if message.sender_id != sender {
+            bail!("{} holds a message of party {}", path.display(), message.sender_id);
+        }
+        Ok(Some(message.message))
+    }
+
+    /// One message from every sender, ordered by sender index like the NATS messenger
+    async fn receive_from<T: DeserializeOwned>(
+        &self,
+        round: &str,
+        senders: &[usize],
+        recipient: Option<usize>
+    ) -> Result<Vec<T>> {
+        let started = Instant::now();
+        let mut received = BTreeMap::new();
+        let mut announced = false;
+        loop {
+            for &sender in senders {
+                if received.contains_key(&sender) {
+                    continue;
+                }
+                if let Some(message) = self.read(round, sender, recipient)? {
+                    received.insert(sender, message);
+                }
+            }
+            if received.len() == senders.len() {
+                return Ok(received.into_values().collect());
+            }
+            let missing: Vec<String> = senders
+                .iter()
+                .filter(|sender| !received.contains_key(sender))
+                .map(|sender| self.file_name(round, *sender, recipient))
+                .collect();
+            if !announced {
+                info!(
+                    "Round {} waits for {} in {}",
+                    round,
+                    missing.join(", "),
+                    self.transport.inbox.display()
+                );
+                announced = true;
+            }
+            if started.elapsed() >= self.round_timeout {
+                bail!("Timed out waiting for {}", missing.join(", "));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl<R> PeerMessenger<R> for FileMessenger<R> where R: AllRounds {
+    async fn broadcast_message<T: Serialize + DeserializeOwned + Clone>(
+        &self,
+        round: &R::BroadcastRound,
+        message: T
+    ) -> Result<()> {
+        self.write(&round.to_string(), None, message)
+    }
+
+    async fn collect_messages<T: Serialize + DeserializeOwned + Clone>(
+        &self,
+        round: &R::BroadcastRound
+    ) -> Result<Vec<T>> {
+        self.receive_from(&round.to_string(), &self.all_party_indices, None).await
+    }
+
+    /// The broadcast of the first other party whose file is in the inbox
+    async fn collect_message<T: Serialize + DeserializeOwned + Clone>(
+        &self,
+        round: &R::BroadcastRound
+    ) -> Result<T> {
+        let round = round.to_string();
+        let started = Instant::now();
+        loop {
+            for sender in self.other_party_indices() {
+                if let Some(message) = self.read(&round, sender, None)? {
+                    return Ok(message);
+                }
+            }
+            if started.elapsed() >= self.round_timeout {
+                bail!("Timed out waiting for a {} message", round);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn broadcast_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
+        &self,
+        round: &R::BroadcastRound,
+        message: T
+    ) -> Result<Vec<T>> {
+        self.broadcast_message(round, message).await?;
+        self.collect_messages(round).await
+    }
+
+    async fn send_p2p_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
+        &self,
+        round: &R::P2PRound,
+        messages: Vec<T>
+    ) -> Result<Vec<T>> {
+        let round = round.to_string();
+        let other_party_indices = self.other_party_indices();
+        if messages.len() != other_party_indices.len() {
+            bail!(
+                "Incorrect number of outgoing messages, expected {}, but found {}",
+                other_party_indices.len(),
+                messages.len()
+            );
+        }
+        for (party_index, message) in other_party_indices.iter().zip(messages) {
+            self.write(&round, Some(*party_index), message)?;
+        }
+        self.receive_from(&round, &other_party_indices, Some(self.party_index)).await
+    }
+}
+
+/// Splits a message file into lines of at most `chunk_len` base64 characters plus a short header,
+/// each small enough to be shown as one QR code. The chunks can be scanned in any order.
+pub fn to_chunks(data: &[u8], chunk_len: usize) -> Vec<String> {
+    let encoded = base64::encode(data);
+    let chunk_len = chunk_len.max(1);
+    let digest = hex::encode(&Sha256::digest(data)[..8]);
+    let count = encoded.len().div_ceil(chunk_len).max(1);
+    (0..count)
+        .map(|i| {
+            let part = &encoded[i * chunk_len..encoded.len().min((i + 1) * chunk_len)];
+            format!("{}:{}:{}/{}:{}", CHUNK_PREFIX, digest, i + 1, count, part)
+        })
+        .collect()
+}
+
+/// Reassembles a message file from its chunks, fails unless every chunk of one file is present
+pub fn from_chunks<'a>(lines: impl IntoIterator<Item = &'a str>) -> Result<Vec<u8>> {
+    let mut digest = None;
+    let mut count = None;
+    let mut parts = BTreeMap::new();
+    for line in lines.into_iter().map(str::trim).filter(|line| !line.is_empty()) {
+        let fields: Vec<&str> = line.splitn(4, ':').collect();
+        let (prefix, chunk_digest, position, part) = match fields[..] {
+            [prefix, chunk_digest, position, part] => (prefix, chunk_digest, position, part),
+            _ => bail!("Not a message chunk: {}", line),
+        };
+        let (index, chunk_count) = match position.split_once('/') {
+            Some((index, chunk_count)) => (index.parse::<usize>()?, chunk_count.parse::<usize>()?),
+            None => bail!("Not a message chunk: {}", line),
+        };
+        if prefix != CHUNK_PREFIX {
+            bail!("Chunk of an unknown format {}", prefix);
+        }
+        if *digest.get_or_insert(chunk_digest) != chunk_digest {
+            bail!("Chunks of different messages were mixed");
+        }
+        if *count.get_or_insert(chunk_count) != chunk_count || index == 0 || index > chunk_count {
+            bail!("Chunk {}/{} doesn't belong to the message", index, chunk_count);
+        }
+        parts.insert(index, part);
+    }
+    let count = match count {
+        Some(count) => count,
+        None => bail!("No chunks were given"),
+    };
+    if parts.len() != count {
+        let missing: Vec<usize> = (1..=count).filter(|i| !parts.contains_key(i)).collect();
+        bail!("Chunks {:?} of {} are missing", missing, count);
+    }
+    let data = base64::decode(parts.into_values().collect::<String>())?;
+    if Some(hex::encode(&Sha256::digest(&data)[..8]).as_str()) != digest {
+        bail!("Reassembled message doesn't match its digest");
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::protocol::{ KeySignBroadcastRound, KeySignEdDSAAllRounds };
+    use crate::session_manager;
+    use futures::future::join_all;
+
+    #[test]
+    fn parties_exchange_rounds_through_files_and_chunks() {
+        let dir = env::temp_dir().join(format!("airgap-{}", uuid::Uuid::new_v4()));
+        let transport = FileTransport { inbox: dir.clone(), outbox: dir.clone() };
+        let parties = vec![1, 2, 3];
+        let messengers: Vec<_> = parties
+            .iter()
+            .map(|party| {
+                let topic = Topic::KeySignEdDSA;
+                let messenger = FileMessenger::<KeySignEdDSAAllRounds>::new(
+                    transport.clone(),
+                    topic,
+                    "session-1",
+                    *party,
+                    parties.clone()
+                );
+                messenger.unwrap()
+            })
+            .collect();
+        let round = KeySignBroadcastRound::LocalSig;
+        let received = session_manager::runtime().block_on(
+            join_all(
+                messengers
+                    .iter()
+                    .map(|messenger| {
+                        messenger.broadcast_and_collect_messages(&round, messenger.party_index())
+                    })
+            )
+        );
+        for messages in received {
+            assert_eq!(messages.unwrap(), parties);
+        }
+
+        let file = dir.join("session-1.KeySignEdDSA.LocalSig.2.all.json");
+        let data = fs::read(&file).unwrap();
+        let mut chunks = to_chunks(&data, 16);
+        chunks.reverse();
+        assert_eq!(from_chunks(chunks.iter().map(String::as_str)).unwrap(), data);
+        assert!(from_chunks(chunks[1..].iter().map(String::as_str)).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
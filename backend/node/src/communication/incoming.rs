@@ -0,0 +1,41 @@
+use crate::communication::blocking;
+use anyhow::{ anyhow, Result };
+
+/// A message received on one of the node's subjects. Handlers take it instead of the client's own
+/// message type, so they don't depend on the client the message arrived through.
+#[derive(Clone, Debug)]
+pub struct IncomingMessage {
+    pub subject: String,
+    pub reply: Option<String>,
+    pub data: Vec<u8>,
+}
+
+impl IncomingMessage {
+    /// Publishes the response on the reply subject, if the sender is waiting for one
+    pub fn respond(&self, nc: &blocking::Connection, response: impl AsRef<[u8]>) -> Result<()> {
+        match &self.reply {
+            Some(reply) => nc.publish(reply, response).map_err(|err| anyhow!(err)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<async_nats::Message> for IncomingMessage {
+    fn from(message: async_nats::Message) -> Self {
+        Self {
+            subject: message.subject,
+            reply: message.reply,
+            data: message.payload.to_vec(),
+        }
+    }
+}
+
+impl From<blocking::Message> for IncomingMessage {
+    fn from(message: blocking::Message) -> Self {
+        Self {
+            subject: message.subject,
+            reply: message.reply,
+            data: message.data,
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use crate::communication::incoming::IncomingMessage;
+use crate::communication::permissions::SubjectPermissions;
+use crate::communication::queue_groups::{
+    dispatch_queued_message,
+    extract_session_id,
+    WorkerConfig,
+};
+use crate::config::{ Config, ConfigProvider };
+use crate::{ handle_message, route_message, App, MessageRoute };
+use anyhow::{ anyhow, Result };
+use async_nats::jetstream::{ self, consumer::{ pull, AckPolicy } };
+use async_nats::Client;
+use std::env;
+use std::fs::{ self, OpenOptions };
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::{ Duration, SystemTime };
+use tracing::{ info, warn };
+
+const DELIVERED_SESSIONS_DIR: &str = "delivered-sessions";
+/// Sessions are remembered this long, the stream should discard messages well before
+const DELIVERED_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Durable delivery of keygen and recovery requests. The operator captures the node's
+/// `network.gridlock.nodes.*.new.<node id>` subjects in a stream, the node consumes them through a
+/// durable consumer so requests published while it was offline run once it reconnects. The same
+/// messages still arrive on the core subscription, where they are skipped.
+#[derive(Clone, Debug)]
+pub struct JetStreamConfig {
+    pub stream: String,
+    /// Durable consumer name, instances of a queue group share it
+    pub consumer: Option<String>,
+}
+
+impl JetStreamConfig {
+    /// Returns `None` unless `NATS_JETSTREAM_STREAM` is set
+    pub fn from_env() -> Option<Self> {
+        let stream = env
+            ::var("NATS_JETSTREAM_STREAM")
+            .ok()
+            .filter(|stream| !stream.is_empty())?;
+        let consumer = env
+            ::var("NATS_JETSTREAM_CONSUMER")
+            .ok()
+            .filter(|consumer| !consumer.is_empty());
+        Some(JetStreamConfig { stream, consumer })
+    }
+
+    fn consumer_name(&self, node_id: &str) -> String {
+        self.consumer.clone().unwrap_or_else(|| format!("guardian-{}", node_id))
+    }
+
+    /// Binds the durable consumer of this node, creating it on first use
+    pub async fn consume(&self, client: &Client, node_id: &str) -> Result<pull::Stream> {
+        if let Err(err) = DeliveredSessions::prune() {
+            warn!("Failed to prune delivered sessions: {}", err);
+        }
+        let stream = jetstream
+            ::new(client.clone())
+            .get_stream(&self.stream).await
+            .map_err(|err| {
+                anyhow!("JetStream stream \"{}\" is unavailable: {}", self.stream, err)
+            })?;
+        let name = self.consumer_name(node_id);
+        let consumer: pull::Consumer = stream
+            .get_or_create_consumer(&name, pull::Config {
+                durable_name: Some(name.clone()),
+                filter_subject: WorkerConfig::shared_subject(node_id),
+                ack_policy: AckPolicy::Explicit,
+                ..Default::default()
+            }).await
+            .map_err(|err| anyhow!("Failed to bind JetStream consumer \"{}\": {}", name, err))?;
+        info!("Consuming durable requests from JetStream stream \"{}\"", self.stream);
+        consumer.messages().await.map_err(|err| anyhow!(err))
+    }
+
+    /// JetStream API subjects the node's NATS user needs for its consumer
+    pub fn grant(&self, permissions: &mut SubjectPermissions, node_id: &str) {
+        let consumer = self.consumer_name(node_id);
+        let api = "$JS.API";
+        permissions.publish.insert(format!("{}.STREAM.INFO.{}", api, self.stream));
+        permissions.publish.insert(format!("{}.CONSUMER.INFO.{}.{}", api, self.stream, consumer));
+        permissions.publish.insert(format!("{}.CONSUMER.CREATE.{}.>", api, self.stream));
+        permissions.publish.insert(
+            format!("{}.CONSUMER.DURABLE.CREATE.{}.{}", api, self.stream, consumer)
+        );
+        permissions.publish.insert(
+            format!("{}.CONSUMER.MSG.NEXT.{}.{}", api, self.stream, consumer)
+        );
+        permissions.publish.insert(format!("$JS.ACK.{}.{}.>", self.stream, consumer));
+    }
+}
+
+/// Whether requests of the route are taken from the stream when JetStream is configured. Signing
+/// requests and commands are only useful while the requester waits, they stay on core NATS.
+pub fn is_durable(route: Option<MessageRoute>) -> bool {
+    matches!(
+        route,
+        Some(
+            | MessageRoute::KeyGenECDSA
+            | MessageRoute::KeyGenEdDSA
+            | MessageRoute::KeyGenFrost
+            | MessageRoute::KeyGenSr25519
+            | MessageRoute::KeyGenBLS
+            | MessageRoute::KeyShareRecovery
+        )
+    )
+}
+
+/// Starts the session of a request taken from the stream unless it was started before, then
+/// acknowledges it. A redelivered request, e.g. after a restart before the acknowledgement, does
+/// not run its session again.
+pub async fn handle_durable_message(
+    app: &App,
+    worker: Option<&WorkerConfig>,
+    message: jetstream::Message
+) -> Result<()> {
+    // The reply subject of a stream message is its acknowledgement, the requester is long gone
+    let incoming = IncomingMessage {
+        subject: message.subject.clone(),
+        reply: None,
+        data: message.payload.to_vec(),
+    };
+    if is_durable(route_message(&incoming.subject)) {
+        match extract_session_id(&incoming.data) {
+            Some(session_id) if !DeliveredSessions::first_delivery(&session_id)? => {
+                info!("Session {} was started before, skipping its redelivery", session_id);
+            }
+            _ =>
+                match worker {
+                    Some(worker) => dispatch_queued_message(app, worker, incoming),
+                    None => handle_message(app, incoming),
+                }
+        }
+    }
+    message.ack().await.map_err(|err| anyhow!(err))
+}
+
+/// Ids of the sessions started from the stream, kept in the storage directory so instances
+/// sharing it don't start a session twice either
+pub struct DeliveredSessions;
+
+impl DeliveredSessions {
+    fn directory() -> PathBuf {
+        let mut path = Config::get_gridlock_directory();
+        path.push(DELIVERED_SESSIONS_DIR);
+        path
+    }
+
+    fn path(session_id: &str) -> PathBuf {
+        let mut path = Self::directory();
+        path.push(session_id.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_"));
+        path
+    }
+
+    /// Records the session, returns false when it was recorded already
+    pub fn first_delivery(session_id: &str) -> Result<bool> {
+        fs::create_dir_all(Self::directory())?;
+        match OpenOptions::new().write(true).create_new(true).open(Self::path(session_id)) {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Forgets sessions recorded longer than `DELIVERED_TTL` ago
+    pub fn prune() -> Result<()> {
+        let entries = match fs::read_dir(Self::directory()) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(());
+            }
+            Err(err) => {
+                return Err(err.into());
+            }
+        };
+        for entry in entries.flatten() {
+            let expired = entry
+                .metadata()
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .map(|age| age > DELIVERED_TTL)
+                .unwrap_or(false);
+            if expired {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_keygen_and_recovery_requests_are_durable() {
+        let route = |subject: &str| route_message(subject);
+        assert!(is_durable(route("network.gridlock.nodes.KeyGenFrost.new.node-1")));
+        assert!(is_durable(route("network.gridlock.nodes.KeyShareRecovery.new.node-1")));
+        assert!(!is_durable(route("network.gridlock.nodes.KeySignFrost.new.node-1")));
+        assert!(!is_durable(route("network.gridlock.nodes.Message.new.node-1")));
+        assert!(!is_durable(None));
+    }
+}
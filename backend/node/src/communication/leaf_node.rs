@@ -0,0 +1,273 @@
+use crate::communication::nats_auth::NatsAuth;
+use crate::config::{ Config, ConfigProvider };
+use anyhow::{ anyhow, bail, Result };
+use rand::RngCore;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{ Child, Command, Stdio };
+use std::sync::{ Mutex, OnceLock };
+use std::time::{ Duration, Instant };
+use std::env;
+use tracing::{ info, warn };
+use zeroize::Zeroizing;
+
+const DEFAULT_SERVER_BIN: &str = "nats-server";
+const DEFAULT_LOCAL_PORT: u16 = 14222;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const CONFIG_FILE_NAME: &str = "leafnode.conf";
+/// Variables the secrets reach the server through, they are not written to the config file
+const USER_VAR: &str = "GRIDLOCK_LEAF_USER";
+const PASSWORD_VAR: &str = "GRIDLOCK_LEAF_PASSWORD";
+const REMOTE_NKEY_VAR: &str = "GRIDLOCK_LEAF_REMOTE_NKEY";
+const LOCAL_USER: &str = "guardian";
+
+static LEAF_PROCESS: Mutex<Option<Child>> = Mutex::new(None);
+/// Password of the loopback listener, drawn once so a restarted leaf node accepts the client's
+/// reconnects
+static LOCAL_PASSWORD: OnceLock<Zeroizing<String>> = OnceLock::new();
+
+/// How the leaf node authenticates to the hub
+#[derive(Debug, PartialEq)]
+enum RemoteAuth {
+    None,
+    Credentials(PathBuf),
+    /// The seed is handed to the server in `REMOTE_NKEY_VAR`
+    NKey,
+}
+
+/// Settings for running a local NATS leaf node which dials out to the hub.
+///
+/// Guardians behind networks that only allow outbound traffic (typically 443) can't reach the
+/// hub's client port directly. When `NATS_LEAF_REMOTE` is set (e.g. `wss://hub.example.com:443`)
+/// the node spawns an external `nats-server` binary as a child process, `NATS_LEAF_SERVER_BIN` or
+/// the one on `PATH`, which has to be installed beside the node (the Docker image ships it). The
+/// server listens on loopback, configured as a leaf node of that remote, and the node connects to
+/// it instead of `NATS_NETWORK`. All traffic to the hub then travels over the single outbound
+/// websocket connection opened by the leaf node.
+///
+/// The leaf node authenticates to the hub with the node's credentials file or NKey seed, or with
+/// `NATS_LEAF_CREDENTIALS` if set. The node itself connects to the loopback listener with a
+/// password drawn when it starts.
+pub struct LeafNodeConfig {
+    pub remote_url: String,
+    pub credentials_file: Option<PathBuf>,
+    pub server_bin: String,
+    pub local_port: u16,
+}
+
+impl LeafNodeConfig {
+    /// Returns `None` if leaf node mode is not configured
+    pub fn from_env() -> Result<Option<Self>> {
+        let remote_url = match env::var("NATS_LEAF_REMOTE") {
+            Ok(url) if !url.is_empty() => url,
+            _ => {
+                return Ok(None);
+            }
+        };
+
+        if !remote_url.starts_with("wss://") && !remote_url.starts_with("ws://") {
+            warn!("NATS_LEAF_REMOTE does not use a websocket scheme: {}", remote_url);
+        }
+
+        let credentials_file = env
+            ::var("NATS_LEAF_CREDENTIALS")
+            .ok()
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+        let server_bin = env
+            ::var("NATS_LEAF_SERVER_BIN")
+            .unwrap_or_else(|_| DEFAULT_SERVER_BIN.to_string());
+        let local_port = match env::var("NATS_LEAF_PORT") {
+            Ok(port) =>
+                port
+                    .parse::<u16>()
+                    .map_err(|_| anyhow!("NATS_LEAF_PORT is not a valid port: {}", port))?,
+            Err(_) => DEFAULT_LOCAL_PORT,
+        };
+
+        Ok(
+            Some(LeafNodeConfig {
+                remote_url,
+                credentials_file,
+                server_bin,
+                local_port,
+            })
+        )
+    }
+
+    pub fn local_address(&self) -> String {
+        format!("nats://127.0.0.1:{}", self.local_port)
+    }
+
+    /// Config of the leaf node, which reads its secrets from the environment
+    fn render_config(&self, remote_auth: &RemoteAuth) -> String {
+        let credentials = match remote_auth {
+            RemoteAuth::Credentials(path) => {
+                format!("\n      credentials: {:?}", path.to_string_lossy())
+            }
+            RemoteAuth::NKey => format!("\n      nkey: ${}", REMOTE_NKEY_VAR),
+            RemoteAuth::None => String::new(),
+        };
+        format!(
+            "listen: \"127.0.0.1:{port}\"\n\
+             authorization {{\n  user: ${user_var}\n  password: ${password_var}\n}}\n\
+             leafnodes {{\n  remotes = [\n    {{\n      urls: [{url:?}]{credentials}\n    }}\n  ]\n}}\n",
+            port = self.local_port,
+            user_var = USER_VAR,
+            password_var = PASSWORD_VAR,
+            url = self.remote_url,
+            credentials = credentials
+        )
+    }
+
+    /// The leaf node's own credentials take precedence over those of the node. A user and
+    /// password only authenticate the node to the loopback listener.
+    fn remote_auth(&self, hub_auth: &NatsAuth) -> RemoteAuth {
+        match (&self.credentials_file, hub_auth) {
+            (Some(path), _) => RemoteAuth::Credentials(path.clone()),
+            (None, NatsAuth::Credentials { path }) => RemoteAuth::Credentials(path.clone()),
+            (None, NatsAuth::NKey { .. }) => RemoteAuth::NKey,
+            (None, NatsAuth::UserPassword { .. }) => RemoteAuth::None,
+        }
+    }
+
+    /// Starts the leaf node if it isn't already running and waits for it to accept connections
+    /// on the loopback port. Returns the credentials the node connects to it with.
+    pub fn ensure_running(&self, hub_auth: &NatsAuth) -> Result<NatsAuth> {
+        let password = LOCAL_PASSWORD.get_or_init(|| {
+            let mut bytes = Zeroizing::new([0u8; 32]);
+            rand::thread_rng().fill_bytes(&mut *bytes);
+            Zeroizing::new(hex::encode(&*bytes))
+        });
+        let local_auth = NatsAuth::UserPassword {
+            user: LOCAL_USER.to_string(),
+            password: password.clone(),
+        };
+        let mut process = LEAF_PROCESS.lock().map_err(|_| anyhow!("Leaf node lock poisoned"))?;
+
+        if let Some(child) = process.as_mut() {
+            match child.try_wait()? {
+                None => {
+                    return Ok(local_auth);
+                }
+                Some(status) => {
+                    warn!("NATS leaf node exited with {}, restarting", status);
+                    *process = None;
+                }
+            }
+        }
+
+        // Whatever listens on the port would otherwise be taken for the leaf node
+        if self.is_listening() {
+            bail!("Port {} of the NATS leaf node is used by another process", self.local_port);
+        }
+
+        let remote_auth = self.remote_auth(hub_auth);
+        let mut config_path = Config::get_gridlock_directory();
+        config_path.push(CONFIG_FILE_NAME);
+        std::fs::write(&config_path, self.render_config(&remote_auth))?;
+
+        info!("Starting NATS leaf node {} towards {}", self.server_bin, &self.remote_url);
+        let mut command = Command::new(&self.server_bin);
+        command
+            .arg("-c")
+            .arg(&config_path)
+            .env(USER_VAR, LOCAL_USER)
+            .env(PASSWORD_VAR, password.as_str())
+            .stdin(Stdio::null());
+        if let NatsAuth::NKey { seed } = hub_auth {
+            if remote_auth == RemoteAuth::NKey {
+                command.env(REMOTE_NKEY_VAR, seed.as_str());
+            }
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|err|
+                anyhow!("Failed to start the NATS leaf node \"{}\": {}", self.server_bin, err)
+            )?;
+
+        if let Err(err) = self.wait_until_listening(&mut child) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(err);
+        }
+        *process = Some(child);
+        Ok(local_auth)
+    }
+
+    fn is_listening(&self) -> bool {
+        TcpStream::connect(("127.0.0.1", self.local_port)).is_ok()
+    }
+
+    /// Waits until the spawned server accepts connections, fails as soon as it exits, e.g. when
+    /// it could not bind the port
+    fn wait_until_listening(&self, child: &mut Child) -> Result<()> {
+        let started = Instant::now();
+        while started.elapsed() < STARTUP_TIMEOUT {
+            if let Some(status) = child.try_wait()? {
+                bail!("NATS leaf node exited with {} before it started listening", status);
+            }
+            if self.is_listening() {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        bail!("NATS leaf node did not start listening on port {}", self.local_port)
+    }
+}
+
+/// Stops the leaf node, if one was started
+pub fn shutdown_leaf_node() {
+    if let Ok(mut process) = LEAF_PROCESS.lock() {
+        if let Some(mut child) = process.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_leaf_remote_with_credentials() {
+        let config = LeafNodeConfig {
+            remote_url: String::from("wss://hub.gridlock.network:443"),
+            credentials_file: Some(PathBuf::from("/etc/gridlock/leaf.creds")),
+            server_bin: String::from(DEFAULT_SERVER_BIN),
+            local_port: DEFAULT_LOCAL_PORT,
+        };
+        let hub_auth = NatsAuth::NKey { seed: Zeroizing::new("SU...".to_string()) };
+        let rendered = config.render_config(&config.remote_auth(&hub_auth));
+        assert!(rendered.contains("listen: \"127.0.0.1:14222\""));
+        assert!(rendered.contains("password: $GRIDLOCK_LEAF_PASSWORD"));
+        assert!(rendered.contains("urls: [\"wss://hub.gridlock.network:443\"]"));
+        assert!(rendered.contains("credentials: \"/etc/gridlock/leaf.creds\""));
+        assert_eq!(config.local_address(), "nats://127.0.0.1:14222");
+    }
+
+    #[test]
+    fn authenticates_to_the_hub_with_the_node_credentials() {
+        let config = LeafNodeConfig {
+            remote_url: String::from("wss://hub.gridlock.network:443"),
+            credentials_file: None,
+            server_bin: String::from(DEFAULT_SERVER_BIN),
+            local_port: DEFAULT_LOCAL_PORT,
+        };
+        let creds = NatsAuth::Credentials { path: PathBuf::from("/app/guardian.creds") };
+        let rendered = config.render_config(&config.remote_auth(&creds));
+        assert!(rendered.contains("credentials: \"/app/guardian.creds\""));
+
+        let nkey = NatsAuth::NKey { seed: Zeroizing::new("SU...".to_string()) };
+        let rendered = config.render_config(&config.remote_auth(&nkey));
+        assert!(rendered.contains("nkey: $GRIDLOCK_LEAF_REMOTE_NKEY"));
+        assert!(!rendered.contains("SU..."));
+
+        let user_password = NatsAuth::UserPassword {
+            user: "guardian".to_string(),
+            password: Zeroizing::new("secret".to_string()),
+        };
+        assert_eq!(config.remote_auth(&user_password), RemoteAuth::None);
+    }
+}
@@ -0,0 +1,254 @@
+use crate::communication::envelope;
+use crate::communication::nats::{ BaseMessenger, JoinResponse, PeerMessenger };
+use crate::communication::protocol::AllRounds;
+use crate::encryption::ENVELOPE_V2_X25519;
+use anyhow::{ anyhow, bail, Result };
+use serde::{ de::DeserializeOwned, Serialize };
+use std::collections::{ BTreeMap, BTreeSet };
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+use strum::IntoEnumIterator;
+use tokio::sync::mpsc::{ unbounded_channel, UnboundedReceiver, UnboundedSender };
+use tokio::sync::Mutex;
+
+/// A party that stops sending fails the round instead of leaving the others waiting
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Serialized message and the index of the party that sent it
+type Envelope = (usize, Vec<u8>);
+
+/// Messenger of one simulated party, exchanging round messages with the other parties of its
+/// network over in-memory channels. Lets the protocol rounds of keygen, signing and recovery run
+/// among several parties inside one process without NATS.
+pub struct LoopbackMessenger<R> {
+    party_index: usize,
+    all_party_indices: Vec<usize>,
+    /// Mailbox of every party for every round, by round name and party index
+    outboxes: Arc<BTreeMap<(String, usize), UnboundedSender<Envelope>>>,
+    inboxes: BTreeMap<String, Mutex<UnboundedReceiver<Envelope>>>,
+    rounds: PhantomData<fn() -> R>,
+}
+
+impl<R> LoopbackMessenger<R> where R: AllRounds {
+    /// Messengers of the parties with the given indices, connected to each other
+    pub fn network(party_indices: &[usize]) -> Result<Vec<Self>> {
+        let mut all_party_indices = party_indices.to_vec();
+        all_party_indices.sort();
+        all_party_indices.dedup();
+        if all_party_indices.len() != party_indices.len() {
+            bail!("Party indices {:?} are not unique", party_indices);
+        }
+        let round_names: Vec<String> = R::BroadcastRound::iter()
+            .map(|round| round.to_string())
+            .chain(R::P2PRound::iter().map(|round| round.to_string()))
+            .collect();
+
+        let mut outboxes = BTreeMap::new();
+        let mut inboxes: BTreeMap<usize, BTreeMap<String, _>> = BTreeMap::new();
+        for &party_index in &all_party_indices {
+            for round in &round_names {
+                let (sender, receiver) = unbounded_channel();
+                outboxes.insert((round.clone(), party_index), sender);
+                inboxes.entry(party_index).or_default().insert(round.clone(), Mutex::new(receiver));
+            }
+        }
+        let outboxes = Arc::new(outboxes);
+        Ok(
+            all_party_indices
+                .iter()
+                .map(|party_index| Self {
+                    party_index: *party_index,
+                    all_party_indices: all_party_indices.clone(),
+                    outboxes: outboxes.clone(),
+                    inboxes: inboxes.remove(party_index).unwrap_or_default(),
+                    rounds: PhantomData,
+                })
+                .collect()
+        )
+    }
+
+    /// Messenger of a party without peers, for roles that don't exchange round messages
+    pub fn standalone(party_index: usize) -> Result<Self> {
+        Self::network(&[party_index])?
+            .pop()
+            .ok_or_else(|| anyhow!("No messenger for party {}", party_index))
+    }
+
+    pub fn party_index(&self) -> usize {
+        self.party_index
+    }
+
+    fn other_party_indices(&self) -> Vec<usize> {
+        self.all_party_indices
+            .iter()
+            .filter(|&&index| index != self.party_index)
+            .copied()
+            .collect()
+    }
+
+    fn send<T: Serialize>(&self, round: &str, recipient: usize, message: &T) -> Result<()> {
+        let outbox = self.outboxes
+            .get(&(round.to_string(), recipient))
+            .ok_or_else(|| anyhow!("Party {} is not part of round {}", recipient, round))?;
+        outbox
+            .send((self.party_index, serde_json::to_vec(message)?))
+            .map_err(|_| anyhow!("Party {} has left the session", recipient))
+    }
+
+    async fn receive(&self, round: &str) -> Result<Envelope> {
+        let inbox = self.inboxes
+            .get(round)
+            .ok_or_else(|| anyhow!("Unknown round {}", round))?;
+        let mut inbox = inbox.lock().await;
+        match tokio::time::timeout(MESSAGE_TIMEOUT, inbox.recv()).await {
+            Ok(Some(envelope)) => Ok(envelope),
+            Ok(None) => bail!("Every party has left round {}", round),
+            Err(_) => bail!("Timed out waiting for round {}", round),
+        }
+    }
+
+    /// One message from every expected sender, ordered by sender index like the NATS messenger
+    async fn receive_from<T: DeserializeOwned>(
+        &self,
+        round: &str,
+        expected_senders: BTreeSet<usize>
+    ) -> Result<Vec<T>> {
+        let mut received = BTreeMap::new();
+        while received.len() < expected_senders.len() {
+            let (sender, data) = self.receive(round).await?;
+            if !expected_senders.contains(&sender) {
+                bail!("Received a {} message from unexpected sender #{}", round, sender);
+            }
+            match received.get(&sender) {
+                Some(previous) if previous != &data => {
+                    bail!("Received conflicting {} messages from sender #{}", round, sender);
+                }
+                _ => {
+                    received.insert(sender, data);
+                }
+            }
+        }
+        received
+            .values()
+            .map(|data| Ok(serde_json::from_slice(data)?))
+            .collect()
+    }
+}
+
+impl<R> PeerMessenger<R> for LoopbackMessenger<R> where R: AllRounds {
+    async fn broadcast_message<T: Serialize + DeserializeOwned + Clone>(
+        &self,
+        round: &R::BroadcastRound,
+        message: T
+    ) -> Result<()> {
+        let round = round.to_string();
+        for &party_index in &self.all_party_indices {
+            self.send(&round, party_index, &message)?;
+        }
+        Ok(())
+    }
+
+    async fn collect_messages<T: Serialize + DeserializeOwned + Clone>(
+        &self,
+        round: &R::BroadcastRound
+    ) -> Result<Vec<T>> {
+        let senders = self.all_party_indices.iter().copied().collect();
+        self.receive_from(&round.to_string(), senders).await
+    }
+
+    async fn collect_message<T: Serialize + DeserializeOwned + Clone>(
+        &self,
+        round: &R::BroadcastRound
+    ) -> Result<T> {
+        let (_, data) = self.receive(&round.to_string()).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    async fn broadcast_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
+        &self,
+        round: &R::BroadcastRound,
+        message: T
+    ) -> Result<Vec<T>> {
+        self.broadcast_message(round, message).await?;
+        self.collect_messages(round).await
+    }
+
+    async fn send_p2p_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
+        &self,
+        round: &R::P2PRound,
+        messages: Vec<T>
+    ) -> Result<Vec<T>> {
+        let round = round.to_string();
+        let other_party_indices = self.other_party_indices();
+        if messages.len() != other_party_indices.len() {
+            bail!(
+                "Incorrect number of outgoing messages, expected {}, but found {}",
+                other_party_indices.len(),
+                messages.len()
+            );
+        }
+        for (party_index, message) in other_party_indices.iter().zip(&messages) {
+            self.send(&round, *party_index, message)?;
+        }
+        self.receive_from(&round, other_party_indices.into_iter().collect()).await
+    }
+}
+
+impl<R> BaseMessenger<R> for LoopbackMessenger<R> where R: AllRounds {
+    /// Every party of the network has joined from the start
+    async fn wait_for_confirmation(&self, _time: Duration) -> Result<JoinResponse> {
+        Ok(JoinResponse {
+            party_count: self.all_party_indices.len(),
+            all_party_indices: self.all_party_indices.clone(),
+            networking_public_keys: BTreeMap::new(),
+            envelope_version: envelope::ENVELOPE_VERSION,
+            encryption_versions: self.all_party_indices
+                .iter()
+                .map(|&party_index| (party_index, ENVELOPE_V2_X25519))
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::protocol::KeyGenAllRounds;
+    use crate::entropy::CeremonyEntropy;
+    use crate::keygen::eddsa::client::KeyGenClient;
+    use crate::keygen::ShareParams;
+    use crate::session_manager;
+    use futures::future::join_all;
+
+    #[test]
+    fn runs_eddsa_keygen_among_simulated_parties() {
+        let parties = vec![1, 2, 3];
+        let messengers = LoopbackMessenger::<KeyGenAllRounds>::network(&parties).unwrap();
+        let clients: Vec<_> = messengers
+            .into_iter()
+            .map(|messenger| KeyGenClient {
+                share_params: ShareParams {
+                    party_count: parties.len(),
+                    party_index: messenger.party_index(),
+                    threshold: 1,
+                },
+                peer_messenger: messenger,
+                all_party_indices: parties.clone(),
+            })
+            .collect();
+
+        let keyshares = session_manager::runtime().block_on(
+            join_all(
+                clients.iter().map(|client| async move {
+                    let party_index = client.share_params.party_index;
+                    let entropy = CeremonyEntropy::local("loopback-key", party_index)?;
+                    client.create_shared_key(&entropy).await
+                })
+            )
+        );
+        let keyshares = keyshares.into_iter().collect::<Result<Vec<_>>>().unwrap();
+        assert!(keyshares.iter().all(|keyshare| keyshare.y_sum == keyshares[0].y_sum));
+        assert_ne!(keyshares[0].x_i, keyshares[1].x_i);
+    }
+}
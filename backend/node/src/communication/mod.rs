@@ -1,5 +1,17 @@
+pub mod blocking;
 pub mod ecdsa;
+pub mod envelope;
+pub mod file_transport;
+pub mod incoming;
+pub mod jetstream;
+pub mod leaf_node;
+pub mod loopback;
 pub mod nats;
+pub mod nats_auth;
 pub mod nats_session;
+pub mod permissions;
 pub mod protocol;
+pub mod queue_groups;
 pub mod round_subscriptions;
+pub mod subscription_registry;
+pub mod transport;
@@ -1,69 +1,91 @@
-use crate::communication::ecdsa::{
-    collect_message,
-    collect_messages_ordered,
-    collect_messages_p2p,
-    HasSenderId,
-};
+use crate::communication::ecdsa::{ receive_message, receive_messages_from, HasSenderId };
+use crate::communication::envelope::{ self, legacy_version };
 use crate::communication::protocol::{ AllRounds, Topic };
-use crate::communication::round_subscriptions::RoundSubscriber;
+use crate::communication::round_subscriptions::{ RoundSubscriber, RoundSubscription };
+use crate::communication::transport::{ RoundTransport, SealedRoundMessage };
+use crate::encryption::{ legacy_encryption_version, ENVELOPE_V2_X25519 };
+use crate::node::NodeIdentity;
+use crate::session_manager;
 use anyhow::{ bail, Result };
-use nats::Connection;
+use async_nats::Client;
 use serde::{ de::DeserializeOwned, Deserialize, Serialize };
 use shared::key_info::NodeId;
+use std::collections::{ BTreeMap, BTreeSet };
 use std::marker::PhantomData;
+use std::sync::Mutex;
 
+/// Exchanges the round messages of one session. Sessions run as tasks, a party waiting for its
+/// peers only parks its task. The futures are not required to be `Send` as sessions are spawned
+/// with their concrete messenger.
+#[allow(async_fn_in_trait)]
 pub trait PeerMessenger<R> where R: AllRounds {
-    fn broadcast_message<T: Serialize + DeserializeOwned + Clone>(
+    async fn broadcast_message<T: Serialize + DeserializeOwned + Clone>(
         &self,
         round: &R::BroadcastRound,
         message: T
     ) -> Result<()>;
-    fn collect_messages<T: Serialize + DeserializeOwned + Clone>(
+    async fn collect_messages<T: Serialize + DeserializeOwned + Clone>(
         &self,
         round: &R::BroadcastRound
     ) -> Result<Vec<T>>;
-    fn collect_message<T: Serialize + DeserializeOwned + Clone>(
+    async fn collect_message<T: Serialize + DeserializeOwned + Clone>(
         &self,
         round: &R::BroadcastRound
     ) -> Result<T>;
-    fn broadcast_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
+    async fn broadcast_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
         &self,
         round: &R::BroadcastRound,
         message: T
     ) -> Result<Vec<T>>;
-    fn send_p2p_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
+    async fn send_p2p_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
         &self,
         round: &R::P2PRound,
         messages: Vec<T>
     ) -> Result<Vec<T>>;
 }
 
+#[allow(async_fn_in_trait)]
 pub trait BaseMessenger<R> where R: AllRounds {
-    fn wait_for_confirmation(&self, time: std::time::Duration) -> Result<JoinResponse>;
+    async fn wait_for_confirmation(&self, time: std::time::Duration) -> Result<JoinResponse>;
 }
 
 pub struct NatsBaseMessenger<R> {
     pub session: NatsBaseSession,
-    nc: Connection,
+    nc: Client,
     subs: RoundSubscriber,
-    rounds: PhantomData<*const R>,
+    /// Networking public keys of the parties from the join response
+    peer_keys: Mutex<BTreeMap<usize, String>>,
+    /// Envelope version all parties speak, from the join response
+    envelope_version: Mutex<u32>,
+    rounds: PhantomData<fn() -> R>,
 }
 
 impl<R> NatsBaseMessenger<R> where R: AllRounds {
-    pub fn new(topic: Topic, nc: Connection, session: NatsBaseSession) -> Result<Self> {
+    pub async fn new(topic: Topic, nc: Client, session: NatsBaseSession) -> Result<Self> {
         let mut subs = RoundSubscriber::new(topic, &nc, &session);
-        subs.subscribe::<R>()?;
+        subs.subscribe::<R>().await?;
         Ok(Self {
             nc,
             subs,
             session,
+            peer_keys: Mutex::new(BTreeMap::new()),
+            envelope_version: Mutex::new(envelope::LEGACY_VERSION),
             rounds: PhantomData,
         })
     }
 }
 
+impl<R> NatsBaseMessenger<R> {
+    /// Takes the parties' keys and envelope version from a join response, also for messengers of
+    /// the session that didn't send the join message themselves
+    pub fn accept_join_response(&self, response: &JoinResponse) {
+        *self.peer_keys.lock().unwrap() = response.networking_public_keys.clone();
+        *self.envelope_version.lock().unwrap() = response.envelope_version;
+    }
+}
+
 impl<R> BaseMessenger<R> for NatsBaseMessenger<R> where R: AllRounds {
-    fn wait_for_confirmation(&self, time: std::time::Duration) -> Result<JoinResponse> {
+    async fn wait_for_confirmation(&self, time: std::time::Duration) -> Result<JoinResponse> {
         let join_subject = self.subs.format_round_subject("Join");
 
         let join_message = serde_json::to_string(
@@ -76,21 +98,57 @@ impl<R> BaseMessenger<R> for NatsBaseMessenger<R> where R: AllRounds {
             )
         )?;
 
-        let resp = match self.nc.request_timeout(&join_subject, &join_message, time) {
-            Ok(resp) => resp,
-            Err(_) => bail!("No response from the 'Join' session"),
+        let request = self.nc.request(join_subject, join_message.into());
+        let resp = match tokio::time::timeout(time, request).await {
+            Ok(Ok(resp)) => resp,
+            _ => bail!("No response from the 'Join' session"),
         };
 
-        let confirmation = serde_json::from_slice::<JoinResponse>(&resp.data)?;
+        let confirmation = serde_json::from_slice::<JoinResponse>(&resp.payload)?;
+        self.accept_join_response(&confirmation);
         Ok(confirmation)
     }
 }
 
+/// Round messages are encrypted pairwise between the parties when the join response carries
+/// their networking keys, the broker then only sees ciphertexts. Observer copies are unchanged.
 pub struct NatsPeerMessenger<R> {
-    nc: Connection,
+    nc: Client,
     subs: RoundSubscriber,
     session: NatsPeerSession,
-    rounds: PhantomData<*const R>,
+    observers: Option<ObserverMirror>,
+    transport: Option<RoundTransport>,
+    envelope_version: u32,
+    rounds: PhantomData<fn() -> R>,
+}
+
+/// Observers the owner consented to. They get a copy of every message this party broadcasts and
+/// the transcript of all broadcast rounds, p2p rounds are never mirrored.
+struct ObserverMirror {
+    observer_ids: Vec<String>,
+    transcript: Mutex<Vec<TranscriptRound>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TranscriptRound {
+    pub round: String,
+    /// Broadcast messages of the round ordered by sender
+    pub messages: Vec<serde_json::Value>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ObserverTranscript {
+    pub session_id: String,
+    pub party_index: usize,
+    pub rounds: Vec<TranscriptRound>,
+}
+
+/// Maps a session subject below `network.gridlock.nodes.` to the observer's copy of it
+pub fn observer_subject(observer_id: &str, session_subject: &str) -> String {
+    let suffix = session_subject
+        .strip_prefix("network.gridlock.nodes.")
+        .unwrap_or(session_subject);
+    format!("network.gridlock.observers.{}.{}", observer_id, suffix)
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -131,10 +189,32 @@ impl<R> NatsPeerMessenger<R> where R: AllRounds {
         party_count: usize,
         party_indices: Vec<usize>
     ) -> Result<Self> {
+        session_manager::check_party_count(party_count)?;
+        if party_indices.len() > party_count {
+            bail!("Join response lists {} parties for {}", party_indices.len(), party_count);
+        }
         let party_index = base_messenger.session.party_index;
         let mut other_party_indices = party_indices.clone();
         other_party_indices.retain(|x| *x != party_index);
 
+        let peer_keys = base_messenger.peer_keys.into_inner().unwrap();
+        let envelope_version = base_messenger.envelope_version.into_inner().unwrap();
+        let transport = if peer_keys.is_empty() {
+            None
+        } else {
+            if let Some(party) = party_indices.iter().find(|party| !peer_keys.contains_key(party)) {
+                bail!("Join response lacks the networking key of party {}", party);
+            }
+            Some(
+                RoundTransport::new(
+                    &base_messenger.session.session_id,
+                    party_index,
+                    &NodeIdentity::cached()?.networking_private_key,
+                    &peer_keys
+                )?
+            )
+        };
+
         let peer_session = NatsPeerSession {
             session_id: base_messenger.session.session_id,
             thread_index: base_messenger.session.thread_index,
@@ -149,13 +229,105 @@ impl<R> NatsPeerMessenger<R> where R: AllRounds {
             nc: base_messenger.nc,
             subs: base_messenger.subs,
             session: peer_session,
+            observers: None,
+            transport,
+            envelope_version,
             rounds: PhantomData,
         })
     }
+
+    pub fn with_observers(mut self, observer_ids: Vec<String>) -> Self {
+        if !observer_ids.is_empty() {
+            self.observers = Some(ObserverMirror {
+                observer_ids,
+                transcript: Mutex::new(Vec::new()),
+            });
+        }
+        self
+    }
+
+    /// Sends the broadcast rounds collected so far to every observer, call once the session ends
+    pub async fn publish_transcript(&self) -> Result<()> {
+        let observers = match &self.observers {
+            Some(observers) => observers,
+            None => {
+                return Ok(());
+            }
+        };
+        let transcript = serde_json::to_string(
+            &(ObserverTranscript {
+                session_id: self.session.session_id.clone(),
+                party_index: self.session.party_index,
+                rounds: observers.transcript.lock().unwrap().clone(),
+            })
+        )?;
+        let subject = self.subs.format_round_subject("Transcript");
+        for observer_id in &observers.observer_ids {
+            let mirror_subject = observer_subject(observer_id, &subject);
+            self.nc.publish(mirror_subject, transcript.clone().into()).await?;
+        }
+        Ok(())
+    }
+
+    /// Encodes a message of the round for the recipients, sealed to each of them when the
+    /// transport is on, in the envelope version of the session
+    fn encode<T: Serialize>(
+        &self,
+        round: &str,
+        subject: &str,
+        message: &BroadcastMessage<T>,
+        recipients: impl IntoIterator<Item = usize>
+    ) -> Result<String> {
+        match &self.transport {
+            Some(transport) => {
+                let sealed = transport.seal(subject, message, recipients)?;
+                envelope::encode(self.envelope_version, round, &sealed)
+            }
+            None => envelope::encode(self.envelope_version, round, message),
+        }
+    }
+
+    fn open<T: DeserializeOwned>(
+        transport: &RoundTransport,
+        subject: &str,
+        sealed: &SealedRoundMessage
+    ) -> Result<BroadcastMessage<T>> {
+        let message = transport.open::<BroadcastMessage<T>>(subject, sealed)?;
+        if message.sender_id != sealed.sender_id {
+            bail!("Party {} sealed a message of party {}", sealed.sender_id, message.sender_id);
+        }
+        Ok(message)
+    }
+
+    async fn receive_round<T: DeserializeOwned + Clone>(
+        &self,
+        round_subscription: &RoundSubscription,
+        senders: BTreeSet<usize>
+    ) -> Result<Vec<BroadcastMessage<T>>> {
+        let mut subscription = round_subscription.subscription.lock().await;
+        let transport = match &self.transport {
+            Some(transport) => transport,
+            None => {
+                return receive_messages_from::<BroadcastMessage<T>>(
+                    &mut subscription,
+                    senders,
+                    &round_subscription.replay
+                ).await;
+            }
+        };
+        receive_messages_from::<SealedRoundMessage>(
+            &mut subscription,
+            senders,
+            &round_subscription.replay
+        ).await?
+            .iter()
+            .map(|sealed| Self::open(transport, &round_subscription.subject, sealed))
+            .collect()
+    }
 }
 
 impl<R> PeerMessenger<R> for NatsPeerMessenger<R> where R: AllRounds {
-    fn broadcast_message<T: Serialize + DeserializeOwned + Clone>(
+    async fn broadcast_message<T: Serialize + DeserializeOwned + Clone>(
         &self,
         round: &R::BroadcastRound,
         message: T
@@ -165,23 +337,44 @@ impl<R> PeerMessenger<R> for NatsPeerMessenger<R> where R: AllRounds {
             sender_id: self.session.party_index,
             message,
         };
-        let _ = &self.nc.publish(
+        let payload = self.encode(
+            &round.to_string(),
             &round_subscription.subject,
-            serde_json::to_string(&broadcast_message)?
+            &broadcast_message,
+            self.session.all_party_indices.iter().copied()
         )?;
+        self.subs.publish(round_subscription.subject.clone(), payload).await?;
+        if let Some(observers) = &self.observers {
+            let payload = serde_json::to_string(&broadcast_message)?;
+            for observer_id in &observers.observer_ids {
+                let subject = observer_subject(observer_id, &round_subscription.subject);
+                self.nc.publish(subject, payload.clone().into()).await?;
+            }
+        }
         Ok(())
     }
 
-    fn collect_messages<T: Serialize + DeserializeOwned + Clone>(
+    async fn collect_messages<T: Serialize + DeserializeOwned + Clone>(
         &self,
         round: &R::BroadcastRound
     ) -> Result<Vec<T>> {
         let round_subscription = self.subs.get_subscription(&round.to_string())?;
         let mut messages = Vec::new();
-        let recieved_broadcasts = collect_messages_ordered::<BroadcastMessage<T>>(
-            &round_subscription.subscription,
-            self.session.party_count
-        )?;
+        let recieved_broadcasts = self.receive_round::<T>(
+            round_subscription,
+            self.session.all_party_indices.iter().copied().collect()
+        ).await?;
+
+        session_manager::report_round_completed(&round.to_string());
+        if let Some(observers) = &self.observers {
+            observers.transcript.lock().unwrap().push(TranscriptRound {
+                round: round.to_string(),
+                messages: recieved_broadcasts
+                    .iter()
+                    .map(serde_json::to_value)
+                    .collect::<Result<_, _>>()?,
+            });
+        }
 
         for broadcast in recieved_broadcasts {
             let recieved_message = broadcast.message;
@@ -190,25 +383,39 @@ impl<R> PeerMessenger<R> for NatsPeerMessenger<R> where R: AllRounds {
         Ok(messages)
     }
 
-    fn collect_message<T: Serialize + DeserializeOwned + Clone>(
+    async fn collect_message<T: Serialize + DeserializeOwned + Clone>(
         &self,
         round: &R::BroadcastRound
     ) -> Result<T> {
         let round_subscription = self.subs.get_subscription(&round.to_string())?;
-        let msg = collect_message::<BroadcastMessage<T>>(&round_subscription.subscription)?;
+        let mut subscription = round_subscription.subscription.lock().await;
+        let msg = match &self.transport {
+            Some(transport) => {
+                let sealed = receive_message::<SealedRoundMessage>(
+                    &mut subscription,
+                    &round_subscription.replay
+                ).await?;
+                Self::open::<T>(transport, &round_subscription.subject, &sealed)?
+            }
+            None =>
+                receive_message::<BroadcastMessage<T>>(
+                    &mut subscription,
+                    &round_subscription.replay
+                ).await?,
+        };
         Ok(msg.message)
     }
 
-    fn broadcast_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
+    async fn broadcast_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
         &self,
         round: &R::BroadcastRound,
         message: T
     ) -> Result<Vec<T>> {
-        self.broadcast_message(round, message)?;
-        self.collect_messages(round)
+        self.broadcast_message(round, message).await?;
+        self.collect_messages(round).await
     }
 
-    fn send_p2p_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
+    async fn send_p2p_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
         &self,
         round: &R::P2PRound,
         messages: Vec<T>
@@ -236,14 +443,20 @@ impl<R> PeerMessenger<R> for NatsPeerMessenger<R> where R: AllRounds {
             };
             let mut round_subject = round_subscription.subject.to_owned();
             round_subject.push_str(&format!(".{}", party_index));
-            let _ = &self.nc.publish(&round_subject, serde_json::to_string(&broadcast_message)?)?;
+            let payload = self.encode(
+                &round.to_string(),
+                &round_subscription.subject,
+                &broadcast_message,
+                [*party_index]
+            )?;
+            self.subs.publish(round_subject, payload).await?;
         }
 
-        let recieved_broadcasts = collect_messages_p2p::<BroadcastMessage<T>>(
-            &round_subscription.subscription,
-            self.session.party_count,
-            self.session.party_index
-        )?;
+        let recieved_broadcasts = self.receive_round::<T>(
+            round_subscription,
+            self.session.other_party_indices.iter().copied().collect()
+        ).await?;
+        session_manager::report_round_completed(&round.to_string());
 
         for broadcast in recieved_broadcasts {
             let recieved_message = broadcast.message;
@@ -253,70 +466,59 @@ impl<R> PeerMessenger<R> for NatsPeerMessenger<R> where R: AllRounds {
     }
 }
 
-pub struct DummyMessenger<R> {
-    rounds: PhantomData<*const R>,
-}
-
-impl<R> DummyMessenger<R> where R: AllRounds {
-    pub fn new(_: Topic) -> Result<Self> {
-        Ok(Self {
-            rounds: PhantomData,
-        })
-    }
-}
-
-impl<R> PeerMessenger<R> for DummyMessenger<R> where R: AllRounds {
-    fn broadcast_message<T: Serialize + DeserializeOwned + Clone>(
-        &self,
-        _: &<R as AllRounds>::BroadcastRound,
-        _: T
-    ) -> Result<()> {
-        unimplemented!()
-    }
-
-    fn collect_messages<T: Serialize + DeserializeOwned + Clone>(
-        &self,
-        _: &<R as AllRounds>::BroadcastRound
-    ) -> Result<Vec<T>> {
-        unimplemented!()
-    }
-
-    fn collect_message<T: Serialize + DeserializeOwned + Clone>(
-        &self,
-        _: &<R as AllRounds>::BroadcastRound
-    ) -> Result<T> {
-        unimplemented!()
-    }
-
-    fn broadcast_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
-        &self,
-        _: &<R as AllRounds>::BroadcastRound,
-        _: T
-    ) -> Result<Vec<T>> {
-        unimplemented!()
-    }
-
-    fn send_p2p_and_collect_messages<T: Serialize + DeserializeOwned + Clone>(
-        &self,
-        _: &<R as AllRounds>::P2PRound,
-        _: Vec<T>
-    ) -> Result<Vec<T>> {
-        unimplemented!()
-    }
-}
-
 #[derive(Serialize, Deserialize)]
 pub struct JoinMessage {
     pub session_id: String,
     pub node_id: NodeId,
     pub party_index: usize,
     pub networking_public_key: String,
+    /// Highest envelope version of round messages the party speaks
+    #[serde(default = "legacy_version")]
+    pub envelope_version: u32,
+    /// Highest version of the encryption envelope the party opens, see `encryption::EnvelopeKeys`
+    #[serde(default = "legacy_encryption_version")]
+    pub encryption_version: u8,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct JoinResponse {
     pub party_count: usize,
     pub all_party_indices: Vec<usize>,
+    /// Networking public key of each party by party index, round messages are encrypted
+    /// between the parties when present
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub networking_public_keys: BTreeMap<usize, String>,
+    /// Envelope version of the session's round messages, the highest all parties speak
+    #[serde(default = "legacy_version")]
+    pub envelope_version: u32,
+    /// Encryption envelope version each party advertised by party index, parties missing here
+    /// only open the legacy version
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub encryption_versions: BTreeMap<usize, u8>,
+}
+
+impl JoinResponse {
+    /// Response sent to every party once all of them joined
+    pub fn new(joins: &[JoinMessage]) -> Self {
+        let mut all_party_indices: Vec<usize> = joins
+            .iter()
+            .map(|join| join.party_index)
+            .collect();
+        all_party_indices.sort();
+        JoinResponse {
+            party_count: all_party_indices.len(),
+            all_party_indices,
+            networking_public_keys: joins
+                .iter()
+                .map(|join| (join.party_index, join.networking_public_key.clone()))
+                .collect(),
+            envelope_version: envelope::negotiate(joins.iter().map(|join| join.envelope_version)),
+            encryption_versions: joins
+                .iter()
+                .map(|join| (join.party_index, join.encryption_version))
+                .collect(),
+        }
+    }
 }
 
 impl JoinMessage {
@@ -336,6 +538,8 @@ impl JoinMessage {
             node_id: NodeId::new(node_id),
             party_index,
             networking_public_key,
+            envelope_version: envelope::ENVELOPE_VERSION,
+            encryption_version: ENVELOPE_V2_X25519,
         }
     }
 }
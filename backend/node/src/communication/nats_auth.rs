@@ -0,0 +1,173 @@
+use crate::provisioning;
+use anyhow::{ anyhow, bail, Context, Result };
+use nkeys::KeyPair;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+/// Decentralized JWT credentials file (`.creds`) holding the user JWT and its NKey seed
+const CREDS_FILE_VAR: &str = "NATS_CREDS_FILE";
+/// NKey user seed (`SU...`), or a file holding it
+const NKEY_SEED_VAR: &str = "NATS_NKEY_SEED";
+const NKEY_SEED_FILE_VAR: &str = "NATS_NKEY_SEED_FILE";
+/// PEM bundle of the CA the server certificate is verified with instead of the system roots
+const TLS_CA_FILE_VAR: &str = "NATS_TLS_CA_FILE";
+/// PEM client certificate and key presented to servers that verify clients (mutual TLS)
+const TLS_CERT_FILE_VAR: &str = "NATS_TLS_CERT_FILE";
+const TLS_KEY_FILE_VAR: &str = "NATS_TLS_KEY_FILE";
+/// Set to true to refuse servers that don't offer TLS
+const TLS_REQUIRED_VAR: &str = "NATS_TLS_REQUIRED";
+
+/// How the node authenticates to NATS. Credentials files take precedence over NKey seeds, which
+/// take precedence over a user and password.
+pub enum NatsAuth {
+    UserPassword {
+        user: String,
+        password: Zeroizing<String>,
+    },
+    NKey {
+        seed: Zeroizing<String>,
+    },
+    Credentials {
+        path: PathBuf,
+    },
+}
+
+impl NatsAuth {
+    pub fn from_env() -> Result<Self> {
+        if let Some(path) = non_empty_var(CREDS_FILE_VAR) {
+            let path = PathBuf::from(path);
+            if !path.is_file() {
+                bail!("{} {} does not exist", CREDS_FILE_VAR, path.display());
+            }
+            return Ok(NatsAuth::Credentials { path });
+        }
+        let seed = match (non_empty_var(NKEY_SEED_VAR), non_empty_var(NKEY_SEED_FILE_VAR)) {
+            (Some(seed), _) => Some(Zeroizing::new(seed)),
+            (None, Some(path)) => {
+                let seed = fs
+                    ::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {} {}", NKEY_SEED_FILE_VAR, path))?;
+                Some(Zeroizing::new(seed.trim().to_string()))
+            }
+            (None, None) => None,
+        };
+        if let Some(seed) = seed {
+            // Fails early on a malformed seed rather than on every connection attempt
+            KeyPair::from_seed(&seed).map_err(|err| anyhow!("Invalid NATS NKey seed: {}", err))?;
+            return Ok(NatsAuth::NKey { seed });
+        }
+        if let (Ok(user), Ok(password)) = (env::var("NATS_USER"), env::var("NATS_PASSWORD")) {
+            return Ok(NatsAuth::UserPassword { user, password: Zeroizing::new(password) });
+        }
+        // Falls back to the credentials saved by provisioning
+        match provisioning::stored_nats_credentials()? {
+            Some(credentials) =>
+                Ok(NatsAuth::UserPassword {
+                    user: credentials.user,
+                    password: Zeroizing::new(credentials.password),
+                }),
+            None =>
+                bail!(
+                    "No NATS credentials, set {}, {} or NATS_USER and NATS_PASSWORD",
+                    CREDS_FILE_VAR,
+                    NKEY_SEED_VAR
+                ),
+        }
+    }
+
+    pub async fn options(&self) -> Result<async_nats::ConnectOptions> {
+        let options = match self {
+            NatsAuth::UserPassword { user, password } => {
+                async_nats::ConnectOptions::with_user_and_password(
+                    user.clone(),
+                    password.to_string()
+                )
+            }
+            NatsAuth::NKey { seed } => async_nats::ConnectOptions::with_nkey(seed.to_string()),
+            NatsAuth::Credentials { path } => {
+                async_nats::ConnectOptions
+                    ::with_credentials_file(path.clone()).await
+                    .with_context(|| format!("Failed to read {}", path.display()))?
+            }
+        };
+        Ok(NatsTls::from_env()?.apply(options))
+    }
+}
+
+/// TLS settings of the NATS connection. Without any, TLS is used if the server URL or the server
+/// asks for it and verified with the system roots.
+#[derive(Debug, Default, PartialEq)]
+pub struct NatsTls {
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate and key for mutual TLS
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+    pub required: bool,
+}
+
+impl NatsTls {
+    pub fn from_env() -> Result<Self> {
+        let client_cert = match
+            (non_empty_var(TLS_CERT_FILE_VAR), non_empty_var(TLS_KEY_FILE_VAR))
+        {
+            (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+            (None, None) => None,
+            _ => bail!("Mutual TLS needs both {} and {}", TLS_CERT_FILE_VAR, TLS_KEY_FILE_VAR),
+        };
+        let tls = NatsTls {
+            ca_file: non_empty_var(TLS_CA_FILE_VAR).map(PathBuf::from),
+            client_cert,
+            required: env::var(TLS_REQUIRED_VAR).is_ok_and(|value| value == "true"),
+        };
+        for path in tls.files() {
+            if !path.is_file() {
+                bail!("NATS TLS file {} does not exist", path.display());
+            }
+        }
+        Ok(tls)
+    }
+
+    fn files(&self) -> Vec<&PathBuf> {
+        let mut files: Vec<&PathBuf> = self.ca_file.iter().collect();
+        if let Some((cert, key)) = &self.client_cert {
+            files.extend([cert, key]);
+        }
+        files
+    }
+
+    /// Any TLS setting makes the connection require TLS
+    fn is_required(&self) -> bool {
+        self.required || self.ca_file.is_some() || self.client_cert.is_some()
+    }
+
+    fn apply(&self, mut options: async_nats::ConnectOptions) -> async_nats::ConnectOptions {
+        if let Some(ca_file) = &self.ca_file {
+            options = options.add_root_certificates(ca_file.clone());
+        }
+        if let Some((cert, key)) = &self.client_cert {
+            options = options.add_client_certificate(cert.clone(), key.clone());
+        }
+        options.require_tls(self.is_required())
+    }
+}
+
+fn non_empty_var(var: &str) -> Option<String> {
+    env::var(var).ok().filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn any_tls_setting_requires_tls() {
+        assert!(!NatsTls::default().is_required());
+        let tls = NatsTls {
+            ca_file: Some(PathBuf::from("ca.pem")),
+            ..NatsTls::default()
+        };
+        assert!(tls.is_required());
+        assert_eq!(tls.files(), vec![&PathBuf::from("ca.pem")]);
+    }
+}
@@ -7,18 +7,21 @@ use crate::communication::nats::{
 use crate::communication::protocol::{ AllRounds, Topic };
 use crate::node::NodeIdentity;
 use anyhow::{ anyhow, Result };
+use std::collections::BTreeMap;
 use tracing::info;
 
 pub struct Nats;
 impl Nats {
-    pub fn new_session<R: AllRounds>(
-        conn: nats::Connection,
+    /// Joins the session and returns the messenger, the party indices and the encryption
+    /// envelope version each party advertised
+    pub async fn new_session<R: AllRounds>(
+        conn: async_nats::Client,
         session_id: &str,
         node: &NodeIdentity,
         key_id: &str,
         party_index: usize,
         topic: Topic
-    ) -> Result<(NatsPeerMessenger<R>, Vec<usize>)> {
+    ) -> Result<(NatsPeerMessenger<R>, Vec<usize>, BTreeMap<usize, u8>)> {
         // We are not attempting more than one keyshare per device
         let thread_index = 0;
 
@@ -36,11 +39,10 @@ impl Nats {
             topic,
             conn.clone(),
             nats_session.clone()
-        )?;
+        ).await?;
 
-        let join_response = regen_messenger.wait_for_confirmation(
-            std::time::Duration::from_secs(10)
-        )?;
+        let join_response = regen_messenger
+            .wait_for_confirmation(std::time::Duration::from_secs(10)).await?;
 
         info!("Got join response");
 
@@ -54,6 +56,6 @@ impl Nats {
             all_party_indices.clone()
         ).map_err(|err| anyhow!("Unable to create peer messenger: {}", err))?;
 
-        Ok((messenger, all_party_indices))
+        Ok((messenger, all_party_indices, join_response.encryption_versions))
     }
 }
@@ -0,0 +1,244 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::communication::protocol::{
+    AllRounds,
+    BLSKeyGenAllRounds,
+    FrostKeyGenAllRounds,
+    KeyGenAllRounds,
+    KeyShareRegenAllRounds,
+    KeySignBLSAllRounds,
+    KeySignCGGMPAllRounds,
+    KeySignEdDSAAllRounds,
+    KeySignFrostAllRounds,
+    KeySignSr25519AllRounds,
+    PresignECDSAAllRounds,
+    ShareRefreshAllRounds,
+    Topic,
+};
+use crate::communication::jetstream::JetStreamConfig;
+use crate::communication::queue_groups::WorkerConfig;
+use crate::communication::round_subscriptions::REPLAY_ROUND;
+use crate::node::NodeIdentity;
+use crate::observer::consented_observers;
+use crate::storage::fs::FileSystem;
+use crate::tenant::Access;
+use anyhow::Result;
+use serde::{ Deserialize, Serialize };
+use std::collections::BTreeSet;
+use std::path::Path;
+use strum::IntoEnumIterator;
+
+/// Prefix of every subject the node uses
+const NAMESPACE: &str = "network.gridlock";
+const ECDSA_KEYGEN_BROADCAST_ROUNDS: [&str; 4] = ["round1", "round2", "round4", "round5"];
+const ECDSA_SIGN_BROADCAST_PHASES: [&str; 7] = [
+    "phase0",
+    "phase1",
+    "phase3",
+    "phase4",
+    "phase5",
+    "phase6",
+    "phase7",
+];
+
+/// Subjects for the `publish` and `subscribe` permissions of the node's NATS user
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct SubjectPermissions {
+    pub publish: BTreeSet<String>,
+    pub subscribe: BTreeSet<String>,
+    /// Replies to requests go to the requester's inbox, which NATS only allows with the
+    /// `allow_responses` permission
+    pub allow_responses: bool,
+}
+
+impl SubjectPermissions {
+    fn both(&mut self, subject: String) {
+        self.publish.insert(subject.clone());
+        self.subscribe.insert(subject);
+    }
+
+    /// Round subjects of the sessions of one topic. Rounds are listed by name, so the wildcard
+    /// only stands for the session id and never matches another node's `new` subject.
+    fn session_rounds<R: AllRounds>(&mut self, topic: Topic) {
+        let prefix = format!("{}.nodes.{}.*", NAMESPACE, topic);
+        for round in R::BroadcastRound::iter() {
+            self.both(format!("{}.{}", prefix, round));
+        }
+        for round in R::P2PRound::iter() {
+            self.both(format!("{}.{}.*", prefix, round));
+        }
+        self.publish.insert(format!("{}.Join", prefix));
+        self.both(format!("{}.{}", prefix, REPLAY_ROUND));
+    }
+}
+
+/// Generates the subjects this node needs instead of `network.gridlock.>`. Permissions only
+/// cover the keys held when they are generated, they have to be regenerated when the owner of a
+/// key consents to a new observer.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetNatsPermissionsCommand {
+    /// Include the subjects needed to orchestrate sessions, only required on nodes that receive
+    /// `Orchestrate*` commands
+    #[serde(default)]
+    pub orchestrator: bool,
+}
+
+impl JsonCommand for GetNatsPermissionsCommand {
+    type Response = SubjectPermissions;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        node_permissions(self.orchestrator)
+    }
+}
+
+/// Permissions for this node, its worker configuration and the observers of the keys it holds
+pub fn node_permissions(orchestrator: bool) -> Result<SubjectPermissions> {
+    let node = NodeIdentity::cached()?;
+    let mut observers = BTreeSet::new();
+    for (email, key_id) in held_account_keys()? {
+        observers.extend(consented_observers(&key_id, &email));
+    }
+    let node_id = node.node_id.to_string();
+    let mut permissions = generate(
+        &node_id,
+        WorkerConfig::from_env().as_ref(),
+        &observers,
+        orchestrator
+    );
+    if let Some(jetstream) = JetStreamConfig::from_env() {
+        jetstream.grant(&mut permissions, &node_id);
+    }
+    Ok(permissions)
+}
+
+/// `node_permissions` as pretty printed JSON, for operators pasting it into the account config
+pub fn node_permissions_json(orchestrator: bool) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&node_permissions(orchestrator)?)?)
+}
+
+/// Email and key id of every keyshare stored in an account directory
+fn held_account_keys() -> Result<Vec<(String, String)>> {
+    let keys = FileSystem::find_all_keyshare_files()?
+        .iter()
+        .filter_map(|path| {
+            let key_directory = path.parent()?;
+            let account = key_directory.parent()?.parent()?;
+            Some((file_name(account)?, file_name(key_directory)?))
+        })
+        .collect();
+    Ok(keys)
+}
+
+fn file_name(path: &Path) -> Option<String> {
+    path.file_name()?.to_str().map(str::to_string)
+}
+
+fn generate(
+    node_id: &str,
+    worker: Option<&WorkerConfig>,
+    observers: &BTreeSet<String>,
+    orchestrator: bool
+) -> SubjectPermissions {
+    let nodes = format!("{}.nodes", NAMESPACE);
+    let mut permissions = SubjectPermissions {
+        allow_responses: true,
+        ..Default::default()
+    };
+
+    permissions.subscribe.insert(WorkerConfig::shared_subject(node_id));
+    if let Some(worker) = worker {
+        permissions.subscribe.insert(worker.instance_subject(node_id));
+        // Session messages are forwarded to the sibling that claimed the session
+        permissions.publish.insert(format!("{}.*", WorkerConfig::shared_subject(node_id)));
+    }
+    // Join requests wait for their response on an inbox
+    permissions.subscribe.insert("_INBOX.>".to_string());
+    for subject in ["nodes.ready", "metrics", "health", "approvals"] {
+        permissions.publish.insert(format!("{}.{}.{}", NAMESPACE, subject, node_id));
+    }
+    // Log tails are published on a subject per tail
+    permissions.publish.insert(format!("{}.logs.{}.*", NAMESPACE, node_id));
+
+    permissions.session_rounds::<KeyGenAllRounds>(Topic::KeyGenEdDSA);
+    permissions.session_rounds::<KeyGenAllRounds>(Topic::EphemeralKeyGenEdDSA);
+    permissions.session_rounds::<KeyGenAllRounds>(Topic::KeyGenSr25519);
+    permissions.session_rounds::<FrostKeyGenAllRounds>(Topic::KeyGenFrost);
+    permissions.session_rounds::<BLSKeyGenAllRounds>(Topic::KeyGenBLS);
+    permissions.session_rounds::<KeySignEdDSAAllRounds>(Topic::KeySignEdDSA);
+    permissions.session_rounds::<KeySignSr25519AllRounds>(Topic::KeySignSr25519);
+    permissions.session_rounds::<KeySignFrostAllRounds>(Topic::KeySignFrost);
+    permissions.session_rounds::<KeySignBLSAllRounds>(Topic::KeySignBLS);
+    permissions.session_rounds::<PresignECDSAAllRounds>(Topic::PresignECDSA);
+    permissions.session_rounds::<KeySignCGGMPAllRounds>(Topic::KeySignCGGMP);
+    permissions.session_rounds::<KeyShareRegenAllRounds>(Topic::KeyShareRecovery);
+    permissions.session_rounds::<ShareRefreshAllRounds>(Topic::ShareRefresh);
+
+    // The original ECDSA sessions name their subjects themselves
+    for round in ECDSA_KEYGEN_BROADCAST_ROUNDS {
+        permissions.both(format!("{}.keyGen.*.{}", nodes, round));
+    }
+    permissions.both(format!("{}.keyGen.*.round3.*", nodes));
+    permissions.subscribe.insert(format!("{}.keyGen.session.*.start", nodes));
+    for suffix in ["join", "ready", "result"] {
+        permissions.publish.insert(format!("{}.keyGen.session.*.{}", nodes, suffix));
+    }
+    for phase in ECDSA_SIGN_BROADCAST_PHASES {
+        permissions.both(format!("{}.keySign.session.*.{}", nodes, phase));
+    }
+    permissions.both(format!("{}.keySign.session.*.phase2.*", nodes));
+    permissions.subscribe.insert(format!("{}.keySign.session.*.start", nodes));
+    for suffix in ["join", "result"] {
+        permissions.publish.insert(format!("{}.keySign.session.*.{}", nodes, suffix));
+    }
+
+    for observer_id in observers {
+        permissions.publish.insert(format!("{}.observers.{}.>", NAMESPACE, observer_id));
+    }
+
+    if orchestrator {
+        permissions.publish.insert(format!("{}.*.new.*", nodes));
+        permissions.publish.insert(format!("{}.async.Message.new.*", nodes));
+        permissions.publish.insert(format!("{}.keyGen.session.*.start", nodes));
+        permissions.publish.insert(format!("{}.keySign.session.*.start", nodes));
+        for suffix in ["Join", "Result", "DeliverRecoveryPackage"] {
+            permissions.subscribe.insert(format!("{}.*.*.{}", nodes, suffix));
+        }
+        for suffix in ["join", "ready", "result"] {
+            permissions.subscribe.insert(format!("{}.keyGen.session.*.{}", nodes, suffix));
+        }
+        for suffix in ["join", "result"] {
+            permissions.subscribe.insert(format!("{}.keySign.session.*.{}", nodes, suffix));
+        }
+    }
+    permissions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_orchestrators_reach_other_nodes() {
+        let node_id = "node-1";
+        let observers = BTreeSet::from(["auditor".to_string()]);
+        let permissions = generate(node_id, None, &observers, false);
+
+        assert!(permissions.subscribe.contains("network.gridlock.nodes.*.new.node-1"));
+        assert!(permissions.subscribe.contains("network.gridlock.nodes.KeySignFrost.*.Result"));
+        assert!(permissions.publish.contains("network.gridlock.nodes.KeyGenEdDSA.*.ShareSecret.*"));
+        assert!(permissions.publish.contains("network.gridlock.observers.auditor.>"));
+        assert!(!permissions.publish.iter().any(|subject| subject.contains(".new.")));
+        let namespace_wildcard = |subject: &String| {
+            subject.starts_with(NAMESPACE) && subject.ends_with(".>")
+        };
+        assert!(!permissions.subscribe.iter().any(namespace_wildcard));
+
+        let orchestrator = generate(node_id, None, &observers, true);
+        assert!(orchestrator.publish.contains("network.gridlock.nodes.*.new.*"));
+        assert!(orchestrator.subscribe.is_superset(&permissions.subscribe));
+    }
+}
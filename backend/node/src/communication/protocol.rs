@@ -15,6 +15,14 @@ pub enum Topic {
     KeySignEdDSA,
     KeyShareRecovery,
     KeySignSr25519,
+    KeyGenFrost,
+    KeySignFrost,
+    KeyGenSr25519,
+    KeyGenBLS,
+    KeySignBLS,
+    PresignECDSA,
+    KeySignCGGMP,
+    ShareRefresh,
 }
 
 pub struct KeyGenAllRounds;
@@ -29,6 +37,8 @@ pub enum KeyGenBroadcastRound {
     Commit,
     Decommit,
     VSS,
+    /// Ristretto public key shares, only exchanged by sr25519 key generation
+    PublicShare,
     Result,
 }
 
@@ -85,3 +95,105 @@ impl AllRounds for KeySignSr25519AllRounds {
     type BroadcastRound = SrMusig25519BroadcastRound;
     type P2PRound = KeySignP2PRound;
 }
+
+pub struct FrostKeyGenAllRounds;
+
+impl AllRounds for FrostKeyGenAllRounds {
+    type BroadcastRound = FrostKeyGenBroadcastRound;
+    type P2PRound = KeyGenP2PRound;
+}
+
+#[derive(macroDisplay, EnumIter)]
+pub enum FrostKeyGenBroadcastRound {
+    Commit,
+    Result,
+}
+
+pub struct KeySignFrostAllRounds;
+
+impl AllRounds for KeySignFrostAllRounds {
+    type BroadcastRound = FrostKeySignBroadcastRound;
+    type P2PRound = KeySignP2PRound;
+}
+
+#[derive(macroDisplay, EnumIter)]
+pub enum FrostKeySignBroadcastRound {
+    NonceCommit,
+    SignatureShare,
+    Result,
+}
+
+pub struct BLSKeyGenAllRounds;
+
+impl AllRounds for BLSKeyGenAllRounds {
+    type BroadcastRound = BLSKeyGenBroadcastRound;
+    type P2PRound = KeyGenP2PRound;
+}
+
+#[derive(macroDisplay, EnumIter)]
+pub enum BLSKeyGenBroadcastRound {
+    Commit,
+    Result,
+}
+
+pub struct KeySignBLSAllRounds;
+
+impl AllRounds for KeySignBLSAllRounds {
+    type BroadcastRound = BLSKeySignBroadcastRound;
+    type P2PRound = KeySignP2PRound;
+}
+
+#[derive(macroDisplay, EnumIter)]
+pub enum BLSKeySignBroadcastRound {
+    SignatureShare,
+    Result,
+}
+
+pub struct PresignECDSAAllRounds;
+
+impl AllRounds for PresignECDSAAllRounds {
+    type BroadcastRound = PresignBroadcastRound;
+    type P2PRound = PresignP2PRound;
+}
+
+#[derive(macroDisplay, EnumIter)]
+pub enum PresignBroadcastRound {
+    Commit,
+    Delta,
+    Result,
+}
+
+#[derive(macroDisplay, EnumIter)]
+pub enum PresignP2PRound {
+    MtA,
+}
+
+pub struct KeySignCGGMPAllRounds;
+
+impl AllRounds for KeySignCGGMPAllRounds {
+    type BroadcastRound = KeySignBroadcastRound;
+    type P2PRound = KeySignP2PRound;
+}
+
+pub struct ShareRefreshAllRounds;
+
+impl AllRounds for ShareRefreshAllRounds {
+    type BroadcastRound = ShareRefreshBroadcastRound;
+    type P2PRound = ShareRefreshP2PRound;
+}
+
+#[derive(macroDisplay, EnumIter)]
+pub enum ShareRefreshBroadcastRound {
+    Commit,
+    /// Digest of the refreshed commitments, every party has to end up with the same ones
+    Confirm,
+    /// Sent once the refreshed share is saved beside the current one, parties replace their share
+    /// only after every party sent it
+    Saved,
+    Result,
+}
+
+#[derive(macroDisplay, EnumIter)]
+pub enum ShareRefreshP2PRound {
+    ZeroShare,
+}
@@ -0,0 +1,193 @@
+use crate::communication::incoming::IncomingMessage;
+use crate::config::{ Config, ConfigProvider };
+use crate::session_manager;
+use crate::{ handle_message, route_message, App, MessageRoute };
+use anyhow::{ anyhow, Result };
+use std::fs::{ self, OpenOptions };
+use std::io::{ ErrorKind, Write };
+use std::path::PathBuf;
+use std::time::{ Duration, SystemTime };
+use std::env;
+use tracing::{ info, warn };
+use uuid::Uuid;
+
+const SESSION_CLAIMS_DIR: &str = "session-claims";
+/// Claims older than this are considered abandoned and may be taken over
+const CLAIM_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Settings for running several worker instances that share one node identity.
+///
+/// All instances join the same NATS queue group so every new-session or command message is
+/// delivered to exactly one of them. Command messages are stateless and handled wherever they
+/// land. Messages that belong to a session are pinned to the instance that first claimed the
+/// session id in the shared storage directory; other instances forward them to that instance's
+/// private subject.
+#[derive(Clone, Debug)]
+pub struct WorkerConfig {
+    pub queue_group: String,
+    pub instance_id: String,
+}
+
+impl WorkerConfig {
+    /// Returns `None` when the node runs as a single instance
+    pub fn from_env() -> Option<Self> {
+        let queue_group = env
+            ::var("NATS_QUEUE_GROUP")
+            .ok()
+            .filter(|group| !group.is_empty())?;
+        let instance_id = env
+            ::var("NODE_INSTANCE_ID")
+            .ok()
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        Some(WorkerConfig { queue_group, instance_id })
+    }
+
+    /// Subject all instances queue subscribe to
+    pub fn shared_subject(node_id: &str) -> String {
+        format!("network.gridlock.nodes.*.new.{}", node_id)
+    }
+
+    /// Subject on which this instance receives messages forwarded by its siblings
+    pub fn instance_subject(&self, node_id: &str) -> String {
+        format!("network.gridlock.nodes.*.new.{}.{}", node_id, self.instance_id)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SessionOwner {
+    This,
+    Other(String),
+}
+
+/// Session ownership records kept in the storage directory shared by all instances
+pub struct SessionAffinity;
+
+impl SessionAffinity {
+    fn claim_path(session_id: &str) -> PathBuf {
+        let mut path = Config::get_gridlock_directory();
+        path.push(SESSION_CLAIMS_DIR);
+        path.push(session_id.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_"));
+        path
+    }
+
+    /// Claims the session for `instance_id`, or reports which instance already holds it
+    pub fn claim(session_id: &str, instance_id: &str) -> Result<SessionOwner> {
+        let path = Self::claim_path(session_id);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(instance_id.as_bytes())?;
+                    return Ok(SessionOwner::This);
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    if Self::is_expired(&path) {
+                        warn!("Taking over abandoned claim for session {}", session_id);
+                        let _ = fs::remove_file(&path);
+                        continue;
+                    }
+                    let owner = fs::read_to_string(&path)?;
+                    return Ok(
+                        if owner == instance_id {
+                            SessionOwner::This
+                        } else {
+                            SessionOwner::Other(owner)
+                        }
+                    );
+                }
+                Err(err) => {
+                    return Err(err.into());
+                }
+            }
+        }
+    }
+
+    pub fn release(session_id: &str) -> Result<()> {
+        let path = Self::claim_path(session_id);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn is_expired(path: &PathBuf) -> bool {
+        fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .map(|age| age > CLAIM_TTL)
+            .unwrap_or(false)
+    }
+}
+
+/// Finds the id tying a message to a session. Messages without one, e.g. the ECDSA keygen
+/// requests, are handled wherever they land: a key id names every session of the key, a claim on
+/// it would pin all of them to one instance.
+pub fn extract_session_id(data: &[u8]) -> Option<String> {
+    let value = serde_json::from_slice::<serde_json::Value>(data).ok()?;
+    value.get("session_id").and_then(|v| v.as_str()).map(String::from)
+}
+
+/// Handles a message received through the queue group, forwarding it if another instance owns
+/// the session it belongs to
+pub fn dispatch_queued_message(app: &App, worker: &WorkerConfig, message: IncomingMessage) {
+    let session_id = match route_message(&message.subject) {
+        Some(MessageRoute::Command) | None => None,
+        Some(_) => extract_session_id(&message.data),
+    };
+
+    let owner = match &session_id {
+        Some(session_id) =>
+            SessionAffinity::claim(session_id, &worker.instance_id).unwrap_or_else(|err| {
+                warn!("Could not claim session {}, handling locally: {}", session_id, err);
+                SessionOwner::This
+            }),
+        None => SessionOwner::This,
+    };
+
+    match owner {
+        SessionOwner::This => {
+            handle_message(app, message);
+            // A refused message starts no session, which would release the claim when it ends
+            if let Some(session_id) = session_id.filter(|id| !session_manager::is_running(id)) {
+                if let Err(err) = SessionAffinity::release(&session_id) {
+                    warn!("Failed to release the claim of session {}: {}", session_id, err);
+                }
+            }
+        }
+        SessionOwner::Other(instance_id) => {
+            if let Err(err) = forward_message(app, &instance_id, &message) {
+                warn!("Failed to forward message to instance {}: {}", instance_id, err);
+            }
+        }
+    }
+}
+
+fn forward_message(app: &App, instance_id: &str, message: &IncomingMessage) -> Result<()> {
+    let subject = format!("{}.{}", message.subject, instance_id);
+    info!("Forwarding message for a session owned by instance {}", instance_id);
+    match &message.reply {
+        Some(reply) => app.nc.publish_request(&subject, reply, &message.data),
+        None => app.nc.publish(&subject, &message.data),
+    }.map_err(|err| anyhow!(err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_session_id() {
+        assert_eq!(
+            extract_session_id(br#"{"session_id":"abc","key_id":"def"}"#),
+            Some(String::from("abc"))
+        );
+        assert_eq!(extract_session_id(br#"{"key_id":"def"}"#), None);
+        assert_eq!(extract_session_id(br#"{"msg":[1,2]}"#), None);
+        assert_eq!(extract_session_id(b"not json"), None);
+    }
+}
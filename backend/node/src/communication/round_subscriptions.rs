@@ -1,26 +1,84 @@
 use crate::communication::nats::NatsBaseSession;
 use crate::communication::protocol::{ AllRounds, Topic };
+use crate::communication::subscription_registry::{ self, TrackedSubscription };
+use crate::session_manager;
 use anyhow::Result;
-use nats::Subscription;
+use async_nats::{ Client, Subscriber };
+use futures::StreamExt;
+use serde::{ Deserialize, Serialize };
 use std::collections::HashMap;
+use std::sync::{ Arc, Mutex as SyncMutex };
+use std::time::{ Duration, Instant };
 use strum::IntoEnumIterator;
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
+use tokio::task::AbortHandle;
+use tracing::warn;
+
+/// Round parties ask each other on to resend the messages they published
+pub(crate) const REPLAY_ROUND: &str = "Replay";
+/// Round a party announces on that it can't go on with the session
+pub(crate) const ABORT_ROUND: &str = "Abort";
+/// A message is resent at most this often, however many parties ask for it
+const REPLAY_MIN_INTERVAL: Duration = Duration::from_secs(1);
+/// Replays are still answered this long after the session is done with its messenger, for the
+/// parties still collecting the last rounds
+const REPLAY_GRACE: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize)]
+struct ReplayRequest {
+    subject: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionAbort {
+    pub session_id: String,
+    pub node_id: String,
+    pub party_index: usize,
+    pub reason: String,
+}
+
+/// Messages this party published in the session by subject, with the time each was last resent
+type Outbox = Arc<SyncMutex<HashMap<String, (Vec<u8>, Option<Instant>)>>>;
 
 pub struct RoundSubscription {
-    pub subscription: Subscription,
+    /// Locked while a round is collected, rounds of one session are collected one at a time
+    pub subscription: Arc<Mutex<Subscriber>>,
     pub subject: String,
+    pub replay: ReplayRequester,
+    _tracked: TrackedSubscription,
+}
+
+/// Asks the other parties to resend what they published on the subject of a round this party
+/// receives, for messages published before it subscribed or lost on the way
+pub struct ReplayRequester {
+    connection: Client,
+    replay_subject: String,
+    subject: String,
+}
+
+impl ReplayRequester {
+    pub async fn request(&self) -> Result<()> {
+        let request = serde_json::to_vec(&(ReplayRequest { subject: self.subject.clone() }))?;
+        self.connection.publish(self.replay_subject.clone(), request.into()).await?;
+        Ok(())
+    }
 }
 
 pub struct RoundSubscriber {
     subscriptions: HashMap<String, RoundSubscription>,
-    connection: nats::Connection,
+    connection: Client,
     topic: Topic,
     node_id: String,
     session_id: String,
     party_index: usize,
+    outbox: Outbox,
+    replay_responder: Option<(AbortHandle, TrackedSubscription)>,
+    abort_listener: Option<(AbortHandle, TrackedSubscription)>,
 }
 
 impl RoundSubscriber {
-    pub fn new(topic: Topic, conn: &nats::Connection, session: &NatsBaseSession) -> Self {
+    pub fn new(topic: Topic, conn: &Client, session: &NatsBaseSession) -> Self {
         let subscriptions = HashMap::new();
 
         Self {
@@ -30,23 +88,27 @@ impl RoundSubscriber {
             node_id: session.node_id.clone(),
             session_id: session.session_id.clone(),
             party_index: session.party_index,
+            outbox: Arc::new(SyncMutex::new(HashMap::new())),
+            replay_responder: None,
+            abort_listener: None,
         }
     }
 
-    pub fn subscribe<R: AllRounds>(&mut self) -> Result<()> {
+    pub async fn subscribe<R: AllRounds>(&mut self) -> Result<()> {
         for round in R::BroadcastRound::iter() {
             let round_name = round.to_string();
-            let round_sub = self.broadcast_round_subscribe(&round_name)?;
+            let round_sub = self.broadcast_round_subscribe(&round_name).await?;
             self.subscriptions.insert(round_name, round_sub);
         }
 
         for round in R::P2PRound::iter() {
             let round_name = round.to_string();
-            let round_sub = self.p2p_round_subscribe(&round_name)?;
+            let round_sub = self.p2p_round_subscribe(&round_name).await?;
             self.subscriptions.insert(round_name, round_sub);
         }
 
-        Ok(())
+        self.start_replay_responder().await?;
+        self.start_abort_listener().await
     }
 
     pub fn get_subscription(&self, name: &str) -> Result<&RoundSubscription> {
@@ -57,33 +119,165 @@ impl RoundSubscriber {
         Ok(sub)
     }
 
-    fn broadcast_round_subscribe(&self, round_name: &str) -> Result<RoundSubscription> {
+    /// Publishes a round message and keeps it to resend on request
+    pub async fn publish(&self, subject: String, payload: String) -> Result<()> {
+        let payload = payload.into_bytes();
+        self.outbox.lock().unwrap().insert(subject.clone(), (payload.clone(), None));
+        self.connection.publish(subject, payload.into()).await?;
+        Ok(())
+    }
+
+    async fn start_replay_responder(&mut self) -> Result<()> {
+        let mut requests = self.connection.subscribe(
+            self.format_round_subject(REPLAY_ROUND)
+        ).await?;
+        let connection = self.connection.clone();
+        let outbox = self.outbox.clone();
+        let responder = tokio::spawn(async move {
+            while let Some(request) = requests.next().await {
+                let subject = match serde_json::from_slice::<ReplayRequest>(&request.payload) {
+                    Ok(request) => request.subject,
+                    Err(_) => {
+                        continue;
+                    }
+                };
+                let payload = match outbox.lock().unwrap().get_mut(&subject) {
+                    Some((payload, last_sent)) if
+                        last_sent.map_or(true, |sent| sent.elapsed() >= REPLAY_MIN_INTERVAL)
+                    => {
+                        *last_sent = Some(Instant::now());
+                        payload.clone()
+                    }
+                    _ => {
+                        continue;
+                    }
+                };
+                if let Err(err) = connection.publish(subject.clone(), payload.into()).await {
+                    warn!("Failed to resend the message on {}: {}", subject, err);
+                }
+            }
+        });
+        let abort = responder.abort_handle();
+        let tracked = subscription_registry::track(
+            &self.session_id,
+            &self.format_round_subject(REPLAY_ROUND),
+            move || abort.abort()
+        );
+        self.replay_responder = Some((responder.abort_handle(), tracked));
+        Ok(())
+    }
+
+    /// Cancels the session on this node once another party aborts it, so it stops instead of
+    /// waiting for rounds until it times out
+    async fn start_abort_listener(&mut self) -> Result<()> {
+        let subject = self.format_round_subject(ABORT_ROUND);
+        let mut aborts = self.connection.subscribe(subject.clone()).await?;
+        let session_id = self.session_id.clone();
+        let listener = tokio::spawn(async move {
+            while let Some(message) = aborts.next().await {
+                let abort = match serde_json::from_slice::<SessionAbort>(&message.payload) {
+                    Ok(abort) if abort.session_id == session_id => abort,
+                    _ => {
+                        continue;
+                    }
+                };
+                warn!(
+                    "Party {} on node {} aborted session {}: {}",
+                    abort.party_index,
+                    abort.node_id,
+                    session_id,
+                    abort.reason
+                );
+                session_manager::cancel_session(&session_id);
+                break;
+            }
+        });
+        let abort = listener.abort_handle();
+        let tracked = subscription_registry::track(&self.session_id, &subject, move ||
+            abort.abort()
+        );
+        self.abort_listener = Some((listener.abort_handle(), tracked));
+        Ok(())
+    }
+
+    /// A stale subscription is unsubscribed once its session, cancelled by the sweeper, is done
+    /// collecting from it
+    fn track(&self, subject: &str, subscription: &Arc<Mutex<Subscriber>>) -> TrackedSubscription {
+        let subscription = subscription.clone();
+        subscription_registry::track(&self.session_id, subject, move || {
+            session_manager::runtime().spawn(async move {
+                if let Err(err) = subscription.lock().await.unsubscribe().await {
+                    warn!("Failed to unsubscribe a stale round subscription: {}", err);
+                }
+            });
+        })
+    }
+
+    fn replay_requester(&self, subject: &str) -> ReplayRequester {
+        ReplayRequester {
+            connection: self.connection.clone(),
+            replay_subject: self.format_round_subject(REPLAY_ROUND),
+            subject: subject.to_string(),
+        }
+    }
+
+    async fn broadcast_round_subscribe(&self, round_name: &str) -> Result<RoundSubscription> {
         let subject = self.format_round_subject(round_name);
-        let subscription = self.connection.subscribe(&subject)?;
+        let subscription = Arc::new(Mutex::new(self.connection.subscribe(subject.clone()).await?));
         Ok(RoundSubscription {
+            _tracked: self.track(&subject, &subscription),
             subscription,
+            replay: self.replay_requester(&subject),
             subject,
         })
     }
 
-    fn p2p_round_subscribe(&self, round_name: &str) -> Result<RoundSubscription> {
+    async fn p2p_round_subscribe(&self, round_name: &str) -> Result<RoundSubscription> {
         let subscribe_name = self.format_round_subject(round_name);
         let subscribe_subject = self.format_round_subject(
             &format!("{}.{}", round_name, &self.party_index)
         );
-        let subscription = self.connection.subscribe(&subscribe_subject)?;
+        let subscription = Arc::new(
+            Mutex::new(self.connection.subscribe(subscribe_subject.clone()).await?)
+        );
         Ok(RoundSubscription {
+            _tracked: self.track(&subscribe_subject, &subscription),
             subscription,
+            replay: self.replay_requester(&subscribe_subject),
             subject: subscribe_name,
         })
     }
 
     pub fn format_round_subject(&self, round_name: &str) -> String {
-        format!(
-            "network.gridlock.nodes.{}.{}.{}",
-            self.topic,
-            self.session_id,
-            &round_name
-        )
+        round_subject(&self.topic, &self.session_id, round_name)
+    }
+}
+
+pub fn round_subject(topic: &Topic, session_id: &str, round_name: &str) -> String {
+    format!("network.gridlock.nodes.{}.{}.{}", topic, session_id, round_name)
+}
+
+impl Drop for RoundSubscriber {
+    fn drop(&mut self) {
+        if let Some((listener, _tracked)) = self.abort_listener.take() {
+            listener.abort();
+        }
+        // The responder stays registered until it is aborted
+        let (responder, tracked) = match self.replay_responder.take() {
+            Some(responder) => responder,
+            None => {
+                return;
+            }
+        };
+        match Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    tokio::time::sleep(REPLAY_GRACE).await;
+                    responder.abort();
+                    drop(tracked);
+                });
+            }
+            Err(_) => responder.abort(),
+        }
     }
 }
@@ -0,0 +1,151 @@
+//! Subscriptions the sessions hold, so none outlives its session. A session's subscriptions are
+//! closed when it drops them, and a sweeper closes the ones of sessions still open after
+//! `SESSION_SUBSCRIPTION_TTL_SECS`, for sessions stuck on a wait that never returns.
+
+use crate::session_manager;
+use anyhow::{ bail, Context, Result };
+use std::collections::HashMap;
+use std::env;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Mutex, OnceLock };
+use std::thread;
+use std::time::{ Duration, Instant };
+use tracing::{ info, warn };
+
+const TTL_VAR: &str = "SESSION_SUBSCRIPTION_TTL_SECS";
+/// Well past the longest session timeout, a session still subscribed then is stuck
+const DEFAULT_TTL_SECS: u64 = 30 * 60;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+type Close = Box<dyn FnOnce() + Send>;
+
+struct OpenSubscription {
+    session_id: String,
+    subject: String,
+    opened: Instant,
+    close: Close,
+}
+
+fn open_subscriptions() -> &'static Mutex<HashMap<u64, OpenSubscription>> {
+    static OPEN: OnceLock<Mutex<HashMap<u64, OpenSubscription>>> = OnceLock::new();
+    OPEN.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Keeps a subscription registered until dropped, along with the subscription it tracks
+pub struct TrackedSubscription {
+    id: u64,
+}
+
+impl Drop for TrackedSubscription {
+    fn drop(&mut self) {
+        open_subscriptions().lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Registers a subscription of a session, `close` unsubscribes it when the sweeper finds it stale
+pub fn track(
+    session_id: &str,
+    subject: &str,
+    close: impl FnOnce() + Send + 'static
+) -> TrackedSubscription {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    open_subscriptions()
+        .lock()
+        .unwrap()
+        .insert(id, OpenSubscription {
+            session_id: session_id.to_string(),
+            subject: subject.to_string(),
+            opened: Instant::now(),
+            close: Box::new(close),
+        });
+    TrackedSubscription { id }
+}
+
+/// Closes the subscriptions opened longer than `ttl` ago and cancels their sessions, returns how
+/// many were closed
+pub fn sweep(ttl: Duration) -> usize {
+    let stale = {
+        let mut open = open_subscriptions().lock().unwrap();
+        let ids = open
+            .iter()
+            .filter(|(_, subscription)| subscription.opened.elapsed() > ttl)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        ids.into_iter()
+            .filter_map(|id| open.remove(&id))
+            .collect::<Vec<_>>()
+    };
+    for subscription in &stale {
+        warn!(
+            "Closing subscription to {} of session {}, open for {}s",
+            subscription.subject,
+            subscription.session_id,
+            subscription.opened.elapsed().as_secs()
+        );
+        session_manager::cancel_session(&subscription.session_id);
+    }
+    // Closed outside of the lock, the sessions drop their guards as they stop
+    let closed = stale.len();
+    for subscription in stale {
+        (subscription.close)();
+    }
+    closed
+}
+
+fn subscription_ttl() -> Result<Duration> {
+    let secs = match env::var(TTL_VAR) {
+        Ok(secs) => secs.parse::<u64>().with_context(|| format!("{} is not a number", secs))?,
+        Err(_) => DEFAULT_TTL_SECS,
+    };
+    if secs == 0 {
+        bail!("{} must be at least one second", TTL_VAR);
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+pub fn spawn_sweeper() -> Result<()> {
+    let ttl = subscription_ttl()?;
+    thread::Builder
+        ::new()
+        .name("subscription-sweeper".to_string())
+        .spawn(move || {
+            loop {
+                thread::sleep(SWEEP_INTERVAL);
+                let closed = sweep(ttl);
+                if closed > 0 {
+                    info!("Closed {} stale session subscriptions", closed);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[test]
+    fn sweeps_only_stale_subscriptions() {
+        let closed = Arc::new(AtomicBool::new(false));
+        let closed_by_sweep = closed.clone();
+        let stale = track("sweep-stale", "stale.subject", move || {
+            closed_by_sweep.store(true, Ordering::Relaxed);
+        });
+        let dropped = track("sweep-dropped", "dropped.subject", || {
+            panic!("Dropped subscriptions are not closed again");
+        });
+        drop(dropped);
+        thread::sleep(Duration::from_millis(20));
+        let fresh = track("sweep-fresh", "fresh.subject", || {
+            panic!("Fresh subscriptions are not closed");
+        });
+
+        assert!(sweep(Duration::from_millis(10)) >= 1);
+        assert!(closed.load(Ordering::Relaxed));
+        drop(stale);
+        drop(fresh);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::communication::ecdsa::HasSenderId;
+use crate::encryption::{
+    decrypt_and_deserialize,
+    serialize_and_encrypt,
+    x25519_shared_secret_from_nkeys,
+};
+use anyhow::{ anyhow, bail, Result };
+use serde::{ de::DeserializeOwned, Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use shared::recovery::EncryptedData;
+use std::collections::BTreeMap;
+use zeroize::Zeroizing;
+
+const ROUND_KEY_CONTEXT: &[u8] = b"gridlock-round-transport-v1";
+
+/// Round message encrypted separately to each recipient under the key the sender shares with it.
+/// Broadcasts carry one ciphertext per party, p2p messages only the one of their target.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SealedRoundMessage {
+    pub sender_id: usize,
+    pub sealed: BTreeMap<usize, EncryptedData>,
+}
+
+impl HasSenderId for SealedRoundMessage {
+    fn get_sender_id(&self) -> usize {
+        self.sender_id
+    }
+}
+
+/// The subject is encrypted along with the message, a ciphertext moved to another round or
+/// session does not open
+#[derive(Serialize, Deserialize)]
+struct RoundPlaintext<T> {
+    subject: String,
+    message: T,
+}
+
+/// Pairwise keys of one party with every party of a session, derived from an X25519 exchange of
+/// the nodes' networking keys as for recovery packages, then bound to the session id
+pub struct RoundTransport {
+    party_index: usize,
+    keys: BTreeMap<usize, Zeroizing<Vec<u8>>>,
+}
+
+impl RoundTransport {
+    pub fn new(
+        session_id: &str,
+        party_index: usize,
+        private_key: &str,
+        public_keys: &BTreeMap<usize, String>
+    ) -> Result<Self> {
+        let keys = public_keys
+            .iter()
+            .map(|(party, public_key)| {
+                let shared = Zeroizing::new(
+                    x25519_shared_secret_from_nkeys(private_key, public_key)?
+                );
+                Ok((*party, session_key(session_id, &shared)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(RoundTransport { party_index, keys })
+    }
+
+    fn key(&self, party: usize) -> Result<&[u8]> {
+        self.keys
+            .get(&party)
+            .map(|key| key.as_slice())
+            .ok_or_else(|| anyhow!("No transport key for party {}", party))
+    }
+
+    pub fn seal<T: Serialize>(
+        &self,
+        subject: &str,
+        message: &T,
+        recipients: impl IntoIterator<Item = usize>
+    ) -> Result<SealedRoundMessage> {
+        let plaintext = RoundPlaintext { subject: subject.to_string(), message };
+        let sealed = recipients
+            .into_iter()
+            .map(|party| Ok((party, serialize_and_encrypt(&plaintext, self.key(party)?)?)))
+            .collect::<Result<_>>()?;
+        Ok(SealedRoundMessage {
+            sender_id: self.party_index,
+            sealed,
+        })
+    }
+
+    pub fn open<T: DeserializeOwned>(
+        &self,
+        subject: &str,
+        message: &SealedRoundMessage
+    ) -> Result<T> {
+        let ciphertext = message.sealed
+            .get(&self.party_index)
+            .ok_or_else(|| {
+                anyhow!("Message of party {} is not sealed for this party", message.sender_id)
+            })?;
+        let plaintext = decrypt_and_deserialize::<RoundPlaintext<T>>(
+            ciphertext,
+            self.key(message.sender_id)?
+        )?;
+        if plaintext.subject != subject {
+            bail!("Message of party {} was sealed for {}", message.sender_id, plaintext.subject);
+        }
+        Ok(plaintext.message)
+    }
+}
+
+fn session_key(session_id: &str, shared: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    hasher.update(ROUND_KEY_CONTEXT);
+    hasher.update((session_id.len() as u64).to_be_bytes());
+    hasher.update(session_id.as_bytes());
+    hasher.update(shared);
+    Zeroizing::new(hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nkeys::KeyPair;
+
+    #[test]
+    fn only_the_recipient_opens_a_message_of_its_round() {
+        let nodes = [KeyPair::new_user(), KeyPair::new_user(), KeyPair::new_user()];
+        let public_keys: BTreeMap<usize, String> = nodes
+            .iter()
+            .enumerate()
+            .map(|(party, node)| (party + 1, node.public_key()))
+            .collect();
+        let transport = |party: usize| {
+            let seed = nodes[party - 1].seed().unwrap();
+            RoundTransport::new("session-1", party, &seed, &public_keys).unwrap()
+        };
+        let subject = "network.gridlock.nodes.KeySignFrost.session-1.Commit";
+
+        let sealed = transport(1).seal(subject, &"commitment", [2]).unwrap();
+        assert_eq!(transport(2).open::<String>(subject, &sealed).unwrap(), "commitment");
+        assert!(transport(3).open::<String>(subject, &sealed).is_err());
+        assert!(transport(2).open::<String>("network.gridlock.nodes.other", &sealed).is_err());
+
+        let other_session = RoundTransport::new(
+            "session-2",
+            2,
+            &nodes[1].seed().unwrap(),
+            &public_keys
+        ).unwrap();
+        assert!(other_session.open::<String>(subject, &sealed).is_err());
+    }
+}
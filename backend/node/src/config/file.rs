@@ -0,0 +1,255 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::config::{ Config, ConfigProvider };
+use crate::logging;
+use crate::tenant::Access;
+use anyhow::{ Context, Result };
+use serde::{ Deserialize, Serialize };
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{ info, warn };
+
+/// Path of the config file, `guardian.toml` in the working directory by default
+const CONFIG_FILE_VAR: &str = "GUARDIAN_CONFIG";
+const DEFAULT_CONFIG_FILE: &str = "guardian.toml";
+
+/// Settings the node only reads when it starts, changing them takes a restart
+const RESTART_SETTINGS: [&str; 10] = [
+    "STORAGE_DIR",
+    "STORAGE_BACKEND",
+    "NATS_NETWORK",
+    "NATS_USER",
+    "NATS_PASSWORD",
+    "NATS_CREDS_FILE",
+    "NATS_NKEY_SEED_FILE",
+    "NATS_TLS_CA_FILE",
+    "NATS_TLS_CERT_FILE",
+    "NATS_TLS_KEY_FILE",
+];
+
+/// Environment variables set from the config file with the value they were set to. Variables
+/// set by the environment with another value take precedence over the file.
+static APPLIED: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Node settings read from `guardian.toml`. Every setting is the config file form of an
+/// environment variable, which overrides it when set.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GuardianConfig {
+    /// `STORAGE_DIR`
+    pub data_dir: Option<String>,
+    /// `LOG_LEVEL`: "error", "warn", "info", "debug" or "trace"
+    pub log_level: Option<String>,
+    #[serde(default)]
+    pub nats: NatsSettings,
+    #[serde(default)]
+    pub sessions: SessionSettings,
+    #[serde(default)]
+    pub storage: StorageSettings,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NatsSettings {
+    /// `NATS_NETWORK`
+    pub address: Option<String>,
+    /// `NATS_USER`
+    pub user: Option<String>,
+    /// `NATS_PASSWORD`
+    pub password: Option<String>,
+    /// `NATS_CREDS_FILE`
+    pub creds_file: Option<String>,
+    /// `NATS_NKEY_SEED_FILE`
+    pub nkey_seed_file: Option<String>,
+    /// `NATS_TLS_CA_FILE`
+    pub tls_ca_file: Option<String>,
+    /// `NATS_TLS_CERT_FILE`
+    pub tls_cert_file: Option<String>,
+    /// `NATS_TLS_KEY_FILE`
+    pub tls_key_file: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SessionSettings {
+    /// `KEYGEN_SESSION_TIMEOUT_SECS`
+    pub keygen_timeout_secs: Option<u64>,
+    /// `SIGNING_SESSION_TIMEOUT_SECS`
+    pub signing_timeout_secs: Option<u64>,
+    /// `RECOVERY_SESSION_TIMEOUT_SECS`
+    pub recovery_timeout_secs: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StorageSettings {
+    /// `STORAGE_BACKEND`
+    pub backend: Option<String>,
+}
+
+impl GuardianConfig {
+    /// The file named by `GUARDIAN_CONFIG`, or `guardian.toml` if there is one. Nodes without a
+    /// config file are configured by the environment alone.
+    pub fn read() -> Result<Self> {
+        let path = match env::var(CONFIG_FILE_VAR) {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => {
+                let path = PathBuf::from(DEFAULT_CONFIG_FILE);
+                if !path.exists() {
+                    return Ok(GuardianConfig::default());
+                }
+                path
+            }
+        };
+        let content = fs
+            ::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// Values of the settings present in the file, by environment variable
+    fn settings(&self) -> BTreeMap<String, String> {
+        let sessions = &self.sessions;
+        let secs = |value: Option<u64>| value.map(|secs| secs.to_string());
+        [
+            ("STORAGE_DIR", self.data_dir.clone()),
+            ("LOG_LEVEL", self.log_level.clone()),
+            ("NATS_NETWORK", self.nats.address.clone()),
+            ("NATS_USER", self.nats.user.clone()),
+            ("NATS_PASSWORD", self.nats.password.clone()),
+            ("NATS_CREDS_FILE", self.nats.creds_file.clone()),
+            ("NATS_NKEY_SEED_FILE", self.nats.nkey_seed_file.clone()),
+            ("NATS_TLS_CA_FILE", self.nats.tls_ca_file.clone()),
+            ("NATS_TLS_CERT_FILE", self.nats.tls_cert_file.clone()),
+            ("NATS_TLS_KEY_FILE", self.nats.tls_key_file.clone()),
+            ("KEYGEN_SESSION_TIMEOUT_SECS", secs(sessions.keygen_timeout_secs)),
+            ("SIGNING_SESSION_TIMEOUT_SECS", secs(sessions.signing_timeout_secs)),
+            ("RECOVERY_SESSION_TIMEOUT_SECS", secs(sessions.recovery_timeout_secs)),
+            ("STORAGE_BACKEND", self.storage.backend.clone()),
+        ]
+            .into_iter()
+            .filter_map(|(var, value)| value.map(|value| (var.to_string(), value)))
+            .collect()
+    }
+}
+
+/// Settings a reload changed, and those of them that only take effect after a restart
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct ConfigReload {
+    pub changed: Vec<String>,
+    pub restart_required: Vec<String>,
+}
+
+/// Reads the config file into the environment the node is configured from, before anything
+/// reads it. `.env` is loaded first so that its values override the file like the environment.
+pub fn load() -> Result<()> {
+    dotenv::dotenv().ok();
+    let reload = apply(&GuardianConfig::read()?);
+    if !reload.changed.is_empty() {
+        info!("Configured {} from the config file", reload.changed.join(", "));
+    }
+    Ok(())
+}
+
+/// Re-reads the config file. Log level and session timeouts apply right away, settings in
+/// `ConfigReload::restart_required` once the node restarts.
+pub fn reload() -> Result<ConfigReload> {
+    let reload = apply(&GuardianConfig::read()?);
+    if reload.changed.iter().any(|var| var == "LOG_LEVEL") {
+        logging::set_log_level(&env::var("LOG_LEVEL").unwrap_or_default())?;
+    }
+    if !reload.restart_required.is_empty() {
+        warn!("Restart the node to apply {}", reload.restart_required.join(", "));
+    }
+    Ok(reload)
+}
+
+fn apply(config: &GuardianConfig) -> ConfigReload {
+    let mut applied = APPLIED.lock().unwrap();
+    let settings = config.settings();
+    let mut changed = Vec::new();
+    for (var, value) in &settings {
+        let from_file = applied.get(var);
+        match env::var(var) {
+            // Set by the environment rather than a previous load of the file
+            Ok(current) if from_file != Some(&current) => {
+                continue;
+            }
+            Ok(current) if current == *value => {
+                continue;
+            }
+            _ => {}
+        }
+        env::set_var(var, value);
+        applied.insert(var.clone(), value.clone());
+        changed.push(var.clone());
+    }
+    // Settings removed from the file fall back to their defaults
+    let removed: Vec<String> = applied
+        .keys()
+        .filter(|var| !settings.contains_key(*var))
+        .cloned()
+        .collect();
+    for var in removed {
+        if env::var(&var).ok() == applied.remove(&var) {
+            env::remove_var(&var);
+            changed.push(var);
+        }
+    }
+    let restart_required = changed
+        .iter()
+        .filter(|var| RESTART_SETTINGS.contains(&var.as_str()))
+        .cloned()
+        .collect();
+    ConfigReload { changed, restart_required }
+}
+
+/// Re-reads the config file of the node
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ReloadConfigCommand {}
+
+impl JsonCommand for ReloadConfigCommand {
+    type Response = ConfigReload;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        Config::reload()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_settings_and_refuses_unknown_ones() {
+        let config = GuardianConfig::parse(
+            r#"
+            data_dir = "/var/lib/guardian"
+            log_level = "debug"
+
+            [nats]
+            address = "nats://hub:4222"
+
+            [sessions]
+            signing_timeout_secs = 60
+            "#
+        ).unwrap();
+        let settings = config.settings();
+        assert_eq!(settings["STORAGE_DIR"], "/var/lib/guardian");
+        assert_eq!(settings["NATS_NETWORK"], "nats://hub:4222");
+        assert_eq!(settings["SIGNING_SESSION_TIMEOUT_SECS"], "60");
+        assert!(!settings.contains_key("NATS_USER"));
+        assert!(GuardianConfig::parse("storage_dir = \"/tmp\"").is_err());
+    }
+}
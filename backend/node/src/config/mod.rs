@@ -1,12 +1,23 @@
 use cfg_if::cfg_if;
 use std::path::PathBuf;
 
+pub mod file;
+
 pub trait ConfigProvider {
     fn create_data_dirs() -> std::io::Result<()>;
     fn get_nats_address() -> String;
     fn get_key_storage_path(key_id: &str, index: usize) -> PathBuf;
     fn get_key_info_storage_path(key_id: &str) -> PathBuf;
     fn get_gridlock_directory() -> PathBuf;
+
+    /// Applies the config file, see `file::load`
+    fn load() -> anyhow::Result<()> {
+        file::load()
+    }
+
+    fn reload() -> anyhow::Result<file::ConfigReload> {
+        file::reload()
+    }
 }
 
 cfg_if! {
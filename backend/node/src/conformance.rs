@@ -0,0 +1,448 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::communication::loopback::LoopbackMessenger;
+use crate::communication::protocol::{
+    KeyGenAllRounds,
+    KeySignCGGMPAllRounds,
+    KeySignEdDSAAllRounds,
+    PresignECDSAAllRounds,
+};
+use crate::keygen::eddsa::client::KeyGenClient;
+use crate::keygen::key_import::{ deal, public_shares, verify_share };
+use crate::keygen::ShareParams;
+use crate::session_manager;
+use crate::signing::presigning::online::OnlineSignClient;
+use crate::signing::presigning::presign::PresignClient;
+use crate::signing::eddsa::client::EdDSAKeySignClient;
+use crate::storage::{ ECDSA, EDDSA };
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Result };
+use curv::arithmetic::Converter;
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::{ Curve, Ed25519, Point, Scalar, Secp256k1 };
+use curv::BigInt;
+use futures::future::join_all;
+use multi_party_eddsa::protocols::Signature as EdDSASignature;
+use paillier::{ KeyGeneration, Paillier };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256, Sha512 };
+use std::convert::TryFrom;
+use tracing::{ info, warn };
+use zk_paillier::zkproofs::DLogStatement;
+
+/// Indices of the fixed test shares that take part in reconstruction (a 3-of-5 quorum)
+const QUORUM_INDICES: [u16; 3] = [1, 3, 5];
+/// Fixed, non-secret polynomial coefficients used to split the test vector keys into shares
+const TEST_COEFFICIENTS: [&str; 2] = [
+    "0d3f2c4a6b8e9f10213243546576879809a1b2c3d4e5f60718293a4b5c6d7e8f",
+    "7a6b5c4d3e2f1a0b9c8d7e6f5a4b3c2d1e0f9a8b7c6d5e4f3a2b1c0d9e8f7a6b",
+];
+/// Party indices of the simulated signing sessions, the vector keys are dealt as 2-of-3 keys
+const SESSION_PARTIES: [usize; 3] = [1, 2, 3];
+/// Library threshold of the dealt keys, one below the number of signers
+const SESSION_THRESHOLD: usize = 1;
+
+// secp256k1 RFC 6979 vector: private key 1, message sha256("Satoshi Nakamoto")
+const ECDSA_SECRET_KEY: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+const ECDSA_PUBLIC_KEY: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+const ECDSA_MESSAGE: &[u8] = b"Satoshi Nakamoto";
+const ECDSA_SIGNATURE: &str =
+    "934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7e6a4a49860d7a6ab210ee3d82442ce9d2b916064108014783e923ec36b49743e2ffa1c4496f01a512aafd9e5";
+
+// RFC 8032 section 7.1, test 1
+const EDDSA_SEED: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+const EDDSA_PUBLIC_KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+const EDDSA_MESSAGE: &[u8] = b"";
+const EDDSA_SIGNATURE: &str =
+    "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ConformanceSuite {
+    ECDSA,
+    EDDSA,
+}
+
+/// Checks the node's cryptography against published test vectors.
+///
+/// Threshold signatures produced by the live protocols are randomized, so they can never be
+/// byte-identical to a published vector. Instead, the vector keys are dealt to simulated parties
+/// that run the node's signing clients over loopback messengers, and the signatures they produce
+/// are verified against the vector public keys with an independent implementation. Share
+/// interpolation and signature verification are checked against the vectors as well.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ConformanceCheckCommand {
+    /// Suites to run, all of them when empty
+    pub conformance_check: Vec<ConformanceSuite>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConformanceCheckResult {
+    pub suite: ConformanceSuite,
+    pub check: String,
+    pub passed: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConformanceReport {
+    pub passed: bool,
+    pub results: Vec<ConformanceCheckResult>,
+}
+
+impl JsonCommand for ConformanceCheckCommand {
+    type Response = ConformanceReport;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let suites = if self.conformance_check.is_empty() {
+            vec![ConformanceSuite::ECDSA, ConformanceSuite::EDDSA]
+        } else {
+            self.conformance_check
+        };
+        Ok(run_conformance_checks(&suites))
+    }
+}
+
+pub fn run_conformance_checks(suites: &[ConformanceSuite]) -> ConformanceReport {
+    let mut results = Vec::new();
+    for suite in suites {
+        let checks: Vec<(&str, fn() -> Result<()>)> = match suite {
+            ConformanceSuite::ECDSA =>
+                vec![
+                    ("share_reconstruction", ecdsa_share_reconstruction),
+                    ("deterministic_signature", ecdsa_deterministic_signature),
+                    ("signature_verification", ecdsa_signature_verification),
+                    ("threshold_signature", ecdsa_threshold_signature)
+                ],
+            ConformanceSuite::EDDSA =>
+                vec![
+                    ("share_reconstruction", eddsa_share_reconstruction),
+                    ("signature_verification", eddsa_signature_verification),
+                    ("threshold_signature", eddsa_threshold_signature)
+                ],
+        };
+        for (check, run) in checks {
+            let result = run();
+            if let Err(err) = &result {
+                warn!("Conformance check {:?}/{} failed: {}", suite, check, err);
+            }
+            results.push(ConformanceCheckResult {
+                suite: suite.clone(),
+                check: check.to_string(),
+                passed: result.is_ok(),
+                error: result.err().map(|err| err.to_string()),
+            });
+        }
+    }
+
+    let passed = results.iter().all(|r| r.passed);
+    info!("Conformance checks finished, passed: {}", passed);
+    ConformanceReport { passed, results }
+}
+
+/// Splits `secret` with the fixed test polynomial and recombines the quorum's shares
+fn split_and_reconstruct<C: Curve>(secret: &Scalar<C>) -> Scalar<C> {
+    let coefficients = TEST_COEFFICIENTS.iter()
+        .map(|c| Scalar::<C>::from_bigint(&BigInt::from_hex(c).expect("valid test coefficient")))
+        .collect::<Vec<_>>();
+
+    let points = QUORUM_INDICES.iter()
+        .map(|i| Scalar::<C>::from_bigint(&BigInt::from(*i as u32)))
+        .collect::<Vec<_>>();
+    let shares = points
+        .iter()
+        .map(|x| {
+            let mut share = secret.clone();
+            let mut power = x.clone();
+            for coefficient in &coefficients {
+                share = share + coefficient * &power;
+                power = &power * x;
+            }
+            share
+        })
+        .collect::<Vec<_>>();
+
+    VerifiableSS::<C>::lagrange_interpolation_at_zero(&points, &shares)
+}
+
+fn ecdsa_secret_key() -> Scalar<Secp256k1> {
+    Scalar::from_bigint(&BigInt::from_hex(ECDSA_SECRET_KEY).expect("valid test key"))
+}
+
+fn ecdsa_message_hash() -> Vec<u8> {
+    Sha256::digest(ECDSA_MESSAGE).to_vec()
+}
+
+fn ecdsa_share_reconstruction() -> Result<()> {
+    let reconstructed = split_and_reconstruct(&ecdsa_secret_key());
+    let public_key = Point::<Secp256k1>::generator() * &reconstructed;
+    if hex::encode(&*public_key.to_bytes(true)) != ECDSA_PUBLIC_KEY {
+        bail!("Reconstructed key does not match the test vector public key");
+    }
+    Ok(())
+}
+
+fn ecdsa_deterministic_signature() -> Result<()> {
+    use secp256k1::{ Message, Secp256k1 as Context, SecretKey };
+
+    let reconstructed = split_and_reconstruct(&ecdsa_secret_key());
+    let secret_key = SecretKey::from_slice(&pad_32(&reconstructed.to_bigint().to_bytes()))?;
+    let message = Message::from_slice(&ecdsa_message_hash())?;
+    let signature = Context::signing_only().sign(&message, &secret_key);
+
+    if hex::encode(signature.serialize_compact()) != ECDSA_SIGNATURE {
+        bail!("Signature does not match the test vector");
+    }
+    Ok(())
+}
+
+fn ecdsa_signature_verification() -> Result<()> {
+    use secp256k1::{ Message, PublicKey, Secp256k1 as Context, Signature };
+
+    let public_key = PublicKey::from_slice(&hex::decode(ECDSA_PUBLIC_KEY)?)?;
+    let signature = Signature::from_compact(&hex::decode(ECDSA_SIGNATURE)?)?;
+    let context = Context::verification_only();
+
+    context.verify(&Message::from_slice(&ecdsa_message_hash())?, &signature, &public_key)?;
+
+    let tampered = Sha256::digest(b"Satoshi Nakamoto!").to_vec();
+    if context.verify(&Message::from_slice(&tampered)?, &signature, &public_key).is_ok() {
+        bail!("Signature verified against a tampered message");
+    }
+    Ok(())
+}
+
+/// Keyshares of the vector key for the simulated parties, dealt like an imported key
+fn ecdsa_session_keyshares() -> Result<Vec<ECDSA>> {
+    let party_count = SESSION_PARTIES.len();
+    let (vss_scheme_vec, shares) = deal(
+        &ecdsa_secret_key(),
+        SESSION_THRESHOLD as u16,
+        party_count as u16
+    );
+    let paillier_keys = (0..party_count).map(|_| Paillier::keypair().keys()).collect::<Vec<_>>();
+    let paillier_key_vec = paillier_keys
+        .iter()
+        .map(|(ek, _)| ek.clone())
+        .collect::<Vec<_>>();
+    // The range proofs only need prover and verifier to agree on h1, h2, N tilde. Proper ones
+    // take minutes to generate and would not check anything more here.
+    let dlog_statements = paillier_key_vec
+        .iter()
+        .map(|ek| DLogStatement { N: ek.n.clone(), g: BigInt::from(4), ni: BigInt::from(9) })
+        .collect::<Vec<_>>();
+    shares
+        .into_iter()
+        .zip(paillier_keys)
+        .zip(SESSION_PARTIES)
+        .map(|((x_i, (_, dk)), party_index)| {
+            Ok(ECDSA {
+                threshold: SESSION_THRESHOLD,
+                y_sum: verify_share(&x_i, party_index, SESSION_THRESHOLD, &vss_scheme_vec)?,
+                x_i,
+                party_index,
+                public_key_vec: public_shares(&vss_scheme_vec),
+                vss_scheme_vec: vss_scheme_vec.clone(),
+                paillier_key_vec: paillier_key_vec.clone(),
+                h1_h2_N_tilde_vec: dlog_statements.clone(),
+                paillier_dk: dk.into(),
+            })
+        })
+        .collect()
+}
+
+/// Signs the vector message with the presign and online signing clients of CGGMP sessions
+fn ecdsa_threshold_signature() -> Result<()> {
+    use secp256k1::{ Message, PublicKey, Secp256k1 as Context, Signature };
+
+    let keyshares = ecdsa_session_keyshares()?;
+    let message = ecdsa_message_hash();
+    let presign_messengers = LoopbackMessenger::<PresignECDSAAllRounds>::network(
+        &SESSION_PARTIES
+    )?;
+    let sign_messengers = LoopbackMessenger::<KeySignCGGMPAllRounds>::network(&SESSION_PARTIES)?;
+    let signatures = session_manager::runtime().block_on(
+        join_all(
+            presign_messengers
+                .into_iter()
+                .zip(sign_messengers)
+                .zip(&keyshares)
+                .map(|((presign_messenger, sign_messenger), keyshare)| {
+                    let message = &message;
+                    async move {
+                        let presign_client = PresignClient {
+                            peer_messenger: presign_messenger,
+                            all_party_indices: SESSION_PARTIES.to_vec(),
+                        };
+                        let presignature = presign_client.create_presignature(
+                            "conformance",
+                            "conformance",
+                            keyshare
+                        ).await?;
+                        let sign_client = OnlineSignClient {
+                            peer_messenger: sign_messenger,
+                            all_party_indices: SESSION_PARTIES.to_vec(),
+                        };
+                        sign_client.create_signature(&presignature, message, &keyshare.y_sum).await
+                    }
+                })
+        )
+    );
+
+    let public_key = PublicKey::from_slice(&hex::decode(ECDSA_PUBLIC_KEY)?)?;
+    let message = Message::from_slice(&message)?;
+    for signature in signatures {
+        let signature = signature?;
+        let mut compact = pad_32(&signature.r.to_bigint().to_bytes());
+        compact.extend(pad_32(&signature.s.to_bigint().to_bytes()));
+        Context::verification_only()
+            .verify(&message, &Signature::from_compact(&compact)?, &public_key)
+            .map_err(|_| anyhow!("Threshold signature does not verify with the vector key"))?;
+    }
+    Ok(())
+}
+
+/// Derives the clamped Ed25519 secret scalar from an RFC 8032 seed
+fn eddsa_secret_scalar() -> Result<Scalar<Ed25519>> {
+    let seed = hex::decode(EDDSA_SEED)?;
+    let mut scalar_bytes = Sha512::digest(&seed)[..32].to_vec();
+    scalar_bytes[0] &= 248;
+    scalar_bytes[31] &= 127;
+    scalar_bytes[31] |= 64;
+    Ok(Scalar::from_bigint(&le_bytes_to_bigint(&scalar_bytes)))
+}
+
+fn eddsa_public_key() -> Result<Point<Ed25519>> {
+    Point::<Ed25519>::from_bytes(&hex::decode(EDDSA_PUBLIC_KEY)?).map_err(|err|
+        anyhow!("Invalid test vector public key: {:?}", err)
+    )
+}
+
+fn eddsa_share_reconstruction() -> Result<()> {
+    let reconstructed = split_and_reconstruct(&eddsa_secret_scalar()?);
+    if Point::<Ed25519>::generator() * &reconstructed != eddsa_public_key()? {
+        bail!("Reconstructed key does not match the test vector public key");
+    }
+    Ok(())
+}
+
+fn eddsa_signature_verification() -> Result<()> {
+    let bytes = hex::decode(EDDSA_SIGNATURE)?;
+    let signature = EdDSASignature {
+        R: Point::<Ed25519>
+            ::from_bytes(&bytes[..32])
+            .map_err(|err| anyhow!("Invalid test vector signature point: {:?}", err))?,
+        s: Scalar::from_bigint(&le_bytes_to_bigint(&bytes[32..])),
+    };
+    let public_key = eddsa_public_key()?;
+
+    signature
+        .verify(EDDSA_MESSAGE, &public_key)
+        .map_err(|_| anyhow!("Test vector signature did not pass verification"))?;
+
+    if signature.verify(b"tampered", &public_key).is_ok() {
+        bail!("Signature verified against a tampered message");
+    }
+    Ok(())
+}
+
+/// Signs the vector message with the ephemeral keygen and signing clients of EdDSA sessions
+fn eddsa_threshold_signature() -> Result<()> {
+    use ed25519_dalek::{ PublicKey, Signature, Verifier };
+
+    let party_count = SESSION_PARTIES.len();
+    let (vss_scheme_vec, shares) = deal(
+        &eddsa_secret_scalar()?,
+        SESSION_THRESHOLD as u16,
+        party_count as u16
+    );
+    let keyshares = shares
+        .into_iter()
+        .zip(SESSION_PARTIES)
+        .map(|(x_i, party_index)| {
+            Ok(EDDSA {
+                threshold: SESSION_THRESHOLD,
+                party_index,
+                y_sum: verify_share(&x_i, party_index, SESSION_THRESHOLD, &vss_scheme_vec)?,
+                x_i,
+                vss_scheme_vec: vss_scheme_vec.clone(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let keygen_messengers = LoopbackMessenger::<KeyGenAllRounds>::network(&SESSION_PARTIES)?;
+    let sign_messengers = LoopbackMessenger::<KeySignEdDSAAllRounds>::network(&SESSION_PARTIES)?;
+    let signatures = session_manager::runtime().block_on(
+        join_all(
+            keygen_messengers
+                .into_iter()
+                .zip(sign_messengers)
+                .zip(&keyshares)
+                .map(|((keygen_messenger, sign_messenger), keyshare)| async move {
+                    let share_params = || ShareParams {
+                        party_count,
+                        party_index: keyshare.party_index,
+                        threshold: keyshare.threshold,
+                    };
+                    let keygen_client = KeyGenClient {
+                        peer_messenger: keygen_messenger,
+                        share_params: share_params(),
+                        all_party_indices: SESSION_PARTIES.to_vec(),
+                    };
+                    let ephemeral_keyshare = keygen_client
+                        .create_ephemeral_shared_key(EDDSA_MESSAGE).await?;
+                    let sign_client = EdDSAKeySignClient {
+                        peer_messenger: sign_messenger,
+                        share_params: share_params(),
+                        all_party_indices: SESSION_PARTIES.to_vec(),
+                    };
+                    sign_client
+                        .create_shared_sig(EDDSA_MESSAGE, &ephemeral_keyshare, keyshare).await
+                })
+        )
+    );
+
+    let public_key = PublicKey::from_bytes(&hex::decode(EDDSA_PUBLIC_KEY)?).map_err(|err|
+        anyhow!("Invalid test vector public key: {}", err)
+    )?;
+    for signature in signatures {
+        let signature = signature?;
+        let mut bytes = signature.R.to_bytes(true).to_vec();
+        let mut s = pad_32(&signature.s.to_bigint().to_bytes());
+        s.reverse();
+        bytes.extend(s);
+        let signature = Signature::try_from(&bytes[..]).map_err(|err|
+            anyhow!("Threshold signature is malformed: {}", err)
+        )?;
+        public_key
+            .verify(EDDSA_MESSAGE, &signature)
+            .map_err(|_| anyhow!("Threshold signature does not verify with the vector key"))?;
+    }
+    Ok(())
+}
+
+fn le_bytes_to_bigint(bytes: &[u8]) -> BigInt {
+    let mut be_bytes = bytes.to_vec();
+    be_bytes.reverse();
+    BigInt::from_bytes(&be_bytes)
+}
+
+fn pad_32(bytes: &[u8]) -> Vec<u8> {
+    let mut padded = vec![0u8; 32usize.saturating_sub(bytes.len())];
+    padded.extend_from_slice(bytes);
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_conformance_checks_pass() {
+        let report = run_conformance_checks(&[ConformanceSuite::ECDSA, ConformanceSuite::EDDSA]);
+        assert!(report.passed, "{:?}", report.results);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::communication::blocking;
 use crate::db::NodeDbContext;
 
 use mvp::{
@@ -31,9 +32,9 @@ pub struct User {
 
 //handler that is called from node bin to create a new user
 pub fn handle_new_user_credentials(
-    connection: nats::Connection,
+    connection: blocking::Connection,
     db_context: &mut NodeDbContext,
-    message: nats::Message
+    message: blocking::Message
 ) -> Result<(), anyhow::Error> {
     let session = serde_json::from_slice::<NewUserSession>(&message.data);
     store_new_user_creds(connection, db_context, session.unwrap())
@@ -41,16 +42,16 @@ pub fn handle_new_user_credentials(
 
 // handler that is called from node bin. checks credentials when a user tries to log in
 pub fn handle_user_auth_credentials(
-    connection: nats::Connection,
+    connection: blocking::Connection,
     db_context: &mut NodeDbContext,
-    message: nats::Message
+    message: blocking::Message
 ) -> Result<(), anyhow::Error> {
     let session = serde_json::from_slice::<UserAuthSession>(&message.data);
     auth_user_creds(connection, db_context, session.unwrap())
 }
 
 fn auth_user_creds(
-    connection: nats::Connection,
+    connection: blocking::Connection,
     db_context: &mut NodeDbContext,
     message: UserAuthSession
 ) -> Result<(), anyhow::Error> {
@@ -126,7 +127,7 @@ fn time_left_from_date_to_now(date: DateTime<Utc>) -> chrono::Duration {
 
 // creates user and returns  response to comm hub
 fn store_new_user_creds(
-    connection: nats::Connection,
+    connection: blocking::Connection,
     db_context: &mut NodeDbContext,
     message: NewUserSession
 ) -> Result<(), anyhow::Error> {
@@ -207,7 +208,7 @@ fn update_user_fade_date(
 // *** GENERIC FUNCTIONS *** //
 
 fn send_result<T: Serialize>(
-    connection: nats::Connection,
+    connection: blocking::Connection,
     msg: T,
     subject: &str
 ) -> Result<(), anyhow::Error> {
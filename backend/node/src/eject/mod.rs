@@ -0,0 +1,461 @@
+pub mod wallet_format;
+
+use anyhow::{ anyhow, bail, Result };
+use curv::elliptic::curves::{ Curve, Ed25519, Point, Scalar, Secp256k1 };
+use curv::{ cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS, BigInt };
+use itertools::Itertools;
+use serde::{ Deserialize, Serialize };
+use tracing::{ error, info };
+use zeroize::{ Zeroize, Zeroizing };
+
+use crate::auth::{ client_e2e_decrypt_secret, e2e_encrypt };
+use crate::command::{ JsonCommand, MsgContext };
+use crate::node::NodeIdentity;
+use crate::storage::{ KeyshareAccessor, ECDSA, EDDSA };
+use crate::tenant::{ Access, TenantAuth };
+use wallet_format::WalletFormat;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct EjectInfo {
+    pub key_id: String,
+    pub share_info: EjectShareInfo,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct KeyReconstructionResult {
+    pub key_id: String,
+    /// Scalar JSON, or the key in the requested wallet format, encrypted to the client e2e key
+    pub key: String,
+    /// E2E public key of the node, the client decrypts with it
+    pub node_e2e_public_key: String,
+}
+
+/// The reconstructed private key is wiped once the response has been serialized
+impl Drop for KeyReconstructionResult {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum EjectShareInfo {
+    Secp256k1(Scalar<Secp256k1>, usize),
+    Ed25519(Scalar<Ed25519>, usize),
+}
+
+impl From<EDDSA> for EjectShareInfo {
+    fn from(ed: EDDSA) -> Self {
+        Self::Ed25519(ed.x_i, ed.party_index)
+    }
+}
+
+impl From<ECDSA> for EjectShareInfo {
+    fn from(ec: ECDSA) -> Self {
+        Self::Secp256k1(ec.x_i, ec.party_index)
+    }
+}
+
+/// VSS commitments of a key, every share of the key has to match the public share they commit to
+/// at its index
+enum KeyCommitments {
+    Secp256k1(Vec<VerifiableSS<Secp256k1>>),
+    Ed25519(Vec<VerifiableSS<Ed25519>>),
+}
+
+impl KeyCommitments {
+    /// Commitments stored with this node's share of the key
+    fn read(key_id: &str, email: &str) -> Result<Self> {
+        if let Ok(ka) = KeyshareAccessor::<ECDSA>::read_only_with_email(key_id, email) {
+            Ok(Self::Secp256k1(ka.key.vss_scheme_vec.clone()))
+        } else if let Ok(ka) = KeyshareAccessor::<EDDSA>::read_only_with_email(key_id, email) {
+            Ok(Self::Ed25519(ka.key.vss_scheme_vec.clone()))
+        } else {
+            bail!("No keyshare of key {} to verify the supplied shares against", key_id)
+        }
+    }
+
+    fn verify(&self, share: &EjectShareInfo) -> bool {
+        match (self, share) {
+            (Self::Secp256k1(vss), EjectShareInfo::Secp256k1(x_i, index)) => {
+                Point::generator() * x_i == public_share(vss, *index)
+            }
+            (Self::Ed25519(vss), EjectShareInfo::Ed25519(x_i, index)) => {
+                Point::generator() * x_i == public_share(vss, *index)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Shares needed to reconstruct a key, one more than the threshold stored with this node's share
+fn required_shares(key_id: &str, email: &str) -> Result<usize> {
+    if let Ok(ka) = KeyshareAccessor::<ECDSA>::read_only_with_email(key_id, email) {
+        Ok(ka.key.threshold + 1)
+    } else if let Ok(ka) = KeyshareAccessor::<EDDSA>::read_only_with_email(key_id, email) {
+        Ok(ka.key.threshold + 1)
+    } else {
+        bail!("No keyshare of key {} to take the threshold from", key_id)
+    }
+}
+
+fn public_share<C: Curve>(vss_scheme_vec: &[VerifiableSS<C>], index: usize) -> Point<C> {
+    vss_scheme_vec
+        .iter()
+        .fold(Point::zero(), |sum, vss| sum + vss.get_point_commitment(index as u16))
+}
+
+/// Returns the shares of keys of one account, to the client holding the account's access key
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct EjectSharesCommand {
+    key_ids_to_eject: Vec<String>,
+    authorization: TenantAuth,
+}
+
+/// Shares of the ejected keys, readable only by the client that requested them
+#[derive(Deserialize, Serialize, Debug)]
+pub struct EncryptedEjectInfo {
+    /// E2E public key of the node, the client decrypts with it
+    pub node_e2e_public_key: String,
+    /// The `EjectInfo` list as JSON, encrypted to the client e2e public key
+    pub encrypted_eject_info: String,
+}
+
+impl JsonCommand for EjectSharesCommand {
+    type Response = EncryptedEjectInfo;
+
+    fn access(&self) -> Access<'_> {
+        let key_ids = self.key_ids_to_eject.iter().cloned().unique().collect();
+        Access::Account { auth: Some(&self.authorization), key_ids }
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let key_ids = self.key_ids_to_eject.iter().cloned().unique().collect::<Vec<String>>();
+        let node = NodeIdentity::cached()?;
+        let email = &self.authorization.email;
+
+        let eject_info = Zeroizing::new(
+            serde_json::to_string(&retrieve_eject_info_from_key_ids(&key_ids, email)?)?
+        );
+        let encrypted_eject_info = e2e_encrypt(
+            eject_info.as_bytes(),
+            &self.authorization.client_e2e_public_key,
+            &node.e2e_private_key
+        )?;
+        info!("Ejected shares of {} keys for {}", key_ids.len(), self.authorization.email);
+        Ok(EncryptedEjectInfo {
+            node_e2e_public_key: node.e2e_public_key,
+            encrypted_eject_info,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct EjectKeysCommand {
+    key_ids: Vec<String>,
+    eject_info: Vec<Vec<EjectInfo>>,
+    /// Proof of the account the keys belong to
+    authorization: TenantAuth,
+    /// Returns the keys in a wallet format instead of as curve scalars
+    #[serde(default)]
+    wallet_format: Option<WalletFormat>,
+}
+
+enum ReconstructedKey {
+    Secp256k1(Scalar<Secp256k1>),
+    Ed25519(Scalar<Ed25519>),
+}
+
+impl ReconstructedKey {
+    fn to_json(&self) -> Result<String> {
+        Ok(match self {
+            Self::Secp256k1(secret) => serde_json::to_string(secret)?,
+            Self::Ed25519(secret) => serde_json::to_string(secret)?,
+        })
+    }
+
+    fn export(&self, format: &WalletFormat, password: Option<&str>) -> Result<String> {
+        match (self, format) {
+            (Self::Secp256k1(secret), WalletFormat::Wif { testnet }) => {
+                Ok(wallet_format::wif(secret, *testnet))
+            }
+            (Self::Secp256k1(secret), WalletFormat::EthereumKeystore { .. }) => {
+                wallet_format::ethereum_keystore(secret, password.unwrap_or_default())
+            }
+            (Self::Ed25519(secret), WalletFormat::Ed25519Base58) => {
+                Ok(wallet_format::ed25519_base58(secret))
+            }
+            (Self::Secp256k1(_), format) => {
+                bail!("secp256k1 keys can't be exported as {:?}", format)
+            }
+            (Self::Ed25519(_), format) => bail!("Ed25519 keys can't be exported as {:?}", format),
+        }
+    }
+}
+
+impl JsonCommand for EjectKeysCommand {
+    type Response = Vec<KeyReconstructionResult>;
+
+    fn access(&self) -> Access<'_> {
+        let key_ids = self.key_ids.iter().cloned().unique().collect();
+        Access::Account { auth: Some(&self.authorization), key_ids }
+    }
+
+    fn execute_message(mut self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        self.retrieve_keys()
+    }
+}
+
+impl EjectKeysCommand {
+    /// Combines two sets of imported keyshares with the set owned by this device to recover the associated private keys
+    fn retrieve_keys(&mut self) -> Result<Vec<KeyReconstructionResult>> {
+        let key_ids = self.key_ids.clone().into_iter().unique().collect::<Vec<String>>();
+        let email = &self.authorization.email;
+        for key_id in &key_ids {
+            verify_supplied_shares(key_id, email, &self.eject_info)?;
+        }
+        let owned_shares = retrieve_eject_info_from_key_ids(&key_ids, email)?;
+
+        self.eject_info.push(owned_shares);
+        let reformed_keys = combine_keyshares(&key_ids, email, &self.eject_info);
+        encrypt_keys(&self.authorization, self.wallet_format.as_ref(), reformed_keys)
+    }
+}
+
+/// Private keys only leave the node encrypted to the client e2e key of the proven account
+fn encrypt_keys(
+    authorization: &TenantAuth,
+    wallet_format: Option<&WalletFormat>,
+    reformed_keys: Vec<(String, ReconstructedKey)>
+) -> Result<Vec<KeyReconstructionResult>> {
+    let node = NodeIdentity::cached()?;
+    let client_e2e_public_key = &authorization.client_e2e_public_key;
+    let password = match wallet_format {
+        Some(WalletFormat::EthereumKeystore { encrypted_password }) => {
+            let password = client_e2e_decrypt_secret(
+                encrypted_password,
+                &node.e2e_private_key,
+                client_e2e_public_key
+            ).map_err(|err| anyhow!("Failed to decrypt the keystore password: {}", err))?;
+            wallet_format::check_keystore_password(&password)?;
+            Some(password)
+        }
+        _ => None,
+    };
+    let results = reformed_keys
+        .into_iter()
+        .map(|(key_id, key)| {
+            let plaintext = Zeroizing::new(match wallet_format {
+                Some(format) => key.export(format, password.as_deref().map(String::as_str))?,
+                None => key.to_json()?,
+            });
+            let key = e2e_encrypt(
+                plaintext.as_bytes(),
+                client_e2e_public_key,
+                &node.e2e_private_key
+            )?;
+            Ok(KeyReconstructionResult {
+                key_id,
+                key,
+                node_e2e_public_key: node.e2e_public_key.clone(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(format) = wallet_format {
+        info!("Exported {} keys as {:?}", results.len(), format);
+    }
+    Ok(results)
+}
+
+fn retrieve_eject_info_from_key_ids(key_ids: &[String], email: &str) -> Result<Vec<EjectInfo>> {
+    let eject_info = key_ids
+        .iter()
+        .filter_map(|key_id| {
+            if let Ok(ka) = KeyshareAccessor::<ECDSA>::read_only_with_email(key_id, email) {
+                let share_info = EjectShareInfo::from(ka.key);
+                Some(EjectInfo {
+                    key_id: key_id.to_string(),
+                    share_info,
+                })
+            } else if let Ok(ka) = KeyshareAccessor::<EDDSA>::read_only_with_email(key_id, email) {
+                let share_info = EjectShareInfo::from(ka.key);
+                Some(EjectInfo {
+                    key_id: key_id.to_string(),
+                    share_info,
+                })
+            } else {
+                None
+            }
+        })
+        .collect();
+    Ok(eject_info)
+}
+
+/// Checks the supplied shares of a key against its commitments before they are interpolated, a
+/// corrupted share would silently yield the wrong key. Contributors are numbered by the position
+/// of their set in `eject_info`.
+fn verify_supplied_shares(
+    key_id: &str,
+    email: &str,
+    eject_info_vec: &[Vec<EjectInfo>]
+) -> Result<()> {
+    let commitments = KeyCommitments::read(key_id, email)?;
+    let invalid = eject_info_vec
+        .iter()
+        .enumerate()
+        .flat_map(|(contributor, eject_info_set)| {
+            eject_info_set
+                .iter()
+                .filter(|x| x.key_id == key_id && !commitments.verify(&x.share_info))
+                .map(move |x| (contributor, share_index(&x.share_info)))
+        })
+        .map(|(contributor, index)| format!("contributor {} (share {})", contributor, index))
+        .collect::<Vec<String>>();
+    if !invalid.is_empty() {
+        let err = anyhow!("Invalid shares of key {} from {}", key_id, invalid.join(", "));
+        error!("{}", err);
+        return Err(err);
+    }
+    Ok(())
+}
+
+fn share_index(share_info: &EjectShareInfo) -> usize {
+    match share_info {
+        EjectShareInfo::Secp256k1(_, index) | EjectShareInfo::Ed25519(_, index) => *index,
+    }
+}
+
+fn combine_keyshares(
+    key_ids: &[String],
+    email: &str,
+    eject_info_vec: &[Vec<EjectInfo>]
+) -> Vec<(String, ReconstructedKey)> {
+    key_ids
+        .iter()
+        .filter_map(|key_id| {
+            let shares = collect_shares_by_key_id_from_supplied_keyshares(key_id, eject_info_vec);
+            let reconstructed = required_shares(key_id, email).and_then(|required|
+                reconstruct_key_from_collected_eject_info(&shares, required)
+            );
+            match reconstructed {
+                Ok(key) => Some((key_id.clone(), key)),
+                Err(err) => {
+                    error!("Unable to reconstruct key with id {key_id}: {err}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn reconstruct_key_from_collected_eject_info(
+    eject_infos: &[EjectShareInfo],
+    required: usize
+) -> Result<ReconstructedKey> {
+    if eject_infos.len() < required {
+        bail!("Not enough keyshares found to reconstruct private key");
+    }
+
+    let mut secp_scalars = Vec::new();
+    let mut ed25519_scalars = Vec::new();
+    let mut indices = Vec::new();
+    eject_infos.iter().for_each(|x| {
+        match x {
+            EjectShareInfo::Secp256k1(scalar, index) => {
+                secp_scalars.push(scalar.clone());
+                indices.push(*index);
+            }
+            EjectShareInfo::Ed25519(scalar, index) => {
+                ed25519_scalars.push(scalar.clone());
+                indices.push(*index);
+            }
+        }
+    });
+
+    if let Some(key) = reconstruct_key::<Secp256k1>(&indices, &secp_scalars, required) {
+        Ok(ReconstructedKey::Secp256k1(key))
+    } else if let Some(key) = reconstruct_key::<Ed25519>(&indices, &ed25519_scalars, required) {
+        Ok(ReconstructedKey::Ed25519(key))
+    } else {
+        bail!(
+            "Not enough keyshares of same key type found to reconstruct private key (this shouldn't happen!)"
+        );
+    }
+}
+
+fn collect_shares_by_key_id_from_supplied_keyshares(
+    key_id: &str,
+    eject_info_vec: &[Vec<EjectInfo>]
+) -> Vec<EjectShareInfo> {
+    eject_info_vec
+        .iter()
+        .enumerate()
+        .filter_map(|(set_index, eject_info_set)| {
+            match eject_info_set.iter().find(|x| x.key_id == key_id) {
+                Some(EjectInfo { key_id: _, share_info }) => Some(share_info.clone()),
+                None => {
+                    info!("No eject info for key id {} in set {}", key_id, set_index);
+                    None
+                }
+            }
+        })
+        .collect::<Vec<EjectShareInfo>>()
+}
+
+fn reconstruct_key<C>(
+    indices: &[usize],
+    shares: &[Scalar<C>],
+    required: usize
+) -> Option<Scalar<C>>
+    where C: Curve
+{
+    if shares.len() != indices.len() || shares.len() < required {
+        return None;
+    }
+
+    let points = indices
+        .iter()
+        .map(|i| {
+            let index_bn = BigInt::from(*i as u32);
+            index_bn.into()
+        })
+        .collect::<Vec<Scalar<C>>>();
+    Some(VerifiableSS::<C>::lagrange_interpolation_at_zero(&points, shares))
+}
+
+fn remove_first_and_last_and_backslash(value: &str) -> String {
+    let mut chars = value.chars();
+    chars.next();
+    chars.next_back();
+    chars.as_str().replace("\\", "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_are_checked_against_commitments() {
+        let secret = Scalar::<Ed25519>::random();
+        let (vss, shares) = VerifiableSS::<Ed25519>::share_at_indices(2, 3, &secret, &[1, 2, 3]);
+        let commitments = KeyCommitments::Ed25519(vec![vss]);
+        for (i, share) in shares.iter().enumerate() {
+            assert!(commitments.verify(&EjectShareInfo::Ed25519(share.clone(), i + 1)));
+        }
+        assert!(!commitments.verify(&EjectShareInfo::Ed25519(shares[0].clone(), 2)));
+        let corrupted = &shares[1] + Scalar::<Ed25519>::from(1u16);
+        assert!(!commitments.verify(&EjectShareInfo::Ed25519(corrupted, 2)));
+        let secp = Scalar::<Secp256k1>::random();
+        assert!(!commitments.verify(&EjectShareInfo::Secp256k1(secp, 1)));
+    }
+    #[test]
+    fn reconstruction_needs_one_share_more_than_the_threshold() {
+        let secret = Scalar::<Secp256k1>::random();
+        let (_, shares) = VerifiableSS::share_at_indices(3, 5, &secret, &[1, 2, 3, 4, 5]);
+        let indices = [1, 3, 4, 5];
+        let chosen = indices.iter().map(|i| shares[i - 1].clone()).collect::<Vec<_>>();
+        assert_eq!(reconstruct_key(&indices, &chosen, 4), Some(secret));
+        assert_eq!(reconstruct_key(&indices[..3], &chosen[..3], 4), None);
+    }
+}
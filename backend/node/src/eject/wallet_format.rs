@@ -0,0 +1,154 @@
+//! Formats single-signer wallets import a private key in, for keys reconstructed by eject
+
+use aes::cipher::{ NewCipher, StreamCipher };
+use aes::Aes128Ctr;
+use anyhow::{ bail, Result };
+use curv::arithmetic::Converter;
+use curv::elliptic::curves::{ Curve, Ed25519, Point, Scalar, Secp256k1 };
+use curv::BigInt;
+use hmac::Hmac;
+use serde::{ Deserialize, Serialize };
+use serde_json::json;
+use sha2::{ Digest, Sha256 };
+use sha3::Keccak256;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::encryption::get_secure_random_bytes;
+
+/// Iterations of the keystore key derivation, the default of geth for PBKDF2
+const KEYSTORE_PBKDF2_ROUNDS: u32 = 262_144;
+const WIF_MAINNET_PREFIX: u8 = 0x80;
+const WIF_TESTNET_PREFIX: u8 = 0xef;
+const WIF_COMPRESSED_SUFFIX: u8 = 0x01;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "format")]
+pub enum WalletFormat {
+    /// Wallet import format of Bitcoin, for a compressed public key
+    Wif {
+        #[serde(default)]
+        testnet: bool,
+    },
+    /// Version 3 keystore JSON of Ethereum wallets
+    EthereumKeystore {
+        /// Password of the keystore, encrypted to the e2e key of the node
+        encrypted_password: String,
+    },
+    /// Base58 of the secret scalar in little endian followed by the public key, the layout of a
+    /// Solana keypair. Threshold EdDSA keys are not derived from a seed, so the first half is the
+    /// scalar itself: it only imports into wallets that accept expanded secret keys.
+    Ed25519Base58,
+}
+
+impl std::fmt::Debug for WalletFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Wif { testnet } => f.debug_struct("Wif").field("testnet", testnet).finish(),
+            Self::EthereumKeystore { .. } => f.write_str("EthereumKeystore"),
+            Self::Ed25519Base58 => f.write_str("Ed25519Base58"),
+        }
+    }
+}
+
+fn big_endian_bytes<E: Curve>(scalar: &Scalar<E>) -> Zeroizing<[u8; 32]> {
+    let bytes = Zeroizing::new(BigInt::to_bytes(&scalar.to_bigint()));
+    let mut padded = Zeroizing::new([0u8; 32]);
+    padded[32 - bytes.len()..].copy_from_slice(&bytes);
+    padded
+}
+
+pub fn wif(secret: &Scalar<Secp256k1>, testnet: bool) -> String {
+    let prefix = if testnet { WIF_TESTNET_PREFIX } else { WIF_MAINNET_PREFIX };
+    let mut payload = Zeroizing::new(vec![prefix]);
+    payload.extend_from_slice(&*big_endian_bytes(secret));
+    payload.push(WIF_COMPRESSED_SUFFIX);
+    let checksum = Sha256::digest(&Sha256::digest(&payload));
+    payload.extend_from_slice(&checksum[..4]);
+    bs58::encode(&*payload).into_string()
+}
+
+pub fn ethereum_address(secret: &Scalar<Secp256k1>) -> String {
+    let public_key = (Point::generator() * secret).to_bytes(false);
+    hex::encode(&Keccak256::digest(&public_key[1..])[12..])
+}
+
+/// Keystore encrypted with AES-128-CTR under a PBKDF2 key, as geth writes them
+pub fn ethereum_keystore(secret: &Scalar<Secp256k1>, password: &str) -> Result<String> {
+    let salt = get_secure_random_bytes(32);
+    let iv = get_secure_random_bytes(16);
+    let mut derived = Zeroizing::new([0u8; 32]);
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(
+        password.as_bytes(),
+        &salt,
+        KEYSTORE_PBKDF2_ROUNDS,
+        &mut *derived
+    );
+
+    let mut ciphertext = big_endian_bytes(secret).to_vec();
+    let mut cipher = Aes128Ctr::new(derived[..16].into(), iv.as_slice().into());
+    cipher.apply_keystream(&mut ciphertext);
+    let mac = Keccak256::new().chain(&derived[16..]).chain(&ciphertext).finalize();
+
+    let keystore = json!({
+        "version": 3,
+        "id": Uuid::new_v4().to_string(),
+        "address": ethereum_address(secret),
+        "crypto": {
+            "cipher": "aes-128-ctr",
+            "cipherparams": { "iv": hex::encode(&iv) },
+            "ciphertext": hex::encode(&ciphertext),
+            "kdf": "pbkdf2",
+            "kdfparams": {
+                "c": KEYSTORE_PBKDF2_ROUNDS,
+                "dklen": 32,
+                "prf": "hmac-sha256",
+                "salt": hex::encode(&salt),
+            },
+            "mac": hex::encode(mac),
+        },
+    });
+    Ok(keystore.to_string())
+}
+
+pub fn ed25519_base58(secret: &Scalar<Ed25519>) -> String {
+    let mut keypair = Zeroizing::new(big_endian_bytes(secret).to_vec());
+    keypair.reverse();
+    keypair.extend_from_slice(&(Point::generator() * secret).to_bytes(true));
+    bs58::encode(&*keypair).into_string()
+}
+
+/// Password of an Ethereum keystore, wallets refuse to open keystores without one
+pub fn check_keystore_password(password: &str) -> Result<()> {
+    if password.is_empty() {
+        bail!("Keystore password must not be empty");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exports_bitcoin_and_ethereum_wallet_formats() {
+        let secret = Scalar::<Secp256k1>::from_bigint(
+            &BigInt::from_hex("0c28fca386c7a227600b2fe50b7cae11ec86d3bf1fbe471be89827e19d72aa1d")
+                .unwrap()
+        );
+        assert_eq!(wif(&secret, false), "KwdMAjGmerYanjeui5SHS7JkmpZvVipYvB2LJGU1ZxJwYvP98617");
+
+        let keystore: serde_json::Value = serde_json
+            ::from_str(&ethereum_keystore(&secret, "password").unwrap())
+            .unwrap();
+        assert_eq!(keystore["address"], ethereum_address(&secret));
+        let crypto = &keystore["crypto"];
+        let mut derived = [0u8; 32];
+        let salt = hex::decode(crypto["kdfparams"]["salt"].as_str().unwrap()).unwrap();
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(b"password", &salt, KEYSTORE_PBKDF2_ROUNDS, &mut derived);
+        let mut plaintext = hex::decode(crypto["ciphertext"].as_str().unwrap()).unwrap();
+        let iv = hex::decode(crypto["cipherparams"]["iv"].as_str().unwrap()).unwrap();
+        Aes128Ctr::new(derived[..16].into(), iv.as_slice().into()).apply_keystream(&mut plaintext);
+        assert_eq!(plaintext, big_endian_bytes(&secret).to_vec());
+    }
+}
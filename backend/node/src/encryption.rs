@@ -1,19 +1,36 @@
-use aes_gcm::aead::{ generic_array::GenericArray, Aead, NewAead };
+use aes_gcm::aead::{ generic_array::GenericArray, Aead, NewAead, Payload };
 use aes_gcm::Aes256Gcm;
 use anyhow::{ anyhow, bail, Context, Result };
 use curv::elliptic::curves::{ Curve, Point, Scalar };
 use curv::{ arithmetic::traits::Converter, BigInt };
 use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::montgomery::MontgomeryPoint;
 use ed25519_dalek::{ Digest, PublicKey, SecretKey, Sha512 };
 use nkeys::{ KeyPair, KeyPairType };
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use shared::recovery::EncryptedData;
+use sha2::Sha256;
+use shared::recovery::{ CipherAlgorithm, EncryptedData };
 use std::fmt::Debug;
 use std::iter::Iterator;
+use zeroize::{ Zeroize, Zeroizing };
 
 pub const AES_KEY_BYTES_LEN: usize = 32;
 
+/// Envelope whose key is the compressed edwards point of an ed25519 DH, derived from nkeys internals
+pub const ENVELOPE_V1_NKEY_EDWARDS: u8 = 1;
+/// Envelope whose key is derived from an X25519 DH between the converted nkeys
+pub const ENVELOPE_V2_X25519: u8 = 2;
+
+const X25519_KDF_CONTEXT: &[u8] = b"gridlock-e2e-x25519-v2";
+/// Associated data of versioned envelopes, followed by the version byte
+const ENVELOPE_AAD_CONTEXT: &[u8] = b"gridlock-e2e-envelope";
+
+// Prefix bytes of the nkeys encoding, see https://docs.nats.io/running-a-nats-service/configuration/securing_nats/auth_intro/nkey_auth
+const NKEYS_PREFIX_BYTE_SEED: u8 = 18 << 3;
+const NKEYS_SEED_RAW_LEN: usize = 36;
+const NKEYS_PUBLIC_RAW_LEN: usize = 35;
+
 macro_rules! length_mismatch {
     () => {
         "The key provided has length {}, rather than the reqired length of {}"
@@ -24,7 +41,7 @@ pub fn serialize_and_encrypt<T: Serialize>(
     input: &T,
     encryption_key: &[u8]
 ) -> Result<EncryptedData> {
-    let s = serde_json::to_vec(&input)?;
+    let s = Zeroizing::new(serde_json::to_vec(&input)?);
 
     aes_encrypt(&s, encryption_key)
 }
@@ -33,47 +50,114 @@ pub fn decrypt_and_deserialize<T: DeserializeOwned>(
     input: &EncryptedData,
     decryption_key: &[u8]
 ) -> Result<T> {
-    let dc = aes_decrypt(input, decryption_key)?;
+    let dc = Zeroizing::new(aes_decrypt(input, decryption_key)?);
     let ds = serde_json::from_slice::<T>(&dc)?;
     Ok(ds)
 }
 
+/// Encrypts with the current cipher
 pub fn aes_encrypt(plaintext: &[u8], encryption_key: &[u8]) -> Result<EncryptedData> {
-    // create aes-gcm for sending encrypted message
+    encrypt_with(CipherAlgorithm::CURRENT, plaintext, encryption_key)
+}
+
+/// Encrypts with the given cipher under a fresh random nonce of the length the cipher expects
+pub fn encrypt_with(
+    algorithm: CipherAlgorithm,
+    plaintext: &[u8],
+    encryption_key: &[u8]
+) -> Result<EncryptedData> {
+    encrypt_with_aad(algorithm, plaintext, encryption_key, &[])
+}
+
+fn encrypt_with_aad(
+    algorithm: CipherAlgorithm,
+    plaintext: &[u8],
+    encryption_key: &[u8],
+    aad: &[u8]
+) -> Result<EncryptedData> {
+    let nonce_len = match algorithm.nonce_len() {
+        Some(len) => len,
+        None => bail!("Cannot encrypt with an unsupported cipher"),
+    };
+    let nonce = get_secure_random_bytes(nonce_len);
+    let aead_pack = match algorithm {
+        CipherAlgorithm::Aes256Gcm => aes_256_gcm_encrypt(plaintext, encryption_key, &nonce, aad)?,
+        CipherAlgorithm::Unsupported => bail!("Cannot encrypt with an unsupported cipher"),
+    };
+    Ok(EncryptedData {
+        aead_pack,
+        nonce,
+        version: None,
+        algorithm: Some(algorithm),
+        key_id: None,
+    })
+}
+
+/// Decrypts with the cipher named in the data, data without one is AES-256-GCM
+pub fn aes_decrypt(encrypted_data: &EncryptedData, encryption_key: &[u8]) -> Result<Vec<u8>> {
+    decrypt_with_aad(encrypted_data, encryption_key, &[])
+}
+
+fn decrypt_with_aad(
+    encrypted_data: &EncryptedData,
+    encryption_key: &[u8],
+    aad: &[u8]
+) -> Result<Vec<u8>> {
+    let algorithm = encrypted_data.algorithm.unwrap_or(CipherAlgorithm::Aes256Gcm);
+    if algorithm.nonce_len() != Some(encrypted_data.nonce.len()) {
+        bail!(
+            "Nonce of length {} does not match the cipher {:?}",
+            encrypted_data.nonce.len(),
+            algorithm
+        );
+    }
+    match algorithm {
+        CipherAlgorithm::Aes256Gcm => {
+            let nonce = &encrypted_data.nonce;
+            aes_256_gcm_decrypt(&encrypted_data.aead_pack, encryption_key, nonce, aad)
+        }
+        CipherAlgorithm::Unsupported => {
+            bail!("Data was encrypted with a cipher this node does not support")
+        }
+    }
+}
+
+fn aes_256_gcm_encrypt(
+    plaintext: &[u8],
+    encryption_key: &[u8],
+    nonce: &[u8],
+    aad: &[u8]
+) -> Result<Vec<u8>> {
     if encryption_key.len() != AES_KEY_BYTES_LEN {
         return Err(anyhow!(length_mismatch!(), encryption_key.len(), AES_KEY_BYTES_LEN));
     }
     let key = GenericArray::from_slice(encryption_key);
-    let nonce_bytes = &mut get_secure_random_bits(96);
-    let nonce = GenericArray::from_slice(nonce_bytes);
     let cipher = Aes256Gcm::new(key);
 
-    let aead_pack_i_ne = cipher
-        .encrypt(nonce, plaintext.as_ref())
+    cipher
+        .encrypt(GenericArray::from_slice(nonce), Payload { msg: plaintext, aad })
         .map_err(|e|
             anyhow::Error::msg(format!("Encryption algorithm failed with an opaque error: {}", e))
-        )?;
-    let send_nonce = nonce_bytes.clone();
-    Ok(EncryptedData {
-        aead_pack: aead_pack_i_ne,
-        nonce: send_nonce,
-    })
+        )
 }
 
-pub fn aes_decrypt(encrypted_data: &EncryptedData, encryption_key: &[u8]) -> Result<Vec<u8>> {
+fn aes_256_gcm_decrypt(
+    aead_pack: &[u8],
+    encryption_key: &[u8],
+    nonce: &[u8],
+    aad: &[u8]
+) -> Result<Vec<u8>> {
     if encryption_key.len() != AES_KEY_BYTES_LEN {
         bail!(length_mismatch!(), encryption_key.len(), AES_KEY_BYTES_LEN);
     }
     let key = GenericArray::from_slice(encryption_key);
-    let nonce = GenericArray::from_slice(&encrypted_data.nonce);
     let cipher = Aes256Gcm::new(key);
 
-    let result = cipher
-        .decrypt(nonce, encrypted_data.aead_pack.as_ref())
+    cipher
+        .decrypt(GenericArray::from_slice(nonce), Payload { msg: aead_pack, aad })
         .map_err(|e|
             anyhow::Error::msg(format!("Decryption algorithm failed with an opaque error: {}", e))
-        )?;
-    Ok(result)
+        )
 }
 
 pub fn encryption_key_for_aes<C>(
@@ -136,12 +220,13 @@ pub fn encrypt_with_shared_secret(
     let private_kp: KeyPairExposed = KeyPair::from_seed(private_key)?.into();
     let public_kp: KeyPairExposed = KeyPair::from_public_key(public_key)?.into();
 
-    let shared_secret = create_shared_secret(&private_kp.sk.unwrap(), &public_kp.pk)?;
+    let shared_secret = Zeroizing::new(
+        create_shared_secret(&private_kp.sk.unwrap(), &public_kp.pk)?
+    );
     let encrypted = aes_encrypt(plaintext, &shared_secret)?;
     Ok(encrypted)
 }
 
-#[allow(dead_code)]
 pub fn decrypt_with_shared_secret(
     encrypted: EncryptedData,
     private_key: &str,
@@ -150,7 +235,9 @@ pub fn decrypt_with_shared_secret(
     let private_kp: KeyPairExposed = KeyPair::from_seed(private_key)?.into();
     let public_kp: KeyPairExposed = KeyPair::from_public_key(public_key)?.into();
 
-    let shared_secret = create_shared_secret(&private_kp.sk.unwrap(), &public_kp.pk)?;
+    let shared_secret = Zeroizing::new(
+        create_shared_secret(&private_kp.sk.unwrap(), &public_kp.pk)?
+    );
     let decrypted = aes_decrypt(&encrypted, &shared_secret)?;
     Ok(decrypted)
 }
@@ -166,6 +253,7 @@ fn create_shared_secret(private_key: &SecretKey, public_key: &PublicKey) -> Resu
     let mut output = [0u8; 32];
     output.copy_from_slice(&hash[..32]);
     let private_key_scalar = clamp_scalar(output);
+    output.zeroize();
 
     let ss = private_key_scalar * pub_key_point;
     Ok(ss.compress().to_bytes().to_vec())
@@ -194,6 +282,151 @@ impl From<KeyPair> for KeyPairExposed {
     }
 }
 
+/// Version assumed for parties that predate the advertisement of envelope versions
+pub fn legacy_encryption_version() -> u8 {
+    ENVELOPE_V1_NKEY_EDWARDS
+}
+
+/// Encryption keys shared with one peer, for every supported envelope version
+pub struct EnvelopeKeys {
+    legacy: Zeroizing<Vec<u8>>,
+    x25519: Zeroizing<Vec<u8>>,
+    /// Version sealed with, the highest the peer advertised and this node speaks
+    version: u8,
+}
+
+impl EnvelopeKeys {
+    /// Keys sealing with the legacy envelope until the peer's version is set
+    pub fn from_nkeys(private_key: &str, public_key: &str) -> Result<Self> {
+        Ok(EnvelopeKeys {
+            legacy: Zeroizing::new(shared_secret_from_nkeys(private_key, public_key)?),
+            x25519: Zeroizing::new(x25519_shared_secret_from_nkeys(private_key, public_key)?),
+            version: ENVELOPE_V1_NKEY_EDWARDS,
+        })
+    }
+
+    pub fn from_nkeys_many(private_key: &str, public_keys: &[String]) -> Result<Vec<Self>> {
+        public_keys
+            .iter()
+            .map(|pk| Self::from_nkeys(private_key, pk))
+            .collect()
+    }
+
+    /// Seals with the highest version both the peer, which advertised `peer_version`, and this
+    /// node speak
+    pub fn with_peer_version(mut self, peer_version: u8) -> Self {
+        self.version = peer_version.clamp(ENVELOPE_V1_NKEY_EDWARDS, ENVELOPE_V2_X25519);
+        self
+    }
+
+    /// Encrypts with the envelope version the peer opens. Legacy envelopes stay untagged, like
+    /// nodes that predate the versions send them.
+    pub fn seal<T: Serialize>(&self, input: &T) -> Result<EncryptedData> {
+        if self.version < ENVELOPE_V2_X25519 {
+            return serialize_and_encrypt(input, &self.legacy);
+        }
+        let plaintext = Zeroizing::new(serde_json::to_vec(input)?);
+        let aad = envelope_aad(ENVELOPE_V2_X25519);
+        let mut encrypted = encrypt_with_aad(
+            CipherAlgorithm::CURRENT,
+            &plaintext,
+            &self.x25519,
+            &aad
+        )?;
+        encrypted.version = Some(ENVELOPE_V2_X25519);
+        Ok(encrypted)
+    }
+
+    /// Decrypts either envelope format, untagged data is treated as the legacy format
+    pub fn open<T: DeserializeOwned>(&self, input: &EncryptedData) -> Result<T> {
+        match input.version {
+            None | Some(ENVELOPE_V1_NKEY_EDWARDS) => decrypt_and_deserialize(input, &self.legacy),
+            Some(ENVELOPE_V2_X25519) => {
+                let aad = envelope_aad(ENVELOPE_V2_X25519);
+                let plaintext = Zeroizing::new(decrypt_with_aad(input, &self.x25519, &aad)?);
+                Ok(serde_json::from_slice::<T>(&plaintext)?)
+            }
+            Some(version) => bail!("Unsupported envelope version {}", version),
+        }
+    }
+}
+
+/// Binds the version into the ciphertext, relabelling the version of an envelope fails to open
+fn envelope_aad(version: u8) -> Vec<u8> {
+    let mut aad = ENVELOPE_AAD_CONTEXT.to_vec();
+    aad.push(version);
+    aad
+}
+
+/// Derives a symmetric key from an X25519 exchange between two nkeys.
+///
+/// The ed25519 keys are read from the documented nkeys encoding and converted to their
+/// Montgomery form (as in libsodium's `crypto_sign_ed25519_*_to_curve25519`).
+pub fn x25519_shared_secret_from_nkeys(private_key: &str, public_key: &str) -> Result<Vec<u8>> {
+    let secret = x25519_secret_from_nkey_seed(private_key)?;
+    let public = x25519_public_from_nkey(public_key)?;
+
+    let shared = (&public * &secret).to_bytes();
+    if shared.iter().all(|b| *b == 0) {
+        bail!("X25519 exchange produced a low order point");
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(X25519_KDF_CONTEXT);
+    hasher.update(shared);
+    Ok(hasher.finalize().to_vec())
+}
+
+fn x25519_secret_from_nkey_seed(seed: &str) -> Result<curve25519_dalek::scalar::Scalar> {
+    let raw = Zeroizing::new(decode_nkey(seed, NKEYS_SEED_RAW_LEN)?);
+    if raw[0] & 248 != NKEYS_PREFIX_BYTE_SEED {
+        bail!("Not an nkeys seed");
+    }
+
+    let hash = Sha512::digest(&raw[2..34]);
+    let mut output = [0u8; 32];
+    output.copy_from_slice(&hash[..32]);
+    let scalar = clamp_scalar(output);
+    output.zeroize();
+    Ok(scalar)
+}
+
+fn x25519_public_from_nkey(public_key: &str) -> Result<MontgomeryPoint> {
+    let raw = decode_nkey(public_key, NKEYS_PUBLIC_RAW_LEN)?;
+    CompressedEdwardsY::from_slice(&raw[1..33])
+        .decompress()
+        .map(|point| point.to_montgomery())
+        .ok_or_else(|| anyhow!("Invalid public key"))
+}
+
+/// Decodes a base32 nkey and checks its CRC16 checksum
+fn decode_nkey(encoded: &str, expected_len: usize) -> Result<Vec<u8>> {
+    let raw = base32
+        ::decode(base32::Alphabet::RFC4648 { padding: false }, encoded)
+        .ok_or_else(|| anyhow!("Invalid nkey encoding"))?;
+    if raw.len() != expected_len {
+        bail!("Invalid nkey length {}", raw.len());
+    }
+
+    let (data, checksum) = raw.split_at(raw.len() - 2);
+    if crc16(data) != u16::from_le_bytes([checksum[0], checksum[1]]) {
+        bail!("Invalid nkey checksum");
+    }
+    Ok(data.to_vec())
+}
+
+// CRC16-CCITT (XMODEM), as used by nkeys
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
 /// Fills the provided buffer with secure random bytes.
 pub fn fill_secure_random(buffer: &mut [u8]) {
     use rand::prelude::*;
@@ -211,3 +444,70 @@ pub fn get_secure_random_bytes(bytes: usize) -> Vec<u8> {
 pub fn get_secure_random_bits(bits: usize) -> Vec<u8> {
     get_secure_random_bytes(bits / 8_usize)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn x25519_secret_is_symmetric() {
+        let alice = KeyPair::new_user();
+        let bob = KeyPair::new_user();
+
+        let alice_secret = x25519_shared_secret_from_nkeys(
+            &alice.seed().unwrap(),
+            &bob.public_key()
+        ).unwrap();
+        let bob_secret = x25519_shared_secret_from_nkeys(
+            &bob.seed().unwrap(),
+            &alice.public_key()
+        ).unwrap();
+        assert_eq!(alice_secret, bob_secret);
+    }
+
+    #[test]
+    fn envelope_opens_both_versions() {
+        let alice = KeyPair::new_user();
+        let bob = KeyPair::new_user();
+        let alice_keys = EnvelopeKeys::from_nkeys(&alice.seed().unwrap(), &bob.public_key()).unwrap();
+        let bob_keys = EnvelopeKeys::from_nkeys(&bob.seed().unwrap(), &alice.public_key()).unwrap();
+
+        // Bob has not advertised the current version yet
+        let legacy = alice_keys.seal(&String::from("share")).unwrap();
+        assert_eq!(legacy.version, None);
+        assert_eq!(bob_keys.open::<String>(&legacy).unwrap(), "share");
+
+        let alice_keys = alice_keys.with_peer_version(ENVELOPE_V2_X25519);
+        let sealed = alice_keys.seal(&String::from("share")).unwrap();
+        assert_eq!(sealed.version, Some(ENVELOPE_V2_X25519));
+        assert_eq!(bob_keys.open::<String>(&sealed).unwrap(), "share");
+
+        let mut relabelled = sealed.clone();
+        relabelled.version = Some(ENVELOPE_V1_NKEY_EDWARDS);
+        assert!(bob_keys.open::<String>(&relabelled).is_err());
+        // Without the version in the associated data
+        let unbound = serialize_and_encrypt(&String::from("share"), &alice_keys.x25519).unwrap();
+        let unbound = EncryptedData { version: Some(ENVELOPE_V2_X25519), ..unbound };
+        assert!(bob_keys.open::<String>(&unbound).is_err());
+    }
+
+    #[test]
+    fn dispatches_on_cipher_algorithm() {
+        let key = [3u8; AES_KEY_BYTES_LEN];
+        let encrypted = aes_encrypt(b"share", &key).unwrap();
+        assert!(encrypted.has_current_cipher());
+
+        // Data written before the algorithm field existed
+        let mut untagged = serde_json::to_value(&encrypted).unwrap();
+        untagged.as_object_mut().unwrap().remove("algorithm");
+        let untagged: EncryptedData = serde_json::from_value(untagged).unwrap();
+        assert!(!untagged.has_current_cipher());
+        assert_eq!(aes_decrypt(&untagged, &key).unwrap(), b"share");
+
+        let mut future = serde_json::to_value(&encrypted).unwrap();
+        future["algorithm"] = serde_json::json!("some-future-cipher");
+        let future: EncryptedData = serde_json::from_value(future).unwrap();
+        assert_eq!(future.algorithm, Some(CipherAlgorithm::Unsupported));
+        assert!(aes_decrypt(&future, &key).is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use crate::config::{ Config, ConfigProvider };
+use crate::encryption::get_secure_random_bytes;
+use crate::test_seed::seeded_bytes;
+use anyhow::{ bail, Context, Result };
+use chrono::{ DateTime, Utc };
+use curv::arithmetic::Converter;
+use curv::elliptic::curves::{ Curve, Scalar };
+use curv::BigInt;
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256, Sha512 };
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{ env, fs };
+use tracing::info;
+
+/// Comma separated sources mixed into key generation on top of the local RNG, `drand` and/or
+/// `operator`
+const SOURCES_VAR: &str = "ENTROPY_SOURCES";
+/// Latest drand beacon as served by `/public/latest`, kept current by a relay next to the node
+const DRAND_BEACON_FILE_VAR: &str = "ENTROPY_DRAND_BEACON_FILE";
+const DRAND_MAX_AGE_VAR: &str = "ENTROPY_DRAND_MAX_AGE_SECS";
+const DEFAULT_DRAND_MAX_AGE_SECS: u64 = 120;
+/// Hex encoded bytes, or a file holding them, supplied by the operator
+const OPERATOR_ENTROPY_VAR: &str = "ENTROPY_OPERATOR_HEX";
+const OPERATOR_ENTROPY_FILE_VAR: &str = "ENTROPY_OPERATOR_FILE";
+const MIN_OPERATOR_ENTROPY_BYTES: usize = 32;
+const LOCAL_ENTROPY_BYTES: usize = 32;
+const PROVENANCE_DIRECTORY: &str = "provenance";
+const SEED_DOMAIN: &[u8] = b"gridlock-keygen-entropy-v1";
+
+/// Source of randomness that can be mixed into the secret a party deals in key generation
+pub trait EntropyProvider {
+    /// Name of the source in the ceremony provenance
+    fn name(&self) -> &'static str;
+    fn sample(&self) -> Result<EntropySample>;
+}
+
+pub struct EntropySample {
+    pub bytes: Vec<u8>,
+    /// What the provenance records about the sample, never the bytes of a secret source
+    pub reference: Option<String>,
+}
+
+/// Operating system RNG, always mixed in so the secret never depends on external sources alone
+pub struct LocalRng;
+
+impl EntropyProvider for LocalRng {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn sample(&self) -> Result<EntropySample> {
+        Ok(EntropySample {
+            bytes: get_secure_random_bytes(LOCAL_ENTROPY_BYTES),
+            reference: None,
+        })
+    }
+}
+
+/// Replaces the local RNG in test builds with `deterministic-seeds` and a configured seed, so a
+/// ceremony deals the same secrets on every run
+struct TestSeed {
+    bytes: Vec<u8>,
+}
+
+impl EntropyProvider for TestSeed {
+    fn name(&self) -> &'static str {
+        "test-seed"
+    }
+
+    fn sample(&self) -> Result<EntropySample> {
+        Ok(EntropySample {
+            bytes: self.bytes.clone(),
+            reference: None,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct DrandBeacon {
+    round: u64,
+    randomness: String,
+    signature: String,
+}
+
+/// Public drand randomness. It makes the mix unpredictable before the round was published, but
+/// as everyone can read it, the secrecy of the dealt secret still comes from the other sources.
+pub struct DrandBeaconFile {
+    path: PathBuf,
+    max_age: Duration,
+}
+
+impl DrandBeaconFile {
+    fn from_env() -> Result<Self> {
+        let path = env
+            ::var(DRAND_BEACON_FILE_VAR)
+            .with_context(|| format!("{} has to be set for drand entropy", DRAND_BEACON_FILE_VAR))?;
+        let max_age = match env::var(DRAND_MAX_AGE_VAR) {
+            Ok(secs) =>
+                secs.parse().with_context(|| format!("{} is not a number", DRAND_MAX_AGE_VAR))?,
+            Err(_) => DEFAULT_DRAND_MAX_AGE_SECS,
+        };
+        Ok(Self {
+            path: PathBuf::from(path),
+            max_age: Duration::from_secs(max_age),
+        })
+    }
+}
+
+impl EntropyProvider for DrandBeaconFile {
+    fn name(&self) -> &'static str {
+        "drand"
+    }
+
+    fn sample(&self) -> Result<EntropySample> {
+        let age = fs::metadata(&self.path)?.modified()?.elapsed().unwrap_or_default();
+        if age > self.max_age {
+            bail!("drand beacon {} is {}s old", self.path.display(), age.as_secs());
+        }
+        let beacon: DrandBeacon = serde_json::from_str(&fs::read_to_string(&self.path)?)?;
+        let randomness = hex::decode(&beacon.randomness)?;
+        // drand defines the randomness of a round as the hash of the round's signature
+        if Sha256::digest(&hex::decode(&beacon.signature)?).as_slice() != &randomness[..] {
+            bail!("drand beacon of round {} has inconsistent randomness", beacon.round);
+        }
+        Ok(EntropySample {
+            bytes: randomness,
+            reference: Some(format!("round {} randomness {}", beacon.round, beacon.randomness)),
+        })
+    }
+}
+
+/// Secret bytes supplied by the operator, recorded only by their fingerprint
+pub struct OperatorEntropy {
+    bytes: Vec<u8>,
+}
+
+impl OperatorEntropy {
+    fn from_env() -> Result<Self> {
+        let encoded = match env::var(OPERATOR_ENTROPY_VAR) {
+            Ok(encoded) => encoded,
+            Err(_) => {
+                let path = env
+                    ::var(OPERATOR_ENTROPY_FILE_VAR)
+                    .with_context(|| {
+                        format!(
+                            "{} or {} has to be set for operator entropy",
+                            OPERATOR_ENTROPY_VAR,
+                            OPERATOR_ENTROPY_FILE_VAR
+                        )
+                    })?;
+                fs::read_to_string(&path).with_context(|| format!("Reading {}", path))?
+            }
+        };
+        let bytes = hex::decode(encoded.trim()).context("Operator entropy is not hex")?;
+        if bytes.len() < MIN_OPERATOR_ENTROPY_BYTES {
+            bail!("Operator entropy has to be at least {} bytes", MIN_OPERATOR_ENTROPY_BYTES);
+        }
+        Ok(Self { bytes })
+    }
+}
+
+impl EntropyProvider for OperatorEntropy {
+    fn name(&self) -> &'static str {
+        "operator"
+    }
+
+    fn sample(&self) -> Result<EntropySample> {
+        Ok(EntropySample {
+            bytes: self.bytes.clone(),
+            reference: Some(format!("sha256 {}", hex::encode(Sha256::digest(&self.bytes)))),
+        })
+    }
+}
+
+fn configured_providers() -> Result<Vec<Box<dyn EntropyProvider>>> {
+    // The key id and party index are mixed in by the ceremony, one seed serves all of them
+    let mut providers: Vec<Box<dyn EntropyProvider>> = match
+        seeded_bytes("ceremony", LOCAL_ENTROPY_BYTES)
+    {
+        Some(bytes) => vec![Box::new(TestSeed { bytes })],
+        None => vec![Box::new(LocalRng)],
+    };
+    let sources = env::var(SOURCES_VAR).unwrap_or_default();
+    for source in sources
+        .split(',')
+        .map(str::trim)
+        .filter(|source| !source.is_empty()) {
+        match source {
+            "drand" => providers.push(Box::new(DrandBeaconFile::from_env()?)),
+            "operator" => providers.push(Box::new(OperatorEntropy::from_env()?)),
+            other => bail!("Unknown entropy source {:?} in {}", other, SOURCES_VAR),
+        }
+    }
+    Ok(providers)
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct EntropySourceRecord {
+    pub source: String,
+    pub reference: Option<String>,
+}
+
+/// Entropy sources one party mixed into the secret it dealt in a key generation ceremony
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CeremonyProvenance {
+    pub key_id: String,
+    pub party_index: usize,
+    pub created_at: DateTime<Utc>,
+    pub sources: Vec<EntropySourceRecord>,
+}
+
+impl CeremonyProvenance {
+    fn save(&self) -> Result<()> {
+        let mut path = Config::get_gridlock_directory();
+        path.push(PROVENANCE_DIRECTORY);
+        fs::create_dir_all(&path)?;
+        path.push(format!("{}--{}.json", self.key_id, self.party_index));
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// Seed of the secret one party deals in a key generation ceremony
+pub struct CeremonyEntropy {
+    seed: [u8; 32],
+}
+
+impl CeremonyEntropy {
+    /// Mixes the local RNG with every source configured in `ENTROPY_SOURCES` and records the
+    /// sources in the ceremony provenance. A configured source that cannot be sampled fails the
+    /// key generation instead of silently leaving the secret to the local RNG.
+    pub fn gather(key_id: &str, party_index: usize) -> Result<Self> {
+        let (entropy, provenance) = Self::mix(key_id, party_index, &configured_providers()?)?;
+        provenance.save()?;
+        info!(
+            "Mixed entropy from {:?} into key generation of {}",
+            provenance.sources
+                .iter()
+                .map(|record| record.source.as_str())
+                .collect::<Vec<_>>(),
+            key_id
+        );
+        Ok(entropy)
+    }
+
+    fn mix(
+        key_id: &str,
+        party_index: usize,
+        providers: &[Box<dyn EntropyProvider>]
+    ) -> Result<(Self, CeremonyProvenance)> {
+        let mut hasher = Sha256::new();
+        hasher.update(SEED_DOMAIN);
+        update_prefixed(&mut hasher, key_id.as_bytes());
+        hasher.update((party_index as u64).to_be_bytes());
+
+        let mut sources = Vec::with_capacity(providers.len());
+        for provider in providers {
+            let sample = provider
+                .sample()
+                .with_context(|| format!("Sampling {} entropy", provider.name()))?;
+            update_prefixed(&mut hasher, provider.name().as_bytes());
+            update_prefixed(&mut hasher, &sample.bytes);
+            sources.push(EntropySourceRecord {
+                source: provider.name().to_string(),
+                reference: sample.reference,
+            });
+        }
+
+        let mut seed = [0u8; 32];
+        seed.copy_from_slice(&hasher.finalize());
+        let provenance = CeremonyProvenance {
+            key_id: key_id.to_string(),
+            party_index,
+            created_at: Utc::now(),
+            sources,
+        };
+        Ok((Self { seed }, provenance))
+    }
+
+    /// Secret scalar for protocols that take the dealt secret directly
+    pub fn secret_scalar<C: Curve>(&self) -> Scalar<C> {
+        let mut hasher = Sha512::new();
+        hasher.update(b"scalar");
+        hasher.update(self.seed);
+        Scalar::from_bigint(&BigInt::from_bytes(&hasher.finalize()))
+    }
+
+    /// Private key bytes for protocols that expand the dealt secret themselves
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        self.seed
+    }
+
+    /// Entropy of the local RNG only and without provenance, for protocol tests
+    #[cfg(test)]
+    pub(crate) fn local(key_id: &str, party_index: usize) -> Result<Self> {
+        Self::mix(key_id, party_index, &[Box::new(LocalRng)]).map(|(entropy, _)| entropy)
+    }
+}
+
+fn update_prefixed(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedEntropy(&'static str, Vec<u8>);
+
+    impl EntropyProvider for FixedEntropy {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn sample(&self) -> Result<EntropySample> {
+            Ok(EntropySample { bytes: self.1.clone(), reference: None })
+        }
+    }
+
+    #[test]
+    fn every_source_changes_the_seed_and_is_recorded() {
+        let providers: Vec<Box<dyn EntropyProvider>> = vec![
+            Box::new(FixedEntropy("local", vec![1; 32])),
+            Box::new(FixedEntropy("drand", vec![2; 32]))
+        ];
+        let (seed, provenance) = CeremonyEntropy::mix("key", 1, &providers).unwrap();
+        let (same, _) = CeremonyEntropy::mix("key", 1, &providers).unwrap();
+        assert_eq!(seed.seed, same.seed);
+        assert_eq!(
+            provenance.sources
+                .iter()
+                .map(|record| record.source.as_str())
+                .collect::<Vec<_>>(),
+            vec!["local", "drand"]
+        );
+
+        let other_beacon: Vec<Box<dyn EntropyProvider>> = vec![
+            Box::new(FixedEntropy("local", vec![1; 32])),
+            Box::new(FixedEntropy("drand", vec![3; 32]))
+        ];
+        let (other, _) = CeremonyEntropy::mix("key", 1, &other_beacon).unwrap();
+        assert_ne!(seed.seed, other.seed);
+        let (other_party, _) = CeremonyEntropy::mix("key", 2, &providers).unwrap();
+        assert_ne!(seed.seed, other_party.seed);
+
+        let operator = OperatorEntropy { bytes: vec![7; 32] };
+        let reference = operator.sample().unwrap().reference.unwrap();
+        assert!(!reference.contains(&hex::encode(vec![7u8; 32])));
+    }
+}
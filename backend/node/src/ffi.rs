@@ -0,0 +1,218 @@
+//! C ABI the iOS and Android wrappers embed the guardian through. The header is generated with
+//! `cbindgen --config cbindgen.toml --output guardian.h` in `backend/node`.
+//!
+//! Strings cross the boundary as NUL terminated UTF-8. Strings returned by the guardian are owned
+//! by the caller and freed with `guardian_free_string`. Functions returning an `i32` return 0 on
+//! success and -1 on failure, the error is then read with `guardian_last_error`.
+
+use crate::command::{ handle_json_message, MsgContext };
+use crate::communication::incoming::IncomingMessage;
+use crate::communication::queue_groups::WorkerConfig;
+use crate::session_manager::{ self, SessionEvent };
+use crate::{ handle_message, start, start_sending_ready_as_cancellable_task_on_thread, App };
+use anyhow::{ anyhow, bail, Result };
+use futures::StreamExt;
+use std::cell::RefCell;
+use std::ffi::{ c_char, c_void, CStr, CString };
+use std::sync::{ mpsc, Mutex, OnceLock };
+use std::time::Duration;
+use tracing::{ error, warn };
+
+const READY_MSG_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Called with a JSON `SessionEvent` and the user data it was registered with. It runs on the
+/// session's thread, hosts move the event to their UI thread themselves.
+pub type ProgressCallback = extern "C" fn(event: *const c_char, user_data: *mut c_void);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Keeps the ready messages going while the node runs, dropping it stops them
+static READY_SENDER: OnceLock<Mutex<mpsc::Sender<()>>> = OnceLock::new();
+
+/// The host's user data, only ever handed back to its callback
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+impl UserData {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+fn set_last_error(err: anyhow::Error) -> i32 {
+    error!("FFI call failed: {:?}", err);
+    let message = CString::new(format!("{:#}", err).replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| last.replace(Some(message)));
+    -1
+}
+
+fn to_status(result: Result<()>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(err) => set_last_error(err),
+    }
+}
+
+unsafe fn read_str<'a>(value: *const c_char) -> Result<&'a str> {
+    if value.is_null() {
+        bail!("Null string passed to the guardian");
+    }
+    Ok(CStr::from_ptr(value).to_str()?)
+}
+
+fn into_c_string(value: String) -> *mut c_char {
+    CString::new(value.replace('\0', " ")).unwrap_or_default().into_raw()
+}
+
+/// Directory the guardian keeps its identity, keyshares and settings in, set before
+/// `guardian_start_node`
+///
+/// # Safety
+/// `path` has to be a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn guardian_set_storage_path(path: *const c_char) -> i32 {
+    to_status(read_str(path).map(set_storage_path))
+}
+
+#[cfg(any(target_os = "android", target_os = "ios"))]
+fn set_storage_path(path: &str) {
+    unsafe { crate::config::mobile_set_storage_path(path) }
+}
+
+/// The storage directory is read from the environment on first use on other platforms
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+fn set_storage_path(path: &str) {
+    std::env::set_var("STORAGE_DIR", path);
+}
+
+/// Starts the node and serves its NATS subjects in the background, only once per process
+#[no_mangle]
+pub extern "C" fn guardian_start_node() -> i32 {
+    to_status(start_node())
+}
+
+fn start_node() -> Result<()> {
+    if READY_SENDER.get().is_some() {
+        bail!("The node is already running");
+    }
+    let app = start()?;
+    let (tx, rx) = mpsc::channel();
+    if READY_SENDER.set(Mutex::new(tx)).is_err() {
+        bail!("The node is already running");
+    }
+    start_sending_ready_as_cancellable_task_on_thread(
+        app.nc.clone(),
+        app.node.node_id.to_string(),
+        rx,
+        READY_MSG_INTERVAL
+    )?;
+    session_manager::runtime().spawn(async move {
+        if let Err(err) = serve(app).await {
+            error!("The embedded node stopped serving: {}", err);
+        }
+    });
+    Ok(())
+}
+
+async fn serve(app: App) -> Result<()> {
+    let subject = WorkerConfig::shared_subject(&app.node.node_id.to_string());
+    let mut subscription = app.nc.client()
+        .subscribe(subject.clone()).await
+        .map_err(|err| anyhow!("Failed to subscribe to \"{}\": {}", subject, err))?;
+    while let Some(message) = subscription.next().await {
+        handle_message(&app, IncomingMessage::from(message));
+    }
+    warn!("Subscription to the node's subjects was closed");
+    Ok(())
+}
+
+/// Runs a command of the JSON API in process, `request` and the response are base64 encoded JSON
+/// as for every FFI command. Failed commands return an "ERROR: " prefixed message like over NATS.
+/// Commands block until they complete, call it off the UI thread.
+///
+/// # Safety
+/// `request` has to be a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn guardian_handle_json_command(request: *const c_char) -> *mut c_char {
+    let response = read_str(request).and_then(|request| {
+        handle_json_message(request, MsgContext::FFI)
+    });
+    into_c_string(response.unwrap_or_else(|err| format!("ERROR: {}", err)))
+}
+
+/// Registers the callback session progress is reported to, a null callback unregisters it
+///
+/// # Safety
+/// `user_data` has to stay valid until the callback is replaced or unregistered.
+#[no_mangle]
+pub unsafe extern "C" fn guardian_set_progress_callback(
+    callback: Option<ProgressCallback>,
+    user_data: *mut c_void
+) {
+    let callback = match callback {
+        Some(callback) => callback,
+        None => {
+            session_manager::set_progress_listener(None);
+            return;
+        }
+    };
+    let user_data = UserData(user_data);
+    session_manager::set_progress_listener(
+        Some(
+            Box::new(move |event: &SessionEvent| {
+                let event = match serde_json::to_string(event).map(CString::new) {
+                    Ok(Ok(event)) => event,
+                    _ => {
+                        return;
+                    }
+                };
+                callback(event.as_ptr(), user_data.get());
+            })
+        )
+    );
+}
+
+/// Message of the last failed call on this thread, null when there is none
+#[no_mangle]
+pub extern "C" fn guardian_last_error() -> *mut c_char {
+    LAST_ERROR.with(|last| {
+        match last.borrow().as_ref() {
+            Some(message) => message.clone().into_raw(),
+            None => std::ptr::null_mut(),
+        }
+    })
+}
+
+/// Frees a string returned by the guardian
+///
+/// # Safety
+/// `value` has to be null or a string returned by the guardian that wasn't freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn guardian_free_string(value: *mut c_char) {
+    if !value.is_null() {
+        drop(CString::from_raw(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_commands_come_back_as_errors() {
+        let request = CString::new("not base64").unwrap();
+        let response = unsafe { guardian_handle_json_command(request.as_ptr()) };
+        let text = unsafe { CStr::from_ptr(response) }.to_str().unwrap().to_string();
+        unsafe { guardian_free_string(response) };
+        assert!(text.starts_with("ERROR: "));
+
+        assert_eq!(unsafe { guardian_set_storage_path(std::ptr::null()) }, -1);
+        let error = guardian_last_error();
+        assert!(unsafe { CStr::from_ptr(error) }.to_str().unwrap().contains("Null string"));
+        unsafe { guardian_free_string(error) };
+    }
+}
@@ -1,6 +1,188 @@
-use crate::storage::{ KeyshareAccessor, ECDSA, EDDSA };
-use anyhow::{ bail, Result };
-use tracing::error;
+//! Ghost shares are shares of a key at indices no guardian holds yet. They are derived by the
+//! guardians of the key like a recovered share, without reconstructing the secret, and kept
+//! encrypted by a node of the pool until a wallet adds a guardian, which then takes over a ghost
+//! share instead of the pool resharing the key.
+//!
+//! NATS contract of `GenerateGhostShares`, run once per ghost index with the session id
+//! `<session_id>-<index>`:
+//! - the orchestrator publishes `NewKeyShareRecoverySession` as a helper to
+//!   `network.gridlock.nodes.KeyShareRecovery.new.<node_id>` of every party node, with the ghost
+//!   index as `recovery_index` and the holder's networking key at that index in `public_keys`
+//! - helpers join on `network.gridlock.nodes.KeyShareRecovery.<session>.Join` and send their
+//!   packages, encrypted to the holder, to `...<session>.DeliverRecoveryPackage`
+//! - the orchestrator requests `network.gridlock.nodes.async.Message.new.<holder_node_id>` with
+//!   `ReceiveRecoveryPackages` marked `ghost`, the holder validates the share against the key's
+//!   commitments and saves it encrypted under the ghost index
+//!
+//! Key info is not changed, ghost shares become part of the pool when a guardian takes one over.
+
+use crate::command::{ JsonCommand, MsgContext };
+use crate::recovery::orchestrate::{
+    recover_target,
+    target_session_id,
+    TargetDelivery,
+    LEGACY_THRESHOLD,
+};
+use crate::recovery::recovery_session::NewKeyShareRecoverySession;
+use crate::recovery::RecoveryRole;
+use crate::replay;
+use crate::storage::{ KeyInfoStore, KeyshareAccessor, ECDSA, EDDSA };
+use crate::tenant::{ Access, TenantAuth };
+use anyhow::{ anyhow, bail, Context, Result };
+use chrono::Utc;
+use itertools::Itertools;
+use serde::{ Deserialize, Serialize };
+use shared::key_info::{ KeyInfo, NodeId };
+use shared::recovery::{ Key, PublicKeysEnum };
+use tracing::{ error, info, instrument };
+
+pub(crate) const ECDSA_GHOST_SHARES_UNSUPPORTED: &str =
+    "ECDSA ghost shares can only be created at keygen, the paillier keys of every party are fixed \
+     then";
+
+/// Derives shares at new indices of an existing key and has a node of the pool keep them
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct GenerateGhostSharesCommand {
+    #[serde(flatten)]
+    pub kind: Key,
+    pub key_id: String,
+    pub session_id: String,
+    /// Guardians deriving the shares, at least threshold + 1 of them
+    pub party_nodes: Vec<NodeId>,
+    /// Node of the pool that keeps the ghost shares
+    pub holder_node_id: NodeId,
+    /// Indices no node of the pool holds a share at
+    pub ghost_indices: Vec<usize>,
+    pub email: String,
+    /// Required on multi user nodes
+    #[serde(default)]
+    pub authorization: Option<TenantAuth>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct GhostSharesResponse {
+    pub key_id: String,
+    pub holder_node_id: NodeId,
+    pub ghost_indices: Vec<usize>,
+}
+
+impl JsonCommand for GenerateGhostSharesCommand {
+    type Response = GhostSharesResponse;
+
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: self.authorization.as_ref(), key_ids: vec![self.key_id.clone()] }
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        generate_ghost_shares(self, ctx)
+    }
+}
+
+#[instrument(skip_all)]
+fn generate_ghost_shares(
+    cmd: GenerateGhostSharesCommand,
+    ctx: MsgContext
+) -> Result<GhostSharesResponse> {
+    ctx.ensure_account(&cmd.email)?;
+    let app = ctx.get_app()?;
+    let key_info = KeyInfoStore::get_key_info(&cmd.key_id).with_context(|| {
+        format!("Key info is not found - key_id: {}", cmd.key_id)
+    })?;
+    let threshold = key_info.threshold.unwrap_or(LEGACY_THRESHOLD);
+    let holder_public_key = check_ghost_shares(&key_info, &cmd, threshold)?;
+
+    let mut public_keys: Vec<(usize, String)> = key_info.node_pool
+        .iter()
+        .map(|node| (node.share_index, node.networking_public_key.clone()))
+        .collect();
+    for &index in &cmd.ghost_indices {
+        let session_id = target_session_id(&cmd.session_id, index);
+        info!("Generating ghost share {} of key {}", index, cmd.key_id);
+        public_keys.push((index, holder_public_key.clone()));
+        let helper_message = NewKeyShareRecoverySession {
+            key_id: cmd.key_id.clone(),
+            session_id: session_id.clone(),
+            kind: cmd.kind.clone(),
+            threshold,
+            recovery_index: index,
+            recovery_indices: Vec::new(),
+            public_keys: PublicKeysEnum::Map(public_keys.clone()),
+            role: RecoveryRole::Helper,
+            email: Some(cmd.email.clone()),
+            timestamp: Some(Utc::now().to_rfc3339()),
+            origin: None,
+        };
+        let subject = |name: &str| {
+            format!("network.gridlock.nodes.KeyShareRecovery.{}.{}", session_id, name)
+        };
+        let join_sub = app.nc.subscribe(&subject("Join"))?;
+        let package_sub = app.nc.subscribe(&subject("DeliverRecoveryPackage"))?;
+        let helper_message = replay::sign_session_message(&helper_message, &app.node)?;
+        for node_id in &cmd.party_nodes {
+            let subject = format!("network.gridlock.nodes.KeyShareRecovery.new.{node_id}");
+            app.nc.publish(&subject, &helper_message)?;
+        }
+        recover_target(
+            &app.nc,
+            &session_id,
+            &cmd.kind,
+            &cmd.key_id,
+            index,
+            threshold,
+            &public_keys,
+            cmd.party_nodes.len(),
+            &join_sub,
+            &package_sub,
+            &cmd.holder_node_id,
+            &TargetDelivery::Nats,
+            true
+        )?;
+        // Later ghost shares are encrypted to the holder at their own index
+        public_keys.pop();
+    }
+    info!("Generated ghost shares {:?} of key {}", cmd.ghost_indices, cmd.key_id);
+    Ok(GhostSharesResponse {
+        key_id: cmd.key_id,
+        holder_node_id: cmd.holder_node_id,
+        ghost_indices: cmd.ghost_indices,
+    })
+}
+
+/// Networking public key of the holder, once the request is known to be possible
+fn check_ghost_shares(
+    key_info: &KeyInfo,
+    cmd: &GenerateGhostSharesCommand,
+    threshold: usize
+) -> Result<String> {
+    if matches!(cmd.kind, Key::ECDSA) {
+        bail!("{}", ECDSA_GHOST_SHARES_UNSUPPORTED);
+    }
+    if cmd.ghost_indices.is_empty() {
+        bail!("No ghost indices were requested");
+    }
+    if !cmd.ghost_indices.iter().all_unique() {
+        bail!("The same ghost index is listed more than once");
+    }
+    for &index in &cmd.ghost_indices {
+        // Index 0 is the secret key itself
+        if index == 0 || key_info.node_pool.iter().any(|node| node.share_index == index) {
+            bail!("Ghost index {} is not a new index of key {}", index, cmd.key_id);
+        }
+    }
+    if cmd.party_nodes.len() < threshold + 1 {
+        bail!("At least {} guardians are needed to derive ghost shares", threshold + 1);
+    }
+    for node_id in &cmd.party_nodes {
+        if !key_info.node_pool.iter().any(|node| &node.node_id == node_id) {
+            bail!("Node {} holds no share of key {}", node_id, cmd.key_id);
+        }
+    }
+    key_info.node_pool
+        .iter()
+        .find(|node| node.node_id == cmd.holder_node_id)
+        .map(|node| node.networking_public_key.clone())
+        .ok_or_else(|| anyhow!("Holder {} is not a node of key {}", cmd.holder_node_id, cmd.key_id))
+}
 
 pub fn decrypt_ghost_shares(key_id: &str) -> Result<usize> {
     match KeyshareAccessor::<ECDSA>::modifiable_from_encrypted(key_id) {
@@ -26,3 +208,44 @@ pub fn decrypt_ghost_shares(key_id: &str) -> Result<usize> {
             }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ghost_indices_must_be_new() {
+        let key_info: KeyInfo = serde_json
+            ::from_str(
+                r#"{"key_type":"EDDSA","y_sum":"","node_pool":[
+                {"node_id":"00000000-0000-0000-0000-000000000001","networking_public_key":"a",
+                 "kind":"Guardian","share_index":1},
+                {"node_id":"00000000-0000-0000-0000-000000000002","networking_public_key":"b",
+                 "kind":"Guardian","share_index":2},
+                {"node_id":"00000000-0000-0000-0000-000000000003","networking_public_key":"c",
+                 "kind":"Guardian","share_index":3}]}"#
+            )
+            .unwrap();
+        let nodes: Vec<NodeId> = key_info.node_pool
+            .iter()
+            .map(|node| node.node_id.clone())
+            .collect();
+        let mut cmd = GenerateGhostSharesCommand {
+            kind: Key::EDDSA,
+            key_id: "key".to_string(),
+            session_id: "session".to_string(),
+            party_nodes: nodes.clone(),
+            holder_node_id: nodes[0].clone(),
+            ghost_indices: vec![4, 5],
+            email: "alice@example.com".to_string(),
+            authorization: None,
+        };
+        assert_eq!(check_ghost_shares(&key_info, &cmd, 2).unwrap(), "a");
+        assert!(check_ghost_shares(&key_info, &cmd, 3).is_err());
+        cmd.ghost_indices = vec![3];
+        assert!(check_ghost_shares(&key_info, &cmd, 2).is_err());
+        cmd.ghost_indices = vec![4];
+        cmd.kind = Key::ECDSA;
+        assert!(check_ghost_shares(&key_info, &cmd, 2).is_err());
+    }
+}
@@ -0,0 +1,414 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::communication::blocking;
+use crate::config::{ Config, ConfigProvider };
+use crate::node::NodeIdentity;
+use crate::operator::OperatorInfo;
+use crate::NATS_CONNECTED;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Result };
+use chrono::{ DateTime, Duration as ChronoDuration, Utc };
+use nkeys::KeyPair;
+use serde::{ Deserialize, Serialize };
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+use tracing::{ warn, Event, Level, Subscriber };
+use tracing_subscriber::layer::{ Context, Layer };
+
+const HEALTH_HISTORY_FILE: &str = "health_history.json";
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const RETENTION_DAYS: i64 = 7;
+const ATTESTATION_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const ATTESTATION_WINDOW_HOURS: i64 = 24;
+
+/// Last attestation this node published, returned with `GetGuardianHealthCommand`
+static LAST_ATTESTATION: RwLock<Option<HealthAttestation>> = RwLock::new(None);
+
+static NATS_DISCONNECTS: AtomicU64 = AtomicU64::new(0);
+static SESSIONS_STARTED: AtomicU64 = AtomicU64::new(0);
+static COMMANDS_HANDLED: AtomicU64 = AtomicU64::new(0);
+static COMMAND_ERRORS: AtomicU64 = AtomicU64::new(0);
+static ERRORS_LOGGED: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_nats_disconnect() {
+    NATS_DISCONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_session_started() {
+    SESSIONS_STARTED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_command(succeeded: bool) {
+    COMMANDS_HANDLED.fetch_add(1, Ordering::Relaxed);
+    if !succeeded {
+        COMMAND_ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts error level events, which is where failed sessions end up regardless of protocol
+pub struct ErrorCountingLayer;
+
+impl<S: Subscriber> Layer<S> for ErrorCountingLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() == Level::ERROR {
+            ERRORS_LOGGED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Activity during one sample interval, counters hold the events since the previous sample
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct HealthSample {
+    pub timestamp: DateTime<Utc>,
+    pub nats_connected: bool,
+    pub nats_disconnects: u64,
+    pub sessions_started: u64,
+    pub commands_handled: u64,
+    pub command_errors: u64,
+    pub errors_logged: u64,
+}
+
+impl HealthSample {
+    /// Reads and resets the counters
+    fn take(timestamp: DateTime<Utc>) -> Self {
+        Self {
+            timestamp,
+            nats_connected: NATS_CONNECTED.load(Ordering::Relaxed),
+            nats_disconnects: NATS_DISCONNECTS.swap(0, Ordering::Relaxed),
+            sessions_started: SESSIONS_STARTED.swap(0, Ordering::Relaxed),
+            commands_handled: COMMANDS_HANDLED.swap(0, Ordering::Relaxed),
+            command_errors: COMMAND_ERRORS.swap(0, Ordering::Relaxed),
+            errors_logged: ERRORS_LOGGED.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Rolling history of health samples covering the last `RETENTION_DAYS`
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct HealthHistory {
+    pub samples: VecDeque<HealthSample>,
+}
+
+impl HealthHistory {
+    pub fn load() -> Result<Self> {
+        let path = health_history_path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::write(health_history_path(), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    fn push(&mut self, sample: HealthSample) {
+        let cutoff = sample.timestamp - ChronoDuration::days(RETENTION_DAYS);
+        self.samples.push_back(sample);
+        while self.samples.front().map_or(false, |oldest| oldest.timestamp < cutoff) {
+            self.samples.pop_front();
+        }
+    }
+
+    fn since(&self, since: DateTime<Utc>) -> Vec<HealthSample> {
+        self.samples
+            .iter()
+            .filter(|sample| sample.timestamp >= since)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Appends a sample to the stored history every `SAMPLE_INTERVAL`
+pub fn spawn_health_sampler() -> Result<()> {
+    thread::Builder
+        ::new()
+        .name("health-sampler".to_string())
+        .spawn(|| {
+            loop {
+                thread::sleep(SAMPLE_INTERVAL);
+                if let Err(err) = record_sample() {
+                    warn!("Failed to record a health sample: {}", err);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn record_sample() -> Result<()> {
+    let mut history = HealthHistory::load().unwrap_or_else(|err| {
+        warn!("Starting a new health history, the stored one is unreadable: {}", err);
+        HealthHistory::default()
+    });
+    history.push(HealthSample::take(Utc::now()));
+    history.save()
+}
+
+/// Returns the health samples recorded over the last `history_hours`
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetHealthHistoryCommand {
+    pub history_hours: u32,
+}
+
+impl JsonCommand for GetHealthHistoryCommand {
+    type Response = Vec<HealthSample>;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        if i64::from(self.history_hours) > RETENTION_DAYS * 24 {
+            bail!("Health history is only kept for {} days", RETENTION_DAYS);
+        }
+        let since = Utc::now() - ChronoDuration::hours(i64::from(self.history_hours));
+        Ok(HealthHistory::load()?.since(since))
+    }
+}
+
+/// Activity over the last `window_hours`, the part of the node's health the hub selects
+/// guardians by
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct HealthSummary {
+    pub nats_connected: bool,
+    pub window_hours: i64,
+    pub samples: usize,
+    pub nats_disconnects: u64,
+    pub sessions_started: u64,
+    pub commands_handled: u64,
+    pub command_errors: u64,
+    pub errors_logged: u64,
+}
+
+impl HealthSummary {
+    fn from_samples(samples: &[HealthSample], nats_connected: bool, window_hours: i64) -> Self {
+        Self {
+            nats_connected,
+            window_hours,
+            samples: samples.len(),
+            nats_disconnects: samples.iter().map(|sample| sample.nats_disconnects).sum(),
+            sessions_started: samples.iter().map(|sample| sample.sessions_started).sum(),
+            commands_handled: samples.iter().map(|sample| sample.commands_handled).sum(),
+            command_errors: samples.iter().map(|sample| sample.command_errors).sum(),
+            errors_logged: samples.iter().map(|sample| sample.errors_logged).sum(),
+        }
+    }
+
+    fn current(now: DateTime<Utc>) -> Result<Self> {
+        let since = now - ChronoDuration::hours(ATTESTATION_WINDOW_HOURS);
+        let samples = HealthHistory::load()?.since(since);
+        let nats_connected = NATS_CONNECTED.load(Ordering::Relaxed);
+        Ok(Self::from_samples(&samples, nats_connected, ATTESTATION_WINDOW_HOURS))
+    }
+}
+
+/// Health summary signed with the networking key of the node, so the hub and other guardians can
+/// rely on it without trusting whoever relayed it
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct HealthAttestation {
+    pub node_id: String,
+    pub networking_public_key: String,
+    pub timestamp: DateTime<Utc>,
+    pub summary: HealthSummary,
+    /// Operator running the node, signed along with the summary when configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator: Option<OperatorInfo>,
+    /// Base64 ed25519 signature over `signed_message`
+    pub signature: String,
+}
+
+impl HealthAttestation {
+    pub fn sign(
+        node: &NodeIdentity,
+        summary: HealthSummary,
+        timestamp: DateTime<Utc>
+    ) -> Result<Self> {
+        let node_id = node.node_id.to_string();
+        let operator = OperatorInfo::configured().cloned();
+        let message = signed_message(&node_id, &timestamp, &summary, operator.as_ref())?;
+        let signature = KeyPair::from_seed(&node.networking_private_key)?.sign(&message)?;
+        Ok(Self {
+            node_id,
+            networking_public_key: node.networking_public_key.clone(),
+            timestamp,
+            summary,
+            operator,
+            signature: base64::encode(signature),
+        })
+    }
+
+    /// Checks the signature against the networking key the verifier knows the node by, and that
+    /// the attestation is not older than `max_age`
+    pub fn verify(
+        &self,
+        networking_public_key: &str,
+        max_age: ChronoDuration,
+        now: DateTime<Utc>
+    ) -> Result<()> {
+        if self.networking_public_key != networking_public_key {
+            bail!("Health attestation of node {} is signed by another key", self.node_id);
+        }
+        let message = signed_message(
+            &self.node_id,
+            &self.timestamp,
+            &self.summary,
+            self.operator.as_ref()
+        )?;
+        KeyPair::from_public_key(networking_public_key)?
+            .verify(&message, &base64::decode(&self.signature)?)
+            .map_err(|_| {
+                anyhow!("Health attestation signature of node {} is invalid", self.node_id)
+            })?;
+        if self.timestamp > now + ChronoDuration::minutes(1) {
+            bail!("Health attestation of node {} is from the future", self.node_id);
+        }
+        if now - self.timestamp > max_age {
+            bail!("Health attestation of node {} from {} is stale", self.node_id, self.timestamp);
+        }
+        Ok(())
+    }
+}
+
+/// Bytes covered by the attestation signature: node id, timestamp, summary and the operator info
+/// if any. Attestations without operator info sign the same bytes as before it was added.
+fn signed_message(
+    node_id: &str,
+    timestamp: &DateTime<Utc>,
+    summary: &HealthSummary,
+    operator: Option<&OperatorInfo>
+) -> Result<Vec<u8>> {
+    let timestamp = timestamp.to_rfc3339();
+    Ok(match operator {
+        Some(operator) => serde_json::to_vec(&(node_id, timestamp, summary, operator))?,
+        None => serde_json::to_vec(&(node_id, timestamp, summary))?,
+    })
+}
+
+fn attest(nc: &blocking::Connection) -> Result<()> {
+    let node = NodeIdentity::cached()?;
+    let now = Utc::now();
+    let attestation = HealthAttestation::sign(&node, HealthSummary::current(now)?, now)?;
+    nc.publish(
+        &format!("network.gridlock.health.{}", attestation.node_id),
+        serde_json::to_vec(&attestation)?
+    )?;
+    *LAST_ATTESTATION.write().unwrap() = Some(attestation);
+    Ok(())
+}
+
+/// Publishes a signed health attestation every `ATTESTATION_INTERVAL`, starting right away so
+/// a restarted node is selectable again without waiting a full interval
+pub fn spawn_attestation_publisher(nc: blocking::Connection) -> Result<()> {
+    thread::Builder
+        ::new()
+        .name("health-attestation".to_string())
+        .spawn(move || {
+            loop {
+                if let Err(err) = attest(&nc) {
+                    warn!("Failed to publish a health attestation: {}", err);
+                }
+                thread::sleep(ATTESTATION_INTERVAL);
+            }
+        })?;
+    Ok(())
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct GuardianHealth {
+    pub node_id: String,
+    pub name: String,
+    pub summary: HealthSummary,
+    pub last_attestation: Option<HealthAttestation>,
+}
+
+/// Current health summary of the guardian together with the last attestation it published
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetGuardianHealthCommand {}
+
+impl JsonCommand for GetGuardianHealthCommand {
+    type Response = GuardianHealth;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let node = NodeIdentity::cached()?;
+        Ok(GuardianHealth {
+            node_id: node.node_id.to_string(),
+            name: node.name,
+            summary: HealthSummary::current(Utc::now())?,
+            last_attestation: LAST_ATTESTATION.read().unwrap().clone(),
+        })
+    }
+}
+
+fn health_history_path() -> PathBuf {
+    let mut path = Config::get_gridlock_directory();
+    path.push(HEALTH_HISTORY_FILE);
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: DateTime<Utc>) -> HealthSample {
+        HealthSample {
+            timestamp,
+            nats_connected: true,
+            nats_disconnects: 0,
+            sessions_started: 1,
+            commands_handled: 2,
+            command_errors: 0,
+            errors_logged: 0,
+        }
+    }
+
+    #[test]
+    fn drops_samples_older_than_retention() {
+        let now = Utc::now();
+        let mut history = HealthHistory::default();
+        history.push(sample(now - ChronoDuration::days(RETENTION_DAYS + 1)));
+        history.push(sample(now - ChronoDuration::hours(2)));
+        history.push(sample(now));
+
+        assert_eq!(history.samples.len(), 2);
+        assert_eq!(history.since(now - ChronoDuration::hours(1)), vec![sample(now)]);
+    }
+
+    #[test]
+    fn attestations_verify_only_unmodified_and_fresh() {
+        let node = NodeIdentity::new();
+        let now = Utc::now();
+        let samples = vec![sample(now - ChronoDuration::hours(1)), sample(now)];
+        let summary = HealthSummary::from_samples(&samples, true, ATTESTATION_WINDOW_HOURS);
+        assert_eq!(summary.commands_handled, 4);
+        let attestation = HealthAttestation::sign(&node, summary, now).unwrap();
+        let max_age = ChronoDuration::hours(1);
+        let key = &node.networking_public_key;
+        assert!(attestation.verify(key, max_age, now).is_ok());
+
+        let mut tampered = attestation.clone();
+        tampered.summary.command_errors = 0;
+        tampered.summary.commands_handled = 1000;
+        assert!(tampered.verify(key, max_age, now).is_err());
+        let mut impersonated = attestation.clone();
+        impersonated.operator = Some(OperatorInfo {
+            display_name: "Acme Custody".to_string(),
+            support_url: None,
+            contact: None,
+        });
+        assert!(impersonated.verify(key, max_age, now).is_err());
+        let other = NodeIdentity::new();
+        assert!(attestation.verify(&other.networking_public_key, max_age, now).is_err());
+        assert!(attestation.verify(key, max_age, now + ChronoDuration::hours(2)).is_err());
+    }
+}
@@ -1,13 +1,187 @@
 use crate::command::{ JsonCommand, MsgContext };
+use crate::node::NodeIdentity;
 use crate::storage::fs::WriteOpts;
+use crate::storage::key_listing::key_accounts;
+use crate::storage::key_metadata_store::{ KeyMetadataStore, KeyUsage };
 use crate::storage::KeyInfoStore;
-use anyhow::Result;
-use shared::key_info::UpdateKeyInfoCommand;
+use crate::tenant::{ Access, TenantAuth };
+use anyhow::{ anyhow, bail, Result };
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{ PublicKey, Signature, Verifier };
+use serde::{ Deserialize, Serialize };
+use shared::key_info::{ KeyInfo, SignedKeyMetadata, UpdateKeyInfoCommand };
+
+/// Upper bound on the encoded size of key metadata, it is replicated to every guardian of the key
+const MAX_KEY_METADATA_BYTES: usize = 4096;
+/// User metadata holding the client e2e public key an account generated its keys with
+const CLIENT_E2E_KEY_METADATA: &str = "e2e_key";
 
 impl JsonCommand for UpdateKeyInfoCommand {
     type Response = ();
 
+    fn access(&self) -> Access<'_> {
+        Access::Peer
+    }
+
     fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        if let Some(metadata) = &self.key_info.metadata {
+            verify_key_metadata(metadata, &self.key_id)?;
+        }
         KeyInfoStore::save_key_info(&self.key_info, &self.key_id, &WriteOpts::Modify)
     }
 }
+
+/// Key info of a stored key, for devices that orchestrate a recovery themselves
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetKeyInfoCommand {
+    pub key_id: String,
+    /// Required on multi user nodes
+    #[serde(default)]
+    pub authorization: Option<TenantAuth>,
+}
+
+impl JsonCommand for GetKeyInfoCommand {
+    type Response = KeyInfo;
+
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: self.authorization.as_ref(), key_ids: vec![self.key_id.clone()] }
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        KeyInfoStore::get_key_info(&self.key_id)
+    }
+}
+
+/// How often and when this guardian last co-signed with a key, so wallets can show its activity
+/// and spot abandoned keys
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetKeyUsageCommand {
+    pub key_id: String,
+    /// Account the key is stored for, the proven account on multi user nodes
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Required on multi user nodes
+    #[serde(default)]
+    pub authorization: Option<TenantAuth>,
+}
+
+impl JsonCommand for GetKeyUsageCommand {
+    /// `None` while the key has not signed anything on this node
+    type Response = Option<KeyUsage>;
+
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: self.authorization.as_ref(), key_ids: vec![self.key_id.clone()] }
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let email = ctx.tenant().or(self.email).unwrap_or_default();
+        KeyMetadataStore::get_usage(&self.key_id, &email)
+    }
+}
+
+/// Checks that key metadata is within the size limit and carries a valid signature of its
+/// claimed signer. Orchestrators don't know the account of the key, guardians storing the
+/// metadata also bind the signer to the key owner with `verify_key_metadata`.
+pub fn verify_key_metadata_signature(signed: &SignedKeyMetadata, key_id: &str) -> Result<()> {
+    let encoded_len = serde_json::to_vec(&signed.metadata)?.len();
+    if encoded_len > MAX_KEY_METADATA_BYTES {
+        bail!(
+            "Key metadata is {} bytes, at most {} bytes are allowed",
+            encoded_len,
+            MAX_KEY_METADATA_BYTES
+        );
+    }
+
+    let public_key = signer_public_key(signed)?;
+    let signature = Signature::try_from(&base64::decode(&signed.signature)?[..]).map_err(|err|
+        anyhow!("Invalid key metadata signature encoding: {}", err)
+    )?;
+
+    public_key
+        .verify(&signed.metadata.signing_payload(key_id), &signature)
+        .map_err(|_| anyhow!("Key metadata signature verification failed"))
+}
+
+/// Checks key metadata like `verify_key_metadata_signature` and that it was signed by the owner
+/// of the key: the X25519 form of the signer key has to be the client e2e key stored for the
+/// account holding the key
+pub fn verify_key_metadata(signed: &SignedKeyMetadata, key_id: &str) -> Result<()> {
+    verify_key_metadata_signature(signed, key_id)?;
+    let accounts = key_accounts(key_id)?;
+    let [email] = accounts.as_slice() else {
+        bail!("Key {} is not held by exactly one account, its metadata can't be verified", key_id);
+    };
+    let owner_e2e_key = KeyMetadataStore::get_user_level(CLIENT_E2E_KEY_METADATA, email).map_err(
+        |err| anyhow!("No client e2e key is stored for the owner of key {}: {}", key_id, err)
+    )?;
+    if !is_signer_of_e2e_key(&signer_public_key(signed)?, &owner_e2e_key)? {
+        bail!("Key metadata is not signed by the owner of key {}", key_id);
+    }
+    Ok(())
+}
+
+fn signer_public_key(signed: &SignedKeyMetadata) -> Result<PublicKey> {
+    PublicKey::from_bytes(&base64::decode(&signed.signer_public_key)?).map_err(|err|
+        anyhow!("Invalid key metadata signer public key: {}", err)
+    )
+}
+
+/// Whether the X25519 form of the ed25519 key, as in libsodium's
+/// `crypto_sign_ed25519_pk_to_curve25519`, is the base64 encoded e2e key
+fn is_signer_of_e2e_key(signer: &PublicKey, e2e_public_key: &str) -> Result<bool> {
+    let montgomery = CompressedEdwardsY::from_slice(signer.as_bytes())
+        .decompress()
+        .ok_or_else(|| anyhow!("Invalid key metadata signer public key"))?
+        .to_montgomery();
+    Ok(montgomery.as_bytes()[..] == base64::decode(e2e_public_key)?[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curve25519_dalek::constants::X25519_BASEPOINT;
+    use curve25519_dalek::scalar::Scalar;
+    use ed25519_dalek::{ ExpandedSecretKey, SecretKey };
+    use sha2::{ Digest, Sha512 };
+    use shared::key_info::KeyMetadata;
+
+    fn sign(metadata: KeyMetadata, key_id: &str, secret: &[u8; 32]) -> SignedKeyMetadata {
+        let secret = SecretKey::from_bytes(secret).unwrap();
+        let public = PublicKey::from(&secret);
+        let signature = ExpandedSecretKey::from(&secret).sign(
+            &metadata.signing_payload(key_id),
+            &public
+        );
+        SignedKeyMetadata {
+            metadata,
+            signer_public_key: base64::encode(public.as_bytes()),
+            signature: base64::encode(signature.to_bytes()),
+        }
+    }
+
+    #[test]
+    fn metadata_is_bound_to_its_key_and_the_owner_e2e_key() {
+        let metadata = KeyMetadata {
+            display_name: Some("Savings".to_string()),
+            chain: Some("ethereum".to_string()),
+            wallet_descriptor: None,
+        };
+        let signed = sign(metadata, "key-1", &[7u8; 32]);
+        assert!(verify_key_metadata_signature(&signed, "key-1").is_ok());
+        assert!(verify_key_metadata_signature(&signed, "key-2").is_err());
+
+        // The owner's box key derived from the same secret, as libsodium does
+        let mut bits = [0u8; 32];
+        bits.copy_from_slice(&Sha512::digest(&[7u8; 32])[..32]);
+        bits[0] &= 248;
+        bits[31] &= 127;
+        bits[31] |= 64;
+        let owner_e2e_key = base64::encode((X25519_BASEPOINT * Scalar::from_bits(bits)).as_bytes());
+        let signer = signer_public_key(&signed).unwrap();
+        assert!(is_signer_of_e2e_key(&signer, &owner_e2e_key).unwrap());
+        let other = signer_public_key(&sign(KeyMetadata::default(), "key-1", &[8u8; 32])).unwrap();
+        assert!(!is_signer_of_e2e_key(&other, &owner_e2e_key).unwrap());
+    }
+}
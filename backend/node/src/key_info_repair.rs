@@ -0,0 +1,230 @@
+//! Repair of missing key info. A guardian holding a keyshare without key info asks the other
+//! guardians of the key for theirs. Their answers are only saved when the public key derives from
+//! the commitments of the local keyshare, the pool places this node at the index of its share and
+//! every peer that answered agrees on the pool.
+
+use crate::command::{ JsonCommand, MsgContext, TaggedCommandType };
+use crate::communication::blocking;
+use crate::key_info::verify_key_metadata;
+use crate::keygen::key_import::verify_share;
+use crate::node::NodeIdentity;
+use crate::storage::fs::WriteOpts;
+use crate::storage::{ Frost, KeyInfoStore, KeyshareAccessor, BLS, ECDSA, EDDSA };
+use crate::tenant::Access;
+use anyhow::{ bail, Context, Result };
+use curv::arithmetic::Converter;
+use serde::{ Deserialize, Serialize };
+use shared::key_info::{ Key, KeyInfo, NodeId };
+use std::time::Duration;
+use tracing::{ info, warn };
+
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Asked of a guardian by another guardian of the key that is missing its key info
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RequestKeyInfoCommand {
+    pub key_id: String,
+    /// Guardian asking, only guardians in the pool of the key get an answer
+    pub node_id: NodeId,
+}
+
+impl JsonCommand for RequestKeyInfoCommand {
+    type Response = KeyInfo;
+
+    fn access(&self) -> Access<'_> {
+        Access::Peer
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let key_info = KeyInfoStore::get_key_info(&self.key_id)?;
+        let requester = self.node_id.to_string();
+        if !key_info.node_pool.iter().any(|node| node.node_id.to_string() == requester) {
+            bail!("Node {} is not a guardian of key {}", requester, self.key_id);
+        }
+        Ok(key_info)
+    }
+}
+
+/// Fetches the key info of a key this node holds a share of from other guardians of the key
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RepairKeyInfoCommand {
+    pub key_id: String,
+    pub node_ids: Vec<NodeId>,
+}
+
+impl JsonCommand for RepairKeyInfoCommand {
+    type Response = KeyInfo;
+
+    fn access(&self) -> Access<'_> {
+        Access::Peer
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let app = ctx.get_app()?;
+        repair_key_info(&app.nc, &self.key_id, &self.node_ids)
+    }
+}
+
+/// Asks the peers for the key info, saves and returns it once authenticated
+pub fn repair_key_info(
+    nc: &blocking::Connection,
+    key_id: &str,
+    node_ids: &[NodeId]
+) -> Result<KeyInfo> {
+    let own_node_id = NodeIdentity::cached()?.node_id.to_string();
+    let request = serde_json::to_string(
+        &TaggedCommandType::RequestKeyInfo(RequestKeyInfoCommand {
+            key_id: key_id.to_string(),
+            node_id: NodeId::new(own_node_id.clone()),
+        })
+    )?;
+    let mut answers = Vec::new();
+    for node_id in node_ids.iter().filter(|node_id| node_id.to_string() != own_node_id) {
+        match
+            fetch_key_info(nc, node_id, &request).and_then(|key_info| {
+                authenticate(key_id, &own_node_id, &key_info)?;
+                Ok(key_info)
+            })
+        {
+            Ok(key_info) => answers.push(key_info),
+            Err(err) => warn!("Key info of {} from {} was not used: {}", key_id, node_id, err),
+        }
+    }
+    let key_info = agreed_key_info(answers)?;
+    KeyInfoStore::save_key_info(&key_info, key_id, &WriteOpts::CreateNewOnly)?;
+    info!("Repaired key info of {} from the other guardians", key_id);
+    Ok(key_info)
+}
+
+fn fetch_key_info(nc: &blocking::Connection, node_id: &NodeId, request: &str) -> Result<KeyInfo> {
+    let subject = format!("network.gridlock.nodes.Message.new.{}", node_id);
+    let response = nc.request_timeout(&subject, request, PEER_TIMEOUT)?;
+    let response = String::from_utf8(response.data)?;
+    if let Some(err) = response.strip_prefix("ERROR: ") {
+        bail!("{}", err);
+    }
+    serde_json::from_str(&response).context("Deserialize key info")
+}
+
+/// The pool and threshold every authenticated answer carries, the peers could otherwise place
+/// nodes of their choosing in the pool
+fn agreed_key_info(answers: Vec<KeyInfo>) -> Result<KeyInfo> {
+    let mut answers = answers.into_iter();
+    let key_info = match answers.next() {
+        Some(key_info) => key_info,
+        None => bail!("No guardian answered with key info matching the keyshare"),
+    };
+    let pool = serde_json::to_string(&(&key_info.node_pool, key_info.threshold))?;
+    for other in answers {
+        if serde_json::to_string(&(&other.node_pool, other.threshold))? != pool {
+            bail!("Guardians disagree on the pool of the key, repair it by hand");
+        }
+    }
+    Ok(key_info)
+}
+
+/// Checks the key info against the local keyshare of the key
+fn authenticate(key_id: &str, own_node_id: &str, key_info: &KeyInfo) -> Result<()> {
+    let (party_index, threshold, matches) = match &key_info.kind {
+        Key::ECDSA { y_sum } => {
+            let share = KeyshareAccessor::<ECDSA>::read_only(key_id)?.key;
+            let public_key = verify_share(
+                &share.x_i,
+                share.party_index,
+                share.threshold,
+                &share.vss_scheme_vec
+            )?;
+            let matches =
+                public_key.x_coord().is_some_and(|x| x.to_hex() == y_sum.x) &&
+                public_key.y_coord().is_some_and(|y| y.to_hex() == y_sum.y);
+            (share.party_index, share.threshold, matches)
+        }
+        Key::EDDSA { y_sum } => {
+            let share = KeyshareAccessor::<EDDSA>::read_only(key_id)?.key;
+            let public_key = verify_share(
+                &share.x_i,
+                share.party_index,
+                share.threshold,
+                &share.vss_scheme_vec
+            )?;
+            let matches = hex::encode(&*public_key.to_bytes(false)) == *y_sum;
+            (share.party_index, share.threshold, matches)
+        }
+        Key::Frost { y_sum } => {
+            let share = KeyshareAccessor::<Frost>::read_only(key_id)?.key;
+            let public_key = verify_share(
+                &share.x_i,
+                share.party_index,
+                share.threshold,
+                &share.vss_scheme_vec
+            )?;
+            let matches = hex::encode(&*public_key.to_bytes(true)) == *y_sum;
+            (share.party_index, share.threshold, matches)
+        }
+        Key::BLS { public_key: expected } => {
+            let share = KeyshareAccessor::<BLS>::read_only(key_id)?.key;
+            let public_key = verify_share(
+                &share.x_i,
+                share.party_index,
+                share.threshold,
+                &share.vss_scheme_vec
+            )?;
+            let matches = hex::encode(&*public_key.to_bytes(true)) == *expected;
+            (share.party_index, share.threshold, matches)
+        }
+        Key::Sr25519 { .. } => bail!("Sr25519 key info can't be checked against the keyshare"),
+    };
+    if !matches {
+        bail!("Public key does not derive from the commitments of the keyshare");
+    }
+    check_pool(key_info, own_node_id, party_index, threshold)?;
+    if let Some(metadata) = &key_info.metadata {
+        verify_key_metadata(metadata, key_id)?;
+    }
+    Ok(())
+}
+
+fn check_pool(
+    key_info: &KeyInfo,
+    own_node_id: &str,
+    party_index: usize,
+    threshold: usize
+) -> Result<()> {
+    let placed = key_info.node_pool
+        .iter()
+        .any(|node| node.node_id.to_string() == own_node_id && node.share_index == party_index);
+    if !placed {
+        bail!("Pool does not hold this node at share index {}", party_index);
+    }
+    if key_info.threshold.is_some_and(|recorded| recorded != threshold) {
+        bail!("Threshold does not match the keyshare");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_info(node_pool: &str) -> KeyInfo {
+        let json = format!(r#"{{"key_type":"EDDSA","y_sum":"","node_pool":{}}}"#, node_pool);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn repaired_key_info_needs_agreeing_peers_and_this_node_in_the_pool() {
+        let pool =
+            r#"[{"node_id":"a","networking_public_key":"","kind":"Owner","share_index":1},
+                {"node_id":"b","networking_public_key":"","kind":"Guardian","share_index":2}]"#;
+        assert!(check_pool(&key_info(pool), "b", 2, 1).is_ok());
+        assert!(check_pool(&key_info(pool), "b", 1, 1).is_err());
+        assert!(check_pool(&key_info(pool), "c", 3, 1).is_err());
+
+        assert!(agreed_key_info(vec![key_info(pool), key_info(pool)]).is_ok());
+        let other = pool.replace("\"a\"", "\"c\"");
+        assert!(agreed_key_info(vec![key_info(pool), key_info(&other)]).is_err());
+        assert!(agreed_key_info(Vec::new()).is_err());
+    }
+}
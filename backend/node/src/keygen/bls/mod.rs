@@ -0,0 +1,11 @@
+pub mod client;
+pub mod orchestrate;
+pub mod session;
+
+use serde::{ Deserialize, Serialize };
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct KeyGenResult {
+    /// Compressed G1 group public key, hex
+    pub public_key: String,
+}
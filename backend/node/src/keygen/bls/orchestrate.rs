@@ -0,0 +1,104 @@
+use crate::command::MsgContext;
+use crate::communication::envelope;
+use crate::communication::nats::{ BroadcastMessage, JoinMessage, JoinResponse };
+use crate::keygen::eddsa::session::NewKeyGenSession;
+use crate::keygen::bls::KeyGenResult;
+use crate::keygen::{ KeyGenCommand, KeyGenResponse };
+use anyhow::{ bail, Context, Result };
+use shared::key_info::{ Key, KeyInfo, Node, NodeInfo, UpdateKeyInfoCommand };
+use tracing::{ error, info, instrument };
+
+static THRESHOLD: usize = 2;
+
+#[instrument(skip_all)]
+pub fn orchestrate(cmd: KeyGenCommand, ctx: MsgContext) -> Result<KeyGenResponse> {
+    let app = ctx.get_app()?;
+    let nc = app.nc;
+
+    let party_nodes = cmd.party_nodes;
+    let key_id = cmd.key_id;
+    let metadata = cmd.metadata;
+
+    let party_count = party_nodes.len();
+    if party_count < 3 {
+        bail!("Not enough nodes in party");
+    }
+
+    let join_key = format!("network.gridlock.nodes.KeyGenBLS.{}.Join", &key_id);
+    let join_sub = nc.subscribe(&join_key)?;
+
+    let result_key = format!("network.gridlock.nodes.KeyGenBLS.{}.Result", &key_id);
+    let result_sub = nc.subscribe(&result_key)?;
+
+    for (i, node_id) in party_nodes.iter().enumerate() {
+        let key_gen_new = format!("network.gridlock.nodes.KeyGenBLS.new.{node_id}");
+        let key_gen_new_data = serde_json::to_string(
+            &(NewKeyGenSession {
+                key_id: key_id.to_owned(),
+                threshold: THRESHOLD,
+                share_indices: vec![i + 1],
+            })
+        )?;
+        nc.publish(&key_gen_new, &key_gen_new_data)?;
+    }
+
+    let mut msg_vec = Vec::new();
+    for _ in 0..party_count {
+        let next = join_sub.next().context("Waiting for parties to join")?;
+        msg_vec.push(next);
+    }
+
+    let mut node_pool = Vec::new();
+    let mut joins = Vec::new();
+    for m in msg_vec.iter() {
+        let confirmation = serde_json::from_slice::<JoinMessage>(&m.data)?;
+        let node_id = confirmation.node_id.clone().try_into()?;
+        node_pool.push(NodeInfo {
+            node_id: confirmation.node_id.clone(),
+            networking_public_key: confirmation.networking_public_key.clone(),
+            kind: {
+                if app.node.node_id == node_id { Node::Owner } else { Node::Guardian }
+            },
+            share_index: confirmation.party_index,
+        });
+        joins.push(confirmation);
+    }
+    let join_resp = JoinResponse::new(&joins);
+    info!("indices: {:?}", &join_resp.all_party_indices);
+    for m in msg_vec.iter() {
+        if let Err(err) = m.respond(serde_json::to_string(&join_resp)?) {
+            error!("Error: {}", err);
+        }
+    }
+    nc.flush()?;
+
+    let mut res_vec = Vec::new();
+    for _ in 0..party_count {
+        let res = result_sub.next().context("Waiting for keygen results")?;
+        res_vec.push(res);
+    }
+
+    let pk = envelope::decode::<BroadcastMessage<KeyGenResult>>(&res_vec[0].data)?.message;
+
+    let key_info = KeyInfo {
+        kind: Key::BLS {
+            public_key: pk.public_key.clone(),
+        },
+        node_pool: node_pool.clone(),
+        metadata,
+        threshold: Some(THRESHOLD),
+    };
+
+    for node in node_pool {
+        nc.publish(
+            &format!("network.gridlock.nodes.Message.new.{}", node.node_id),
+            &serde_json::to_string(
+                &(UpdateKeyInfoCommand {
+                    key_id: key_id.to_string(),
+                    key_info: key_info.clone(),
+                })
+            )?
+        )?;
+    }
+    Ok(KeyGenResponse::BLS(pk))
+}
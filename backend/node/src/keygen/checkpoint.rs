@@ -0,0 +1,267 @@
+//! Checkpoints of running key generations. Every party records its session on disk, encrypted
+//! with the storage key, until the session returns. A node restarted while its parties were still
+//! waiting to join the session rejoins it within `KEYGEN_RESUME_GRACE_SECS`. Once joined, the
+//! secrets a party dealt only live in memory, so a restarted node publishes an abort instead and
+//! its peers stop waiting for rounds that will never come.
+
+use crate::communication::blocking;
+use crate::communication::protocol::Topic;
+use crate::communication::round_subscriptions::{ round_subject, SessionAbort, ABORT_ROUND };
+use crate::keygen::{ bls, ecdsa, eddsa, frost, sr25519, Key };
+use crate::node::NodeIdentity;
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::App;
+use anyhow::{ Context, Result };
+use chrono::{ DateTime, Duration, Utc };
+use serde::{ Deserialize, Serialize };
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Mutex;
+use tracing::{ info, warn };
+
+const CHECKPOINTS_KEY: &str = "keygen_checkpoints";
+const RESUME_GRACE_VAR: &str = "KEYGEN_RESUME_GRACE_SECS";
+const DEFAULT_RESUME_GRACE_SECS: i64 = 60;
+
+/// Serializes the updates of the stored checkpoints
+static CHECKPOINTS_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct KeygenCheckpoint {
+    #[serde(flatten)]
+    pub kind: Key,
+    pub key_id: String,
+    pub party_index: usize,
+    pub thread_index: usize,
+    pub email: Option<String>,
+    /// Session as the handler of the protocol received it
+    pub session: serde_json::Value,
+    pub started_at: DateTime<Utc>,
+    #[serde(default)]
+    pub joined_at: Option<DateTime<Utc>>,
+}
+
+/// Checkpoints by key id and thread index
+type Checkpoints = BTreeMap<String, KeygenCheckpoint>;
+
+fn checkpoint_id(key_id: &str, thread_index: usize) -> String {
+    format!("{}--{}", key_id, thread_index)
+}
+
+fn update<T>(change: impl FnOnce(&mut Checkpoints) -> T) -> Result<T> {
+    let _lock = CHECKPOINTS_LOCK.lock().unwrap();
+    let mut checkpoints: Checkpoints = match KeyMetadataStore::get_node_level(CHECKPOINTS_KEY)? {
+        Some(content) => serde_json::from_str(&content)?,
+        None => Checkpoints::new(),
+    };
+    let changed = change(&mut checkpoints);
+    let content = serde_json::to_string(&checkpoints)?;
+    KeyMetadataStore::save_node_level(&content, CHECKPOINTS_KEY, &WriteOpts::Modify)?;
+    Ok(changed)
+}
+
+/// Checkpoint of a running party, removed when dropped as the session returns
+pub struct CheckpointGuard {
+    id: String,
+}
+
+impl CheckpointGuard {
+    /// Records that the party joined, from here on a restart aborts the session
+    pub fn joined(&self) {
+        let joined = update(|checkpoints| {
+            if let Some(checkpoint) = checkpoints.get_mut(&self.id) {
+                checkpoint.joined_at = Some(Utc::now());
+            }
+        });
+        if let Err(err) = joined {
+            warn!("Failed to checkpoint the join of keygen party {}: {}", self.id, err);
+        }
+    }
+}
+
+impl Drop for CheckpointGuard {
+    fn drop(&mut self) {
+        if let Err(err) = update(|checkpoints| checkpoints.remove(&self.id)) {
+            warn!("Failed to remove the checkpoint of keygen party {}: {}", self.id, err);
+        }
+    }
+}
+
+/// Checkpoints a party before it joins its session
+pub fn begin<S: Serialize>(
+    kind: Key,
+    key_id: &str,
+    session: &S,
+    party_index: usize,
+    thread_index: usize,
+    email: Option<&str>
+) -> Result<CheckpointGuard> {
+    let id = checkpoint_id(key_id, thread_index);
+    let checkpoint = KeygenCheckpoint {
+        kind,
+        key_id: key_id.to_string(),
+        party_index,
+        thread_index,
+        email: email.map(str::to_string),
+        session: serde_json::to_value(session)?,
+        started_at: Utc::now(),
+        joined_at: None,
+    };
+    update(|checkpoints| checkpoints.insert(id.clone(), checkpoint)).context(
+        "Checkpoint the keygen session"
+    )?;
+    Ok(CheckpointGuard { id })
+}
+
+fn resume_grace() -> Result<Duration> {
+    let secs = match env::var(RESUME_GRACE_VAR) {
+        Ok(secs) => secs.parse().with_context(|| format!("{} is not a number", secs))?,
+        Err(_) => DEFAULT_RESUME_GRACE_SECS,
+    };
+    Ok(Duration::seconds(secs))
+}
+
+/// A key generation is only rejoined when none of its parties on this node got past the join
+fn is_resumable(parties: &[KeygenCheckpoint], now: DateTime<Utc>, grace: Duration) -> bool {
+    parties
+        .iter()
+        .all(|party| party.joined_at.is_none() && now - party.started_at <= grace)
+}
+
+/// Rejoins or aborts the key generations this node was running when it stopped
+pub fn resume_interrupted(app: &App) -> Result<()> {
+    let interrupted = update(std::mem::take)?;
+    let grace = resume_grace()?;
+    let mut by_key: BTreeMap<String, Vec<KeygenCheckpoint>> = BTreeMap::new();
+    for checkpoint in interrupted.into_values() {
+        by_key.entry(checkpoint.key_id.clone()).or_default().push(checkpoint);
+    }
+    for (key_id, parties) in by_key {
+        if is_resumable(&parties, Utc::now(), grace) {
+            info!("Rejoining the interrupted key generation of {}", key_id);
+            for party in parties {
+                if let Err(err) = resume(app, party) {
+                    warn!("Failed to rejoin the key generation of {}: {}", key_id, err);
+                }
+            }
+        } else if let Err(err) = publish_abort(&app.nc, &parties[0]) {
+            warn!("Failed to abort the interrupted key generation of {}: {}", key_id, err);
+        } else {
+            info!("Aborted the interrupted key generation of {}", key_id);
+        }
+    }
+    Ok(())
+}
+
+fn resume(app: &App, party: KeygenCheckpoint) -> Result<()> {
+    let email = party.email.unwrap_or_default();
+    match party.kind {
+        Key::ECDSA => {
+            ecdsa::session::spawn_keygen(
+                app,
+                serde_json::from_value(party.session)?,
+                party.thread_index
+            );
+        }
+        Key::EDDSA => {
+            eddsa::session::spawn_keygen(
+                app,
+                serde_json::from_value(party.session)?,
+                party.party_index,
+                party.thread_index,
+                &email
+            );
+        }
+        Key::Sr25519 => {
+            sr25519::session::spawn_keygen(
+                app,
+                serde_json::from_value(party.session)?,
+                party.party_index,
+                party.thread_index,
+                &email
+            );
+        }
+        Key::Frost => {
+            frost::session::spawn_keygen(
+                app,
+                serde_json::from_value(party.session)?,
+                party.party_index,
+                party.thread_index,
+                &email
+            );
+        }
+        Key::BLS => {
+            bls::session::spawn_keygen(
+                app,
+                serde_json::from_value(party.session)?,
+                party.party_index,
+                party.thread_index,
+                &email
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Subject the parties of the key generation listen on for aborts. ECDSA parties are driven by
+/// the hub, which is told on the abort subject of its session.
+fn abort_subject(kind: &Key, key_id: &str) -> String {
+    let topic = match kind {
+        Key::ECDSA => {
+            return format!("network.gridlock.nodes.keyGen.session.{}.abort", key_id);
+        }
+        Key::EDDSA => Topic::KeyGenEdDSA,
+        Key::Sr25519 => Topic::KeyGenSr25519,
+        Key::Frost => Topic::KeyGenFrost,
+        Key::BLS => Topic::KeyGenBLS,
+    };
+    round_subject(&topic, key_id, ABORT_ROUND)
+}
+
+fn publish_abort(nc: &blocking::Connection, party: &KeygenCheckpoint) -> Result<()> {
+    let abort = SessionAbort {
+        session_id: party.key_id.clone(),
+        node_id: NodeIdentity::cached()?.node_id.to_string(),
+        party_index: party.party_index,
+        reason: "Node restarted during the key generation".to_string(),
+    };
+    nc.publish(&abort_subject(&party.kind, &party.key_id), serde_json::to_string(&abort)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn party(thread_index: usize, started_at: DateTime<Utc>) -> KeygenCheckpoint {
+        KeygenCheckpoint {
+            kind: Key::EDDSA,
+            key_id: "key".to_string(),
+            party_index: thread_index + 1,
+            thread_index,
+            email: None,
+            session: serde_json::Value::Null,
+            started_at,
+            joined_at: None,
+        }
+    }
+
+    #[test]
+    fn only_keygens_not_joined_within_the_grace_window_are_resumed() {
+        let now = Utc::now();
+        let grace = Duration::seconds(60);
+        let parties = vec![party(0, now - Duration::seconds(30)), party(1, now)];
+        assert!(is_resumable(&parties, now, grace));
+        assert!(!is_resumable(&parties, now + Duration::seconds(31), grace));
+
+        let mut joined = parties.clone();
+        joined[1].joined_at = Some(now);
+        assert!(!is_resumable(&joined, now, grace));
+
+        assert_eq!(
+            abort_subject(&Key::Frost, "key"),
+            "network.gridlock.nodes.KeyGenFrost.key.Abort"
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! Non-hardened BIP-32 derivation of child keys from a threshold ECDSA key. A child key is the
+//! parent key plus a public tweak, every guardian adds the same tweak to its share, so the shares
+//! of one keygen sign for all the children.
+//!
+//! No guardian can derive hardened children without the full secret. The threshold key stands for
+//! the extended key at the hardened prefix of a path, e.g. `m/44'/60'/0'` of `m/44'/60'/0'/0/7`,
+//! and its chain code is derived from the public key and that prefix, so each prefix has its own
+//! children. Wallets derive addresses from the returned public key and chain code like from any
+//! extended public key. Signing sessions given a `derivation_path` sign for the child with
+//! `derive_keyshare` applied to each guardian's share before the first round.
+
+use crate::command::{ JsonCommand, MsgContext };
+use crate::node::NodeIdentity;
+use crate::storage::{ KeyshareAccessor, ECDSA };
+use crate::tenant::{ Access, TenantAuth };
+use anyhow::{ anyhow, bail, Context, Result };
+use curv::arithmetic::Converter;
+use curv::elliptic::curves::{ Point, Scalar, Secp256k1 };
+use curv::BigInt;
+use hmac::{ Hmac, Mac, NewMac };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256, Sha512 };
+use std::str::FromStr;
+
+const HARDENED_OFFSET: u32 = 0x8000_0000;
+const CHAIN_CODE_DOMAIN: &[u8] = b"gridlock-threshold-chain-code";
+
+type HmacSha512 = Hmac<Sha512>;
+
+/// Path like `m/44'/60'/0'/0/7`, hardened components may only lead the path
+#[derive(Clone, Debug, PartialEq)]
+pub struct DerivationPath {
+    /// Hardened indices without the hardened offset
+    pub hardened_prefix: Vec<u32>,
+    pub tail: Vec<u32>,
+}
+
+impl FromStr for DerivationPath {
+    type Err = anyhow::Error;
+
+    fn from_str(path: &str) -> Result<Self> {
+        let mut components = path.split('/');
+        if components.next() != Some("m") {
+            bail!("Derivation path {} does not start with m", path);
+        }
+        let mut derivation_path = DerivationPath { hardened_prefix: Vec::new(), tail: Vec::new() };
+        for component in components {
+            let (index, hardened) = match component.strip_suffix(['\'', 'h']) {
+                Some(index) => (index, true),
+                None => (component, false),
+            };
+            let index = index
+                .parse::<u32>()
+                .ok()
+                .filter(|index| *index < HARDENED_OFFSET)
+                .ok_or_else(|| anyhow!("Invalid component {} of derivation path", component))?;
+            if !hardened {
+                derivation_path.tail.push(index);
+            } else if derivation_path.tail.is_empty() {
+                derivation_path.hardened_prefix.push(index);
+            } else {
+                bail!("Hardened component {} follows a non-hardened one in {}", component, path);
+            }
+        }
+        Ok(derivation_path)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtendedPublicKey {
+    pub public_key: Point<Secp256k1>,
+    pub chain_code: [u8; 32],
+}
+
+impl ExtendedPublicKey {
+    /// Extended key the threshold key stands for at the hardened prefix of a path
+    pub fn at_prefix(public_key: &Point<Secp256k1>, hardened_prefix: &[u32]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(CHAIN_CODE_DOMAIN);
+        hasher.update(&*public_key.to_bytes(true));
+        for index in hardened_prefix {
+            hasher.update((index + HARDENED_OFFSET).to_be_bytes());
+        }
+        let mut chain_code = [0u8; 32];
+        chain_code.copy_from_slice(&hasher.finalize());
+        ExtendedPublicKey {
+            public_key: public_key.clone(),
+            chain_code,
+        }
+    }
+
+    /// BIP-32 `CKDpub`, with the tweak added to the parent key
+    pub fn child(&self, index: u32) -> Result<(ExtendedPublicKey, Scalar<Secp256k1>)> {
+        if index >= HARDENED_OFFSET {
+            bail!("Hardened child {} can't be derived from a public key", index - HARDENED_OFFSET);
+        }
+        let mut mac = HmacSha512::new_from_slice(&self.chain_code).map_err(|err|
+            anyhow!("Failed to create HMAC instance: {}", err)
+        )?;
+        mac.update(&self.public_key.to_bytes(true));
+        mac.update(&index.to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+        let (tweak, chain_code) = digest.split_at(32);
+
+        let tweak = BigInt::from_bytes(tweak);
+        // Such indices are skipped by BIP-32 wallets, the probability is below 2^-127
+        if &tweak >= Scalar::<Secp256k1>::group_order() {
+            bail!("Child {} is not a valid key, use the next index", index);
+        }
+        let tweak = Scalar::from_bigint(&tweak);
+        let public_key = &self.public_key + Point::generator() * &tweak;
+        if public_key.is_zero() {
+            bail!("Child {} is not a valid key, use the next index", index);
+        }
+        let child = ExtendedPublicKey {
+            public_key,
+            chain_code: chain_code.try_into()?,
+        };
+        Ok((child, tweak))
+    }
+}
+
+/// Extended public key at the end of `path` and the sum of the tweaks along its tail
+pub fn derive(
+    public_key: &Point<Secp256k1>,
+    path: &DerivationPath
+) -> Result<(ExtendedPublicKey, Scalar<Secp256k1>)> {
+    let mut key = ExtendedPublicKey::at_prefix(public_key, &path.hardened_prefix);
+    let mut tweak = Scalar::zero();
+    for index in &path.tail {
+        let (child, child_tweak) = key.child(*index)?;
+        key = child;
+        tweak = tweak + child_tweak;
+    }
+    Ok((key, tweak))
+}
+
+/// Keyshare of the child key at `path`. Adding the tweak to the secret of the first dealing shifts
+/// every share and every public share by the same amount, the Paillier keys stay as they are.
+pub fn derive_keyshare(
+    keyshare: &ECDSA,
+    path: &DerivationPath
+) -> Result<(ECDSA, ExtendedPublicKey)> {
+    let (child, tweak) = derive(&keyshare.y_sum, path)?;
+    let shift = Point::generator() * &tweak;
+    let mut derived = keyshare.clone();
+    derived.x_i = &keyshare.x_i + &tweak;
+    derived.y_sum = child.public_key.clone();
+    for public_share in derived.public_key_vec.iter_mut() {
+        *public_share = &*public_share + &shift;
+    }
+    let first_dealing = derived.vss_scheme_vec
+        .first_mut()
+        .context("The keyshare has no commitments")?;
+    first_dealing.commitments[0] = &first_dealing.commitments[0] + &shift;
+
+    let own_public_share = keyshare.party_index
+        .checked_sub(1)
+        .and_then(|position| derived.public_key_vec.get(position));
+    if own_public_share != Some(&(Point::generator() * &derived.x_i)) {
+        bail!("Derived share does not match its public share");
+    }
+    Ok((derived, child))
+}
+
+/// Public key and chain code of a child of an ECDSA key, derived on the guardian from its share
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DeriveChildKeyCommand {
+    pub key_id: String,
+    /// BIP-32 path, only the non-hardened tail is derived, e.g. `m/44'/60'/0'/0/7`
+    pub path: String,
+    /// Account the key is stored for, the proven account on multi user nodes
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Required on multi user nodes
+    #[serde(default)]
+    pub authorization: Option<TenantAuth>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct DerivedChildKey {
+    pub path: String,
+    /// Compressed public key of the child, hex encoded
+    pub public_key: String,
+    pub chain_code: String,
+}
+
+impl JsonCommand for DeriveChildKeyCommand {
+    type Response = DerivedChildKey;
+
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: self.authorization.as_ref(), key_ids: vec![self.key_id.clone()] }
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let path = self.path.parse::<DerivationPath>()?;
+        let keyshare = match ctx.tenant().or(self.email) {
+            Some(email) => KeyshareAccessor::<ECDSA>::read_only_with_email(&self.key_id, &email)?,
+            None => KeyshareAccessor::<ECDSA>::read_only(&self.key_id)?,
+        };
+        let (_, child) = derive_keyshare(&keyshare.key, &path)?;
+        Ok(DerivedChildKey {
+            path: self.path,
+            public_key: hex::encode(&*child.public_key.to_bytes(true)),
+            chain_code: hex::encode(child.chain_code),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::loopback::LoopbackMessenger;
+    use crate::communication::protocol::PresignECDSAAllRounds;
+    use crate::session_manager;
+    use crate::signing::presigning::online;
+    use crate::signing::presigning::presign::PresignClient;
+    use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+    use futures::future::try_join_all;
+    use paillier::{ KeyGeneration, Paillier };
+    use zk_paillier::zkproofs::DLogStatement;
+
+    /// Keyshares of a 2 of 3 key, every party dealt a share of its own secret like at keygen
+    fn dealt_keyshares() -> Vec<ECDSA> {
+        let dealings = (0..3)
+            .map(|_| VerifiableSS::<Secp256k1>::share(1, 3, &Scalar::random()))
+            .collect::<Vec<_>>();
+        let y_sum = dealings
+            .iter()
+            .fold(Point::zero(), |sum, (vss, _)| sum + &vss.commitments[0]);
+        let shares = (0..3)
+            .map(|i| dealings.iter().fold(Scalar::zero(), |sum, (_, shares)| sum + &shares[i]))
+            .collect::<Vec<_>>();
+        let public_key_vec = shares
+            .iter()
+            .map(|x_i| Point::generator() * x_i)
+            .collect::<Vec<_>>();
+        let paillier_keys = (0..3).map(|_| Paillier::keypair().keys()).collect::<Vec<_>>();
+        let paillier_key_vec = paillier_keys
+            .iter()
+            .map(|(ek, _)| ek.clone())
+            .collect::<Vec<_>>();
+        // Range proofs only need prover and verifier to agree on the statement
+        let dlog_statements = paillier_key_vec
+            .iter()
+            .map(|ek| DLogStatement { N: ek.n.clone(), g: BigInt::from(4), ni: BigInt::from(9) })
+            .collect::<Vec<_>>();
+        shares
+            .into_iter()
+            .zip(paillier_keys)
+            .enumerate()
+            .map(|(position, (x_i, (_, dk)))| ECDSA {
+                threshold: 1,
+                y_sum: y_sum.clone(),
+                x_i,
+                party_index: position + 1,
+                public_key_vec: public_key_vec.clone(),
+                vss_scheme_vec: dealings
+                    .iter()
+                    .map(|(vss, _)| vss.clone())
+                    .collect(),
+                paillier_key_vec: paillier_key_vec.clone(),
+                h1_h2_N_tilde_vec: dlog_statements.clone(),
+                paillier_dk: dk.into(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn derived_shares_sign_for_the_child_key() {
+        let keyshares = dealt_keyshares();
+        let path = "m/44'/60'/0'/0/7".parse::<DerivationPath>().unwrap();
+        let derived = keyshares
+            .iter()
+            .map(|keyshare| derive_keyshare(keyshare, &path).unwrap().0)
+            .collect::<Vec<_>>();
+        let (child, _) = derive(&keyshares[0].y_sum, &path).unwrap();
+        assert!(derived.iter().all(|keyshare| keyshare.y_sum == child.public_key));
+
+        let messengers = LoopbackMessenger::<PresignECDSAAllRounds>
+            ::network(&[1, 2, 3])
+            .unwrap();
+        let presignatures = session_manager
+            ::runtime()
+            .block_on(
+                try_join_all(
+                    messengers
+                        .into_iter()
+                        .zip(&derived)
+                        .map(|(peer_messenger, keyshare)| async move {
+                            let client = PresignClient {
+                                peer_messenger,
+                                all_party_indices: vec![1, 2, 3],
+                            };
+                            client.create_presignature("presign", "key", keyshare).await
+                        })
+                )
+            )
+            .unwrap();
+
+        let message = Sha256::digest(b"child key").to_vec();
+        let shares = presignatures
+            .iter()
+            .map(|presignature| online::signature_share(presignature, &message).unwrap())
+            .collect::<Vec<_>>();
+        let nonce = &presignatures[0].R;
+        assert!(online::combine(nonce, &shares, &message, &child.public_key).is_ok());
+        assert!(online::combine(nonce, &shares, &message, &keyshares[0].y_sum).is_err());
+    }
+
+    #[test]
+    fn derives_public_children_like_bip32() {
+        // Test vector 1 of BIP-32, m/0H to m/0H/1
+        let parent = ExtendedPublicKey {
+            public_key: Point::from_bytes(
+                &hex
+                    ::decode("035a784662a4a20a65bf6aab9ae98a6c068a81c52e4b032c0fb5400c706cfccc56")
+                    .unwrap()
+            ).unwrap(),
+            chain_code: hex
+                ::decode("47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141")
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        };
+        let (child, _) = parent.child(1).unwrap();
+        assert_eq!(
+            hex::encode(&*child.public_key.to_bytes(true)),
+            "03501e454bf00751f24b1b489aa925215d66af2234e3891c3b21a52bedb3cd711c"
+        );
+        assert_eq!(
+            hex::encode(child.chain_code),
+            "2a7857631386ba23dacac34180dd1983734e444fdbf774041578e9b6adb37c19"
+        );
+
+        let path = "m/44'/60'/0'/0/7".parse::<DerivationPath>().unwrap();
+        assert_eq!(path.hardened_prefix, vec![44, 60, 0]);
+        assert_eq!(path.tail, vec![0, 7]);
+        assert!("m/0/1'".parse::<DerivationPath>().is_err());
+    }
+}
@@ -1,5 +1,7 @@
+use crate::communication::blocking;
 use crate::communication::ecdsa::{ collect_messages_ordered, collect_messages_p2p };
 use crate::encryption::{ aes_decrypt, aes_encrypt, AES_KEY_BYTES_LEN };
+use crate::entropy::CeremonyEntropy;
 use crate::keygen::ecdsa::KeyGenMessage;
 use crate::keygen::ecdsa::{ KeyGenContext, NewKeyGenSession };
 use crate::security::check_for_small_primes;
@@ -18,7 +20,7 @@ use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::{
     Parameters as ThresholdParameters,
     SharedKeys,
 };
-use nats::Subscription;
+use crate::communication::blocking::Subscription;
 use paillier::EncryptionKey;
 use sha2::Sha256;
 use shared::recovery::EncryptedData;
@@ -99,7 +101,8 @@ impl KeygenClient {
         context: KeyGenContext,
         all_round_subs: AllRoundSubscriptions
     ) -> anyhow::Result<Self> {
-        let phase1_part1_data = Self::phase1_part1(&context);
+        let entropy = CeremonyEntropy::gather(context.key_id, context.share_params.party_index)?;
+        let phase1_part1_data = Self::phase1_part1(&context, &entropy);
 
         let commit_vec = Self::phase1_round1(
             &context,
@@ -239,8 +242,11 @@ impl KeygenClient {
         Ok((decom_vec, point_vec, enc_keys))
     }
 
-    fn phase1_part1(params: &KeyGenContext) -> Phase1Part1Data {
-        let keys = Keys::create(params.share_params.party_index);
+    fn phase1_part1(params: &KeyGenContext, entropy: &CeremonyEntropy) -> Phase1Part1Data {
+        let keys = Keys::create_from(
+            entropy.secret_scalar::<Secp256k1>(),
+            params.share_params.party_index
+        );
         let (commit_i, decom_i) =
             keys.phase1_broadcast_phase3_proof_of_correct_key_proof_of_correct_h1h2();
         Phase1Part1Data {
@@ -507,7 +513,7 @@ impl KeygenClient {
             paillier_key_vec,
             h1_h2_N_tilde_vec: self.h1_h2_n_tilde_vec.to_vec(),
             public_key_vec,
-            paillier_dk: self.private_keys.dk.clone(),
+            paillier_dk: self.private_keys.dk.clone().into(),
         };
 
         keysaver.save_key(&keyshare)
@@ -518,7 +524,7 @@ impl AllRoundSubscriptions {
     pub fn subscribe_to_all_rounds(
         session: &NewKeyGenSession,
         party_num: u16,
-        conn: &nats::Connection
+        conn: &blocking::Connection
     ) -> anyhow::Result<AllRoundSubscriptions> {
         let round1_subject = format_round_subject(&session.key_id, "round1");
         let round1_sub = Self::round_subscribe(round1_subject, conn)?;
@@ -549,7 +555,7 @@ impl AllRoundSubscriptions {
 
     fn round_subscribe(
         round: String,
-        conn: &nats::Connection
+        conn: &blocking::Connection
     ) -> anyhow::Result<RoundSubscription> {
         let subscription = conn.subscribe(&round)?;
         Ok(RoundSubscription {
@@ -2,9 +2,11 @@ mod client;
 pub mod orchestrate;
 pub mod session;
 
+pub use client::THRESHOLD;
+
 use crate::communication::ecdsa::HasSenderId;
 use crate::keygen::ShareParams;
-use nats::Connection;
+use crate::communication::blocking::Connection;
 use serde::{ Deserialize, Serialize };
 use shared::ecdsa::Sum;
 
@@ -47,12 +49,19 @@ impl HasSenderId for KeyGenMessage {
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct NewKeyGenMessage {
     pub key_id: String,
     pub extra_shares: Vec<Option<String>>,
     pub client_e2e_public_key: String,
     pub encrypted_signing_key: String,
     pub email: String,
+    /// Checked against the replay window, see `replay`
+    #[serde(default)]
+    pub timestamp: Option<String>,
+    /// HMAC of timestamp and email made with the signing key
+    #[serde(default)]
+    pub message_hmac: Option<String>,
 }
 
 #[test]
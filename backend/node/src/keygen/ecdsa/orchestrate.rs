@@ -1,5 +1,6 @@
 use crate::command::MsgContext;
 use crate::communication::ecdsa::JoinMessage;
+use crate::keygen::ecdsa::client::THRESHOLD;
 use crate::keygen::ecdsa::{ KeyGenParams, KeyGenResult, NewKeyGenSession };
 use crate::keygen::{ KeyGenCommand, KeyGenResponse };
 use crate::storage::fs::WriteOpts;
@@ -15,6 +16,7 @@ pub fn orchestrate(cmd: KeyGenCommand, ctx: MsgContext) -> Result<KeyGenResponse
 
     let party_nodes = cmd.party_nodes;
     let key_id = cmd.key_id;
+    let metadata = cmd.metadata;
 
     let party_count = party_nodes.len();
     if party_count < 3 {
@@ -93,6 +95,8 @@ pub fn orchestrate(cmd: KeyGenCommand, ctx: MsgContext) -> Result<KeyGenResponse
             y_sum: key_gen_result.y_sum.clone(),
         },
         node_pool: node_pool.clone(),
+        metadata,
+        threshold: Some(THRESHOLD),
     };
 
     for node in node_pool {
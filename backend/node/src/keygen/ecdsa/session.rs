@@ -1,3 +1,5 @@
+use crate::audit::{ AuditAction, AuditedRequest };
+use crate::communication::incoming::IncomingMessage;
 use crate::communication::ecdsa::JoinMessage;
 use crate::keygen::ecdsa::client::{
     AllRoundSubscriptions,
@@ -13,22 +15,50 @@ use crate::keygen::ecdsa::{
     NewKeyGenMessage,
     Sum,
 };
-use crate::keygen::ShareParams;
+use crate::keygen::{ checkpoint, Key, ShareParams };
+use crate::metrics::{ SessionKind, SessionOutcome };
 use crate::storage::KeyshareSaver;
 use crate::App;
-use anyhow::anyhow;
+use anyhow::{ anyhow, bail };
 use curv::arithmetic::Converter;
-use std::thread;
 use std::time::Duration;
 use tracing::{ error, info, instrument };
-use crate::auth::e2e_decrypt;
+use crate::auth::client_e2e_decrypt_secret;
 use crate::node::NodeIdentity;
+use crate::pairing;
 use crate::storage::fs::WriteOpts;
 use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::session_error::{ self, SessionErrorCode };
+use crate::session_manager;
 
 #[instrument(skip_all)]
 fn keygen_session(app: App, session: NewKeyGenSession, extra_share_index: usize) {
     info!("Joining keygen session key_id: {:?}", &session.key_id);
+    let outcome = SessionOutcome::new(SessionKind::KeyGen);
+    let audit = AuditedRequest::new(
+        AuditAction::KeyGen,
+        "ecdsa",
+        &session.key_id,
+        &session.key_id,
+        session.email.as_deref()
+    ).pending();
+    // The hub assigns the party index on join
+    let checkpoint = match
+        checkpoint::begin(
+            Key::ECDSA,
+            &session.key_id,
+            &session,
+            0,
+            extra_share_index,
+            session.email.as_deref()
+        )
+    {
+        Ok(checkpoint) => checkpoint,
+        Err(err) => {
+            error!("{:?}", err);
+            return;
+        }
+    };
     let received_params = match keygen_session_join(&app, &session, extra_share_index) {
         Ok(rp) => rp,
         Err(e) => {
@@ -36,6 +66,7 @@ fn keygen_session(app: App, session: NewKeyGenSession, extra_share_index: usize)
             return;
         }
     };
+    checkpoint.joined();
     info!("Successfully joined the ECDSA key generation session");
 
     let ready_subject = &format!("network.gridlock.nodes.keyGen.session.{}.ready", session.key_id);
@@ -98,8 +129,11 @@ fn keygen_session(app: App, session: NewKeyGenSession, extra_share_index: usize)
                     .unwrap()
             )
         {
-            Ok(()) =>
-                info!("Key gen result successfully published for key id: {:?}", &session.key_id),
+            Ok(()) => {
+                info!("Key gen result successfully published for key id: {:?}", &session.key_id);
+                outcome.complete();
+                audit.complete();
+            }
             Err(err) => {
                 error!("Failed to publish keygen result: {}", err);
                 return;
@@ -134,7 +168,11 @@ fn keygen_session_join(
                 resp_data
             )
         })?;
+    session_manager::check_party_count(params_w_id.num_parties)?;
     let party_index = params_w_id.party_num;
+    if party_index >= params_w_id.num_parties {
+        bail!("Party index {} is outside of {} parties", party_index, params_w_id.num_parties);
+    }
     let all_round_subs = AllRoundSubscriptions::subscribe_to_all_rounds(
         session,
         (party_index + 1) as u16,
@@ -149,25 +187,45 @@ fn keygen_session_join(
     })
 }
 
-pub fn handle_new_session_message(app: &App, message: nats::Message) {
+pub fn handle_new_session_message(app: &App, message: IncomingMessage) {
     let parsed_message = match serde_json::from_slice::<NewKeyGenMessage>(&message.data) {
         Ok(session) => session,
         Err(e) => {
-            error!("Unable to deserialize NewKeyGenMessage message - {e}");
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Unable to deserialize NewKeyGenMessage message - {e}")
+            );
             return;
         }
     };
 
-    let node = match NodeIdentity::load() {
+    if
+        let Err(err) = pairing::ensure_paired(
+            &parsed_message.email,
+            &parsed_message.client_e2e_public_key
+        )
+    {
+        session_error::refuse(app, &message, SessionErrorCode::AuthenticationFailed, err);
+        return;
+    }
+
+    let node = match NodeIdentity::cached() {
         Ok(node) => node,
         Err(err) => {
-            error!("Failed to load node identity: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::InternalError,
+                format!("Failed to load node identity: {}", err)
+            );
             return;
         }
     };
 
-    let decrypted_signing_key = match
-        e2e_decrypt(
+    let node_signing_key = match
+        client_e2e_decrypt_secret(
             &parsed_message.encrypted_signing_key,
             &node.e2e_private_key,
             &parsed_message.client_e2e_public_key
@@ -175,15 +233,12 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
     {
         Ok(key) => key,
         Err(err) => {
-            error!("Failed to decrypt signing key: {}", err);
-            return;
-        }
-    };
-
-    let node_signing_key = match String::from_utf8(decrypted_signing_key.clone()) {
-        Ok(key) => key,
-        Err(err) => {
-            error!("Failed to convert decrypted signing key to string: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::AuthenticationFailed,
+                format!("Failed to decrypt signing key: {}", err)
+            );
             return;
         }
     };
@@ -225,18 +280,22 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
     let num_extra_shares = session.extra_shares.len();
 
     for index in 0..=num_extra_shares {
-        let key = session.key_id.clone();
-        let session = session.clone();
-        let app = app.clone();
-        info!("Spawning ECDSA key gen session thread");
-        match
-            thread::Builder
-                ::new()
-                .name(format!("key_gen_session_{}_{}", key, index))
-                .spawn(move || keygen_session(app, session, index))
-        {
-            Ok(_) => (),
-            Err(_) => error!("Failed to spawn thread for keygen session {}", key),
-        };
+        spawn_keygen(app, session.clone(), index);
     }
 }
+
+/// Spawns the session of one share, also for parties rejoining after a restart
+pub(crate) fn spawn_keygen(app: &App, session: NewKeyGenSession, index: usize) {
+    let key = session.key_id.clone();
+    let app = app.clone();
+    info!("Spawning ECDSA key gen session thread");
+    let thread_name = format!("key_gen_session_{}_{}", key, index);
+    match
+        session_manager::spawn_blocking_session(SessionKind::KeyGen, &key, thread_name, move ||
+            keygen_session(app, session, index)
+        )
+    {
+        Ok(_) => (),
+        Err(_) => error!("Failed to spawn thread for keygen session {}", key),
+    };
+}
@@ -2,8 +2,10 @@ use crate::communication::ecdsa::HasSenderId;
 use crate::communication::nats::PeerMessenger;
 use crate::communication::protocol::{ AllRounds, KeyGenAllRounds };
 use crate::encryption::{ aes_decrypt, aes_encrypt, encryption_key_for_aes };
+use crate::entropy::CeremonyEntropy;
 use crate::keygen::ShareParams;
 use crate::storage::EDDSA;
+use crate::test_seed::seeded_bytes;
 use anyhow::anyhow;
 use curv::arithmetic::Converter;
 use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
@@ -19,6 +21,7 @@ use multi_party_eddsa::protocols::thresholdsig::{
 };
 use serde::de::DeserializeOwned;
 use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
 
 pub struct KeyGenClient<C> {
     pub peer_messenger: C,
@@ -27,24 +30,27 @@ pub struct KeyGenClient<C> {
 }
 
 impl<C> KeyGenClient<C> where C: PeerMessenger<KeyGenAllRounds> {
-    pub fn create_shared_key(&self) -> anyhow::Result<EDDSA> {
+    pub async fn create_shared_key(&self, entropy: &CeremonyEntropy) -> anyhow::Result<EDDSA> {
         let params = ThresholdParameters {
             threshold: self.share_params.threshold as u16,
             share_count: self.share_params.party_count as u16,
         };
 
-        let key = Keys::phase1_create(self.share_params.party_index as u16);
+        let key = Keys::phase1_create_from_private_key(
+            self.share_params.party_index as u16,
+            entropy.secret_bytes()
+        );
 
         let (commitment_to_y_i, blind) = key.phase1_broadcast();
 
-        let commitments = self.exchange_commitments_to_y_i(commitment_to_y_i)?;
+        let commitments = self.exchange_commitments_to_y_i(commitment_to_y_i).await?;
 
         let decommitment_for_y_i = Decommitment {
             blind,
             y_i: key.keypair.public_key.clone(),
         };
 
-        let (blindings, y_vec) = self.exchange_decommitments(decommitment_for_y_i)?;
+        let (blindings, y_vec) = self.exchange_decommitments(decommitment_for_y_i).await?;
 
         let all_party_indices = &*self.all_party_indices
             .iter()
@@ -64,9 +70,9 @@ impl<C> KeyGenClient<C> where C: PeerMessenger<KeyGenAllRounds> {
             &key.keypair.expanded_private_key.private_key
         )?;
 
-        let secret_shares = self.exchange_secret_shares(&enc_vec, &local_secrets)?;
+        let secret_shares = self.exchange_secret_shares(&enc_vec, &local_secrets).await?;
 
-        let vss_scheme_vec = self.exchange_vss(&local_vss)?;
+        let vss_scheme_vec = self.exchange_vss(&local_vss).await?;
 
         let shared_key = key
             .phase2_verify_vss_construct_keypair(
@@ -87,29 +93,41 @@ impl<C> KeyGenClient<C> where C: PeerMessenger<KeyGenAllRounds> {
         })
     }
 
-    pub fn create_ephemeral_shared_key(&self, message: &[u8]) -> anyhow::Result<EphemeralEdDSAKey> {
+    pub async fn create_ephemeral_shared_key(
+        &self,
+        message: &[u8]
+    ) -> anyhow::Result<EphemeralEdDSAKey> {
         let params = ThresholdParameters {
             threshold: self.share_params.threshold as u16,
             share_count: self.share_params.party_count as u16,
         };
 
-        let key = Keys::phase1_create(self.share_params.party_index as u16);
+        let party_index = self.share_params.party_index as u16;
+        let label = format!("eddsa-nonce/{}/{}", party_index, hex::encode(Sha256::digest(message)));
+        let key = match seeded_bytes(&label, 32) {
+            Some(bytes) => {
+                let mut secret = [0u8; 32];
+                secret.copy_from_slice(&bytes);
+                Keys::phase1_create_from_private_key(party_index, secret)
+            }
+            None => Keys::phase1_create(party_index),
+        };
         let key = EphemeralKey::ephermeral_key_create_from_deterministic_secret(
             &key,
             message,
-            self.share_params.party_index as u16
+            party_index
         );
 
         let (commitment_to_y_i, blind) = key.phase1_broadcast();
 
-        let commitments = self.exchange_commitments_to_y_i(commitment_to_y_i)?;
+        let commitments = self.exchange_commitments_to_y_i(commitment_to_y_i).await?;
 
         let decommitment_for_y_i = Decommitment {
             blind,
             y_i: key.R_i.clone(),
         };
 
-        let (blindings, y_vec) = self.exchange_decommitments(decommitment_for_y_i)?;
+        let (blindings, y_vec) = self.exchange_decommitments(decommitment_for_y_i).await?;
 
         let all_party_indices = &*self.all_party_indices
             .iter()
@@ -125,9 +143,9 @@ impl<C> KeyGenClient<C> where C: PeerMessenger<KeyGenAllRounds> {
 
         let enc_vec = Self::encryption_keys_from_y_vec(self, &y_vec, &key.r_i)?;
 
-        let secret_shares = self.exchange_secret_shares(&enc_vec, &local_secrets)?;
+        let secret_shares = self.exchange_secret_shares(&enc_vec, &local_secrets).await?;
 
-        let vss_scheme_vec = self.exchange_vss(&local_vss)?;
+        let vss_scheme_vec = self.exchange_vss(&local_vss).await?;
 
         let shared_key = key
             .phase2_verify_vss_construct_keypair(
@@ -146,35 +164,35 @@ impl<C> KeyGenClient<C> where C: PeerMessenger<KeyGenAllRounds> {
         })
     }
 
-    pub fn publish_result<T: Serialize + DeserializeOwned + Clone>(
+    pub async fn publish_result<T: Serialize + DeserializeOwned + Clone>(
         &self,
         y_sum: T
     ) -> anyhow::Result<()> {
         let _ = self.peer_messenger.broadcast_and_collect_messages(
             &<KeyGenAllRounds as AllRounds>::BroadcastRound::Result,
             y_sum
-        )?;
+        ).await?;
         Ok(())
     }
 
-    fn exchange_commitments_to_y_i(
+    async fn exchange_commitments_to_y_i(
         &self,
         com: KeyGenBroadcastMessage1
     ) -> anyhow::Result<Vec<KeyGenBroadcastMessage1>> {
         self.peer_messenger.broadcast_and_collect_messages(
             &<KeyGenAllRounds as AllRounds>::BroadcastRound::Commit,
             com
-        )
+        ).await
     }
 
-    fn exchange_decommitments(
+    async fn exchange_decommitments(
         &self,
         decommitment: Decommitment
     ) -> anyhow::Result<(Vec<BigInt>, Vec<Point<Ed25519>>)> {
         let decommitments = self.peer_messenger.broadcast_and_collect_messages(
             &<KeyGenAllRounds as AllRounds>::BroadcastRound::Decommit,
             decommitment
-        )?;
+        ).await?;
         let mut blinding_factors = Vec::new();
         let mut y_vec = Vec::new();
         for decom in decommitments {
@@ -185,14 +203,14 @@ impl<C> KeyGenClient<C> where C: PeerMessenger<KeyGenAllRounds> {
         Ok((blinding_factors, y_vec))
     }
 
-    fn exchange_vss(
+    async fn exchange_vss(
         &self,
         vss_scheme: &VerifiableSS<Ed25519>
     ) -> anyhow::Result<Vec<VerifiableSS<Ed25519>>> {
         let vss_schemes = self.peer_messenger.broadcast_and_collect_messages(
             &<KeyGenAllRounds as AllRounds>::BroadcastRound::VSS,
             vss_scheme.clone()
-        )?;
+        ).await?;
         let mut vss_schemes_fixed = Vec::new();
         for vss in vss_schemes {
             let fixed_vss = vss;
@@ -212,7 +230,7 @@ impl<C> KeyGenClient<C> where C: PeerMessenger<KeyGenAllRounds> {
             .collect()
     }
 
-    fn exchange_secret_shares(
+    async fn exchange_secret_shares(
         &self,
         enc_vec: &[Vec<u8>],
         secret_shares: &[Scalar<Ed25519>]
@@ -231,7 +249,7 @@ impl<C> KeyGenClient<C> where C: PeerMessenger<KeyGenAllRounds> {
         let msg_vec = self.peer_messenger.send_p2p_and_collect_messages(
             &<KeyGenAllRounds as AllRounds>::P2PRound::ShareSecret,
             outgoing_messages
-        )?;
+        ).await?;
         let mut party_shares = Vec::new();
         let mut encrypted_data = msg_vec.into_iter();
 
@@ -1,4 +1,5 @@
 use crate::command::MsgContext;
+use crate::communication::envelope;
 use crate::communication::nats::{ BroadcastMessage, JoinMessage, JoinResponse };
 use crate::keygen::eddsa::session::NewKeyGenSession;
 use crate::keygen::eddsa::KeyGenResult;
@@ -17,6 +18,7 @@ pub fn orchestrate(cmd: KeyGenCommand, ctx: MsgContext) -> Result<KeyGenResponse
 
     let party_nodes = cmd.party_nodes;
     let key_id = cmd.key_id;
+    let metadata = cmd.metadata;
 
     let party_count = party_nodes.len();
     if party_count < 3 {
@@ -61,26 +63,22 @@ pub fn orchestrate(cmd: KeyGenCommand, ctx: MsgContext) -> Result<KeyGenResponse
 
     let mut node_pool = Vec::new();
     if msg_vec.len() >= 3 {
-        let mut indices = Vec::new();
+        let mut joins = Vec::new();
         for m in msg_vec.iter() {
             let confirmation = serde_json::from_slice::<JoinMessage>(&m.data)?;
             let node_id = confirmation.node_id.clone().try_into()?;
             node_pool.push(NodeInfo {
-                node_id: confirmation.node_id,
-                networking_public_key: confirmation.networking_public_key,
+                node_id: confirmation.node_id.clone(),
+                networking_public_key: confirmation.networking_public_key.clone(),
                 kind: {
                     if app.node.node_id == node_id { Node::Owner } else { Node::Guardian }
                 },
                 share_index: confirmation.party_index,
             });
-            indices.push(confirmation.party_index);
+            joins.push(confirmation);
         }
-        indices.sort();
-        info!("indices: {:?}", &indices);
-        let join_resp = JoinResponse {
-            party_count: indices.len(),
-            all_party_indices: indices,
-        };
+        let join_resp = JoinResponse::new(&joins);
+        info!("indices: {:?}", &join_resp.all_party_indices);
         for m in msg_vec.iter() {
             match m.respond(serde_json::to_string(&join_resp).unwrap()) {
                 Ok(_) => {}
@@ -99,13 +97,15 @@ pub fn orchestrate(cmd: KeyGenCommand, ctx: MsgContext) -> Result<KeyGenResponse
         res_vec.push(res);
     }
 
-    let pk = serde_json::from_slice::<BroadcastMessage<KeyGenResult>>(&res_vec[0].data)?.message;
+    let pk = envelope::decode::<BroadcastMessage<KeyGenResult>>(&res_vec[0].data)?.message;
 
     let key_info = KeyInfo {
         kind: Key::EDDSA {
             y_sum: pk.y_sum.clone(),
         },
         node_pool: node_pool.clone(),
+        metadata,
+        threshold: Some(THRESHOLD),
     };
 
     for node in node_pool {
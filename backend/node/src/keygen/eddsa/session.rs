@@ -1,4 +1,6 @@
-use crate::auth::e2e_decrypt;
+use crate::audit::{ AuditAction, AuditedRequest };
+use crate::communication::incoming::IncomingMessage;
+use crate::auth::client_e2e_decrypt_secret;
 use crate::communication::nats::{
     BaseMessenger,
     NatsBaseMessenger,
@@ -6,17 +8,21 @@ use crate::communication::nats::{
     NatsPeerMessenger,
 };
 use crate::communication::protocol::{ KeyGenAllRounds, Topic };
+use crate::entropy::CeremonyEntropy;
 use crate::keygen::eddsa::client::KeyGenClient;
 use crate::keygen::eddsa::KeyGenResult;
-use crate::keygen::ShareParams;
+use crate::keygen::{ checkpoint, Key, ShareParams };
 use crate::node::NodeIdentity;
+use crate::pairing;
 use crate::storage::fs::WriteOpts;
 use crate::storage::KeyshareSaver;
 use crate::App;
 use crate::storage::key_metadata_store::KeyMetadataStore;
-use anyhow::bail;
+use crate::metrics::SessionKind;
+use crate::session_error::{ self, SessionErrorCode };
+use crate::session_manager;
+use anyhow::{ anyhow, bail };
 use serde::{ Deserialize, Serialize };
-use std::thread;
 use tracing::{ error, info, instrument };
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -33,6 +39,7 @@ pub struct E2EData {
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct NewKeyGenMessage {
     pub key_id: String,
     pub share_indices: Vec<usize>,
@@ -40,62 +47,89 @@ pub struct NewKeyGenMessage {
     pub client_e2e_public_key: String,
     pub encrypted_signing_key: String,
     pub email: String,
+    /// Checked against the replay window, see `replay`
+    #[serde(default)]
+    pub timestamp: Option<String>,
+    /// HMAC of timestamp and email made with the signing key
+    #[serde(default)]
+    pub message_hmac: Option<String>,
 }
 
-pub fn handle_new_session_message(app: &App, message: nats::Message) {
+pub fn handle_new_session_message(app: &App, message: IncomingMessage) {
     let parsed_message = match serde_json::from_slice::<NewKeyGenMessage>(&message.data[..]) {
         Ok(parsed) => parsed,
         Err(err) => {
-            error!("Failed to parse message: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Failed to parse message: {}", err)
+            );
             return;
         }
     };
 
+    if let Err(err) = save_client_access(&parsed_message) {
+        session_error::refuse(app, &message, SessionErrorCode::AuthenticationFailed, err);
+        return;
+    }
+    let recovery_email = parsed_message.email.clone();
+
     let session = NewKeyGenSession {
         key_id: parsed_message.key_id,
         share_indices: parsed_message.share_indices,
         threshold: parsed_message.threshold,
     };
-    let e2e = E2EData {
-        client_e2e_public_key: parsed_message.client_e2e_public_key.clone(),
-        encrypted_signing_key: parsed_message.encrypted_signing_key,
-    };
 
-    let node = match NodeIdentity::load() {
-        Ok(node) => node,
-        Err(err) => {
-            error!("Failed to load node identity: {}", err);
-            return;
-        }
-    };
+    for (thread_index, party_index) in session.share_indices.iter().enumerate() {
+        spawn_keygen(app, session.clone(), *party_index, thread_index, &recovery_email);
+    }
+}
 
-    let decrypted_signing_key = match
-        e2e_decrypt(&e2e.encrypted_signing_key, &node.e2e_private_key, &e2e.client_e2e_public_key)
-    {
-        Ok(key) => key,
-        Err(err) => {
-            error!("Failed to decrypt signing key: {}", err);
-            return;
-        }
+/// Spawns the session of one share, also for parties rejoining after a restart
+pub(crate) fn spawn_keygen(
+    app: &App,
+    session: NewKeyGenSession,
+    party_index: usize,
+    thread_index: usize,
+    recovery_email: &str
+) {
+    let key = session.key_id.clone();
+    let keyshare_saver = if thread_index > 0 {
+        KeyshareSaver::new_encryptor(&key, thread_index).with_email(recovery_email)
+    } else {
+        KeyshareSaver::new_creator(&key).with_email(recovery_email)
     };
 
-    let node_signing_key = match String::from_utf8(decrypted_signing_key.clone()) {
-        Ok(key) => key,
-        Err(err) => {
-            error!("Failed to convert decrypted signing key to string: {}", err);
-            return;
-        }
-    };
+    session_manager::spawn_session(
+        SessionKind::KeyGen,
+        &key,
+        keygen_session(app.nc.client().clone(), session, party_index, thread_index, keyshare_saver)
+    );
+    info!("Spawned a task to handle key gen");
+}
 
-    let recovery_email = parsed_message.email.clone();
+/// Stores the client's access key and e2e public key sent along with a keygen request, refusing
+/// clients the account has not been paired with
+pub fn save_client_access(message: &NewKeyGenMessage) -> anyhow::Result<()> {
+    pairing::ensure_paired(&message.email, &message.client_e2e_public_key)?;
+    let node = NodeIdentity::cached().map_err(|err|
+        anyhow!("Failed to load node identity: {}", err)
+    )?;
+
+    let node_signing_key = client_e2e_decrypt_secret(
+        &message.encrypted_signing_key,
+        &node.e2e_private_key,
+        &message.client_e2e_public_key
+    ).map_err(|err| anyhow!("Failed to decrypt signing key: {}", err))?;
 
     // Save node_signing_key to file with email
     if
         let Err(e) = KeyMetadataStore::save(
             &node_signing_key,
-            &session.key_id,
+            &message.key_id,
             "access",
-            &recovery_email,
+            &message.email,
             &WriteOpts::Modify
         )
     {
@@ -105,55 +139,39 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
     // Also save the client's e2e public key
     if
         let Err(e) = KeyMetadataStore::save_user_level(
-            &e2e.client_e2e_public_key,
+            &message.client_e2e_public_key,
             "e2e_key",
-            &recovery_email,
+            &message.email,
             &WriteOpts::Modify
         )
     {
         error!("Failed to save client's e2e public key: {}", e);
     } else {
-        info!("Saved client e2e public key for email: {}", recovery_email);
+        info!("Saved client e2e public key for email: {}", message.email);
     }
 
-    for (thread_index, party_index) in session.share_indices.clone().iter().enumerate() {
-        let key = session.key_id.clone();
-        let nc = app.nc.clone();
-        let session = session.clone();
-        let party_index = *party_index;
-        let recovery_email = recovery_email.clone();
-
-        let mut keyshare_saver = KeyshareSaver::new_creator(&key).with_email(&recovery_email);
-        if thread_index > 0 {
-            keyshare_saver = KeyshareSaver::new_encryptor(&key, thread_index).with_email(
-                &recovery_email
-            );
-        }
-
-        match
-            thread::Builder
-                ::new()
-                .name(format!("key_gen_session_{}_{}", key, thread_index))
-                .spawn(move ||
-                    keygen_session(nc, session, party_index, thread_index, keyshare_saver)
-                )
-        {
-            Ok(_) => info!("Spawned a thread to handle key gen"),
-            Err(_) => error!("Failed to spawn thread for keygen session {}", key),
-        };
-    }
+    Ok(())
 }
 
 #[instrument(skip_all)]
-fn keygen_session(
-    conn: nats::Connection,
+async fn keygen_session(
+    conn: async_nats::Client,
     session: NewKeyGenSession,
     party_index: usize,
     thread_index: usize,
     keysaver: KeyshareSaver
 ) -> anyhow::Result<()> {
     let session_id = session.key_id.clone();
-    match keygen_session_inner(conn, session, party_index, thread_index, keysaver) {
+    let audit = AuditedRequest::new(
+        AuditAction::KeyGen,
+        "eddsa",
+        &session_id,
+        &session_id,
+        keysaver.email()
+    );
+    let result = keygen_session_inner(conn, session, party_index, thread_index, keysaver).await;
+    audit.record(&result);
+    match result {
         Ok(_) => {
             info!("EdDSA key generation completed sucessfully, key id: {}", session_id);
         }
@@ -162,18 +180,28 @@ fn keygen_session(
     Ok(())
 }
 
-fn keygen_session_inner(
-    conn: nats::Connection,
+async fn keygen_session_inner(
+    conn: async_nats::Client,
     session: NewKeyGenSession,
     party_index: usize,
     thread_index: usize,
     keysaver: KeyshareSaver
 ) -> anyhow::Result<()> {
-    let node = NodeIdentity::load()?;
+    let node = NodeIdentity::cached()?;
     let node_id = node.node_id.to_string();
     let public_key = node.networking_public_key;
 
     let key_id = session.key_id.clone();
+    // Sampled before joining so an unavailable entropy source does not stall the other parties
+    let entropy = CeremonyEntropy::gather(&key_id, party_index)?;
+    let checkpoint = checkpoint::begin(
+        Key::EDDSA,
+        &key_id,
+        &session,
+        party_index,
+        thread_index,
+        keysaver.email()
+    )?;
 
     let nats_session = NatsBaseSession {
         session_id: key_id.clone(),
@@ -187,8 +215,10 @@ fn keygen_session_inner(
         Topic::KeyGenEdDSA,
         conn.clone(),
         nats_session
-    )?;
-    let join_response = messenger.wait_for_confirmation(std::time::Duration::from_secs(10))?;
+    ).await?;
+    let join_response = messenger
+        .wait_for_confirmation(std::time::Duration::from_secs(10)).await?;
+    checkpoint.joined();
 
     let party_count = join_response.party_count;
     let mut all_party_indices = join_response.all_party_indices;
@@ -210,7 +240,7 @@ fn keygen_session_inner(
         all_party_indices,
     };
 
-    let keyshare = keygen_client.create_shared_key()?;
+    let keyshare = keygen_client.create_shared_key(&entropy).await?;
 
     match keysaver.save_key(&keyshare) {
         Ok(()) => {
@@ -224,7 +254,7 @@ fn keygen_session_inner(
     let y_sum = hex::encode(&*keyshare.y_sum.to_bytes(false));
 
     let y_sum = KeyGenResult { y_sum };
-    keygen_client.publish_result(y_sum)?;
+    keygen_client.publish_result(y_sum).await?;
 
     Ok(())
 }
@@ -0,0 +1,144 @@
+use crate::communication::nats::PeerMessenger;
+use crate::communication::protocol::{ AllRounds, FrostKeyGenAllRounds };
+use crate::encryption::{ aes_decrypt, aes_encrypt, encryption_key_for_aes };
+use crate::entropy::CeremonyEntropy;
+use crate::keygen::ShareParams;
+use crate::storage::Frost;
+use anyhow::{ anyhow, bail, Result };
+use curv::arithmetic::Converter;
+use curv::cryptographic_primitives::proofs::sigma_dlog::DLogProof;
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::{ Point, Scalar, Secp256k1 };
+use curv::BigInt;
+use serde::de::DeserializeOwned;
+use serde::{ Deserialize, Serialize };
+use sha2::Sha256;
+
+/// A party's dealing commitments together with a proof that it knows the dealt secret, which
+/// stops a party from choosing its contribution to cancel out the others'
+#[derive(Clone, Serialize, Deserialize)]
+struct DealingCommitment {
+    vss: VerifiableSS<Secp256k1>,
+    proof: DLogProof<Secp256k1, Sha256>,
+}
+
+pub struct FrostKeyGenClient<C> {
+    pub peer_messenger: C,
+    pub share_params: ShareParams,
+    pub all_party_indices: Vec<usize>,
+}
+
+impl<C> FrostKeyGenClient<C> where C: PeerMessenger<FrostKeyGenAllRounds> {
+    /// Pedersen style distributed key generation: every party deals a random secret with
+    /// Feldman VSS, the group key is the sum of all dealt secrets
+    pub async fn create_shared_key(&self, entropy: &CeremonyEntropy) -> Result<Frost> {
+        let threshold = self.share_params.threshold as u16;
+        let party_index = self.share_params.party_index;
+        let indices = self.all_party_indices
+            .iter()
+            .map(|&i| i as u16)
+            .collect::<Vec<_>>();
+
+        let secret = entropy.secret_scalar::<Secp256k1>();
+        let (vss, secret_shares) = VerifiableSS::<Secp256k1>::share_at_indices(
+            threshold,
+            self.share_params.party_count as u16,
+            &secret,
+            &indices
+        );
+        let proof = DLogProof::<Secp256k1, Sha256>::prove(&secret);
+
+        let commitments = self.peer_messenger.broadcast_and_collect_messages(
+            &<FrostKeyGenAllRounds as AllRounds>::BroadcastRound::Commit,
+            DealingCommitment { vss, proof }
+        ).await?;
+        for (sender, commitment) in self.all_party_indices.iter().zip(&commitments) {
+            DLogProof::verify(&commitment.proof).map_err(|_|
+                anyhow!("Invalid proof of knowledge from party {}", sender)
+            )?;
+            if
+                commitment.vss.parameters.threshold != threshold ||
+                commitment.vss.commitments.first() != Some(&commitment.proof.pk)
+            {
+                bail!("Dealing commitment of party {} does not match its proof", sender);
+            }
+        }
+
+        let enc_vec = commitments
+            .iter()
+            .map(|c| encryption_key_for_aes(&c.vss.commitments[0], &secret))
+            .collect::<Result<Vec<_>>>()?;
+        let received_shares = self.exchange_secret_shares(&enc_vec, &secret_shares).await?;
+
+        for ((sender, commitment), share) in self.all_party_indices
+            .iter()
+            .zip(&commitments)
+            .zip(&received_shares) {
+            commitment.vss
+                .validate_share(share, party_index as u16)
+                .map_err(|_| anyhow!("Secret share from party {} failed VSS verification", sender))?;
+        }
+
+        let x_i = received_shares.iter().fold(Scalar::zero(), |sum, share| sum + share);
+        let group_public_key = commitments
+            .iter()
+            .fold(Point::zero(), |sum, c| sum + &c.vss.commitments[0]);
+
+        Ok(Frost {
+            threshold: self.share_params.threshold,
+            party_index,
+            x_i,
+            group_public_key,
+            vss_scheme_vec: commitments
+                .into_iter()
+                .map(|c| c.vss)
+                .collect(),
+        })
+    }
+
+    pub async fn publish_result<T: Serialize + DeserializeOwned + Clone>(
+        &self,
+        result: T
+    ) -> Result<()> {
+        let _ = self.peer_messenger.broadcast_and_collect_messages(
+            &<FrostKeyGenAllRounds as AllRounds>::BroadcastRound::Result,
+            result
+        ).await?;
+        Ok(())
+    }
+
+    async fn exchange_secret_shares(
+        &self,
+        enc_vec: &[Vec<u8>],
+        secret_shares: &[Scalar<Secp256k1>]
+    ) -> Result<Vec<Scalar<Secp256k1>>> {
+        let mut outgoing_messages = Vec::new();
+
+        for (i, party_index) in self.all_party_indices.iter().enumerate() {
+            if *party_index != self.share_params.party_index {
+                let plaintext = BigInt::to_bytes(&secret_shares[i].to_bigint());
+                outgoing_messages.push(aes_encrypt(&plaintext, &enc_vec[i])?);
+            }
+        }
+        let msg_vec = self.peer_messenger.send_p2p_and_collect_messages(
+            &<FrostKeyGenAllRounds as AllRounds>::P2PRound::ShareSecret,
+            outgoing_messages
+        ).await?;
+        let mut encrypted_data = msg_vec.into_iter();
+
+        let mut party_shares = Vec::new();
+        for (index, party_index) in self.all_party_indices.iter().enumerate() {
+            if *party_index != self.share_params.party_index {
+                let encrypted = encrypted_data
+                    .next()
+                    .ok_or_else(|| anyhow!("Missing secret share from party {}", party_index))?;
+                let plaintext = aes_decrypt(&encrypted, &enc_vec[index])?;
+                party_shares.push(Scalar::from_bigint(&BigInt::from_bytes(&plaintext)));
+            } else {
+                party_shares.push(secret_shares[index].clone());
+            }
+        }
+
+        Ok(party_shares)
+    }
+}
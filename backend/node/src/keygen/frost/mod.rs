@@ -0,0 +1,13 @@
+pub mod client;
+pub mod orchestrate;
+pub mod session;
+
+use serde::{ Deserialize, Serialize };
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct KeyGenResult {
+    /// Compressed group public key, hex
+    pub y_sum: String,
+    /// BIP-340 x-only form of the group public key (the Taproot internal key), hex
+    pub x_only_public_key: String,
+}
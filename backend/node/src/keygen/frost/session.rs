@@ -0,0 +1,172 @@
+use crate::audit::{ AuditAction, AuditedRequest };
+use crate::communication::incoming::IncomingMessage;
+use crate::communication::nats::{
+    BaseMessenger,
+    NatsBaseMessenger,
+    NatsBaseSession,
+    NatsPeerMessenger,
+};
+use crate::communication::protocol::{ FrostKeyGenAllRounds, Topic };
+use crate::entropy::CeremonyEntropy;
+use crate::keygen::eddsa::session::{ save_client_access, NewKeyGenMessage, NewKeyGenSession };
+use crate::keygen::frost::client::FrostKeyGenClient;
+use crate::keygen::frost::KeyGenResult;
+use crate::keygen::{ checkpoint, Key, ShareParams };
+use crate::node::NodeIdentity;
+use crate::signing::frost::protocol::x_only;
+use crate::storage::KeyshareSaver;
+use crate::App;
+use crate::metrics::SessionKind;
+use crate::session_error::{ self, SessionErrorCode };
+use crate::session_manager;
+use anyhow::bail;
+use tracing::{ error, info, instrument };
+
+pub fn handle_new_session_message(app: &App, message: IncomingMessage) {
+    let parsed_message = match serde_json::from_slice::<NewKeyGenMessage>(&message.data[..]) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Failed to parse message: {}", err)
+            );
+            return;
+        }
+    };
+
+    if let Err(err) = save_client_access(&parsed_message) {
+        session_error::refuse(app, &message, SessionErrorCode::AuthenticationFailed, err);
+        return;
+    }
+    let recovery_email = parsed_message.email.clone();
+
+    let session = NewKeyGenSession {
+        key_id: parsed_message.key_id,
+        share_indices: parsed_message.share_indices,
+        threshold: parsed_message.threshold,
+    };
+
+    for (thread_index, party_index) in session.share_indices.iter().enumerate() {
+        spawn_keygen(app, session.clone(), *party_index, thread_index, &recovery_email);
+    }
+}
+
+/// Spawns the session of one share, also for parties rejoining after a restart
+pub(crate) fn spawn_keygen(
+    app: &App,
+    session: NewKeyGenSession,
+    party_index: usize,
+    thread_index: usize,
+    recovery_email: &str
+) {
+    let key = session.key_id.clone();
+    let keyshare_saver = if thread_index > 0 {
+        KeyshareSaver::new_encryptor(&key, thread_index).with_email(recovery_email)
+    } else {
+        KeyshareSaver::new_creator(&key).with_email(recovery_email)
+    };
+
+    session_manager::spawn_session(
+        SessionKind::KeyGen,
+        &key,
+        keygen_session(app.nc.client().clone(), session, party_index, thread_index, keyshare_saver)
+    );
+    info!("Spawned a task to handle FROST key gen");
+}
+
+#[instrument(skip_all)]
+async fn keygen_session(
+    conn: async_nats::Client,
+    session: NewKeyGenSession,
+    party_index: usize,
+    thread_index: usize,
+    keysaver: KeyshareSaver
+) {
+    let key_id = session.key_id.clone();
+    let audit = AuditedRequest::new(
+        AuditAction::KeyGen,
+        "frost",
+        &key_id,
+        &key_id,
+        keysaver.email()
+    );
+    let result = keygen_session_inner(conn, session, party_index, thread_index, keysaver).await;
+    audit.record(&result);
+    match result {
+        Ok(_) => info!("FROST key generation completed sucessfully, key id: {}", key_id),
+        Err(err) => error!("Error in FROST key generation: key id: {}, error: {}", key_id, err),
+    }
+}
+
+async fn keygen_session_inner(
+    conn: async_nats::Client,
+    session: NewKeyGenSession,
+    party_index: usize,
+    thread_index: usize,
+    keysaver: KeyshareSaver
+) -> anyhow::Result<()> {
+    let node = NodeIdentity::cached()?;
+    let key_id = session.key_id.clone();
+    // Sampled before joining so an unavailable entropy source does not stall the other parties
+    let entropy = CeremonyEntropy::gather(&key_id, party_index)?;
+    let checkpoint = checkpoint::begin(
+        Key::Frost,
+        &key_id,
+        &session,
+        party_index,
+        thread_index,
+        keysaver.email()
+    )?;
+
+    let nats_session = NatsBaseSession {
+        session_id: key_id.clone(),
+        thread_index,
+        node_id: node.node_id.to_string(),
+        public_key: node.networking_public_key,
+        party_index,
+    };
+
+    let messenger = NatsBaseMessenger::<FrostKeyGenAllRounds>::new(
+        Topic::KeyGenFrost,
+        conn,
+        nats_session
+    ).await?;
+    let join_response = messenger.wait_for_confirmation(std::time::Duration::from_secs(10)).await?;
+    checkpoint.joined();
+
+    let party_count = join_response.party_count;
+    let mut all_party_indices = join_response.all_party_indices;
+    all_party_indices.sort();
+
+    let peer_messenger = NatsPeerMessenger::from(
+        messenger,
+        party_count,
+        all_party_indices.clone()
+    )?;
+
+    let keygen_client = FrostKeyGenClient {
+        peer_messenger,
+        share_params: ShareParams {
+            threshold: session.threshold,
+            party_count,
+            party_index,
+        },
+        all_party_indices,
+    };
+
+    let keyshare = keygen_client.create_shared_key(&entropy).await?;
+
+    if let Err(err) = keysaver.save_key(&keyshare) {
+        bail!("Unable to save key to file: {}", err);
+    }
+    info!("Saved new key to file: {}", &key_id);
+
+    keygen_client.publish_result(KeyGenResult {
+        y_sum: hex::encode(&*keyshare.group_public_key.to_bytes(true)),
+        x_only_public_key: hex::encode(x_only(&keyshare.group_public_key)),
+    }).await?;
+
+    Ok(())
+}
@@ -0,0 +1,468 @@
+pub mod party;
+
+use crate::auth::client_e2e_decrypt_secret;
+use crate::command::{ JsonCommand, MsgContext, TaggedCommandType };
+use crate::communication::blocking;
+use crate::encryption::encrypt_with_shared_secret;
+use crate::keygen::key_import::party::{
+    ImportAuthorization,
+    ImportedDeal,
+    PrepareKeyImportCommand,
+    PreparedParty,
+    ReceiveImportedShareCommand,
+};
+use crate::keygen::{ ecdsa, eddsa, KeyGenResponse };
+use crate::key_info::verify_key_metadata_signature;
+use crate::reputation;
+use crate::storage::fs::WriteOpts;
+use crate::storage::{ KeyInfoStore, KeyshareSaver, SchnorrkelSecretKey, Sr25519 };
+use crate::App;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Context, Result };
+use curv::arithmetic::Converter;
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::{ Curve, Ed25519, Point, Scalar, Secp256k1 };
+use curv::BigInt;
+use serde::de::DeserializeOwned;
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha512 };
+use shared::ecdsa::Sum;
+use shared::key_info::{ Key, KeyInfo, Node, NodeId, NodeInfo, SignedKeyMetadata };
+use shared::key_info::UpdateKeyInfoCommand;
+use std::convert::{ TryFrom, TryInto };
+use std::thread;
+use std::time::Duration;
+use tracing::info;
+use zeroize::Zeroizing;
+
+/// Generating the safe primes of h1, h2, N tilde takes a while
+const PARTY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Splits an existing private key among the party nodes, this node deals the shares. The parties
+/// end up with keyshares like the ones of a keygen with the same parties.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyImportCommand {
+    pub key_id: String,
+    pub key_type: String,
+    /// Hex private key encrypted to the e2e key of this node. An EdDSA key is the 32 byte seed,
+    /// optionally followed by the public key.
+    pub key: String,
+    pub client_e2e_public_key: String,
+    /// Number of parties able to sign
+    pub threshold: usize,
+    pub party_nodes: Vec<NodeId>,
+    /// Authorization of the owner for each of the party nodes, in the order of `party_nodes`
+    pub authorizations: Vec<ImportAuthorization>,
+    /// Optional signed metadata replicated to all guardians with the key info
+    #[serde(default)]
+    pub metadata: Option<SignedKeyMetadata>,
+    /// Starts the import even if some of the party nodes are quarantined
+    #[serde(default)]
+    pub allow_quarantined_peers: bool,
+}
+
+impl JsonCommand for KeyImportCommand {
+    type Response = KeyGenResponse;
+
+    /// Every party checks the owner's authorization of the import
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        match self.key_type.as_str() {
+            "sr25519" => bail!("sr25519 import not yet implemented"),
+            "eddsa" | "ecdsa" => {}
+            _ => bail!("Unknown type provided for key being imported"),
+        }
+        check_parameters(self.threshold, self.party_nodes.len())?;
+        if self.authorizations.len() != self.party_nodes.len() {
+            bail!("Expected an authorization for each of the {} parties", self.party_nodes.len());
+        }
+        if let Some(metadata) = &self.metadata {
+            verify_key_metadata_signature(metadata, &self.key_id)?;
+        }
+        reputation::check_parties(&self.party_nodes, self.allow_quarantined_peers)?;
+        import_key(&ctx.get_app()?, self)
+    }
+}
+
+fn check_parameters(threshold: usize, party_count: usize) -> Result<()> {
+    if party_count < 3 {
+        bail!("Not enough nodes in party");
+    }
+    if threshold < 2 || threshold > party_count {
+        bail!("Threshold {} is not between 2 and the {} parties", threshold, party_count);
+    }
+    Ok(())
+}
+
+fn import_key(app: &App, cmd: KeyImportCommand) -> Result<KeyGenResponse> {
+    let key = client_e2e_decrypt_secret(
+        &cmd.key,
+        &app.node.e2e_private_key,
+        &cmd.client_e2e_public_key
+    ).map_err(|err| anyhow!("Failed to decrypt the imported key: {}", err))?;
+    let key = Zeroizing::new(
+        hex::decode(key.trim_start_matches("0x")).context("Imported key is not hex")?
+    );
+    // Parsed before the parties spend time generating their auxiliary material
+    let secp256k1_secret = match cmd.key_type.as_str() {
+        "ecdsa" => Some(secp256k1_secret(&key)?),
+        _ => None,
+    };
+    let ed25519_secret = match cmd.key_type.as_str() {
+        "eddsa" => Some(ed25519_secret(&key)?),
+        _ => None,
+    };
+
+    let prepares = cmd.authorizations
+        .iter()
+        .map(|authorization| PrepareKeyImportCommand {
+            key_id: cmd.key_id.clone(),
+            key_type: cmd.key_type.clone(),
+            dealer_public_key: app.node.networking_public_key.clone(),
+            authorization: authorization.clone(),
+        })
+        .collect::<Vec<_>>();
+    let parties = on_every_party(
+        app,
+        &cmd.party_nodes,
+        &prepares,
+        TaggedCommandType::PrepareKeyImport,
+        party::prepare
+    )?;
+    for (node_id, party) in cmd.party_nodes.iter().zip(&parties) {
+        if &party.node_id != node_id {
+            bail!("Node {} answered for {}", party.node_id, node_id);
+        }
+    }
+
+    // The library threshold, see the note in `keygen::ecdsa::client`
+    let threshold = cmd.threshold - 1;
+    let party_count = parties.len() as u16;
+    let (imported, shares, response) = match (secp256k1_secret, ed25519_secret) {
+        (Some(secret), _) => {
+            let mut auxiliary = Vec::new();
+            for party in &parties {
+                let party_auxiliary = party.ecdsa
+                    .clone()
+                    .ok_or_else(|| anyhow!("Node {} sent no Paillier key", party.node_id))?;
+                party_auxiliary
+                    .verify()
+                    .map_err(|err| anyhow!("Node {} auxiliary material: {}", party.node_id, err))?;
+                auxiliary.push(party_auxiliary);
+            }
+            let (vss_scheme_vec, shares) = deal(&secret, threshold as u16, party_count);
+            let y_sum = Point::generator() * &secret;
+            let imported = ImportedDeal::ECDSA { vss_scheme_vec, auxiliary };
+            let response = KeyGenResponse::ECDSA(ecdsa::KeyGenResult {
+                y_sum: Sum {
+                    x: y_sum.x_coord().unwrap().to_hex(),
+                    y: y_sum.y_coord().unwrap().to_hex(),
+                },
+            });
+            (imported, encode_shares(&shares)?, response)
+        }
+        (None, Some(secret)) => {
+            let (vss_scheme_vec, shares) = deal(&secret, threshold as u16, party_count);
+            let y_sum = Point::<Ed25519>::generator() * &secret;
+            let response = KeyGenResponse::EDDSA(eddsa::KeyGenResult {
+                y_sum: hex::encode(&*y_sum.to_bytes(false)),
+            });
+            (ImportedDeal::EDDSA { vss_scheme_vec }, encode_shares(&shares)?, response)
+        }
+        (None, None) => bail!("Unknown type provided for key being imported"),
+    };
+
+    let mut receives = Vec::new();
+    for (i, (party, share)) in parties.iter().zip(shares.iter()).enumerate() {
+        receives.push(ReceiveImportedShareCommand {
+            key_id: cmd.key_id.clone(),
+            threshold,
+            party_index: i + 1,
+            encrypted_share: encrypt_with_shared_secret(
+                share,
+                &app.node.networking_private_key,
+                &party.networking_public_key
+            )?,
+            dealer_public_key: app.node.networking_public_key.clone(),
+            deal: imported.clone(),
+        });
+    }
+    on_every_party::<_, ()>(
+        app,
+        &cmd.party_nodes,
+        &receives,
+        TaggedCommandType::ReceiveImportedShare,
+        |receive| party::receive_share(receive.clone())
+    )?;
+
+    let key_info = KeyInfo {
+        kind: match &response {
+            KeyGenResponse::ECDSA(result) => Key::ECDSA { y_sum: result.y_sum.clone() },
+            KeyGenResponse::EDDSA(result) => Key::EDDSA { y_sum: result.y_sum.clone() },
+            _ => bail!("Unknown type provided for key being imported"),
+        },
+        node_pool: parties
+            .iter()
+            .enumerate()
+            .map(|(i, party)| NodeInfo {
+                node_id: party.node_id.clone(),
+                networking_public_key: party.networking_public_key.clone(),
+                kind: if is_this_node(app, &party.node_id) { Node::Owner } else { Node::Guardian },
+                share_index: i + 1,
+            })
+            .collect(),
+        metadata: cmd.metadata,
+        threshold: Some(threshold),
+    };
+    for node in &key_info.node_pool {
+        app.nc.publish(
+            &format!("network.gridlock.nodes.Message.new.{}", node.node_id),
+            &serde_json::to_string(
+                &(UpdateKeyInfoCommand {
+                    key_id: cmd.key_id.clone(),
+                    key_info: key_info.clone(),
+                })
+            )?
+        )?;
+    }
+    KeyInfoStore::save_key_info(&key_info, &cmd.key_id, &WriteOpts::CreateNewOnly)?;
+
+    info!("Imported key {} into {} parties", cmd.key_id, party_count);
+    Ok(response)
+}
+
+fn is_this_node(app: &App, node_id: &NodeId) -> bool {
+    node_id.to_string() == app.node.node_id.to_string()
+}
+
+/// Sends each party its command at once and collects their answers in order. This node runs
+/// `local` instead of answering its own request.
+fn on_every_party<C, T>(
+    app: &App,
+    node_ids: &[NodeId],
+    commands: &[C],
+    tag: impl Fn(C) -> TaggedCommandType,
+    local: impl Fn(&C) -> Result<T> + Sync
+) -> Result<Vec<T>>
+    where C: Clone + Sync, T: DeserializeOwned + Send
+{
+    let requests = commands
+        .iter()
+        .map(|command| serde_json::to_string(&tag(command.clone())))
+        .collect::<Result<Vec<_>, _>>()?;
+    let local = &local;
+    thread::scope(|scope| {
+        let handles = node_ids
+            .iter()
+            .zip(commands)
+            .zip(&requests)
+            .map(|((node_id, command), request)| {
+                scope.spawn(move || {
+                    if is_this_node(app, node_id) {
+                        return local(command);
+                    }
+                    request_party(&app.nc, node_id, request)
+                })
+            })
+            .collect::<Vec<_>>();
+        node_ids
+            .iter()
+            .zip(handles)
+            .map(|(node_id, handle)| {
+                let answer = handle.join().map_err(|_| anyhow!("Request to {} panicked", node_id))?;
+                answer.map_err(|err| anyhow!("Key import failed on node {}: {}", node_id, err))
+            })
+            .collect()
+    })
+}
+
+fn request_party<T: DeserializeOwned>(
+    nc: &blocking::Connection,
+    node_id: &NodeId,
+    request: &str
+) -> Result<T> {
+    let subject = format!("network.gridlock.nodes.Message.new.{}", node_id);
+    let response = nc
+        .request_timeout(&subject, request, PARTY_TIMEOUT)
+        .context("did not answer the key import, it may be offline")?;
+    let response = String::from_utf8(response.data)?;
+    if let Some(err) = response.strip_prefix("ERROR: ") {
+        bail!("{}", err);
+    }
+    Ok(serde_json::from_str(&response)?)
+}
+
+fn secp256k1_secret(key: &[u8]) -> Result<Scalar<Secp256k1>> {
+    if key.len() != 32 {
+        bail!("Expected a secp256k1 private key of 32 bytes, got {}", key.len());
+    }
+    let number = BigInt::from_bytes(key);
+    if &number >= Scalar::<Secp256k1>::group_order() || number == BigInt::from(0) {
+        bail!("Imported key is not a valid secp256k1 private key");
+    }
+    Ok(Scalar::from_bigint(&number))
+}
+
+/// The signing scalar of an Ed25519 seed as RFC 8032 derives it
+fn ed25519_secret(key: &[u8]) -> Result<Scalar<Ed25519>> {
+    let seed = match key.len() {
+        32 | 64 => &key[..32],
+        len => bail!("Expected an Ed25519 seed of 32 bytes, got {}", len),
+    };
+    let hash = Sha512::digest(seed);
+    let mut scalar = Zeroizing::new([0u8; 32]);
+    scalar.copy_from_slice(&hash[..32]);
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    // Little endian, reduced modulo the group order which leaves the public key unchanged
+    scalar.reverse();
+    Ok(Scalar::from_bigint(&BigInt::from_bytes(&*scalar)))
+}
+
+fn encode_shares<E: Curve>(shares: &[Scalar<E>]) -> Result<Vec<Zeroizing<Vec<u8>>>> {
+    shares
+        .iter()
+        .map(|share| Ok(Zeroizing::new(serde_json::to_vec(share)?)))
+        .collect()
+}
+
+/// Splits the secret the way a keygen of `party_count` parties does: one additive part of the
+/// secret per party, each shared with VSS. Signing and refresh expect a dealing per party.
+pub fn deal<E: Curve>(
+    secret: &Scalar<E>,
+    threshold: u16,
+    party_count: u16
+) -> (Vec<VerifiableSS<E>>, Vec<Scalar<E>>) {
+    let mut parts = (1..party_count).map(|_| Scalar::<E>::random()).collect::<Vec<_>>();
+    let last = parts.iter().fold(secret.clone(), |rest, part| rest - part);
+    parts.push(last);
+
+    let mut shares = vec![Scalar::<E>::zero(); party_count as usize];
+    let mut vss_scheme_vec = Vec::new();
+    for part in &parts {
+        let (vss_scheme, part_shares) = VerifiableSS::share(threshold, party_count, part);
+        for (share, part_share) in shares.iter_mut().zip(part_shares.iter()) {
+            *share = &*share + part_share;
+        }
+        vss_scheme_vec.push(vss_scheme);
+    }
+    (vss_scheme_vec, shares)
+}
+
+/// Public key shares of every party, `g^x_i`
+pub fn public_shares<E: Curve>(vss_scheme_vec: &[VerifiableSS<E>]) -> Vec<Point<E>> {
+    (1..=vss_scheme_vec.len())
+        .map(|index| {
+            vss_scheme_vec
+                .iter()
+                .map(|vss_scheme| vss_scheme.get_point_commitment(index as u16))
+                .fold(Point::zero(), |sum, commitment| sum + commitment)
+        })
+        .collect()
+}
+
+/// Checks a share against the dealing of every party and returns the public key
+pub fn verify_share<E: Curve>(
+    x_i: &Scalar<E>,
+    party_index: usize,
+    threshold: usize,
+    vss_scheme_vec: &[VerifiableSS<E>]
+) -> Result<Point<E>> {
+    let party_count = vss_scheme_vec.len();
+    if party_index == 0 || party_index > party_count {
+        bail!("Party index {} is not one of the {} parties", party_index, party_count);
+    }
+    for vss_scheme in vss_scheme_vec {
+        let parameters = &vss_scheme.parameters;
+        if
+            usize::from(parameters.threshold) != threshold ||
+            usize::from(parameters.share_count) != party_count
+        {
+            bail!("Dealing is not {} of {}", threshold + 1, party_count);
+        }
+    }
+    if Point::generator() * x_i != public_shares(vss_scheme_vec)[party_index - 1] {
+        bail!("Share of party {} does not match the commitments", party_index);
+    }
+    Ok(
+        vss_scheme_vec
+            .iter()
+            .fold(Point::zero(), |sum, vss_scheme| sum + &vss_scheme.commitments[0])
+    )
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KeyImportShareCommand {
+    pub key_id: String,
+    pub key_type: String,
+    pub key_share: String,
+    pub vss: String,
+    pub threshold: usize,
+    pub index: usize,
+    pub key: Option<String>,
+}
+
+impl TryFrom<KeyImportShareCommand> for Sr25519 {
+    type Error = anyhow::Error;
+    fn try_from(k: KeyImportShareCommand) -> Result<Self> {
+        let secret = serde_json::from_str::<Scalar<Ed25519>>(&k.key_share)?;
+        let vss = serde_json::from_str::<VerifiableSS<Ed25519>>(&k.vss)?;
+        let secret_key = match k.key {
+            None => None,
+            Some(key) => Some(serde_json::from_str::<SchnorrkelSecretKey>(&key)?),
+        };
+        Ok(Self {
+            secret_key,
+            threshold: k.threshold,
+            party_index: k.index,
+            x_i: secret.into(),
+            vss_scheme: vss.into(),
+        })
+    }
+}
+
+impl JsonCommand for KeyImportShareCommand {
+    type Response = ();
+
+    fn access(&self) -> Access<'_> {
+        Access::Peer
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        match self.key_type.as_str() {
+            "sr25519" => {
+                let keyfile: Sr25519 = self.clone().try_into()?;
+                let ks = KeyshareSaver::new_creator(&self.key_id);
+                ks.save_key(&keyfile)
+            }
+            _ => bail!("Unknown type provided for key being imported"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dealt_shares_reconstruct_the_imported_key() {
+        let secret = Scalar::<Secp256k1>::random();
+        let (vss_scheme_vec, shares) = deal(&secret, 2, 5);
+        let y_sum = verify_share(&shares[3], 4, 2, &vss_scheme_vec).unwrap();
+        assert_eq!(y_sum, Point::generator() * &secret);
+        assert!(verify_share(&shares[3], 3, 2, &vss_scheme_vec).is_err());
+
+        let reconstructed = vss_scheme_vec[0].reconstruct(&[0, 2, 4], &[
+            shares[0].clone(),
+            shares[2].clone(),
+            shares[4].clone(),
+        ]);
+        assert_eq!(reconstructed, secret);
+    }
+}
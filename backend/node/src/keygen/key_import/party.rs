@@ -0,0 +1,410 @@
+use crate::auth::client_e2e_decrypt_secret;
+use crate::command::{ JsonCommand, MsgContext };
+use crate::encryption::decrypt_with_shared_secret;
+use crate::keygen::key_import::{ public_shares, verify_share };
+use crate::node::NodeIdentity;
+use crate::pairing;
+use crate::security::{
+    check_peer_paillier_key,
+    prove_paillier_key,
+    verify_h1_h2_n_tilde,
+    verify_paillier_key,
+};
+use crate::signing::validation::{ verify_hmac_input, verify_timestamp };
+use crate::storage::backend::{ storage_backend, StorageItem };
+use crate::storage::fs::{ FileSystem, WriteOpts };
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::storage::{ KeyshareSaver, ECDSA, EDDSA };
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Result };
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::{ Curve, Ed25519, Scalar, Secp256k1 };
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::Keys;
+use paillier::{ DecryptionKey, EncryptionKey };
+use serde::{ Deserialize, Serialize };
+use shared::key_info::NodeId;
+use shared::recovery::EncryptedData;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+use tracing::info;
+use zeroize::Zeroizing;
+use zk_paillier::zkproofs::{ CompositeDLogProof, DLogStatement, NiCorrectKeyProof };
+
+/// Imports prepared on this node by key id, until the dealer sends the share. They are only kept
+/// in memory, an import interrupted by a restart is started again.
+static PENDING_IMPORTS: Mutex<BTreeMap<String, PendingImport>> = Mutex::new(BTreeMap::new());
+/// Imports a node prepares at once, each of them holds a fresh Paillier key
+const MAX_PENDING_IMPORTS: usize = 16;
+/// Time the dealer has to send the share once the party prepared
+const PENDING_IMPORT_TTL: Duration = Duration::from_secs(600);
+
+#[derive(Clone)]
+struct PendingImport {
+    /// Networking key of the dealer the owner authorized, the share has to come from it
+    dealer_public_key: String,
+    email: String,
+    client_e2e_public_key: String,
+    node_signing_key: Zeroizing<String>,
+    /// Only ECDSA imports have one
+    paillier_dk: Option<DecryptionKey>,
+    expires_at: Instant,
+}
+
+/// Keeps the import until the share arrives, after dropping the expired ones
+fn reserve(
+    pending: &mut BTreeMap<String, PendingImport>,
+    key_id: &str,
+    import: PendingImport,
+    now: Instant
+) -> Result<()> {
+    pending.retain(|_, pending_import| pending_import.expires_at > now);
+    if pending.contains_key(key_id) {
+        bail!("Key import {} is already being prepared on this node", key_id);
+    }
+    if pending.len() >= MAX_PENDING_IMPORTS {
+        bail!("{} key imports are already being prepared on this node", pending.len());
+    }
+    pending.insert(key_id.to_string(), import);
+    Ok(())
+}
+
+/// Import the share of the dealer is for, it stays pending until the share is saved
+fn pending_import(key_id: &str, dealer_public_key: &str, now: Instant) -> Result<PendingImport> {
+    let mut pending = PENDING_IMPORTS.lock().unwrap();
+    pending.retain(|_, pending_import| pending_import.expires_at > now);
+    let import = pending
+        .get(key_id)
+        .ok_or_else(|| anyhow!("Key import {} was not prepared on this node or expired", key_id))?;
+    if import.dealer_public_key != dealer_public_key {
+        bail!("Share of key {} does not come from the dealer the owner authorized", key_id);
+    }
+    Ok(import.clone())
+}
+
+/// Proof that the owner asked for the import, the same fields a keygen request carries. The HMAC
+/// covers the key id and the dealer, so it can't start another import or one with another dealer.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportAuthorization {
+    pub email: String,
+    pub client_e2e_public_key: String,
+    /// Access key of the imported key, encrypted to the e2e key of the party
+    pub encrypted_signing_key: String,
+    pub timestamp: String,
+    /// Base64 HMAC-SHA256 of `timestamp + email + key id + dealer public key` with the access key
+    pub message_hmac: String,
+}
+
+impl Debug for ImportAuthorization {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ImportAuthorization")
+            .field("email", &self.email)
+            .field("timestamp", &self.timestamp)
+            .finish()
+    }
+}
+
+/// Asked of every party before the dealer splits an imported key
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PrepareKeyImportCommand {
+    pub key_id: String,
+    pub key_type: String,
+    /// Networking public key of the node dealing the shares
+    pub dealer_public_key: String,
+    /// Forwarded by the dealer from the owner's request
+    pub authorization: ImportAuthorization,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PreparedParty {
+    pub node_id: NodeId,
+    pub networking_public_key: String,
+    /// Public auxiliary material of the party, only ECDSA keys have any
+    #[serde(default)]
+    pub ecdsa: Option<EcdsaAuxiliary>,
+}
+
+/// What a GG20 keygen broadcasts in its first round besides the commitment
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EcdsaAuxiliary {
+    pub ek: EncryptionKey,
+    pub ek_proof: NiCorrectKeyProof,
+    pub dlog_statement: DLogStatement,
+    pub composite_dlog_proof_base_h1: CompositeDLogProof,
+    pub composite_dlog_proof_base_h2: CompositeDLogProof,
+}
+
+impl EcdsaAuxiliary {
+    /// Security issue: CVE-2023-33241
+    pub fn verify(&self) -> Result<()> {
+        verify_paillier_key(&self.ek, Some(&self.ek_proof))?;
+        check_peer_paillier_key(&self.ek)?;
+        verify_h1_h2_n_tilde(
+            &self.dlog_statement,
+            &self.composite_dlog_proof_base_h1,
+            &self.composite_dlog_proof_base_h2
+        )
+    }
+}
+
+/// Checks that the owner asked for the import and generates the auxiliary material of this party
+/// for the key being imported
+pub fn prepare(cmd: &PrepareKeyImportCommand) -> Result<PreparedParty> {
+    let is_ecdsa = match cmd.key_type.as_str() {
+        "ecdsa" => true,
+        "eddsa" => false,
+        _ => bail!("Unknown type provided for key being imported"),
+    };
+    let node = NodeIdentity::cached()?;
+    let node_signing_key = authorize(cmd, &node)?;
+    let (ecdsa, paillier_dk) = match is_ecdsa {
+        true => {
+            let (auxiliary, dk) = prepare_ecdsa();
+            (Some(auxiliary), Some(dk))
+        }
+        false => (None, None),
+    };
+    let import = PendingImport {
+        dealer_public_key: cmd.dealer_public_key.clone(),
+        email: cmd.authorization.email.clone(),
+        client_e2e_public_key: cmd.authorization.client_e2e_public_key.clone(),
+        node_signing_key,
+        paillier_dk,
+        expires_at: Instant::now() + PENDING_IMPORT_TTL,
+    };
+    reserve(&mut PENDING_IMPORTS.lock().unwrap(), &cmd.key_id, import, Instant::now())?;
+    Ok(PreparedParty {
+        node_id: NodeId::new_from_uuid(node.node_id),
+        networking_public_key: node.networking_public_key,
+        ecdsa,
+    })
+}
+
+/// Checks the owner's authorization of the import with this dealer and returns the access key
+/// it sent for the key
+fn authorize(cmd: &PrepareKeyImportCommand, node: &NodeIdentity) -> Result<Zeroizing<String>> {
+    let auth = &cmd.authorization;
+    pairing::ensure_paired(&auth.email, &auth.client_e2e_public_key)?;
+    let node_signing_key = client_e2e_decrypt_secret(
+        &auth.encrypted_signing_key,
+        &node.e2e_private_key,
+        &auth.client_e2e_public_key
+    ).map_err(|err| anyhow!("Failed to decrypt signing key: {}", err))?;
+    let message_input = format!(
+        "{}{}{}{}",
+        auth.timestamp,
+        auth.email,
+        cmd.key_id,
+        cmd.dealer_public_key
+    );
+    if !verify_hmac_input(&auth.message_hmac, &message_input, &node_signing_key) {
+        bail!("Key import {} is not authorized by the owner", cmd.key_id);
+    }
+    let key_info_exists = storage_backend()?.exists(&StorageItem::KeyInfo { key_id: &cmd.key_id })?;
+    if key_info_exists || FileSystem::keyfile_exists(&cmd.key_id, 0, Some(&auth.email))? {
+        bail!("Key {} already exists on this node", cmd.key_id);
+    }
+    if !verify_timestamp(&cmd.key_id, &auth.timestamp, &auth.email) {
+        bail!("Timestamp verification failed for key {}", cmd.key_id);
+    }
+    Ok(node_signing_key)
+}
+
+fn prepare_ecdsa() -> (EcdsaAuxiliary, DecryptionKey) {
+    // Only the Paillier key and h1, h2, N tilde are kept, the key share comes from the dealer
+    let keys = Keys::create(0);
+    let (broadcast, _) = keys.phase1_broadcast_phase3_proof_of_correct_key_proof_of_correct_h1h2();
+    let auxiliary = EcdsaAuxiliary {
+        ek: keys.ek.clone(),
+        ek_proof: prove_paillier_key(&keys.dk),
+        dlog_statement: broadcast.dlog_statement,
+        composite_dlog_proof_base_h1: broadcast.composite_dlog_proof_base_h1,
+        composite_dlog_proof_base_h2: broadcast.composite_dlog_proof_base_h2,
+    };
+    (auxiliary, keys.dk)
+}
+
+impl JsonCommand for PrepareKeyImportCommand {
+    type Response = PreparedParty;
+
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        prepare(&self)
+    }
+}
+
+/// Share of an imported key the dealer sends to each party
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReceiveImportedShareCommand {
+    pub key_id: String,
+    /// One below the number of parties able to sign, see the note in `keygen::ecdsa::client`
+    pub threshold: usize,
+    pub party_index: usize,
+    /// Share of this party, encrypted with the secret it shares with the dealer
+    pub encrypted_share: EncryptedData,
+    pub dealer_public_key: String,
+    pub deal: ImportedDeal,
+}
+
+impl Debug for ReceiveImportedShareCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ReceiveImportedShareCommand")
+            .field("key_id", &self.key_id)
+            .field("threshold", &self.threshold)
+            .field("party_index", &self.party_index)
+            .finish()
+    }
+}
+
+/// Public part of the deal, the same for every party
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "key_type")]
+pub enum ImportedDeal {
+    ECDSA {
+        vss_scheme_vec: Vec<VerifiableSS<Secp256k1>>,
+        /// Auxiliary material of every party with its proofs, checked by each of them
+        auxiliary: Vec<EcdsaAuxiliary>,
+    },
+    EDDSA {
+        vss_scheme_vec: Vec<VerifiableSS<Ed25519>>,
+    },
+}
+
+fn decrypt_share<E: Curve>(cmd: &ReceiveImportedShareCommand) -> Result<Scalar<E>> {
+    let node = NodeIdentity::cached()?;
+    let share = Zeroizing::new(
+        decrypt_with_shared_secret(
+            cmd.encrypted_share.clone(),
+            &node.networking_private_key,
+            &cmd.dealer_public_key
+        )?
+    );
+    Ok(serde_json::from_slice(&share)?)
+}
+
+/// Checks the share against the deal and saves it as a keyshare of this node
+pub fn receive_share(cmd: ReceiveImportedShareCommand) -> Result<()> {
+    let pending = pending_import(&cmd.key_id, &cmd.dealer_public_key, Instant::now())?;
+    let saver = KeyshareSaver::new_creator(&cmd.key_id).with_email(&pending.email);
+    match &cmd.deal {
+        ImportedDeal::ECDSA { vss_scheme_vec, auxiliary } => {
+            let x_i = decrypt_share::<Secp256k1>(&cmd)?;
+            let y_sum = verify_share(&x_i, cmd.party_index, cmd.threshold, vss_scheme_vec)?;
+            let party_count = vss_scheme_vec.len();
+            if auxiliary.len() != party_count {
+                bail!("Expected the auxiliary material of {} parties", party_count);
+            }
+            let paillier_dk = pending.paillier_dk
+                .clone()
+                .ok_or_else(|| anyhow!("Key import {} was prepared for EdDSA", cmd.key_id))?;
+            for (position, party_auxiliary) in auxiliary.iter().enumerate() {
+                if position + 1 == cmd.party_index {
+                    if EncryptionKey::from(&paillier_dk).n != party_auxiliary.ek.n {
+                        bail!("Paillier key of party {} is not the one of this node", position + 1);
+                    }
+                    continue;
+                }
+                party_auxiliary
+                    .verify()
+                    .map_err(|err| anyhow!("Party {} auxiliary material: {}", position + 1, err))?;
+            }
+            saver.save_key(
+                &(ECDSA {
+                    threshold: cmd.threshold,
+                    y_sum,
+                    x_i,
+                    party_index: cmd.party_index,
+                    public_key_vec: public_shares(vss_scheme_vec),
+                    vss_scheme_vec: vss_scheme_vec.clone(),
+                    paillier_key_vec: auxiliary
+                        .iter()
+                        .map(|party_auxiliary| party_auxiliary.ek.clone())
+                        .collect(),
+                    h1_h2_N_tilde_vec: auxiliary
+                        .iter()
+                        .map(|party_auxiliary| party_auxiliary.dlog_statement.clone())
+                        .collect(),
+                    paillier_dk: paillier_dk.into(),
+                })
+            )?;
+        }
+        ImportedDeal::EDDSA { vss_scheme_vec } => {
+            let x_i = decrypt_share::<Ed25519>(&cmd)?;
+            let y_sum = verify_share(&x_i, cmd.party_index, cmd.threshold, vss_scheme_vec)?;
+            saver.save_key(
+                &(EDDSA {
+                    threshold: cmd.threshold,
+                    party_index: cmd.party_index,
+                    x_i,
+                    y_sum,
+                    vss_scheme_vec: vss_scheme_vec.clone(),
+                })
+            )?;
+        }
+    }
+    PENDING_IMPORTS.lock().unwrap().remove(&cmd.key_id);
+    save_client_access(&cmd.key_id, &pending)?;
+    info!("Saved imported keyshare {} of party {}", cmd.key_id, cmd.party_index);
+    Ok(())
+}
+
+/// Stores the access key and e2e public key of the owner like a keygen does, so the owner can
+/// sign with the imported key
+fn save_client_access(key_id: &str, import: &PendingImport) -> Result<()> {
+    let email = &import.email;
+    KeyMetadataStore::save(&import.node_signing_key, key_id, "access", email, &WriteOpts::Modify)?;
+    let e2e_public_key = &import.client_e2e_public_key;
+    KeyMetadataStore::save_user_level(e2e_public_key, "e2e_key", email, &WriteOpts::Modify)
+}
+
+impl JsonCommand for ReceiveImportedShareCommand {
+    type Response = ();
+
+    fn access(&self) -> Access<'_> {
+        Access::Peer
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        receive_share(self).map_err(|err| anyhow!("Failed to import keyshare: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(dealer_public_key: &str, expires_at: Instant) -> PendingImport {
+        PendingImport {
+            dealer_public_key: dealer_public_key.to_string(),
+            email: "owner@example.com".to_string(),
+            client_e2e_public_key: String::new(),
+            node_signing_key: Zeroizing::new(String::new()),
+            paillier_dk: None,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn pending_imports_are_bounded_and_expire() {
+        let now = Instant::now();
+        let later = now + PENDING_IMPORT_TTL;
+        let mut pending = BTreeMap::new();
+        reserve(&mut pending, "key", import("dealer", later), now).unwrap();
+        assert!(reserve(&mut pending, "key", import("other dealer", later), now).is_err());
+
+        for index in 1..MAX_PENDING_IMPORTS {
+            reserve(&mut pending, &format!("key {}", index), import("dealer", later), now).unwrap();
+        }
+        assert!(reserve(&mut pending, "one more", import("dealer", later), now).is_err());
+        // Expired imports make room again
+        reserve(&mut pending, "one more", import("dealer", later), later).unwrap();
+        assert_eq!(pending.len(), 1);
+    }
+}
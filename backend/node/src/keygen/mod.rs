@@ -1,12 +1,21 @@
+pub mod bls;
+pub mod checkpoint;
+pub mod derivation;
 pub mod ecdsa;
 pub mod eddsa;
+pub mod frost;
 pub mod key_import;
+pub mod preflight;
 pub mod sr25519;
 
 use crate::command::{ JsonCommand, MsgContext };
+use crate::key_info::verify_key_metadata_signature;
+use crate::reputation;
+use crate::signing::preflight::PreflightReport;
+use crate::tenant::Access;
 use anyhow::Result;
 use serde::{ Deserialize, Serialize };
-use shared::key_info::NodeId;
+use shared::key_info::{ NodeId, SignedKeyMetadata };
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct KeyGenCommand {
@@ -15,6 +24,15 @@ pub struct KeyGenCommand {
     pub party_nodes: Vec<NodeId>,
     pub key_id: String,
     pub session_id: String,
+    /// Optional signed metadata replicated to all guardians with the key info
+    #[serde(default)]
+    pub metadata: Option<SignedKeyMetadata>,
+    /// Starts the keygen even if some of the party nodes are quarantined
+    #[serde(default)]
+    pub allow_quarantined_peers: bool,
+    /// Only checks whether the key could be generated, answers with a `PreflightReport`
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
 impl KeyGenCommand {
@@ -26,11 +44,26 @@ impl KeyGenCommand {
 impl JsonCommand for KeyGenCommand {
     type Response = KeyGenResponse;
 
+    /// Creates a new key, nothing of the account is read
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
     fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        if self.dry_run {
+            return Ok(KeyGenResponse::DryRun(preflight::dry_run(&ctx.get_app()?, &self)));
+        }
+        if let Some(metadata) = &self.metadata {
+            verify_key_metadata_signature(metadata, &self.key_id)?;
+        }
+        reputation::check_parties(&self.party_nodes, self.allow_quarantined_peers)?;
+        preflight::check_parties(&ctx.get_app()?, &self)?;
         match self.kind {
             Key::ECDSA => ecdsa::orchestrate::orchestrate(self, ctx),
             Key::EDDSA => eddsa::orchestrate::orchestrate(self, ctx),
-            Key::Sr25519 => { todo!() }
+            Key::Sr25519 => sr25519::orchestrate::orchestrate(self, ctx),
+            Key::Frost => frost::orchestrate::orchestrate(self, ctx),
+            Key::BLS => bls::orchestrate::orchestrate(self, ctx),
         }
     }
 }
@@ -41,6 +74,8 @@ pub enum Key {
     ECDSA,
     EDDSA,
     Sr25519,
+    Frost,
+    BLS,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -49,6 +84,10 @@ pub enum KeyGenResponse {
     ECDSA(ecdsa::KeyGenResult),
     EDDSA(eddsa::KeyGenResult),
     Sr25519(sr25519::KeyGenResponse),
+    Frost(frost::KeyGenResult),
+    BLS(bls::KeyGenResult),
+    /// Readiness of a dry run, no key was generated
+    DryRun(PreflightReport),
 }
 
 pub struct ShareParams {
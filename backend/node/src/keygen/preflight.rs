@@ -0,0 +1,179 @@
+use crate::command::{ JsonCommand, MsgContext, TaggedCommandType };
+use crate::communication::blocking;
+use crate::key_info::verify_key_metadata_signature;
+use crate::keygen::ecdsa::THRESHOLD;
+use crate::keygen::{ Key, KeyGenCommand };
+use crate::reputation;
+use crate::session_manager;
+use crate::signing::preflight::PreflightReport;
+use crate::storage::key_protocol::ProtocolVersion;
+use crate::storage::KeyInfoStore;
+use crate::App;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Context, Result };
+use serde::{ Deserialize, Serialize };
+use shared::key_info::NodeId;
+use std::env;
+use std::time::Duration;
+use tracing::{ info, warn };
+
+/// Set to true to start keygen without asking the parties what they support, for pools with
+/// nodes that predate the pre-flight
+const SKIP_PREFLIGHT_VAR: &str = "SKIP_KEYGEN_PREFLIGHT";
+const PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Protocols this node can generate keys with
+const SUPPORTED_PROTOCOLS: [ProtocolVersion; 5] = [
+    ProtocolVersion::GG2020_V1,
+    ProtocolVersion::EDDSA_V1,
+    ProtocolVersion::SR25519_V1,
+    ProtocolVersion::FROST_V1,
+    ProtocolVersion::BLS_V1,
+];
+
+/// Asked of every party before keygen starts, a party that can't run the protocol would
+/// otherwise never join and keygen would hang until the phase timeout
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetKeygenCapabilitiesCommand {}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct KeygenCapabilities {
+    pub node_version: String,
+    pub protocols: Vec<ProtocolVersion>,
+}
+
+impl KeygenCapabilities {
+    fn current() -> Self {
+        Self {
+            node_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocols: SUPPORTED_PROTOCOLS.to_vec(),
+        }
+    }
+
+    fn check(&self, required: ProtocolVersion) -> Result<()> {
+        if !self.protocols.contains(&required) {
+            bail!("node {} does not support {}", self.node_version, required);
+        }
+        Ok(())
+    }
+}
+
+impl JsonCommand for GetKeygenCapabilitiesCommand {
+    type Response = KeygenCapabilities;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        Ok(KeygenCapabilities::current())
+    }
+}
+
+fn required_protocol(kind: &Key) -> ProtocolVersion {
+    match kind {
+        Key::ECDSA => ProtocolVersion::GG2020_V1,
+        Key::EDDSA => ProtocolVersion::EDDSA_V1,
+        Key::Sr25519 => ProtocolVersion::SR25519_V1,
+        Key::Frost => ProtocolVersion::FROST_V1,
+        Key::BLS => ProtocolVersion::BLS_V1,
+    }
+}
+
+/// Fails with every incompatible or unreachable party before any keygen session is started
+pub fn check_parties(app: &App, cmd: &KeyGenCommand) -> Result<()> {
+    if env::var(SKIP_PREFLIGHT_VAR).is_ok_and(|value| value == "true") {
+        return Ok(());
+    }
+    let required = required_protocol(&cmd.kind);
+    let request = serde_json::to_string(
+        &TaggedCommandType::GetKeygenCapabilities(GetKeygenCapabilitiesCommand {})
+    )?;
+    let mut incompatible = Vec::new();
+    for node_id in &cmd.party_nodes {
+        // The orchestrator is usually a party, it can't answer its own request while it waits
+        let capabilities = if node_id.to_string() == app.node.node_id.to_string() {
+            Ok(KeygenCapabilities::current())
+        } else {
+            fetch_capabilities(&app.nc, node_id, &request)
+        };
+        if let Err(err) = capabilities.and_then(|capabilities| capabilities.check(required)) {
+            warn!("Party {} can't take part in keygen {}: {}", node_id, cmd.key_id, err);
+            incompatible.push(format!("{}: {}", node_id, err));
+        }
+    }
+    if !incompatible.is_empty() {
+        bail!("Keygen of {} with {} aborted, {}", cmd.key_id, required, incompatible.join("; "));
+    }
+    info!("All {} parties support {}", cmd.party_nodes.len(), required);
+    Ok(())
+}
+
+fn fetch_capabilities(
+    nc: &blocking::Connection,
+    node_id: &NodeId,
+    request: &str
+) -> Result<KeygenCapabilities> {
+    let subject = format!("network.gridlock.nodes.Message.new.{}", node_id);
+    let response = nc
+        .request_timeout(&subject, request, PREFLIGHT_TIMEOUT)
+        .context("did not answer the keygen pre-flight, it may be offline")?;
+    let response = String::from_utf8(response.data)?;
+    if let Some(err) = response.strip_prefix("ERROR: ") {
+        bail!("could not report its capabilities, it may predate the pre-flight: {}", err);
+    }
+    serde_json::from_str(&response).context("Deserialize keygen capabilities")
+}
+
+/// Readiness of a `KeyGenCommand` sent with `dry_run`, without starting any session
+pub fn dry_run(app: &App, cmd: &KeyGenCommand) -> PreflightReport {
+    let mut report = PreflightReport::new();
+    report.record("metadata", match &cmd.metadata {
+        Some(metadata) => verify_key_metadata_signature(metadata, &cmd.key_id),
+        None => Ok(()),
+    });
+    report.record("party_count", check_party_count(cmd.party_nodes.len()));
+    report.record(
+        "key_id",
+        if KeyInfoStore::get_key_info(&cmd.key_id).is_ok() {
+            Err(anyhow!("Key {} already exists on this node", cmd.key_id))
+        } else {
+            Ok(())
+        }
+    );
+    report.record(
+        "quarantine",
+        reputation::check_parties(&cmd.party_nodes, cmd.allow_quarantined_peers)
+    );
+    report.record("capabilities", check_parties(app, cmd));
+    report
+}
+
+fn check_party_count(party_count: usize) -> Result<()> {
+    session_manager::check_party_count(party_count)?;
+    // Every protocol generates keys with this threshold, signing needs one more party
+    if party_count <= THRESHOLD {
+        bail!("{} parties can't hold a key, at least {} are needed", party_count, THRESHOLD + 1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parties_without_the_protocol_are_incompatible() {
+        let capabilities = KeygenCapabilities {
+            node_version: "0.9.0".to_string(),
+            protocols: vec![ProtocolVersion::GG2020_V1, ProtocolVersion::EDDSA_V1],
+        };
+        assert!(capabilities.check(required_protocol(&Key::ECDSA)).is_ok());
+        let err = capabilities.check(required_protocol(&Key::Sr25519)).unwrap_err();
+        assert!(err.to_string().contains("Sr25519"));
+        for protocol in SUPPORTED_PROTOCOLS {
+            assert!(KeygenCapabilities::current().check(protocol).is_ok());
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use crate::communication::nats::PeerMessenger;
+use crate::communication::protocol::{ AllRounds, KeyGenAllRounds };
+use crate::entropy::CeremonyEntropy;
+use crate::keygen::eddsa::client::KeyGenClient;
+use crate::keygen::sr25519::KeyGenResult;
+use crate::storage::Sr25519;
+use anyhow::{ anyhow, bail, Context, Result };
+use curv::arithmetic::Converter;
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::{ Ed25519, Point, Scalar };
+use curv::BigInt;
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{ CompressedRistretto, RistrettoPoint };
+use curve25519_dalek::scalar::Scalar as RistrettoScalar;
+
+/// Distributed sr25519 key generation. Ristretto uses the scalar field of Ed25519, so the shares
+/// are created by the EdDSA key generation rounds. The sr25519 public key is then interpolated
+/// from the Ristretto public shares of the parties.
+pub struct Sr25519KeyGenClient<C> {
+    pub keygen_client: KeyGenClient<C>,
+}
+
+impl<C> Sr25519KeyGenClient<C> where C: PeerMessenger<KeyGenAllRounds> {
+    pub async fn create_shared_key(
+        &self,
+        entropy: &CeremonyEntropy
+    ) -> Result<(Sr25519, CompressedRistretto)> {
+        let eddsa = self.keygen_client.create_shared_key(entropy).await?;
+        let vss_scheme = combine_vss(&eddsa.vss_scheme_vec)?;
+        let all_party_indices = &self.keygen_client.all_party_indices;
+        check_share_indices(&vss_scheme, all_party_indices, eddsa.threshold)?;
+
+        let public_share = RISTRETTO_BASEPOINT_POINT * to_ristretto_scalar(&eddsa.x_i);
+        let public_shares = self.keygen_client.peer_messenger
+            .broadcast_and_collect_messages(
+                &<KeyGenAllRounds as AllRounds>::BroadcastRound::PublicShare,
+                hex::encode(public_share.compress().as_bytes())
+            ).await?
+            .iter()
+            .map(|share| decode_public_share(share))
+            .collect::<Result<Vec<_>>>()?;
+
+        let own_position = all_party_indices
+            .iter()
+            .position(|&index| index == eddsa.party_index)
+            .context("Own party index is missing from the session")?;
+        if public_shares.get(own_position) != Some(&public_share) {
+            bail!("Own public share was not received back unchanged");
+        }
+        let public_key = interpolate_public_key(
+            all_party_indices,
+            &public_shares,
+            eddsa.threshold
+        )?;
+
+        let keyshare = Sr25519 {
+            secret_key: None,
+            threshold: eddsa.threshold,
+            party_index: eddsa.party_index,
+            x_i: eddsa.x_i.into(),
+            vss_scheme: vss_scheme.into(),
+        };
+        Ok((keyshare, public_key.compress()))
+    }
+
+    pub async fn publish_result(&self, result: KeyGenResult) -> Result<()> {
+        self.keygen_client.publish_result(result).await
+    }
+}
+
+/// Sums the VSS schemes of all parties into the scheme of the shared key
+fn combine_vss(vss_scheme_vec: &[VerifiableSS<Ed25519>]) -> Result<VerifiableSS<Ed25519>> {
+    let first = vss_scheme_vec.first().context("Key generation produced no VSS schemes")?;
+    let mut combined = first.clone();
+    for vss in &vss_scheme_vec[1..] {
+        if vss.commitments.len() != combined.commitments.len() {
+            bail!("VSS schemes of the parties have different thresholds");
+        }
+        for (sum, commitment) in combined.commitments.iter_mut().zip(&vss.commitments) {
+            *sum = sum.clone() + commitment;
+        }
+    }
+    Ok(combined)
+}
+
+/// Interpolating the VSS commitments of the first `threshold + 1` parties has to give back the
+/// group key, otherwise the public shares would be interpolated at the wrong points
+fn check_share_indices(
+    vss_scheme: &VerifiableSS<Ed25519>,
+    all_party_indices: &[usize],
+    threshold: usize
+) -> Result<()> {
+    if all_party_indices.len() <= threshold {
+        bail!("Key generation needs more than {} parties", threshold);
+    }
+    let signers = &all_party_indices[..=threshold];
+    let interpolated = signers.iter().fold(Point::<Ed25519>::zero(), |sum, &index| {
+        let coefficient = to_curv_scalar(&lagrange_coefficient(0, index, signers));
+        sum + vss_scheme.get_point_commitment(index as u16) * coefficient
+    });
+    if interpolated != vss_scheme.commitments[0] {
+        bail!("VSS commitments do not interpolate to the group key");
+    }
+    Ok(())
+}
+
+/// Interpolates the public key from the first `threshold + 1` public shares. The shares of the
+/// remaining parties have to lie on the same polynomial, which catches a party that broadcast a
+/// public share not matching its secret share whenever there are more parties than needed.
+fn interpolate_public_key(
+    all_party_indices: &[usize],
+    public_shares: &[RistrettoPoint],
+    threshold: usize
+) -> Result<RistrettoPoint> {
+    if public_shares.len() != all_party_indices.len() || public_shares.len() <= threshold {
+        bail!(
+            "Received {} public shares from {} parties",
+            public_shares.len(),
+            all_party_indices.len()
+        );
+    }
+    let (signers, others) = all_party_indices.split_at(threshold + 1);
+    let interpolate = |x: usize| -> RistrettoPoint {
+        signers
+            .iter()
+            .zip(public_shares)
+            .map(|(&index, share)| share * lagrange_coefficient(x, index, signers))
+            .sum()
+    };
+    for (&index, share) in others.iter().zip(&public_shares[threshold + 1..]) {
+        if interpolate(index) != *share {
+            bail!("Public share of party {} is inconsistent with the other parties", index);
+        }
+    }
+    Ok(interpolate(0))
+}
+
+/// Lagrange coefficient of the party at `index` for evaluating the polynomial at `x`
+pub fn lagrange_coefficient(x: usize, index: usize, signers: &[usize]) -> RistrettoScalar {
+    let x = RistrettoScalar::from(x as u64);
+    let x_i = RistrettoScalar::from(index as u64);
+    let mut numerator = RistrettoScalar::one();
+    let mut denominator = RistrettoScalar::one();
+    for &j in signers.iter().filter(|&&j| j != index) {
+        let x_j = RistrettoScalar::from(j as u64);
+        numerator *= x - x_j;
+        denominator *= x_i - x_j;
+    }
+    numerator * denominator.invert()
+}
+
+pub fn decode_public_share(share: &str) -> Result<RistrettoPoint> {
+    let bytes = hex::decode(share)?;
+    if bytes.len() != 32 {
+        bail!("Public share has length {}", bytes.len());
+    }
+    CompressedRistretto::from_slice(&bytes)
+        .decompress()
+        .ok_or_else(|| anyhow!("Public share is not a valid Ristretto point"))
+}
+
+pub fn to_ristretto_scalar(scalar: &Scalar<Ed25519>) -> RistrettoScalar {
+    let big_endian = scalar.to_bigint().to_bytes();
+    let mut little_endian = [0u8; 32];
+    for (byte, value) in little_endian.iter_mut().zip(big_endian.iter().rev()) {
+        *byte = *value;
+    }
+    RistrettoScalar::from_bytes_mod_order(little_endian)
+}
+
+fn to_curv_scalar(scalar: &RistrettoScalar) -> Scalar<Ed25519> {
+    let mut big_endian = scalar.to_bytes();
+    big_endian.reverse();
+    Scalar::from_bigint(&BigInt::from_bytes(&big_endian))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_public_key_from_any_consistent_shares() {
+        let secret = Scalar::<Ed25519>::random();
+        let indices = vec![1, 2, 3, 4];
+        let (_, shares) = VerifiableSS::<Ed25519>::share_at_indices(1, 4, &secret, &[1, 2, 3, 4]);
+        let mut public_shares = shares
+            .iter()
+            .map(|share| RISTRETTO_BASEPOINT_POINT * to_ristretto_scalar(share))
+            .collect::<Vec<_>>();
+
+        let public_key = interpolate_public_key(&indices, &public_shares, 1).unwrap();
+        assert_eq!(public_key, RISTRETTO_BASEPOINT_POINT * to_ristretto_scalar(&secret));
+
+        public_shares[3] = RISTRETTO_BASEPOINT_POINT;
+        assert!(interpolate_public_key(&indices, &public_shares, 1).is_err());
+    }
+}
@@ -1,6 +1,11 @@
+pub mod client;
+pub mod orchestrate;
+pub mod session;
+
 use crate::command::{ JsonCommand, MsgContext };
 use crate::keygen::key_import::KeyImportShareCommand;
 use crate::storage::SchnorrkelSecretKey;
+use crate::tenant::Access;
 use anyhow::{ bail, Result };
 use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
 use curv::elliptic::curves::{ Ed25519, Scalar };
@@ -17,6 +22,13 @@ pub struct KeyGenCommand {
     pub share_count: usize,
 }
 
+/// Result every party publishes after distributed key generation
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct KeyGenResult {
+    /// Hex encoded compressed Ristretto public key
+    pub pk: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KeyGenResponse {
     pub pk: String,
@@ -26,6 +38,11 @@ pub struct KeyGenResponse {
 impl JsonCommand for KeyGenCommand {
     type Response = KeyGenResponse;
 
+    /// Creates a new key, nothing of the account is read
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
     fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
         match self.key_type.as_str() {
             "sr25519" => generate_key_for_sr25519(&self.key_id, self.threshold, self.share_count),
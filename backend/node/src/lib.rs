@@ -1,36 +1,87 @@
 #![allow(dead_code)]
 #![allow(non_snake_case)]
 
+pub mod approval;
+pub mod attestation;
+pub mod audit;
 pub mod auth;
+pub mod cli;
 pub mod command;
 pub mod communication;
 pub mod config;
+pub mod conformance;
 pub mod eject;
 pub mod encryption;
+pub mod entropy;
+pub mod ffi;
 pub mod ghost_shares;
+pub mod health;
 pub mod key_info;
+pub mod key_info_repair;
 pub mod keygen;
+pub mod liveness;
+pub mod log_tail;
 pub mod logging;
+pub mod metrics;
 pub mod node;
+pub mod observer;
+pub mod operator;
+pub mod pairing;
+pub mod policy;
+pub mod providers;
+pub mod provisioning;
+pub mod rate_limit;
 pub mod recovery;
+pub mod refresh;
+pub mod replay;
+pub mod reputation;
+pub mod revocation;
 mod security;
+pub mod session_error;
+pub mod session_manager;
 pub mod signing;
+pub mod slo;
 pub mod storage;
+pub mod strict;
+pub mod tenant;
+pub mod test_seed;
+#[cfg(feature = "testing")]
+pub mod testkit;
 pub mod user_recovery;
 
 use crate::{ config::*, node::NodeIdentity, logging::GridlockLogInitializer };
-use anyhow::{ anyhow, bail, Result };
+use crate::communication::blocking::Connection;
+use crate::communication::incoming::IncomingMessage;
+use crate::communication::leaf_node::LeafNodeConfig;
+use crate::communication::nats_auth::NatsAuth;
+use crate::metrics::SessionKind;
+use crate::session_error::SessionErrorCode;
+use crate::storage::fs::FileSystem;
+use crate::storage::key_protocol::{
+    KeyProtocol,
+    KeyProtocolStore,
+    ProtocolVersion,
+    SessionProtocol,
+};
+use crate::providers::{
+    ConnectionProvider,
+    IdentityProvider,
+    NatsConnectionProvider,
+    StoredIdentityProvider,
+};
+use anyhow::{ bail, Result };
 use keygen::eddsa;
 use std::sync::atomic::{ AtomicBool, Ordering };
-use std::sync::mpsc;
+use std::sync::{ mpsc, Arc };
 use std::sync::mpsc::TryRecvError;
 use std::time::Duration;
 use tracing::{ info, warn };
-use std::env;
 
 #[derive(Clone)]
 pub struct App {
-    pub nc: nats::Connection,
+    /// The node's only NATS connection, it reconnects on its own. Sessions running as tasks use
+    /// its async `client()`.
+    pub nc: Connection,
     pub node: NodeIdentity,
 }
 
@@ -38,10 +89,14 @@ pub static NATS_CONNECTED: AtomicBool = AtomicBool::new(false);
 
 impl App {
     pub fn new() -> Result<App> {
-        let node = match node::NodeIdentity::load() {
-            Ok(node) => node,
-            Err(_) => { create_new_node_identity()? }
-        };
+        App::with_providers(&StoredIdentityProvider, Arc::new(NatsConnectionProvider))
+    }
+
+    pub fn with_providers(
+        identity: &impl IdentityProvider,
+        connector: Arc<dyn ConnectionProvider>
+    ) -> Result<App> {
+        let node = identity.identity()?;
         info!("Version: {}", env!("CARGO_PKG_VERSION"));
         info!("-----------------------------------");
         info!("Hello, you can call me \x1b[34m\x1b[1m{}\x1b[0m", node.name);
@@ -65,83 +120,99 @@ impl App {
             node.e2e_public_key
         );
         info!("-----------------------------------");
-        let nc = get_nats_connection()?;
+        let nc = Connection::new(connector.connect()?);
 
         Ok(App { nc, node })
     }
 
-    pub fn try_reconnect(&mut self) -> Result<()> {
-        warn!("Try reconnect NATs");
-        self.nc = get_nats_connection()?;
-        Ok(())
+    /// Current identity of this node. Unlike `node`, which is taken at startup, it reflects an
+    /// identity saved since.
+    pub fn node_identity(&self) -> Result<NodeIdentity> {
+        NodeIdentity::cached()
     }
 }
 
 pub fn start() -> Result<App> {
+    Config::load()?;
     if Config::create_data_dirs().is_err() {
         bail!("Failed to create application data directories");
     }
     GridlockLogInitializer::init();
-    App::new()
+    test_seed::check_configured()?;
+    let app = App::new()?;
+    health::spawn_health_sampler()?;
+    health::spawn_attestation_publisher(app.nc.clone())?;
+    metrics::spawn_nats_publisher(app.nc.clone(), &app.node.node_id.to_string())?;
+    refresh::orchestrate::spawn_refresh_scheduler(app.nc.clone(), app.node.node_id.to_string())?;
+    liveness::spawn_heartbeat(app.nc.clone(), app.node.node_id.to_string())?;
+    communication::subscription_registry::spawn_sweeper()?;
+    if let Err(err) = keygen::checkpoint::resume_interrupted(&app) {
+        warn!("Failed to resume the interrupted key generations: {}", err);
+    }
+
+    // Moves files still under the legacy or a rotated-out storage key to the current one
+    if let Err(err) = storage::reencryption::spawn_reencryption_job() {
+        warn!("Failed to start re-encrypting files with the current storage key: {}", err);
+    }
+    storage::keyshare_check::verify_keyshares_on_startup()?;
+    Ok(app)
 }
 
-pub fn get_nats_connection() -> Result<nats::Connection> {
-    let address = Config::get_nats_address();
-    let NATS_USER = env
-        ::var("NATS_USER")
-        .map_err(|_| anyhow!("NATS_USER environment variable is not set"))?;
-    let NATS_PASSWORD = env
-        ::var("NATS_PASSWORD")
-        .map_err(|_| anyhow!("NATS_PASSWORD environment variable is not set"))?;
-
-    // Add retry logic with exponential backoff
-    let mut retry_count = 0;
-    let max_retries = 5;
-    let mut wait_time = Duration::from_secs(1);
-
-    loop {
-        match
-            nats::Options
-                ::with_user_pass(&NATS_USER, &NATS_PASSWORD)
-                .disconnect_callback(|| {
-                    warn!("NATs disconnected");
-                    NATS_CONNECTED.store(false, Ordering::Relaxed);
-                })
-                .reconnect_callback(|| {
-                    warn!("NATs reconnected");
-                    NATS_CONNECTED.store(true, Ordering::Relaxed);
-                })
-                .retry_on_failed_connect()
-                .connect(&address)
-        {
-            Ok(conn) => {
-                NATS_CONNECTED.store(true, Ordering::Relaxed);
-                info!("Connected to NATS successfully at: {:?}", &address);
-                return Ok(conn);
-            }
-            Err(err) => {
-                if retry_count >= max_retries {
-                    return Err(
-                        anyhow!(
-                            "Failed to connect to NATS at \"{}\" after {} attempts. Last error: {}. Please check that:\n1. The NATS server is running\n2. The address is correct\n3. Network connectivity is available",
-                            address,
-                            max_retries,
-                            err
-                        )
-                    );
-                }
-                warn!(
-                    "Failed to connect to NATS (attempt {}/{}). Retrying in {} seconds...",
-                    retry_count + 1,
-                    max_retries,
-                    wait_time.as_secs()
-                );
-                std::thread::sleep(wait_time);
-                wait_time *= 2; // Exponential backoff
-                retry_count += 1;
-            }
-        }
+/// Setup of an air-gapped guardian, which only reads its identity and keyshares and never
+/// connects to NATS
+pub fn start_offline() -> Result<NodeIdentity> {
+    Config::load()?;
+    if Config::create_data_dirs().is_err() {
+        bail!("Failed to create application data directories");
     }
+    GridlockLogInitializer::init();
+    test_seed::check_configured()?;
+    storage::keyshare_check::verify_keyshares_on_startup()?;
+    NodeIdentity::cached()
+}
+
+/// Connects to the NATS network. The client connects in the background and retries for as long
+/// as the node runs, `NATS_CONNECTED` tells whether it is connected.
+pub fn get_nats_client() -> Result<async_nats::Client> {
+    static CONNECTED_BEFORE: AtomicBool = AtomicBool::new(false);
+    let auth = NatsAuth::from_env()?;
+
+    // In outbound-only mode we talk to a local leaf node which dials out to the hub over WSS
+    let (address, auth) = match LeafNodeConfig::from_env()? {
+        Some(leaf) => {
+            let local_auth = leaf.ensure_running(&auth)?;
+            (leaf.local_address(), local_auth)
+        }
+        None => (Config::get_nats_address(), auth),
+    };
+    let client = session_manager::runtime().block_on(async {
+        let options = auth
+            .options().await?
+            .event_callback(|event| async move {
+                match event {
+                    async_nats::Event::Connected => {
+                        // The first connection isn't a reconnect
+                        if CONNECTED_BEFORE.swap(true, Ordering::Relaxed) {
+                            warn!("NATs reconnected");
+                            metrics::record_nats_reconnect();
+                        } else {
+                            info!("Connected to NATS successfully");
+                        }
+                        NATS_CONNECTED.store(true, Ordering::Relaxed);
+                    }
+                    async_nats::Event::Disconnected => {
+                        warn!("NATs disconnected");
+                        health::record_nats_disconnect();
+                        NATS_CONNECTED.store(false, Ordering::Relaxed);
+                    }
+                    _ => {}
+                }
+            })
+            .retry_on_initial_connect();
+        anyhow::Ok(options.connect(address.as_str()).await?)
+    })?;
+    info!("Connecting to NATS at: {:?}", &address);
+    Ok(client)
 }
 
 pub fn create_new_node_identity() -> Result<NodeIdentity> {
@@ -152,36 +223,256 @@ pub fn create_new_node_identity() -> Result<NodeIdentity> {
     Ok(node)
 }
 
-pub fn handle_message(app: &App, message: nats::Message) {
-    info!("Received a message with subject \"{}\"", message.subject);
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageRoute {
+    KeyGenECDSA,
+    KeySignECDSA,
+    KeyGenEdDSA,
+    KeySignEdDSA,
+    KeySignSr25519,
+    KeyGenFrost,
+    KeySignFrost,
+    KeyGenSr25519,
+    KeyGenBLS,
+    KeySignBLS,
+    PresignECDSA,
+    Command,
+    KeyShareRecovery,
+    UserRecovery,
+    UserRecoveryConfirm,
+    ShareRefresh,
+}
+
+impl MessageRoute {
+    /// Kind of session the route starts, `None` for commands
+    pub fn session_kind(&self) -> Option<SessionKind> {
+        match self {
+            | MessageRoute::KeyGenECDSA
+            | MessageRoute::KeyGenEdDSA
+            | MessageRoute::KeyGenFrost
+            | MessageRoute::KeyGenSr25519
+            | MessageRoute::KeyGenBLS => Some(SessionKind::KeyGen),
+            | MessageRoute::KeySignECDSA
+            | MessageRoute::KeySignEdDSA
+            | MessageRoute::KeySignSr25519
+            | MessageRoute::KeySignFrost
+            | MessageRoute::KeySignBLS
+            | MessageRoute::PresignECDSA => Some(SessionKind::Signing),
+            | MessageRoute::KeyShareRecovery
+            | MessageRoute::UserRecovery
+            | MessageRoute::UserRecoveryConfirm => Some(SessionKind::Recovery),
+            MessageRoute::ShareRefresh => Some(SessionKind::Refresh),
+            MessageRoute::Command => None,
+        }
+    }
+
+    /// Protocol the key of a session on this route must have been generated with, `None` for
+    /// routes that aren't tied to one
+    fn key_protocol(&self, data: &serde_json::Value) -> Option<KeyProtocol> {
+        match self {
+            MessageRoute::KeySignECDSA | MessageRoute::PresignECDSA => Some(KeyProtocol::GG2020),
+            MessageRoute::KeySignEdDSA => Some(KeyProtocol::EdDSA),
+            MessageRoute::KeySignSr25519 => Some(KeyProtocol::Sr25519),
+            MessageRoute::KeySignFrost => Some(KeyProtocol::Frost),
+            MessageRoute::KeySignBLS => Some(KeyProtocol::BLS),
+            MessageRoute::KeyShareRecovery | MessageRoute::ShareRefresh =>
+                match data.get("key_type").and_then(|kind| kind.as_str()) {
+                    Some("ECDSA") => Some(KeyProtocol::GG2020),
+                    Some("EDDSA") => Some(KeyProtocol::EdDSA),
+                    Some("Sr25519") => Some(KeyProtocol::Sr25519),
+                    Some("Frost") => Some(KeyProtocol::Frost),
+                    Some("BLS") => Some(KeyProtocol::BLS),
+                    _ => None,
+                }
+            _ => None,
+        }
+    }
+}
+
+/// Refuses signing and recovery sessions for a key generated with another protocol or version
+/// than the session runs, before any key material is loaded. The session may declare the exact
+/// version in `protocol_version`. Keys stored before versions were recorded are not checked.
+fn check_key_protocol(route: MessageRoute, data: &[u8]) -> Result<()> {
+    let checked =
+        route.session_kind() == Some(SessionKind::Signing) ||
+        route == MessageRoute::KeyShareRecovery ||
+        route == MessageRoute::ShareRefresh;
+    if !checked {
+        return Ok(());
+    }
+    // Malformed messages are reported by the session handler
+    let value = match serde_json::from_slice::<serde_json::Value>(data) {
+        Ok(value) => value,
+        Err(_) => {
+            return Ok(());
+        }
+    };
+    let key_id = match value.get("key_id").and_then(|key_id| key_id.as_str()) {
+        Some(key_id) => key_id,
+        None => {
+            return Ok(());
+        }
+    };
+    let stored = match KeyProtocolStore::get(key_id)? {
+        Some(stored) => stored,
+        None => {
+            return Ok(());
+        }
+    };
+    let version = match value.get("protocol_version").filter(|version| !version.is_null()) {
+        Some(version) => Some(serde_json::from_value::<ProtocolVersion>(version.clone())?),
+        None => None,
+    };
+    let declared = SessionProtocol {
+        protocol: route.key_protocol(&value),
+        version,
+    };
+    declared.check(key_id, stored)?;
+    Ok(())
+}
+
+/// Refuses signing sessions for keys this node holds no keyshare of, before the request is
+/// authenticated and its approval is asked for
+fn check_keyshare_present(route: MessageRoute, data: &[u8]) -> Result<()> {
+    if route.session_kind() != Some(SessionKind::Signing) {
+        return Ok(());
+    }
+    // Malformed messages are reported by the session handler
+    let value = match serde_json::from_slice::<serde_json::Value>(data) {
+        Ok(value) => value,
+        Err(_) => {
+            return Ok(());
+        }
+    };
+    let key_id = match value.get("key_id").and_then(|key_id| key_id.as_str()) {
+        Some(key_id) => key_id,
+        None => {
+            return Ok(());
+        }
+    };
+    let email = value.get("email").and_then(|email| email.as_str());
+    if !FileSystem::keyfile_exists(key_id, 0, email)? {
+        bail!("No keyshare of key {} on this node", key_id);
+    }
+    Ok(())
+}
 
-    if message.subject.starts_with("network.gridlock.nodes.keyGen.") {
-        info!("start keygen process");
-        keygen::ecdsa::session::handle_new_session_message(app, message);
-    } else if message.subject.starts_with("network.gridlock.nodes.keySign.") {
-        signing::ecdsa::session::handle_new_session_message(app, message);
-    } else if message.subject.starts_with("network.gridlock.nodes.KeyGenEdDSA.") {
-        eddsa::session::handle_new_session_message(app, message);
-    } else if message.subject.starts_with("network.gridlock.nodes.KeySignEdDSA.") {
-        signing::eddsa::session::handle_new_session_message(app, message);
-    } else if message.subject.starts_with("network.gridlock.nodes.KeySignSr25519.") {
-        signing::sr25519_musign::handle_new_session_message(app, message);
-    } else if message.subject.starts_with("network.gridlock.nodes.Message.") {
+/// Maps an incoming subject to the handler responsible for it
+pub fn route_message(subject: &str) -> Option<MessageRoute> {
+    let routes = [
+        ("network.gridlock.nodes.keyGen.", MessageRoute::KeyGenECDSA),
+        ("network.gridlock.nodes.keySign.", MessageRoute::KeySignECDSA),
+        ("network.gridlock.nodes.KeyGenEdDSA.", MessageRoute::KeyGenEdDSA),
+        ("network.gridlock.nodes.KeySignEdDSA.", MessageRoute::KeySignEdDSA),
+        ("network.gridlock.nodes.KeySignSr25519.", MessageRoute::KeySignSr25519),
+        ("network.gridlock.nodes.KeyGenFrost.", MessageRoute::KeyGenFrost),
+        ("network.gridlock.nodes.KeySignFrost.", MessageRoute::KeySignFrost),
+        ("network.gridlock.nodes.KeyGenSr25519.", MessageRoute::KeyGenSr25519),
+        ("network.gridlock.nodes.KeyGenBLS.", MessageRoute::KeyGenBLS),
+        ("network.gridlock.nodes.KeySignBLS.", MessageRoute::KeySignBLS),
+        ("network.gridlock.nodes.PresignECDSA.", MessageRoute::PresignECDSA),
         // To be able manage partner, user and gridlock nodes
-        let _ = command::handle_nats_command(app, message);
-    } else if message.subject.starts_with("network.gridlock.nodes.KeyShareRecovery.") {
-        recovery::recovery_session::handle_new_session_message(app, message);
-    } else if message.subject.starts_with("network.gridlock.nodes.UserRecovery.") {
-        user_recovery::session::handle_new_session_message(app, message);
-    } else if message.subject.starts_with("network.gridlock.nodes.UserRecoveryConfirm.") {
-        user_recovery::confirm::handle_new_session_message(app, message);
-    } else {
-        warn!("Received message with an unrecognized subject: {}", message.subject);
+        ("network.gridlock.nodes.Message.", MessageRoute::Command),
+        ("network.gridlock.nodes.KeyShareRecovery.", MessageRoute::KeyShareRecovery),
+        ("network.gridlock.nodes.UserRecovery.", MessageRoute::UserRecovery),
+        ("network.gridlock.nodes.UserRecoveryConfirm.", MessageRoute::UserRecoveryConfirm),
+        ("network.gridlock.nodes.ShareRefresh.", MessageRoute::ShareRefresh),
+    ];
+    routes
+        .iter()
+        .find(|(prefix, _)| subject.starts_with(prefix))
+        .map(|(_, route)| *route)
+}
+
+pub fn handle_message(app: &App, message: IncomingMessage) {
+    info!("Received a message with subject \"{}\"", message.subject);
+
+    let route = route_message(&message.subject);
+    // Checked first, the checks below already cost the node work
+    if let Some(route) = route.filter(|route| route.session_kind().is_some()) {
+        if let Err(rejection) = rate_limit::admit(&format!("{:?}", route), &message.data) {
+            session_error::report(app, &message, rejection);
+            return;
+        }
+    }
+    if let Some(route) = route {
+        if let Err(err) = check_key_protocol(route, &message.data) {
+            session_error::refuse(app, &message, SessionErrorCode::VersionMismatch, err);
+            return;
+        }
+        if let Err(err) = check_keyshare_present(route, &message.data) {
+            session_error::refuse(app, &message, SessionErrorCode::MissingKeyshare, err);
+            return;
+        }
+    }
+    let started = route.and_then(|route| route.session_kind().map(|kind| (route, kind)));
+    if let Some((route, kind)) = started {
+        if let Err(err) = replay::check_session_message(route, &message.subject, &message.data) {
+            session_error::refuse(app, &message, SessionErrorCode::AuthenticationFailed, err);
+            return;
+        }
+        health::record_session_started();
+        metrics::session_started(kind);
+    }
+
+    match route {
+        Some(MessageRoute::KeyGenECDSA) => {
+            info!("start keygen process");
+            keygen::ecdsa::session::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::KeySignECDSA) => {
+            signing::ecdsa::session::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::KeyGenEdDSA) => {
+            eddsa::session::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::KeySignEdDSA) => {
+            signing::eddsa::session::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::KeySignSr25519) => {
+            signing::sr25519_musign::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::KeyGenFrost) => {
+            keygen::frost::session::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::KeySignFrost) => {
+            signing::frost::session::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::KeyGenSr25519) => {
+            keygen::sr25519::session::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::KeyGenBLS) => {
+            keygen::bls::session::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::KeySignBLS) => {
+            signing::bls::session::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::PresignECDSA) => {
+            signing::presigning::session::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::Command) => {
+            let _ = command::handle_nats_command(app, message);
+        }
+        Some(MessageRoute::KeyShareRecovery) => {
+            recovery::recovery_session::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::UserRecovery) => {
+            user_recovery::session::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::UserRecoveryConfirm) => {
+            user_recovery::confirm::handle_new_session_message(app, message);
+        }
+        Some(MessageRoute::ShareRefresh) => {
+            refresh::session::handle_new_session_message(app, message);
+        }
+        None => {
+            warn!("Received message with an unrecognized subject: {}", message.subject);
+        }
     }
 }
 
 pub fn start_sending_ready_as_cancellable_task_on_thread(
-    conn: nats::Connection,
+    conn: Connection,
     node_id: String,
     rx: mpsc::Receiver<()>,
     interval_duration: Duration
@@ -201,3 +492,42 @@ pub fn start_sending_ready_as_cancellable_task_on_thread(
     });
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_subjects_to_handlers() {
+        let node_id = "4a4c2f6e-9d0b-4a8e-9a57-6a1f0d5f6e21";
+        assert_eq!(
+            route_message(&format!("network.gridlock.nodes.keyGen.new.{node_id}")),
+            Some(MessageRoute::KeyGenECDSA)
+        );
+        assert_eq!(
+            route_message(&format!("network.gridlock.nodes.Message.new.{node_id}")),
+            Some(MessageRoute::Command)
+        );
+        assert_eq!(
+            route_message(&format!("network.gridlock.nodes.KeyGenSr25519.new.{node_id}")),
+            Some(MessageRoute::KeyGenSr25519)
+        );
+        assert_eq!(
+            route_message(&format!("network.gridlock.nodes.KeySignBLS.new.{node_id}")),
+            Some(MessageRoute::KeySignBLS)
+        );
+        assert_eq!(
+            route_message(&format!("network.gridlock.nodes.UserRecovery.new.{node_id}")),
+            Some(MessageRoute::UserRecovery)
+        );
+        assert_eq!(
+            route_message(&format!("network.gridlock.nodes.UserRecoveryConfirm.new.{node_id}")),
+            Some(MessageRoute::UserRecoveryConfirm)
+        );
+        assert_eq!(
+            route_message(&format!("network.gridlock.nodes.ShareRefresh.new.{node_id}")),
+            Some(MessageRoute::ShareRefresh)
+        );
+        assert_eq!(route_message("network.gridlock.nodes.unknown.new"), None);
+    }
+}
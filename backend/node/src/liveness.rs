@@ -0,0 +1,347 @@
+//! Liveness of the other guardians of the keys this node holds a share of. Every
+//! `PEER_HEARTBEAT_SECS` the node pings each guardian it shares a key with and records when it
+//! last answered, a ping received counts as seeing its sender too. `GetPoolHealthCommand` reports
+//! which guardians of a key are reachable, before a signing attempt that needs enough of them.
+
+use crate::command::{ JsonCommand, MsgContext, TaggedCommandType };
+use crate::communication::blocking;
+use crate::node::NodeIdentity;
+use crate::recovery::orchestrate::LEGACY_THRESHOLD;
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_listing::list_keys;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::storage::KeyInfoStore;
+use crate::tenant::Access;
+use anyhow::{ bail, Context, Result };
+use chrono::{ DateTime, Duration as ChronoDuration, Utc };
+use serde::{ Deserialize, Serialize };
+use shared::key_info::{ Node, NodeId };
+use std::collections::{ BTreeMap, BTreeSet };
+use std::env;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tracing::{ debug, warn };
+
+const LIVENESS_KEY: &str = "peer_liveness";
+const HEARTBEAT_SECS_VAR: &str = "PEER_HEARTBEAT_SECS";
+const DEFAULT_HEARTBEAT_SECS: u64 = 5 * 60;
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Heartbeats a peer may miss before it counts as unreachable
+const MISSED_HEARTBEATS: i32 = 3;
+
+/// Serializes the updates of the stored liveness
+static LIVENESS_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct PeerLiveness {
+    pub last_seen: Option<DateTime<Utc>>,
+    pub last_ping: Option<DateTime<Utc>>,
+    /// Pings in a row the peer did not answer
+    pub missed_pings: u32,
+}
+
+impl PeerLiveness {
+    fn is_reachable(&self, now: DateTime<Utc>, interval: ChronoDuration) -> bool {
+        self.last_seen.is_some_and(|seen| now - seen <= interval * MISSED_HEARTBEATS)
+    }
+}
+
+/// Liveness by node id
+type Liveness = BTreeMap<String, PeerLiveness>;
+
+fn load() -> Result<Liveness> {
+    match KeyMetadataStore::get_node_level(LIVENESS_KEY)? {
+        Some(content) => Ok(serde_json::from_str(&content)?),
+        None => Ok(Liveness::new()),
+    }
+}
+
+fn heartbeat_interval() -> Result<Duration> {
+    let secs = match env::var(HEARTBEAT_SECS_VAR) {
+        Ok(secs) => secs.parse::<u64>().with_context(|| format!("{} is not a number", secs))?,
+        Err(_) => DEFAULT_HEARTBEAT_SECS,
+    };
+    if secs == 0 {
+        bail!("{} must be at least one second", HEARTBEAT_SECS_VAR);
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+fn apply(liveness: &mut Liveness, node_id: &str, answered: bool, now: DateTime<Utc>) {
+    let peer = liveness.entry(node_id.to_string()).or_default();
+    if answered {
+        peer.last_seen = Some(now);
+        peer.missed_pings = 0;
+    } else {
+        peer.missed_pings += 1;
+    }
+}
+
+/// Pings are recorded with `last_ping`, messages from the peer only with `last_seen`. A failure
+/// to write is logged, liveness is only informative.
+fn record(results: &[(String, bool)], pinged: bool) {
+    if results.is_empty() {
+        return;
+    }
+    let _lock = LIVENESS_LOCK.lock().unwrap();
+    let now = Utc::now();
+    let recorded = load().and_then(|mut liveness| {
+        for (node_id, answered) in results {
+            apply(&mut liveness, node_id, *answered, now);
+            if pinged {
+                liveness.get_mut(node_id).unwrap().last_ping = Some(now);
+            }
+        }
+        let content = serde_json::to_string(&liveness)?;
+        KeyMetadataStore::save_node_level(&content, LIVENESS_KEY, &WriteOpts::Modify)
+    });
+    if let Err(err) = recorded {
+        warn!("Failed to record the liveness of {} peers: {}", results.len(), err);
+    }
+}
+
+/// Sent by a guardian to the guardians it shares a key with
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PingPeerCommand {
+    /// Sender of the ping
+    pub node_id: NodeId,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct PeerPong {
+    pub node_id: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl JsonCommand for PingPeerCommand {
+    type Response = PeerPong;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        record(&[(self.node_id.to_string(), true)], false);
+        let node = NodeIdentity::cached()?;
+        Ok(PeerPong { node_id: node.node_id.to_string(), timestamp: Utc::now() })
+    }
+}
+
+fn ping(nc: &blocking::Connection, node_id: &str, request: &str) -> Result<()> {
+    let subject = format!("network.gridlock.nodes.Message.new.{}", node_id);
+    let response = nc.request_timeout(&subject, request, PING_TIMEOUT)?;
+    let response = String::from_utf8(response.data)?;
+    if let Some(err) = response.strip_prefix("ERROR: ") {
+        bail!("{}", err);
+    }
+    let pong: PeerPong = serde_json::from_str(&response).context("Deserialize ping response")?;
+    if pong.node_id != node_id {
+        bail!("Node {} answered the ping of {}", pong.node_id, node_id);
+    }
+    Ok(())
+}
+
+/// Pings the peers and records which answered, returns the ones that did not
+fn ping_peers(
+    nc: &blocking::Connection,
+    own_node_id: &str,
+    peers: &BTreeSet<String>
+) -> Vec<String> {
+    let request = TaggedCommandType::PingPeer(PingPeerCommand {
+        node_id: NodeId::new(own_node_id.to_string()),
+    });
+    let request = serde_json::to_string(&request).unwrap();
+    let results = peers
+        .iter()
+        .map(|node_id| {
+            let answered = match ping(nc, node_id, &request) {
+                Ok(()) => true,
+                Err(err) => {
+                    debug!("Peer {} did not answer the heartbeat: {}", node_id, err);
+                    false
+                }
+            };
+            (node_id.clone(), answered)
+        })
+        .collect::<Vec<_>>();
+    record(&results, true);
+    results
+        .into_iter()
+        .filter(|(_, answered)| !answered)
+        .map(|(node_id, _)| node_id)
+        .collect()
+}
+
+/// Pings the nodes now, returns the ones that did not answer
+pub fn unreachable(
+    nc: &blocking::Connection,
+    own_node_id: &str,
+    node_ids: &[NodeId]
+) -> Vec<String> {
+    let peers = node_ids
+        .iter()
+        .map(|node_id| node_id.to_string())
+        .filter(|node_id| node_id != own_node_id)
+        .collect();
+    ping_peers(nc, own_node_id, &peers)
+}
+
+/// Pings the nodes now, fails with the ones that did not answer
+pub fn probe(nc: &blocking::Connection, own_node_id: &str, node_ids: &[NodeId]) -> Result<()> {
+    let unreachable = unreachable(nc, own_node_id, node_ids);
+    if !unreachable.is_empty() {
+        bail!("Guardians {} did not answer", unreachable.join(", "));
+    }
+    Ok(())
+}
+
+/// Other guardians of every key this node holds a share of with key info
+fn pool_peers(own_node_id: &str) -> Result<BTreeSet<String>> {
+    let mut peers = BTreeSet::new();
+    for listing in list_keys(None)? {
+        if !listing.has_key_info {
+            continue;
+        }
+        let key_info = match KeyInfoStore::get_key_info(&listing.key_id) {
+            Ok(key_info) => key_info,
+            Err(err) => {
+                warn!("Skipping the guardians of key {}: {}", listing.key_id, err);
+                continue;
+            }
+        };
+        peers.extend(
+            key_info.node_pool
+                .iter()
+                .map(|node| node.node_id.to_string())
+                .filter(|node_id| node_id != own_node_id)
+        );
+    }
+    Ok(peers)
+}
+
+pub fn spawn_heartbeat(nc: blocking::Connection, node_id: String) -> Result<()> {
+    let interval = heartbeat_interval()?;
+    thread::Builder
+        ::new()
+        .name("peer-heartbeat".to_string())
+        .spawn(move || {
+            loop {
+                match pool_peers(&node_id) {
+                    Ok(peers) => {
+                        ping_peers(&nc, &node_id, &peers);
+                    }
+                    Err(err) => warn!("Failed to list the peers to ping: {}", err),
+                }
+                thread::sleep(interval);
+            }
+        })?;
+    Ok(())
+}
+
+/// Which guardians of a key are reachable, with `probe` they are pinged first instead of relying
+/// on the last heartbeat
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetPoolHealthCommand {
+    pub key_id: String,
+    #[serde(default)]
+    pub probe: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct GuardianHealth {
+    pub node_id: String,
+    pub kind: Node,
+    pub share_index: usize,
+    pub reachable: bool,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub missed_pings: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PoolHealth {
+    pub key_id: String,
+    pub guardians: Vec<GuardianHealth>,
+    pub reachable_count: usize,
+    /// Guardians a signature needs
+    pub required_signers: usize,
+    pub can_sign: bool,
+}
+
+impl JsonCommand for GetPoolHealthCommand {
+    type Response = PoolHealth;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let key_info = KeyInfoStore::get_key_info(&self.key_id)?;
+        let own_node_id = NodeIdentity::cached()?.node_id.to_string();
+        if self.probe {
+            let app = ctx.get_app()?;
+            let peers = key_info.node_pool
+                .iter()
+                .map(|node| node.node_id.to_string())
+                .filter(|node_id| *node_id != own_node_id)
+                .collect();
+            ping_peers(&app.nc, &own_node_id, &peers);
+        }
+        let liveness = load()?;
+        let interval = ChronoDuration::from_std(heartbeat_interval()?)?;
+        let now = Utc::now();
+        let guardians = key_info.node_pool
+            .iter()
+            .map(|node| {
+                let node_id = node.node_id.to_string();
+                let peer = liveness.get(&node_id).cloned().unwrap_or_default();
+                GuardianHealth {
+                    reachable: node_id == own_node_id || peer.is_reachable(now, interval),
+                    node_id,
+                    kind: node.kind.clone(),
+                    share_index: node.share_index,
+                    last_seen: peer.last_seen,
+                    missed_pings: peer.missed_pings,
+                }
+            })
+            .collect::<Vec<_>>();
+        let reachable_count = guardians
+            .iter()
+            .filter(|guardian| guardian.reachable)
+            .count();
+        // The stored threshold is one below the number of signers, see `keygen::ecdsa::client`
+        let required_signers = key_info.threshold.unwrap_or(LEGACY_THRESHOLD) + 1;
+        Ok(PoolHealth {
+            key_id: self.key_id,
+            guardians,
+            reachable_count,
+            required_signers,
+            can_sign: reachable_count >= required_signers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_are_unreachable_after_missing_heartbeats() {
+        let interval = ChronoDuration::minutes(5);
+        let now = Utc::now();
+        let mut liveness = Liveness::new();
+        apply(&mut liveness, "peer", true, now);
+        assert!(liveness["peer"].is_reachable(now + interval * MISSED_HEARTBEATS, interval));
+
+        apply(&mut liveness, "peer", false, now + interval);
+        assert_eq!(liveness["peer"].missed_pings, 1);
+        assert_eq!(liveness["peer"].last_seen, Some(now));
+        assert!(!liveness["peer"].is_reachable(now + interval * (MISSED_HEARTBEATS + 1), interval));
+
+        apply(&mut liveness, "peer", true, now + interval * 2);
+        assert_eq!(liveness["peer"].missed_pings, 0);
+        assert!(!PeerLiveness::default().is_reachable(now, interval));
+    }
+}
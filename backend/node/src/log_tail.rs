@@ -0,0 +1,355 @@
+use crate::auth::e2e_encrypt;
+use crate::command::{ JsonCommand, MsgContext };
+use crate::communication::blocking;
+use crate::config::{ Config, ConfigProvider };
+use crate::node::NodeIdentity;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Result };
+use chrono::{ DateTime, Duration as ChronoDuration, Utc };
+use ed25519_dalek::{ PublicKey, Signature, Verifier };
+use serde::{ Deserialize, Serialize };
+use std::env;
+use std::fmt::Debug;
+use std::fs;
+use std::str::FromStr;
+use std::sync::mpsc::{ self, Receiver, RecvTimeoutError, SyncSender, TrySendError };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tracing::field::{ Field, Visit };
+use tracing::{ info, warn, Event, Level, Subscriber };
+use tracing_subscriber::layer::{ Context, Layer };
+use uuid::Uuid;
+
+/// Base64 ed25519 key of the operator whose support staff may tail the logs of the node
+const SIGNER_PUBLIC_KEY_VAR: &str = "LOG_TAIL_SIGNER_PUBLIC_KEY";
+const LOG_FILE: &str = "logs.log";
+const MAX_TAIL_MINUTES: i64 = 30;
+const MAX_RECENT_LINES: usize = 1000;
+const MAX_CONCURRENT_TAILS: usize = 4;
+/// Live lines waiting to be published, further lines are dropped instead of slowing down logging
+const LIVE_BUFFER_LINES: usize = 1024;
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Receivers of live log lines, one per running tail
+static TAILS: Mutex<Vec<SyncSender<LogLine>>> = Mutex::new(Vec::new());
+/// Live lines lost because a tail could not keep up, reported with every batch
+static DROPPED_LINES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct LogLine {
+    pub timestamp: String,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+impl LogLine {
+    /// Parses a line of the log file as written by the `fmt` layer:
+    /// `<timestamp> <level> <target>: <message>`
+    fn parse(line: &str) -> Option<Self> {
+        let (timestamp, rest) = line.split_once(char::is_whitespace)?;
+        let (level, rest) = rest.trim_start().split_once(char::is_whitespace)?;
+        Level::from_str(level).ok()?;
+        let (target, message) = rest.trim_start().split_once(": ")?;
+        Some(Self {
+            timestamp: timestamp.to_string(),
+            level: level.to_string(),
+            target: target.to_string(),
+            message: message.to_string(),
+        })
+    }
+}
+
+/// What a log tail streams
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct LogFilter {
+    /// Most verbose level included, `info` by default
+    #[serde(default = "default_level")]
+    pub level: String,
+    /// Only lines whose target starts with this module path, e.g. `node::signing`
+    #[serde(default)]
+    pub module: Option<String>,
+}
+
+fn default_level() -> String {
+    "info".to_string()
+}
+
+impl LogFilter {
+    fn max_level(&self) -> Result<Level> {
+        Level::from_str(&self.level).map_err(|_| anyhow!("Unknown log level {}", self.level))
+    }
+
+    fn matches(&self, max_level: Level, line: &LogLine) -> bool {
+        let level_matches = Level::from_str(&line.level).is_ok_and(|level| level <= max_level);
+        let module_matches = self.module
+            .as_ref()
+            .map_or(true, |module| line.target.starts_with(module.as_str()));
+        level_matches && module_matches
+    }
+}
+
+/// Permission to tail the logs of one node until `expires_at`, signed by the operator
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct LogTailGrant {
+    pub node_id: String,
+    /// Base64 curve25519 key the log lines are encrypted to
+    pub operator_e2e_public_key: String,
+    pub expires_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub filter: LogFilter,
+    /// Lines from the log file sent before the live lines
+    #[serde(default)]
+    pub recent_lines: usize,
+}
+
+/// Streams recent and live log lines of the node, e2e encrypted to the operator, so support can
+/// debug a guardian without shell access to it
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TailLogsCommand {
+    pub grant: LogTailGrant,
+    /// Base64 ed25519 signature over the JSON encoding of `grant`
+    pub signature: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct LogTailStarted {
+    /// Subject the encrypted `LogBatch`es are published on
+    pub subject: String,
+    /// Key the batches are encrypted with, together with the operator key of the grant
+    pub node_e2e_public_key: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Log lines published together, the last batch of a tail has `finished` set
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct LogBatch {
+    pub lines: Vec<LogLine>,
+    pub dropped_lines: u64,
+    pub finished: bool,
+}
+
+impl TailLogsCommand {
+    fn verify(&self, signer_public_key: &str, node_id: &str, now: DateTime<Utc>) -> Result<()> {
+        let public_key = PublicKey::from_bytes(&base64::decode(signer_public_key)?).map_err(|err|
+            anyhow!("Invalid log tail signer public key: {}", err)
+        )?;
+        let signature = Signature::try_from(&base64::decode(&self.signature)?[..]).map_err(|err|
+            anyhow!("Invalid log tail grant signature encoding: {}", err)
+        )?;
+        public_key
+            .verify(&serde_json::to_vec(&self.grant)?, &signature)
+            .map_err(|_| anyhow!("Log tail grant signature verification failed"))?;
+
+        if self.grant.node_id != node_id {
+            bail!("Log tail grant is for node {}", self.grant.node_id);
+        }
+        if self.grant.expires_at <= now {
+            bail!("Log tail grant expired at {}", self.grant.expires_at);
+        }
+        if self.grant.expires_at - now > ChronoDuration::minutes(MAX_TAIL_MINUTES) {
+            bail!("Log tails may run for at most {} minutes", MAX_TAIL_MINUTES);
+        }
+        Ok(())
+    }
+}
+
+impl JsonCommand for TailLogsCommand {
+    type Response = LogTailStarted;
+
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let signer_public_key = match env::var(SIGNER_PUBLIC_KEY_VAR) {
+            Ok(key) => key,
+            Err(_) => bail!("{} is not set, log tails are disabled", SIGNER_PUBLIC_KEY_VAR),
+        };
+        let node = NodeIdentity::cached()?;
+        self.verify(&signer_public_key, &node.node_id.to_string(), Utc::now())?;
+        let max_level = self.grant.filter.max_level()?;
+
+        let receiver = register_tail()?;
+        let nc = ctx.get_app()?.nc;
+        let subject = format!("network.gridlock.logs.{}.{}", node.node_id, Uuid::new_v4());
+        let started = LogTailStarted {
+            subject: subject.clone(),
+            node_e2e_public_key: node.e2e_public_key.clone(),
+            expires_at: self.grant.expires_at,
+        };
+        let grant = self.grant;
+        thread::Builder
+            ::new()
+            .name("log-tail".to_string())
+            .spawn(move || {
+                let tail = LogTail { nc, subject, grant, max_level, node };
+                if let Err(err) = tail.run(receiver) {
+                    warn!("Log tail on {} stopped: {}", tail.subject, err);
+                }
+            })?;
+        info!("Streaming logs on {} until {}", started.subject, started.expires_at);
+        Ok(started)
+    }
+}
+
+fn register_tail() -> Result<Receiver<LogLine>> {
+    // Senders of finished tails are dropped by `LogTailLayer` on the next event
+    let mut tails = TAILS.lock().unwrap();
+    if tails.len() >= MAX_CONCURRENT_TAILS {
+        bail!("{} log tails are running already", tails.len());
+    }
+    let (sender, receiver) = mpsc::sync_channel(LIVE_BUFFER_LINES);
+    tails.push(sender);
+    Ok(receiver)
+}
+
+struct LogTail {
+    nc: blocking::Connection,
+    subject: String,
+    grant: LogTailGrant,
+    max_level: Level,
+    node: NodeIdentity,
+}
+
+impl LogTail {
+    fn run(&self, receiver: Receiver<LogLine>) -> Result<()> {
+        let recent = self.recent_lines()?;
+        self.publish(recent, 0, false)?;
+
+        let mut lines = Vec::new();
+        loop {
+            let now = Utc::now();
+            if now >= self.grant.expires_at {
+                break;
+            }
+            match receiver.recv_timeout(BATCH_INTERVAL) {
+                Ok(line) => {
+                    if self.grant.filter.matches(self.max_level, &line) {
+                        lines.push(line);
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    break;
+                }
+            }
+            if !lines.is_empty() {
+                self.publish(std::mem::take(&mut lines), dropped_lines(), false)?;
+            }
+        }
+        self.publish(lines, dropped_lines(), true)
+    }
+
+    fn recent_lines(&self) -> Result<Vec<LogLine>> {
+        let wanted = self.grant.recent_lines.min(MAX_RECENT_LINES);
+        if wanted == 0 {
+            return Ok(Vec::new());
+        }
+        let log = fs::read_to_string(Config::get_gridlock_directory().join(LOG_FILE))?;
+        let mut lines = log
+            .lines()
+            .rev()
+            .filter_map(LogLine::parse)
+            .filter(|line| self.grant.filter.matches(self.max_level, line))
+            .take(wanted)
+            .collect::<Vec<LogLine>>();
+        lines.reverse();
+        Ok(lines)
+    }
+
+    fn publish(&self, lines: Vec<LogLine>, dropped_lines: u64, finished: bool) -> Result<()> {
+        let batch = LogBatch { lines, dropped_lines, finished };
+        let encrypted = e2e_encrypt(
+            &serde_json::to_vec(&batch)?,
+            &self.grant.operator_e2e_public_key,
+            &self.node.e2e_private_key
+        )?;
+        self.nc.publish(&self.subject, encrypted)?;
+        Ok(())
+    }
+}
+
+fn dropped_lines() -> u64 {
+    DROPPED_LINES.load(Ordering::Relaxed)
+}
+
+/// Hands every event to the running log tails, does nothing while none is running
+pub struct LogTailLayer;
+
+impl<S: Subscriber> Layer<S> for LogTailLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut tails = match TAILS.try_lock() {
+            Ok(tails) => tails,
+            // A tail is being registered, losing a line is better than blocking the caller
+            Err(_) => {
+                return;
+            }
+        };
+        if tails.is_empty() {
+            return;
+        }
+        let mut visitor = MessageVisitor(String::new());
+        event.record(&mut visitor);
+        let line = LogLine {
+            timestamp: Utc::now().to_rfc3339(),
+            level: event.metadata().level().to_string(),
+            target: event.metadata().target().to_string(),
+            message: visitor.0,
+        };
+        tails.retain(|sender| {
+            match sender.try_send(line.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    DROPPED_LINES.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+/// Formats the message and fields of an event like the `fmt` layer does
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        if field.name() == "message" {
+            self.0.push_str(&format!("{:?}", value));
+        } else {
+            self.0.push_str(&format!("{}={:?}", field.name(), value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_parsed_log_file_lines() {
+        let line = LogLine::parse(
+            "2024-05-01T12:00:00.000000Z  WARN node::signing::eddsa: HMAC verification failed"
+        ).unwrap();
+        assert_eq!(line.level, "WARN");
+        assert_eq!(line.target, "node::signing::eddsa");
+        assert_eq!(line.message, "HMAC verification failed");
+        assert!(LogLine::parse("continuation of a multi line message").is_none());
+
+        let filter = LogFilter { level: "warn".to_string(), module: Some("node::signing".into()) };
+        let max_level = filter.max_level().unwrap();
+        assert!(filter.matches(max_level, &line));
+        let info = LogLine { level: "INFO".to_string(), ..line.clone() };
+        assert!(!filter.matches(max_level, &info));
+        let other_module = LogLine { target: "node::keygen".to_string(), ..line };
+        assert!(!filter.matches(max_level, &other_module));
+    }
+}
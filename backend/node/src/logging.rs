@@ -1,16 +1,50 @@
 use crate::config::{ Config as NodeConfig, ConfigProvider };
-use anyhow::{ Context, Result };
+use crate::health::ErrorCountingLayer;
+use crate::log_tail::LogTailLayer;
+use anyhow::{ anyhow, Context, Result };
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::{ BufRead, BufReader, Write };
 use std::path::Path;
-use tracing::info;
+use std::sync::OnceLock;
+use tracing::{ info, warn };
 use tracing_log::LogTracer;
+use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt;
 use tracing_subscriber::fmt::writer::MakeWriterExt;
 use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{ reload, Registry };
+
+/// Most verbose level logged: "error", "warn", "info" (default), "debug" or "trace"
+const LOG_LEVEL_VAR: &str = "LOG_LEVEL";
 
 static mut LOGGING_INITIALIZED: bool = false;
+/// Changes the level of the node logger after it is initialized
+static LEVEL_HANDLE: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+fn configured_level() -> LevelFilter {
+    match std::env::var(LOG_LEVEL_VAR) {
+        Ok(level) =>
+            level.parse().unwrap_or_else(|_| {
+                warn!("Ignoring invalid {} {:?}, using info", LOG_LEVEL_VAR, level);
+                LevelFilter::INFO
+            }),
+        Err(_) => LevelFilter::INFO,
+    }
+}
+
+/// Applies a new level to the node logger, an empty level restores the default
+pub fn set_log_level(level: &str) -> Result<()> {
+    let level = match level {
+        "" => LevelFilter::INFO,
+        level => level.parse().map_err(|_| anyhow!("Invalid log level {:?}", level))?,
+    };
+    if let Some(handle) = LEVEL_HANDLE.get() {
+        handle.reload(level).context("Set log level")?;
+        info!("Log level set to {}", level);
+    }
+    Ok(())
+}
 
 pub struct MobileLogInitializer;
 
@@ -59,17 +93,25 @@ impl GridlockLogInitializer {
         let log_path = NodeConfig::get_gridlock_directory().join("logs.log");
         truncate_log_file(log_path.clone(), 1024 * 1024)?;
 
-        let output = std::io::stdout.with_max_level(tracing::Level::INFO);
+        let output = std::io::stdout.with_max_level(tracing::Level::TRACE);
         let stdout_sub = fmt::Layer::new().with_writer(output).with_ansi(true);
 
         let log_file = OpenOptions::new()
 
             .append(true)
             .open(log_path)?
-            .with_max_level(tracing::Level::INFO);
+            .with_max_level(tracing::Level::TRACE);
         let logfile_sub = fmt::Layer::new().with_writer(log_file).with_ansi(false);
 
-        let collector = tracing_subscriber::registry().with(stdout_sub).with(logfile_sub);
+        let (level, handle) = reload::Layer::new(configured_level());
+        let _ = LEVEL_HANDLE.set(handle);
+        let collector = tracing_subscriber
+            ::registry()
+            .with(level)
+            .with(stdout_sub)
+            .with(logfile_sub)
+            .with(ErrorCountingLayer)
+            .with(LogTailLayer);
         LogTracer::init().context("Sset logger")?;
         tracing::subscriber::set_global_default(collector).context("Set tracing subscriber")
     }
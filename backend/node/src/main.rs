@@ -1,8 +1,10 @@
-use anyhow::Result;
-use node::logging::GridlockLogInitializer;
+use node::cli;
+use std::env;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    GridlockLogInitializer::init();
-    Ok(())
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(err) = cli::run(&args) {
+        eprintln!("{err:?}");
+        std::process::exit(1);
+    }
 }
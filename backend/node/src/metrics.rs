@@ -0,0 +1,223 @@
+use crate::communication::blocking;
+use anyhow::{ anyhow, Result };
+use prometheus::{
+    Encoder,
+    HistogramOpts,
+    HistogramVec,
+    IntCounter,
+    IntCounterVec,
+    Opts,
+    Registry,
+    TextEncoder,
+};
+use std::sync::OnceLock;
+use std::time::{ Duration, Instant };
+use std::{ env, thread };
+use tracing::warn;
+
+/// Seconds between pushes of the metrics to `network.gridlock.metrics.{node_id}`, unset disables it
+const PUSH_INTERVAL_VAR: &str = "METRICS_PUSH_INTERVAL_SECS";
+
+/// Upper bounds in seconds of the signing latency buckets, shared with the monthly SLO ledger
+pub const SIGNING_LATENCY_BUCKETS: [f64; 9] = [0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionKind {
+    KeyGen,
+    Signing,
+    Recovery,
+    Refresh,
+}
+
+impl SessionKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            SessionKind::KeyGen => "keygen",
+            SessionKind::Signing => "signing",
+            SessionKind::Recovery => "recovery",
+            SessionKind::Refresh => "refresh",
+        }
+    }
+}
+
+struct Metrics {
+    registry: Registry,
+    sessions: IntCounterVec,
+    signing_phase_seconds: HistogramVec,
+    nats_reconnects: IntCounter,
+    key_signings: IntCounterVec,
+    key_signing_seconds: HistogramVec,
+    key_recoveries: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("guardian".to_string()), None)?;
+        let sessions = IntCounterVec::new(
+            Opts::new("sessions_total", "Sessions by kind and outcome"),
+            &["kind", "outcome"]
+        )?;
+        let signing_phase_seconds = HistogramVec::new(
+            HistogramOpts::new("signing_phase_seconds", "Duration of each ECDSA signing phase"),
+            &["phase"]
+        )?;
+        let nats_reconnects = IntCounter::new("nats_reconnects_total", "NATS reconnections")?;
+        let key_signings = IntCounterVec::new(
+            Opts::new("key_signings_total", "Signing sessions by scheme, key pool and outcome"),
+            &["scheme", "pool", "outcome"]
+        )?;
+        let key_signing_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "key_signing_seconds",
+                "Duration of signing sessions by scheme and key pool"
+            ).buckets(SIGNING_LATENCY_BUCKETS.to_vec()),
+            &["scheme", "pool"]
+        )?;
+        let key_recoveries = IntCounterVec::new(
+            Opts::new("key_recoveries_total", "Keyshare recoveries by key pool and outcome"),
+            &["pool", "outcome"]
+        )?;
+        registry.register(Box::new(sessions.clone()))?;
+        registry.register(Box::new(signing_phase_seconds.clone()))?;
+        registry.register(Box::new(nats_reconnects.clone()))?;
+        registry.register(Box::new(key_signings.clone()))?;
+        registry.register(Box::new(key_signing_seconds.clone()))?;
+        registry.register(Box::new(key_recoveries.clone()))?;
+        Ok(Self {
+            registry,
+            sessions,
+            signing_phase_seconds,
+            nats_reconnects,
+            key_signings,
+            key_signing_seconds,
+            key_recoveries,
+        })
+    }
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("Metric definitions are valid"))
+}
+
+fn count_session(kind: SessionKind, outcome: &str) {
+    metrics().sessions.with_label_values(&[kind.label(), outcome]).inc();
+}
+
+pub fn session_started(kind: SessionKind) {
+    count_session(kind, "started");
+}
+
+pub fn session_completed(kind: SessionKind) {
+    count_session(kind, "completed");
+}
+
+pub fn session_failed(kind: SessionKind) {
+    count_session(kind, "failed");
+}
+
+/// Counts the session as failed when dropped before `complete` is called, for sessions that
+/// have many early returns
+pub struct SessionOutcome {
+    kind: SessionKind,
+    completed: bool,
+}
+
+impl SessionOutcome {
+    pub fn new(kind: SessionKind) -> Self {
+        Self { kind, completed: false }
+    }
+
+    pub fn complete(mut self) {
+        self.completed = true;
+        session_completed(self.kind);
+    }
+}
+
+impl Drop for SessionOutcome {
+    fn drop(&mut self) {
+        if !self.completed {
+            session_failed(self.kind);
+        }
+    }
+}
+
+fn outcome_label(succeeded: bool) -> &'static str {
+    if succeeded { "succeeded" } else { "failed" }
+}
+
+/// Records a finished signing session of a key, `pool` has to come from `slo::key_pool` so the
+/// label never carries a key id
+pub fn record_key_signing(scheme: &str, pool: &str, duration: Duration, succeeded: bool) {
+    let metrics = metrics();
+    metrics.key_signings.with_label_values(&[scheme, pool, outcome_label(succeeded)]).inc();
+    metrics.key_signing_seconds
+        .with_label_values(&[scheme, pool])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn record_key_recovery(pool: &str, succeeded: bool) {
+    metrics().key_recoveries.with_label_values(&[pool, outcome_label(succeeded)]).inc();
+}
+
+pub fn record_nats_reconnect() {
+    metrics().nats_reconnects.inc();
+}
+
+/// Runs one signing phase, recording how long it took whether it succeeded or not
+pub fn time_signing_phase<T>(phase: &str, run: impl FnOnce() -> Result<T>) -> Result<T> {
+    let started = Instant::now();
+    let result = run();
+    metrics()
+        .signing_phase_seconds.with_label_values(&[phase])
+        .observe(started.elapsed().as_secs_f64());
+    result
+}
+
+/// All metrics in the Prometheus text format
+pub fn gather() -> Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&metrics().registry.gather(), &mut buffer)?;
+    String::from_utf8(buffer).map_err(|err| anyhow!("Metrics are not valid UTF-8: {}", err))
+}
+
+/// Periodically publishes the metrics over NATS for nodes that cannot be scraped
+pub fn spawn_nats_publisher(nc: blocking::Connection, node_id: &str) -> Result<()> {
+    let interval = match env::var(PUSH_INTERVAL_VAR) {
+        Ok(secs) => Duration::from_secs(secs.parse()?),
+        Err(_) => {
+            return Ok(());
+        }
+    };
+    let subject = format!("network.gridlock.metrics.{}", node_id);
+    thread::Builder
+        ::new()
+        .name("metrics-publisher".to_string())
+        .spawn(move || {
+            loop {
+                thread::sleep(interval);
+                let published = gather().and_then(|text| Ok(nc.publish(&subject, text)?));
+                if let Err(err) = published {
+                    warn!("Failed to publish metrics: {}", err);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_sessions_by_outcome() {
+        session_started(SessionKind::Recovery);
+        SessionOutcome::new(SessionKind::Recovery).complete();
+        drop(SessionOutcome::new(SessionKind::Recovery));
+
+        let text = gather().unwrap();
+        assert!(text.contains("guardian_sessions_total{kind=\"recovery\",outcome=\"started\"}"));
+        assert!(text.contains("guardian_sessions_total{kind=\"recovery\",outcome=\"completed\"}"));
+        assert!(text.contains("guardian_sessions_total{kind=\"recovery\",outcome=\"failed\"}"));
+    }
+}
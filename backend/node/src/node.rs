@@ -1,10 +1,14 @@
+pub mod identity_backend;
+
 use crate::storage::fs::FileSystem;
 use anyhow::Result;
 use nkeys::KeyPair;
 use serde::{ Deserialize, Serialize };
 use sodiumoxide::crypto::box_::gen_keypair;
+use std::sync::RwLock;
 use uuid::Uuid;
 use rand::seq::SliceRandom;
+use tracing::info;
 
 const NODE_NAMES: &[&str] = &[
     "Cletus",
@@ -59,6 +63,10 @@ const NODE_NAMES: &[&str] = &[
     "Enos",
 ];
 
+/// Identity shared by all session threads, filled on first use and replaced whenever the identity
+/// is saved
+static CACHED_IDENTITY: RwLock<Option<NodeIdentity>> = RwLock::new(None);
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct NodeIdentity {
     pub node_id: Uuid,
@@ -111,15 +119,51 @@ impl NodeIdentity {
         }
     }
 
+    #[deprecated(note = "reads node.json on every call, use `NodeIdentity::cached` instead")]
     pub fn load() -> Result<Self> {
+        Self::read_from_storage()
+    }
+
+    /// Identity of this node. node.json is only read when nothing is cached yet, a failed read is
+    /// not cached so the next call tries again.
+    pub fn cached() -> Result<Self> {
+        if let Some(node) = CACHED_IDENTITY.read().unwrap().as_ref() {
+            return Ok(node.clone());
+        }
+        let mut cache = CACHED_IDENTITY.write().unwrap();
+        if let Some(node) = cache.as_ref() {
+            return Ok(node.clone());
+        }
+        let node = Self::read_from_storage()?;
+        *cache = Some(node.clone());
+        Ok(node)
+    }
+
+    /// Drops the cached identity so the next `cached` call reads node.json again. Needed when the
+    /// identity file is rotated by anything other than `save`.
+    pub fn invalidate_cache() {
+        *CACHED_IDENTITY.write().unwrap() = None;
+    }
+
+    fn read_from_storage() -> Result<Self> {
         let data = FileSystem::read_node_identity()?;
-        let node = serde_json::from_str::<Self>(&data)?;
+        let (node, plaintext) = identity_backend::decode(&data)?;
+        // Identities created before a backend was configured are sealed on first read
+        if plaintext {
+            if let Some(backend) = identity_backend::configured()? {
+                FileSystem::save_node_identity(&identity_backend::encode(&node)?)?;
+                info!("Sealed the node identity with {}", backend.name());
+            }
+        }
         Ok(node)
     }
 
+    /// Saves node.json, with the private keys sealed by the identity backend if one is configured
     pub fn save(&self) -> Result<()> {
-        let contents = serde_json::to_string(&self)?;
+        let contents = identity_backend::encode(self)?;
+        let mut cache = CACHED_IDENTITY.write().unwrap();
         FileSystem::save_node_identity(&contents)?;
+        *cache = Some(self.clone());
         Ok(())
     }
 }
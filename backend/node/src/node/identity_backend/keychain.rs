@@ -0,0 +1,34 @@
+use super::IdentityBackend;
+use anyhow::{ anyhow, Result };
+use security_framework::passwords::{ get_generic_password, set_generic_password };
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+const SERVICE: &str = "network.gridlock.guardian.identity";
+
+/// Generic password item of the macOS Keychain, one per node id. The login keychain is encrypted
+/// with a key protected by the Secure Enclave on machines that have one. node.json only keeps
+/// the account name of the item.
+pub struct KeychainBackend;
+
+impl IdentityBackend for KeychainBackend {
+    fn name(&self) -> &'static str {
+        "keychain"
+    }
+
+    fn seal(&self, node_id: &Uuid, secrets: &[u8]) -> Result<Vec<u8>> {
+        let account = node_id.to_string();
+        set_generic_password(SERVICE, &account, secrets).map_err(|err|
+            anyhow!("Failed to store the node identity in the keychain: {}", err)
+        )?;
+        Ok(account.into_bytes())
+    }
+
+    fn unseal(&self, _node_id: &Uuid, sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let account = std::str::from_utf8(sealed)?;
+        let secrets = get_generic_password(SERVICE, account).map_err(|err|
+            anyhow!("Failed to read the node identity from the keychain: {}", err)
+        )?;
+        Ok(Zeroizing::new(secrets))
+    }
+}
@@ -0,0 +1,167 @@
+#[cfg(all(feature = "keychain-identity", target_os = "macos"))]
+mod keychain;
+#[cfg(feature = "pkcs11-identity")]
+mod pkcs11;
+#[cfg(feature = "tpm-identity")]
+mod tpm2;
+
+use crate::node::NodeIdentity;
+use anyhow::{ bail, Context, Result };
+use serde::{ Deserialize, Serialize };
+use std::env;
+use uuid::Uuid;
+use zeroize::{ Zeroize, Zeroizing };
+
+/// Selects where the identity private keys are kept: "file" (default, plaintext in node.json),
+/// "tpm2", "keychain" or "pkcs11"
+const BACKEND_VAR: &str = "IDENTITY_BACKEND";
+
+/// Keeps the private keys of the node identity out of node.json. The networking key is an
+/// Ed25519 NKey and the e2e key an X25519 key, which TPM 2.0 and the Secure Enclave don't
+/// implement, so backends seal the keys to a key that never leaves the hardware instead. The
+/// keys are unsealed in memory when the identity is loaded, node.json only holds the sealed blob.
+pub trait IdentityBackend {
+    fn name(&self) -> &'static str;
+
+    /// Seals the serialized private keys of `node_id`, the result is stored in node.json
+    fn seal(&self, node_id: &Uuid, secrets: &[u8]) -> Result<Vec<u8>>;
+
+    fn unseal(&self, node_id: &Uuid, sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>>;
+}
+
+/// node.json of an identity whose private keys are sealed by a backend
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SealedIdentity {
+    node_id: Uuid,
+    networking_public_key: String,
+    e2e_public_key: String,
+    name: String,
+    identity_backend: String,
+    /// Base64 blob produced by the backend
+    sealed_secrets: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IdentitySecrets {
+    networking_private_key: String,
+    e2e_private_key: String,
+}
+
+impl Drop for IdentitySecrets {
+    fn drop(&mut self) {
+        self.networking_private_key.zeroize();
+        self.e2e_private_key.zeroize();
+    }
+}
+
+/// The backend selected with `IDENTITY_BACKEND`, `None` for plaintext node.json
+pub fn configured() -> Result<Option<Box<dyn IdentityBackend>>> {
+    match env::var(BACKEND_VAR).unwrap_or_default().as_str() {
+        "" | "file" => Ok(None),
+        name => backend(name).map(Some),
+    }
+}
+
+fn backend(name: &str) -> Result<Box<dyn IdentityBackend>> {
+    match name {
+        #[cfg(feature = "tpm-identity")]
+        "tpm2" => Ok(Box::new(tpm2::Tpm2Backend::from_env()?)),
+        #[cfg(all(feature = "keychain-identity", target_os = "macos"))]
+        "keychain" => Ok(Box::new(keychain::KeychainBackend)),
+        #[cfg(feature = "pkcs11-identity")]
+        "pkcs11" => Ok(Box::new(pkcs11::Pkcs11Backend::from_env()?)),
+        #[allow(unreachable_patterns)]
+        "tpm2" => bail!("Identity backend tpm2 needs a node built with the tpm-identity feature"),
+        #[allow(unreachable_patterns)]
+        "keychain" => {
+            bail!(
+                "Identity backend keychain needs a macOS node built with the keychain-identity \
+                 feature"
+            )
+        }
+        #[allow(unreachable_patterns)]
+        "pkcs11" =>
+            bail!("Identity backend pkcs11 needs a node built with the pkcs11-identity feature"),
+        _ => bail!("Unknown identity backend {}", name),
+    }
+}
+
+/// node.json contents of the identity, with the private keys sealed if a backend is configured
+pub fn encode(node: &NodeIdentity) -> Result<String> {
+    let backend = match configured()? {
+        Some(backend) => backend,
+        None => {
+            return Ok(serde_json::to_string(node)?);
+        }
+    };
+    let secrets = Zeroizing::new(
+        serde_json::to_vec(
+            &(IdentitySecrets {
+                networking_private_key: node.networking_private_key.clone(),
+                e2e_private_key: node.e2e_private_key.clone(),
+            })
+        )?
+    );
+    let sealed = backend.seal(&node.node_id, &secrets)?;
+    Ok(
+        serde_json::to_string(
+            &(SealedIdentity {
+                node_id: node.node_id,
+                networking_public_key: node.networking_public_key.clone(),
+                e2e_public_key: node.e2e_public_key.clone(),
+                name: node.name.clone(),
+                identity_backend: backend.name().to_string(),
+                sealed_secrets: base64::encode(sealed),
+            })
+        )?
+    )
+}
+
+/// Identity from node.json, unsealing the private keys with the backend that sealed them.
+/// Returns whether they were stored in plaintext.
+pub fn decode(data: &str) -> Result<(NodeIdentity, bool)> {
+    let sealed = match serde_json::from_str::<SealedIdentity>(data) {
+        Ok(sealed) => sealed,
+        Err(_) => {
+            return Ok((serde_json::from_str::<NodeIdentity>(data)?, true));
+        }
+    };
+    let backend = backend(&sealed.identity_backend)?;
+    let secrets = backend
+        .unseal(&sealed.node_id, &base64::decode(&sealed.sealed_secrets)?)
+        .with_context(|| format!("Failed to unseal the node identity with {}", backend.name()))?;
+    let secrets = serde_json::from_slice::<IdentitySecrets>(&secrets)?;
+    let node = NodeIdentity::from(
+        sealed.node_id,
+        sealed.networking_public_key,
+        secrets.networking_private_key.clone(),
+        sealed.e2e_public_key,
+        secrets.e2e_private_key.clone(),
+        sealed.name
+    );
+    Ok((node, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plaintext_identities_are_read_without_a_backend() {
+        let node = NodeIdentity::new();
+        let (decoded, plaintext) = decode(&serde_json::to_string(&node).unwrap()).unwrap();
+        assert!(plaintext);
+        assert_eq!(decoded.e2e_private_key, node.e2e_private_key);
+
+        let sealed = SealedIdentity {
+            node_id: node.node_id,
+            networking_public_key: node.networking_public_key.clone(),
+            e2e_public_key: node.e2e_public_key.clone(),
+            name: node.name.clone(),
+            identity_backend: "unknown".to_string(),
+            sealed_secrets: String::new(),
+        };
+        assert!(decode(&serde_json::to_string(&sealed).unwrap()).is_err());
+    }
+}
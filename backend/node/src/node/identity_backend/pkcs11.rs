@@ -0,0 +1,108 @@
+use super::IdentityBackend;
+use crate::encryption::{ aes_decrypt, aes_encrypt, AES_KEY_BYTES_LEN };
+use anyhow::{ anyhow, bail, Context, Result };
+use cryptoki::context::{ CInitializeArgs, Pkcs11 };
+use cryptoki::mechanism::rsa::{ PkcsMgfType, PkcsOaepParams, PkcsOaepSource };
+use cryptoki::mechanism::{ Mechanism, MechanismType };
+use cryptoki::object::{ Attribute, ObjectClass, ObjectHandle };
+use cryptoki::session::{ Session, UserType };
+use cryptoki::types::AuthPin;
+use rand::RngCore;
+use serde::{ Deserialize, Serialize };
+use shared::recovery::EncryptedData;
+use std::env;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Path of the PKCS#11 module of the token, e.g. libykcs11.so for YubiKeys
+const MODULE_VAR: &str = "PKCS11_MODULE";
+const PIN_VAR: &str = "PKCS11_PIN";
+/// Label of the RSA key pair on the token the identity is sealed to
+const KEY_LABEL_VAR: &str = "PKCS11_KEY_LABEL";
+const DEFAULT_KEY_LABEL: &str = "gridlock-identity";
+
+/// RSA key pair of a PKCS#11 token such as a YubiKey or an HSM. The private keys are encrypted
+/// with a random AES key, which is wrapped with RSA-OAEP by the token and only unwrapped by it.
+pub struct Pkcs11Backend {
+    pkcs11: Pkcs11,
+    pin: String,
+    key_label: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WrappedSecrets {
+    /// Base64 AES key wrapped by the token
+    wrapped_key: String,
+    secrets: EncryptedData,
+}
+
+impl Pkcs11Backend {
+    pub fn from_env() -> Result<Self> {
+        let module = env::var(MODULE_VAR).with_context(|| format!("{} is not set", MODULE_VAR))?;
+        let pkcs11 = Pkcs11::new(&module).with_context(|| format!("Failed to load {}", module))?;
+        pkcs11.initialize(CInitializeArgs::OsThreads)?;
+        Ok(Self {
+            pkcs11,
+            pin: env::var(PIN_VAR).with_context(|| format!("{} is not set", PIN_VAR))?,
+            key_label: env::var(KEY_LABEL_VAR).unwrap_or_else(|_| DEFAULT_KEY_LABEL.to_string()),
+        })
+    }
+
+    fn session(&self) -> Result<Session> {
+        let slot = match self.pkcs11.get_slots_with_token()?.first() {
+            Some(slot) => *slot,
+            None => bail!("No PKCS#11 token is present"),
+        };
+        let session = self.pkcs11.open_ro_session(slot)?;
+        session.login(UserType::User, Some(&AuthPin::new(self.pin.clone())))?;
+        Ok(session)
+    }
+
+    fn find_key(&self, session: &Session, class: ObjectClass) -> Result<ObjectHandle> {
+        let template = [Attribute::Class(class), Attribute::Label(self.key_label.clone().into())];
+        match session.find_objects(&template)?.first() {
+            Some(handle) => Ok(*handle),
+            None => bail!("No {:?} labelled {} on the PKCS#11 token", class, self.key_label),
+        }
+    }
+}
+
+fn oaep() -> Mechanism<'static> {
+    let params = PkcsOaepParams::new(
+        MechanismType::SHA256,
+        PkcsMgfType::MGF1_SHA256,
+        PkcsOaepSource::empty()
+    );
+    Mechanism::RsaPkcsOaep(params)
+}
+
+impl IdentityBackend for Pkcs11Backend {
+    fn name(&self) -> &'static str {
+        "pkcs11"
+    }
+
+    fn seal(&self, _node_id: &Uuid, secrets: &[u8]) -> Result<Vec<u8>> {
+        let mut key = Zeroizing::new(vec![0u8; AES_KEY_BYTES_LEN]);
+        rand::thread_rng().fill_bytes(&mut key);
+        let session = self.session()?;
+        let public_key = self.find_key(&session, ObjectClass::PUBLIC_KEY)?;
+        let wrapped_key = session.encrypt(&oaep(), public_key, &key)?;
+        let sealed = WrappedSecrets {
+            wrapped_key: base64::encode(wrapped_key),
+            secrets: aes_encrypt(secrets, &key)?,
+        };
+        Ok(serde_json::to_vec(&sealed)?)
+    }
+
+    fn unseal(&self, _node_id: &Uuid, sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let sealed: WrappedSecrets = serde_json::from_slice(sealed)?;
+        let session = self.session()?;
+        let private_key = self.find_key(&session, ObjectClass::PRIVATE_KEY)?;
+        let key = Zeroizing::new(
+            session
+                .decrypt(&oaep(), private_key, &base64::decode(&sealed.wrapped_key)?)
+                .map_err(|err| anyhow!("The token could not unwrap the identity key: {}", err))?
+        );
+        Ok(Zeroizing::new(aes_decrypt(&sealed.secrets, &key)?))
+    }
+}
@@ -0,0 +1,114 @@
+use super::IdentityBackend;
+use anyhow::{ anyhow, Result };
+use serde::{ Deserialize, Serialize };
+use std::sync::Mutex;
+use tss_esapi::attributes::ObjectAttributesBuilder;
+use tss_esapi::handles::KeyHandle;
+use tss_esapi::interface_types::algorithm::{ HashingAlgorithm, PublicAlgorithm };
+use tss_esapi::interface_types::key_bits::RsaKeyBits;
+use tss_esapi::interface_types::resource_handles::Hierarchy;
+use tss_esapi::structures::{
+    Digest,
+    KeyedHashScheme,
+    Private,
+    Public,
+    PublicBuilder,
+    PublicKeyedHashParameters,
+    RsaExponent,
+    SensitiveData,
+    SymmetricDefinitionObject,
+};
+use tss_esapi::traits::{ Marshall, UnMarshall };
+use tss_esapi::utils::create_restricted_decryption_rsa_public;
+use tss_esapi::{ Context, TctiNameConf };
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Sealed data object of the TPM, only loadable under the storage primary key of the TPM it was
+/// created on. The TPM is found with the TCTI in `TPM2TOOLS_TCTI`, `TCTI` or `TEST_TCTI`, the
+/// kernel resource manager by default.
+pub struct Tpm2Backend {
+    context: Mutex<Context>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SealedObject {
+    /// Base64 TPM2B_PUBLIC of the sealed object
+    public: String,
+    /// Base64 TPM2B_PRIVATE, encrypted by the TPM to the primary key
+    private: String,
+}
+
+impl Tpm2Backend {
+    pub fn from_env() -> Result<Self> {
+        let tcti = TctiNameConf::from_environment_variable().unwrap_or(TctiNameConf::Device(
+            Default::default()
+        ));
+        let context = Context::new(tcti).map_err(|err| anyhow!("Failed to open the TPM: {}", err))?;
+        Ok(Self { context: Mutex::new(context) })
+    }
+
+    /// The storage primary key is derived from the owner seed, the same template gives the same
+    /// key on every start without it being persisted
+    fn primary_key(context: &mut Context) -> Result<KeyHandle> {
+        let public = create_restricted_decryption_rsa_public(
+            SymmetricDefinitionObject::AES_128_CFB,
+            RsaKeyBits::Rsa2048,
+            RsaExponent::default()
+        )?;
+        let primary = context.execute_with_nullauth_session(|ctx| {
+            ctx.create_primary(Hierarchy::Owner, public, None, None, None, None)
+        })?;
+        Ok(primary.key_handle)
+    }
+}
+
+impl IdentityBackend for Tpm2Backend {
+    fn name(&self) -> &'static str {
+        "tpm2"
+    }
+
+    fn seal(&self, _node_id: &Uuid, secrets: &[u8]) -> Result<Vec<u8>> {
+        let mut context = self.context.lock().unwrap();
+        let primary = Self::primary_key(&mut context)?;
+        let attributes = ObjectAttributesBuilder::new()
+            .with_fixed_tpm(true)
+            .with_fixed_parent(true)
+            .with_user_with_auth(true)
+            .build()?;
+        let public = PublicBuilder::new()
+            .with_public_algorithm(PublicAlgorithm::KeyedHash)
+            .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+            .with_object_attributes(attributes)
+            .with_keyed_hash_parameters(PublicKeyedHashParameters::new(KeyedHashScheme::Null))
+            .with_keyed_hash_unique_identifier(Digest::default())
+            .build()?;
+        let sensitive = SensitiveData::try_from(secrets.to_vec())?;
+        let created = context.execute_with_nullauth_session(|ctx| {
+            ctx.create(primary, public, None, Some(sensitive), None, None)
+        });
+        context.flush_context(primary.into())?;
+        let created = created?;
+        let sealed = SealedObject {
+            public: base64::encode(created.out_public.marshall()?),
+            private: base64::encode(created.out_private.value()),
+        };
+        Ok(serde_json::to_vec(&sealed)?)
+    }
+
+    fn unseal(&self, _node_id: &Uuid, sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let sealed: SealedObject = serde_json::from_slice(sealed)?;
+        let public = Public::unmarshall(&base64::decode(&sealed.public)?)?;
+        let private = Private::try_from(base64::decode(&sealed.private)?)?;
+        let mut context = self.context.lock().unwrap();
+        let primary = Self::primary_key(&mut context)?;
+        let unsealed = context.execute_with_nullauth_session(|ctx| {
+            let object = ctx.load(primary, private, public)?;
+            let unsealed = ctx.unseal(object.into());
+            ctx.flush_context(object.into())?;
+            unsealed
+        });
+        context.flush_context(primary.into())?;
+        Ok(Zeroizing::new(unsealed?.value().to_vec()))
+    }
+}
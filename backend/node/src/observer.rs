@@ -0,0 +1,133 @@
+use crate::auth::client_e2e_decrypt_secret;
+use crate::command::{ JsonCommand, MsgContext };
+use crate::communication::nats::NatsPeerMessenger;
+use crate::communication::protocol::AllRounds;
+use crate::signing::validation::{ check_access_key, verify_hmac, verify_timestamp };
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::tenant::Access;
+use anyhow::{ bail, Result };
+use serde::{ Deserialize, Serialize };
+use tracing::{ info, warn };
+
+const OBSERVERS_METADATA: &str = "observers";
+
+/// Grants or withdraws an observer's read-only access to the sessions of a key. Authenticated
+/// like a signing request, so only the key owner can change who observes its ceremonies.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ObserverConsentCommand {
+    pub key_id: String,
+    pub email: String,
+    pub observer_id: String,
+    /// `false` withdraws a previously granted consent
+    pub granted: bool,
+    pub client_e2e_public_key: String,
+    pub encrypted_signing_key: String,
+    pub timestamp: String,
+    pub message_hmac: String,
+}
+
+impl JsonCommand for ObserverConsentCommand {
+    type Response = Vec<String>;
+
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        validate_observer_id(&self.observer_id)?;
+
+        let app = ctx.get_app()?;
+        let node_signing_key = client_e2e_decrypt_secret(
+            &self.encrypted_signing_key,
+            &app.node.e2e_private_key,
+            &self.client_e2e_public_key
+        )?;
+        if !verify_hmac(&self.message_hmac, &self.timestamp, &self.email, &node_signing_key) {
+            bail!("HMAC verification failed");
+        }
+        check_access_key(&self.key_id, &self.email, &node_signing_key)?;
+        // Recorded last, once the caller is known to hold the access key
+        if !verify_timestamp(&self.key_id, &self.timestamp, &self.email) {
+            bail!("Timestamp verification failed");
+        }
+
+        let mut observers = consented_observers(&self.key_id, &self.email);
+        observers.retain(|id| id != &self.observer_id);
+        if self.granted {
+            observers.push(self.observer_id.clone());
+        }
+        KeyMetadataStore::save(
+            &serde_json::to_string(&observers)?,
+            &self.key_id,
+            OBSERVERS_METADATA,
+            &self.email,
+            &WriteOpts::Modify
+        )?;
+        info!(
+            "Observer {} {} for key {}",
+            self.observer_id,
+            if self.granted { "granted" } else { "withdrawn" },
+            self.key_id
+        );
+        Ok(observers)
+    }
+}
+
+/// Observers the owner of the key has consented to, empty if there are none
+pub fn consented_observers(key_id: &str, email: &str) -> Vec<String> {
+    match KeyMetadataStore::get(key_id, OBSERVERS_METADATA, email) {
+        Ok(stored) =>
+            serde_json::from_str(&stored).unwrap_or_else(|err| {
+                warn!("Ignoring unreadable observer list of key {}: {}", key_id, err);
+                Vec::new()
+            }),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Attaches the key's consented observers to a session messenger
+pub fn with_consented_observers<R: AllRounds>(
+    messenger: NatsPeerMessenger<R>,
+    key_id: &str,
+    email: Option<&str>
+) -> NatsPeerMessenger<R> {
+    match email {
+        Some(email) => messenger.with_observers(consented_observers(key_id, email)),
+        None => messenger,
+    }
+}
+
+/// Observer ids become a NATS subject token, so wildcards and separators are not allowed
+fn validate_observer_id(observer_id: &str) -> Result<()> {
+    let valid =
+        !observer_id.is_empty() &&
+        observer_id.chars().all(|c| (c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    if !valid {
+        bail!("Observer id may only contain ASCII letters, digits, '-' and '_'");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::nats::observer_subject;
+
+    #[test]
+    fn observer_ids_are_single_subject_tokens() {
+        assert!(validate_observer_id("auditor-01_eu").is_ok());
+        assert!(validate_observer_id("").is_err());
+        assert!(validate_observer_id("auditor.*").is_err());
+        assert!(validate_observer_id(">").is_err());
+    }
+
+    #[test]
+    fn maps_session_subjects_to_observer_subjects() {
+        assert_eq!(
+            observer_subject("auditor", "network.gridlock.nodes.KeySignFrost.session-1.NonceCommit"),
+            "network.gridlock.observers.auditor.KeySignFrost.session-1.NonceCommit"
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::node::NodeIdentity;
+use crate::tenant::Access;
+use anyhow::{ bail, Result };
+use serde::{ Deserialize, Serialize };
+use std::env;
+use std::sync::OnceLock;
+use tracing::warn;
+
+const DISPLAY_NAME_VAR: &str = "OPERATOR_DISPLAY_NAME";
+const SUPPORT_URL_VAR: &str = "OPERATOR_SUPPORT_URL";
+const CONTACT_VAR: &str = "OPERATOR_CONTACT";
+/// Wallets show these fields as they are, longer values are refused rather than truncated
+const MAX_FIELD_CHARS: usize = 256;
+
+/// Who runs this guardian, shown by wallets next to what the guardian did, e.g. "Your guardian
+/// 'Acme Custody' approved the signature"
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct OperatorInfo {
+    pub display_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub support_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contact: Option<String>,
+}
+
+impl OperatorInfo {
+    /// Operator info from the environment, `None` unless a display name is set. Invalid values
+    /// are logged once and the info left out.
+    pub fn configured() -> Option<&'static OperatorInfo> {
+        static OPERATOR: OnceLock<Option<OperatorInfo>> = OnceLock::new();
+        OPERATOR.get_or_init(|| {
+            match Self::from_env() {
+                Ok(operator) => operator,
+                Err(err) => {
+                    warn!("Ignoring the operator info: {}", err);
+                    None
+                }
+            }
+        }).as_ref()
+    }
+
+    fn from_env() -> Result<Option<Self>> {
+        let var = |name: &str| {
+            env::var(name)
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let display_name = match var(DISPLAY_NAME_VAR) {
+            Some(display_name) => display_name,
+            None => {
+                return Ok(None);
+            }
+        };
+        let operator = OperatorInfo {
+            display_name,
+            support_url: var(SUPPORT_URL_VAR),
+            contact: var(CONTACT_VAR),
+        };
+        operator.validate()?;
+        Ok(Some(operator))
+    }
+
+    fn validate(&self) -> Result<()> {
+        let fields = [
+            Some(&self.display_name),
+            self.support_url.as_ref(),
+            self.contact.as_ref(),
+        ];
+        for field in fields.into_iter().flatten() {
+            if field.chars().count() > MAX_FIELD_CHARS {
+                bail!("Operator fields are limited to {} characters", MAX_FIELD_CHARS);
+            }
+            if field.chars().any(char::is_control) {
+                bail!("Operator fields must not contain control characters");
+            }
+        }
+        if let Some(url) = &self.support_url {
+            if !url.starts_with("https://") {
+                bail!("Support URL {} must be an https URL", url);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct NodeInfoResponse {
+    pub node_id: String,
+    pub name: String,
+    pub networking_public_key: String,
+    pub e2e_public_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator: Option<OperatorInfo>,
+}
+
+/// Identity of the node and the operator running it, for wallets pairing with the guardian
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetNodeInfoCommand {}
+
+impl JsonCommand for GetNodeInfoCommand {
+    type Response = NodeInfoResponse;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let node = NodeIdentity::cached()?;
+        Ok(NodeInfoResponse {
+            node_id: node.node_id.to_string(),
+            name: node.name,
+            networking_public_key: node.networking_public_key,
+            e2e_public_key: node.e2e_public_key,
+            operator: OperatorInfo::configured().cloned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_plain_http_support_urls_and_oversized_fields() {
+        let mut operator = OperatorInfo {
+            display_name: "Acme Custody".to_string(),
+            support_url: Some("https://support.acme.example".to_string()),
+            contact: Some("help@acme.example".to_string()),
+        };
+        assert!(operator.validate().is_ok());
+
+        operator.support_url = Some("http://support.acme.example".to_string());
+        assert!(operator.validate().is_err());
+
+        operator.support_url = None;
+        operator.display_name = "A".repeat(MAX_FIELD_CHARS + 1);
+        assert!(operator.validate().is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use crate::auth::client_e2e_decrypt;
+use crate::command::{ JsonCommand, MsgContext };
+use crate::node::NodeIdentity;
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Result };
+use chrono::{ DateTime, Duration, Utc };
+use rand::RngCore;
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use std::env;
+use tracing::{ info, warn };
+
+/// Set to true to accept keygen requests from clients that never paired, as nodes did before
+/// pairing was introduced
+const ALLOW_UNPAIRED_VAR: &str = "ALLOW_UNPAIRED_CLIENTS";
+/// User metadata holding the e2e public key of the client an account is paired with
+const PAIRED_CLIENT_METADATA: &str = "paired_client";
+const PENDING_PAIRING_METADATA: &str = "pending_pairing";
+/// Time the owner has to compare the codes and confirm the pairing
+const PAIRING_TTL_MINUTES: i64 = 10;
+const PAIRING_CODE_DIGITS: u32 = 6;
+
+#[derive(Serialize, Deserialize)]
+struct PendingPairing {
+    client_e2e_public_key: String,
+    /// Hex nonce of the node, so every attempt shows a fresh code
+    nonce: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl PendingPairing {
+    fn code(&self, node_e2e_public_key: &str) -> Result<String> {
+        pairing_code(node_e2e_public_key, &self.client_e2e_public_key, &hex::decode(&self.nonce)?)
+    }
+}
+
+/// Starts pairing a client app with the node for an account. The node answers with its e2e key
+/// and a short code derived from both keys; the owner compares the code the app computes with
+/// the one the node logs, or scans it as a QR code, before the app confirms the pairing.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PairDeviceCommand {
+    pub email: String,
+    pub client_e2e_public_key: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct PairingChallenge {
+    pub node_id: String,
+    pub node_e2e_public_key: String,
+    /// Hex nonce the code is derived with
+    pub nonce: String,
+    pub pairing_code: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl JsonCommand for PairDeviceCommand {
+    type Response = PairingChallenge;
+
+    /// The owner compares the pairing code before the pairing is confirmed
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let node = NodeIdentity::cached()?;
+        match paired_client(&self.email) {
+            Some(paired) if paired != self.client_e2e_public_key => {
+                bail!("Account {} is already paired with another client", self.email);
+            }
+            _ => {}
+        }
+        let mut nonce = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let pending = PendingPairing {
+            client_e2e_public_key: self.client_e2e_public_key,
+            nonce: hex::encode(nonce),
+            expires_at: Utc::now() + Duration::minutes(PAIRING_TTL_MINUTES),
+        };
+        let pairing_code = pending.code(&node.e2e_public_key)?;
+        KeyMetadataStore::save_user_level(
+            &serde_json::to_string(&pending)?,
+            PENDING_PAIRING_METADATA,
+            &self.email,
+            &WriteOpts::Modify
+        )?;
+        info!("Pairing code for account {} is {}", self.email, pairing_code);
+        Ok(PairingChallenge {
+            node_id: node.node_id.to_string(),
+            node_e2e_public_key: node.e2e_public_key.clone(),
+            nonce: pending.nonce,
+            pairing_code,
+            expires_at: pending.expires_at,
+        })
+    }
+}
+
+/// Completes a pairing once the owner has compared the codes. Encrypting the code to the node
+/// proves the app holds the private key of the client e2e key being paired.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ConfirmPairingCommand {
+    pub email: String,
+    pub client_e2e_public_key: String,
+    /// Pairing code e2e-encrypted from the client key to the node
+    pub encrypted_pairing_code: String,
+}
+
+impl JsonCommand for ConfirmPairingCommand {
+    type Response = ();
+
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let node = NodeIdentity::cached()?;
+        let pending = KeyMetadataStore::get_user_level(PENDING_PAIRING_METADATA, &self.email)
+            .map_err(|_| anyhow!("No pairing is pending for account {}", self.email))?;
+        let pending: PendingPairing = serde_json::from_str(&pending)?;
+        if pending.expires_at < Utc::now() {
+            bail!("Pairing of account {} has expired", self.email);
+        }
+        if pending.client_e2e_public_key != self.client_e2e_public_key {
+            bail!("Pairing of account {} was started by another client", self.email);
+        }
+        let code = client_e2e_decrypt(
+            &self.encrypted_pairing_code,
+            &node.e2e_private_key,
+            &self.client_e2e_public_key
+        )?;
+        if code != pending.code(&node.e2e_public_key)?.as_bytes() {
+            bail!("Pairing code does not match");
+        }
+        KeyMetadataStore::save_user_level(
+            &self.client_e2e_public_key,
+            PAIRED_CLIENT_METADATA,
+            &self.email,
+            &WriteOpts::Modify
+        )?;
+        let removed = KeyMetadataStore::remove_user_level(PENDING_PAIRING_METADATA, &self.email);
+        if let Err(err) = removed {
+            warn!("Failed to remove pending pairing of account {}: {}", self.email, err);
+        }
+        info!("Paired account {} with client {}", self.email, self.client_e2e_public_key);
+        Ok(())
+    }
+}
+
+/// Refuses keygen requests from a client the account has not been paired with
+pub fn ensure_paired(email: &str, client_e2e_public_key: &str) -> Result<()> {
+    if env::var(ALLOW_UNPAIRED_VAR).is_ok_and(|value| value == "true") {
+        return Ok(());
+    }
+    match paired_client(email) {
+        Some(paired) if paired == client_e2e_public_key => Ok(()),
+        Some(_) => bail!("Client is not the one paired with account {}", email),
+        None => bail!("Account {} has not paired a client with this node", email),
+    }
+}
+
+fn paired_client(email: &str) -> Option<String> {
+    KeyMetadataStore::get_user_level(PAIRED_CLIENT_METADATA, email).ok()
+}
+
+/// Decimal code over both e2e keys and the nonce, a man in the middle pairing with its own keys
+/// makes the app and the node show different codes
+fn pairing_code(
+    node_e2e_public_key: &str,
+    client_e2e_public_key: &str,
+    nonce: &[u8]
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"gridlock-pairing");
+    hasher.update(base64::decode(node_e2e_public_key)?);
+    hasher.update(base64::decode(client_e2e_public_key)?);
+    hasher.update(nonce);
+    let digest = hasher.finalize();
+    let value = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    let digits = PAIRING_CODE_DIGITS as usize;
+    Ok(format!("{:0digits$}", value % 10u32.pow(PAIRING_CODE_DIGITS)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairing_codes_depend_on_both_keys() {
+        let node = base64::encode([1u8; 32]);
+        let client = base64::encode([2u8; 32]);
+        let intruder = base64::encode([3u8; 32]);
+        let code = pairing_code(&node, &client, b"nonce").unwrap();
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(code, pairing_code(&node, &client, b"nonce").unwrap());
+        assert_ne!(code, pairing_code(&node, &intruder, b"nonce").unwrap());
+        assert_ne!(code, pairing_code(&intruder, &client, b"nonce").unwrap());
+        assert_ne!(code, pairing_code(&node, &client, b"other").unwrap());
+    }
+}
@@ -0,0 +1,462 @@
+use crate::auth::client_e2e_decrypt_secret;
+use crate::command::{ JsonCommand, MsgContext };
+use crate::node::NodeIdentity;
+use crate::signing::validation::{
+    check_access_key,
+    transfer_destination,
+    verify_hmac_input,
+    verify_timestamp,
+};
+use crate::signing::tx_inspector::EvmTransaction;
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Result };
+use chrono::{ DateTime, Duration, Timelike, Utc };
+use nkeys::KeyPair;
+use serde::{ Deserialize, Serialize };
+use std::fmt::Debug;
+use std::sync::Mutex;
+use tracing::info;
+
+const POLICY_KEY: &str = "signing_policy";
+const SIGNING_HISTORY_KEY: &str = "signing_history";
+const DEFAULT_APPROVAL_TTL_SECS: u64 = 300;
+/// Keeps a forgotten request from holding its session open for long
+const MAX_APPROVAL_TTL_SECS: u64 = 3600;
+
+/// Serializes the rate limit check and the update of the signing history between sessions
+static HISTORY_LOCK: Mutex<()> = Mutex::new(());
+
+/// Restrictions on what a key signs, checked by the node before it joins a signing session. A
+/// key without a stored policy signs anything its owner authorizes.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SigningPolicy {
+    /// Most signing sessions the node joins for the key in any rolling hour
+    #[serde(default)]
+    pub max_signatures_per_hour: Option<u32>,
+    /// Patterns the destination of a transfer message has to match, `*` matches any characters.
+    /// Empty allows any destination.
+    #[serde(default)]
+    pub allowed_destinations: Vec<String>,
+    /// Every message has to start with one of these, empty allows any message
+    #[serde(default)]
+    pub required_prefixes: Vec<String>,
+    #[serde(default)]
+    pub time_window: Option<TimeWindow>,
+    /// Requests the owner has to approve before the node signs them, see `approval`
+    #[serde(default)]
+    pub approval: Option<ApprovalPolicy>,
+    /// Rules for requests flagged as Ethereum transactions
+    #[serde(default)]
+    pub evm: Option<EvmPolicy>,
+}
+
+/// UTC hours signing is allowed in, from `start_hour` up to but excluding `end_hour`. A window
+/// with `end_hour` before `start_hour` spans midnight.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TimeWindow {
+    pub start_hour: u32,
+    pub end_hour: u32,
+}
+
+impl TimeWindow {
+    fn contains(&self, now: DateTime<Utc>) -> bool {
+        let hour = now.hour();
+        if self.start_hour <= self.end_hour {
+            self.start_hour <= hour && hour < self.end_hour
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+/// Signing requests that wait for an `ApproveSigningCommand` signed by the approver key
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApprovalPolicy {
+    /// NKey public key of the approver, usually a second device of the owner
+    pub approver_public_key: String,
+    /// Only transfer requests need approval, other requests are signed right away
+    #[serde(default)]
+    pub transfers_only: bool,
+    /// How long the node waits for the approval, 5 minutes if not set
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+impl ApprovalPolicy {
+    pub fn applies_to(&self, request: &SigningRequest) -> bool {
+        request.is_transfer || !self.transfers_only
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::seconds(self.ttl_secs.unwrap_or(DEFAULT_APPROVAL_TTL_SECS) as i64)
+    }
+
+    fn validate(&self) -> Result<()> {
+        KeyPair::from_public_key(&self.approver_public_key).map_err(|err| {
+            anyhow!("Invalid approver public key: {}", err)
+        })?;
+        let ttl = self.ttl_secs.unwrap_or(DEFAULT_APPROVAL_TTL_SECS);
+        if ttl == 0 || ttl > MAX_APPROVAL_TTL_SECS {
+            bail!("Approval TTL has to be between 1 and {} seconds", MAX_APPROVAL_TTL_SECS);
+        }
+        Ok(())
+    }
+}
+
+/// Restrictions on Ethereum transactions, e.g. only ERC-20 transfers to some addresses with
+/// `allowed_selectors` set to the `transfer` selector and `allowed_token_recipients`. Address
+/// patterns are matched against lowercase addresses.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EvmPolicy {
+    /// Requests that aren't decodable Ethereum transactions are refused
+    #[serde(default)]
+    pub transactions_only: bool,
+    /// Patterns the destination has to match, empty allows any. Contract creations are refused
+    /// once set.
+    #[serde(default)]
+    pub allowed_targets: Vec<String>,
+    /// Selectors the calldata may call, `0x` for transactions without calldata. Empty allows any.
+    #[serde(default)]
+    pub allowed_selectors: Vec<String>,
+    /// Patterns the recipient of an ERC-20 transfer has to match, empty allows any
+    #[serde(default)]
+    pub allowed_token_recipients: Vec<String>,
+}
+
+impl EvmPolicy {
+    fn validate(&self) -> Result<()> {
+        for selector in &self.allowed_selectors {
+            let digits = selector.strip_prefix("0x").unwrap_or_default();
+            let valid = digits.is_empty() || (digits.len() == 8 && hex::decode(digits).is_ok());
+            if !selector.starts_with("0x") || !valid {
+                bail!("Invalid calldata selector {}", selector);
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, transaction: Option<&EvmTransaction>) -> Result<()> {
+        let transaction = match transaction {
+            Some(transaction) => transaction,
+            None if self.transactions_only => {
+                bail!("The policy only allows signing Ethereum transactions");
+            }
+            None => {
+                return Ok(());
+            }
+        };
+        if !self.allowed_targets.is_empty() {
+            let to = match &transaction.to {
+                Some(to) => to,
+                None => bail!("Contract creations are not allowed by the policy"),
+            };
+            if !matches_any(&self.allowed_targets, to) {
+                bail!("Transactions to {} are not allowed by the policy", to);
+            }
+        }
+        if !self.allowed_selectors.is_empty() {
+            let selector = transaction.selector.as_deref().unwrap_or("0x");
+            let allowed = self.allowed_selectors
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(selector));
+            if !allowed {
+                bail!("Calls of {} are not allowed by the policy", selector);
+            }
+        }
+        if let Some(transfer) = &transaction.erc20_transfer {
+            let recipients = &self.allowed_token_recipients;
+            if !recipients.is_empty() && !matches_any(recipients, &transfer.recipient) {
+                bail!("Token transfers to {} are not allowed by the policy", transfer.recipient);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a signing request asks the key to sign
+pub struct SigningRequest<'a> {
+    pub messages: Vec<&'a [u8]>,
+    pub is_transfer: bool,
+    /// Decoded transaction of requests flagged as Ethereum transactions
+    pub evm_transaction: Option<&'a EvmTransaction>,
+}
+
+impl SigningPolicy {
+    fn validate(&self) -> Result<()> {
+        if let Some(window) = &self.time_window {
+            let out_of_range = window.start_hour > 23 || window.end_hour > 24;
+            if out_of_range || window.start_hour == window.end_hour {
+                bail!("Invalid signing time window {:?}", window);
+            }
+        }
+        if let Some(approval) = &self.approval {
+            approval.validate()?;
+        }
+        if let Some(evm) = &self.evm {
+            evm.validate()?;
+        }
+        if self.max_signatures_per_hour == Some(0) {
+            bail!("A limit of 0 signatures per hour disables the key, delete it instead");
+        }
+        Ok(())
+    }
+
+    /// Checks the request against the policy, given the signing sessions of the last hour
+    fn check(
+        &self,
+        request: &SigningRequest,
+        recent_signatures: usize,
+        now: DateTime<Utc>
+    ) -> Result<()> {
+        if let Some(window) = &self.time_window {
+            if !window.contains(now) {
+                bail!(
+                    "Signing is only allowed between {}:00 and {}:00 UTC",
+                    window.start_hour,
+                    window.end_hour
+                );
+            }
+        }
+        if let Some(max) = self.max_signatures_per_hour {
+            if recent_signatures >= (max as usize) {
+                bail!("The key already signed {} times in the last hour", recent_signatures);
+            }
+        }
+        if !self.required_prefixes.is_empty() {
+            for message in &request.messages {
+                let allowed = self.required_prefixes
+                    .iter()
+                    .any(|prefix| message.starts_with(prefix.as_bytes()));
+                if !allowed {
+                    bail!("Message does not start with any of the required prefixes");
+                }
+            }
+        }
+        if request.is_transfer && !self.allowed_destinations.is_empty() {
+            for message in &request.messages {
+                let destination = transfer_destination(message)?;
+                let allowed = self.allowed_destinations
+                    .iter()
+                    .any(|pattern| matches_pattern(pattern, &destination));
+                if !allowed {
+                    bail!("Transfer destination {} is not allowed by the policy", destination);
+                }
+            }
+        }
+        if let Some(evm) = &self.evm {
+            evm.check(request.evm_transaction)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(key_id: &str, email: &str) -> Result<Option<Self>> {
+        // A missing policy file means the key has no policy
+        match KeyMetadataStore::get(key_id, POLICY_KEY, email) {
+            Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Fails if the key's policy does not allow the request, otherwise counts it towards the hourly
+/// limit. Called before the node joins a signing session.
+pub fn enforce_signing_policy(key_id: &str, email: &str, request: &SigningRequest) -> Result<()> {
+    apply_signing_policy(key_id, email, request, true)
+}
+
+/// Checks a request against the policy like `enforce_signing_policy`, without counting it as a
+/// signature
+pub fn check_signing_policy(key_id: &str, email: &str, request: &SigningRequest) -> Result<()> {
+    apply_signing_policy(key_id, email, request, false)
+}
+
+fn apply_signing_policy(
+    key_id: &str,
+    email: &str,
+    request: &SigningRequest,
+    record: bool
+) -> Result<()> {
+    let policy = match SigningPolicy::get(key_id, email)? {
+        Some(policy) => policy,
+        None => {
+            return Ok(());
+        }
+    };
+    let now = Utc::now();
+    let _guard = HISTORY_LOCK.lock().unwrap();
+    let mut history: Vec<DateTime<Utc>> = match
+        KeyMetadataStore::get(key_id, SIGNING_HISTORY_KEY, email)
+    {
+        Ok(contents) => serde_json::from_str(&contents)?,
+        Err(_) => Vec::new(),
+    };
+    history.retain(|signed_at| now - *signed_at < Duration::hours(1));
+    policy.check(request, history.len(), now)?;
+
+    if record && policy.max_signatures_per_hour.is_some() {
+        history.push(now);
+        KeyMetadataStore::save(
+            &serde_json::to_string(&history)?,
+            key_id,
+            SIGNING_HISTORY_KEY,
+            email,
+            &WriteOpts::Modify
+        )?;
+    }
+    Ok(())
+}
+
+fn matches_any(patterns: &[String], address: &str) -> bool {
+    patterns.iter().any(|pattern| matches_pattern(&pattern.to_lowercase(), address))
+}
+
+/// `*` matches any run of characters, everything else matches itself
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == value;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    let fits = value.len() >= first.len() + last.len();
+    if !fits || !value.starts_with(first) || !value.ends_with(last) {
+        return false;
+    }
+    let mut rest = &value[first.len()..value.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(position) => {
+                rest = &rest[position + part.len()..];
+            }
+            None => {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Replaces the signing policy of a key. Authorized like a signing request with the access key
+/// of the account, the HMAC additionally covers the policy.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetPolicyCommand {
+    pub key_id: String,
+    pub email: String,
+    /// JSON encoded `SigningPolicy`, empty to remove the policy
+    pub policy: String,
+    pub timestamp: String,
+    /// Base64 HMAC-SHA256 of `timestamp + email + policy` with the access key
+    pub message_hmac: String,
+    pub client_e2e_public_key: String,
+    pub encrypted_signing_key: String,
+}
+
+impl Debug for SetPolicyCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SetPolicyCommand")
+            .field("key_id", &self.key_id)
+            .field("email", &self.email)
+            .field("policy", &self.policy)
+            .field("timestamp", &self.timestamp)
+            .finish()
+    }
+}
+
+impl JsonCommand for SetPolicyCommand {
+    type Response = Option<SigningPolicy>;
+
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let node = NodeIdentity::cached()?;
+        let node_signing_key = client_e2e_decrypt_secret(
+            &self.encrypted_signing_key,
+            &node.e2e_private_key,
+            &self.client_e2e_public_key
+        )?;
+        let message_input = format!("{}{}{}", self.timestamp, self.email, self.policy);
+        if !verify_hmac_input(&self.message_hmac, &message_input, &node_signing_key) {
+            bail!("HMAC verification failed");
+        }
+        check_access_key(&self.key_id, &self.email, &node_signing_key)?;
+        // Only recorded once the key is authorized, a forged request mustn't burn the timestamp
+        if !verify_timestamp(&self.key_id, &self.timestamp, &self.email) {
+            bail!("Timestamp verification failed");
+        }
+
+        if self.policy.is_empty() {
+            KeyMetadataStore::remove(&self.key_id, POLICY_KEY, &self.email)?;
+            info!("Removed the signing policy of key {}", self.key_id);
+            return Ok(None);
+        }
+        let policy: SigningPolicy = serde_json
+            ::from_str(&self.policy)
+            .map_err(|err| anyhow!("Invalid signing policy: {}", err))?;
+        policy.validate()?;
+        KeyMetadataStore::save(
+            &serde_json::to_string(&policy)?,
+            &self.key_id,
+            POLICY_KEY,
+            &self.email,
+            &WriteOpts::Modify
+        )?;
+        info!("Updated the signing policy of key {}", self.key_id);
+        Ok(Some(policy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn enforces_prefixes_destinations_windows_and_rate() {
+        let policy = SigningPolicy {
+            max_signatures_per_hour: Some(2),
+            allowed_destinations: vec!["UA*XYZ".to_string()],
+            required_prefixes: vec!["Authorizing".to_string()],
+            time_window: Some(TimeWindow { start_hour: 22, end_hour: 6 }),
+            approval: None,
+            evm: None,
+        };
+        let night = Utc.with_ymd_and_hms(2026, 3, 1, 23, 30, 0).unwrap();
+        let noon = Utc.with_ymd_and_hms(2026, 3, 1, 12, 0, 0).unwrap();
+        let transfer = |destination: &str| {
+            format!("Authorizing ownership transfer to {}", destination).into_bytes()
+        };
+        let allowed = transfer("UABCXYZ");
+        let request = SigningRequest {
+            messages: vec![allowed.as_slice()],
+            is_transfer: true,
+            evm_transaction: None,
+        };
+
+        assert!(policy.check(&request, 1, night).is_ok());
+        assert!(policy.check(&request, 2, night).is_err());
+        assert!(policy.check(&request, 0, noon).is_err());
+
+        let elsewhere = transfer("UABCDEF");
+        let request = SigningRequest {
+            messages: vec![elsewhere.as_slice()],
+            is_transfer: true,
+            evm_transaction: None,
+        };
+        assert!(policy.check(&request, 0, night).is_err());
+
+        let request = SigningRequest {
+            messages: vec![&b"hello"[..]],
+            is_transfer: false,
+            evm_transaction: None,
+        };
+        assert!(policy.check(&request, 0, night).is_err());
+    }
+}
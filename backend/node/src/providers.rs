@@ -0,0 +1,126 @@
+use crate::node::NodeIdentity;
+use crate::provisioning;
+use crate::{ create_new_node_identity, get_nats_client };
+use anyhow::Result;
+
+/// Supplies the identity an `App` runs as
+pub trait IdentityProvider {
+    fn identity(&self) -> Result<NodeIdentity>;
+}
+
+/// Opens the NATS connection an `App` communicates over, once as the client reconnects on its own
+pub trait ConnectionProvider: Send + Sync {
+    fn connect(&self) -> Result<async_nats::Client>;
+}
+
+/// Loads the identity from storage. On first start it is taken from the provisioning token if
+/// one is configured, otherwise a new random identity is created and saved.
+pub struct StoredIdentityProvider;
+
+impl IdentityProvider for StoredIdentityProvider {
+    fn identity(&self) -> Result<NodeIdentity> {
+        if let Ok(node) = NodeIdentity::cached() {
+            return Ok(node);
+        }
+        match provisioning::provision_from_env()? {
+            Some(node) => Ok(node),
+            None => create_new_node_identity(),
+        }
+    }
+}
+
+/// Connects to the configured NATS network
+pub struct NatsConnectionProvider;
+
+impl ConnectionProvider for NatsConnectionProvider {
+    fn connect(&self) -> Result<async_nats::Client> {
+        get_nats_client()
+    }
+}
+
+/// Providers for tests, built with the `testing` feature
+#[cfg(any(test, feature = "testing"))]
+pub mod mock {
+    use super::*;
+    use crate::session_manager;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    /// Always returns the same in-memory identity, nothing is read from or written to storage
+    pub struct MockIdentityProvider {
+        pub identity: NodeIdentity,
+    }
+
+    impl MockIdentityProvider {
+        pub fn new() -> Self {
+            MockIdentityProvider {
+                identity: NodeIdentity::new(),
+            }
+        }
+    }
+
+    impl Default for MockIdentityProvider {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl IdentityProvider for MockIdentityProvider {
+        fn identity(&self) -> Result<NodeIdentity> {
+            Ok(self.identity.clone())
+        }
+    }
+
+    /// Hands out connections that don't require a reachable server. Messages published while
+    /// disconnected are buffered by the client, so handlers can run without a broker.
+    pub struct MockConnectionProvider {
+        address: String,
+        connections: AtomicUsize,
+    }
+
+    impl MockConnectionProvider {
+        pub fn new(address: &str) -> Self {
+            MockConnectionProvider {
+                address: address.to_string(),
+                connections: AtomicUsize::new(0),
+            }
+        }
+
+        /// Number of connections opened so far
+        pub fn connection_count(&self) -> usize {
+            self.connections.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Default for MockConnectionProvider {
+        fn default() -> Self {
+            Self::new("nats://127.0.0.1:1")
+        }
+    }
+
+    impl ConnectionProvider for MockConnectionProvider {
+        fn connect(&self) -> Result<async_nats::Client> {
+            self.connections.fetch_add(1, Ordering::Relaxed);
+            let options = async_nats::ConnectOptions::new().retry_on_initial_connect();
+            let connect = options.connect(self.address.as_str());
+            Ok(session_manager::runtime().block_on(connect)?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::*;
+    use crate::App;
+    use std::sync::Arc;
+
+    #[test]
+    fn app_uses_injected_identity_and_connection() {
+        let identity = MockIdentityProvider::new();
+        let connection = Arc::new(MockConnectionProvider::default());
+        let app = App::with_providers(&identity, connection.clone()).unwrap();
+
+        assert_eq!(app.node.node_id, identity.identity.node_id);
+        // Commands and sessions share the one connection
+        assert_eq!(connection.connection_count(), 1);
+    }
+}
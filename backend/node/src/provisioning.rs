@@ -0,0 +1,259 @@
+use crate::config::{ Config, ConfigProvider };
+use crate::node::NodeIdentity;
+use crate::storage::storage_key::StorageKeyring;
+use anyhow::{ anyhow, bail, Context, Result };
+use chrono::{ DateTime, Utc };
+use ed25519_dalek::{ PublicKey, Signature, Verifier };
+use nkeys::KeyPair;
+use serde::{ Deserialize, Serialize };
+use sodiumoxide::crypto::box_;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::{ env, fs };
+use tracing::{ info, warn };
+use uuid::Uuid;
+
+/// Base64 token, or a file holding it, used once to bootstrap the identity of a fresh node
+const TOKEN_VAR: &str = "PROVISIONING_TOKEN";
+const TOKEN_FILE_VAR: &str = "PROVISIONING_TOKEN_FILE";
+/// Base64 ed25519 key of the operator that provisioning tokens have to be signed with
+const SIGNER_PUBLIC_KEY_VAR: &str = "PROVISIONING_SIGNER_PUBLIC_KEY";
+const CONSUMED_TOKENS_FILE: &str = "provisioning.json";
+const NATS_CREDENTIALS_FILE: &str = "nats_credentials.json";
+
+/// Identity and NATS credentials the operator registered for one node
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProvisioningPayload {
+    pub token_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+    pub node_id: Uuid,
+    pub name: String,
+    /// NKey seed of the networking key pair
+    pub networking_private_key: String,
+    pub e2e_public_key: String,
+    pub e2e_private_key: String,
+    pub nats_user: String,
+    pub nats_password: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProvisioningToken {
+    pub payload: ProvisioningPayload,
+    /// Base64 ed25519 signature over the JSON encoding of `payload`
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NatsCredentials {
+    pub user: String,
+    pub password: String,
+}
+
+/// Ids of the tokens this node was provisioned with, so a token cannot be applied twice even if
+/// the identity file is removed later
+#[derive(Serialize, Deserialize, Default)]
+struct ConsumedTokens {
+    token_ids: BTreeSet<Uuid>,
+}
+
+impl ConsumedTokens {
+    fn load() -> Result<Self> {
+        let path = gridlock_path(CONSUMED_TOKENS_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::write(gridlock_path(CONSUMED_TOKENS_FILE), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+fn gridlock_path(file: &str) -> PathBuf {
+    let mut path = Config::get_gridlock_directory();
+    path.push(file);
+    path
+}
+
+impl ProvisioningToken {
+    pub fn decode(encoded: &str) -> Result<Self> {
+        let json = base64::decode(encoded.trim()).context("Provisioning token is not base64")?;
+        serde_json::from_slice(&json).context("Provisioning token is malformed")
+    }
+
+    fn verify(&self, signer_public_key: &str) -> Result<()> {
+        let public_key = PublicKey::from_bytes(&base64::decode(signer_public_key)?).map_err(|err|
+            anyhow!("Invalid provisioning signer public key: {}", err)
+        )?;
+        let signature = Signature::try_from(&base64::decode(&self.signature)?[..]).map_err(|err|
+            anyhow!("Invalid provisioning token signature encoding: {}", err)
+        )?;
+        public_key
+            .verify(&serde_json::to_vec(&self.payload)?, &signature)
+            .map_err(|_| anyhow!("Provisioning token signature verification failed"))
+    }
+
+    /// Checks everything that does not depend on the node's storage: signature, expiry and that
+    /// the key pairs in the token belong together
+    fn validate(&self, signer_public_key: &str, now: DateTime<Utc>) -> Result<NodeIdentity> {
+        self.verify(signer_public_key)?;
+        let payload = &self.payload;
+        if payload.expires_at <= now {
+            bail!("Provisioning token {} expired at {}", payload.token_id, payload.expires_at);
+        }
+
+        let networking = KeyPair::from_seed(&payload.networking_private_key).map_err(|err|
+            anyhow!("Provisioning token has an invalid networking key: {}", err)
+        )?;
+        let e2e_private_key = box_::SecretKey
+            ::from_slice(&base64::decode(&payload.e2e_private_key)?)
+            .ok_or_else(|| anyhow!("Provisioning token has an invalid e2e private key"))?;
+        if base64::encode(e2e_private_key.public_key().as_ref()) != payload.e2e_public_key {
+            bail!("Provisioning token e2e public key does not match its private key");
+        }
+
+        Ok(
+            NodeIdentity::from(
+                payload.node_id,
+                networking.public_key(),
+                payload.networking_private_key.clone(),
+                payload.e2e_public_key.clone(),
+                payload.e2e_private_key.clone(),
+                payload.name.clone()
+            )
+        )
+    }
+}
+
+fn read_token() -> Result<Option<(String, Option<PathBuf>)>> {
+    if let Ok(token) = env::var(TOKEN_VAR) {
+        if !token.is_empty() {
+            return Ok(Some((token, None)));
+        }
+    }
+    match env::var(TOKEN_FILE_VAR) {
+        Ok(path) if !path.is_empty() => {
+            let path = PathBuf::from(path);
+            let token = fs
+                ::read_to_string(&path)
+                .with_context(|| format!("Reading provisioning token {}", path.display()))?;
+            Ok(Some((token, Some(path))))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Creates the node identity from a provisioning token when one is configured. Only called when
+/// the node has no identity yet, an existing identity is never replaced. A configured token
+/// that fails validation stops the node instead of falling back to a random identity.
+pub fn provision_from_env() -> Result<Option<NodeIdentity>> {
+    let (encoded, token_file) = match read_token()? {
+        Some(token) => token,
+        None => {
+            return Ok(None);
+        }
+    };
+    let signer_public_key = match env::var(SIGNER_PUBLIC_KEY_VAR) {
+        Ok(key) => key,
+        Err(_) => bail!("{} is not set, cannot verify provisioning tokens", SIGNER_PUBLIC_KEY_VAR),
+    };
+
+    let token = ProvisioningToken::decode(&encoded)?;
+    let node = token.validate(&signer_public_key, Utc::now())?;
+    let mut consumed = ConsumedTokens::load()?;
+    if !consumed.token_ids.insert(token.payload.token_id) {
+        bail!("Provisioning token {} has already been used", token.payload.token_id);
+    }
+    // Marked as used before anything else is written, a crash in between leaves the token spent
+    consumed.save()?;
+
+    node.save()?;
+    let credentials = NatsCredentials {
+        user: token.payload.nats_user,
+        password: token.payload.nats_password,
+    };
+    let sealed = StorageKeyring::load()?.seal(&serde_json::to_vec(&credentials)?)?;
+    fs::write(gridlock_path(NATS_CREDENTIALS_FILE), sealed)?;
+
+    if let Some(path) = token_file {
+        if let Err(err) = fs::remove_file(&path) {
+            warn!("Failed to remove the used provisioning token {}: {}", path.display(), err);
+        }
+    }
+    info!("Provisioned node {} from token {}", node.node_id, token.payload.token_id);
+    Ok(Some(node))
+}
+
+/// NATS credentials saved from the provisioning token, none for nodes that were not provisioned
+pub fn stored_nats_credentials() -> Result<Option<NatsCredentials>> {
+    let path = gridlock_path(NATS_CREDENTIALS_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let plaintext = StorageKeyring::load()?.open(&fs::read_to_string(path)?)?;
+    Ok(Some(serde_json::from_slice(&plaintext)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use ed25519_dalek::{ Keypair, SecretKey, Signer };
+
+    fn signer() -> Keypair {
+        let secret = SecretKey::from_bytes(&[9u8; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    fn signed_token(payload: ProvisioningPayload, signer: &Keypair) -> ProvisioningToken {
+        let signature = signer.sign(&serde_json::to_vec(&payload).unwrap());
+        ProvisioningToken {
+            payload,
+            signature: base64::encode(signature.to_bytes()),
+        }
+    }
+
+    fn payload(expires_at: DateTime<Utc>) -> ProvisioningPayload {
+        let node = NodeIdentity::new();
+        ProvisioningPayload {
+            token_id: Uuid::new_v4(),
+            expires_at,
+            node_id: node.node_id,
+            name: node.name,
+            networking_private_key: node.networking_private_key,
+            e2e_public_key: node.e2e_public_key,
+            e2e_private_key: node.e2e_private_key,
+            nats_user: "node".to_string(),
+            nats_password: "secret".to_string(),
+        }
+    }
+
+    #[test]
+    fn accepts_only_valid_signed_tokens() {
+        let signer = signer();
+        let signer_public_key = base64::encode(signer.public.to_bytes());
+        let now = Utc::now();
+
+        let token = signed_token(payload(now + Duration::hours(1)), &signer);
+        let encoded = base64::encode(serde_json::to_vec(&token).unwrap());
+        let node = ProvisioningToken::decode(&encoded)
+            .unwrap()
+            .validate(&signer_public_key, now)
+            .unwrap();
+        assert_eq!(node.node_id, token.payload.node_id);
+
+        let mut tampered = token.clone();
+        tampered.payload.nats_user = "other".to_string();
+        assert!(tampered.validate(&signer_public_key, now).is_err());
+
+        let expired = signed_token(payload(now - Duration::minutes(1)), &signer);
+        assert!(expired.validate(&signer_public_key, now).is_err());
+
+        let mut mismatched = payload(now + Duration::hours(1));
+        mismatched.e2e_public_key = NodeIdentity::new().e2e_public_key;
+        assert!(signed_token(mismatched, &signer).validate(&signer_public_key, now).is_err());
+    }
+}
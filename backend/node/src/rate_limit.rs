@@ -0,0 +1,135 @@
+//! Admission of the messages that start sessions, checked before anything else is done with them.
+//! Anyone able to publish on the node's subjects could otherwise make it start sessions without
+//! bound:
+//! - Each route starts at most `SESSION_RATE_LIMIT_PER_MINUTE` sessions a minute, and the
+//!   requests of one email at most `SESSION_RATE_LIMIT_PER_EMAIL_PER_MINUTE` over all routes.
+//!   0 lifts a limit.
+//! - No session starts while `MAX_CONCURRENT_SESSIONS` are running, see `session_manager`.
+//!
+//! Refused requests are reported to the requester with a `SessionError`.
+
+use crate::session_error::{ SessionError, SessionErrorCode };
+use crate::session_manager::{ self, env_limit };
+use std::collections::{ HashMap, VecDeque };
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+const ROUTE_LIMIT_VAR: &str = "SESSION_RATE_LIMIT_PER_MINUTE";
+const DEFAULT_ROUTE_LIMIT: usize = 600;
+const EMAIL_LIMIT_VAR: &str = "SESSION_RATE_LIMIT_PER_EMAIL_PER_MINUTE";
+const DEFAULT_EMAIL_LIMIT: usize = 60;
+const WINDOW: Duration = Duration::from_secs(60);
+/// Retry hint of requests refused because the node is busy
+const BUSY_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+static ADMITTED: Mutex<Option<AdmittedSessions>> = Mutex::new(None);
+
+/// Start times of the sessions admitted within the window, by route and by email
+#[derive(Default)]
+struct AdmittedSessions {
+    starts: HashMap<String, VecDeque<Instant>>,
+}
+
+impl AdmittedSessions {
+    /// Time until the bucket admits another session, `None` if it does now
+    fn wait_time(&mut self, bucket: &str, limit: usize, now: Instant) -> Option<Duration> {
+        if limit == 0 {
+            return None;
+        }
+        let starts = self.starts.entry(bucket.to_string()).or_default();
+        while starts.front().is_some_and(|start| now.duration_since(*start) >= WINDOW) {
+            starts.pop_front();
+        }
+        match starts.front() {
+            Some(oldest) if starts.len() >= limit => Some(WINDOW - now.duration_since(*oldest)),
+            _ => None,
+        }
+    }
+
+    fn record(&mut self, bucket: &str, limit: usize, now: Instant) {
+        if limit > 0 {
+            self.starts.entry(bucket.to_string()).or_default().push_back(now);
+        }
+    }
+
+    /// Admits a session of the route and email, counting it against both limits
+    fn admit(
+        &mut self,
+        route: &str,
+        email: Option<&str>,
+        limits: (usize, usize),
+        now: Instant
+    ) -> Result<(), SessionError> {
+        let (route_limit, email_limit) = limits;
+        // Emails without a session in the window are forgotten, so made up ones don't pile up
+        self.starts.retain(|_, starts| {
+            starts.back().is_some_and(|start| now.duration_since(*start) < WINDOW)
+        });
+        let email_bucket = email.map(|email| format!("email {}", email));
+        let wait = match &email_bucket {
+            Some(bucket) => self.wait_time(bucket, email_limit, now),
+            None => None,
+        };
+        let wait = wait.or_else(|| self.wait_time(route, route_limit, now));
+        if let Some(wait) = wait {
+            let message = format!("Too many {} requests, try again later", route);
+            return Err(
+                SessionError::new(SessionErrorCode::RateLimited, message).with_retry_after(
+                    wait.as_secs().max(1)
+                )
+            );
+        }
+        self.record(route, route_limit, now);
+        if let Some(bucket) = &email_bucket {
+            self.record(bucket, email_limit, now);
+        }
+        Ok(())
+    }
+}
+
+/// Admits a request to start a session on the route, or says why it is refused
+pub fn admit(route: &str, data: &[u8]) -> Result<(), SessionError> {
+    if let Err(err) = session_manager::check_capacity() {
+        return Err(
+            SessionError::new(SessionErrorCode::Overloaded, err).with_retry_after(
+                BUSY_RETRY_AFTER.as_secs()
+            )
+        );
+    }
+    let email = serde_json
+        ::from_slice::<serde_json::Value>(data)
+        .ok()
+        .and_then(|value| value.get("email")?.as_str().map(str::to_string));
+    let limits = (
+        env_limit(ROUTE_LIMIT_VAR, DEFAULT_ROUTE_LIMIT),
+        env_limit(EMAIL_LIMIT_VAR, DEFAULT_EMAIL_LIMIT),
+    );
+    ADMITTED.lock()
+        .unwrap()
+        .get_or_insert_with(AdmittedSessions::default)
+        .admit(route, email.as_deref(), limits, Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_sessions_per_route_and_per_email_within_the_window() {
+        let now = Instant::now();
+        let mut admitted = AdmittedSessions::default();
+        let limits = (3, 2);
+        assert!(admitted.admit("KeyGenEdDSA", Some("a@example.com"), limits, now).is_ok());
+        assert!(admitted.admit("KeyGenEdDSA", Some("a@example.com"), limits, now).is_ok());
+        let refused = admitted.admit("KeySignEdDSA", Some("a@example.com"), limits, now);
+        assert_eq!(refused.unwrap_err().code, SessionErrorCode::RateLimited);
+
+        assert!(admitted.admit("KeyGenEdDSA", Some("b@example.com"), limits, now).is_ok());
+        let refused = admitted.admit("KeyGenEdDSA", None, limits, now).unwrap_err();
+        assert_eq!(refused.retry_after_secs, Some(60));
+
+        let later = now + WINDOW;
+        assert!(admitted.admit("KeyGenEdDSA", Some("a@example.com"), limits, later).is_ok());
+        assert!(admitted.admit("KeyGenEdDSA", None, (0, 0), later).is_ok());
+    }
+}
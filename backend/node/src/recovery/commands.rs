@@ -1,8 +1,9 @@
 use crate::command::{ JsonCommand, MsgContext };
-use crate::communication::{ nats::DummyMessenger, protocol::Topic };
+use crate::communication::loopback::LoopbackMessenger;
 use crate::node::NodeIdentity;
 use crate::recovery::encryption::NKeyTargetEncryptor;
 use crate::recovery::target_role::{
+    BLSBehaviourTargetRole,
     ECDSABehaviourTargetRole,
     EdDSABehaviourTargetRole,
     KeyshareBehaviourTargetRole,
@@ -15,9 +16,11 @@ use crate::recovery::{
     Key,
     RecoveryValidationResult,
 };
-use crate::security::check_for_small_primes;
+use crate::ghost_shares::ECDSA_GHOST_SHARES_UNSUPPORTED;
+use crate::security::verify_paillier_key;
 use crate::storage::{ KeyshareAccessor, ECDSA };
-use anyhow::{ anyhow, Result };
+use crate::tenant::{ Access, TenantAuth };
+use anyhow::{ anyhow, bail, Result };
 use paillier::EncryptionKey;
 use serde::{ Deserialize, Serialize };
 use shared::recovery::{
@@ -31,10 +34,11 @@ fn process_rec_package<TR: KeyshareBehaviourTargetRole>(
     rec_package: ReceiveRecoveryPackages,
     target_role: TR
 ) -> Result<RecoveryValidationResult> {
-    let node = NodeIdentity::load()?;
+    let node = NodeIdentity::cached()?;
     let private_key = node.networking_private_key;
 
-    let messenger = DummyMessenger::new(Topic::KeyShareRecovery)?;
+    // The target only receives packages, it never exchanges round messages
+    let messenger = LoopbackMessenger::standalone(rec_package.recovery_info.recovery_index)?;
 
     let encryptor = NKeyTargetEncryptor::new(
         &rec_package.recovery_info.public_keys.into(),
@@ -51,33 +55,63 @@ fn process_rec_package<TR: KeyshareBehaviourTargetRole>(
     )
 }
 
+/// Recovers and saves the keyshare of this node from the helpers' packages
+pub(crate) fn receive_recovery_packages(
+    rec_package: ReceiveRecoveryPackages
+) -> Result<RecoveryValidationResult> {
+    let key_id = rec_package.recovery_info.key_id.clone();
+    let index = rec_package.recovery_info.recovery_index;
+    match (rec_package.kind.clone(), rec_package.recovery_info.ghost) {
+        (Key::ECDSA, false) => {
+            let role = ECDSABehaviourTargetRole::new(&key_id);
+            process_rec_package(rec_package, role)
+        }
+        (Key::ECDSA, true) => bail!("{}", ECDSA_GHOST_SHARES_UNSUPPORTED),
+        (Key::EDDSA, false) => {
+            process_rec_package(rec_package, EdDSABehaviourTargetRole::new(&key_id))
+        }
+        (Key::EDDSA, true) => {
+            process_rec_package(rec_package, EdDSABehaviourTargetRole::ghost(&key_id, index))
+        }
+        (Key::Sr25519, false) => {
+            process_rec_package(rec_package, Sr25519BehaviourTargetRole::new(&key_id))
+        }
+        (Key::Sr25519, true) => {
+            process_rec_package(rec_package, Sr25519BehaviourTargetRole::ghost(&key_id, index))
+        }
+        (Key::BLS, false) => process_rec_package(rec_package, BLSBehaviourTargetRole::new(&key_id)),
+        (Key::BLS, true) => {
+            process_rec_package(rec_package, BLSBehaviourTargetRole::ghost(&key_id, index))
+        }
+    }
+}
+
 impl JsonCommand for ReceiveRecoveryPackages {
     type Response = RecoveryValidationResult;
 
+    fn access(&self) -> Access<'_> {
+        Access::Peer
+    }
+
     fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
-        match self.kind {
-            Key::ECDSA => {
-                let role = ECDSABehaviourTargetRole::new(&self.recovery_info.key_id);
-                process_rec_package(self, role)
-            }
-            Key::EDDSA => {
-                let role = EdDSABehaviourTargetRole::new(&self.recovery_info.key_id);
-                process_rec_package(self, role)
-            }
-            Key::Sr25519 => {
-                let role = Sr25519BehaviourTargetRole::new(&self.recovery_info.key_id);
-                process_rec_package(self, role)
-            }
-        }
+        receive_recovery_packages(self)
     }
 }
 
 impl JsonCommand for UpdatePaillierKeysCommand {
     type Response = ();
+
+    fn access(&self) -> Access<'_> {
+        Access::Peer
+    }
+
     fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
         // Security issue: CVE-2023-33241
-        for ek in &self.new_eks {
-            check_for_small_primes(ek)?;
+        if self.new_ek_proofs.len() != self.new_eks.len() {
+            bail!("Expected a proof for each of the {} Paillier keys", self.new_eks.len());
+        }
+        for (ek, proof) in self.new_eks.iter().zip(&self.new_ek_proofs) {
+            verify_paillier_key(ek, Some(proof))?;
         }
 
         let mut ka = KeyshareAccessor::<ECDSA>::modifiable(&self.key_id)?;
@@ -88,9 +122,14 @@ impl JsonCommand for UpdatePaillierKeysCommand {
 
 impl JsonCommand for UpdateSinglePaillierKeyCommand {
     type Response = ();
+
+    fn access(&self) -> Access<'_> {
+        Access::Peer
+    }
+
     fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
         // Security issue: CVE-2023-33241
-        check_for_small_primes(&self.new_ek)?;
+        verify_paillier_key(&self.new_ek, self.new_ek_proof.as_ref())?;
 
         let mut ka = KeyshareAccessor::<ECDSA>::modifiable(&self.key_id)?;
         update_paillier_keys(&mut ka, self.index, self.new_ek)?;
@@ -102,6 +141,9 @@ impl JsonCommand for UpdateSinglePaillierKeyCommand {
 #[serde(deny_unknown_fields)]
 pub struct GetPaillierKeysCommand {
     pub key_id: String,
+    /// Required on multi user nodes
+    #[serde(default)]
+    pub authorization: Option<TenantAuth>,
 }
 
 impl Debug for GetPaillierKeysCommand {
@@ -112,8 +154,16 @@ impl Debug for GetPaillierKeysCommand {
 
 impl JsonCommand for GetPaillierKeysCommand {
     type Response = PaillierKeysResponse;
-    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
-        let ka = KeyshareAccessor::<ECDSA>::read_only(&self.key_id)?;
+
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: self.authorization.as_ref(), key_ids: vec![self.key_id.clone()] }
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let ka = match ctx.tenant() {
+            Some(email) => KeyshareAccessor::<ECDSA>::read_only_with_email(&self.key_id, &email)?,
+            None => KeyshareAccessor::<ECDSA>::read_only(&self.key_id)?,
+        };
         Ok(PaillierKeysResponse {
             eks: ka.key.paillier_key_vec,
         })
@@ -0,0 +1,313 @@
+//! Time-delayed recovery. Keys with a recovery delay are only recovered once the delay passed since
+//! the recovery was requested, which gives their owner time to cancel a recovery started by someone
+//! who took over the account. Every guardian of the key records the request and its helpers refuse
+//! to produce packages before the delay passed on their own clock.
+
+use crate::command::{ JsonCommand, MsgContext, TaggedCommandType };
+use crate::communication::blocking;
+use crate::recovery::expiry::RevokedRecoverySessions;
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::tenant::{ Access, TenantAuth };
+use anyhow::{ bail, Context, Result };
+use chrono::{ DateTime, Duration, Utc };
+use serde::{ Deserialize, Serialize };
+use shared::key_info::NodeId;
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Mutex;
+use tracing::{ info, warn };
+
+const DELAYS_KEY: &str = "recovery_delays";
+const PENDING_SUBJECT: &str = "network.gridlock.recovery.pending";
+const DEFAULT_DELAY_VAR: &str = "RECOVERY_DELAY_HOURS";
+/// Requests are kept this long past their delay for recoveries that are retried
+const PENDING_RETENTION_DAYS: i64 = 30;
+
+/// Serializes the updates of the stored delays and requests
+static DELAYS_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct PendingRecovery {
+    pub key_id: String,
+    pub session_id: String,
+    pub email: String,
+    pub requested_at: DateTime<Utc>,
+    pub not_before: DateTime<Utc>,
+    #[serde(default)]
+    pub cancelled: bool,
+}
+
+/// Delays set per key and the recoveries waiting for them, by session id
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+struct RecoveryDelays {
+    delays_hours: BTreeMap<String, u32>,
+    pending: BTreeMap<String, PendingRecovery>,
+}
+
+impl RecoveryDelays {
+    fn load() -> Result<Self> {
+        match KeyMetadataStore::get_node_level(DELAYS_KEY)? {
+            Some(content) => Ok(serde_json::from_str(&content)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let content = serde_json::to_string(self)?;
+        KeyMetadataStore::save_node_level(&content, DELAYS_KEY, &WriteOpts::Modify)
+    }
+
+    fn delay(&self, key_id: &str) -> Result<Duration> {
+        let hours = match self.delays_hours.get(key_id) {
+            Some(hours) => *hours as i64,
+            None => default_delay_hours()?,
+        };
+        Ok(Duration::hours(hours))
+    }
+
+    /// Records the request of a recovery once, later requests of the same session keep the time
+    /// of the first
+    fn request(
+        &mut self,
+        key_id: &str,
+        session_id: &str,
+        email: &str,
+        now: DateTime<Utc>
+    ) -> Result<PendingRecovery> {
+        self.pending.retain(|_, pending| {
+            now - pending.not_before <= Duration::days(PENDING_RETENTION_DAYS)
+        });
+        let delay = self.delay(key_id)?;
+        let pending = self.pending
+            .entry(session_id.to_string())
+            .or_insert_with(|| PendingRecovery {
+                key_id: key_id.to_string(),
+                session_id: session_id.to_string(),
+                email: email.to_string(),
+                requested_at: now,
+                not_before: now + delay,
+                cancelled: false,
+            });
+        if pending.key_id != key_id {
+            bail!("Recovery session {} was requested for another key", session_id);
+        }
+        Ok(pending.clone())
+    }
+
+    /// The request of a session, of which per-target sessions extend the id
+    fn find(&self, session_id: &str) -> Option<&PendingRecovery> {
+        self.pending.iter().find_map(|(requested, pending)| {
+            let matches =
+                session_id == requested ||
+                session_id
+                    .strip_prefix(requested.as_str())
+                    .is_some_and(|rest| rest.starts_with('-'));
+            if matches { Some(pending) } else { None }
+        })
+    }
+
+    fn check_elapsed(&self, key_id: &str, session_id: &str, now: DateTime<Utc>) -> Result<()> {
+        if self.delay(key_id)? == Duration::zero() {
+            return Ok(());
+        }
+        match self.find(session_id) {
+            Some(pending) if pending.key_id != key_id => {
+                bail!("Recovery session {} was requested for another key", session_id)
+            }
+            Some(pending) if pending.cancelled => {
+                bail!("Recovery session {} was cancelled by the owner", session_id)
+            }
+            Some(pending) if pending.not_before > now => {
+                bail!("Recovery of key {} is delayed until {}", key_id, pending.not_before)
+            }
+            Some(_) => Ok(()),
+            None => bail!("Recovery of key {} was not requested on this node", key_id),
+        }
+    }
+}
+
+fn default_delay_hours() -> Result<i64> {
+    match env::var(DEFAULT_DELAY_VAR) {
+        Ok(hours) => hours.parse().with_context(|| format!("{} is not a number", hours)),
+        Err(_) => Ok(0),
+    }
+}
+
+/// Records the request on this node and tells the owner about it, returns the request. The owner's
+/// clients also find the requests still waiting with `GetPendingRecoveriesCommand`.
+pub fn request_recovery(
+    nc: &blocking::Connection,
+    key_id: &str,
+    session_id: &str,
+    email: &str
+) -> Result<PendingRecovery> {
+    let _lock = DELAYS_LOCK.lock().unwrap();
+    let mut delays = RecoveryDelays::load()?;
+    let is_new = delays.find(session_id).is_none();
+    let pending = delays.request(key_id, session_id, email, Utc::now())?;
+    delays.save()?;
+    if is_new && pending.not_before > pending.requested_at {
+        info!("Recovery of key {} is delayed until {}", key_id, pending.not_before);
+        let notification = serde_json::to_string(&pending)?;
+        if let Err(err) = nc.publish(&format!("{}.{}", PENDING_SUBJECT, key_id), &notification) {
+            warn!("Failed to notify the owner of key {} of its recovery: {}", key_id, err);
+        }
+    }
+    Ok(pending)
+}
+
+/// Fails unless the delay of a recovery of the key passed on this node
+pub fn ensure_delay_elapsed(key_id: &str, session_id: &str) -> Result<()> {
+    RecoveryDelays::load()?.check_elapsed(key_id, session_id, Utc::now())
+}
+
+/// Records a recovery requested from another guardian of the key, so the delay also runs here
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RecordPendingRecoveryCommand {
+    pub key_id: String,
+    pub session_id: String,
+    pub email: String,
+}
+
+impl JsonCommand for RecordPendingRecoveryCommand {
+    type Response = PendingRecovery;
+
+    fn access(&self) -> Access<'_> {
+        Access::Peer
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let app = ctx.get_app()?;
+        request_recovery(&app.nc, &self.key_id, &self.session_id, &self.email)
+    }
+}
+
+/// Tells the other guardians about a recovery request
+pub fn forward_request(nc: &blocking::Connection, pending: &PendingRecovery, node_ids: &[NodeId]) {
+    let command = TaggedCommandType::RecordPendingRecovery(RecordPendingRecoveryCommand {
+        key_id: pending.key_id.clone(),
+        session_id: pending.session_id.clone(),
+        email: pending.email.clone(),
+    });
+    let forwarded = serde_json::to_string(&command).unwrap();
+    for node_id in node_ids {
+        let subject = format!("network.gridlock.nodes.async.Message.new.{}", node_id);
+        if let Err(err) = nc.publish(&subject, &forwarded) {
+            warn!("Failed to forward the recovery request to {}: {}", node_id, err);
+        }
+    }
+}
+
+/// Sets the recovery delay of a key on this node, sent by its owner to every guardian of the key
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SetRecoveryDelayCommand {
+    pub key_id: String,
+    pub delay_hours: u32,
+    pub authorization: TenantAuth,
+}
+
+impl JsonCommand for SetRecoveryDelayCommand {
+    type Response = ();
+
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: Some(&self.authorization), key_ids: vec![self.key_id.clone()] }
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let _lock = DELAYS_LOCK.lock().unwrap();
+        let mut delays = RecoveryDelays::load()?;
+        delays.delays_hours.insert(self.key_id.clone(), self.delay_hours);
+        delays.save()?;
+        info!("Recovery delay of key {} set to {} hours", self.key_id, self.delay_hours);
+        Ok(())
+    }
+}
+
+/// Cancels a pending recovery of a key, signed by its owner with the access key. The session is
+/// revoked as by `RevokeRecoverySessionCommand`, so a cancelled recovery can't be run later.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CancelRecoveryCommand {
+    pub key_id: String,
+    pub session_id: String,
+    pub authorization: TenantAuth,
+}
+
+impl JsonCommand for CancelRecoveryCommand {
+    type Response = ();
+
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: Some(&self.authorization), key_ids: vec![self.key_id.clone()] }
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let _lock = DELAYS_LOCK.lock().unwrap();
+        let mut delays = RecoveryDelays::load()?;
+        match delays.pending.get_mut(&self.session_id) {
+            Some(pending) if pending.key_id == self.key_id => {
+                pending.cancelled = true;
+            }
+            _ => bail!("No recovery of key {} in session {}", self.key_id, self.session_id),
+        }
+        delays.save()?;
+        RevokedRecoverySessions::revoke_now(&self.session_id)?;
+        info!("Owner cancelled recovery session {} of key {}", self.session_id, self.key_id);
+        Ok(())
+    }
+}
+
+/// Recoveries of the keys of an account waiting for their delay
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetPendingRecoveriesCommand {
+    pub authorization: TenantAuth,
+}
+
+impl JsonCommand for GetPendingRecoveriesCommand {
+    type Response = Vec<PendingRecovery>;
+
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: Some(&self.authorization), key_ids: Vec::new() }
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let now = Utc::now();
+        Ok(
+            RecoveryDelays::load()?
+                .pending.into_values()
+                .filter(|pending| pending.email == self.authorization.email)
+                .filter(|pending| !pending.cancelled && pending.not_before > now)
+                .collect()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovery_waits_for_the_delay_of_its_key() {
+        let now = Utc::now();
+        let mut delays = RecoveryDelays::default();
+        delays.delays_hours.insert("key".to_string(), 48);
+        delays.delays_hours.insert("undelayed".to_string(), 0);
+        assert!(delays.check_elapsed("undelayed", "session-0", now).is_ok());
+        assert!(delays.check_elapsed("key", "session-1", now).is_err());
+
+        let pending = delays.request("key", "session-1", "owner@example.com", now).unwrap();
+        assert_eq!(pending.not_before, now + Duration::hours(48));
+        let later = now + Duration::hours(1);
+        let retried = delays.request("key", "session-1", "owner@example.com", later).unwrap();
+        assert_eq!(retried, pending);
+        assert!(delays.request("undelayed", "session-1", "owner@example.com", now).is_err());
+
+        assert!(delays.check_elapsed("key", "session-1-2", now + Duration::hours(47)).is_err());
+        assert!(delays.check_elapsed("key", "session-1-2", now + Duration::hours(48)).is_ok());
+        delays.pending.get_mut("session-1").unwrap().cancelled = true;
+        assert!(delays.check_elapsed("key", "session-1", now + Duration::hours(49)).is_err());
+    }
+}
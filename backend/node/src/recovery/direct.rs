@@ -0,0 +1,229 @@
+use crate::command::{ JsonCommand, MsgContext, TaggedCommandType };
+use crate::communication::blocking;
+use crate::key_info::GetKeyInfoCommand;
+use crate::node::NodeIdentity;
+use crate::recovery::orchestrate::{ orchestrate_with_key_info, TargetDelivery, LEGACY_THRESHOLD };
+use crate::recovery::{ Key, RecoveryCommand, RecoveryResponse };
+use crate::storage::fs::WriteOpts;
+use crate::storage::KeyInfoStore;
+use crate::tenant::{ Access, TenantAuth };
+use anyhow::{ bail, Context, Result };
+use itertools::Itertools;
+use serde::{ Deserialize, Serialize };
+use shared::key_info::{ KeyInfo, NodeId };
+use std::time::Duration;
+use tracing::{ info, instrument };
+
+const KEY_INFO_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Recovery run by the user's new device without the hub: the key info comes from the guardian,
+/// the guardian, the user's previous device and as many other guardians as the key's threshold
+/// needs regenerate the lost share and the new device receives it directly
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DirectRecoveryCommand {
+    #[serde(flatten)]
+    kind: Key,
+    key_id: String,
+    session_id: String,
+    /// Guardian that provides the key info and helps regenerate the share
+    guardian_node_id: NodeId,
+    /// The user's previous device, still holding its own share
+    old_device_node_id: NodeId,
+    /// Further guardians helping to regenerate the share, threshold + 1 helpers are needed
+    #[serde(default)]
+    other_helper_node_ids: Vec<NodeId>,
+    /// Node whose share is taken over by this device
+    lost_node_id: NodeId,
+    email: String,
+    /// Proof of the account for guardians holding keys of several accounts, forwarded with the
+    /// key info request
+    #[serde(default)]
+    authorization: Option<TenantAuth>,
+}
+
+impl JsonCommand for DirectRecoveryCommand {
+    type Response = RecoveryResponse;
+
+    /// The guardian asked for the key info checks the forwarded proof, the helpers the recovery
+    /// rules
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        orchestrate_direct(self, ctx).map(|_| RecoveryResponse::Completed)
+    }
+}
+
+#[instrument(skip_all)]
+fn orchestrate_direct(cmd: DirectRecoveryCommand, ctx: MsgContext) -> Result<()> {
+    let app = ctx.get_app()?;
+    let node = NodeIdentity::cached()?;
+
+    let key_info = fetch_key_info(
+        &app.nc,
+        &cmd.guardian_node_id,
+        &cmd.key_id,
+        cmd.authorization.clone()
+    )?;
+    check_parties(&key_info, &cmd, key_info.threshold.unwrap_or(LEGACY_THRESHOLD))?;
+    info!("Starting direct recovery of key {} from this device", cmd.key_id);
+
+    let recovery = RecoveryCommand {
+        kind: cmd.kind,
+        key_id: cmd.key_id.clone(),
+        session_id: cmd.session_id,
+        new_node_id: NodeId::new_from_uuid(node.node_id),
+        new_node_public_key: node.networking_public_key,
+        old_node_id: cmd.lost_node_id,
+        party_nodes: helpers(&cmd),
+        email: cmd.email,
+        additional_targets: Vec::new(),
+    };
+    let key_info = orchestrate_with_key_info(
+        &app.nc,
+        recovery,
+        key_info,
+        LEGACY_THRESHOLD,
+        TargetDelivery::Local
+    )?;
+
+    // This device may not be subscribed to its own command subject, so the update is saved here
+    KeyInfoStore::save_key_info(&key_info, &cmd.key_id, &WriteOpts::Modify)
+}
+
+fn fetch_key_info(
+    nc: &blocking::Connection,
+    guardian: &NodeId,
+    key_id: &str,
+    authorization: Option<TenantAuth>
+) -> Result<KeyInfo> {
+    let request = serde_json::to_string(
+        &TaggedCommandType::GetKeyInfo(GetKeyInfoCommand {
+            key_id: key_id.to_string(),
+            authorization,
+        })
+    )?;
+    let subject = format!("network.gridlock.nodes.Message.new.{}", guardian);
+    let response = nc
+        .request_timeout(&subject, request, KEY_INFO_TIMEOUT)
+        .with_context(|| format!("Requesting key info from guardian {}", guardian))?;
+    let response = String::from_utf8(response.data)?;
+    if let Some(err) = response.strip_prefix("ERROR: ") {
+        bail!("Guardian {} could not provide key info: {}", guardian, err);
+    }
+    serde_json::from_str(&response).context("Deserialize key info from guardian")
+}
+
+fn helpers(cmd: &DirectRecoveryCommand) -> Vec<NodeId> {
+    let mut helpers = vec![cmd.old_device_node_id.clone(), cmd.guardian_node_id.clone()];
+    helpers.extend(cmd.other_helper_node_ids.iter().cloned());
+    helpers
+}
+
+/// The helpers hold shares of the key and are enough to regenerate one at `threshold`
+fn check_parties(key_info: &KeyInfo, cmd: &DirectRecoveryCommand, threshold: usize) -> Result<()> {
+    if cmd.guardian_node_id == cmd.old_device_node_id {
+        bail!("The guardian and the previous device have to be different nodes");
+    }
+    let helpers = helpers(cmd);
+    if !helpers.iter().all_unique() {
+        bail!("The same helper is listed more than once");
+    }
+    for helper in &helpers {
+        if helper == &cmd.lost_node_id {
+            bail!("Lost node {} cannot take part as a helper", helper);
+        }
+        if !key_info.node_pool.iter().any(|n| &n.node_id == helper) {
+            bail!("Node {} holds no share of key {}", helper, cmd.key_id);
+        }
+    }
+    if helpers.len() < threshold + 1 {
+        bail!(
+            "{} helpers can't regenerate a share of key {}, its threshold of {} needs {}",
+            helpers.len(),
+            cmd.key_id,
+            threshold,
+            threshold + 1
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::key_info::{ Node, NodeInfo };
+
+    fn node(id: &str, share_index: usize) -> NodeInfo {
+        NodeInfo {
+            node_id: NodeId::new(id.to_string()),
+            networking_public_key: String::new(),
+            kind: Node::Guardian,
+            share_index,
+        }
+    }
+
+    fn command(
+        guardian: &str,
+        old_device: &str,
+        others: &[&str],
+        lost: &str
+    ) -> DirectRecoveryCommand {
+        DirectRecoveryCommand {
+            kind: Key::EDDSA,
+            key_id: "key".to_string(),
+            session_id: "session".to_string(),
+            guardian_node_id: NodeId::new(guardian.to_string()),
+            old_device_node_id: NodeId::new(old_device.to_string()),
+            other_helper_node_ids: others
+                .iter()
+                .map(|id| NodeId::new(id.to_string()))
+                .collect(),
+            lost_node_id: NodeId::new(lost.to_string()),
+            email: "user@example.com".to_string(),
+            authorization: None,
+        }
+    }
+
+    fn key_info() -> KeyInfo {
+        KeyInfo {
+            kind: shared::key_info::Key::EDDSA { y_sum: String::new() },
+            node_pool: vec![
+                node("guardian", 1),
+                node("phone", 2),
+                node("lost", 3),
+                node("backup", 4),
+                node("partner", 5)
+            ],
+            metadata: None,
+            threshold: None,
+        }
+    }
+
+    #[test]
+    fn helpers_have_to_hold_shares_of_the_key() {
+        let key_info = key_info();
+        let check = |cmd| check_parties(&key_info, &cmd, 1);
+
+        assert!(check(command("guardian", "phone", &[], "lost")).is_ok());
+        assert!(check(command("guardian", "guardian", &[], "lost")).is_err());
+        assert!(check(command("guardian", "lost", &[], "lost")).is_err());
+        assert!(check(command("guardian", "tablet", &[], "lost")).is_err());
+        assert!(check(command("guardian", "phone", &["lost"], "lost")).is_err());
+        assert!(check(command("guardian", "phone", &["phone"], "lost")).is_err());
+    }
+
+    #[test]
+    fn threshold_plus_one_helpers_are_needed() {
+        let key_info = key_info();
+        let check = |cmd, threshold| check_parties(&key_info, &cmd, threshold);
+
+        // Keys are generated 3 of n, the guardian and the previous device alone can't help
+        assert!(check(command("guardian", "phone", &[], "lost"), LEGACY_THRESHOLD).is_err());
+        assert!(check(command("guardian", "phone", &["backup"], "lost"), LEGACY_THRESHOLD).is_ok());
+        assert!(check(command("guardian", "phone", &["backup"], "lost"), 3).is_err());
+        let helpers = command("guardian", "phone", &["backup", "partner"], "lost");
+        assert!(check(helpers, 3).is_ok());
+    }
+}
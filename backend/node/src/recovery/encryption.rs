@@ -1,14 +1,9 @@
-use crate::encryption::{
-    decrypt_and_deserialize,
-    serialize_and_encrypt,
-    shared_secret_from_nkeys,
-    shared_secrets_from_nkeys,
-};
+use crate::encryption::{ legacy_encryption_version, EnvelopeKeys };
 use anyhow::{ anyhow, Result };
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use shared::recovery::EncryptedData;
-use std::collections::HashMap;
+use std::collections::{ BTreeMap, HashMap };
 use tracing::info;
 
 pub trait HelperEncryptor {
@@ -75,8 +70,8 @@ impl TargetEncryptor for Plaintext {
 }
 
 pub struct NKeyHelperEncryptor {
-    peer_encryption_keys: Vec<Vec<u8>>,
-    target_encryption_key: Vec<u8>,
+    peer_encryption_keys: Vec<EnvelopeKeys>,
+    target_encryption_key: EnvelopeKeys,
 }
 
 impl NKeyHelperEncryptor {
@@ -85,25 +80,36 @@ impl NKeyHelperEncryptor {
         recovery_index: usize,
         own_index: usize,
         peers: &'a [usize],
+        encryption_versions: &'a BTreeMap<usize, u8>,
         private_key: String
     ) -> Result<Self> {
-        let peer_pks = peers
+        // Parties that did not advertise a version only open the legacy envelope
+        let keys_for = |index: usize, public_key: &str| {
+            let version = encryption_versions
+                .get(&index)
+                .copied()
+                .unwrap_or_else(legacy_encryption_version);
+            Ok::<_, anyhow::Error>(
+                EnvelopeKeys::from_nkeys(&private_key, public_key)?.with_peer_version(version)
+            )
+        };
+        let peer_encryption_keys = peers
             .iter()
             .filter(|&x| *x != own_index)
             .map(|i| {
-                public_keys
-                    .get(i).map(|s| s.to_owned())
+                let public_key = public_keys
+                    .get(i)
                     .ok_or_else(|| {
                         anyhow!("Could not find public key corresponding to node with keyshare {i}")
-                    })
+                    })?;
+                keys_for(*i, public_key)
             })
             .collect::<Result<Vec<_>>>()?;
+        info!("Created peer encryption keys");
         let target_pk = public_keys
-            .get(&recovery_index).map(|s| s.to_owned())
+            .get(&recovery_index)
             .ok_or_else(|| anyhow!("Could not find public key corresponding to the target node"))?;
-        let peer_encryption_keys = shared_secrets_from_nkeys(&private_key, &peer_pks)?;
-        info!("Created peer encryption keys");
-        let target_encryption_key = shared_secret_from_nkeys(&private_key, &target_pk)?;
+        let target_encryption_key = keys_for(recovery_index, target_pk)?;
         info!("Created target encryption key");
         Ok(Self {
             peer_encryption_keys,
@@ -119,23 +125,23 @@ impl HelperEncryptor for NKeyHelperEncryptor {
         inputs
             .iter()
             .enumerate()
-            .map(|(i, input)| serialize_and_encrypt(&input, &self.peer_encryption_keys[i]))
+            .map(|(i, input)| self.peer_encryption_keys[i].seal(&input))
             .collect()
     }
     fn decrypt_from_peers<T: DeserializeOwned>(&self, inputs: Vec<Self::Output>) -> Result<Vec<T>> {
         inputs
             .iter()
             .enumerate()
-            .map(|(i, input)| decrypt_and_deserialize(input, &self.peer_encryption_keys[i]))
+            .map(|(i, input)| self.peer_encryption_keys[i].open(input))
             .collect()
     }
     fn encrypt_for_target<T: Serialize>(&self, input: T) -> Result<Self::Output> {
-        serialize_and_encrypt(&input, &self.target_encryption_key)
+        self.target_encryption_key.seal(&input)
     }
 }
 
 pub struct NKeyTargetEncryptor {
-    helper_encryption_keys: Vec<Vec<u8>>,
+    helper_encryption_keys: Vec<EnvelopeKeys>,
 }
 
 impl NKeyTargetEncryptor {
@@ -154,7 +160,7 @@ impl NKeyTargetEncryptor {
                     })
             })
             .collect::<Result<Vec<_>>>()?;
-        let helper_encryption_keys = shared_secrets_from_nkeys(&private_key, &helper_pks)?;
+        let helper_encryption_keys = EnvelopeKeys::from_nkeys_many(&private_key, &helper_pks)?;
 
         Ok(Self {
             helper_encryption_keys,
@@ -172,7 +178,7 @@ impl TargetEncryptor for NKeyTargetEncryptor {
         inputs
             .iter()
             .enumerate()
-            .map(|(i, input)| decrypt_and_deserialize(input, &self.helper_encryption_keys[i]))
+            .map(|(i, input)| self.helper_encryption_keys[i].open(input))
             .collect()
     }
 }
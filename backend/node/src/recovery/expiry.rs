@@ -0,0 +1,206 @@
+use crate::command::{ JsonCommand, MsgContext, TaggedCommandType };
+use crate::config::{ Config, ConfigProvider };
+use crate::session_manager;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Result };
+use chrono::{ DateTime, Duration, Utc };
+use serde::{ Deserialize, Serialize };
+use shared::key_info::NodeId;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use tracing::{ info, warn };
+
+/// How long the target accepts a recovery package after a helper produced it
+const PACKAGE_TTL_MINS: i64 = 15;
+/// Tolerated difference between the clocks of the helpers and the target
+const CLOCK_SKEW_MINS: i64 = 2;
+const REVOKED_SESSIONS_FILE: &str = "revoked_recovery_sessions.json";
+/// Revocations are kept well past the expiry of any package they apply to
+const REVOCATION_RETENTION_DAYS: i64 = 7;
+
+/// Recovery package as encrypted by a helper for the target, bound to the session it was produced
+/// in and only valid until `expires_at`
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpiringPackage<T> {
+    pub session_id: String,
+    pub expires_at: DateTime<Utc>,
+    pub package: T,
+}
+
+impl<T> ExpiringPackage<T> {
+    pub fn new(session_id: &str, package: T, now: DateTime<Utc>) -> Self {
+        ExpiringPackage {
+            session_id: session_id.to_string(),
+            expires_at: now + Duration::minutes(PACKAGE_TTL_MINS),
+            package,
+        }
+    }
+
+    fn check(&self, now: DateTime<Utc>) -> Result<()> {
+        if self.expires_at < now {
+            bail!("Recovery package of session {} expired at {}", self.session_id, self.expires_at);
+        }
+        // A helper with a clock far ahead would otherwise produce packages that never expire
+        if self.expires_at > now + Duration::minutes(PACKAGE_TTL_MINS + CLOCK_SKEW_MINS) {
+            bail!("Recovery package of session {} expires too far ahead", self.session_id);
+        }
+        Ok(())
+    }
+}
+
+/// Unwraps the packages the target received, which all have to come from the same unexpired and
+/// unrevoked session
+pub fn open_packages<T>(packages: Vec<ExpiringPackage<T>>, now: DateTime<Utc>) -> Result<Vec<T>> {
+    let session_id = match packages.first() {
+        Some(first) => first.session_id.clone(),
+        None => bail!("No recovery packages were received"),
+    };
+    if packages.iter().any(|package| package.session_id != session_id) {
+        bail!("Recovery packages were produced in different sessions");
+    }
+    ensure_session_not_revoked(&session_id)?;
+    packages
+        .into_iter()
+        .map(|package| {
+            package.check(now)?;
+            Ok(package.package)
+        })
+        .collect()
+}
+
+/// Recovery sessions revoked on this node, with the time of their revocation
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct RevokedRecoverySessions {
+    sessions: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl RevokedRecoverySessions {
+    pub fn load() -> Result<Self> {
+        let path = revoked_sessions_path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::write(revoked_sessions_path(), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    fn revoke(&mut self, session_id: &str, now: DateTime<Utc>) {
+        self.sessions.retain(|_, revoked_at| {
+            now - *revoked_at <= Duration::days(REVOCATION_RETENTION_DAYS)
+        });
+        self.sessions.insert(session_id.to_string(), now);
+    }
+
+    /// Revokes the session on this node and stops it if it is running, returns how many running
+    /// sessions were stopped
+    pub fn revoke_now(session_id: &str) -> Result<usize> {
+        let mut revoked = Self::load()?;
+        revoked.revoke(session_id, Utc::now());
+        revoked.save()?;
+        Ok(session_manager::cancel_session(session_id))
+    }
+
+    /// Revoking a session also revokes the per-target sessions of a multi-target recovery, whose
+    /// ids extend the session id
+    pub fn is_revoked(&self, session_id: &str) -> bool {
+        self.sessions.keys().any(|revoked| {
+            session_id == revoked ||
+                session_id
+                    .strip_prefix(revoked.as_str())
+                    .is_some_and(|rest| rest.starts_with('-'))
+        })
+    }
+}
+
+/// Fails if the recovery session has been revoked. An unreadable list counts as a failure, as
+/// for the client key revocation list.
+pub fn ensure_session_not_revoked(session_id: &str) -> Result<()> {
+    let revoked = RevokedRecoverySessions::load().map_err(|err| {
+        warn!("Failed to read the revoked recovery sessions: {}", err);
+        anyhow!("Unable to check the recovery session against the revoked sessions")
+    })?;
+    if revoked.is_revoked(session_id) {
+        bail!("Recovery session {} has been revoked", session_id);
+    }
+    Ok(())
+}
+
+fn revoked_sessions_path() -> PathBuf {
+    let mut path = Config::get_gridlock_directory();
+    path.push(REVOKED_SESSIONS_FILE);
+    path
+}
+
+/// Aborts a recovery for good: the session is stopped if it is running, helpers won't produce
+/// packages for it and the target refuses its packages, even if they are replayed later. Sent to
+/// the orchestrator, which forwards it to `node_ids`.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RevokeRecoverySessionCommand {
+    pub session_id: String,
+    /// Helpers and target of the session
+    #[serde(default)]
+    pub node_ids: Vec<NodeId>,
+}
+
+impl JsonCommand for RevokeRecoverySessionCommand {
+    type Response = ();
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let cancelled = RevokedRecoverySessions::revoke_now(&self.session_id)?;
+        info!(
+            "Revoked recovery session {}, stopped {} running sessions",
+            self.session_id,
+            cancelled
+        );
+
+        if self.node_ids.is_empty() {
+            return Ok(());
+        }
+        let app = ctx.get_app()?;
+        let forwarded = serde_json::to_string(
+            &TaggedCommandType::RevokeRecoverySession(RevokeRecoverySessionCommand {
+                session_id: self.session_id.clone(),
+                node_ids: Vec::new(),
+            })
+        )?;
+        for node_id in &self.node_ids {
+            let subject = format!("network.gridlock.nodes.async.Message.new.{}", node_id);
+            app.nc.publish(&subject, &forwarded)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_expired_packages_and_revoked_sessions() {
+        let now = Utc::now();
+        let package = ExpiringPackage::new("session-1", 7u8, now);
+        assert!(package.check(now + Duration::minutes(PACKAGE_TTL_MINS - 1)).is_ok());
+        assert!(package.check(now + Duration::minutes(PACKAGE_TTL_MINS + 1)).is_err());
+        assert!(package.check(now - Duration::minutes(CLOCK_SKEW_MINS + 1)).is_err());
+
+        let mut revoked = RevokedRecoverySessions::default();
+        revoked.revoke("session-1", now);
+        assert!(revoked.is_revoked("session-1"));
+        assert!(revoked.is_revoked("session-1-3"));
+        assert!(!revoked.is_revoked("session-10"));
+
+        revoked.revoke("session-2", now + Duration::days(REVOCATION_RETENTION_DAYS + 1));
+        assert!(!revoked.is_revoked("session-1"));
+    }
+}
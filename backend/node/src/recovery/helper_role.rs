@@ -1,10 +1,18 @@
 use crate::communication::nats::PeerMessenger;
 use crate::communication::protocol::{ AllRounds, KeyShareRegenAllRounds };
 use crate::recovery::encryption::HelperEncryptor;
-use crate::recovery::{ ECDSARecoveryPackage, EdDSARecoveryPackage, Party, ShareRecoveryInfo };
-use crate::storage::{ KeyshareAccessor, ECDSA, EDDSA };
+use crate::recovery::expiry::{ ensure_session_not_revoked, ExpiringPackage };
+use crate::recovery::{
+    BLSRecoveryPackage,
+    ECDSARecoveryPackage,
+    EdDSARecoveryPackage,
+    Party,
+    ShareRecoveryInfo,
+};
+use crate::storage::{ KeyshareAccessor, BLS, ECDSA, EDDSA };
 use anyhow::Result;
-use curv::elliptic::curves::{ Curve, Ed25519, Scalar, Secp256k1 };
+use chrono::Utc;
+use curv::elliptic::curves::{ Bls12_381_1, Curve, Ed25519, Scalar, Secp256k1 };
 use itertools::Itertools;
 use serde::Serialize;
 use tracing::info;
@@ -33,11 +41,21 @@ impl<M, E, K> KeyshareRecoveryHelper<M, E, K>
         }
     }
 
-    pub fn try_recovery(&mut self, recovery_index: usize, party: Party) -> Result<()> {
-        self.send_recovery_package(recovery_index, party)
+    pub async fn try_recovery(
+        &mut self,
+        session_id: &str,
+        recovery_index: usize,
+        party: Party
+    ) -> Result<()> {
+        self.send_recovery_package(session_id, recovery_index, party).await
     }
 
-    fn send_recovery_package(&mut self, recovery_index: usize, party: Party) -> Result<()> {
+    async fn send_recovery_package(
+        &mut self,
+        session_id: &str,
+        recovery_index: usize,
+        party: Party
+    ) -> Result<()> {
         info!("Starting recovery process as a helper node");
         let recovery = self.key.get_recovery_params(recovery_index, party);
         let contrib = recovery.create_secret_sharing_of_lost_share();
@@ -47,29 +65,32 @@ impl<M, E, K> KeyshareRecoveryHelper<M, E, K>
         let received_shares = self.messenger.send_p2p_and_collect_messages(
             &<KeyShareRegenAllRounds as AllRounds>::P2PRound::ExchangePartShares,
             encrypted_shares
-        )?;
+        ).await?;
 
         let decrypted_shares = self.encryptor.decrypt_from_peers(received_shares)?;
         info!("Decrypted secret shares");
         let partial_share = recovery.sum_secret_shares(contrib.retained, decrypted_shares);
 
-        let recovery_package = self.package_result(partial_share)?;
+        // The session may have been revoked while the shares were exchanged
+        ensure_session_not_revoked(session_id)?;
+        let recovery_package = self.package_result(session_id, partial_share)?;
 
         self.messenger.broadcast_message(
             &<KeyShareRegenAllRounds as AllRounds>::BroadcastRound::DeliverRecoveryPackage,
             recovery_package
-        )?;
+        ).await?;
         info!("Sent a recovery package");
         Ok(())
     }
 
     pub fn package_result(
         &self,
+        session_id: &str,
         secret_share: Scalar<K::Curve>
     ) -> Result<<E as HelperEncryptor>::Output> {
         let result = self.key.create_recovery_result(secret_share);
         info!("Encrypting recovery packages");
-        self.encryptor.encrypt_for_target(result)
+        self.encryptor.encrypt_for_target(ExpiringPackage::new(session_id, result, Utc::now()))
     }
 }
 
@@ -177,3 +198,41 @@ impl KeyshareBehaviourHelperRole for ECDSABehaviourHelperRole {
         }
     }
 }
+
+/// BLS specific behaviour for keyshare recovery by a helper guardian
+pub struct BLSBehaviourHelperRole {
+    key_accessor: KeyshareAccessor<BLS>,
+}
+
+impl BLSBehaviourHelperRole {
+    pub fn from_key_accessor(key_accessor: KeyshareAccessor<BLS>) -> Self {
+        Self { key_accessor }
+    }
+}
+
+impl KeyshareBehaviourHelperRole for BLSBehaviourHelperRole {
+    type Curve = Bls12_381_1;
+    type RecoveryPackage = BLSRecoveryPackage;
+
+    fn create_recovery_result(&self, result: Scalar<Self::Curve>) -> Self::RecoveryPackage {
+        BLSRecoveryPackage {
+            share_recovery_info: ShareRecoveryInfo {
+                partial_secret: result,
+                vss_vec: self.key_accessor.key.vss_scheme_vec.clone(),
+            },
+        }
+    }
+
+    fn get_recovery_params(
+        &self,
+        recovery_index: usize,
+        party: Party
+    ) -> RecoveryCalculator<Self::Curve> {
+        RecoveryCalculator::<Self::Curve> {
+            secret_share: self.key_accessor.key.x_i.clone(),
+            threshold: self.key_accessor.key.threshold,
+            party,
+            recovery_index,
+        }
+    }
+}
@@ -1,21 +1,36 @@
 mod calculator;
 mod commands;
+pub mod delay;
+pub mod direct;
 mod encryption;
+mod expiry;
 mod helper_role;
 pub mod orchestrate;
 pub mod recovery_session;
+mod replace;
 mod target_role;
 
 use crate::command::{ JsonCommand, MsgContext };
 use crate::recovery::orchestrate::orchestrate;
 use crate::storage::KeyshareAccessor;
 use crate::storage::ECDSA;
+use crate::tenant::Access;
 use anyhow::{ anyhow, Result };
+use chrono::{ DateTime, Utc };
 pub use calculator::RecoveryCalculator;
 pub use commands::GetPaillierKeysCommand;
+pub use delay::{
+    CancelRecoveryCommand,
+    GetPendingRecoveriesCommand,
+    RecordPendingRecoveryCommand,
+    SetRecoveryDelayCommand,
+};
+pub use direct::DirectRecoveryCommand;
+pub use expiry::RevokeRecoverySessionCommand;
+pub use replace::ReplaceGuardianCommand;
 use curv::arithmetic::Zero;
 use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
-use curv::elliptic::curves::{ Curve, Ed25519, Point, Scalar, Secp256k1 };
+use curv::elliptic::curves::{ Bls12_381_1, Curve, Ed25519, Point, Scalar, Secp256k1 };
 use curv::BigInt;
 use derive_more::Display;
 use itertools::Itertools;
@@ -24,7 +39,7 @@ use serde::{ Deserialize, Serialize };
 use shared::key_info::NodeId;
 use shared::recovery::Key;
 use std::collections::HashMap;
-use zk_paillier::zkproofs::DLogStatement;
+use zk_paillier::zkproofs::{ DLogStatement, NiCorrectKeyProof };
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct RecoveryCommand {
@@ -37,12 +52,44 @@ pub struct RecoveryCommand {
     old_node_id: NodeId,
     party_nodes: Vec<NodeId>,
     email: String,
+    /// Further lost nodes to replace in the same session, their shares are regenerated by the
+    /// same helpers and key info is updated once for all of them
+    #[serde(default)]
+    additional_targets: Vec<RecoveryTarget>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RecoveryTarget {
+    new_node_id: NodeId,
+    new_node_public_key: String,
+    old_node_id: NodeId,
 }
 
 impl JsonCommand for RecoveryCommand {
     type Response = RecoveryResponse;
 
+    /// The helpers check the recovery rules of the key when the sessions start
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
     fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        // Keys with a recovery delay are only recovered when the orchestrator retries the
+        // command once the delay passed, the first attempt records the request everywhere
+        let app = ctx.get_app()?;
+        let pending = delay::request_recovery(
+            &app.nc,
+            &self.key_id,
+            &self.session_id,
+            &self.email
+        )?;
+        if pending.cancelled {
+            return Err(anyhow!("Recovery session {} was cancelled by the owner", self.session_id));
+        }
+        if pending.not_before > Utc::now() {
+            delay::forward_request(&app.nc, &pending, &self.party_nodes);
+            return Ok(RecoveryResponse::Pending { not_before: pending.not_before });
+        }
         orchestrate(self, ctx).map(|_| RecoveryResponse::Completed)
     }
 }
@@ -50,6 +97,10 @@ impl JsonCommand for RecoveryCommand {
 #[derive(Serialize)]
 pub enum RecoveryResponse {
     Completed,
+    /// The key has a recovery delay, the command has to be sent again after `not_before`
+    Pending {
+        not_before: DateTime<Utc>,
+    },
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -68,6 +119,11 @@ pub struct EdDSARecoveryPackage {
     pub share_recovery_info: ShareRecoveryInfo<Ed25519>,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BLSRecoveryPackage {
+    pub share_recovery_info: ShareRecoveryInfo<Bls12_381_1>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ECDSARecoveryPackage {
     pub share_recovery_info: ShareRecoveryInfo<Secp256k1>,
@@ -102,8 +158,11 @@ impl RecoveryValidationResult {
         RecoveryValidationResult::EDDSA(ValidatedResult::Validated)
     }
 
-    pub fn validated_with_eks(eks: EncryptionKey) -> RecoveryValidationResult {
-        RecoveryValidationResult::ECDSA(ValidatedWithEksResult::Validated(eks))
+    pub fn validated_with_eks(
+        ek: EncryptionKey,
+        proof: NiCorrectKeyProof
+    ) -> RecoveryValidationResult {
+        RecoveryValidationResult::ECDSA(ValidatedWithEksResult::Proven { ek, proof })
     }
 
     pub fn error(err: String) -> RecoveryValidationResult {
@@ -113,13 +172,19 @@ impl RecoveryValidationResult {
 
 #[derive(Serialize, Clone, Deserialize, Debug)]
 pub enum ValidatedWithEksResult {
+    /// Sent by targets that don't prove their new Paillier key, refused by the orchestrator
     Validated(EncryptionKey),
+    Proven {
+        ek: EncryptionKey,
+        proof: NiCorrectKeyProof,
+    },
 }
 
 impl ValidatedWithEksResult {
-    pub fn eks(self) -> EncryptionKey {
+    pub fn eks(self) -> (EncryptionKey, Option<NiCorrectKeyProof>) {
         match self {
-            ValidatedWithEksResult::Validated(eks) => eks,
+            ValidatedWithEksResult::Validated(ek) => (ek, None),
+            ValidatedWithEksResult::Proven { ek, proof } => (ek, Some(proof)),
         }
     }
 }
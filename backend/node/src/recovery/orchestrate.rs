@@ -1,27 +1,83 @@
 use crate::command::MsgContext;
+use crate::communication::blocking;
+use crate::communication::envelope;
 use crate::communication::nats::{ BroadcastMessage, JoinMessage, JoinResponse };
+use crate::encryption::ENVELOPE_V2_X25519;
+use crate::key_info_repair::repair_key_info;
+use crate::node::NodeIdentity;
+use crate::recovery::commands::receive_recovery_packages;
+use crate::recovery::expiry::ensure_session_not_revoked;
 use crate::recovery::recovery_session::NewKeyShareRecoverySession;
-use crate::recovery::{ Key, NodeId, RecoveryCommand, RecoveryRole, RecoveryValidationResult };
+use crate::recovery::{
+    Key,
+    NodeId,
+    RecoveryCommand,
+    RecoveryRole,
+    RecoveryTarget,
+    RecoveryValidationResult,
+};
+use crate::replay;
+use crate::security::verify_paillier_key;
 use crate::storage::KeyInfoStore;
 use anyhow::{ anyhow, bail, Context, Result };
+use chrono::Utc;
+use itertools::Itertools;
+use paillier::EncryptionKey;
+use serde::Serialize;
 use shared::recovery::{
     EncryptedData,
     PublicKeysEnum,
     ReceiveRecoveryPackages,
     RecoveryPackageInfo,
     UpdatePaillierKeysCommand,
+    UpdateSinglePaillierKeyCommand,
 };
 
 use shared::key_info::{ KeyInfo, NodeInfo, UpdateKeyInfoCommand };
+use std::collections::BTreeMap;
 use tracing::{ error, info, instrument };
+use zk_paillier::zkproofs::NiCorrectKeyProof;
+
+/// Threshold of keys generated before key info recorded it
+pub(crate) static LEGACY_THRESHOLD: usize = 2;
 
-static THRESHOLD: usize = 2;
+/// Where the recovery packages for a target are sent
+pub enum TargetDelivery {
+    /// To the target node over NATS
+    Nats,
+    /// Processed by this node, which is the target itself
+    Local,
+}
 
 #[instrument(skip_all)]
 pub fn orchestrate(cmd: RecoveryCommand, ctx: MsgContext) -> Result<()> {
     let app = ctx.get_app()?;
-    let nc = app.nc;
 
+    // Key info missing on this node is fetched from the helpers, which hold it too
+    let key_info = KeyInfoStore::get_key_info(&cmd.key_id)
+        .or_else(|_| repair_key_info(&app.nc, &cmd.key_id, &cmd.party_nodes))
+        .map_err(|err| {
+            let msg = format!("Key info is not found - key_id: {}", &cmd.key_id);
+            error!("{}: {}", &msg, err);
+            anyhow!("{msg}\n
+            Try node that has information about the key")
+        })?;
+
+    orchestrate_with_key_info(&app.nc, cmd, key_info, LEGACY_THRESHOLD, TargetDelivery::Nats)?;
+    Ok(())
+}
+
+/// Runs a recovery with the given key info, returning the key info updated with the new nodes.
+/// Shares are regenerated with the threshold in the key info, `legacy_threshold` for keys whose
+/// key info doesn't record one.
+pub fn orchestrate_with_key_info(
+    nc: &blocking::Connection,
+    cmd: RecoveryCommand,
+    key_info: KeyInfo,
+    legacy_threshold: usize,
+    delivery: TargetDelivery
+) -> Result<KeyInfo> {
+    let threshold = key_info.threshold.unwrap_or(legacy_threshold);
     let RecoveryCommand {
         kind,
         key_id,
@@ -31,61 +87,227 @@ pub fn orchestrate(cmd: RecoveryCommand, ctx: MsgContext) -> Result<()> {
         old_node_id,
         party_nodes,
         email,
+        additional_targets,
     } = cmd;
 
-    let key_info = KeyInfoStore::get_key_info(&key_id).map_err(|_| {
-        let msg = format!("Key info is not found - key_id: {}", &key_id);
-        error!("{}", &msg);
-        anyhow!("{msg}\n
-            Try node that has information about the key")
-    })?;
+    let mut targets = vec![RecoveryTarget {
+        new_node_id,
+        new_node_public_key,
+        old_node_id,
+    }];
+    targets.extend(additional_targets);
+    ensure_session_not_revoked(&session_id)?;
 
-    let recovery_share_index = key_info.node_pool
-        .iter()
-        .find(|&n| n.node_id == old_node_id)
-        .ok_or_else(|| {
-            let msg = format!("Old node id was not found - old_node_id: {old_node_id}");
-            error!("{}", &msg);
-            anyhow!("{}", &msg)
-        })?.share_index;
+    // At least threshold + 1 shares have to survive to regenerate the lost ones
+    let max_targets = key_info.node_pool.len().saturating_sub(threshold + 1);
+    if targets.len() > max_targets {
+        bail!(
+            "Cannot recover {} keyshares in one session, at most {} of {} can be regenerated",
+            targets.len(),
+            max_targets,
+            key_info.node_pool.len()
+        );
+    }
+    if !targets.iter().map(|t| &t.old_node_id).all_unique() {
+        bail!("The same old node is listed more than once");
+    }
+    if let Some(target) = targets.iter().find(|t| party_nodes.contains(&t.old_node_id)) {
+        bail!("Lost node {} cannot take part as a helper", target.old_node_id);
+    }
 
-    let key_info = enrich_key_info(key_info, &new_node_id, &new_node_public_key, &old_node_id);
+    let mut recovery_indices = Vec::new();
+    for target in &targets {
+        let share_index = key_info.node_pool
+            .iter()
+            .find(|&n| n.node_id == target.old_node_id)
+            .ok_or_else(|| {
+                let msg = format!("Old node id was not found - old_node_id: {}", target.old_node_id);
+                error!("{}", &msg);
+                anyhow!("{}", &msg)
+            })?.share_index;
+        recovery_indices.push(share_index);
+    }
+
+    // Helpers encrypt to every target of the session, so they get all the new nodes up front. The
+    // key info itself only changes as targets are recovered.
+    let session_key_info = targets
+        .iter()
+        .fold(key_info.clone(), |key_info, target| {
+            enrich_key_info(
+                key_info,
+                &target.new_node_id,
+                &target.new_node_public_key,
+                &target.old_node_id
+            )
+        });
 
     // Reorder public keys to be in order of the share index they hold
     let mut rearranged_keys = Vec::new();
-    for node in &key_info.node_pool {
+    for node in &session_key_info.node_pool {
         rearranged_keys.push((node.share_index, node.networking_public_key.clone()));
     }
 
+    let multi_target = targets.len() > 1;
     let helper_message = NewKeyShareRecoverySession {
         key_id: key_id.to_string(),
         session_id: session_id.to_string(),
         kind: kind.clone(),
-        threshold: THRESHOLD,
-        recovery_index: recovery_share_index,
+        threshold,
+        recovery_index: recovery_indices[0],
+        recovery_indices: if multi_target { recovery_indices.clone() } else { Vec::new() },
         public_keys: PublicKeysEnum::Map(rearranged_keys.clone()),
         role: RecoveryRole::Helper,
         email: Some(email.clone()),
+        timestamp: Some(Utc::now().to_rfc3339()),
+        origin: None,
     };
 
-    let join_key = format!("network.gridlock.nodes.KeyShareRecovery.{}.Join", &session_id);
-    let join_sub = nc.subscribe(&join_key)?;
-
-    let package_key = format!(
-        "network.gridlock.nodes.KeyShareRecovery.{}.DeliverRecoveryPackage",
-        &session_id
-    );
-    let package_sub = nc.subscribe(&package_key)?;
+    // Subscribe to every target's subjects before helpers are told to start, they move on to the
+    // next target as soon as they are done with the previous one
+    let mut target_sessions = Vec::new();
+    for &recovery_index in &recovery_indices {
+        let target_session_id = if multi_target {
+            target_session_id(&session_id, recovery_index)
+        } else {
+            session_id.clone()
+        };
+        let join_key = format!("network.gridlock.nodes.KeyShareRecovery.{}.Join", &target_session_id);
+        let package_key = format!(
+            "network.gridlock.nodes.KeyShareRecovery.{}.DeliverRecoveryPackage",
+            &target_session_id
+        );
+        target_sessions.push((nc.subscribe(&join_key)?, nc.subscribe(&package_key)?));
+    }
 
-    let recovery_new_helper_message = serde_json::to_string(&helper_message)?;
+    let recovery_new_helper_message = replay::sign_session_message(
+        &helper_message,
+        &NodeIdentity::cached()?
+    )?;
 
     for node_id in &party_nodes {
         let recovery_new_key = format!("network.gridlock.nodes.KeyShareRecovery.new.{node_id}");
         nc.publish(&recovery_new_key, &recovery_new_helper_message)?;
     }
 
+    // Committed target by target, a target failing leaves the shares recovered before it in use
+    let mut key_info = key_info;
+    let mut recovered_eks = Vec::new();
+    for ((target, &recovery_index), (join_sub, package_sub)) in targets
+        .iter()
+        .zip(&recovery_indices)
+        .zip(&target_sessions) {
+        info!("Recovering keyshare - recovery_index: {}", recovery_index);
+        let eks = recover_target(
+            nc,
+            &session_id,
+            &kind,
+            &key_id,
+            recovery_index,
+            threshold,
+            &rearranged_keys,
+            party_nodes.len(),
+            join_sub,
+            package_sub,
+            &target.new_node_id,
+            &delivery,
+            false
+        ).with_context(|| {
+            format!(
+                "Recovery stopped after {} of {} keyshares",
+                recovery_indices.iter().position(|&i| i == recovery_index).unwrap_or_default(),
+                targets.len()
+            )
+        })?;
+        key_info = enrich_key_info(
+            key_info,
+            &target.new_node_id,
+            &target.new_node_public_key,
+            &target.old_node_id
+        );
+
+        if let Some((new_ek, proof)) = eks {
+            info!("Updating paillier keys");
+            if multi_target {
+                let update = UpdateSinglePaillierKeyCommand {
+                    key_id: key_id.to_string(),
+                    new_ek,
+                    index: recovery_index,
+                    new_ek_proof: Some(proof),
+                };
+                let node_ids_to_update = key_info.node_pool
+                    .iter()
+                    .filter(|n| n.share_index != recovery_index)
+                    .map(|n| &n.node_id);
+                publish_async(nc, node_ids_to_update, &update)?;
+
+                // The recovered node also needs the keys of the shares recovered before it
+                for update in &recovered_eks {
+                    publish_async(nc, [&target.new_node_id], update)?;
+                }
+                recovered_eks.push(update);
+            } else {
+                let update = UpdatePaillierKeysCommand {
+                    key_id: key_id.to_string(),
+                    new_eks: vec![new_ek],
+                    new_ek_proofs: vec![proof],
+                };
+                let node_ids_to_update = party_nodes
+                    .iter()
+                    .filter(|&node_id| *node_id != target.old_node_id);
+                publish_async(nc, node_ids_to_update, &update)?;
+            }
+            info!("Paillier keys updated");
+        }
+
+        info!("Publishing key info updates");
+        let update = UpdateKeyInfoCommand {
+            key_id: key_id.to_string(),
+            key_info: key_info.clone(),
+        };
+        publish_async(nc, key_info.node_pool.iter().map(|n| &n.node_id), &update)?;
+        info!("Key info updated");
+    }
+
+    Ok(key_info)
+}
+
+/// Sends a command to every node over their async message subjects
+fn publish_async<'a, T: Serialize>(
+    nc: &blocking::Connection,
+    node_ids: impl IntoIterator<Item = &'a NodeId>,
+    command: &T
+) -> Result<()> {
+    let msg = serde_json::to_string(command)?;
+    for node_id in node_ids {
+        nc.publish(&format!("network.gridlock.nodes.async.Message.new.{node_id}"), &msg)?;
+    }
+    Ok(())
+}
+
+/// Session id helpers and the orchestrator use for one target of a multi-target recovery
+pub fn target_session_id(session_id: &str, recovery_index: usize) -> String {
+    format!("{}-{}", session_id, recovery_index)
+}
+
+/// Runs the recovery of a single keyshare: waits for helpers to join, gathers their packages and
+/// hands them to the target. Returns the recovered paillier key and its proof for ECDSA keys.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn recover_target(
+    nc: &blocking::Connection,
+    session_id: &str,
+    kind: &Key,
+    key_id: &str,
+    recovery_index: usize,
+    threshold: usize,
+    rearranged_keys: &[(usize, String)],
+    party_count: usize,
+    join_sub: &blocking::Subscription,
+    package_sub: &blocking::Subscription,
+    new_node_id: &NodeId,
+    delivery: &TargetDelivery,
+    ghost: bool
+) -> Result<Option<(EncryptionKey, NiCorrectKeyProof)>> {
     let mut join_msgs = Vec::new();
-    let party_count = party_nodes.len();
     for _ in 0..party_count {
         let join_msg = join_sub.next().context("Waiting for parties to join")?;
         join_msgs.push(join_msg);
@@ -96,16 +318,29 @@ pub fn orchestrate(cmd: RecoveryCommand, ctx: MsgContext) -> Result<()> {
     }
 
     let mut share_indices = Vec::new();
+    let mut envelope_versions = Vec::new();
+    let mut encryption_versions = BTreeMap::new();
     for m in join_msgs.iter() {
         let confirmation = serde_json::from_slice::<JoinMessage>(&m.data)?;
         share_indices.push(confirmation.party_index);
+        envelope_versions.push(confirmation.envelope_version);
+        encryption_versions.insert(confirmation.party_index, confirmation.encryption_version);
+    }
+    // The target doesn't join, packages are only sealed with the current envelope when it is
+    // this node
+    if matches!(delivery, TargetDelivery::Local) {
+        encryption_versions.insert(recovery_index, ENVELOPE_V2_X25519);
     }
     share_indices.sort();
 
     info!("Parties joined to recovery orchestration - share_indices: {:?}", &share_indices);
+    // Recovery packages are encrypted to the target on their own
     let join_resp = JoinResponse {
         party_count: share_indices.len(),
         all_party_indices: share_indices.clone(),
+        networking_public_keys: BTreeMap::new(),
+        envelope_version: envelope::negotiate(envelope_versions),
+        encryption_versions,
     };
     for m in &join_msgs {
         m.respond(&serde_json::to_string(&join_resp)?)?;
@@ -115,8 +350,8 @@ pub fn orchestrate(cmd: RecoveryCommand, ctx: MsgContext) -> Result<()> {
     // Gather regeneration packages
     let mut encrypted_packages = Vec::new();
     for _ in 0..party_count {
-        let m = package_sub.next().unwrap();
-        let resp = serde_json::from_slice::<BroadcastMessage<EncryptedData>>(&m.data).unwrap();
+        let m = package_sub.next().context("Waiting for recovery packages")?;
+        let resp = envelope::decode::<BroadcastMessage<EncryptedData>>(&m.data)?;
 
         encrypted_packages.push(resp);
     }
@@ -128,78 +363,50 @@ pub fn orchestrate(cmd: RecoveryCommand, ctx: MsgContext) -> Result<()> {
         .map(|x| x.message.clone())
         .collect();
 
+    // A revocation that arrived while the helpers worked stops the packages from being delivered
+    ensure_session_not_revoked(session_id)?;
     let message = ReceiveRecoveryPackages {
         recovery_info: RecoveryPackageInfo {
             key_id: key_id.to_string(),
-            recovery_index: recovery_share_index,
-            threshold: THRESHOLD,
+            recovery_index,
+            threshold,
             peers: share_indices.clone(),
-            public_keys: PublicKeysEnum::Map(rearranged_keys.clone()),
+            public_keys: PublicKeysEnum::Map(rearranged_keys.to_vec()),
             encrypted_packages,
+            ghost,
         },
         kind: kind.clone(),
     };
-    let msg = serde_json::to_string(&message)?;
-    let message_new_key = format!("network.gridlock.nodes.async.Message.new.{new_node_id}");
-    let res = nc.request(&message_new_key, msg)?;
+    let validation_msg = match delivery {
+        TargetDelivery::Nats => {
+            let msg = serde_json::to_string(&message)?;
+            let message_new_key = format!("network.gridlock.nodes.async.Message.new.{new_node_id}");
+            let res = nc.request(&message_new_key, msg)?;
+            serde_json::from_slice::<RecoveryValidationResult>(&res.data)?
+        }
+        TargetDelivery::Local => receive_recovery_packages(message)?,
+    };
     info!("Validating recovery result");
-    match kind {
-        Key::EDDSA | Key::Sr25519 => {
-            let validation_msg = serde_json::from_slice::<RecoveryValidationResult>(&res.data)?;
-            match validation_msg {
-                RecoveryValidationResult::EDDSA(_) => {
-                    info!("{} recovery validated", kind);
-                }
-                RecoveryValidationResult::Error(err) => {
-                    bail!("{}", err);
-                }
-                _ => {
-                    bail!("Wrong validation result");
-                }
-            }
+    match (kind, validation_msg) {
+        (Key::EDDSA | Key::Sr25519 | Key::BLS, RecoveryValidationResult::EDDSA(_)) => {
+            info!("{} recovery validated", kind);
+            Ok(None)
         }
-        Key::ECDSA => {
-            let validation_msg = serde_json::from_slice::<RecoveryValidationResult>(&res.data)?;
-            if let RecoveryValidationResult::ECDSA(res) = validation_msg {
-                info!("ECDSA recovery validated");
-
-                info!("Updating paillier keys");
-                let update = UpdatePaillierKeysCommand {
-                    key_id: key_id.to_string(),
-                    new_eks: vec![res.eks()],
-                };
-
-                let node_ids_to_update = party_nodes
-                    .iter()
-                    .filter(|&node_id| *node_id != old_node_id);
-
-                for node_id in node_ids_to_update {
-                    let message_new_key = format!(
-                        "network.gridlock.nodes.async.Message.new.{node_id}"
-                    );
-                    let msg = serde_json::to_string(&update)?;
-                    nc.publish(&message_new_key, msg)?;
-                }
-                info!("Paillier keys updated");
-            }
+        (Key::ECDSA, RecoveryValidationResult::ECDSA(res)) => {
+            info!("ECDSA recovery validated");
+            let (ek, proof) = res.eks();
+            verify_paillier_key(&ek, proof.as_ref()).with_context(|| {
+                format!("Paillier key of recovered share {} is refused", recovery_index)
+            })?;
+            Ok(proof.map(|proof| (ek, proof)))
+        }
+        (_, RecoveryValidationResult::Error(err)) => {
+            bail!("{}", err);
+        }
+        _ => {
+            bail!("Wrong validation result");
         }
     }
-
-    info!("Publishing key info updates");
-    for node in &key_info.node_pool {
-        nc.publish(
-            &format!("network.gridlock.nodes.async.Message.new.{}", node.node_id),
-            &serde_json::to_string(
-                &(UpdateKeyInfoCommand {
-                    key_id: key_id.to_string(),
-                    key_info: key_info.clone(),
-                })
-            )?
-        )?;
-    }
-    info!("Key info updated");
-
-    Ok(())
 }
 
 /// Enrich key info with new recovery node id and public key
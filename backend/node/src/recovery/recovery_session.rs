@@ -1,27 +1,39 @@
+use crate::audit::{ AuditAction, AuditedRequest };
+use crate::communication::incoming::IncomingMessage;
 use crate::communication::nats_session::Nats;
 use crate::communication::protocol::Topic;
 use crate::config::ConfigProvider;
+use crate::metrics::{ self, SessionKind };
 use crate::node::NodeIdentity;
 use crate::recovery::encryption::{ NKeyHelperEncryptor, NKeyTargetEncryptor };
+use crate::recovery::delay;
+use crate::recovery::expiry::ensure_session_not_revoked;
 use crate::recovery::helper_role::{
+    BLSBehaviourHelperRole,
     ECDSABehaviourHelperRole,
     EdDSABehaviourHelperRole,
     KeyshareRecoveryHelper,
 };
+use crate::recovery::orchestrate::target_session_id;
 use crate::recovery::target_role::{
+    BLSBehaviourTargetRole,
     ECDSABehaviourTargetRole,
     EdDSABehaviourTargetRole,
     KeyshareRecoveryTarget,
     Sr25519BehaviourTargetRole,
 };
 use crate::recovery::{ Key, Party, RecoveryRole };
-use crate::storage::{ KeyshareAccessor, ECDSA, EDDSA };
+use crate::replay::GuardianOrigin;
+use crate::storage::{ KeyshareAccessor, BLS, ECDSA, EDDSA };
 use crate::App;
+use crate::session_error::{ self, SessionErrorCode };
+use crate::session_manager;
+use crate::slo;
+use crate::strict;
 use anyhow::{ anyhow, bail, Result };
 use serde::{ Deserialize, Serialize };
 use shared::recovery::PublicKeysEnum;
 use std::collections::HashMap;
-use std::thread;
 use tracing::{ error, info };
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -31,15 +43,37 @@ pub struct NewKeyShareRecoverySession {
     pub key_id: String,
     pub session_id: String,
     pub recovery_index: usize,
+    /// Set when several keyshares are recovered in one session, helpers then produce packages
+    /// for each index in turn and `recovery_index` is ignored
+    #[serde(default)]
+    pub recovery_indices: Vec<usize>,
     pub threshold: usize,
     pub public_keys: PublicKeysEnum,
     pub role: RecoveryRole,
     #[serde(default)]
     pub email: Option<String>,
+    /// When the orchestrator sent the session, checked against the replay window, see `replay`
+    #[serde(default)]
+    pub timestamp: Option<String>,
+    /// Signature of the orchestrating guardian, see `replay::sign_session_message`
+    #[serde(default)]
+    pub origin: Option<GuardianOrigin>,
 }
 
 impl NewKeyShareRecoverySession {
-    pub fn handle(&self, conn: nats::Connection) -> Result<()> {
+    pub async fn handle(&self, conn: async_nats::Client) -> Result<()> {
+        ensure_session_not_revoked(&self.session_id)?;
+        if matches!(self.role, RecoveryRole::Helper) {
+            delay::ensure_delay_elapsed(&self.key_id, &self.session_id)?;
+        }
+        if !self.recovery_indices.is_empty() {
+            return self.handle_targets(conn).await;
+        }
+        self.handle_index(conn).await
+    }
+
+    /// Recovery of the keyshare at `recovery_index`
+    async fn handle_index(&self, conn: async_nats::Client) -> Result<()> {
         let key_id = self.key_id.clone();
         let session_id = self.session_id.clone();
 
@@ -49,7 +83,7 @@ impl NewKeyShareRecoverySession {
             None => Self::find_email_for_key(&key_id)?,
         };
 
-        let node = NodeIdentity::load()?;
+        let node = NodeIdentity::cached()?;
         let private_key = node.networking_private_key.clone();
         info!("Retrieved node identity");
 
@@ -75,15 +109,16 @@ impl NewKeyShareRecoverySession {
                     &email
                 )?;
                 let party_index = key_accessor.key.party_index;
+                self.check_threshold(key_accessor.key.threshold)?;
 
-                let (messenger, peers) = Nats::new_session(
+                let (messenger, peers, encryption_versions) = Nats::new_session(
                     conn,
                     &session_id,
                     &node,
                     &key_id,
                     party_index,
                     topic
-                )?;
+                ).await?;
 
                 let key_behaviour = EdDSABehaviourHelperRole::from_key_accessor(key_accessor);
 
@@ -92,6 +127,7 @@ impl NewKeyShareRecoverySession {
                     self.recovery_index,
                     party_index,
                     &peers,
+                    &encryption_versions,
                     private_key
                 ).map_err(|err| anyhow!("Unable to create encryptor: {}", err))?;
 
@@ -101,10 +137,10 @@ impl NewKeyShareRecoverySession {
                     key_behaviour
                 );
 
-                recoverer.try_recovery(self.recovery_index, Party {
+                recoverer.try_recovery(&session_id, self.recovery_index, Party {
                     party_index,
                     all_parties: peers,
-                })
+                }).await
             }
             //Recovery of a EdCSA keyshare by a helper guardian
             (RecoveryRole::Helper, Key::Sr25519) => {
@@ -113,15 +149,16 @@ impl NewKeyShareRecoverySession {
                     &email
                 )?;
                 let party_index = key_accessor.key.party_index;
+                self.check_threshold(key_accessor.key.threshold)?;
 
-                let (messenger, peers) = Nats::new_session(
+                let (messenger, peers, encryption_versions) = Nats::new_session(
                     conn,
                     &session_id,
                     &node,
                     &key_id,
                     party_index,
                     topic
-                )?;
+                ).await?;
 
                 let key_behaviour = EdDSABehaviourHelperRole::from_key_accessor(key_accessor);
 
@@ -130,6 +167,7 @@ impl NewKeyShareRecoverySession {
                     self.recovery_index,
                     party_index,
                     &peers,
+                    &encryption_versions,
                     private_key
                 ).map_err(|err| anyhow!("Unable to create encryptor: {}", err))?;
 
@@ -139,23 +177,23 @@ impl NewKeyShareRecoverySession {
                     key_behaviour
                 );
 
-                recoverer.try_recovery(self.recovery_index, Party {
+                recoverer.try_recovery(&session_id, self.recovery_index, Party {
                     party_index,
                     all_parties: peers,
-                })
+                }).await
             }
             //Recovery procedure followed by target of EdDSA key recovery to receive and validate their new keyshare
             (RecoveryRole::Target, Key::EDDSA) => {
                 let party_index = self.recovery_index;
 
-                let (messenger, peers) = Nats::new_session(
+                let (messenger, peers, _) = Nats::new_session(
                     conn,
                     &session_id,
                     &node,
                     &key_id,
                     party_index,
                     topic
-                )?;
+                ).await?;
 
                 let key_behaviour = EdDSABehaviourTargetRole::new(&key_id);
 
@@ -165,7 +203,7 @@ impl NewKeyShareRecoverySession {
 
                 let recoverer = KeyshareRecoveryTarget::new(messenger, encryptor, key_behaviour);
 
-                let encrypted_packages = recoverer.try_recieve_encrypted_packages()?;
+                let encrypted_packages = recoverer.try_recieve_encrypted_packages().await?;
 
                 let result = recoverer.recover_keyshare(
                     self.recovery_index,
@@ -173,7 +211,7 @@ impl NewKeyShareRecoverySession {
                     encrypted_packages
                 )?;
 
-                recoverer.broadcast_result(result)
+                recoverer.broadcast_result(result).await
             }
             //Recovery of a ECDSA keyshare by a helper guardian
             (RecoveryRole::Helper, Key::ECDSA) => {
@@ -182,15 +220,16 @@ impl NewKeyShareRecoverySession {
                     &email
                 )?;
                 let party_index = key_accessor.key.party_index;
+                self.check_threshold(key_accessor.key.threshold)?;
 
-                let (messenger, peers) = Nats::new_session(
+                let (messenger, peers, encryption_versions) = Nats::new_session(
                     conn,
                     &session_id,
                     &node,
                     &key_id,
                     party_index,
                     topic
-                )?;
+                ).await?;
 
                 let key_behaviour = ECDSABehaviourHelperRole::from_key_accessor(key_accessor);
 
@@ -199,6 +238,7 @@ impl NewKeyShareRecoverySession {
                     self.recovery_index,
                     party_index,
                     &peers,
+                    &encryption_versions,
                     private_key
                 ).map_err(|err| anyhow!("Unable to create encryptor: {}", err))?;
 
@@ -208,23 +248,23 @@ impl NewKeyShareRecoverySession {
                     key_behaviour
                 );
 
-                recoverer.try_recovery(self.recovery_index, Party {
+                recoverer.try_recovery(&session_id, self.recovery_index, Party {
                     party_index,
                     all_parties: peers,
-                })
+                }).await
             }
             //Recovery procedure followed by target of ECDSA key recovery to receive and validate their new keyshare
             (RecoveryRole::Target, Key::ECDSA) => {
                 let party_index = self.recovery_index;
 
-                let (messenger, peers) = Nats::new_session(
+                let (messenger, peers, _) = Nats::new_session(
                     conn,
                     &session_id,
                     &node,
                     &key_id,
                     party_index,
                     topic
-                )?;
+                ).await?;
 
                 let key_behaviour = ECDSABehaviourTargetRole::new(&key_id);
 
@@ -234,7 +274,7 @@ impl NewKeyShareRecoverySession {
 
                 let recoverer = KeyshareRecoveryTarget::new(messenger, encryptor, key_behaviour);
 
-                let encrypted_packages = recoverer.try_recieve_encrypted_packages()?;
+                let encrypted_packages = recoverer.try_recieve_encrypted_packages().await?;
 
                 let result = recoverer.recover_keyshare(
                     self.recovery_index,
@@ -242,20 +282,20 @@ impl NewKeyShareRecoverySession {
                     encrypted_packages
                 )?;
 
-                recoverer.broadcast_result(result)
+                recoverer.broadcast_result(result).await
             }
             //Recovery procedure followed by target of 2fa key recovery to receive and validate their new keyshare
             (RecoveryRole::Target, Key::Sr25519) => {
                 let party_index = self.recovery_index;
 
-                let (messenger, peers) = Nats::new_session(
+                let (messenger, peers, _) = Nats::new_session(
                     conn,
                     &session_id,
                     &node,
                     &key_id,
                     party_index,
                     topic
-                )?;
+                ).await?;
 
                 let key_behaviour = Sr25519BehaviourTargetRole::new(&key_id);
 
@@ -265,7 +305,78 @@ impl NewKeyShareRecoverySession {
 
                 let recoverer = KeyshareRecoveryTarget::new(messenger, encryptor, key_behaviour);
 
-                let encrypted_packages = recoverer.try_recieve_encrypted_packages()?;
+                let encrypted_packages = recoverer.try_recieve_encrypted_packages().await?;
+
+                let result = recoverer.recover_keyshare(
+                    self.recovery_index,
+                    self.threshold,
+                    encrypted_packages
+                )?;
+
+                recoverer.broadcast_result(result).await
+            }
+            //Recovery of a BLS keyshare by a helper guardian
+            (RecoveryRole::Helper, Key::BLS) => {
+                let key_accessor = KeyshareAccessor::<BLS>::read_only_with_email(
+                    &key_id,
+                    &email
+                )?;
+                let party_index = key_accessor.key.party_index;
+                self.check_threshold(key_accessor.key.threshold)?;
+
+                let (messenger, peers, encryption_versions) = Nats::new_session(
+                    conn,
+                    &session_id,
+                    &node,
+                    &key_id,
+                    party_index,
+                    topic
+                ).await?;
+
+                let key_behaviour = BLSBehaviourHelperRole::from_key_accessor(key_accessor);
+
+                let encryptor = NKeyHelperEncryptor::new(
+                    &public_keys,
+                    self.recovery_index,
+                    party_index,
+                    &peers,
+                    &encryption_versions,
+                    private_key
+                ).map_err(|err| anyhow!("Unable to create encryptor: {}", err))?;
+
+                let mut recoverer = KeyshareRecoveryHelper::new(
+                    messenger,
+                    encryptor,
+                    key_behaviour
+                );
+
+                recoverer.try_recovery(&session_id, self.recovery_index, Party {
+                    party_index,
+                    all_parties: peers,
+                }).await
+            }
+            //Recovery procedure followed by target of BLS key recovery to receive and validate their new keyshare
+            (RecoveryRole::Target, Key::BLS) => {
+                let party_index = self.recovery_index;
+
+                let (messenger, peers, _) = Nats::new_session(
+                    conn,
+                    &session_id,
+                    &node,
+                    &key_id,
+                    party_index,
+                    topic
+                ).await?;
+
+                let key_behaviour = BLSBehaviourTargetRole::new(&key_id);
+
+                let encryptor = NKeyTargetEncryptor::new(&public_keys, &peers, private_key).map_err(
+                    |err| anyhow!("Unable to create encryptor: {}", err)
+                )?;
+
+                let recoverer = KeyshareRecoveryTarget::new(messenger, encryptor, key_behaviour);
+
+                let encrypted_packages = recoverer.try_recieve_encrypted_packages().await?;
 
                 let result = recoverer.recover_keyshare(
                     self.recovery_index,
@@ -273,11 +384,45 @@ impl NewKeyShareRecoverySession {
                     encrypted_packages
                 )?;
 
-                recoverer.broadcast_result(result)
+                recoverer.broadcast_result(result).await
             }
         }
     }
 
+    // Helper side of a multi-target recovery, one sub-session per lost keyshare
+    async fn handle_targets(&self, conn: async_nats::Client) -> Result<()> {
+        if !matches!(self.role, RecoveryRole::Helper) {
+            bail!("Only helpers take part in multi-target recovery sessions");
+        }
+
+        for &recovery_index in &self.recovery_indices {
+            let session = NewKeyShareRecoverySession {
+                session_id: target_session_id(&self.session_id, recovery_index),
+                recovery_index,
+                recovery_indices: Vec::new(),
+                ..self.clone()
+            };
+            session.handle_index(conn.clone()).await?;
+            info!("Recovery package delivered - recovery_index: {}", recovery_index);
+        }
+        Ok(())
+    }
+
+    /// Helpers regenerate the share with the threshold of their keyshare, the target would reject
+    /// the packages if the session was started with another one
+    fn check_threshold(&self, keyshare_threshold: usize) -> Result<()> {
+        if self.threshold != keyshare_threshold {
+            bail!(
+                "Recovery session {} uses threshold {}, key {} was generated with {}",
+                self.session_id,
+                self.threshold,
+                self.key_id,
+                keyshare_threshold
+            );
+        }
+        Ok(())
+    }
+
     // Function to find the email for a key ID by searching the file system
     fn find_email_for_key(key_id: &str) -> Result<String> {
         use std::fs;
@@ -317,45 +462,49 @@ impl NewKeyShareRecoverySession {
     }
 }
 
-pub fn handle_new_session_message(app: &App, message: nats::Message) {
-    let session = match serde_json::from_slice::<NewKeyShareRecoverySession>(&message.data[..]) {
+pub fn handle_new_session_message(app: &App, message: IncomingMessage) {
+    let session = match strict::from_slice::<NewKeyShareRecoverySession>(&message.data[..]) {
         Ok(session) => session,
         Err(err) => {
-            error!("Incorrect keyshare recovery message format: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Incorrect keyshare recovery message format: {}", err)
+            );
             return;
         }
     };
 
-    let nc = app.nc.clone();
+    let nc = app.nc.client().clone();
     let session_id = session.session_id.clone();
-    let thread_session_id = session_id.clone(); // Clone again for thread
-    match
-        thread::Builder
-            ::new()
-            .name(format!("keyshare_recovery_session_{}", &session_id))
-            .spawn(move || {
-                match session.handle(nc) {
-                    Ok(_) => {
-                        info!(
-                            "Keyshare recovery was successful for session id {}",
-                            &thread_session_id
-                        );
-                    }
-                    Err(err) => {
-                        error!(
-                            "Keyshare recovery failed: session id: {}, error: {}",
-                            &thread_session_id,
-                            err
-                        );
-                    }
-                };
-            })
-    {
-        Ok(_) => {
-            info!("Spawned a thread to handle keyshare recovery");
-        }
-        Err(_) => {
-            error!("Failed to spawn thread for keyshare recovery");
-        }
-    };
+    let task_session_id = session_id.clone(); // Clone again for the task
+    let key_id = session.key_id.clone();
+    let audit = AuditedRequest::new(
+        AuditAction::Recovery,
+        &session.kind.to_string().to_lowercase(),
+        &key_id,
+        &session_id,
+        session.email.as_deref()
+    );
+    session_manager::spawn_session(SessionKind::Recovery, &session_id, async move {
+        let result = session.handle(nc).await;
+        slo::record_recovery(&key_id, result.is_ok());
+        audit.record(&result);
+        match result {
+            Ok(_) => {
+                info!("Keyshare recovery was successful for session id {}", &task_session_id);
+                metrics::session_completed(SessionKind::Recovery);
+            }
+            Err(err) => {
+                metrics::session_failed(SessionKind::Recovery);
+                error!(
+                    "Keyshare recovery failed: session id: {}, error: {}",
+                    &task_session_id,
+                    err
+                );
+            }
+        };
+    });
+    info!("Spawned a task to handle keyshare recovery");
 }
@@ -0,0 +1,342 @@
+use crate::command::{ JsonCommand, MsgContext, TaggedCommandType };
+use crate::communication::blocking;
+use crate::health::{ GetGuardianHealthCommand, GuardianHealth };
+use crate::recovery::orchestrate::{ orchestrate_with_key_info, TargetDelivery };
+use crate::recovery::{ Key, RecoveryCommand };
+use crate::signing::response::VersionedSigningResponse;
+use crate::signing::{ self, SigningCommand };
+use crate::storage::fs::WriteOpts;
+use crate::storage::KeyInfoStore;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Context, Result };
+use chrono::{ DateTime, Duration as ChronoDuration, Utc };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use shared::key_info::{ KeyInfo, NodeId, UpdateKeyInfoCommand };
+use std::time::Duration;
+use tracing::{ info, instrument, warn };
+
+/// Threshold of keys generated before key info recorded it
+const LEGACY_THRESHOLD: usize = 2;
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Attestations are published every 15 minutes, a candidate that missed two is not selected
+const ATTESTATION_MAX_AGE_MINUTES: i64 = 30;
+const CANARY_CONTEXT: &[u8] = b"gridlock-guardian-replacement-canary";
+
+/// A node that may take over the share of the guardian being replaced
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct GuardianCandidate {
+    pub node_id: NodeId,
+    pub networking_public_key: String,
+}
+
+/// Replaces a guardian that can no longer be reached in a single call: selects the first healthy
+/// candidate, regenerates the old guardian's share on it, updates the key info of every guardian,
+/// has the new guardian co-sign a canary message and retires the old guardian
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReplaceGuardianCommand {
+    #[serde(flatten)]
+    kind: Key,
+    key_id: String,
+    session_id: String,
+    old_node_id: NodeId,
+    /// Guardians still holding their shares, they regenerate the lost one
+    party_nodes: Vec<NodeId>,
+    /// Candidates in order of preference
+    candidates: Vec<GuardianCandidate>,
+    email: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReplaceGuardianResponse {
+    pub key_id: String,
+    pub old_node_id: NodeId,
+    pub new_node_id: NodeId,
+    pub share_index: usize,
+    /// Signature over the canary message, produced by the new guardian and as many of the helpers
+    /// as the threshold of the key
+    pub canary_signature: VersionedSigningResponse,
+}
+
+impl JsonCommand for ReplaceGuardianCommand {
+    type Response = ReplaceGuardianResponse;
+
+    /// The helpers check the recovery rules of the key when the sessions start
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        replace_guardian(self, ctx)
+    }
+}
+
+#[instrument(skip_all)]
+fn replace_guardian(
+    cmd: ReplaceGuardianCommand,
+    ctx: MsgContext
+) -> Result<ReplaceGuardianResponse> {
+    let app = ctx.get_app()?;
+    let key_info = KeyInfoStore::get_key_info(&cmd.key_id).with_context(|| {
+        format!("Key info is not found - key_id: {}", cmd.key_id)
+    })?;
+    let share_index = check_replacement(&key_info, &cmd)?;
+
+    let candidate = select_candidate(&app.nc, &key_info, &cmd.candidates)?;
+    info!("Replacing guardian {} with {}", cmd.old_node_id, candidate.node_id);
+
+    let recovery = RecoveryCommand {
+        kind: cmd.kind.clone(),
+        key_id: cmd.key_id.clone(),
+        session_id: cmd.session_id.clone(),
+        new_node_id: candidate.node_id.clone(),
+        new_node_public_key: candidate.networking_public_key.clone(),
+        old_node_id: cmd.old_node_id.clone(),
+        party_nodes: cmd.party_nodes.clone(),
+        email: cmd.email.clone(),
+        additional_targets: Vec::new(),
+    };
+    let key_info = orchestrate_with_key_info(
+        &app.nc,
+        recovery,
+        key_info,
+        LEGACY_THRESHOLD,
+        TargetDelivery::Nats
+    )?;
+    // The orchestrator only receives the update if it is one of the guardians of the key
+    KeyInfoStore::save_key_info(&key_info, &cmd.key_id, &WriteOpts::Modify)?;
+
+    let mut canary_party = cmd.party_nodes[..threshold(&key_info)].to_vec();
+    canary_party.push(candidate.node_id.clone());
+    let canary = SigningCommand {
+        kind: signing_key(&cmd.kind),
+        key_id: cmd.key_id.clone(),
+        session_id: format!("{}-canary", cmd.session_id),
+        party_nodes: canary_party,
+        msg: canary_message(&cmd.key_id, &cmd.session_id),
+        encoding: None,
+        taproot_merkle_root: None,
+        presignature_id: None,
+        hash_mode: Default::default(),
+        network_mode: Default::default(),
+        response_version: Default::default(),
+        allow_resign: false,
+        allow_quarantined_peers: false,
+        dry_run: false,
+        derivation_path: None,
+    };
+    let canary_signature = canary
+        .execute_message(MsgContext::nats(app.clone()))
+        .with_context(|| format!("New guardian {} failed to co-sign", candidate.node_id))?;
+    info!("New guardian {} co-signed the canary message", candidate.node_id);
+
+    retire_guardian(&app.nc, &cmd.old_node_id, &cmd.key_id, &key_info);
+
+    Ok(ReplaceGuardianResponse {
+        key_id: cmd.key_id,
+        old_node_id: cmd.old_node_id,
+        new_node_id: candidate.node_id,
+        share_index,
+        canary_signature,
+    })
+}
+
+/// Returns the share index of the guardian being replaced
+fn check_replacement(key_info: &KeyInfo, cmd: &ReplaceGuardianCommand) -> Result<usize> {
+    let share_index = key_info.node_pool
+        .iter()
+        .find(|n| n.node_id == cmd.old_node_id)
+        .ok_or_else(|| anyhow!("Node {} holds no share of key {}", cmd.old_node_id, cmd.key_id))?
+        .share_index;
+    let threshold = threshold(key_info);
+    if cmd.party_nodes.len() < threshold + 1 {
+        bail!("At least {} helpers are needed to replace a guardian", threshold + 1);
+    }
+    for helper in &cmd.party_nodes {
+        if helper == &cmd.old_node_id {
+            bail!("Lost node {} cannot take part as a helper", helper);
+        }
+        if !key_info.node_pool.iter().any(|n| &n.node_id == helper) {
+            bail!("Node {} holds no share of key {}", helper, cmd.key_id);
+        }
+    }
+    Ok(share_index)
+}
+
+fn threshold(key_info: &KeyInfo) -> usize {
+    key_info.threshold.unwrap_or(LEGACY_THRESHOLD)
+}
+
+/// First candidate that is not a guardian of the key yet and proves it is healthy
+fn select_candidate(
+    nc: &blocking::Connection,
+    key_info: &KeyInfo,
+    candidates: &[GuardianCandidate]
+) -> Result<GuardianCandidate> {
+    let request = serde_json::to_string(
+        &TaggedCommandType::GetGuardianHealth(GetGuardianHealthCommand {})
+    )?;
+    for candidate in candidates {
+        let checked = fetch_health(nc, &candidate.node_id, &request).and_then(|health| {
+            check_candidate(key_info, candidate, &health, Utc::now())
+        });
+        match checked {
+            Ok(()) => {
+                return Ok(candidate.clone());
+            }
+            Err(err) => warn!("Skipping candidate {}: {}", candidate.node_id, err),
+        }
+    }
+    bail!("None of the {} candidates can take over the share", candidates.len())
+}
+
+fn fetch_health(
+    nc: &blocking::Connection,
+    node_id: &NodeId,
+    request: &str
+) -> Result<GuardianHealth> {
+    let subject = format!("network.gridlock.nodes.Message.new.{}", node_id);
+    let response = nc
+        .request_timeout(&subject, request, HEALTH_TIMEOUT)
+        .with_context(|| format!("Requesting health of candidate {}", node_id))?;
+    let response = String::from_utf8(response.data)?;
+    if let Some(err) = response.strip_prefix("ERROR: ") {
+        bail!("Candidate {} could not report its health: {}", node_id, err);
+    }
+    serde_json::from_str(&response).context("Deserialize guardian health")
+}
+
+fn check_candidate(
+    key_info: &KeyInfo,
+    candidate: &GuardianCandidate,
+    health: &GuardianHealth,
+    now: DateTime<Utc>
+) -> Result<()> {
+    if key_info.node_pool.iter().any(|n| n.node_id == candidate.node_id) {
+        bail!("already a guardian of the key");
+    }
+    if health.node_id != candidate.node_id.to_string() {
+        bail!("answered as node {}", health.node_id);
+    }
+    if !health.summary.nats_connected {
+        bail!("not connected to NATS");
+    }
+    health.last_attestation
+        .as_ref()
+        .ok_or_else(|| anyhow!("has not published a health attestation yet"))?
+        .verify(
+            &candidate.networking_public_key,
+            ChronoDuration::minutes(ATTESTATION_MAX_AGE_MINUTES),
+            now
+        )
+}
+
+/// 32 byte message fitting every signing protocol, unique to the replacement
+fn canary_message(key_id: &str, session_id: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(CANARY_CONTEXT);
+    hasher.update(key_id.as_bytes());
+    hasher.update(session_id.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn signing_key(kind: &Key) -> signing::Key {
+    match kind {
+        Key::ECDSA => signing::Key::ECDSA,
+        Key::EDDSA => signing::Key::EDDSA,
+        Key::Sr25519 => signing::Key::Sr25519,
+        Key::BLS => signing::Key::BLS,
+    }
+}
+
+/// The old guardian is already gone from the key info of every other guardian. It is sent the
+/// updated key info as well, so it stops acting as a guardian of the key if it comes back.
+fn retire_guardian(
+    nc: &blocking::Connection,
+    old_node_id: &NodeId,
+    key_id: &str,
+    key_info: &KeyInfo
+) {
+    let update = UpdateKeyInfoCommand {
+        key_id: key_id.to_string(),
+        key_info: key_info.clone(),
+    };
+    let published = serde_json
+        ::to_string(&update)
+        .map_err(anyhow::Error::from)
+        .and_then(|msg| {
+            let subject = format!("network.gridlock.nodes.async.Message.new.{}", old_node_id);
+            Ok(nc.publish(&subject, msg)?)
+        });
+    match published {
+        Ok(()) => info!("Retired guardian {} from key {}", old_node_id, key_id),
+        Err(err) => warn!("Failed to notify retired guardian {}: {}", old_node_id, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::{ HealthAttestation, HealthSummary };
+    use crate::node::NodeIdentity;
+    use shared::key_info::{ Node, NodeInfo };
+
+    fn summary() -> HealthSummary {
+        HealthSummary {
+            nats_connected: true,
+            window_hours: 24,
+            samples: 1,
+            nats_disconnects: 0,
+            sessions_started: 0,
+            commands_handled: 0,
+            command_errors: 0,
+            errors_logged: 0,
+        }
+    }
+
+    #[test]
+    fn only_attested_outside_nodes_are_selected() {
+        let now = Utc::now();
+        let node = NodeIdentity::new();
+        let candidate = GuardianCandidate {
+            node_id: NodeId::new_from_uuid(node.node_id),
+            networking_public_key: node.networking_public_key.clone(),
+        };
+        let health = GuardianHealth {
+            node_id: node.node_id.to_string(),
+            name: node.name.clone(),
+            summary: summary(),
+            last_attestation: Some(HealthAttestation::sign(&node, summary(), now).unwrap()),
+        };
+        let mut key_info = KeyInfo {
+            kind: shared::key_info::Key::EDDSA { y_sum: String::new() },
+            node_pool: Vec::new(),
+            metadata: None,
+            threshold: None,
+        };
+        assert!(check_candidate(&key_info, &candidate, &health, now).is_ok());
+
+        let stale = now + ChronoDuration::minutes(ATTESTATION_MAX_AGE_MINUTES + 1);
+        assert!(check_candidate(&key_info, &candidate, &health, stale).is_err());
+        let unattested = GuardianHealth { last_attestation: None, ..health.clone() };
+        assert!(check_candidate(&key_info, &candidate, &unattested, now).is_err());
+
+        key_info.node_pool.push(NodeInfo {
+            node_id: candidate.node_id.clone(),
+            networking_public_key: candidate.networking_public_key.clone(),
+            kind: Node::Guardian,
+            share_index: 1,
+        });
+        assert!(check_candidate(&key_info, &candidate, &health, now).is_err());
+    }
+
+    #[test]
+    fn keys_without_recorded_threshold_use_the_legacy_one() {
+        let mut key_info: KeyInfo = serde_json
+            ::from_str(r#"{"key_type":"EDDSA","y_sum":"","node_pool":[]}"#)
+            .unwrap();
+        assert_eq!(threshold(&key_info), LEGACY_THRESHOLD);
+        key_info.threshold = Some(3);
+        assert_eq!(threshold(&key_info), 3);
+    }
+}
@@ -5,22 +5,26 @@ use crate::communication::{
 };
 use crate::recovery::calculator::RecoveryCalculator;
 use crate::recovery::encryption::TargetEncryptor;
-use crate::storage::{ KeyshareSaver, Sr25519, ECDSA, EDDSA };
+use crate::recovery::expiry::{ open_packages, ExpiringPackage };
+use crate::storage::{ KeyshareSaver, Sr25519, BLS, ECDSA, EDDSA };
 use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
 use paillier::{ DecryptionKey, EncryptionKey, KeyGeneration, Paillier };
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use zk_paillier::zkproofs::DLogStatement;
+use zk_paillier::zkproofs::{ DLogStatement, NiCorrectKeyProof };
+use crate::security::prove_paillier_key;
 
 use crate::recovery::{
     replace_elem_in_vec,
+    BLSRecoveryPackage,
     ECDSARecoveryPackage,
     EdDSARecoveryPackage,
     RecoveryValidationResult,
     ShareRecoveryInfo,
 };
 use anyhow::{ bail, Result };
-use curv::elliptic::curves::{ Curve, Ed25519, Point, Scalar, Secp256k1 };
+use chrono::Utc;
+use curv::elliptic::curves::{ Bls12_381_1, Curve, Ed25519, Point, Scalar, Secp256k1 };
 use itertools::Itertools;
 use tracing::{ error, info };
 
@@ -53,10 +57,23 @@ impl<PM, E, R> KeyshareRecoveryTarget<PM, E, R>
         threshold: usize,
         encrypted_packages: Vec<<E as TargetEncryptor>::Output>
     ) -> Result<RecoveryValidationResult> {
-        let recovery_packages = self.encryptor.decrypt_from_all_parties(encrypted_packages)?;
+        let recovery_packages = self.encryptor.decrypt_from_all_parties::<
+            ExpiringPackage<R::RecoveryPackage>
+        >(encrypted_packages)?;
 
         info!("Decrypted recovery packages");
 
+        let recovery_packages = match open_packages(recovery_packages, Utc::now()) {
+            Ok(packages) => packages,
+            Err(err) => {
+                return Ok(
+                    RecoveryValidationResult::error(
+                        format!("The provided recovery packages are not valid anymore: {}", err)
+                    )
+                );
+            }
+        };
+
         let validation_result = self.key_behaviour.process_recovery_packages(
             recovery_index,
             threshold,
@@ -68,27 +85,29 @@ impl<PM, E, R> KeyshareRecoveryTarget<PM, E, R>
         Ok(validation_result)
     }
 
-    pub fn broadcast_result(&self, result: RecoveryValidationResult) -> Result<()> {
-        self.broadcast_validation_result(result)
+    pub async fn broadcast_result(&self, result: RecoveryValidationResult) -> Result<()> {
+        self.broadcast_validation_result(result).await
     }
 
-    pub fn try_recieve_encrypted_packages(&self) -> Result<Vec<<E as TargetEncryptor>::Output>> {
+    pub async fn try_recieve_encrypted_packages(
+        &self
+    ) -> Result<Vec<<E as TargetEncryptor>::Output>> {
         info!("Attempting to recieve recovery packages as the target node");
         let received_packages = self.messenger.collect_messages::<E::Output>(
             &<KeyShareRegenAllRounds as AllRounds>::BroadcastRound::DeliverRecoveryPackage
-        )?;
+        ).await?;
         info!("Received recovery packages");
         Ok(received_packages)
     }
 
-    fn broadcast_validation_result(
+    async fn broadcast_validation_result(
         &self,
         validation_result: RecoveryValidationResult
     ) -> Result<()> {
         self.messenger.broadcast_message::<RecoveryValidationResult>(
             &<KeyShareRegenAllRounds as AllRounds>::BroadcastRound::ValidationResult,
             validation_result
-        )
+        ).await
     }
 }
 
@@ -113,6 +132,13 @@ impl EdDSABehaviourTargetRole {
             key_saver: KeyshareSaver::new_creator_modifier(key_id),
         }
     }
+
+    /// Saves the share encrypted under `index`, as keygen saves extra shares
+    pub fn ghost(key_id: &str, index: usize) -> Self {
+        Self {
+            key_saver: KeyshareSaver::new_encryptor(key_id, index),
+        }
+    }
 }
 
 impl KeyshareBehaviourTargetRole for EdDSABehaviourTargetRole {
@@ -233,14 +259,17 @@ impl KeyshareBehaviourTargetRole for ECDSABehaviourTargetRole {
                 .cloned()
                 .map_into()
                 .collect(),
-            paillier_dk: validated_recovery_items.paillier_dk,
+            paillier_dk: validated_recovery_items.paillier_dk.into(),
         };
         info!("Calculated new keyshare");
 
         match self.key_saver.save_key(&keyshare) {
             Ok(()) => {
                 info!("New file successfully saved for keyshare {}", recovery_index);
-                RecoveryValidationResult::validated_with_eks(validated_recovery_items.paillier_ek)
+                RecoveryValidationResult::validated_with_eks(
+                    validated_recovery_items.paillier_ek,
+                    validated_recovery_items.paillier_ek_proof
+                )
             }
             Err(err) => {
                 let msg =
@@ -262,6 +291,13 @@ impl Sr25519BehaviourTargetRole {
             key_saver: KeyshareSaver::new_creator(key_id),
         }
     }
+
+    /// Saves the share encrypted under `index`, as keygen saves extra shares
+    pub fn ghost(key_id: &str, index: usize) -> Self {
+        Self {
+            key_saver: KeyshareSaver::new_encryptor(key_id, index),
+        }
+    }
 }
 
 impl KeyshareBehaviourTargetRole for Sr25519BehaviourTargetRole {
@@ -327,11 +363,78 @@ impl KeyshareBehaviourTargetRole for Sr25519BehaviourTargetRole {
     }
 }
 
+pub struct BLSBehaviourTargetRole {
+    key_saver: KeyshareSaver,
+}
+
+impl BLSBehaviourTargetRole {
+    pub fn new(key_id: &str) -> Self {
+        Self {
+            key_saver: KeyshareSaver::new_creator_modifier(key_id),
+        }
+    }
+
+    /// Saves the share encrypted under `index`, as keygen saves extra shares
+    pub fn ghost(key_id: &str, index: usize) -> Self {
+        Self {
+            key_saver: KeyshareSaver::new_encryptor(key_id, index),
+        }
+    }
+}
+
+impl KeyshareBehaviourTargetRole for BLSBehaviourTargetRole {
+    type Curve = Bls12_381_1;
+    type RecoveryPackage = BLSRecoveryPackage;
+    fn process_recovery_packages(
+        &self,
+        recovery_index: usize,
+        threshold: usize,
+        packages: &[Self::RecoveryPackage]
+    ) -> RecoveryValidationResult {
+        let share_info = packages
+            .iter()
+            .map(|x| x.share_recovery_info.clone())
+            .collect::<Vec<ShareRecoveryInfo<Self::Curve>>>();
+        let (x_i, vss_scheme_vec, public_key) = match
+            recover_and_validate_secret(recovery_index, share_info)
+        {
+            Ok(ss) => ss,
+            Err(err) => {
+                return RecoveryValidationResult::error(
+                    format!("The provided recovery packages could not be validated: {}", err)
+                );
+            }
+        };
+
+        let new_keyshare = BLS {
+            threshold,
+            party_index: recovery_index,
+            x_i,
+            public_key,
+            vss_scheme_vec,
+        };
+
+        match self.key_saver.save_key(&new_keyshare) {
+            Ok(()) => {
+                info!("New file successfully saved for keyshare {}", recovery_index);
+                RecoveryValidationResult::validated()
+            }
+            Err(err) => {
+                let msg =
+                    format!("The keyshare was recovered and validated successfully but the new keyshare file could not be saved: {}", err);
+                error!("{}", msg);
+                RecoveryValidationResult::error(msg.to_string())
+            }
+        }
+    }
+}
+
 struct ECDSASpecificValidatedRecoveryItems {
     public_key_vec: Vec<Point<Secp256k1>>,
     new_paillier_key_vec: Vec<EncryptionKey>,
     h1_h2_N_tilde_vec: Vec<DLogStatement>,
     paillier_ek: EncryptionKey,
+    paillier_ek_proof: NiCorrectKeyProof,
     paillier_dk: DecryptionKey,
 }
 
@@ -368,12 +471,15 @@ fn validate_ecdsa_specific_recovery_package_items(
         &mut paillier_key_vec,
         recovery_index
     )?;
+    // The other parties only store the new key once its modulus is proven to be square-free
+    let paillier_ek_proof = prove_paillier_key(&paillier_dk);
 
     Ok(ECDSASpecificValidatedRecoveryItems {
         public_key_vec,
         new_paillier_key_vec: paillier_key_vec,
         h1_h2_N_tilde_vec,
         paillier_ek,
+        paillier_ek_proof,
         paillier_dk,
     })
 }
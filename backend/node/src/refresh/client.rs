@@ -0,0 +1,256 @@
+use crate::communication::nats::PeerMessenger;
+use crate::communication::protocol::{ AllRounds, ShareRefreshAllRounds };
+use crate::keygen::ShareParams;
+use anyhow::{ anyhow, bail, Result };
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::{ Curve, Point, Scalar };
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{ Digest, Sha256 };
+
+pub struct ShareRefreshClient<C> {
+    pub peer_messenger: C,
+    pub share_params: ShareParams,
+    pub all_party_indices: Vec<usize>,
+}
+
+/// Share and commitments of a party after the refresh
+pub struct RefreshedShare<E: Curve> {
+    pub x_i: Scalar<E>,
+    pub vss_scheme_vec: Vec<VerifiableSS<E>>,
+    pub commitments_digest: String,
+}
+
+impl<C> ShareRefreshClient<C> where C: PeerMessenger<ShareRefreshAllRounds> {
+    /// Every party deals a sharing of zero and adds the shares it receives to its own. Zero
+    /// shares are sent in the `ZeroShare` round, which is sealed to the receiving party.
+    pub async fn refresh<E: Curve>(
+        &self,
+        x_i: &Scalar<E>,
+        vss_scheme_vec: &[VerifiableSS<E>]
+    ) -> Result<RefreshedShare<E>> {
+        if self.share_params.party_count != vss_scheme_vec.len() {
+            bail!(
+                "The key was dealt to {} parties but {} joined the refresh, every share has to be \
+                 refreshed",
+                vss_scheme_vec.len(),
+                self.share_params.party_count
+            );
+        }
+        let indices = self.all_party_indices
+            .iter()
+            .map(|&i| i as u16)
+            .collect::<Vec<_>>();
+        let (dealing, zero_shares) = VerifiableSS::<E>::share_at_indices(
+            self.share_params.threshold as u16,
+            self.share_params.party_count as u16,
+            &Scalar::zero(),
+            &indices
+        );
+
+        let dealings = self.peer_messenger.broadcast_and_collect_messages(
+            &<ShareRefreshAllRounds as AllRounds>::BroadcastRound::Commit,
+            dealing
+        ).await?;
+        let received_shares = self.exchange_zero_shares(&zero_shares).await?;
+        let (x_i, vss_scheme_vec) = apply_zero_sharings(
+            self.share_params.party_index,
+            x_i,
+            vss_scheme_vec,
+            &self.all_party_indices,
+            &dealings,
+            &received_shares
+        )?;
+
+        let commitments_digest = commitments_digest(&vss_scheme_vec)?;
+        let digests = self.peer_messenger.broadcast_and_collect_messages(
+            &<ShareRefreshAllRounds as AllRounds>::BroadcastRound::Confirm,
+            commitments_digest.clone()
+        ).await?;
+        for (sender, digest) in self.all_party_indices.iter().zip(&digests) {
+            if digest != &commitments_digest {
+                bail!("Party {} ended the refresh with other commitments", sender);
+            }
+        }
+
+        Ok(RefreshedShare { x_i, vss_scheme_vec, commitments_digest })
+    }
+
+    /// Tells the other parties the refreshed share is saved and waits until every party did
+    pub async fn confirm_saved(&self, commitments_digest: &str) -> Result<()> {
+        let digests = self.peer_messenger.broadcast_and_collect_messages(
+            &<ShareRefreshAllRounds as AllRounds>::BroadcastRound::Saved,
+            commitments_digest.to_string()
+        ).await?;
+        for (sender, digest) in self.all_party_indices.iter().zip(&digests) {
+            if digest != commitments_digest {
+                bail!("Party {} saved other commitments", sender);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn publish_result<T: Serialize + DeserializeOwned + Clone>(
+        &self,
+        result: T
+    ) -> Result<()> {
+        let _ = self.peer_messenger.broadcast_and_collect_messages(
+            &<ShareRefreshAllRounds as AllRounds>::BroadcastRound::Result,
+            result
+        ).await?;
+        Ok(())
+    }
+
+    /// Zero shares of every party for this one, in party order
+    async fn exchange_zero_shares<E: Curve>(
+        &self,
+        zero_shares: &[Scalar<E>]
+    ) -> Result<Vec<Scalar<E>>> {
+        let outgoing_messages = self.all_party_indices
+            .iter()
+            .zip(zero_shares)
+            .filter(|(&party_index, _)| party_index != self.share_params.party_index)
+            .map(|(_, share)| share.clone())
+            .collect::<Vec<_>>();
+        let msg_vec = self.peer_messenger.send_p2p_and_collect_messages(
+            &<ShareRefreshAllRounds as AllRounds>::P2PRound::ZeroShare,
+            outgoing_messages
+        ).await?;
+        let mut received = msg_vec.into_iter();
+
+        let mut party_shares = Vec::new();
+        for (index, party_index) in self.all_party_indices.iter().enumerate() {
+            if *party_index != self.share_params.party_index {
+                let share = received
+                    .next()
+                    .ok_or_else(|| anyhow!("Missing zero share from party {}", party_index))?;
+                party_shares.push(share);
+            } else {
+                party_shares.push(zero_shares[index].clone());
+            }
+        }
+        Ok(party_shares)
+    }
+}
+
+/// Adds the zero shares dealt to `party_index` to its share and the dealers' commitments to the
+/// first dealing of the key. The number of dealings in the keyshare stays the same, so shares
+/// keep deriving their public counterparts the way they did before.
+pub(crate) fn apply_zero_sharings<E: Curve>(
+    party_index: usize,
+    x_i: &Scalar<E>,
+    vss_scheme_vec: &[VerifiableSS<E>],
+    senders: &[usize],
+    dealings: &[VerifiableSS<E>],
+    shares: &[Scalar<E>]
+) -> Result<(Scalar<E>, Vec<VerifiableSS<E>>)> {
+    let first = match vss_scheme_vec.first() {
+        Some(first) => first,
+        None => bail!("The keyshare has no commitments to refresh"),
+    };
+    if dealings.len() != senders.len() || shares.len() != senders.len() {
+        bail!("Expected a zero sharing from each of the {} parties", senders.len());
+    }
+
+    let mut new_x_i = x_i.clone();
+    let mut commitments = first.commitments.clone();
+    for ((sender, dealing), share) in senders.iter().zip(dealings).zip(shares) {
+        if
+            dealing.parameters.threshold != first.parameters.threshold ||
+            dealing.commitments.len() != commitments.len()
+        {
+            bail!("Zero sharing of party {} has another threshold than the key", sender);
+        }
+        if !dealing.commitments[0].is_zero() {
+            bail!("Party {} dealt a sharing of a value other than zero", sender);
+        }
+        dealing.validate_share(share, party_index as u16).map_err(|_|
+            anyhow!("Zero share from party {} failed VSS verification", sender)
+        )?;
+        new_x_i = new_x_i + share;
+        for (sum, commitment) in commitments.iter_mut().zip(&dealing.commitments) {
+            *sum = &*sum + commitment;
+        }
+    }
+
+    let mut new_vss_scheme_vec = vss_scheme_vec.to_vec();
+    new_vss_scheme_vec[0] = VerifiableSS {
+        parameters: first.parameters.clone(),
+        commitments,
+    };
+    let public_share = new_vss_scheme_vec
+        .iter()
+        .fold(Point::zero(), |sum, vss| sum + vss.get_point_commitment(party_index as u16));
+    if Point::generator() * &new_x_i != public_share {
+        bail!("Refreshed share does not match the refreshed commitments");
+    }
+    Ok((new_x_i, new_vss_scheme_vec))
+}
+
+fn commitments_digest<E: Curve>(vss_scheme_vec: &[VerifiableSS<E>]) -> Result<String> {
+    Ok(hex::encode(Sha256::digest(serde_json::to_vec(vss_scheme_vec)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curv::arithmetic::Converter;
+    use curv::elliptic::curves::Ed25519;
+    use curv::BigInt;
+
+    #[test]
+    fn refresh_keeps_the_secret_and_public_key() {
+        let indices = [1u16, 2, 3];
+        let secret = Scalar::<Ed25519>::random();
+        let (vss, shares) = VerifiableSS::<Ed25519>::share_at_indices(1, 3, &secret, &indices);
+        let vss_scheme_vec = vec![vss.clone()];
+        let senders = vec![1, 2, 3];
+        let zero_sharings = senders
+            .iter()
+            .map(|_| VerifiableSS::<Ed25519>::share_at_indices(1, 3, &Scalar::zero(), &indices))
+            .collect::<Vec<_>>();
+        let dealings = zero_sharings
+            .iter()
+            .map(|(dealing, _)| dealing.clone())
+            .collect::<Vec<_>>();
+
+        let mut refreshed = Vec::new();
+        for (position, &party_index) in senders.iter().enumerate() {
+            let received = zero_sharings
+                .iter()
+                .map(|(_, zero_shares)| zero_shares[position].clone())
+                .collect::<Vec<_>>();
+            let (x_i, new_vss_scheme_vec) = apply_zero_sharings(
+                party_index,
+                &shares[position],
+                &vss_scheme_vec,
+                &senders,
+                &dealings,
+                &received
+            ).unwrap();
+            assert_ne!(x_i, shares[position]);
+            assert_eq!(new_vss_scheme_vec[0].commitments[0], vss.commitments[0]);
+            refreshed.push((x_i, commitments_digest(&new_vss_scheme_vec).unwrap()));
+        }
+        assert!(refreshed.iter().all(|(_, digest)| digest == &refreshed[0].1));
+
+        let points = [1u32, 3]
+            .iter()
+            .map(|i| Scalar::<Ed25519>::from_bigint(&BigInt::from(*i)))
+            .collect::<Vec<_>>();
+        let quorum = [refreshed[0].0.clone(), refreshed[2].0.clone()];
+        let reconstructed = VerifiableSS::<Ed25519>::lagrange_interpolation_at_zero(
+            &points,
+            &quorum
+        );
+        assert_eq!(reconstructed, secret);
+
+        let mut forged = dealings.clone();
+        forged[1] = vss;
+        let received = vec![Scalar::zero(), shares[0].clone(), Scalar::zero()];
+        assert!(
+            apply_zero_sharings(1, &shares[0], &vss_scheme_vec, &senders, &forged, &received)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Shares a node keeps beside its current one across a share refresh. The refreshed share is
+//! saved as pending before the party tells the others it is ready, and replaces the current share
+//! only once every party did. The replaced share is kept as the previous generation until the next
+//! refresh, so a refresh that did not complete on every node can be reverted with
+//! `RevertShareRefreshCommand`.
+
+use crate::command::{ JsonCommand, MsgContext };
+use crate::refresh::RefreshableShare;
+use crate::session_manager;
+use crate::signing::Key;
+use crate::storage::backend::{ storage_backend, StorageItem };
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::storage::{ CurrentKeyshareFormat, KeyshareAccessor, KeyshareFormat };
+use crate::storage::{ Frost, BLS, EDDSA };
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Result };
+use curv::elliptic::curves::Scalar;
+use serde::{ Deserialize, Serialize };
+use std::convert::TryFrom;
+use std::fmt::Display;
+use tracing::info;
+use zeroize::Zeroizing;
+
+const PENDING_SHARE_KEY: &str = "refresh_pending_share";
+const PREVIOUS_SHARE_KEY: &str = "refresh_previous_share";
+
+/// A keyshare kept in the key's metadata, which is encrypted with the storage key like keyfiles
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetainedShare {
+    session_id: String,
+    /// Serialized keyshare
+    keyshare: String,
+}
+
+/// Saves the refreshed share of the session beside the current one, replacing a pending share
+/// left by an earlier refresh that did not complete
+pub(crate) fn save_pending<K: Serialize>(
+    key_id: &str,
+    email: Option<&str>,
+    session_id: &str,
+    keyshare: &K
+) -> Result<()> {
+    save(key_id, PENDING_SHARE_KEY, email, session_id, keyshare)
+}
+
+/// Replaces the current share with the pending one of the session and keeps the current one as
+/// the previous generation. Fails without changing anything if the share is no longer the one the
+/// refresh started from, or the pending share was reverted in the meantime.
+pub(crate) fn commit<K>(
+    key_id: &str,
+    email: Option<&str>,
+    session_id: &str,
+    started_from: &Scalar<K::Curve>
+) -> Result<()>
+    where
+        K: CurrentKeyshareFormat + RefreshableShare,
+        <K as TryFrom<KeyshareFormat>>::Error: Display
+{
+    let _lock = session_manager::lock_key(key_id)?;
+    let mut accessor = modifiable::<K>(key_id, email)?;
+    if accessor.key.share().0 != started_from {
+        bail!("Share of key {} changed during the refresh", key_id);
+    }
+    let pending = retained(key_id, PENDING_SHARE_KEY, email)?
+        .filter(|pending| pending.session_id == session_id)
+        .ok_or_else(|| anyhow!("Refreshed share of session {} was reverted", session_id))?;
+    let refreshed = serde_json::from_str::<K>(&pending.keyshare)?;
+
+    save(key_id, PREVIOUS_SHARE_KEY, email, session_id, &accessor.key)?;
+    accessor.key = refreshed;
+    accessor.update_saved_key()?;
+    KeyMetadataStore::remove(key_id, PENDING_SHARE_KEY, email.unwrap_or_default())
+}
+
+/// What reverting a refresh did on this node
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RevertOutcome {
+    /// The share the refresh replaced is the current one again
+    Restored,
+    /// The refreshed share was dropped before it replaced the current one
+    Discarded,
+    /// The node kept nothing of the session, or a later refresh replaced it
+    NothingToRevert,
+}
+
+/// Undoes the refresh `session_id` on this node
+pub(crate) fn revert<K>(
+    key_id: &str,
+    email: Option<&str>,
+    session_id: &str
+) -> Result<RevertOutcome>
+    where
+        K: CurrentKeyshareFormat + RefreshableShare,
+        <K as TryFrom<KeyshareFormat>>::Error: Display
+{
+    let _lock = session_manager::lock_key(key_id)?;
+    let metadata_email = email.unwrap_or_default();
+    let pending = retained(key_id, PENDING_SHARE_KEY, email)?;
+    if pending.is_some_and(|pending| pending.session_id == session_id) {
+        KeyMetadataStore::remove(key_id, PENDING_SHARE_KEY, metadata_email)?;
+        return Ok(RevertOutcome::Discarded);
+    }
+    let previous = retained(key_id, PREVIOUS_SHARE_KEY, email)?;
+    let Some(previous) = previous.filter(|previous| previous.session_id == session_id) else {
+        return Ok(RevertOutcome::NothingToRevert);
+    };
+    let mut accessor = modifiable::<K>(key_id, email)?;
+    accessor.key = serde_json::from_str::<K>(&previous.keyshare)?;
+    accessor.update_saved_key()?;
+    KeyMetadataStore::remove(key_id, PREVIOUS_SHARE_KEY, metadata_email)?;
+    Ok(RevertOutcome::Restored)
+}
+
+fn modifiable<K>(key_id: &str, email: Option<&str>) -> Result<KeyshareAccessor<K>>
+    where K: CurrentKeyshareFormat, <K as TryFrom<KeyshareFormat>>::Error: Display
+{
+    match email {
+        Some(email) => KeyshareAccessor::<K>::modifiable_with_email(key_id, email),
+        None => KeyshareAccessor::<K>::modifiable(key_id),
+    }
+}
+
+fn save<K: Serialize>(
+    key_id: &str,
+    metadata_type: &str,
+    email: Option<&str>,
+    session_id: &str,
+    keyshare: &K
+) -> Result<()> {
+    let retained = RetainedShare {
+        session_id: session_id.to_string(),
+        keyshare: serde_json::to_string(keyshare)?,
+    };
+    let content = Zeroizing::new(serde_json::to_string(&retained)?);
+    let email = email.unwrap_or_default();
+    KeyMetadataStore::save(&content, key_id, metadata_type, email, &WriteOpts::Modify)
+}
+
+fn retained(
+    key_id: &str,
+    metadata_type: &str,
+    email: Option<&str>
+) -> Result<Option<RetainedShare>> {
+    let email = email.unwrap_or_default();
+    let item = StorageItem::KeyMetadata { key_id, metadata_type, email };
+    if !storage_backend()?.exists(&item)? {
+        return Ok(None);
+    }
+    let content = Zeroizing::new(KeyMetadataStore::get(key_id, metadata_type, email)?);
+    Ok(Some(serde_json::from_str(&content)?))
+}
+
+/// Sent by the orchestrator of a refresh that did not complete on every node, some may have
+/// replaced their share already
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RevertShareRefreshCommand {
+    #[serde(flatten)]
+    pub kind: Key,
+    pub key_id: String,
+    pub session_id: String,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+impl JsonCommand for RevertShareRefreshCommand {
+    type Response = RevertOutcome;
+
+    fn access(&self) -> Access<'_> {
+        Access::Peer
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let email = self.email.as_deref();
+        let outcome = match self.kind {
+            Key::EDDSA => revert::<EDDSA>(&self.key_id, email, &self.session_id)?,
+            Key::Frost => revert::<Frost>(&self.key_id, email, &self.session_id)?,
+            Key::BLS => revert::<BLS>(&self.key_id, email, &self.session_id)?,
+            _ => bail!("Shares of {:?} keys can't be refreshed", self.kind),
+        };
+        info!("Reverted share refresh {} of key {}: {:?}", self.session_id, self.key_id, outcome);
+        Ok(outcome)
+    }
+}
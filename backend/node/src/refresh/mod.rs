@@ -0,0 +1,176 @@
+//! Proactive share refresh: the parties holding a key deal sharings of zero to each other and add
+//! them to their shares. The secret and the public key stay the same, shares taken before the
+//! refresh can't be combined with shares taken after it.
+//!
+//! NATS contract of `RefreshShares`:
+//! - the orchestrator publishes `NewShareRefreshSession` to
+//!   `network.gridlock.nodes.ShareRefresh.new.<node_id>` of every node of the key's pool
+//! - parties join on `network.gridlock.nodes.ShareRefresh.<session>.Join`, run the `Commit`,
+//!   `ZeroShare`, `Confirm` and `Saved` rounds and publish the public key on `...<session>.Result`
+//! - if not every party publishes the same result, the orchestrator sends
+//!   `RevertShareRefreshCommand` to `network.gridlock.nodes.async.Message.new.<node_id>` of every
+//!   node, see `generations`
+//!
+//! Only EdDSA, FROST and BLS keys can be refreshed, ECDSA shares are tied to the Paillier keys of
+//! their keygen and sr25519 shares are not Feldman shares. Every share dealt at keygen has to take
+//! part, ghost shares derived before a refresh no longer match the key after it.
+
+mod client;
+pub mod generations;
+pub mod orchestrate;
+pub mod session;
+
+use crate::command::{ JsonCommand, MsgContext };
+use crate::signing::Key;
+use crate::storage::{ Frost, BLS, EDDSA };
+use crate::tenant::{ Access, TenantAuth };
+use anyhow::Result;
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::{ Bls12_381_1, Curve, Ed25519, Scalar, Secp256k1 };
+use serde::{ Deserialize, Serialize };
+use shared::key_info::NodeId;
+
+/// Re-randomizes the shares of a key on every node of its pool
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RefreshSharesCommand {
+    #[serde(flatten)]
+    pub kind: Key,
+    pub key_id: String,
+    pub session_id: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Refreshes even if some nodes of the pool are quarantined
+    #[serde(default)]
+    pub allow_quarantined_peers: bool,
+    /// Required on multi user nodes
+    #[serde(default)]
+    pub authorization: Option<TenantAuth>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RefreshSharesResponse {
+    pub key_id: String,
+    /// Nodes whose shares were refreshed
+    pub party_nodes: Vec<NodeId>,
+    /// Unchanged public key of the refreshed key, hex encoded
+    pub public_key: String,
+}
+
+/// Published by every party once its refreshed share is saved
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RefreshResult {
+    pub public_key: String,
+    /// Digest of the refreshed commitments, the same for all parties
+    pub commitments_digest: String,
+}
+
+impl JsonCommand for RefreshSharesCommand {
+    type Response = RefreshSharesResponse;
+
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: self.authorization.as_ref(), key_ids: vec![self.key_id.clone()] }
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        orchestrate::orchestrate(self, ctx)
+    }
+}
+
+/// Keyshares made of a Feldman share and the commitments of every keygen dealing
+pub trait RefreshableShare {
+    type Curve: Curve;
+    const SCHEME: &'static str;
+
+    fn threshold(&self) -> usize;
+    fn party_index(&self) -> usize;
+    fn share(&self) -> (&Scalar<Self::Curve>, &[VerifiableSS<Self::Curve>]);
+    fn set_share(
+        &mut self,
+        x_i: Scalar<Self::Curve>,
+        vss_scheme_vec: Vec<VerifiableSS<Self::Curve>>
+    );
+    /// Compressed public key, hex encoded
+    fn public_key(&self) -> String;
+}
+
+impl RefreshableShare for EDDSA {
+    type Curve = Ed25519;
+    const SCHEME: &'static str = "eddsa";
+
+    fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    fn party_index(&self) -> usize {
+        self.party_index
+    }
+
+    fn share(&self) -> (&Scalar<Ed25519>, &[VerifiableSS<Ed25519>]) {
+        (&self.x_i, &self.vss_scheme_vec)
+    }
+
+    fn set_share(&mut self, x_i: Scalar<Ed25519>, vss_scheme_vec: Vec<VerifiableSS<Ed25519>>) {
+        self.x_i = x_i;
+        self.vss_scheme_vec = vss_scheme_vec;
+    }
+
+    fn public_key(&self) -> String {
+        hex::encode(&*self.y_sum.to_bytes(true))
+    }
+}
+
+impl RefreshableShare for Frost {
+    type Curve = Secp256k1;
+    const SCHEME: &'static str = "frost";
+
+    fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    fn party_index(&self) -> usize {
+        self.party_index
+    }
+
+    fn share(&self) -> (&Scalar<Secp256k1>, &[VerifiableSS<Secp256k1>]) {
+        (&self.x_i, &self.vss_scheme_vec)
+    }
+
+    fn set_share(&mut self, x_i: Scalar<Secp256k1>, vss_scheme_vec: Vec<VerifiableSS<Secp256k1>>) {
+        self.x_i = x_i;
+        self.vss_scheme_vec = vss_scheme_vec;
+    }
+
+    fn public_key(&self) -> String {
+        hex::encode(&*self.group_public_key.to_bytes(true))
+    }
+}
+
+impl RefreshableShare for BLS {
+    type Curve = Bls12_381_1;
+    const SCHEME: &'static str = "bls";
+
+    fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    fn party_index(&self) -> usize {
+        self.party_index
+    }
+
+    fn share(&self) -> (&Scalar<Bls12_381_1>, &[VerifiableSS<Bls12_381_1>]) {
+        (&self.x_i, &self.vss_scheme_vec)
+    }
+
+    fn set_share(
+        &mut self,
+        x_i: Scalar<Bls12_381_1>,
+        vss_scheme_vec: Vec<VerifiableSS<Bls12_381_1>>
+    ) {
+        self.x_i = x_i;
+        self.vss_scheme_vec = vss_scheme_vec;
+    }
+
+    fn public_key(&self) -> String {
+        hex::encode(&*self.public_key.to_bytes(true))
+    }
+}
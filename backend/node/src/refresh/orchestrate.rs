@@ -0,0 +1,267 @@
+use crate::command::{ MsgContext, TaggedCommandType };
+use crate::communication::blocking;
+use crate::communication::envelope;
+use crate::communication::nats::{ BroadcastMessage, JoinMessage, JoinResponse };
+use crate::node::NodeIdentity;
+use crate::refresh::generations::RevertShareRefreshCommand;
+use crate::refresh::session::NewShareRefreshSession;
+use crate::refresh::{ RefreshResult, RefreshSharesCommand, RefreshSharesResponse };
+use crate::replay;
+use crate::reputation;
+use crate::signing::Key;
+use crate::storage::key_listing::list_keys;
+use crate::storage::key_protocol::KeyProtocol;
+use crate::storage::KeyInfoStore;
+use anyhow::{ bail, Context, Result };
+use chrono::Utc;
+use itertools::Itertools;
+use shared::key_info::{ Key as KeyInfoKind, KeyInfo, Node, NodeId };
+use std::env;
+use std::thread;
+use std::time::Duration;
+use tracing::{ error, info, instrument, warn };
+
+const REFRESH_INTERVAL_VAR: &str = "SHARE_REFRESH_INTERVAL_DAYS";
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+const RESULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[instrument(skip_all)]
+pub fn orchestrate(cmd: RefreshSharesCommand, ctx: MsgContext) -> Result<RefreshSharesResponse> {
+    if let Some(email) = &cmd.email {
+        ctx.ensure_account(email)?;
+    }
+    let app = ctx.get_app()?;
+    refresh_key(&app.nc, cmd)
+}
+
+fn refresh_key(
+    nc: &blocking::Connection,
+    cmd: RefreshSharesCommand
+) -> Result<RefreshSharesResponse> {
+    let key_info = KeyInfoStore::get_key_info(&cmd.key_id).with_context(|| {
+        format!("Key info is not found - key_id: {}", cmd.key_id)
+    })?;
+    check_refreshable(&cmd, &key_info)?;
+    let party_nodes = key_info.node_pool
+        .iter()
+        .map(|node| node.node_id.clone())
+        .collect::<Vec<NodeId>>();
+    reputation::check_parties(&party_nodes, cmd.allow_quarantined_peers)?;
+
+    let subject = |name: &str| {
+        format!("network.gridlock.nodes.ShareRefresh.{}.{}", cmd.session_id, name)
+    };
+    let join_sub = nc.subscribe(&subject("Join"))?;
+    let result_sub = nc.subscribe(&subject("Result"))?;
+
+    let session_message = replay::sign_session_message(
+        &(NewShareRefreshSession {
+            kind: cmd.kind.clone(),
+            key_id: cmd.key_id.clone(),
+            session_id: cmd.session_id.clone(),
+            email: cmd.email.clone(),
+            timestamp: Some(Utc::now().to_rfc3339()),
+            origin: None,
+        }),
+        &NodeIdentity::cached()?
+    )?;
+    for node_id in &party_nodes {
+        let subject = format!("network.gridlock.nodes.ShareRefresh.new.{node_id}");
+        nc.publish(&subject, &session_message)?;
+    }
+
+    let mut msg_vec = Vec::new();
+    let mut joins = Vec::new();
+    for _ in 0..party_nodes.len() {
+        let next = match join_sub.next_timeout(JOIN_TIMEOUT) {
+            Ok(next) => next,
+            Err(err) => {
+                let joined = |node_id: &NodeId| {
+                    joins.iter().any(|join: &JoinMessage| &join.node_id == node_id)
+                };
+                let missing = party_nodes
+                    .iter()
+                    .filter(|node_id| !joined(node_id))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                reputation::record_timeout(&missing, "did not join a share refresh");
+                return Err(err).context("Waiting for every node of the key to join the refresh");
+            }
+        };
+        joins.push(serde_json::from_slice::<JoinMessage>(&next.data)?);
+        msg_vec.push(next);
+    }
+    for join in &joins {
+        // Parties seal their rounds to the keys in the join response, they must be the pool's
+        let known = key_info.node_pool
+            .iter()
+            .any(|node| {
+                node.node_id == join.node_id &&
+                    node.share_index == join.party_index &&
+                    node.networking_public_key == join.networking_public_key
+            });
+        if !known {
+            bail!("Node {} joined the refresh with a share it doesn't hold", join.node_id);
+        }
+    }
+    let join_resp = JoinResponse::new(&joins);
+    for m in msg_vec.iter() {
+        if let Err(err) = m.respond(serde_json::to_string(&join_resp)?) {
+            error!("Error: {}", err);
+        }
+    }
+    nc.flush()?;
+
+    let outcome = collect_results(&result_sub, party_nodes.len()).and_then(|results| {
+        if !results.iter().all_equal() {
+            bail!("Parties ended the refresh of key {} with different results", cmd.key_id);
+        }
+        Ok(results)
+    });
+    let results = match outcome {
+        Ok(results) => results,
+        Err(err) => {
+            // Parties that got through the `Saved` round may have replaced their share already
+            request_revert(nc, &cmd, &party_nodes);
+            return Err(err);
+        }
+    };
+    info!("Refreshed the shares of key {} on {} nodes", cmd.key_id, party_nodes.len());
+    Ok(RefreshSharesResponse {
+        key_id: cmd.key_id,
+        party_nodes,
+        public_key: results[0].public_key.clone(),
+    })
+}
+
+fn collect_results(
+    result_sub: &blocking::Subscription,
+    party_count: usize
+) -> Result<Vec<RefreshResult>> {
+    let mut results = Vec::new();
+    for _ in 0..party_count {
+        let res = result_sub
+            .next_timeout(RESULT_TIMEOUT)
+            .context("Waiting for share refresh results")?;
+        results.push(envelope::decode::<BroadcastMessage<RefreshResult>>(&res.data)?.message);
+    }
+    Ok(results)
+}
+
+/// Has every node put back the share it held before the refresh
+fn request_revert(nc: &blocking::Connection, cmd: &RefreshSharesCommand, party_nodes: &[NodeId]) {
+    let request = TaggedCommandType::RevertShareRefresh(RevertShareRefreshCommand {
+        kind: cmd.kind.clone(),
+        key_id: cmd.key_id.clone(),
+        session_id: cmd.session_id.clone(),
+        email: cmd.email.clone(),
+    });
+    let request = match serde_json::to_string(&request) {
+        Ok(request) => request,
+        Err(err) => {
+            error!("Failed to serialize the revert of refresh {}: {}", cmd.session_id, err);
+            return;
+        }
+    };
+    for node_id in party_nodes {
+        let subject = format!("network.gridlock.nodes.async.Message.new.{node_id}");
+        if let Err(err) = nc.publish(&subject, &request) {
+            error!("Failed to ask node {} to revert refresh {}: {}", node_id, cmd.session_id, err);
+        }
+    }
+    warn!("Asked every node of key {} to revert refresh {}", cmd.key_id, cmd.session_id);
+}
+
+fn check_refreshable(cmd: &RefreshSharesCommand, key_info: &KeyInfo) -> Result<()> {
+    match (&cmd.kind, &key_info.kind) {
+        | (Key::EDDSA, KeyInfoKind::EDDSA { .. })
+        | (Key::Frost, KeyInfoKind::Frost { .. })
+        | (Key::BLS, KeyInfoKind::BLS { .. }) => (),
+        (Key::ECDSA | Key::Sr25519, _) => {
+            bail!("Shares of {:?} keys can't be refreshed", cmd.kind);
+        }
+        _ => bail!("Key {} is not a {:?} key", cmd.key_id, cmd.kind),
+    }
+    if
+        !key_info.node_pool
+            .iter()
+            .map(|node| node.node_id.to_string())
+            .all_unique()
+    {
+        bail!("Key {} has more than one share on a node, they can't be refreshed", cmd.key_id);
+    }
+    Ok(())
+}
+
+/// Refreshes the keys this node owns every `SHARE_REFRESH_INTERVAL_DAYS`, counted from the start
+/// of the node. Not started when the variable is unset.
+pub fn spawn_refresh_scheduler(nc: blocking::Connection, node_id: String) -> Result<()> {
+    let days = match env::var(REFRESH_INTERVAL_VAR) {
+        Ok(days) =>
+            days.parse::<u64>().with_context(|| format!("{} is not a number of days", days))?,
+        Err(_) => {
+            return Ok(());
+        }
+    };
+    if days == 0 {
+        bail!("{} must be at least one day", REFRESH_INTERVAL_VAR);
+    }
+    let interval = Duration::from_secs(days * 24 * 60 * 60);
+    thread::Builder
+        ::new()
+        .name("share-refresh".to_string())
+        .spawn(move || {
+            loop {
+                thread::sleep(interval);
+                refresh_owned_keys(&nc, &node_id);
+            }
+        })?;
+    Ok(())
+}
+
+fn refresh_owned_keys(nc: &blocking::Connection, node_id: &str) {
+    let keys = match list_keys(None) {
+        Ok(keys) => keys,
+        Err(err) => {
+            warn!("Failed to list the keys to refresh: {}", err);
+            return;
+        }
+    };
+    for listing in keys {
+        let kind = match listing.protocol {
+            KeyProtocol::EdDSA => Key::EDDSA,
+            KeyProtocol::Frost => Key::Frost,
+            KeyProtocol::BLS => Key::BLS,
+            _ => {
+                continue;
+            }
+        };
+        if !listing.has_key_info || !is_owner(&listing.key_id, node_id) {
+            continue;
+        }
+        let cmd = RefreshSharesCommand {
+            kind,
+            session_id: format!("refresh-{}-{}", listing.key_id, Utc::now().timestamp()),
+            key_id: listing.key_id.clone(),
+            email: listing.email,
+            allow_quarantined_peers: false,
+            authorization: None,
+        };
+        match refresh_key(nc, cmd) {
+            Ok(_) => info!("Scheduled refresh of key {} completed", listing.key_id),
+            Err(err) => warn!("Scheduled refresh of key {} failed: {}", listing.key_id, err),
+        }
+    }
+}
+
+fn is_owner(key_id: &str, node_id: &str) -> bool {
+    match KeyInfoStore::get_key_info(key_id) {
+        Ok(key_info) =>
+            key_info.node_pool
+                .iter()
+                .any(|node| {
+                    matches!(node.kind, Node::Owner) && node.node_id.to_string() == node_id
+                }),
+        Err(_) => false,
+    }
+}
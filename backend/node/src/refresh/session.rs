@@ -0,0 +1,168 @@
+use crate::audit::{ AuditAction, AuditedRequest };
+use crate::communication::incoming::IncomingMessage;
+use crate::communication::nats::{
+    BaseMessenger,
+    NatsBaseMessenger,
+    NatsBaseSession,
+    NatsPeerMessenger,
+};
+use crate::communication::protocol::{ ShareRefreshAllRounds, Topic };
+use crate::keygen::ShareParams;
+use crate::metrics::{ self, SessionKind };
+use crate::node::NodeIdentity;
+use crate::refresh::client::ShareRefreshClient;
+use crate::refresh::{ generations, RefreshResult, RefreshableShare };
+use crate::replay::GuardianOrigin;
+use crate::session_error::{ self, SessionErrorCode };
+use crate::session_manager;
+use crate::signing::Key;
+use crate::storage::{ CurrentKeyshareFormat, KeyshareAccessor, KeyshareFormat };
+use crate::storage::{ Frost, BLS, EDDSA };
+use crate::strict;
+use crate::App;
+use anyhow::{ anyhow, Result };
+use serde::{ Deserialize, Serialize };
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::time::Duration;
+use tracing::{ error, info };
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct NewShareRefreshSession {
+    #[serde(flatten)]
+    pub kind: Key,
+    pub key_id: String,
+    pub session_id: String,
+    pub email: Option<String>,
+    pub timestamp: Option<String>,
+    /// Signature of the orchestrating guardian, see `replay::sign_session_message`
+    #[serde(default)]
+    pub origin: Option<GuardianOrigin>,
+}
+
+pub fn handle_new_session_message(app: &App, message: IncomingMessage) {
+    let session = match strict::from_slice::<NewShareRefreshSession>(&message.data[..]) {
+        Ok(session) => session,
+        Err(err) => {
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Incorrect share refresh message format: {}", err)
+            );
+            return;
+        }
+    };
+
+    let nc = app.nc.client().clone();
+    let session_id = session.session_id.clone();
+    let task_session_id = session_id.clone();
+    session_manager::spawn_session(SessionKind::Refresh, &session_id, async move {
+        let result = match session.kind {
+            Key::EDDSA => refresh_session::<EDDSA>(nc, &session).await,
+            Key::Frost => refresh_session::<Frost>(nc, &session).await,
+            Key::BLS => refresh_session::<BLS>(nc, &session).await,
+            _ => Err(anyhow!("Shares of {:?} keys can't be refreshed", session.kind)),
+        };
+        match result {
+            Ok(()) => {
+                info!("Share refresh was successful for session id {}", &task_session_id);
+                metrics::session_completed(SessionKind::Refresh);
+            }
+            Err(err) => {
+                metrics::session_failed(SessionKind::Refresh);
+                error!("Share refresh failed: session id: {}, error: {}", &task_session_id, err);
+            }
+        }
+    });
+    info!("Spawned a task to handle share refresh");
+}
+
+async fn refresh_session<K>(
+    conn: async_nats::Client,
+    session: &NewShareRefreshSession
+) -> Result<()>
+    where
+        K: CurrentKeyshareFormat + RefreshableShare + Clone,
+        <K as TryFrom<KeyshareFormat>>::Error: Display
+{
+    let audit = AuditedRequest::new(
+        AuditAction::ShareRefresh,
+        K::SCHEME,
+        &session.key_id,
+        &session.session_id,
+        session.email.as_deref()
+    );
+    let result = refresh_session_inner::<K>(conn, session).await;
+    audit.record(&result);
+    result
+}
+
+async fn refresh_session_inner<K>(
+    conn: async_nats::Client,
+    session: &NewShareRefreshSession
+) -> Result<()>
+    where
+        K: CurrentKeyshareFormat + RefreshableShare + Clone,
+        <K as TryFrom<KeyshareFormat>>::Error: Display
+{
+    let key_id = &session.key_id;
+    let keyshare = match &session.email {
+        Some(email) => KeyshareAccessor::<K>::read_only_with_email(key_id, email)?.key,
+        None => KeyshareAccessor::<K>::read_only(key_id)?.key,
+    };
+    let party_index = keyshare.party_index();
+    info!("Joining share refresh of key {} as party {}", key_id, party_index);
+
+    let node = NodeIdentity::cached()?;
+    let nats_session = NatsBaseSession {
+        session_id: session.session_id.clone(),
+        thread_index: 0,
+        node_id: node.node_id.to_string(),
+        public_key: node.networking_public_key,
+        party_index,
+    };
+    let messenger = NatsBaseMessenger::<ShareRefreshAllRounds>::new(
+        Topic::ShareRefresh,
+        conn,
+        nats_session
+    ).await?;
+    let join_response = messenger.wait_for_confirmation(Duration::from_secs(10)).await?;
+
+    let party_count = join_response.party_count;
+    let mut all_party_indices = join_response.all_party_indices;
+    all_party_indices.sort();
+
+    let peer_messenger = NatsPeerMessenger::from(
+        messenger,
+        party_count,
+        all_party_indices.clone()
+    )?;
+    let client = ShareRefreshClient {
+        peer_messenger,
+        share_params: ShareParams {
+            threshold: keyshare.threshold(),
+            party_count,
+            party_index,
+        },
+        all_party_indices,
+    };
+
+    let (x_i, vss_scheme_vec) = keyshare.share();
+    let refreshed = client.refresh(x_i, vss_scheme_vec).await?;
+
+    // The current share is only replaced once every party saved its refreshed one, a party that
+    // fails before keeps the share the others still hold too
+    let email = session.email.as_deref();
+    let mut refreshed_keyshare = keyshare.clone();
+    refreshed_keyshare.set_share(refreshed.x_i, refreshed.vss_scheme_vec);
+    generations::save_pending(key_id, email, &session.session_id, &refreshed_keyshare)?;
+    client.confirm_saved(&refreshed.commitments_digest).await?;
+    generations::commit::<K>(key_id, email, &session.session_id, keyshare.share().0)?;
+    info!("Saved the refreshed share of key {}", key_id);
+
+    client.publish_result(RefreshResult {
+        public_key: keyshare.public_key(),
+        commitments_digest: refreshed.commitments_digest,
+    }).await
+}
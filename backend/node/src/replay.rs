@@ -0,0 +1,360 @@
+//! Replay protection of the messages that start sessions. Every one has to carry an RFC 3339
+//! `timestamp` within the replay window of the node's clock and a proof of where it comes from:
+//! - Key generation and user recovery requests come from the client, with a `message_hmac` over
+//!   timestamp and email. Keygen requests make it with the signing key they send encrypted to the
+//!   node, user recovery requests with the recovery key they send and confirmations with the
+//!   recovery key the node stored for the account.
+//! - Share recovery and refresh sessions are started by a guardian of the key, which signs the
+//!   message with its networking key in `origin`. Recovery helpers also accept the nodes the
+//!   session recovers shares for, a new device orchestrates its own recovery.
+//! - Every session is only started once: its session id, or the proof of requests without one, is
+//!   remembered for twice the window, also across restarts.
+//!
+//! Signing handlers check timestamp and HMAC of their requests themselves.
+//! `ALLOW_UNAUTHENTICATED_SESSIONS=true` accepts messages without timestamp and proof while the
+//! hub and the guardians of a pool are upgraded, it is meant to be removed once they are.
+
+use crate::auth::client_e2e_decrypt_secret;
+use crate::metrics::SessionKind;
+use crate::node::NodeIdentity;
+use crate::signing::validation::verify_hmac;
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::storage::KeyInfoStore;
+use crate::MessageRoute;
+use anyhow::{ anyhow, bail, Result };
+use chrono::{ DateTime, Duration, Utc };
+use nkeys::KeyPair;
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use shared::recovery::PublicKeysEnum;
+use std::collections::btree_map::Entry;
+use std::collections::{ BTreeMap, HashMap };
+use std::env;
+use std::sync::Mutex;
+use tracing::warn;
+use zeroize::Zeroizing;
+
+const WINDOW_VAR: &str = "SESSION_REPLAY_WINDOW_SECS";
+const DEFAULT_WINDOW_SECS: i64 = 300;
+/// Migration only, accepts session messages without timestamp and proof of origin
+const ALLOW_UNAUTHENTICATED_VAR: &str = "ALLOW_UNAUTHENTICATED_SESSIONS";
+const SEEN_SESSIONS_KEY: &str = "seen_sessions";
+
+/// Loaded from storage on the first session message
+static SEEN_SESSIONS: Mutex<Option<SeenSessions>> = Mutex::new(None);
+
+/// Sessions started recently, by id with when they were started
+#[derive(Default, Serialize, Deserialize)]
+struct SeenSessions(BTreeMap<String, DateTime<Utc>>);
+
+impl SeenSessions {
+    fn load() -> Result<Self> {
+        match KeyMetadataStore::get_node_level(SEEN_SESSIONS_KEY)? {
+            Some(content) => Ok(serde_json::from_str(&content)?),
+            None => Ok(SeenSessions::default()),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let content = serde_json::to_string(self)?;
+        KeyMetadataStore::save_node_level(&content, SEEN_SESSIONS_KEY, &WriteOpts::Modify)
+    }
+
+    /// Remembers the id and forgets those older than `retention`, false when it was seen before
+    fn insert(&mut self, id: String, now: DateTime<Utc>, retention: Duration) -> bool {
+        self.0.retain(|_, seen| now - *seen <= retention);
+        match self.0.entry(id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+}
+
+/// Signature of the guardian that started a session, over the message without this field
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GuardianOrigin {
+    pub networking_public_key: String,
+    /// Base64 signature of the canonical JSON of the message
+    pub signature: String,
+}
+
+/// Fields of a session message the checks look at, whatever its type
+#[derive(Deserialize)]
+struct SessionFields {
+    session_id: Option<String>,
+    key_id: Option<String>,
+    timestamp: Option<String>,
+    message_hmac: Option<String>,
+    email: Option<String>,
+    client_e2e_public_key: Option<String>,
+    encrypted_signing_key: Option<String>,
+    encrypted_recovery_key: Option<String>,
+    origin: Option<GuardianOrigin>,
+    public_keys: Option<PublicKeysEnum>,
+}
+
+/// Serializes a session message this node starts, signed with its networking key. The message
+/// has to carry its `timestamp` and leave `origin` empty.
+pub fn sign_session_message<T: Serialize>(message: &T, node: &NodeIdentity) -> Result<String> {
+    let mut value = serde_json::to_value(message)?;
+    let fields = value
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("Session messages are JSON objects"))?;
+    fields.remove("origin");
+    let signature = KeyPair::from_seed(&node.networking_private_key)?.sign(
+        canonical_json(&value).as_bytes()
+    )?;
+    let origin = GuardianOrigin {
+        networking_public_key: node.networking_public_key.clone(),
+        signature: base64::encode(signature),
+    };
+    if let Some(fields) = value.as_object_mut() {
+        fields.insert("origin".to_string(), serde_json::to_value(origin)?);
+    }
+    Ok(serde_json::to_string(&value)?)
+}
+
+/// Refuses a replayed or unauthenticated session message before its handler runs
+pub fn check_session_message(route: MessageRoute, subject: &str, data: &[u8]) -> Result<()> {
+    // Malformed messages are reported by the session handler
+    let value = match serde_json::from_slice::<Value>(data) {
+        Ok(value) => value,
+        Err(_) => {
+            return Ok(());
+        }
+    };
+    let fields = match serde_json::from_value::<SessionFields>(value.clone()) {
+        Ok(fields) => fields,
+        Err(_) => {
+            return Ok(());
+        }
+    };
+    // Signing handlers check timestamp and HMAC themselves
+    let proof = if route.session_kind() == Some(SessionKind::Signing) {
+        None
+    } else {
+        check_origin(route, &fields, &value, allow_unauthenticated())?
+    };
+    let id = match (fields.session_id, proof) {
+        (Some(id), _) => id,
+        (None, Some(proof)) => proof,
+        (None, None) => {
+            return Ok(());
+        }
+    };
+    remember_session(format!("{} {}", subject, id))
+}
+
+fn allow_unauthenticated() -> bool {
+    env::var(ALLOW_UNAUTHENTICATED_VAR).is_ok_and(|value| value == "true")
+}
+
+/// Checks timestamp and proof of the message, returns the proof
+fn check_origin(
+    route: MessageRoute,
+    fields: &SessionFields,
+    value: &Value,
+    allow_unauthenticated: bool
+) -> Result<Option<String>> {
+    let timestamp = match &fields.timestamp {
+        Some(timestamp) => timestamp,
+        None if allow_unauthenticated => {
+            warn!(
+                "Accepting a session message without timestamp, {} is set",
+                ALLOW_UNAUTHENTICATED_VAR
+            );
+            return Ok(None);
+        }
+        None => bail!("Session message has no timestamp"),
+    };
+    check_fresh(timestamp, Utc::now(), replay_window())?;
+
+    match route {
+        MessageRoute::KeyShareRecovery | MessageRoute::ShareRefresh => {
+            match &fields.origin {
+                Some(origin) => check_guardian_signature(route, fields, origin, value).map(Some),
+                None if allow_unauthenticated => Ok(None),
+                None => bail!("Session message is not signed by a guardian of the key"),
+            }
+        }
+        _ =>
+            match &fields.message_hmac {
+                Some(hmac) => {
+                    let key = hmac_key(route, fields)?;
+                    let email = fields.email.as_deref().unwrap_or_default();
+                    if !verify_hmac(hmac, timestamp, email, &key) {
+                        bail!("Session message HMAC verification failed");
+                    }
+                    Ok(Some(hmac.clone()))
+                }
+                None if allow_unauthenticated => Ok(None),
+                None => bail!("Session message has no HMAC"),
+            }
+    }
+}
+
+/// Key the client made the HMAC of its request with
+fn hmac_key(route: MessageRoute, fields: &SessionFields) -> Result<Zeroizing<String>> {
+    if route == MessageRoute::UserRecoveryConfirm {
+        let email = fields.email
+            .as_deref()
+            .ok_or_else(|| anyhow!("Recovery confirmation has no email"))?;
+        return KeyMetadataStore::get_user_level("recovery", email).map(Zeroizing::new);
+    }
+    let encrypted_key = match route {
+        MessageRoute::UserRecovery => &fields.encrypted_recovery_key,
+        _ => &fields.encrypted_signing_key,
+    };
+    let (encrypted_key, client_public_key) = match
+        (encrypted_key, &fields.client_e2e_public_key)
+    {
+        (Some(key), Some(client_public_key)) => (key, client_public_key),
+        _ => bail!("Session message HMAC can't be verified without the client's key"),
+    };
+    let node = NodeIdentity::cached()?;
+    client_e2e_decrypt_secret(encrypted_key, &node.e2e_private_key, client_public_key).map_err(
+        |err| anyhow!("Failed to decrypt the client's key: {}", err)
+    )
+}
+
+/// Checks the signature is made by a guardian of the key, returns it
+fn check_guardian_signature(
+    route: MessageRoute,
+    fields: &SessionFields,
+    origin: &GuardianOrigin,
+    value: &Value
+) -> Result<String> {
+    let key_id = fields.key_id.as_deref().ok_or_else(|| anyhow!("Session message has no key id"))?;
+    let key_info = KeyInfoStore::get_key_info(key_id)?;
+    let mut signers = key_info.node_pool
+        .iter()
+        .map(|node| node.networking_public_key.clone())
+        .collect::<Vec<_>>();
+    if let (MessageRoute::KeyShareRecovery, Some(public_keys)) = (route, &fields.public_keys) {
+        signers.extend(HashMap::<usize, String>::from(public_keys.clone()).into_values());
+    }
+    if !signers.contains(&origin.networking_public_key) {
+        bail!("Session message is signed by a node that is no guardian of key {}", key_id);
+    }
+    let mut unsigned = value.clone();
+    if let Some(fields) = unsigned.as_object_mut() {
+        fields.remove("origin");
+    }
+    let signature = base64::decode(&origin.signature)?;
+    KeyPair::from_public_key(&origin.networking_public_key)?
+        .verify(canonical_json(&unsigned).as_bytes(), &signature)
+        .map_err(|_| anyhow!("Session message signature is invalid"))?;
+    Ok(origin.signature.clone())
+}
+
+/// JSON with the fields of every object sorted, the same however the message was serialized
+fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Object(fields) => {
+            let sorted = fields.iter().collect::<BTreeMap<_, _>>();
+            let fields = sorted
+                .into_iter()
+                .map(|(name, value)| {
+                    format!("{}:{}", Value::from(name.as_str()), canonical_json(value))
+                })
+                .collect::<Vec<_>>();
+            format!("{{{}}}", fields.join(","))
+        }
+        Value::Array(items) => {
+            let items = items.iter().map(canonical_json).collect::<Vec<_>>();
+            format!("[{}]", items.join(","))
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Fails for a session started before. Remembered sessions are stored, a message replayed after a
+/// restart is refused as well.
+fn remember_session(id: String) -> Result<()> {
+    let mut seen = SEEN_SESSIONS.lock().unwrap();
+    let mut sessions = match seen.take() {
+        Some(sessions) => sessions,
+        None => SeenSessions::load()?,
+    };
+    // Timestamps are accepted up to the window ahead of the node's clock
+    let fresh = sessions.insert(id.clone(), Utc::now(), replay_window() * 2);
+    let saved = if fresh { sessions.save() } else { Ok(()) };
+    *seen = Some(sessions);
+    if !fresh {
+        bail!("Session {} was already started by a message on this subject", id);
+    }
+    saved
+}
+
+fn replay_window() -> Duration {
+    let secs = match env::var(WINDOW_VAR).map(|value| value.parse()) {
+        Ok(Ok(secs)) => secs,
+        _ => DEFAULT_WINDOW_SECS,
+    };
+    Duration::seconds(secs)
+}
+
+fn check_fresh(timestamp: &str, now: DateTime<Utc>, window: Duration) -> Result<()> {
+    let timestamp = DateTime::parse_from_rfc3339(timestamp)
+        .map_err(|err| anyhow!("Failed to parse session message timestamp: {}", err))?
+        .with_timezone(&Utc);
+    if (now - timestamp).abs() > window {
+        bail!(
+            "Session message timestamp {} is outside the replay window of {}s",
+            timestamp,
+            window.num_seconds()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(json: &str) -> (SessionFields, Value) {
+        let value: Value = serde_json::from_str(json).unwrap();
+        (serde_json::from_value(value.clone()).unwrap(), value)
+    }
+
+    #[test]
+    fn refuses_stale_and_repeated_messages() {
+        let now = Utc::now();
+        let window = Duration::seconds(300);
+        assert!(check_fresh(&now.to_rfc3339(), now, window).is_ok());
+        assert!(check_fresh(&(now - Duration::seconds(301)).to_rfc3339(), now, window).is_err());
+        assert!(check_fresh(&(now + Duration::seconds(301)).to_rfc3339(), now, window).is_err());
+
+        let mut seen = SeenSessions::default();
+        let id = "network.gridlock.nodes.KeyShareRecovery.new.node-1 session-1".to_string();
+        assert!(seen.insert(id.clone(), now, window * 2));
+        assert!(!seen.insert(id.clone(), now + Duration::seconds(599), window * 2));
+        // Forgotten once no timestamp in the window can be accepted anymore
+        assert!(seen.insert(id, now + Duration::seconds(601), window * 2));
+    }
+
+    #[test]
+    fn requires_timestamp_and_proof_unless_allowed() {
+        let (unsigned, value) = fields(r#"{"session_id":"session-1","key_id":"key-1"}"#);
+        let route = MessageRoute::KeyShareRecovery;
+        assert!(check_origin(route, &unsigned, &value, false).is_err());
+        assert_eq!(check_origin(route, &unsigned, &value, true).unwrap(), None);
+
+        let json = format!(r#"{{"key_id":"key-1","timestamp":"{}"}}"#, Utc::now().to_rfc3339());
+        let (no_hmac, value) = fields(&json);
+        assert!(check_origin(MessageRoute::KeyGenECDSA, &no_hmac, &value, false).is_err());
+        assert!(check_origin(MessageRoute::ShareRefresh, &no_hmac, &value, false).is_err());
+    }
+
+    #[test]
+    fn canonical_json_ignores_field_order() {
+        let first: Value = serde_json::from_str(r#"{"b":1,"a":{"d":[1,2],"c":"x"}}"#).unwrap();
+        let second: Value = serde_json::from_str(r#"{"a":{"c":"x","d":[1,2]},"b":1}"#).unwrap();
+        assert_eq!(canonical_json(&first), canonical_json(&second));
+        assert_eq!(canonical_json(&first), r#"{"a":{"c":"x","d":[1,2]},"b":1}"#);
+    }
+}
@@ -0,0 +1,198 @@
+//! Reputation of the peers this node orchestrated sessions with. A peer blamed by the blame
+//! protocol of a session, or that timed out `TIMEOUTS_TO_QUARANTINE` times, is quarantined: the
+//! node refuses to start sessions with it until `PEER_QUARANTINE_DAYS` passed since its last
+//! incident, unless the command overrides the quarantine.
+
+use crate::command::{ JsonCommand, MsgContext };
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::tenant::Access;
+use anyhow::{ bail, Context, Result };
+use chrono::{ DateTime, Duration, Utc };
+use serde::{ Deserialize, Serialize };
+use shared::key_info::NodeId;
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Mutex;
+use tracing::warn;
+
+const REPUTATION_KEY: &str = "peer_reputation";
+const QUARANTINE_DAYS_VAR: &str = "PEER_QUARANTINE_DAYS";
+const DEFAULT_QUARANTINE_DAYS: i64 = 7;
+const TIMEOUTS_TO_QUARANTINE: u32 = 3;
+
+/// Serializes the updates of the stored reputation
+static REPUTATION_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct PeerRecord {
+    pub blame_count: u32,
+    pub timeout_count: u32,
+    pub last_incident: Option<DateTime<Utc>>,
+    pub last_reason: Option<String>,
+}
+
+impl PeerRecord {
+    fn is_quarantined(&self, now: DateTime<Utc>, period: Duration) -> bool {
+        let recent = self.last_incident.is_some_and(|incident| now - incident < period);
+        recent && (self.blame_count > 0 || self.timeout_count >= TIMEOUTS_TO_QUARANTINE)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Incident {
+    Blamed,
+    TimedOut,
+}
+
+/// Records by node id
+type Reputation = BTreeMap<String, PeerRecord>;
+
+fn load() -> Result<Reputation> {
+    match KeyMetadataStore::get_node_level(REPUTATION_KEY)? {
+        Some(content) => Ok(serde_json::from_str(&content)?),
+        None => Ok(Reputation::new()),
+    }
+}
+
+fn quarantine_period() -> Result<Duration> {
+    let days = match env::var(QUARANTINE_DAYS_VAR) {
+        Ok(days) => days.parse::<i64>().with_context(|| format!("{} is not a number", days))?,
+        Err(_) => DEFAULT_QUARANTINE_DAYS,
+    };
+    Ok(Duration::days(days))
+}
+
+fn apply(reputation: &mut Reputation, node_id: &str, incident: Incident, reason: &str) {
+    let record = reputation.entry(node_id.to_string()).or_default();
+    match incident {
+        Incident::Blamed => {
+            record.blame_count += 1;
+        }
+        Incident::TimedOut => {
+            record.timeout_count += 1;
+        }
+    }
+    record.last_incident = Some(Utc::now());
+    record.last_reason = Some(reason.to_string());
+}
+
+/// A failure to write is logged, the session already failed
+fn record(node_ids: &[NodeId], incident: Incident, reason: &str) {
+    if node_ids.is_empty() {
+        return;
+    }
+    let _lock = REPUTATION_LOCK.lock().unwrap();
+    let recorded = load().and_then(|mut reputation| {
+        for node_id in node_ids {
+            apply(&mut reputation, &node_id.to_string(), incident, reason);
+        }
+        let content = serde_json::to_string(&reputation)?;
+        KeyMetadataStore::save_node_level(&content, REPUTATION_KEY, &WriteOpts::Modify)
+    });
+    if let Err(err) = recorded {
+        warn!("Failed to record {:?} of peers {:?}: {}", incident, node_ids, err);
+    }
+}
+
+/// Peers the blame protocol of a session found misbehaving
+pub fn record_blame(node_ids: &[NodeId], reason: &str) {
+    record(node_ids, Incident::Blamed, reason)
+}
+
+/// Peers that did not answer a session in time
+pub fn record_timeout(node_ids: &[NodeId], reason: &str) {
+    record(node_ids, Incident::TimedOut, reason)
+}
+
+/// Fails with the quarantined parties of a session about to start, unless `allow_quarantined`
+pub fn check_parties(party_nodes: &[NodeId], allow_quarantined: bool) -> Result<()> {
+    let reputation = load()?;
+    let period = quarantine_period()?;
+    let now = Utc::now();
+    let quarantined = party_nodes
+        .iter()
+        .map(|node_id| node_id.to_string())
+        .filter(|node_id| {
+            reputation.get(node_id).is_some_and(|record| record.is_quarantined(now, period))
+        })
+        .collect::<Vec<_>>();
+    if quarantined.is_empty() {
+        return Ok(());
+    }
+    if allow_quarantined {
+        warn!("Starting a session with quarantined peers {}", quarantined.join(", "));
+        return Ok(());
+    }
+    bail!(
+        "Peers {} are quarantined, set allow_quarantined_peers to start the session anyway",
+        quarantined.join(", ")
+    );
+}
+
+/// Reputation of every peer this node recorded an incident of, or of a single one
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetPeerReputationCommand {
+    #[serde(default)]
+    pub node_id: Option<NodeId>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct PeerReputation {
+    pub node_id: String,
+    #[serde(flatten)]
+    pub record: PeerRecord,
+    pub quarantined: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct PeerReputationResponse {
+    pub peers: Vec<PeerReputation>,
+}
+
+impl JsonCommand for GetPeerReputationCommand {
+    type Response = PeerReputationResponse;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let period = quarantine_period()?;
+        let now = Utc::now();
+        let requested = self.node_id.map(|node_id| node_id.to_string());
+        let peers = load()?
+            .into_iter()
+            .filter(|(node_id, _)| requested.is_none() || requested.as_ref() == Some(node_id))
+            .map(|(node_id, record)| PeerReputation {
+                node_id,
+                quarantined: record.is_quarantined(now, period),
+                record,
+            })
+            .collect();
+        Ok(PeerReputationResponse { peers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarantines_blamed_and_repeatedly_timed_out_peers() {
+        let period = Duration::days(DEFAULT_QUARANTINE_DAYS);
+        let mut reputation = Reputation::new();
+        apply(&mut reputation, "blamed", Incident::Blamed, "phase 5");
+        for _ in 0..TIMEOUTS_TO_QUARANTINE - 1 {
+            apply(&mut reputation, "slow", Incident::TimedOut, "join");
+        }
+        let now = Utc::now();
+        assert!(reputation["blamed"].is_quarantined(now, period));
+        assert!(!reputation["slow"].is_quarantined(now, period));
+
+        apply(&mut reputation, "slow", Incident::TimedOut, "join");
+        assert!(reputation["slow"].is_quarantined(now, period));
+        assert!(!reputation["slow"].is_quarantined(now + period, period));
+    }
+}
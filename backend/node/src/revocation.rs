@@ -0,0 +1,168 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Result };
+use ed25519_dalek::{ PublicKey, Signature, Verifier };
+use serde::{ Deserialize, Serialize };
+use std::collections::BTreeSet;
+use std::env;
+use tracing::{ info, warn };
+
+/// Base64 ed25519 key the hub signs revocation lists with
+const SIGNER_PUBLIC_KEY_VAR: &str = "REVOCATION_SIGNER_PUBLIC_KEY";
+const REVOCATION_LIST_KEY: &str = "revoked_client_keys";
+
+/// Client e2e public keys that must not be accepted in any request, regardless of the account.
+/// Every update replaces the whole list and has to carry a higher version than the stored one,
+/// so an old list cannot be replayed to unblock a key.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct RevocationList {
+    pub version: u64,
+    pub revoked_e2e_keys: BTreeSet<String>,
+}
+
+impl RevocationList {
+    /// Stored as node metadata, so it lives in the configured storage backend
+    pub fn load() -> Result<Self> {
+        match KeyMetadataStore::get_node_level(REVOCATION_LIST_KEY)? {
+            Some(content) => Ok(serde_json::from_str(&content)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let content = serde_json::to_string(self)?;
+        KeyMetadataStore::save_node_level(&content, REVOCATION_LIST_KEY, &WriteOpts::Modify)
+    }
+
+    pub fn is_revoked(&self, client_e2e_public_key: &str) -> bool {
+        self.revoked_e2e_keys.contains(client_e2e_public_key)
+    }
+}
+
+/// Replaces the node's revocation list, sent by the hub to every guardian
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateRevocationListCommand {
+    pub revocation_list: RevocationList,
+    /// Base64 ed25519 signature over the JSON encoding of `revocation_list`
+    pub signature: String,
+}
+
+impl UpdateRevocationListCommand {
+    fn verify(&self, signer_public_key: &str) -> Result<()> {
+        let public_key = PublicKey::from_bytes(&base64::decode(signer_public_key)?).map_err(|err|
+            anyhow!("Invalid revocation list signer public key: {}", err)
+        )?;
+        let signature = Signature::try_from(&base64::decode(&self.signature)?[..]).map_err(|err|
+            anyhow!("Invalid revocation list signature encoding: {}", err)
+        )?;
+        public_key
+            .verify(&serde_json::to_vec(&self.revocation_list)?, &signature)
+            .map_err(|_| anyhow!("Revocation list signature verification failed"))
+    }
+
+    fn apply(self, current: &RevocationList) -> Result<RevocationList> {
+        if self.revocation_list.version <= current.version {
+            bail!(
+                "Revocation list version {} is not newer than the stored version {}",
+                self.revocation_list.version,
+                current.version
+            );
+        }
+        Ok(self.revocation_list)
+    }
+}
+
+impl JsonCommand for UpdateRevocationListCommand {
+    type Response = u64;
+
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let signer_public_key = match env::var(SIGNER_PUBLIC_KEY_VAR) {
+            Ok(key) => key,
+            Err(_) => bail!("{} is not set, revocation lists cannot be verified", SIGNER_PUBLIC_KEY_VAR),
+        };
+        self.verify(&signer_public_key)?;
+
+        let updated = self.apply(&RevocationList::load()?)?;
+        updated.save()?;
+        info!(
+            "Revocation list updated to version {} with {} revoked keys",
+            updated.version,
+            updated.revoked_e2e_keys.len()
+        );
+        Ok(updated.version)
+    }
+}
+
+/// Fails if the client e2e key has been revoked. An unreadable list is treated as a failure
+/// rather than as an empty list, otherwise corrupting the stored list would unblock every key.
+pub fn ensure_not_revoked(client_e2e_public_key: &str) -> Result<()> {
+    let list = RevocationList::load().map_err(|err| {
+        warn!("Failed to read the revocation list: {}", err);
+        anyhow!("Unable to check the client key against the revocation list")
+    })?;
+    if list.is_revoked(client_e2e_public_key) {
+        bail!("Client e2e public key has been revoked");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{ Keypair, SecretKey, Signer };
+
+    fn signer() -> Keypair {
+        let secret = SecretKey::from_bytes(&[7u8; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    fn signed_update(version: u64, keys: &[&str], signer: &Keypair) -> UpdateRevocationListCommand {
+        let revocation_list = RevocationList {
+            version,
+            revoked_e2e_keys: keys
+                .iter()
+                .map(|k| k.to_string())
+                .collect(),
+        };
+        let signature = signer.sign(&serde_json::to_vec(&revocation_list).unwrap());
+        UpdateRevocationListCommand {
+            revocation_list,
+            signature: base64::encode(signature.to_bytes()),
+        }
+    }
+
+    #[test]
+    fn verifies_signed_updates() {
+        let signer = signer();
+        let signer_public_key = base64::encode(signer.public.to_bytes());
+
+        let update = signed_update(1, &["stolen-key"], &signer);
+        assert!(update.verify(&signer_public_key).is_ok());
+
+        let mut tampered = update.clone();
+        tampered.revocation_list.revoked_e2e_keys.clear();
+        assert!(tampered.verify(&signer_public_key).is_err());
+    }
+
+    #[test]
+    fn rejects_stale_versions() {
+        let signer = signer();
+        let current = signed_update(2, &["stolen-key"], &signer)
+            .apply(&RevocationList::default())
+            .unwrap();
+        assert!(current.is_revoked("stolen-key"));
+        assert!(!current.is_revoked("other-key"));
+
+        assert!(signed_update(2, &[], &signer).apply(&current).is_err());
+        assert!(signed_update(1, &[], &signer).apply(&current).is_err());
+        assert!(signed_update(3, &[], &signer).apply(&current).is_ok());
+    }
+}
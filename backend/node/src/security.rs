@@ -1,7 +1,13 @@
-use anyhow::{ anyhow, Result };
-use curv::arithmetic::Zero;
+use anyhow::{ anyhow, bail, Result };
+use curv::arithmetic::{ BitManipulation, Zero };
 use curv::BigInt;
-use paillier::EncryptionKey;
+use paillier::{ DecryptionKey, EncryptionKey };
+use zk_paillier::zkproofs::{
+    CompositeDLogProof,
+    DLogStatement,
+    NiCorrectKeyProof,
+    SALT_STRING,
+};
 
 /// Check paillier public key for small prime factors (<2^16).
 /// Security issue: CVE-2023-33241
@@ -21,6 +27,57 @@ pub fn check_for_small_primes(ek: &EncryptionKey) -> Result<()> {
     Ok(())
 }
 
+/// Bit length of the Paillier moduli generated at keygen and recovery
+const PAILLIER_KEY_SIZE: usize = 2048;
+
+/// Checks the Paillier key of a co-signer before encrypting to it in MtA: a modulus of the size
+/// keygen generates without small prime factors. Keys are loaded from disk, so a party that
+/// crafted its key at keygen or recovery is caught at every signing.
+/// Security issue: CVE-2023-33241
+pub fn check_peer_paillier_key(ek: &EncryptionKey) -> Result<()> {
+    let bits = ek.n.bit_length();
+    // The product of two primes of half the size may be a bit shorter
+    if bits < PAILLIER_KEY_SIZE - 1 {
+        bail!("Paillier modulus of {} bits is shorter than {} bits", bits, PAILLIER_KEY_SIZE);
+    }
+    check_for_small_primes(ek)
+}
+
+/// Proof that the modulus of a freshly generated Paillier key is square-free, sent along with
+/// the public key to the parties that will encrypt to it
+pub fn prove_paillier_key(dk: &DecryptionKey) -> NiCorrectKeyProof {
+    NiCorrectKeyProof::proof(dk, None)
+}
+
+/// Checks the Paillier key of another party before it is stored: no prime factor below 2^16 and
+/// a proof that the modulus is coprime to its totient, so it has no square factor either
+pub fn verify_paillier_key(ek: &EncryptionKey, proof: Option<&NiCorrectKeyProof>) -> Result<()> {
+    check_for_small_primes(ek)?;
+    let proof = proof.ok_or_else(|| anyhow!("Paillier key comes without a proof of its modulus"))?;
+    proof
+        .verify(ek, SALT_STRING)
+        .map_err(|_| anyhow!("Proof of the Paillier modulus failed verification"))
+}
+
+/// Checks the h1, h2, N tilde of another party: h1 and h2 generate the same group modulo N tilde,
+/// proven by the discrete logarithm of each in the base of the other
+pub fn verify_h1_h2_n_tilde(
+    statement: &DLogStatement,
+    proof_base_h1: &CompositeDLogProof,
+    proof_base_h2: &CompositeDLogProof
+) -> Result<()> {
+    let statement_base_h2 = DLogStatement {
+        N: statement.N.clone(),
+        g: statement.ni.clone(),
+        ni: statement.g.clone(),
+    };
+    let h1_proven = proof_base_h1.verify(statement).is_ok();
+    if !h1_proven || proof_base_h2.verify(&statement_base_h2).is_err() {
+        bail!("Proof of h1, h2, N tilde failed verification");
+    }
+    Ok(())
+}
+
 const MAX_PRIME: usize = 65536;
 const PRIMES_COUNT: usize = 6542;
 const PRIMES: [u16; PRIMES_COUNT] = get_primes();
@@ -47,3 +104,20 @@ const fn get_primes() -> [u16; PRIMES_COUNT] {
     }
     primes
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use paillier::{ KeyGeneration, Paillier };
+
+    #[test]
+    fn verifies_proofs_of_paillier_keys() {
+        let (ek, dk) = Paillier::keypair().keys();
+        let proof = prove_paillier_key(&dk);
+        assert!(verify_paillier_key(&ek, Some(&proof)).is_ok());
+        assert!(verify_paillier_key(&ek, None).is_err());
+
+        let (other_ek, _) = Paillier::keypair().keys();
+        assert!(verify_paillier_key(&other_ek, Some(&proof)).is_err());
+    }
+}
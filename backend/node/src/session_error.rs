@@ -0,0 +1,125 @@
+//! Errors reported to the requester of a session. A node refusing a session publishes a
+//! `SessionError` on `<topic>.<session id>.error`, e.g.
+//! `network.gridlock.nodes.KeySignEdDSA.<session id>.error`, and answers the request with it when
+//! the requester waits for a reply. Clients then fail right away with the reason instead of
+//! waiting for the session to time out. Keygen requests carry no session id, their errors are
+//! published under the key id.
+
+use crate::communication::incoming::IncomingMessage;
+use crate::communication::queue_groups::extract_session_id;
+use crate::App;
+use serde::{ Deserialize, Serialize };
+use std::fmt::Display;
+use tracing::{ error, warn };
+
+const SUBJECT_PREFIX: &str = "network.gridlock.nodes.";
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionErrorCode {
+    /// The request can't be parsed or lacks a required field
+    MalformedRequest,
+    /// HMAC, timestamp, pairing or access key of the request don't check out
+    AuthenticationFailed,
+    /// The node holds no keyshare or key info for the session
+    MissingKeyshare,
+    /// The signing policy of the key or its approvers refused the request
+    PolicyDenied,
+    /// The key was generated with another protocol or version than the session runs
+    VersionMismatch,
+    RateLimited,
+    /// The node runs as many sessions as it may
+    Overloaded,
+    /// The node failed to start the session, e.g. on a storage error
+    InternalError,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct SessionError {
+    pub code: SessionErrorCode,
+    pub message: String,
+    /// Session or key id of the request, when it could be read
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(default)]
+    pub node_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+}
+
+impl SessionError {
+    pub fn new(code: SessionErrorCode, message: impl Display) -> Self {
+        SessionError {
+            code,
+            message: message.to_string(),
+            session_id: None,
+            node_id: None,
+            retry_after_secs: None,
+        }
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after_secs = Some(secs);
+        self
+    }
+}
+
+/// Subject the errors of a session requested on `subject` are published on
+pub fn error_subject(subject: &str, session_id: &str) -> Option<String> {
+    let topic = subject.strip_prefix(SUBJECT_PREFIX)?.split('.').next()?;
+    Some(format!("{}{}.{}.error", SUBJECT_PREFIX, topic, session_id))
+}
+
+/// Logs the error and reports it to the requester of the session
+pub fn report(app: &App, message: &IncomingMessage, mut error: SessionError) {
+    error!("Refusing the session on \"{}\": {}", message.subject, error.message);
+    if error.session_id.is_none() {
+        error.session_id = extract_session_id(&message.data).or_else(|| {
+            let value = serde_json::from_slice::<serde_json::Value>(&message.data).ok()?;
+            value.get("key_id").and_then(|v| v.as_str()).map(String::from)
+        });
+    }
+    error.node_id = Some(app.node.node_id.to_string());
+    let payload = match serde_json::to_vec(&error) {
+        Ok(payload) => payload,
+        Err(err) => {
+            warn!("Failed to serialize the session error: {}", err);
+            return;
+        }
+    };
+    let subject = error.session_id
+        .as_deref()
+        .and_then(|session_id| error_subject(&message.subject, session_id));
+    if let Some(subject) = subject {
+        if let Err(err) = app.nc.publish(&subject, &payload) {
+            warn!("Failed to publish the session error on {}: {}", subject, err);
+        }
+    }
+    if let Err(err) = message.respond(&app.nc, &payload) {
+        warn!("Failed to answer the refused request: {}", err);
+    }
+}
+
+/// Refuses the session requested by the message, see `report`
+pub fn refuse(app: &App, message: &IncomingMessage, code: SessionErrorCode, reason: impl Display) {
+    report(app, message, SessionError::new(code, reason));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_published_under_the_topic_of_the_request() {
+        assert_eq!(
+            error_subject("network.gridlock.nodes.KeySignEdDSA.new.node-1", "session-1").unwrap(),
+            "network.gridlock.nodes.KeySignEdDSA.session-1.error"
+        );
+        assert!(error_subject("other.subject", "session-1").is_none());
+
+        let error = SessionError::new(SessionErrorCode::PolicyDenied, "Daily limit reached");
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["code"], "policy_denied");
+        assert!(json.get("retry_after_secs").is_none());
+    }
+}
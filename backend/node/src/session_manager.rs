@@ -0,0 +1,625 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::communication::queue_groups::SessionAffinity;
+use crate::metrics::SessionKind;
+use crate::node::NodeIdentity;
+use crate::tenant::{ self, Access, TenantAuth };
+use anyhow::{ anyhow, bail, Result };
+use chrono::{ DateTime, Duration as ChronoDuration, Utc };
+use ed25519_dalek::{ PublicKey, Signature, Verifier };
+use serde::{ Deserialize, Serialize };
+use std::cell::RefCell;
+use std::collections::{ HashMap, HashSet };
+use std::future::Future;
+use std::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering };
+use std::sync::{ Arc, Condvar, Mutex, OnceLock, RwLock };
+use std::time::{ Duration, Instant };
+use std::{ env, io, thread };
+use tokio::runtime::{ Builder, Runtime };
+use tracing::{ error, info, warn };
+
+/// Per kind overrides of the session timeouts, in seconds
+const KEYGEN_TIMEOUT_VAR: &str = "KEYGEN_SESSION_TIMEOUT_SECS";
+const SIGNING_TIMEOUT_VAR: &str = "SIGNING_SESSION_TIMEOUT_SECS";
+const RECOVERY_TIMEOUT_VAR: &str = "RECOVERY_SESSION_TIMEOUT_SECS";
+const REFRESH_TIMEOUT_VAR: &str = "REFRESH_SESSION_TIMEOUT_SECS";
+/// Largest party count a session may declare, checked before anything is allocated for the parties
+const MAX_PARTIES_VAR: &str = "MAX_SESSION_PARTIES";
+const DEFAULT_MAX_PARTIES: usize = 16;
+/// Sessions that may run at once, requests for more are refused before anything is spawned
+const MAX_SESSIONS_VAR: &str = "MAX_CONCURRENT_SESSIONS";
+const DEFAULT_MAX_SESSIONS: usize = 256;
+/// Bytes of protocol messages one session may receive before it is stopped
+const MEMORY_BUDGET_VAR: &str = "SESSION_MEMORY_BUDGET_BYTES";
+const DEFAULT_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+/// Seconds a session waits for another one to release the metadata of a key before giving up
+const KEY_LOCK_TIMEOUT_VAR: &str = "KEY_LOCK_TIMEOUT_SECS";
+const DEFAULT_KEY_LOCK_TIMEOUT_SECS: u64 = 10;
+/// Base64 ed25519 key of the operator that may list and cancel the sessions of the node
+const CONTROL_SIGNER_PUBLIC_KEY_VAR: &str = "SESSION_CONTROL_SIGNER_PUBLIC_KEY";
+const MAX_GRANT_MINUTES: i64 = 10;
+
+struct ActiveSession {
+    session_id: String,
+    kind: SessionKind,
+    started: Instant,
+    timeout: Duration,
+    cancelled: AtomicBool,
+    memory_budget: usize,
+    received_bytes: AtomicUsize,
+}
+
+impl ActiveSession {
+    fn ensure_active(&self) -> Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            bail!("Session {} was cancelled", self.session_id);
+        }
+        if self.started.elapsed() > self.timeout {
+            bail!("Session {} timed out after {}s", self.session_id, self.timeout.as_secs());
+        }
+        Ok(())
+    }
+
+    fn charge_received(&self, bytes: usize) -> Result<()> {
+        let received = self.received_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if received > self.memory_budget {
+            bail!(
+                "Session {} received {} bytes of messages, over its budget of {} bytes",
+                self.session_id,
+                received,
+                self.memory_budget
+            );
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static CURRENT_SESSION: RefCell<Option<Arc<ActiveSession>>> = const { RefCell::new(None) };
+}
+
+tokio::task_local! {
+    static TASK_SESSION: Arc<ActiveSession>;
+}
+
+/// Progress of a tracked session, reported to the listener of an embedding host
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+    Started {
+        session_id: String,
+        kind: String,
+    },
+    RoundCompleted {
+        session_id: String,
+        round: String,
+    },
+    Finished {
+        session_id: String,
+        kind: String,
+    },
+}
+
+pub type ProgressListener = Box<dyn Fn(&SessionEvent) + Send + Sync>;
+
+fn progress_listener() -> &'static RwLock<Option<ProgressListener>> {
+    static LISTENER: OnceLock<RwLock<Option<ProgressListener>>> = OnceLock::new();
+    LISTENER.get_or_init(|| RwLock::new(None))
+}
+
+/// Replaces the listener session events are reported to, `None` stops reporting them
+pub fn set_progress_listener(listener: Option<ProgressListener>) {
+    *progress_listener().write().unwrap() = listener;
+}
+
+fn report(event: SessionEvent) {
+    if let Some(listener) = progress_listener().read().unwrap().as_ref() {
+        listener(&event);
+    }
+}
+
+/// Reports that the session running on this task or thread has received a whole round
+pub fn report_round_completed(round: &str) {
+    let session_id = match TASK_SESSION.try_with(|session| session.session_id.clone()) {
+        Ok(session_id) => Some(session_id),
+        Err(_) =>
+            CURRENT_SESSION.with(|current| {
+                current
+                    .borrow()
+                    .as_ref()
+                    .map(|session| session.session_id.clone())
+            }),
+    };
+    if let Some(session_id) = session_id {
+        report(SessionEvent::RoundCompleted { session_id, round: round.to_string() });
+    }
+}
+
+fn active_sessions() -> &'static Mutex<HashMap<u64, Arc<ActiveSession>>> {
+    static ACTIVE_SESSIONS: OnceLock<Mutex<HashMap<u64, Arc<ActiveSession>>>> = OnceLock::new();
+    ACTIVE_SESSIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Runtime the session tasks and the async NATS client run on. Sessions spend most of their time
+/// waiting for their peers, so a worker thread per core serves all of them.
+pub fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .thread_name("session-worker")
+            .enable_all()
+            .build()
+            .expect("Failed to start the session runtime")
+    })
+}
+
+/// Signing sessions subscribe to round subjects named after their session id, so a second one
+/// with the same id would read the rounds of the first. Key generation runs one session per share
+/// under the key id and is not checked.
+fn register_session(kind: SessionKind, session_id: &str) -> Result<(u64, Arc<ActiveSession>)> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let mut sessions = active_sessions().lock().unwrap();
+    let duplicate = sessions
+        .values()
+        .any(|session| session.kind == kind && session.session_id == session_id);
+    if duplicate && kind == SessionKind::Signing {
+        bail!("Signing session {} is already running on this node", session_id);
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let session = Arc::new(ActiveSession {
+        session_id: session_id.to_string(),
+        kind,
+        started: Instant::now(),
+        timeout: session_timeout(kind),
+        cancelled: AtomicBool::new(false),
+        memory_budget: env_limit(MEMORY_BUDGET_VAR, DEFAULT_MEMORY_BUDGET),
+        received_bytes: AtomicUsize::new(0),
+    });
+    sessions.insert(id, session.clone());
+    drop(sessions);
+    report(SessionEvent::Started {
+        session_id: session_id.to_string(),
+        kind: kind.label().to_string(),
+    });
+    Ok((id, session))
+}
+
+fn unregister_session(id: u64) {
+    let session = active_sessions().lock().unwrap().remove(&id);
+    if let Some(session) = session {
+        // Whether it completed or failed, sibling worker instances may take the session id again
+        if !is_running(&session.session_id) {
+            if let Err(err) = SessionAffinity::release(&session.session_id) {
+                warn!("Failed to release the claim of session {}: {}", session.session_id, err);
+            }
+        }
+        report(SessionEvent::Finished {
+            session_id: session.session_id.clone(),
+            kind: session.kind.label().to_string(),
+        });
+    }
+}
+
+/// Runs `check` against the session of the current task, or of the current thread for sessions
+/// spawned with `spawn_blocking_session`
+fn with_current_session(check: impl Fn(&ActiveSession) -> Result<()>) -> Result<()> {
+    match TASK_SESSION.try_with(|session| check(session)) {
+        Ok(result) => result,
+        Err(_) =>
+            CURRENT_SESSION.with(|current| {
+                match current.borrow().as_ref() {
+                    Some(session) => check(session),
+                    None => Ok(()),
+                }
+            }),
+    }
+}
+
+pub(crate) fn env_limit<T: std::str::FromStr + std::fmt::Display>(var: &str, default: T) -> T {
+    match env::var(var).map(|value| value.parse()) {
+        Ok(Ok(value)) => value,
+        Ok(Err(_)) => {
+            warn!("Ignoring invalid {}, using {}", var, default);
+            default
+        }
+        Err(_) => default,
+    }
+}
+
+fn session_timeout(kind: SessionKind) -> Duration {
+    let (var, default_secs) = match kind {
+        // Key generation includes the Paillier key and proof generation
+        SessionKind::KeyGen => (KEYGEN_TIMEOUT_VAR, 10 * 60),
+        SessionKind::Signing => (SIGNING_TIMEOUT_VAR, 2 * 60),
+        SessionKind::Recovery => (RECOVERY_TIMEOUT_VAR, 5 * 60),
+        SessionKind::Refresh => (REFRESH_TIMEOUT_VAR, 5 * 60),
+    };
+    Duration::from_secs(env_limit(var, default_secs))
+}
+
+/// Rejects a party count declared by a session peer before it sizes any collection. Applies
+/// outside of tracked sessions too, a join response is not trusted more there.
+pub fn check_party_count(party_count: usize) -> Result<()> {
+    let max_parties = env_limit(MAX_PARTIES_VAR, DEFAULT_MAX_PARTIES);
+    if party_count > max_parties {
+        bail!("Session declares {} parties, at most {} are allowed", party_count, max_parties);
+    }
+    Ok(())
+}
+
+/// Fails while as many sessions run as `MAX_CONCURRENT_SESSIONS` allows
+pub fn check_capacity() -> Result<()> {
+    let max_sessions = env_limit(MAX_SESSIONS_VAR, DEFAULT_MAX_SESSIONS);
+    let running = active_sessions().lock().unwrap().len();
+    if running >= max_sessions {
+        bail!("{} sessions are running, at most {} are allowed", running, max_sessions);
+    }
+    Ok(())
+}
+
+/// Runs a session as a task on the session runtime and tracks it until it returns. Messages the
+/// session waits for are received through `ensure_active`, so a cancelled or timed out session
+/// stops at its next wait instead of hanging on a subscription.
+pub fn spawn_session<F>(kind: SessionKind, session_id: &str, run: F)
+    where F: Future + Send + 'static, F::Output: Send
+{
+    let (id, session) = match register_session(kind, session_id) {
+        Ok(registered) => registered,
+        Err(err) => {
+            error!("Not starting session: {}", err);
+            return;
+        }
+    };
+    runtime().spawn(
+        TASK_SESSION.scope(session, async move {
+            let _ = run.await;
+            unregister_session(id);
+        })
+    );
+}
+
+/// Runs a session on its own thread, for protocols still waiting on blocking subscriptions.
+/// Tracked like the sessions spawned with `spawn_session`.
+pub fn spawn_blocking_session<F, T>(
+    kind: SessionKind,
+    session_id: &str,
+    thread_name: String,
+    run: F
+) -> io::Result<()>
+    where F: FnOnce() -> T + Send + 'static
+{
+    let (id, session) = register_session(kind, session_id).map_err(|err| {
+        io::Error::new(io::ErrorKind::AlreadyExists, err.to_string())
+    })?;
+    let spawned = thread::Builder
+        ::new()
+        .name(thread_name)
+        .spawn(move || {
+            CURRENT_SESSION.with(|current| current.replace(Some(session)));
+            let _ = run();
+            CURRENT_SESSION.with(|current| current.replace(None));
+            unregister_session(id);
+        });
+    if spawned.is_err() {
+        unregister_session(id);
+    }
+    spawned.map(|_| ())
+}
+
+fn locked_keys() -> &'static (Mutex<HashSet<String>>, Condvar) {
+    static LOCKED_KEYS: OnceLock<(Mutex<HashSet<String>>, Condvar)> = OnceLock::new();
+    LOCKED_KEYS.get_or_init(|| (Mutex::new(HashSet::new()), Condvar::new()))
+}
+
+/// Exclusive access to the metadata of a key, released when dropped
+pub struct KeyLock {
+    key_id: String,
+}
+
+impl Drop for KeyLock {
+    fn drop(&mut self) {
+        let (keys, released) = locked_keys();
+        keys.lock().unwrap().remove(&self.key_id);
+        released.notify_all();
+    }
+}
+
+/// Waits until no other session holds the key, for checks and updates of its metadata that
+/// concurrent signing sessions must not interleave. Fails with a busy error when the key isn't
+/// released within `KEY_LOCK_TIMEOUT_SECS`.
+pub fn lock_key(key_id: &str) -> Result<KeyLock> {
+    let timeout = env_limit(KEY_LOCK_TIMEOUT_VAR, DEFAULT_KEY_LOCK_TIMEOUT_SECS);
+    lock_key_within(key_id, Duration::from_secs(timeout))
+}
+
+fn lock_key_within(key_id: &str, timeout: Duration) -> Result<KeyLock> {
+    let (keys, released) = locked_keys();
+    let keys = keys.lock().unwrap();
+    let (mut keys, wait) = released
+        .wait_timeout_while(keys, timeout, |keys| keys.contains(key_id))
+        .unwrap();
+    if wait.timed_out() {
+        bail!("Key {} is busy with another session, try again later", key_id);
+    }
+    keys.insert(key_id.to_string());
+    Ok(KeyLock { key_id: key_id.to_string() })
+}
+
+/// Fails once the session running on this task or thread has been cancelled or has run past its
+/// timeout. Always succeeds outside of sessions.
+pub fn ensure_active() -> Result<()> {
+    with_current_session(ActiveSession::ensure_active)
+}
+
+/// Counts a received message against the memory budget of the session running on this task or
+/// thread, fails once the session has received more than its budget. Always succeeds outside of
+/// sessions.
+pub fn charge_received(bytes: usize) -> Result<()> {
+    with_current_session(|session| session.charge_received(bytes))
+}
+
+/// Whether a session with the id runs on this node
+pub fn is_running(session_id: &str) -> bool {
+    active_sessions()
+        .lock()
+        .unwrap()
+        .values()
+        .any(|session| session.session_id == session_id)
+}
+
+/// Cancels every running session with the id, returns how many were cancelled
+pub fn cancel_session(session_id: &str) -> usize {
+    let sessions = active_sessions().lock().unwrap();
+    let mut cancelled = 0;
+    for session in sessions.values().filter(|session| session.session_id == session_id) {
+        session.cancelled.store(true, Ordering::Relaxed);
+        cancelled += 1;
+    }
+    cancelled
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub kind: String,
+    pub running_secs: u64,
+    pub timeout_secs: u64,
+    pub cancelled: bool,
+    pub received_bytes: usize,
+}
+
+pub fn list_sessions() -> Vec<SessionInfo> {
+    let sessions = active_sessions().lock().unwrap();
+    let mut list: Vec<SessionInfo> = sessions
+        .values()
+        .map(|session| SessionInfo {
+            session_id: session.session_id.clone(),
+            kind: session.kind.label().to_string(),
+            running_secs: session.started.elapsed().as_secs(),
+            timeout_secs: session.timeout.as_secs(),
+            cancelled: session.cancelled.load(Ordering::Relaxed),
+            received_bytes: session.received_bytes.load(Ordering::Relaxed),
+        })
+        .collect();
+    list.sort_by(|a, b| b.running_secs.cmp(&a.running_secs));
+    list
+}
+
+/// Permission to list and cancel the sessions of one node until `expires_at`, signed by the
+/// operator
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SessionControlGrant {
+    pub node_id: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Who lists or cancels sessions. Sessions of every account run side by side, on multi user
+/// nodes only the operator controls them.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionControlAuth {
+    Operator {
+        grant: SessionControlGrant,
+        /// Base64 ed25519 signature over the JSON encoding of `grant`
+        signature: String,
+    },
+    /// Proof of the account of a single user node
+    Owner(TenantAuth),
+}
+
+impl SessionControlAuth {
+    /// Owner proofs are checked by the dispatcher, operator grants by `check`
+    fn access(&self) -> Access<'_> {
+        match self {
+            SessionControlAuth::Operator { .. } => Access::Checked,
+            SessionControlAuth::Owner(auth) => {
+                Access::Account { auth: Some(auth), key_ids: Vec::new() }
+            }
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        match self {
+            SessionControlAuth::Operator { grant, signature } => {
+                let signer_public_key = match env::var(CONTROL_SIGNER_PUBLIC_KEY_VAR) {
+                    Ok(key) => key,
+                    Err(_) => {
+                        bail!(
+                            "{} is not set, operators can't control sessions",
+                            CONTROL_SIGNER_PUBLIC_KEY_VAR
+                        )
+                    }
+                };
+                let node = NodeIdentity::cached()?;
+                grant.verify(signature, &signer_public_key, &node.node_id.to_string(), Utc::now())
+            }
+            SessionControlAuth::Owner(_) if tenant::is_multi_user() => {
+                bail!("Sessions of several accounts run here, only the operator controls them")
+            }
+            SessionControlAuth::Owner(_) => Ok(()),
+        }
+    }
+}
+
+impl SessionControlGrant {
+    fn verify(
+        &self,
+        signature: &str,
+        signer_public_key: &str,
+        node_id: &str,
+        now: DateTime<Utc>
+    ) -> Result<()> {
+        let public_key = PublicKey::from_bytes(&base64::decode(signer_public_key)?).map_err(|err|
+            anyhow!("Invalid session control signer public key: {}", err)
+        )?;
+        let signature = Signature::try_from(&base64::decode(signature)?[..]).map_err(|err|
+            anyhow!("Invalid session control grant signature encoding: {}", err)
+        )?;
+        public_key
+            .verify(&serde_json::to_vec(self)?, &signature)
+            .map_err(|_| anyhow!("Session control grant signature verification failed"))?;
+
+        if self.node_id != node_id {
+            bail!("Session control grant is for node {}", self.node_id);
+        }
+        if self.expires_at <= now {
+            bail!("Session control grant expired at {}", self.expires_at);
+        }
+        if self.expires_at - now > ChronoDuration::minutes(MAX_GRANT_MINUTES) {
+            bail!("Session control grants are valid for at most {} minutes", MAX_GRANT_MINUTES);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CancelSessionCommand {
+    pub session_id: String,
+    pub auth: SessionControlAuth,
+}
+
+impl JsonCommand for CancelSessionCommand {
+    type Response = usize;
+
+    fn access(&self) -> Access<'_> {
+        self.auth.access()
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        self.auth.check()?;
+        let cancelled = cancel_session(&self.session_id);
+        if cancelled == 0 {
+            warn!("No active session with id {} to cancel", self.session_id);
+        } else {
+            info!("Cancelled {} threads of session {}", cancelled, self.session_id);
+        }
+        Ok(cancelled)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ListSessionsCommand {
+    pub auth: SessionControlAuth,
+}
+
+impl JsonCommand for ListSessionsCommand {
+    type Response = Vec<SessionInfo>;
+
+    fn access(&self) -> Access<'_> {
+        self.auth.access()
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        self.auth.check()?;
+        Ok(list_sessions())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{ Keypair, SecretKey, Signer };
+    use std::sync::mpsc;
+
+    #[test]
+    fn cancelled_sessions_stop_at_their_next_check() {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (result_tx, result_rx) = mpsc::channel();
+        spawn_session(SessionKind::Signing, "cancel-me", async move {
+            started_tx.send(()).unwrap();
+            while ensure_active().is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            result_tx.send(ensure_active().unwrap_err().to_string()).unwrap();
+        });
+
+        started_rx.recv().unwrap();
+        assert!(list_sessions().iter().any(|session| session.session_id == "cancel-me"));
+        assert_eq!(cancel_session("cancel-me"), 1);
+        assert!(result_rx.recv().unwrap().contains("cancelled"));
+        assert!(ensure_active().is_ok());
+    }
+
+    #[test]
+    fn sessions_stop_once_over_their_memory_budget() {
+        let session = ActiveSession {
+            session_id: "budget".to_string(),
+            kind: SessionKind::Signing,
+            started: Instant::now(),
+            timeout: Duration::from_secs(60),
+            cancelled: AtomicBool::new(false),
+            memory_budget: 1000,
+            received_bytes: AtomicUsize::new(0),
+        };
+        assert!(session.charge_received(600).is_ok());
+        assert!(session.charge_received(400).is_ok());
+        assert!(session.charge_received(1).unwrap_err().to_string().contains("over its budget"));
+
+        assert!(check_party_count(DEFAULT_MAX_PARTIES).is_ok());
+        assert!(check_party_count(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn keys_are_locked_until_released() {
+        let lock = lock_key_within("locked-key", Duration::from_millis(10)).unwrap();
+        let busy = lock_key_within("locked-key", Duration::from_millis(10));
+        assert!(busy.err().unwrap().to_string().contains("busy"));
+        assert!(lock_key_within("other-key", Duration::from_millis(10)).is_ok());
+        drop(lock);
+        assert!(lock_key_within("locked-key", Duration::from_millis(10)).is_ok());
+    }
+
+    #[test]
+    fn verifies_session_control_grants() {
+        let secret = SecretKey::from_bytes(&[5u8; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        let signer = Keypair { secret, public };
+        let signer_public_key = base64::encode(signer.public.to_bytes());
+        let now = Utc::now();
+        let sign = |grant: &SessionControlGrant| {
+            base64::encode(signer.sign(&serde_json::to_vec(grant).unwrap()).to_bytes())
+        };
+
+        let grant = SessionControlGrant {
+            node_id: "node-1".to_string(),
+            expires_at: now + ChronoDuration::minutes(5),
+        };
+        let signature = sign(&grant);
+        assert!(grant.verify(&signature, &signer_public_key, "node-1", now).is_ok());
+        assert!(grant.verify(&signature, &signer_public_key, "node-2", now).is_err());
+        let later = now + ChronoDuration::minutes(6);
+        assert!(grant.verify(&signature, &signer_public_key, "node-1", later).is_err());
+
+        let expires_at = now + ChronoDuration::minutes(4);
+        let tampered = SessionControlGrant { expires_at, ..grant };
+        assert!(tampered.verify(&signature, &signer_public_key, "node-1", now).is_err());
+
+        let long_lived = SessionControlGrant {
+            node_id: "node-1".to_string(),
+            expires_at: now + ChronoDuration::minutes(MAX_GRANT_MINUTES + 1),
+        };
+        let signature = sign(&long_lived);
+        assert!(long_lived.verify(&signature, &signer_public_key, "node-1", now).is_err());
+    }
+}
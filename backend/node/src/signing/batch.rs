@@ -0,0 +1,83 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::reputation;
+use crate::signing::encoding::SignatureEncoding;
+use crate::signing::hashing::HashMode;
+use crate::signing::response::{ ResponseVersion, VersionedSigningResponse };
+use crate::signing::{ ecdsa, Key, SigningResponse };
+use crate::tenant::Access;
+use anyhow::{ bail, Result };
+use serde::{ Deserialize, Serialize };
+use shared::key_info::NodeId;
+
+/// Upper bound on the messages of one batch, every message adds seven protocol rounds to the
+/// session and has to fit in the signing session timeout
+pub(crate) const MAX_BATCH_SIZE: usize = 64;
+
+/// Signs several messages with the same key in one session, e.g. all inputs of a transaction.
+/// The parties join once and only repeat the rounds that produce each signature.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BatchSigningCommand {
+    #[serde(flatten)]
+    pub kind: Key,
+    pub key_id: String,
+    pub session_id: String,
+    pub party_nodes: Vec<NodeId>,
+    pub msgs: Vec<Vec<u8>>,
+    /// Applied to every signature of the batch
+    #[serde(default)]
+    pub encoding: Option<SignatureEncoding>,
+    /// Hash applied by every node to each message before signing it
+    #[serde(default)]
+    pub hash_mode: HashMode,
+    /// Format of each response, see `ResponseVersion`
+    #[serde(default)]
+    pub response_version: ResponseVersion,
+    /// Starts the session even if some of the party nodes are quarantined
+    #[serde(default)]
+    pub allow_quarantined_peers: bool,
+}
+
+impl JsonCommand for BatchSigningCommand {
+    type Response = Vec<VersionedSigningResponse>;
+
+    /// Every guardian checks the owner's HMAC of the request when the session starts
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        if self.msgs.is_empty() {
+            bail!("Batch has no messages to sign");
+        }
+        if self.msgs.len() > MAX_BATCH_SIZE {
+            bail!("Batch has {} messages, at most {} allowed", self.msgs.len(), MAX_BATCH_SIZE);
+        }
+        reputation::check_parties(&self.party_nodes, self.allow_quarantined_peers)?;
+        let encoding = self.encoding;
+        let version = self.response_version;
+        let kind = self.kind.clone();
+        let responses: Vec<SigningResponse> = match self.kind {
+            Key::ECDSA =>
+                ecdsa::orchestrate
+                    ::orchestrate_batch(self, ctx)?
+                    .into_iter()
+                    .map(SigningResponse::ECDSA)
+                    .collect(),
+            kind => bail!("Batch signing is not supported for {:?} keys", kind),
+        };
+        let responses = match encoding {
+            Some(encoding) =>
+                responses
+                    .into_iter()
+                    .map(|response| response.encode(encoding))
+                    .collect::<Result<Vec<_>>>()?,
+            None => responses,
+        };
+        Ok(
+            responses
+                .into_iter()
+                .map(|response| VersionedSigningResponse::new(version, kind.clone(), response))
+                .collect()
+        )
+    }
+}
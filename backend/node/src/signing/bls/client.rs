@@ -0,0 +1,46 @@
+use crate::communication::nats::PeerMessenger;
+use crate::communication::protocol::{ AllRounds, KeySignBLSAllRounds };
+use crate::signing::bls::protocol::{ aggregate, sign_share, verify, verify_share };
+use crate::signing::bls::SignatureResult;
+use crate::storage::BLS;
+use anyhow::Result;
+use curv::elliptic::curves::{ Bls12_381_2, Point };
+use tracing::info;
+
+pub struct BLSKeySignClient<C> {
+    pub peer_messenger: C,
+    pub all_party_indices: Vec<usize>,
+}
+
+impl<C> BLSKeySignClient<C> where C: PeerMessenger<KeySignBLSAllRounds> {
+    /// BLS signatures need no nonces, a single round of signature shares is enough
+    pub async fn create_signature(
+        &self,
+        message: &[u8],
+        keyshare: &BLS
+    ) -> Result<Point<Bls12_381_2>> {
+        let signature_share = sign_share(message, &keyshare.x_i)?;
+        let signature_shares: Vec<Point<Bls12_381_2>> =
+            self.peer_messenger.broadcast_and_collect_messages(
+                &<KeySignBLSAllRounds as AllRounds>::BroadcastRound::SignatureShare,
+                signature_share
+            ).await?;
+        for (party_index, share) in self.all_party_indices.iter().zip(&signature_shares) {
+            verify_share(message, *party_index, share, &keyshare.public_share(*party_index))?;
+        }
+        info!("Verified all signature shares");
+
+        let signature = aggregate(&self.all_party_indices, &signature_shares)?;
+        verify(message, &signature, &keyshare.public_key)?;
+        info!("Full signature generated and verified");
+        Ok(signature)
+    }
+
+    pub async fn publish_result(&self, signature: SignatureResult) -> Result<()> {
+        let _ = self.peer_messenger.broadcast_and_collect_messages(
+            &<KeySignBLSAllRounds as AllRounds>::BroadcastRound::Result,
+            signature
+        ).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,14 @@
+pub mod client;
+pub mod orchestrate;
+pub mod protocol;
+pub mod session;
+
+use serde::{ Deserialize, Serialize };
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SignatureResult {
+    /// Compressed G2 signature, hex
+    pub signature: String,
+    /// Compressed G1 public key the signature verifies against, hex
+    pub public_key: String,
+}
@@ -0,0 +1,81 @@
+use crate::command::MsgContext;
+use crate::communication::envelope;
+use crate::communication::nats::{ BroadcastMessage, JoinMessage, JoinResponse };
+use crate::signing::bls::session::NewBLSKeySignSession;
+use crate::signing::bls::SignatureResult;
+use crate::signing::{ SigningCommand, SigningResponse };
+use anyhow::{ bail, Context, Result };
+use tracing::{ error, info, instrument };
+
+#[instrument(skip_all)]
+pub fn orchestrate(cmd: SigningCommand, ctx: MsgContext) -> Result<SigningResponse> {
+    let app = ctx.get_app()?;
+    let nc = app.nc;
+    let session_id = cmd.session_id.clone();
+
+    let party_nodes = cmd.party_nodes;
+    let key_id = cmd.key_id;
+
+    let party_count = party_nodes.len();
+    if party_count < 3 {
+        bail!("Not enough nodes in party");
+    }
+
+    let join_key = format!("network.gridlock.nodes.KeySignBLS.{}.Join", &session_id);
+    let join_sub = nc.subscribe(&join_key)?;
+
+    let result_key = format!("network.gridlock.nodes.KeySignBLS.{}.Result", &session_id);
+    let result_sub = nc.subscribe(&result_key)?;
+
+    for node in party_nodes.iter() {
+        let sign_new_key = format!("network.gridlock.nodes.KeySignBLS.new.{}", node);
+        let key_sign_new_data = serde_json::to_string(
+            &(NewBLSKeySignSession {
+                key_id: key_id.to_owned(),
+                session_id: session_id.to_owned(),
+                message: cmd.msg.clone(),
+                email: None,
+            })
+        )?;
+        nc.publish(&sign_new_key, key_sign_new_data)?;
+    }
+
+    let mut join_msg_vec = Vec::new();
+    for _ in 0..party_count {
+        let next = join_sub.next().context("Waiting for parties to join")?;
+        join_msg_vec.push(next);
+    }
+
+    if join_msg_vec.len() < party_count {
+        let msg = format!("Not every party joined - party_joined_count: {}", join_msg_vec.len());
+        error!("{}", &msg);
+        bail!(msg);
+    }
+
+    let mut joins = Vec::new();
+    for m in join_msg_vec.iter() {
+        joins.push(serde_json::from_slice::<JoinMessage>(&m.data)?);
+    }
+    let join_resp = JoinResponse::new(&joins);
+    for msg in join_msg_vec {
+        msg.respond(
+            &serde_json::to_string(&join_resp).context("Respond to join message for every party")?
+        )?;
+    }
+    nc.flush()?;
+
+    info!("Parties joined to BLS signing");
+
+    let mut res_vec = Vec::new();
+    for _ in 0..party_count {
+        let res = result_sub.next().context("Waiting for signature results")?;
+        res_vec.push(res);
+    }
+
+    info!("Signature result received");
+
+    let sig = envelope::decode::<BroadcastMessage<SignatureResult>>(
+        &res_vec[0].data
+    )?.message;
+    Ok(SigningResponse::BLS(sig))
+}
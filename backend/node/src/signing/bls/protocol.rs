@@ -0,0 +1,147 @@
+use anyhow::{ anyhow, bail, Result };
+use blst::min_pk::{ PublicKey, SecretKey, Signature };
+use blst::BLST_ERROR;
+use curv::arithmetic::Converter;
+use curv::elliptic::curves::{ Bls12_381_1, Bls12_381_2, Point, Scalar };
+use curv::BigInt;
+
+/// Ciphersuite of the Ethereum consensus layer: public keys are points in G1, signatures points
+/// in G2 and messages are hashed to G2 with the proof of possession scheme's tag, so signatures
+/// verify with any eth2 BLS library
+const DST: &[u8] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+
+/// Signature share of the party holding `x_i`, combined by Lagrange interpolation in `aggregate`.
+/// BLS signatures are deterministic, so the share is the signature of `x_i` as a secret key.
+pub fn sign_share(message: &[u8], x_i: &Scalar<Bls12_381_1>) -> Result<Point<Bls12_381_2>> {
+    let secret_key = SecretKey::from_bytes(&scalar_bytes(x_i)).map_err(|err|
+        anyhow!("Keyshare is not a valid BLS secret key: {:?}", err)
+    )?;
+    let signature = secret_key.sign(message, DST, &[]);
+    Point::from_bytes(&signature.compress()).map_err(|_|
+        anyhow!("Signature share is not a point of G2")
+    )
+}
+
+/// Checks a signature share against the public share of the party that created it
+pub fn verify_share(
+    message: &[u8],
+    party_index: usize,
+    share: &Point<Bls12_381_2>,
+    public_share: &Point<Bls12_381_1>
+) -> Result<()> {
+    verify(message, share, public_share).map_err(|_|
+        anyhow!("Signature share of party {} is invalid", party_index)
+    )
+}
+
+/// Interpolates the signature from the shares of `signers`, given in the same order
+pub fn aggregate(signers: &[usize], shares: &[Point<Bls12_381_2>]) -> Result<Point<Bls12_381_2>> {
+    if signers.len() != shares.len() {
+        bail!("Received {} signature shares from {} signers", shares.len(), signers.len());
+    }
+    signers
+        .iter()
+        .zip(shares)
+        .try_fold(Point::zero(), |sum, (&party_index, share)| {
+            Ok(sum + share * lagrange_coefficient(party_index, signers)?)
+        })
+}
+
+/// Verifies like the eth2 `Verify`, including the subgroup checks of both points
+pub fn verify(
+    message: &[u8],
+    signature: &Point<Bls12_381_2>,
+    public_key: &Point<Bls12_381_1>
+) -> Result<()> {
+    let public_key = PublicKey::from_bytes(&public_key.to_bytes(true)).map_err(|err|
+        anyhow!("Invalid BLS public key: {:?}", err)
+    )?;
+    let signature = Signature::from_bytes(&signature.to_bytes(true)).map_err(|err|
+        anyhow!("Invalid BLS signature: {:?}", err)
+    )?;
+    match signature.verify(true, message, DST, &[], &public_key, true) {
+        BLST_ERROR::BLST_SUCCESS => Ok(()),
+        _ => bail!("BLS signature verification failed"),
+    }
+}
+
+/// Big-endian encoding of a scalar, the secret key format of eth2
+fn scalar_bytes(scalar: &Scalar<Bls12_381_1>) -> [u8; 32] {
+    let bytes = BigInt::to_bytes(&scalar.to_bigint());
+    let mut padded = [0u8; 32];
+    padded[32 - bytes.len()..].copy_from_slice(&bytes);
+    padded
+}
+
+/// Both groups have the same prime order, the coefficient scales the G2 signature shares
+fn lagrange_coefficient(party_index: usize, signers: &[usize]) -> Result<Scalar<Bls12_381_2>> {
+    let x_i = index_to_scalar(party_index);
+    let mut numerator = index_to_scalar(1);
+    let mut denominator = index_to_scalar(1);
+    for &j in signers.iter().filter(|&&j| j != party_index) {
+        let x_j = index_to_scalar(j);
+        numerator = numerator * &x_j;
+        denominator = denominator * (x_j - &x_i);
+    }
+    let inverse = denominator
+        .invert()
+        .ok_or_else(|| anyhow!("Signer indices must be distinct and non-zero"))?;
+    Ok(numerator * inverse)
+}
+
+fn index_to_scalar(index: usize) -> Scalar<Bls12_381_2> {
+    Scalar::from_bigint(&BigInt::from(index as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+
+    fn scalar(hex: &str) -> Scalar<Bls12_381_1> {
+        Scalar::from_bigint(&BigInt::from_bytes(&hex::decode(hex).unwrap()))
+    }
+
+    #[test]
+    fn threshold_signature_shares_combine_and_verify() {
+        let indices = [1u16, 2, 3, 4];
+        let secret = Scalar::<Bls12_381_1>::random();
+        let (vss, shares) = VerifiableSS::<Bls12_381_1>::share_at_indices(1, 4, &secret, &indices);
+        let public_key = Point::<Bls12_381_1>::generator() * &secret;
+        let message = b"attestation";
+
+        let signers = [2, 4];
+        let signature_shares = signers
+            .iter()
+            .map(|&i| sign_share(message, &shares[i - 1]).unwrap())
+            .collect::<Vec<_>>();
+        for (&i, share) in signers.iter().zip(&signature_shares) {
+            verify_share(message, i, share, &vss.get_point_commitment(i as u16)).unwrap();
+        }
+
+        let signature = aggregate(&signers, &signature_shares).unwrap();
+        verify(message, &signature, &public_key).unwrap();
+        // The same signature the group secret makes on its own
+        assert_eq!(signature, sign_share(message, &secret).unwrap());
+        assert!(verify(b"other message", &signature, &public_key).is_err());
+        let wrong_public_share = vss.get_point_commitment(1);
+        assert!(verify_share(message, 1, &signature_shares[0], &wrong_public_share).is_err());
+    }
+
+    // consensus-spec-tests, general/phase0/bls/sign/small/sign_case_84d45c9c7cca6b92
+    #[test]
+    fn matches_the_eth2_sign_vector() {
+        let secret = scalar("263dbd792f5b1be47ed85f8938c0f29586af0d3ac7b977f21c278fe1462040e3");
+        let message = [0u8; 32];
+        let expected = concat!(
+            "b6ed936746e01f8ecf281f020953fbf1f01debd5657c4a383940b020b26507f6",
+            "076334f91e2366c96e9ab279fb5158090352ea1c5b0c9274504f4f0e7053af24",
+            "802e51e4568d164fe986834f41e55c8e850ce1f98458c0cfc9ab380b55285a55"
+        );
+
+        let signature = sign_share(&message, &secret).unwrap();
+        assert_eq!(hex::encode(&*signature.to_bytes(true)), expected);
+        let public_key = Point::<Bls12_381_1>::generator() * &secret;
+        verify(&message, &signature, &public_key).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+use crate::audit::{ AuditAction, AuditedRequest };
+use crate::communication::incoming::IncomingMessage;
+use crate::communication::nats::{
+    BaseMessenger,
+    NatsBaseMessenger,
+    NatsBaseSession,
+    NatsPeerMessenger,
+};
+use crate::communication::protocol::{ KeySignBLSAllRounds, Topic };
+use crate::node::NodeIdentity;
+use crate::observer::with_consented_observers;
+use crate::signing::bls::client::BLSKeySignClient;
+use crate::signing::bls::SignatureResult;
+use crate::storage::{ KeyshareAccessor, BLS };
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::App;
+use crate::metrics::SessionKind;
+use crate::session_error::{ self, SessionErrorCode };
+use crate::session_manager;
+use anyhow::{ bail, Result };
+use serde::{ Deserialize, Serialize };
+use crate::slo;
+use std::time::Instant;
+use tracing::{ error, info, instrument };
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NewBLSKeySignSession {
+    pub key_id: String,
+    pub session_id: String,
+    pub message: Vec<u8>,
+    pub email: Option<String>,
+}
+
+#[instrument(skip_all)]
+async fn sign_session(conn: async_nats::Client, session: NewBLSKeySignSession) {
+    let session_id = session.session_id.clone();
+    let key_id = session.key_id.clone();
+    let audit = AuditedRequest::new(
+        AuditAction::Signing,
+        "bls",
+        &key_id,
+        &session_id,
+        session.email.as_deref()
+    );
+    let email = session.email.clone().unwrap_or_default();
+    let message = session.message.clone();
+    let started = Instant::now();
+    let result = keysign_session_inner(conn, session).await;
+    slo::record_signing("bls", &key_id, started.elapsed(), result.is_ok());
+    audit.record(&result);
+    if result.is_ok() {
+        KeyMetadataStore::record_usage(&key_id, &email, &message);
+    }
+    match result {
+        Ok(()) => info!("Signing completed successfully for session id: {}", session_id),
+        Err(err) => error!("Error in BLS signing: session id: {}, error: {}", session_id, err),
+    }
+}
+
+async fn keysign_session_inner(
+    conn: async_nats::Client,
+    session: NewBLSKeySignSession
+) -> Result<()> {
+    let key_id = session.key_id.clone();
+    info!("joining BLS keysign session key_id: {}", &key_id);
+
+    let keyshare = match &session.email {
+        Some(email) => KeyshareAccessor::<BLS>::read_only_with_email(&key_id, email)?.key,
+        None => KeyshareAccessor::<BLS>::read_only(&key_id)?.key,
+    };
+    info!("Retrieved keyshare");
+
+    let node = NodeIdentity::cached()?;
+    let nats_session = NatsBaseSession {
+        session_id: session.session_id.clone(),
+        thread_index: 0,
+        node_id: node.node_id.to_string(),
+        public_key: node.networking_public_key,
+        party_index: keyshare.party_index,
+    };
+
+    let messenger = NatsBaseMessenger::<KeySignBLSAllRounds>::new(
+        Topic::KeySignBLS,
+        conn,
+        nats_session
+    ).await?;
+    let join_response = messenger.wait_for_confirmation(std::time::Duration::from_secs(10)).await?;
+    info!("Got join response");
+
+    let party_count = join_response.party_count;
+    let mut all_party_indices = join_response.all_party_indices;
+    all_party_indices.sort();
+    if all_party_indices.len() <= keyshare.threshold {
+        bail!(
+            "{} signers joined, at least {} are needed",
+            all_party_indices.len(),
+            keyshare.threshold + 1
+        );
+    }
+
+    let peer_messenger = with_consented_observers(
+        NatsPeerMessenger::from(messenger, party_count, all_party_indices.clone())?,
+        &key_id,
+        session.email.as_deref()
+    );
+    let keysign_client = BLSKeySignClient {
+        peer_messenger,
+        all_party_indices,
+    };
+
+    let signature = keysign_client.create_signature(&session.message, &keyshare).await?;
+    keysign_client.publish_result(SignatureResult {
+        signature: hex::encode(&*signature.to_bytes(true)),
+        public_key: hex::encode(&*keyshare.public_key.to_bytes(true)),
+    }).await?;
+    info!("Signature published successfully");
+
+    keysign_client.peer_messenger.publish_transcript().await
+}
+
+pub fn handle_new_session_message(app: &App, message: IncomingMessage) {
+    let session = match serde_json::from_slice::<NewBLSKeySignSession>(&message.data[..]) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Failed to parse message: {}", err)
+            );
+            return;
+        }
+    };
+
+    info!("Spawning a task to handle BLS signature generation");
+    let nc = app.nc.client().clone();
+    let session_id = session.session_id.clone();
+    session_manager::spawn_session(SessionKind::Signing, &session_id, sign_session(nc, session));
+}
@@ -1,7 +1,12 @@
 pub mod orchestrate;
 pub mod session;
+pub mod warmup;
 
 use crate::communication::ecdsa::{ HasSenderId, HasTargetId };
+use crate::security::check_peer_paillier_key;
+use crate::signing::hashing::HashMode;
+use crate::storage::ECDSA;
+use anyhow::{ anyhow, Result };
 use curv::cryptographic_primitives::proofs::sigma_correct_homomorphic_elgamal_enc::HomoELGamalProof;
 use curv::elliptic::curves::{ Point, Scalar, Secp256k1 };
 use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::{
@@ -12,15 +17,31 @@ use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::{
 use multi_party_ecdsa::utilities::mta::{ MessageA, MessageB };
 use serde::{ Deserialize, Serialize };
 use sha2::Sha256;
+use shared::key_info::NodeId;
+use std::fmt;
+use zk_paillier::zkproofs::DLogStatement;
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct NewSignSession {
     pub session_id: String,
     pub key_id: String,
     pub message: Vec<u8>,
+    /// Sign with this stored presignature in a single round instead of the GG20 session
+    #[serde(default)]
+    pub presignature_id: Option<String>,
+    /// Hash applied to the message before signing
+    #[serde(default)]
+    pub hash_mode: HashMode,
+    /// Decoded transaction of requests flagged as Ethereum transactions, for the audit log
+    #[serde(default)]
+    pub transaction_summary: Option<String>,
+    /// BIP-32 path of the child key to sign for, every party derives its share of the child
+    #[serde(default)]
+    pub derivation_path: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct NewSignMessage {
     pub session_id: String,
     pub key_id: String,
@@ -31,12 +52,33 @@ pub struct NewSignMessage {
     pub timestamp: Option<String>,
     pub message_hmac: Option<String>,
     pub email: Option<String>,
+    #[serde(default)]
+    pub presignature_id: Option<String>,
+    #[serde(default)]
+    pub hash_mode: HashMode,
+    /// The message is an unsigned Ethereum transaction, decoded for the policy and the audit log
+    #[serde(default)]
+    pub is_ethereum_tx: Option<bool>,
+    /// BIP-32 path of the child key to sign for, see `keygen::derivation`
+    #[serde(default)]
+    pub derivation_path: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct JoinSignSessionResponse {
     pub id_in_session: usize,
     pub message: Vec<u8>,
+    /// Batch signing: further messages signed after `message` by the same parties, reusing the
+    /// session instead of joining a new one per message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional_messages: Vec<Vec<u8>>,
+}
+
+impl JoinSignSessionResponse {
+    /// Every message to sign in this session, in order
+    pub fn messages(&self) -> impl Iterator<Item = &Vec<u8>> {
+        std::iter::once(&self.message).chain(&self.additional_messages)
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -51,6 +93,34 @@ pub struct SigningResult {
     pub recid: u8,
 }
 
+/// Published on the result subject instead of a signature when a check of phase 5, 6 or 7 failed
+/// and the blame protocol ran
+#[derive(Deserialize, PartialEq, Serialize, Clone, Debug)]
+pub struct SigningFailure {
+    pub phase: u8,
+    /// Shareholder indices of the blamed parties, empty when the blame was inconclusive
+    pub blamed_parties: Vec<usize>,
+    /// Nodes holding the blamed shares, filled in by the orchestrator
+    #[serde(default)]
+    pub blamed_nodes: Vec<NodeId>,
+    pub reason: String,
+}
+
+impl fmt::Display for SigningFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.blamed_parties.is_empty() {
+            return write!(f, "Signing failed in phase {}: {}", self.phase, self.reason);
+        }
+        write!(
+            f,
+            "Signing failed in phase {}, blamed parties {:?}: {}",
+            self.phase,
+            self.blamed_parties,
+            self.reason
+        )
+    }
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Phase0Identity {
     pub id_in_session: usize,
@@ -158,3 +228,25 @@ impl HasSenderId for Phase7Signature {
         self.sender_id
     }
 }
+
+/// Ring-Pedersen parameters of the signers, in session order. Every MtA ciphertext carries a range
+/// proof under each of them, and each signer checks them all before using the ciphertext.
+pub(crate) fn signer_dlog_statements(keyshare: &ECDSA, signers: &[usize]) -> Vec<DLogStatement> {
+    signers
+        .iter()
+        .map(|&signer| keyshare.h1_h2_N_tilde_vec[signer].clone())
+        .collect()
+}
+
+/// Refuses co-signers whose Paillier key would leak bits of our secrets once encrypted to it.
+/// Keys are loaded from disk, so a party that crafted its key at keygen or recovery is caught at
+/// every signing.
+pub(crate) fn check_signer_paillier_keys(keyshare: &ECDSA, signers: &[usize]) -> Result<()> {
+    let own_signer = keyshare.party_index - 1;
+    for &signer in signers.iter().filter(|&&signer| signer != own_signer) {
+        check_peer_paillier_key(&keyshare.paillier_key_vec[signer]).map_err(|err|
+            anyhow!("Paillier key of party {} is refused: {}", signer + 1, err)
+        )?;
+    }
+    Ok(())
+}
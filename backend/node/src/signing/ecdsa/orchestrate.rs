@@ -1,18 +1,89 @@
 use crate::command::MsgContext;
-use crate::signing::ecdsa::{ JoinSignSessionResponse, NewSignSession, SigningResult };
+use crate::communication::blocking;
+use crate::reputation;
+use crate::signing::batch::BatchSigningCommand;
+use crate::signing::ecdsa::{
+    JoinSignSessionResponse,
+    NewSignSession,
+    SigningFailure,
+    SigningResult,
+};
+use crate::signing::hashing::HashMode;
 use crate::signing::{ SigningCommand, SigningResponse };
+use crate::storage::KeyInfoStore;
 use anyhow::{ bail, Context, Result };
-use tracing::{ error, info, instrument };
+use serde::de::DeserializeOwned;
+use shared::key_info::NodeId;
+use tracing::{ error, info, instrument, warn };
+
+/// Outcome of a GG20 session: what the parties signed or who they blamed for the failure
+enum SessionResult<T> {
+    Signed(T),
+    Failed(SigningFailure),
+}
 
 #[instrument(skip_all)]
 pub fn orchestrate(cmd: SigningCommand, ctx: MsgContext) -> Result<SigningResponse> {
     let app = ctx.get_app()?;
-    let nc = app.nc;
-    let session_id = cmd.session_id.clone();
+    let result = run_session::<SigningResult>(
+        &app.nc,
+        &cmd.session_id,
+        cmd.key_id,
+        &cmd.party_nodes,
+        cmd.msg,
+        Vec::new(),
+        cmd.hash_mode,
+        cmd.derivation_path
+    )?;
+    match result {
+        SessionResult::Signed(sig) => Ok(SigningResponse::ECDSA(sig)),
+        SessionResult::Failed(failure) => Ok(SigningResponse::Failed(failure)),
+    }
+}
 
-    let party_nodes = cmd.party_nodes;
-    let key_id = cmd.key_id;
+/// Signs every message of the batch in one session, the parties join once and sign the messages
+/// one after the other
+#[instrument(skip_all)]
+pub fn orchestrate_batch(cmd: BatchSigningCommand, ctx: MsgContext) -> Result<Vec<SigningResult>> {
+    let app = ctx.get_app()?;
+    let mut msgs = cmd.msgs.into_iter();
+    let first = msgs.next().context("Batch has no messages to sign")?;
+    let message_count = msgs.len() + 1;
+    let result = run_session::<Vec<SigningResult>>(
+        &app.nc,
+        &cmd.session_id,
+        cmd.key_id,
+        &cmd.party_nodes,
+        first,
+        msgs.collect(),
+        cmd.hash_mode,
+        None
+    )?;
+    let sigs = match result {
+        SessionResult::Signed(sigs) => sigs,
+        SessionResult::Failed(failure) => {
+            bail!("{}, blamed nodes {:?}", failure, failure.blamed_nodes);
+        }
+    };
+    if sigs.len() != message_count {
+        bail!("Received {} signatures for {} messages", sigs.len(), message_count);
+    }
+    Ok(sigs)
+}
 
+/// Starts a GG20 signing session and returns the result of the first party, a single signature
+/// or a list of them for a batch. A party that ran the blame protocol ends the session with the
+/// blamed parties.
+fn run_session<T: DeserializeOwned>(
+    nc: &blocking::Connection,
+    session_id: &str,
+    key_id: String,
+    party_nodes: &[NodeId],
+    message: Vec<u8>,
+    additional_messages: Vec<Vec<u8>>,
+    hash_mode: HashMode,
+    derivation_path: Option<String>
+) -> Result<SessionResult<T>> {
     let party_count = party_nodes.len();
     if party_count < 3 {
         let msg = "Not enough nodes in party";
@@ -28,9 +99,13 @@ pub fn orchestrate(cmd: SigningCommand, ctx: MsgContext) -> Result<SigningRespon
 
     let new_sign_session_msg = serde_json::to_string(
         &(NewSignSession {
-            session_id: session_id.clone(),
-            key_id,
-            message: cmd.msg.clone(),
+            session_id: session_id.to_string(),
+            key_id: key_id.clone(),
+            message: message.clone(),
+            presignature_id: None,
+            hash_mode,
+            transaction_summary: None,
+            derivation_path,
         })
     )?;
     for node_id in party_nodes.iter() {
@@ -46,7 +121,8 @@ pub fn orchestrate(cmd: SigningCommand, ctx: MsgContext) -> Result<SigningRespon
                 &serde_json::to_string(
                     &(JoinSignSessionResponse {
                         id_in_session: i,
-                        message: cmd.msg.clone(),
+                        message: message.clone(),
+                        additional_messages: additional_messages.clone(),
                     })
                 )?
             )
@@ -66,11 +142,33 @@ pub fn orchestrate(cmd: SigningCommand, ctx: MsgContext) -> Result<SigningRespon
 
     for _ in 0..party_count {
         let res = result_sub.next().context("Signature result received from every party")?;
+        // Blamed parties may never send a result, the first failure ends the session
+        if let Ok(mut failure) = serde_json::from_slice::<SigningFailure>(&res.data) {
+            failure.blamed_nodes = blamed_nodes(&key_id, &failure.blamed_parties);
+            reputation::record_blame(&failure.blamed_nodes, &failure.to_string());
+            warn!("ECDSA signing session {} failed: {}", session_id, failure);
+            return Ok(SessionResult::Failed(failure));
+        }
         res_vec.push(res);
     }
 
     info!("Signature result received");
 
-    let sig = serde_json::from_slice::<SigningResult>(&res_vec[0].data)?;
-    Ok(SigningResponse::ECDSA(sig))
+    Ok(SessionResult::Signed(serde_json::from_slice::<T>(&res_vec[0].data)?))
+}
+
+/// Nodes of the key's pool holding the blamed shares
+fn blamed_nodes(key_id: &str, blamed_parties: &[usize]) -> Vec<NodeId> {
+    match KeyInfoStore::get_key_info(key_id) {
+        Ok(key_info) =>
+            key_info.node_pool
+                .into_iter()
+                .filter(|node| blamed_parties.contains(&node.share_index))
+                .map(|node| node.node_id)
+                .collect(),
+        Err(err) => {
+            warn!("Failed to look up the nodes of the blamed parties: {}", err);
+            Vec::new()
+        }
+    }
 }
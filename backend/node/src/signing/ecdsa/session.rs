@@ -1,10 +1,27 @@
-use crate::communication::ecdsa::{ collect_messages_ordered, collect_messages_p2p, JoinMessage };
+use crate::approval::request_approval;
+use crate::audit::{ AuditAction, AuditedRequest };
+use crate::communication::blocking;
+use crate::communication::incoming::IncomingMessage;
+use crate::policy::{ enforce_signing_policy, SigningRequest };
+use crate::communication::ecdsa::{
+    collect_messages_ordered,
+    collect_messages_p2p,
+    HasSenderId,
+    JoinMessage,
+};
+use crate::communication::nats::{ observer_subject, ObserverTranscript, TranscriptRound };
+use crate::communication::subscription_registry::{ self, TrackedSubscription };
+use crate::metrics::{ self, time_signing_phase, SessionKind };
+use crate::keygen::derivation::{ derive_keyshare, DerivationPath };
+use crate::signing::presigning;
 use crate::signing::ecdsa;
+use crate::signing::ecdsa::warmup;
 use crate::signing::ecdsa::{
     JoinSignSessionErrorResponse,
     JoinSignSessionResponse,
     NewSignSession,
     NewSignMessage,
+    SigningFailure,
     SigningResult,
 };
 use crate::storage::{ KeyshareAccessor, ECDSA };
@@ -23,6 +40,7 @@ use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::blame::{
     LocalStatePhase5,
     LocalStatePhase6,
 };
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::ErrorType;
 use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::{
     Keys,
     LocalSignature,
@@ -35,28 +53,40 @@ use multi_party_ecdsa::utilities::mta::{ MessageA, MessageB };
 use multi_party_ecdsa::utilities::zk_pdl_with_slack::PDLwSlackProof;
 use paillier::EncryptionKey;
 use sha2::Sha256;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::any::type_name;
-use std::thread;
-use std::time::Duration;
-use tracing::{ error, info, instrument };
-use chrono::{ DateTime, Utc };
-use hmac::{ Hmac, Mac, NewMac };
-use base64;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+use tracing::{ error, info, instrument, warn };
 use crate::node::NodeIdentity;
 use crate::storage::fs::WriteOpts;
 use crate::storage::key_metadata_store::KeyMetadataStore;
-use crate::auth::e2e_decrypt;
+use crate::auth::client_e2e_decrypt_secret;
+use crate::signing::validation::{
+    check_access_key,
+    check_transfer_target,
+    verify_hmac,
+    verify_timestamp,
+};
+use crate::session_error::{ self, SessionErrorCode };
+use crate::observer::consented_observers;
+use crate::session_manager;
+use crate::slo;
+use crate::signing::batch::MAX_BATCH_SIZE;
+use crate::signing::ecdsa::{ check_signer_paillier_keys, signer_dlog_statements };
+use crate::signing::hashing::HashMode;
+use crate::signing::tx_inspector;
 
-const PARTIES: usize = 5;
-const THRESHOLD: usize = 3;
 
 const PHASES: usize = 8;
 const P2P_PHASE: usize = 2;
 
-fn format_session_subject(sess: &NewSignSession, suffix: &str) -> String {
+fn format_session_subject(session_id: &str, suffix: &str) -> String {
     format!(
         "network.gridlock.nodes.keySign.session.{}{}{}",
-        sess.session_id,
+        session_id,
         if suffix.is_empty() {
             ""
         } else {
@@ -66,7 +96,7 @@ fn format_session_subject(sess: &NewSignSession, suffix: &str) -> String {
     )
 }
 
-fn signature_recid_to_signing_result(sig: &SignatureRecid) -> SigningResult {
+pub(crate) fn signature_recid_to_signing_result(sig: &SignatureRecid) -> SigningResult {
     let fe_to_string = |x: &Scalar<Secp256k1>| {
         format!("{:0>width$}", x.to_bigint().to_str_radix(16), width = 64usize)
     };
@@ -78,29 +108,134 @@ fn signature_recid_to_signing_result(sig: &SignatureRecid) -> SigningResult {
     }
 }
 
+/// Verifies the signature against the group public key with libsecp256k1
+pub(crate) fn check_sig(
+    r: &Scalar<Secp256k1>,
+    s: &Scalar<Secp256k1>,
+    msg: &BigInt,
+    pk: &Point<Secp256k1>
+) -> anyhow::Result<()> {
+    use secp256k1::{ Message, PublicKey, Secp256k1, Signature };
+
+    let raw_msg = BigInt::to_bytes(msg);
+    if raw_msg.len() > 32 {
+        panic!("Message longer then 32 bytes! msg: {:?}", raw_msg);
+    }
+
+    let mut msg: Vec<u8> = Vec::new(); // padding
+    msg.extend(vec![0u8; 32 - raw_msg.len()]);
+    msg.extend(raw_msg.iter());
+
+    let msg = Message::from_slice(msg.as_slice())?;
+    let mut raw_pk = pk.to_bytes(false).to_vec();
+    if raw_pk.len() == 64 {
+        raw_pk.insert(0, 4u8);
+    }
+    let pk = PublicKey::from_slice(&raw_pk)?;
+
+    let mut compact: Vec<u8> = Vec::new();
+    let bytes_r = &r.to_bytes()[..];
+    compact.extend(vec![0u8; 32 - bytes_r.len()]);
+    compact.extend(bytes_r.iter());
+
+    let bytes_s = &s.to_bytes()[..];
+    compact.extend(vec![0u8; 32 - bytes_s.len()]);
+    compact.extend(bytes_s.iter());
+
+    let secp_sig = Signature::from_compact(compact.as_slice())?;
+
+    Ok(Secp256k1::new().verify(&msg, &secp_sig, &pk)?)
+}
+
 pub struct SignPhase {
     topic: String,
-    sub: nats::Subscription,
+    sub: blocking::Subscription,
+    _tracked: TrackedSubscription,
 }
 
 impl SignPhase {
     pub fn new(
-        connection: &nats::Connection,
-        session: &NewSignSession,
+        connection: &blocking::Connection,
+        session_id: &str,
         name: &str
     ) -> anyhow::Result<Self> {
-        let subject = format_session_subject(session, name);
+        let subject = format_session_subject(session_id, name);
         info!("Subscribing to topic \"{}\"", &subject);
 
         let subscription = connection.subscribe(&subject)?;
+        // Closing a stale subscription ends the wait of a session blocked on it
+        let stale = subscription.clone();
+        let tracked = subscription_registry::track(session_id, &subject, move || {
+            let _ = stale.unsubscribe();
+        });
 
         Ok(Self {
             sub: subscription,
             topic: subject,
+            _tracked: tracked,
         })
     }
 }
 
+impl Drop for SignPhase {
+    fn drop(&mut self) {
+        if let Err(err) = self.sub.clone().unsubscribe() {
+            warn!("Failed to unsubscribe from {}: {}", self.topic, err);
+        }
+    }
+}
+
+/// Subscriptions to every subject of a signing session, made before joining it so no message of
+/// the other parties is missed
+pub(crate) struct SessionSubscriptions {
+    start: SignPhase,
+    phases: Vec<SignPhase>,
+    phase2_p2p: Vec<SignPhase>,
+}
+
+impl SessionSubscriptions {
+    pub(crate) fn subscribe(
+        connection: &blocking::Connection,
+        session_id: &str,
+        parties: usize
+    ) -> anyhow::Result<Self> {
+        let start = SignPhase::new(connection, session_id, "start")?;
+
+        let mut phases: Vec<SignPhase> = Vec::with_capacity(PHASES);
+        for i in 0..PHASES {
+            // phase2 is a p2p phase and receives special treatment
+            if i == P2P_PHASE {
+                continue;
+            }
+
+            phases.push(SignPhase::new(connection, session_id, &format!("phase{}", i))?);
+        }
+
+        let mut phase2_p2p: Vec<SignPhase> = Vec::with_capacity(parties);
+        for i in 0..parties {
+            phase2_p2p.push(SignPhase::new(connection, session_id, &format!("phase2.to{}", i))?);
+        }
+
+        Ok(Self { start, phases, phase2_p2p })
+    }
+}
+
+/// Commitments to the parties' shares of the secret, they only depend on the keyshare
+pub(crate) fn xi_commitments(keyshare: &ECDSA) -> Vec<Point<Secp256k1>> {
+    Keys::get_commitments_to_xi(
+        &keyshare.vss_scheme_vec.iter().cloned().map_into().collect::<Vec<_>>()
+    )
+}
+
+/// Public shares of the signers, weighted by their lagrange coefficients for this set of signers
+pub(crate) fn compute_g_w_vec(keyshare: &ECDSA, signers_vec: &[usize]) -> Vec<Point<Secp256k1>> {
+    SignKeys::g_w_vec(
+        &keyshare.public_key_vec.iter().cloned().map_into().collect::<Vec<_>>(),
+        signers_vec,
+        &keyshare.vss_scheme_vec[keyshare.party_index - 1].clone()
+    )
+}
+
 struct Phase1Data {
     pub decommit: SignDecommitPhase1,
     pub bc1_vec: Vec<SignBroadcastPhase1>,
@@ -157,11 +292,11 @@ struct Phase7Data {
 impl SignSession {
     #[instrument(skip_all)]
     fn session_join(
-        conn: &nats::Connection,
+        conn: &blocking::Connection,
         sess: &NewSignSession
     ) -> anyhow::Result<JoinSignSessionResponse> {
         info!("START");
-        let join_subject = format_session_subject(sess, "join");
+        let join_subject = format_session_subject(&sess.session_id, "join");
         let join_message = serde_json::to_string(&JoinMessage::new(sess.session_id.clone(), 0))?;
         info!(
             "Sending Request on Subject {} session_id: {}, key_id: {}",
@@ -178,15 +313,30 @@ impl SignSession {
         match serde_json::from_slice::<JoinSignSessionResponse>(&response_json.data) {
             Ok(ok) => {
                 info!("OK RESPONSE");
-                if ok.message.len() > 32 {
+                if ok.additional_messages.len() >= MAX_BATCH_SIZE {
+                    bail!(
+                        "Join response carries {} messages, at most {} are signed per session",
+                        ok.additional_messages.len() + 1,
+                        MAX_BATCH_SIZE
+                    );
+                }
+                let hashed = JoinSignSessionResponse {
+                    id_in_session: ok.id_in_session,
+                    message: sess.hash_mode.apply(&ok.message),
+                    additional_messages: ok.additional_messages
+                        .iter()
+                        .map(|message| sess.hash_mode.apply(message))
+                        .collect(),
+                };
+                if let Some(message) = hashed.messages().find(|message| message.len() > 32) {
                     let err_msg = format!(
                         "message has size more than 32 bytes! message: {:?}",
-                        ok.message
+                        message
                     );
                     error!("{}", err_msg);
                     bail!("{}", err_msg);
                 } else {
-                    Ok(ok)
+                    Ok(hashed)
                 }
             }
             Err(_) => {
@@ -212,50 +362,120 @@ impl SignSession {
 
     #[instrument(skip_all)]
     pub fn new(
-        connection: nats::Connection,
+        connection: blocking::Connection,
         session: NewSignSession,
         email: Option<String>
     ) -> anyhow::Result<Self> {
-        // Use email-aware keyshare accessor if email is provided
-        let keyshare = (
-            if let Some(email_str) = email {
-                KeyshareAccessor::<ECDSA>::read_only_with_email(&session.key_id, &email_str)?
-            } else {
-                KeyshareAccessor::<ECDSA>::read_only(&session.key_id)?
+        let warm = warmup::take(&session.session_id, &session.key_id, email.as_deref());
+        let observers = email
+            .as_deref()
+            .map(|email| consented_observers(&session.key_id, email))
+            .unwrap_or_default();
+        let (keyshare, subscriptions, xi_com_vec, warm_g_w) = match warm {
+            Some(warm) => {
+                info!("Using the warmed up state of session {}", session.session_id);
+                (warm.keyshare, warm.subscriptions, warm.xi_com_vec, warm.g_w_by_signer)
             }
-        ).key;
-
-        let start_phase = SignPhase::new(&connection, &session, "start")?;
-
-        let mut phase_vec: Vec<SignPhase> = Vec::with_capacity(PHASES);
-        for i in 0..PHASES {
-            // phase2 is a p2p phase and receives special treatment
-            if i == P2P_PHASE {
-                continue;
+            None => {
+                // Use email-aware keyshare accessor if email is provided
+                let keyshare = (
+                    if let Some(email_str) = email {
+                        KeyshareAccessor::<ECDSA>::read_only_with_email(
+                            &session.key_id,
+                            &email_str
+                        )?
+                    } else {
+                        KeyshareAccessor::<ECDSA>::read_only(&session.key_id)?
+                    }
+                ).key;
+                let subscriptions = SessionSubscriptions::subscribe(
+                    &connection,
+                    &session.session_id,
+                    keyshare.public_key_vec.len()
+                )?;
+                let xi_com_vec = xi_commitments(&keyshare);
+                (keyshare, subscriptions, xi_com_vec, None)
             }
-
-            let phase = SignPhase::new(&connection, &session, &format!("phase{}", i))?;
-            phase_vec.push(phase);
-        }
-
-        let mut phase2_p2p_vec: Vec<SignPhase> = Vec::with_capacity(PARTIES);
-        for i in 0..PARTIES {
-            let phase = SignPhase::new(&connection, &session, &format!("phase2.to{}", i))?;
-            phase2_p2p_vec.push(phase);
-        }
+        };
+        let SessionSubscriptions { start, mut phases, mut phase2_p2p } = subscriptions;
+        // Shares of a child key are the parent's shifted by the child's tweak, so are the
+        // commitments the warmed up state was computed from
+        let (keyshare, xi_com_vec, warm_g_w) = match &session.derivation_path {
+            Some(path) => {
+                let (derived, _) = derive_keyshare(&keyshare, &path.parse::<DerivationPath>()?)?;
+                let xi_com_vec = xi_commitments(&derived);
+                (derived, xi_com_vec, None)
+            }
+            None => (keyshare, xi_com_vec, warm_g_w),
+        };
 
         let party_info = Self::session_join(&connection, &session)?;
-        phase_vec.insert(P2P_PHASE, phase2_p2p_vec.remove(party_info.id_in_session));
+        phases.insert(P2P_PHASE, phase2_p2p.remove(party_info.id_in_session));
         Ok(Self {
             connection,
-            start_phase,
-            phases: phase_vec,
+            start_phase: start,
+            phases,
             keyshare,
             party_info,
             session,
+            xi_com_vec,
+            warm_g_w,
+            observers,
+            transcript: Mutex::new(Vec::new()),
         })
     }
 
+    /// Parties taking part in the signature, one more than the threshold the key was generated with
+    fn signer_count(&self) -> usize {
+        self.keyshare.threshold + 1
+    }
+
+    /// Publishes the message of a broadcast phase to the parties and the observers
+    fn broadcast(&self, phase: usize, json: &str) -> anyhow::Result<()> {
+        let topic = &self.phases[phase].topic;
+        self.connection.publish(topic, json)?;
+        for observer_id in &self.observers {
+            self.connection.publish(&observer_subject(observer_id, topic), json)?;
+        }
+        Ok(())
+    }
+
+    /// Collects the messages of a broadcast phase, recorded in the transcript when observed
+    fn collect_broadcasts<T>(&self, phase: usize) -> anyhow::Result<Vec<T>>
+        where T: Serialize + DeserializeOwned + HasSenderId + Clone
+    {
+        let messages = collect_messages_ordered::<T>(&self.phases[phase].sub, self.signer_count())?;
+        if !self.observers.is_empty() {
+            self.transcript.lock().unwrap().push(TranscriptRound {
+                round: format!("phase{}", phase),
+                messages: messages
+                    .iter()
+                    .map(serde_json::to_value)
+                    .collect::<Result<_, _>>()?,
+            });
+        }
+        Ok(messages)
+    }
+
+    /// Sends the broadcast phases collected so far to every observer, call once the session ends
+    fn publish_transcript(&self) -> anyhow::Result<()> {
+        if self.observers.is_empty() {
+            return Ok(());
+        }
+        let transcript = serde_json::to_string(
+            &(ObserverTranscript {
+                session_id: self.session.session_id.clone(),
+                party_index: self.party_info.id_in_session,
+                rounds: self.transcript.lock().unwrap().clone(),
+            })
+        )?;
+        let subject = format_session_subject(&self.session.session_id, "Transcript");
+        for observer_id in &self.observers {
+            self.connection.publish(&observer_subject(observer_id, &subject), &transcript)?;
+        }
+        Ok(())
+    }
+
     fn wait_for_start_message(&self) {
         self.start_phase.sub.next_timeout(Duration::from_secs(10)).unwrap();
     }
@@ -268,14 +488,14 @@ impl SignSession {
         };
         let json = serde_json::to_string(&mesg).unwrap();
         info!("publishing on subject {}", &self.phases[0].topic);
-        self.connection.publish(&self.phases[0].topic, &json).unwrap();
+        self.broadcast(0, &json)?;
 
-        // Shareholder IDs generated during keygen are in 1..=PARTIES range,
-        // but most of the signing code expects them to be in 0..PARTIES range,
+        // Shareholder IDs generated during keygen are in 1..=n range for n parties,
+        // but most of the signing code expects them to be in 0..n range,
         // hence the -1 in the lambda.
         info!("collecting Phase0Identity");
         Ok(
-            collect_messages_ordered::<ecdsa::Phase0Identity>(&self.phases[0].sub, THRESHOLD)?
+            self.collect_broadcasts::<ecdsa::Phase0Identity>(0)?
                 .into_iter()
                 .map(|p0i| p0i.shareholder_id - 1)
                 .collect()
@@ -295,16 +515,13 @@ impl SignSession {
         };
         let json = serde_json::to_string(&mesg).unwrap();
         info!("publishing on subject {}", &self.phases[1].topic);
-        self.connection.publish(&self.phases[1].topic, &json).unwrap();
+        self.broadcast(1, &json)?;
 
         let mut com_vec: Vec<SignBroadcastPhase1> = vec![];
         let mut m_vec: Vec<MessageA> = vec![];
         info!("collecting phase1_broadcast_commitment");
 
-        for p1c in collect_messages_ordered::<ecdsa::Phase1Commitment>(
-            &self.phases[1].sub,
-            THRESHOLD
-        )? {
+        for p1c in self.collect_broadcasts::<ecdsa::Phase1Commitment>(1)? {
             com_vec.push(p1c.commitment);
             m_vec.push(p1c.message);
         }
@@ -314,11 +531,13 @@ impl SignSession {
 
     #[instrument(skip_all)]
     fn phase1(&self, signers_vec: &[usize]) -> anyhow::Result<Phase1Data> {
-        let g_w_vec = SignKeys::g_w_vec(
-            &self.keyshare.public_key_vec.iter().cloned().map_into().collect::<Vec<_>>(),
-            signers_vec,
-            &self.keyshare.vss_scheme_vec[self.keyshare.party_index - 1].clone()
-        );
+        let warm_g_w_vec = self.warm_g_w
+            .as_ref()
+            .and_then(|g_w_by_signer| warmup::g_w_vec_for(g_w_by_signer, signers_vec));
+        let g_w_vec = match warm_g_w_vec {
+            Some(g_w_vec) => g_w_vec,
+            None => compute_g_w_vec(&self.keyshare, signers_vec),
+        };
         /* let private = PartyPrivate::set_private(
             self.keyshare.party_keys.clone(),
             self.keyshare.shared_keys.clone(),
@@ -331,15 +550,15 @@ impl SignSession {
             signers_vec
         );
 
-        let xi_com_vec = Keys::get_commitments_to_xi(
-            &self.keyshare.vss_scheme_vec.iter().cloned().map_into().collect::<Vec<_>>()
-        );
+        let xi_com_vec = self.xi_com_vec.clone();
 
         let (com, decommit) = sign_keys.phase1_broadcast();
+        // Range proofs of k_i under the ring-Pedersen parameters of every signer, so each of them
+        // can check that k_i is small before multiplying it with its secrets in phase 2
         let (m_a_k, randomness) = MessageA::a(
             &sign_keys.k_i,
             &self.keyshare.paillier_key_vec[&self.keyshare.party_index - 1],
-            &[]
+            &signer_dlog_statements(&self.keyshare, &signers_vec[..self.signer_count()])
         );
 
         let (bc1_vec, m_a_vec) = self.phase1_broadcast_commitment(&com, &m_a_k)?;
@@ -363,7 +582,7 @@ impl SignSession {
         m_b_vec: &[MessageB]
     ) -> anyhow::Result<(Vec<MessageB>, Vec<MessageB>)> {
         let mut index: usize = 0;
-        for party_id in 0..THRESHOLD {
+        for party_id in 0..self.signer_count() {
             if party_id == self.party_info.id_in_session {
                 continue;
             }
@@ -376,7 +595,10 @@ impl SignSession {
             };
             let json = serde_json::to_string(&mesg).unwrap();
 
-            let subject = format_session_subject(&self.session, &format!("phase2.to{}", party_id));
+            let subject = format_session_subject(
+                &self.session.session_id,
+                &format!("phase2.to{}", party_id)
+            );
             info!("publish on subject {}", &subject);
             self.connection.publish(&subject, &json).unwrap();
 
@@ -388,7 +610,7 @@ impl SignSession {
         info!("collect_messages_p2p Phase2Gamma");
         for p2g in collect_messages_p2p::<ecdsa::Phase2Gamma>(
             &self.phases[2].sub,
-            THRESHOLD,
+            self.signer_count(),
             self.party_info.id_in_session
         )? {
             gamma_vec.push(p2g.gamma);
@@ -405,26 +627,30 @@ impl SignSession {
         let mut beta_randomness_vec = Vec::new();
         let mut beta_tag_vec = Vec::new();
         let mut ni_vec = Vec::new();
+        let signers = &signers_vec[..self.signer_count()];
+        check_signer_paillier_keys(&self.keyshare, signers)?;
+        let dlog_statements = signer_dlog_statements(&self.keyshare, signers);
 
-        for (i, &signer) in signers_vec.iter().enumerate().take(THRESHOLD) {
+        for (i, &signer) in signers_vec.iter().enumerate().take(self.signer_count()) {
             if i != self.party_info.id_in_session {
+                // MessageB::b verifies the range proofs of the co-signer's MessageA
                 let (m_b_gamma, beta_gamma, beta_randomness, beta_tag) = match
                     MessageB::b(
                         &p1d.sign_keys.gamma_i,
                         &self.keyshare.paillier_key_vec[signer],
                         p1d.m_a_vec[i].clone(),
-                        &[]
+                        &dlog_statements
                     )
                 {
                     Ok((a, b, c, d)) => (a, b, c, d),
-                    Err(_) => bail!("Message b failure in Phase 2"),
+                    Err(_) => bail!("MtA range proofs of party {} failed in Phase 2", signer + 1),
                 };
                 let (m_b_w, beta_wi, _, _) = match
                     MessageB::b(
                         &p1d.sign_keys.w_i,
                         &self.keyshare.paillier_key_vec[signer],
                         p1d.m_a_vec[i].clone(),
-                        &[]
+                        &dlog_statements
                     )
                 {
                     Ok((a, b, c, d)) => (a, b, c, d),
@@ -458,7 +684,7 @@ impl SignSession {
         let mut miu_bigint_vec = Vec::new();
         let mut j = 0;
 
-        for i in 0..THRESHOLD {
+        for i in 0..self.signer_count() {
             if i != self.party_info.id_in_session {
                 let m_b = m_b_gamma_rec_vec[j].clone();
 
@@ -515,15 +741,12 @@ impl SignSession {
         let json = serde_json::to_string(&mesg).unwrap();
         info!("publish on {} ", &self.phases[3].topic);
 
-        self.connection.publish(&self.phases[3].topic, &json).unwrap();
+        self.broadcast(3, &json)?;
 
         let mut delta_vec: Vec<Scalar<Secp256k1>> = vec![];
         let mut t_vec: Vec<Point<Secp256k1>> = vec![];
         info!("collect Phase3Broadcast");
-        for p3b in collect_messages_ordered::<ecdsa::Phase3Broadcast>(
-            &self.phases[3].sub,
-            THRESHOLD
-        )? {
+        for p3b in self.collect_broadcasts::<ecdsa::Phase3Broadcast>(3)? {
             delta_vec.push(p3b.delta);
             t_vec.push(p3b.t);
         }
@@ -560,10 +783,10 @@ impl SignSession {
         };
         let json = serde_json::to_string(&mesg).unwrap();
         info!("publish {}", &self.phases[4].topic);
-        self.connection.publish(&self.phases[4].topic, &json).unwrap();
+        self.broadcast(4, &json)?;
         info!("collect Phase4Decommit");
         Ok(
-            collect_messages_ordered::<ecdsa::Phase4Decommit>(&self.phases[4].sub, THRESHOLD)?
+            self.collect_broadcasts::<ecdsa::Phase4Decommit>(4)?
                 .into_iter()
                 .map(|p4d| p4d.decommit)
                 .collect()
@@ -606,11 +829,11 @@ impl SignSession {
         };
         let json = serde_json::to_string(&mesg).unwrap();
         info!("publish {}", &self.phases[5].topic);
-        self.connection.publish(&self.phases[5].topic, &json).unwrap();
+        self.broadcast(5, &json)?;
         info!("collect Phase5RDash");
 
         Ok(
-            collect_messages_ordered::<ecdsa::Phase5RDash>(&self.phases[5].sub, THRESHOLD)?
+            self.collect_broadcasts::<ecdsa::Phase5RDash>(5)?
                 .into_iter()
                 .map(|p5rd| p5rd.r_dash)
                 .collect()
@@ -618,12 +841,19 @@ impl SignSession {
     }
 
     #[instrument(skip_all)]
-    fn phase5_blame(&self, p1d: &Phase1Data, p2d: &Phase2Data, p3d: &Phase3Data, p4d: &Phase4Data) {
+    fn phase5_blame(
+        &self,
+        signers_vec: &[usize],
+        p1d: &Phase1Data,
+        p2d: &Phase2Data,
+        p3d: &Phase3Data,
+        p4d: &Phase4Data
+    ) -> SigningFailure {
         let mut local_state_vec = Vec::new();
         // compose beta tag vector:
         let mut beta_tag_vec_to_test = Vec::new();
         let mut beta_randomness_vec_to_test = Vec::new();
-        for j in 0..THRESHOLD - 1 {
+        for j in 0..self.signer_count() - 1 {
             // this code is different from the "simplify to continue" case
             let index = if j < self.party_info.id_in_session + 1 {
                 self.party_info.id_in_session - 1
@@ -654,7 +884,7 @@ impl SignSession {
             .map(|i| p1d.m_a_vec[i].clone())
             .collect::<Vec<MessageA>>();
         // reduce ek vec to only ek of participants :
-        let paillier_key_vector = (0..THRESHOLD)
+        let paillier_key_vector = (0..self.signer_count())
             .map(|k| self.keyshare.paillier_key_vec[k].clone())
             .collect::<Vec<EncryptionKey>>();
 
@@ -666,10 +896,7 @@ impl SignSession {
             p2d.m_b_gamma_all_mtx.clone(),
             &local_state_vec[..]
         );
-        match global_state.phase5_blame() {
-            Ok(_) => error!("Unable to determine blame during phase5"),
-            Err(err) => error!("Assigned blame to signer(s): {:?}", err),
-        };
+        blame_failure(5, signers_vec, global_state.phase5_blame())
     }
 
     #[instrument(skip_all)]
@@ -686,7 +913,7 @@ impl SignSession {
 
         // phase 5
         let mut phase5_proofs: Vec<PDLwSlackProof> = Vec::new();
-        for i in 0..THRESHOLD {
+        for i in 0..self.signer_count() {
             if i == self.party_info.id_in_session {
                 continue;
             }
@@ -718,7 +945,8 @@ impl SignSession {
             Ok(_) => {}
             Err(_) => {
                 error!("Phase5 R_dash sum check failed, initiating blame protocol");
-                self.phase5_blame(p1d, p2d, p3d, p4d);
+                let failure = self.phase5_blame(signers_vec, p1d, p2d, p3d, p4d);
+                return Err(self.publish_failure(failure));
             }
         }
 
@@ -742,16 +970,13 @@ impl SignSession {
         };
         let json = serde_json::to_string(&mesg).unwrap();
         info!("publish on subject {} ", &self.phases[6].topic);
-        self.connection.publish(&self.phases[6].topic, &json).unwrap();
+        self.broadcast(6, &json)?;
 
         let mut S_vec: Vec<Point<Secp256k1>> = vec![];
         let mut R_vec: Vec<Point<Secp256k1>> = vec![];
         let mut zk_proof_vec: Vec<HomoELGamalProof<Secp256k1, Sha256>> = vec![];
         info!("collect Phase6Broadcast");
-        for msg in collect_messages_ordered::<ecdsa::Phase6Broadcast>(
-            &self.phases[6].sub,
-            THRESHOLD
-        )? {
+        for msg in self.collect_broadcasts::<ecdsa::Phase6Broadcast>(6)? {
             S_vec.push(msg.s);
             R_vec.push(msg.r);
             zk_proof_vec.push(msg.zk_proof);
@@ -769,7 +994,7 @@ impl SignSession {
         p2d: &Phase2Data,
         p3d: &Phase3Data,
         p4d: &Phase4Data
-    ) {
+    ) -> SigningFailure {
         // initiate phase 6 blame protocol to learn which parties acted maliciously.
         // each party generates local state and share with other parties.
         // assuming sync communication - if a message was failed to arrive from a party -
@@ -777,7 +1002,7 @@ impl SignSession {
         let proof = GlobalStatePhase6::ecddh_proof(&p3d.sigma, &p4d.R, S_i);
 
         let mut miu_randomness_vec = Vec::new();
-        for j in 0..THRESHOLD - 1 {
+        for j in 0..self.signer_count() - 1 {
             let rand = GlobalStatePhase6::extract_paillier_randomness(
                 &p2d.m_b_w_rec_vec[j].c,
                 &self.keyshare.paillier_dk
@@ -801,7 +1026,7 @@ impl SignSession {
             .collect::<Vec<MessageA>>();
 
         // reduce ek vec to only ek of participants :
-        let ek_vec = (0..THRESHOLD)
+        let ek_vec = (0..self.signer_count())
             .map(|k| self.keyshare.paillier_key_vec[signers_vec[k]].clone())
             .collect::<Vec<EncryptionKey>>();
 
@@ -814,10 +1039,7 @@ impl SignSession {
             &local_state_vec[..]
         );
         //changed this to R, as it seems to just use a generic R by calling R_vec[0]
-        match global_state.phase6_blame(&p4d.R) {
-            Ok(_) => error!("Unable to determine blame during phase6"),
-            Err(err) => error!("Assigned blame to signer(s): {:?}", err),
-        }
+        blame_failure(6, signers_vec, global_state.phase6_blame(&p4d.R))
     }
 
     #[instrument(skip_all)]
@@ -845,7 +1067,8 @@ impl SignSession {
             Ok(_) => {}
             Err(_) => {
                 info!("Phase6 S_i sum check failed, initiating blame protocol");
-                self.phase6_blame(&S_i, &S_vec, signers_vec, p1d, p2d, p3d, p4d);
+                let failure = self.phase6_blame(&S_i, &S_vec, signers_vec, p1d, p2d, p3d, p4d);
+                return Err(self.publish_failure(failure));
             }
         }
 
@@ -864,10 +1087,10 @@ impl SignSession {
         let json = serde_json::to_string(&mesg).unwrap();
         info!("publish subject {}", &self.phases[7].topic);
         info!("About to publish message: {json}");
-        self.connection.publish(&self.phases[7].topic, &json)?;
+        self.broadcast(7, &json)?;
         info!("collect Phase7Signature");
         Ok(
-            collect_messages_ordered::<ecdsa::Phase7Signature>(&self.phases[7].sub, THRESHOLD)?
+            self.collect_broadcasts::<ecdsa::Phase7Signature>(7)?
                 .into_iter()
                 .map(|p7s| p7s.signature)
                 .collect()
@@ -877,11 +1100,12 @@ impl SignSession {
     #[instrument(skip_all)]
     fn phase7_blame(
         &self,
+        signers_vec: &[usize],
         s_vec: Vec<Scalar<Secp256k1>>,
         local_sig_vec: &[LocalSignature],
         p5d: &Phase5Data,
         p6d: &Phase6Data
-    ) {
+    ) -> SigningFailure {
         let global_state = GlobalStatePhase7 {
             s_vec,
             r: local_sig_vec[0].r.clone(),
@@ -890,22 +1114,21 @@ impl SignSession {
             R: local_sig_vec[0].R.clone(),
             S_vec: p6d.S_vec.clone(),
         };
-        match global_state.phase7_blame() {
-            Ok(_) => error!("Unable to determine blame during phase7"),
-            Err(err) => error!("Assigned blame to signer(s): {:?}", err),
-        }
+        blame_failure(7, signers_vec, global_state.phase7_blame())
     }
 
     #[instrument(skip_all)]
     fn phase7(
         &self,
+        signers_vec: &[usize],
         p1d: &Phase1Data,
         p3d: &Phase3Data,
         p4d: &Phase4Data,
         p5d: &Phase5Data,
-        p6d: &Phase6Data
+        p6d: &Phase6Data,
+        message: &[u8]
     ) -> anyhow::Result<Phase7Data> {
-        let message_bn: BigInt = BigInt::from_bytes(&self.party_info.message[..]);
+        let message_bn: BigInt = BigInt::from_bytes(message);
         let mut s_vec: Vec<Scalar<Secp256k1>> = Vec::new();
 
         let local_sig = LocalSignature::phase7_local_sig(
@@ -919,7 +1142,7 @@ impl SignSession {
         let local_sig_vec = self.phase7_broadcast_signature(&local_sig)?;
 
         // sum the s_i's
-        for i in 0..THRESHOLD {
+        for i in 0..self.signer_count() {
             if i != self.party_info.id_in_session {
                 s_vec.push(local_sig_vec[i].s_i.clone());
             } else {
@@ -931,10 +1154,8 @@ impl SignSession {
             Ok(val) => val,
             Err(_) => {
                 error!("Failed to output signature during phase7, initiating blame protocol");
-                self.phase7_blame(s_vec, &local_sig_vec, p5d, p6d);
-
-                // phase7_blame calls panic! already, we need this so the type checker won't complain
-                panic!();
+                let failure = self.phase7_blame(signers_vec, s_vec, &local_sig_vec, p5d, p6d);
+                return Err(self.publish_failure(failure));
             }
         };
 
@@ -945,88 +1166,121 @@ impl SignSession {
         })
     }
 
-    fn check_sig(
-        r: &Scalar<Secp256k1>,
-        s: &Scalar<Secp256k1>,
-        msg: &BigInt,
-        pk: &Point<Secp256k1>
-    ) -> anyhow::Result<()> {
-        use secp256k1::{ Message, PublicKey, Secp256k1, Signature };
-
-        let raw_msg = BigInt::to_bytes(msg);
-        if raw_msg.len() > 32 {
-            panic!("Message longer then 32 bytes! msg: {:?}", raw_msg);
-        }
-
-        let mut msg: Vec<u8> = Vec::new(); // padding
-        msg.extend(vec![0u8; 32 - raw_msg.len()]);
-        msg.extend(raw_msg.iter());
-
-        let msg = Message::from_slice(msg.as_slice())?;
-        let mut raw_pk = pk.to_bytes(false).to_vec();
-        if raw_pk.len() == 64 {
-            raw_pk.insert(0, 4u8);
+    /// Tells the orchestrator who the blame protocol blamed, the session ends with the returned
+    /// error
+    fn publish_failure(&self, failure: SigningFailure) -> anyhow::Error {
+        let subject = format_session_subject(&self.session.session_id, "result");
+        let json = serde_json::to_string(&failure).unwrap();
+        if let Err(err) = self.connection.publish(&subject, &json) {
+            error!("Failed to publish the signing failure: {}", err);
         }
-        let pk = PublicKey::from_slice(&raw_pk)?;
-
-        let mut compact: Vec<u8> = Vec::new();
-        let bytes_r = &r.to_bytes()[..];
-        compact.extend(vec![0u8; 32 - bytes_r.len()]);
-        compact.extend(bytes_r.iter());
-
-        let bytes_s = &s.to_bytes()[..];
-        compact.extend(vec![0u8; 32 - bytes_s.len()]);
-        compact.extend(bytes_s.iter());
-
-        let secp_sig = Signature::from_compact(compact.as_slice())?;
-
-        Ok(Secp256k1::new().verify(&msg, &secp_sig, &pk)?)
+        anyhow!("{}", failure)
     }
 
+    /// A single signature for a single message, the list of signatures in order for a batch
     #[instrument(skip_all)]
-    fn send_result(&mut self, p7d: &Phase7Data) -> anyhow::Result<()> {
-        let subject = format_session_subject(&self.session, "result");
-        let mesg = signature_recid_to_signing_result(&p7d.sig);
-
-        let json = serde_json::to_string(&mesg)?;
+    fn send_result(&mut self, mut results: Vec<SigningResult>) -> anyhow::Result<()> {
+        let subject = format_session_subject(&self.session.session_id, "result");
+        let json = if results.len() == 1 {
+            serde_json::to_string(&results.remove(0))?
+        } else {
+            serde_json::to_string(&results)?
+        };
         self.connection.publish(&subject, &json)?;
 
         info!("Signing session result sent by node #{}!", self.party_info.id_in_session);
         Ok(())
     }
 
+    /// Signs every message of the session. The parties and their identities are exchanged once,
+    /// phases 1 to 7 run again for each message on the same subscriptions so every signature
+    /// gets a fresh nonce.
     #[instrument(skip_all)]
     pub fn sign(&mut self) -> anyhow::Result<()> {
         info!("waiting for START message from communication-hub");
         self.wait_for_start_message();
         info!("calling phase 0");
-        let signers = self.phase0__exchange_party_ids()?;
+        let signers = time_signing_phase("phase0", || self.phase0__exchange_party_ids())?;
+
+        let messages: Vec<Vec<u8>> = self.party_info.messages().cloned().collect();
+        let mut results = Vec::with_capacity(messages.len());
+        for (index, message) in messages.iter().enumerate() {
+            if messages.len() > 1 {
+                info!("Signing message {} of {}", index + 1, messages.len());
+            }
+            let sig = self.sign_message(&signers, message)?;
+            results.push(signature_recid_to_signing_result(&sig));
+        }
+        info!("send result");
+        self.send_result(results)?;
+        self.publish_transcript()
+    }
+
+    fn sign_message(&self, signers: &[usize], message: &[u8]) -> anyhow::Result<SignatureRecid> {
         info!("calling phase 1");
-        let p1d = self.phase1(&signers)?;
+        let p1d = time_signing_phase("phase1", || self.phase1(signers))?;
         info!("calling phase 2");
-        let p2d = self.phase2(&signers, &p1d)?;
+        let p2d = time_signing_phase("phase2", || self.phase2(signers, &p1d))?;
         info!("calling phase 3");
-        let p3d = self.phase3(&p1d, &p2d)?;
+        let p3d = time_signing_phase("phase3", || self.phase3(&p1d, &p2d))?;
         info!("calling phase 4");
-        let p4d = self.phase4(&p1d, &p2d, &p3d)?;
+        let p4d = time_signing_phase("phase4", || self.phase4(&p1d, &p2d, &p3d))?;
         info!("calling phase 5");
-        let p5d = self.phase5(&signers, &p1d, &p2d, &p3d, &p4d)?;
+        let p5d = time_signing_phase("phase5", || {
+            self.phase5(signers, &p1d, &p2d, &p3d, &p4d)
+        })?;
         info!("calling phase 6");
-        let p6d = self.phase6(&signers, &p1d, &p2d, &p3d, &p4d)?;
+        let p6d = time_signing_phase("phase6", || {
+            self.phase6(signers, &p1d, &p2d, &p3d, &p4d)
+        })?;
         info!("calling phase 7");
-        let p7d = self.phase7(&p1d, &p3d, &p4d, &p5d, &p6d)?;
+        let p7d = time_signing_phase("phase7", || {
+            self.phase7(signers, &p1d, &p3d, &p4d, &p5d, &p6d, message)
+        })?;
         info!("checking signature");
-        Self::check_sig(&p7d.sig.r, &p7d.sig.s, &p7d.message_bn, &self.keyshare.y_sum)?;
-        info!("send result");
-        self.send_result(&p7d)
+        check_sig(&p7d.sig.r, &p7d.sig.s, &p7d.message_bn, &self.keyshare.y_sum)?;
+        Ok(p7d.sig)
+    }
+}
+
+/// Failure of a blame phase, the blamed positions in the session mapped to shareholder indices
+fn blame_failure(phase: u8, signers_vec: &[usize], blame: Result<(), ErrorType>) -> SigningFailure {
+    match blame {
+        Ok(()) => {
+            error!("Unable to determine blame during phase{}", phase);
+            SigningFailure {
+                phase,
+                blamed_parties: Vec::new(),
+                blamed_nodes: Vec::new(),
+                reason: "the blame protocol found no misbehaving party".to_string(),
+            }
+        }
+        Err(err) => {
+            error!("Assigned blame to signer(s): {:?}", err);
+            SigningFailure {
+                phase,
+                blamed_parties: err.bad_actors
+                    .iter()
+                    .filter_map(|position| signers_vec.get(*position))
+                    .map(|signer| signer + 1)
+                    .collect(),
+                blamed_nodes: Vec::new(),
+                reason: err.error_type,
+            }
+        }
     }
 }
 
-pub fn handle_new_session_message(app: &App, message: nats::Message) {
+pub fn handle_new_session_message(app: &App, message: IncomingMessage) {
     let parsed_message = match serde_json::from_slice::<NewSignMessage>(&message.data[..]) {
         Ok(parsed) => parsed,
         Err(err) => {
-            error!("Failed to parse message: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Failed to parse message: {}", err)
+            );
             return;
         }
     };
@@ -1036,20 +1290,43 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
         parsed_message.message_hmac.is_none() ||
         parsed_message.email.is_none()
     {
-        error!("Missing required security fields: timestamp, message_hmac, or email");
+        session_error::refuse(
+            app,
+            &message,
+            SessionErrorCode::MalformedRequest,
+            "Missing required security fields: timestamp, message_hmac, or email"
+        );
         return;
     }
+    if let Some(path) = &parsed_message.derivation_path {
+        let refused = match path.parse::<DerivationPath>() {
+            Err(err) => Some(format!("Invalid derivation path: {}", err)),
+            Ok(_) if parsed_message.presignature_id.is_some() => {
+                Some("Presignatures are made for the parent key, not for its children".to_string())
+            }
+            Ok(_) => None,
+        };
+        if let Some(reason) = refused {
+            session_error::refuse(app, &message, SessionErrorCode::MalformedRequest, reason);
+            return;
+        }
+    }
 
-    let node = match NodeIdentity::load() {
+    let node = match NodeIdentity::cached() {
         Ok(node) => node,
         Err(err) => {
-            error!("Failed to load node identity: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::InternalError,
+                format!("Failed to load node identity: {}", err)
+            );
             return;
         }
     };
 
-    let decrypted_signing_key = match
-        e2e_decrypt(
+    let node_signing_key = match
+        client_e2e_decrypt_secret(
             &parsed_message.encrypted_signing_key,
             &node.e2e_private_key,
             &parsed_message.client_e2e_public_key
@@ -1057,15 +1334,12 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
     {
         Ok(key) => key,
         Err(err) => {
-            error!("Failed to decrypt signing key: {}", err);
-            return;
-        }
-    };
-
-    let node_signing_key = match String::from_utf8(decrypted_signing_key) {
-        Ok(key) => key,
-        Err(err) => {
-            error!("Failed to convert decrypted signing key to string: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::AuthenticationFailed,
+                format!("Failed to decrypt signing key: {}", err)
+            );
             return;
         }
     };
@@ -1077,12 +1351,22 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
 
     // Security verification: HMAC then timestamp
     if !verify_hmac(message_hmac, timestamp, &email, &node_signing_key) {
-        error!("HMAC verification failed");
+        session_error::refuse(
+            app,
+            &message,
+            SessionErrorCode::AuthenticationFailed,
+            "HMAC verification failed"
+        );
         return;
     }
 
     if !verify_timestamp(&parsed_message.key_id, timestamp, &email) {
-        error!("Timestamp verification failed");
+        session_error::refuse(
+            app,
+            &message,
+            SessionErrorCode::AuthenticationFailed,
+            "Timestamp verification failed"
+        );
         return;
     }
     info!("Timestamp verified");
@@ -1091,35 +1375,8 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
     if parsed_message.is_transfer_tx.unwrap_or(false) {
         info!("Initiating ownership transfer");
 
-        let message_str = match String::from_utf8(parsed_message.message.clone()) {
-            Ok(s) => s,
-            Err(err) => {
-                error!("Failed to convert message to string: {}", err);
-                return;
-            }
-        };
-
-        if !message_str.starts_with("Authorizing ownership transfer to ") {
-            error!("Invalid transfer message format: {}", message_str);
-            return;
-        }
-
-        let target_client_key = message_str.replace("Authorizing ownership transfer to ", "");
-
-        let stored_identity = match KeyMetadataStore::get_user_level("new_identity_key", &email) {
-            Ok(identity) => identity,
-            Err(err) => {
-                error!("Failed to retrieve identity using KeyMetadataStore: {}", err);
-                return;
-            }
-        };
-
-        if stored_identity.trim() != target_client_key.trim() {
-            error!(
-                "Transfer target mismatch. Expected: {}, Actual: {}",
-                stored_identity.trim(),
-                target_client_key.trim()
-            );
+        if let Err(err) = check_transfer_target(&parsed_message.message, &email) {
+            session_error::refuse(app, &message, SessionErrorCode::AuthenticationFailed, err);
             return;
         }
 
@@ -1127,7 +1384,12 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
 
         // Delete the new_identity_key file after successful verification
         if let Err(err) = KeyMetadataStore::remove_user_level("new_identity_key", &email) {
-            error!("Failed to remove new_identity_key: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::InternalError,
+                format!("Failed to remove new_identity_key: {}", err)
+            );
             return;
         }
 
@@ -1135,18 +1397,79 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
     }
 
     // Validate access key
-    let saved_access_key = match KeyMetadataStore::get(&parsed_message.key_id, "access", &email) {
-        Ok(key) => key,
-        Err(err) => {
-            error!("Failed to load saved access key: {}", err);
+    if let Err(err) = check_access_key(&parsed_message.key_id, &email, &node_signing_key) {
+        session_error::refuse(app, &message, SessionErrorCode::AuthenticationFailed, err);
+        return;
+    }
+
+    // Ethereum transactions are decoded so the policy can check what they do
+    let evm_transaction = if parsed_message.is_ethereum_tx.unwrap_or(false) {
+        if parsed_message.hash_mode != HashMode::Keccak256 {
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                "Ethereum transactions have to be signed with the keccak256 hash mode"
+            );
             return;
         }
+        match tx_inspector::inspect(&parsed_message.message) {
+            Ok(transaction) => {
+                info!(
+                    "Signing request for session {}: {}",
+                    parsed_message.session_id,
+                    transaction.summary()
+                );
+                Some(transaction)
+            }
+            Err(err) => {
+                session_error::refuse(
+                    app,
+                    &message,
+                    SessionErrorCode::MalformedRequest,
+                    format!("Failed to decode the Ethereum transaction: {}", err)
+                );
+                return;
+            }
+        }
+    } else {
+        None
     };
 
-    if node_signing_key != saved_access_key {
-        error!("Access key mismatch: decrypted key does not match saved access key");
+    let request = SigningRequest {
+        messages: vec![parsed_message.message.as_slice()],
+        is_transfer: parsed_message.is_transfer_tx.unwrap_or(false),
+        evm_transaction: evm_transaction.as_ref(),
+    };
+    if let Err(err) = enforce_signing_policy(&parsed_message.key_id, &email, &request) {
+        session_error::refuse(
+            app,
+            &message,
+            SessionErrorCode::PolicyDenied,
+            format!("Signing request refused by the key's policy: {}", err)
+        );
         return;
     }
+    let approval = match
+        request_approval(
+            app,
+            &parsed_message.key_id,
+            &email,
+            &parsed_message.session_id,
+            &request
+        )
+    {
+        Ok(approval) => approval,
+        Err(err) => {
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::PolicyDenied,
+                format!("Failed to request approval of the signing request: {}", err)
+            );
+            return;
+        }
+    };
 
     // Store the client_e2e_public_key at user level
     if
@@ -1165,8 +1488,17 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
         key_id: parsed_message.key_id,
         session_id: parsed_message.session_id,
         message: parsed_message.message,
+        hash_mode: parsed_message.hash_mode,
+        presignature_id: None,
+        transaction_summary: evm_transaction.as_ref().map(|transaction| transaction.summary()),
+        derivation_path: parsed_message.derivation_path,
     };
 
+    if let Some(presignature_id) = parsed_message.presignature_id {
+        presigning::session::spawn_online_sign_session(app, session, presignature_id, email, approval);
+        return;
+    }
+
     // Create a new thread for this signing session
     info!("Spawning a thread to handle ECDSA signature generation");
     let app_clone = app.clone();
@@ -1174,28 +1506,59 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
     let thread_name = format!("sign_session_{}", session_clone.session_id);
     let session_id = session_clone.session_id.clone();
     match
-        thread::Builder
-            ::new()
-            .name(thread_name)
-            .spawn(move || {
+        session_manager::spawn_blocking_session(
+            SessionKind::Signing,
+            &session_id,
+            thread_name,
+            move || {
+                if let Some(approval) = approval {
+                    if let Err(err) = approval.wait_blocking() {
+                        error!("{}", err);
+                        metrics::session_failed(SessionKind::Signing);
+                        return;
+                    }
+                }
+                let key_id = session_clone.key_id.clone();
+                let audit = AuditedRequest::new(
+                    AuditAction::Signing,
+                    "ecdsa",
+                    &key_id,
+                    &session_clone.session_id,
+                    Some(email.as_str())
+                ).with_details(session_clone.transaction_summary.clone());
+                let message = session_clone.message.clone();
+                let usage_email = email.clone();
+                let started = Instant::now();
                 let mut sign_session = match
                     SignSession::new(app_clone.nc, session_clone, Some(email))
                 {
                     Ok(ss) => ss,
                     Err(err) => {
                         error!("Error creating signing session: {}", err);
+                        metrics::session_failed(SessionKind::Signing);
+                        slo::record_signing("ecdsa", &key_id, started.elapsed(), false);
+                        audit.record::<(), _>(&Err(err));
                         return;
                     }
                 };
-                match sign_session.sign() {
+                let result = sign_session.sign();
+                slo::record_signing("ecdsa", &key_id, started.elapsed(), result.is_ok());
+                audit.record(&result);
+                if result.is_ok() {
+                    KeyMetadataStore::record_usage(&key_id, &usage_email, &message);
+                }
+                match result {
                     Ok(()) => {
                         info!("Signing completed successfully");
+                        metrics::session_completed(SessionKind::Signing);
                     }
                     Err(err) => {
                         error!("Error in signing: {}", err);
+                        metrics::session_failed(SessionKind::Signing);
                     }
                 }
-            })
+            }
+        )
     {
         Ok(_) => (),
         Err(err) => error!("Failed to spawn thread for signing session {}: {}", session_id, err),
@@ -1203,84 +1566,17 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
 }
 
 struct SignSession {
-    connection: nats::Connection,
+    connection: blocking::Connection,
     start_phase: SignPhase,
     phases: Vec<SignPhase>,
     keyshare: ECDSA,
     party_info: JoinSignSessionResponse,
     session: NewSignSession,
-}
-
-// Verify that the timestamp is newer than the last one we've seen
-fn verify_timestamp(key_id: &str, new_timestamp: &str, email: &str) -> bool {
-    let timestamp_key = "timestamp";
-    let new_dt = match DateTime::parse_from_rfc3339(new_timestamp) {
-        Ok(dt) => dt.with_timezone(&Utc),
-        Err(err) => {
-            error!("Failed to parse timestamp: {}", err);
-            return false;
-        }
-    };
-
-    // It's fine if this fails - it just means first tx
-    let previous_timestamp_result = KeyMetadataStore::get(key_id, timestamp_key, email);
-
-    match previous_timestamp_result {
-        Ok(prev_timestamp_str) => {
-            let prev_dt = match DateTime::parse_from_rfc3339(&prev_timestamp_str) {
-                Ok(dt) => dt.with_timezone(&Utc),
-                Err(err) => {
-                    error!("Failed to parse stored timestamp: {}", err);
-                    return false;
-                }
-            };
-
-            if new_dt <= prev_dt {
-                error!(
-                    "Timestamp validation failed: provided timestamp ({}) is not newer than stored timestamp ({})",
-                    new_timestamp,
-                    prev_timestamp_str
-                );
-                return false;
-            }
-        }
-        Err(err) => {
-            info!("No previous timestamp found, likely first transaction: {}", err);
-        }
-    }
-
-    match KeyMetadataStore::save(new_timestamp, key_id, timestamp_key, email, &WriteOpts::Modify) {
-        Ok(_) => true,
-        Err(err) => {
-            error!("Failed to save new timestamp: {}", err);
-            false
-        }
-    }
-}
-
-// HMAC verification using SHA256(timestamp + email) with signing key
-fn verify_hmac(provided_hmac: &str, timestamp: &str, email: &str, signing_key: &str) -> bool {
-    type HmacSha256 = Hmac<Sha256>;
-    let message_input = format!("{}{}", timestamp, email);
-
-    let mut mac = match HmacSha256::new_from_slice(signing_key.as_bytes()) {
-        Ok(m) => m,
-        Err(err) => {
-            error!("Failed to create HMAC instance: {}", err);
-            return false;
-        }
-    };
-
-    mac.update(message_input.as_bytes());
-    let calculated_hmac_bytes = mac.finalize().into_bytes();
-
-    // Use base64 encoding instead of hex to match TypeScript implementation
-    let calculated_hmac = base64::encode(calculated_hmac_bytes);
-
-    if calculated_hmac != provided_hmac {
-        error!("HMAC verification failed: expected {}, got {}", provided_hmac, calculated_hmac);
-        return false;
-    }
-
-    true
+    xi_com_vec: Vec<Point<Secp256k1>>,
+    /// Weighted public shares precomputed by a warm-up for the signers it was told to expect
+    warm_g_w: Option<HashMap<usize, Point<Secp256k1>>>,
+    /// Observers the owner consented to, they get a copy of every broadcast this party sends and
+    /// the transcript of all broadcast phases. The p2p phase is never mirrored.
+    observers: Vec<String>,
+    transcript: Mutex<Vec<TranscriptRound>>,
 }
@@ -0,0 +1,184 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::signing::ecdsa::session::{ compute_g_w_vec, xi_commitments, SessionSubscriptions };
+use crate::storage::{ KeyshareAccessor, ECDSA };
+use crate::tenant::{ Access, TenantAuth };
+use anyhow::{ bail, Result };
+use curv::elliptic::curves::{ Point, Secp256k1 };
+use serde::{ Deserialize, Serialize };
+use std::collections::{ BTreeSet, HashMap };
+use std::sync::{ Mutex, OnceLock };
+use std::time::{ Duration, Instant };
+use tracing::info;
+
+/// Warmed up state is dropped when the signing request doesn't follow within this time
+const WARMUP_TTL: Duration = Duration::from_secs(60);
+/// Each warm session holds a decrypted keyshare and the subscriptions of a session
+const MAX_WARM_SESSIONS: usize = 16;
+
+/// Prepares this node for an ECDSA signing session the hub expects shortly, e.g. while the user
+/// composes the transaction. The session's subjects are subscribed to, the keyshare is decrypted
+/// and the commitments that don't depend on the message are computed, so the signing request with
+/// the same session id starts right away.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WarmupSessionCommand {
+    pub session_id: String,
+    pub key_id: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Party indices of the keyshares expected to sign, this node's included. Their weighted
+    /// public shares are precomputed and used if the session ends up with the same signers.
+    #[serde(default)]
+    pub signers: Option<Vec<usize>>,
+    /// Required on multi user nodes
+    #[serde(default)]
+    pub authorization: Option<TenantAuth>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct WarmupSessionResponse {
+    pub session_id: String,
+    pub expires_in_secs: u64,
+    pub precomputed_signers: bool,
+}
+
+pub(crate) struct WarmSession {
+    pub keyshare: ECDSA,
+    pub subscriptions: SessionSubscriptions,
+    pub xi_com_vec: Vec<Point<Secp256k1>>,
+    /// Weighted public share of each expected signer, by zero based party index
+    pub g_w_by_signer: Option<HashMap<usize, Point<Secp256k1>>>,
+    key_id: String,
+    email: Option<String>,
+    expires: Instant,
+}
+
+fn warm_sessions() -> &'static Mutex<HashMap<String, WarmSession>> {
+    static WARM_SESSIONS: OnceLock<Mutex<HashMap<String, WarmSession>>> = OnceLock::new();
+    WARM_SESSIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Takes the state warmed up for the session, if it was prepared for the same key and user and
+/// hasn't expired yet
+pub(crate) fn take(session_id: &str, key_id: &str, email: Option<&str>) -> Option<WarmSession> {
+    let mut sessions = warm_sessions().lock().unwrap();
+    sessions.retain(|_, warm| warm.expires > Instant::now());
+    match sessions.remove(session_id) {
+        Some(warm) if warm.key_id == key_id && warm.email.as_deref() == email => Some(warm),
+        _ => None,
+    }
+}
+
+/// The weighted public shares in the order of `signers_vec`, if they were precomputed for
+/// exactly these signers
+pub(crate) fn g_w_vec_for(
+    g_w_by_signer: &HashMap<usize, Point<Secp256k1>>,
+    signers_vec: &[usize]
+) -> Option<Vec<Point<Secp256k1>>> {
+    if signers_vec.len() != g_w_by_signer.len() {
+        return None;
+    }
+    signers_vec
+        .iter()
+        .map(|signer| g_w_by_signer.get(signer).cloned())
+        .collect()
+}
+
+impl WarmupSessionCommand {
+    /// Zero based indices of the expected signers, as the signing session orders them
+    fn signers_vec(&self, keyshare: &ECDSA) -> Result<Option<Vec<usize>>> {
+        let signers = match &self.signers {
+            Some(signers) => signers,
+            None => {
+                return Ok(None);
+            }
+        };
+        let unique: BTreeSet<usize> = signers.iter().copied().collect();
+        if unique.len() != signers.len() {
+            bail!("Expected signers must be distinct");
+        }
+        if !unique.contains(&keyshare.party_index) {
+            bail!("Expected signers don't include this node's party {}", keyshare.party_index);
+        }
+        if unique.iter().any(|index| *index == 0 || *index > keyshare.public_key_vec.len()) {
+            bail!("Expected signers must be party indices of the key");
+        }
+        Ok(Some(unique.iter().map(|index| index - 1).collect()))
+    }
+}
+
+impl JsonCommand for WarmupSessionCommand {
+    type Response = WarmupSessionResponse;
+
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: self.authorization.as_ref(), key_ids: vec![self.key_id.clone()] }
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let app = ctx.get_app()?;
+        let keyshare = (
+            match &ctx.tenant().or(self.email.clone()) {
+                Some(email) =>
+                    KeyshareAccessor::<ECDSA>::read_only_with_email(&self.key_id, email)?,
+                None => KeyshareAccessor::<ECDSA>::read_only(&self.key_id)?,
+            }
+        ).key;
+
+        let g_w_by_signer = self.signers_vec(&keyshare)?.map(|signers_vec| {
+            signers_vec.iter().copied().zip(compute_g_w_vec(&keyshare, &signers_vec)).collect()
+        });
+        let precomputed_signers = g_w_by_signer.is_some();
+        let warm = WarmSession {
+            subscriptions: SessionSubscriptions::subscribe(
+                &app.nc,
+                &self.session_id,
+                keyshare.public_key_vec.len()
+            )?,
+            xi_com_vec: xi_commitments(&keyshare),
+            keyshare,
+            g_w_by_signer,
+            key_id: self.key_id,
+            email: self.email,
+            expires: Instant::now() + WARMUP_TTL,
+        };
+
+        let mut sessions = warm_sessions().lock().unwrap();
+        sessions.retain(|_, warm| warm.expires > Instant::now());
+        if sessions.len() >= MAX_WARM_SESSIONS && !sessions.contains_key(&self.session_id) {
+            bail!("{} sessions are warmed up already", MAX_WARM_SESSIONS);
+        }
+        sessions.insert(self.session_id.clone(), warm);
+        info!("Warmed up signing session {}", self.session_id);
+
+        Ok(WarmupSessionResponse {
+            session_id: self.session_id,
+            expires_in_secs: WARMUP_TTL.as_secs(),
+            precomputed_signers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curv::elliptic::curves::Scalar;
+
+    #[test]
+    fn precomputed_shares_follow_the_session_order() {
+        let share = |n: u16| Point::<Secp256k1>::generator() * Scalar::<Secp256k1>::from(n);
+        let g_w_by_signer: HashMap<usize, Point<Secp256k1>> = [
+            (0, share(1)),
+            (2, share(3)),
+            (4, share(5)),
+        ]
+            .into_iter()
+            .collect();
+
+        assert_eq!(
+            g_w_vec_for(&g_w_by_signer, &[4, 0, 2]),
+            Some(vec![share(5), share(1), share(3)])
+        );
+        assert_eq!(g_w_vec_for(&g_w_by_signer, &[0, 1, 2]), None);
+        assert_eq!(g_w_vec_for(&g_w_by_signer, &[0, 2]), None);
+    }
+}
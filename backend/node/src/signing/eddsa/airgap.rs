@@ -0,0 +1,113 @@
+use crate::communication::file_transport::{ FileMessenger, FileTransport };
+use crate::communication::protocol::{ KeyGenAllRounds, KeySignEdDSAAllRounds, Topic };
+use crate::keygen::eddsa::client::KeyGenClient;
+use crate::keygen::ShareParams;
+use crate::policy::{ enforce_signing_policy, SigningRequest };
+use crate::signing::eddsa::client::EdDSAKeySignClient;
+use crate::signing::eddsa::SignatureResult;
+use crate::signing::hashing::HashMode;
+use crate::signing::network::NetworkMode;
+use crate::signing::nonce_ledger;
+use crate::storage::KeyshareAccessor;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::storage::EDDSA;
+use anyhow::{ bail, Result };
+use serde::{ Deserialize, Serialize };
+use tracing::info;
+
+/// Signing request carried to every air-gapped party as a file, all parties sign the same one
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OfflineSigningRequest {
+    pub key_id: String,
+    pub session_id: String,
+    pub message: Vec<u8>,
+    /// Share indices of the parties taking part, more than the key's threshold
+    pub party_indices: Vec<usize>,
+    pub email: String,
+    #[serde(default)]
+    pub hash_mode: HashMode,
+    #[serde(default)]
+    pub network_mode: NetworkMode,
+    /// Owner's override for signing a message again with a new nonce, see `nonce_ledger`
+    #[serde(default)]
+    pub allow_resign: bool,
+}
+
+/// EdDSA signing with round messages exchanged as files, the guardian needs no network access.
+/// The ephemeral key and signature rounds run as on NATS, each waiting until the other parties'
+/// files have been carried into the inbox.
+pub async fn sign(
+    request: OfflineSigningRequest,
+    transport: FileTransport
+) -> Result<SignatureResult> {
+    let message = request.network_mode.signing_payload(&request.message, request.hash_mode)?;
+    let keyshare = KeyshareAccessor::<EDDSA>::read_only_with_email(
+        &request.key_id,
+        &request.email
+    )?.key;
+    let threshold = keyshare.threshold;
+    let party_index = keyshare.party_index;
+
+    let mut all_party_indices = request.party_indices.clone();
+    all_party_indices.sort();
+    all_party_indices.dedup();
+    if !all_party_indices.contains(&party_index) {
+        bail!("Share {} of key {} is not one of the signing parties", party_index, request.key_id);
+    }
+    if all_party_indices.len() <= threshold {
+        bail!("{} parties can't sign with a threshold of {}", all_party_indices.len(), threshold);
+    }
+    let signing_request = SigningRequest {
+        messages: vec![request.message.as_slice()],
+        is_transfer: false,
+        evm_transaction: None,
+    };
+    enforce_signing_policy(&request.key_id, &request.email, &signing_request)?;
+
+    let party_count = all_party_indices.len();
+    info!("Signing with key {} offline as party {}", request.key_id, party_index);
+
+    let keygen_client = KeyGenClient {
+        peer_messenger: FileMessenger::<KeyGenAllRounds>::new(
+            transport.clone(),
+            Topic::EphemeralKeyGenEdDSA,
+            &request.session_id,
+            party_index,
+            all_party_indices.clone()
+        )?,
+        share_params: ShareParams { threshold, party_count, party_index },
+        all_party_indices: all_party_indices.clone(),
+    };
+    let ephemeral_keyshare = keygen_client.create_ephemeral_shared_key(&message).await?;
+    keygen_client.publish_result(ephemeral_keyshare.shared_key.R.clone()).await?;
+    nonce_ledger::check_and_record(
+        &request.key_id,
+        &request.email,
+        &message,
+        &*ephemeral_keyshare.shared_key.R.to_bytes(true),
+        request.allow_resign
+    )?;
+
+    let keysign_client = EdDSAKeySignClient {
+        peer_messenger: FileMessenger::<KeySignEdDSAAllRounds>::new(
+            transport,
+            Topic::KeySignEdDSA,
+            &request.session_id,
+            party_index,
+            all_party_indices.clone()
+        )?,
+        share_params: ShareParams { threshold, party_count, party_index },
+        all_party_indices,
+    };
+    let signature = keysign_client
+        .create_shared_sig(&message, &ephemeral_keyshare, &keyshare).await?;
+    let result = SignatureResult {
+        sigma: hex::encode(&*signature.s.to_bytes()),
+        R: hex::encode(&*signature.R.to_bytes(false)),
+    };
+    keysign_client.publish_result(result.clone()).await?;
+    KeyMetadataStore::record_usage(&request.key_id, &request.email, &request.message);
+    info!("Offline signing session {} completed", request.session_id);
+    Ok(result)
+}
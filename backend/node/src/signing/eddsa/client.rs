@@ -18,7 +18,7 @@ pub struct EdDSAKeySignClient<C> {
 }
 
 impl<C> EdDSAKeySignClient<C> where C: PeerMessenger<KeySignEdDSAAllRounds> {
-    pub fn create_shared_sig(
+    pub async fn create_shared_sig(
         &self,
         message: &[u8],
         ephemeral_keyshare: &EphemeralEdDSAKey,
@@ -35,7 +35,7 @@ impl<C> EdDSAKeySignClient<C> where C: PeerMessenger<KeySignEdDSAAllRounds> {
             &keyshare.y_sum
         )?;
         info!("Local signature created successfully");
-        let local_sigs = self.exchange_local_sigs(local_sig)?;
+        let local_sigs = self.exchange_local_sigs(local_sig).await?;
         info!("Exchanged local signatures");
         let party_indices = &*party_indices
             .iter()
@@ -64,11 +64,11 @@ impl<C> EdDSAKeySignClient<C> where C: PeerMessenger<KeySignEdDSAAllRounds> {
         Ok(signature)
     }
 
-    pub fn publish_result(&self, signature: SignatureResult) -> anyhow::Result<()> {
+    pub async fn publish_result(&self, signature: SignatureResult) -> anyhow::Result<()> {
         let _ = self.peer_messenger.broadcast_and_collect_messages(
             &<KeySignEdDSAAllRounds as AllRounds>::BroadcastRound::Result,
             signature
-        )?;
+        ).await?;
         Ok(())
     }
 
@@ -89,11 +89,11 @@ impl<C> EdDSAKeySignClient<C> where C: PeerMessenger<KeySignEdDSAAllRounds> {
         Ok(local_sig)
     }
 
-    pub fn exchange_local_sigs(&self, local_sig: LocalSig) -> anyhow::Result<Vec<LocalSig>> {
+    pub async fn exchange_local_sigs(&self, local_sig: LocalSig) -> anyhow::Result<Vec<LocalSig>> {
         let local_sigs = self.peer_messenger.broadcast_and_collect_messages(
             &<KeySignEdDSAAllRounds as AllRounds>::BroadcastRound::LocalSig,
             local_sig
-        )?;
+        ).await?;
 
         Ok(local_sigs)
     }
@@ -1,3 +1,4 @@
+pub mod airgap;
 pub mod client;
 pub mod orchestrate;
 pub mod session;
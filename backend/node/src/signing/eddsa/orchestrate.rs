@@ -1,4 +1,5 @@
 use crate::command::MsgContext;
+use crate::communication::envelope;
 use crate::communication::nats::{ BroadcastMessage, JoinMessage, JoinResponse };
 use crate::signing::eddsa::session::NewEdDSAKeySignSession;
 use crate::signing::eddsa::SignatureResult;
@@ -34,6 +35,9 @@ pub fn orchestrate(cmd: SigningCommand, ctx: MsgContext) -> Result<SigningRespon
                 session_id: session_id.to_owned(),
                 message: cmd.msg.clone(),
                 email: None,
+                hash_mode: cmd.hash_mode,
+                network_mode: cmd.network_mode.clone(),
+                allow_resign: cmd.allow_resign,
             })
         )?;
         nc.publish(&sign_new_key, key_sign_new_data)?;
@@ -51,16 +55,11 @@ pub fn orchestrate(cmd: SigningCommand, ctx: MsgContext) -> Result<SigningRespon
         bail!(msg);
     }
 
-    let mut indices = Vec::new();
+    let mut joins = Vec::new();
     for m in join_msg_vec.iter() {
-        let confirmation = serde_json::from_slice::<JoinMessage>(&m.data)?;
-        indices.push(confirmation.party_index);
+        joins.push(serde_json::from_slice::<JoinMessage>(&m.data)?);
     }
-    indices.sort();
-    let join_resp = JoinResponse {
-        party_count: indices.len(),
-        all_party_indices: indices,
-    };
+    let join_resp = JoinResponse::new(&joins);
     for msg in join_msg_vec {
         msg.respond(
             &serde_json::to_string(&join_resp).context("Respond to join message for every party")?
@@ -78,7 +77,7 @@ pub fn orchestrate(cmd: SigningCommand, ctx: MsgContext) -> Result<SigningRespon
 
     info!("Signature result received");
 
-    let sig = serde_json::from_slice::<BroadcastMessage<SignatureResult>>(
+    let sig = envelope::decode::<BroadcastMessage<SignatureResult>>(
         &res_vec[0].data
     )?.message;
     Ok(SigningResponse::EDDSA(sig))
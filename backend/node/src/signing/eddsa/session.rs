@@ -1,4 +1,8 @@
-use crate::auth::e2e_decrypt;
+use crate::approval::request_approval;
+use crate::audit::{ AuditAction, AuditedRequest };
+use crate::communication::incoming::IncomingMessage;
+use crate::policy::{ enforce_signing_policy, SigningRequest };
+use crate::auth::client_e2e_decrypt_secret;
 use crate::communication::nats::{
     BaseMessenger,
     NatsBaseMessenger,
@@ -9,26 +13,56 @@ use crate::communication::protocol::{ KeyGenAllRounds, KeySignEdDSAAllRounds, To
 use crate::keygen::eddsa::client::KeyGenClient;
 use crate::keygen::ShareParams;
 use crate::node::NodeIdentity;
+use crate::observer::with_consented_observers;
 use crate::signing::eddsa::client::EdDSAKeySignClient;
 use crate::signing::eddsa::SignatureResult;
+use crate::signing::hashing::HashMode;
+use crate::signing::network::NetworkMode;
+use crate::signing::nonce_ledger;
 use crate::storage::fs::WriteOpts;
 use crate::storage::KeyshareAccessor;
 use crate::storage::EDDSA;
 use crate::App;
 use serde::{ Deserialize, Serialize };
-use std::thread;
+use crate::slo;
+use std::time::Instant;
 use tracing::{ error, info, instrument, warn };
 use crate::storage::key_metadata_store::KeyMetadataStore;
-use chrono::{ DateTime, Utc };
-use hmac::{ Hmac, Mac, NewMac };
-use sha2::Sha256;
-use base64;
+use crate::signing::validation::{
+    check_access_key,
+    check_transfer_target,
+    verify_hmac,
+    verify_timestamp,
+};
+use crate::metrics::SessionKind;
+use crate::session_error::{ self, SessionErrorCode };
+use crate::session_manager;
 use hex;
 
 #[instrument(skip_all)]
-fn sign_session(conn: nats::Connection, session: NewEdDSAKeySignSession) -> anyhow::Result<()> {
+async fn sign_session(
+    conn: async_nats::Client,
+    session: NewEdDSAKeySignSession
+) -> anyhow::Result<()> {
     let session_id = session.session_id.clone();
-    match keysign_session_inner(conn, session) {
+    let key_id = session.key_id.clone();
+    let audit = AuditedRequest::new(
+        AuditAction::Signing,
+        "eddsa",
+        &key_id,
+        &session_id,
+        session.email.as_deref()
+    );
+    let email = session.email.clone().unwrap_or_default();
+    let message = session.message.clone();
+    let started = Instant::now();
+    let result = keysign_session_inner(conn, session).await;
+    slo::record_signing("eddsa", &key_id, started.elapsed(), result.is_ok());
+    audit.record(&result);
+    if result.is_ok() {
+        KeyMetadataStore::record_usage(&key_id, &email, &message);
+    }
+    match result {
         Ok(()) => info!("Signing completed successfully for session id: {}", session_id),
         Err(err) => error!("Error in EdDSA signing: session id: {}, error: {}", session_id, err),
     }
@@ -36,6 +70,7 @@ fn sign_session(conn: nats::Connection, session: NewEdDSAKeySignSession) -> anyh
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct NewEdDSAKeySignMessage {
     pub key_id: String,
     pub session_id: String,
@@ -46,6 +81,14 @@ pub struct NewEdDSAKeySignMessage {
     pub timestamp: Option<String>,
     pub message_hmac: Option<String>,
     pub email: Option<String>,
+    #[serde(default)]
+    pub hash_mode: HashMode,
+    /// Chain specific pre-processing, the message is then a transaction in the chain's encoding
+    #[serde(default)]
+    pub network_mode: NetworkMode,
+    /// Owner's override for signing a message again with a new nonce, see `nonce_ledger`
+    #[serde(default)]
+    pub allow_resign: bool,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -54,6 +97,13 @@ pub struct NewEdDSAKeySignSession {
     pub session_id: String,
     pub message: Vec<u8>,
     pub email: Option<String>,
+    /// Hash applied to the message before signing
+    #[serde(default)]
+    pub hash_mode: HashMode,
+    #[serde(default)]
+    pub network_mode: NetworkMode,
+    #[serde(default)]
+    pub allow_resign: bool,
 }
 
 pub struct E2EData {
@@ -61,13 +111,13 @@ pub struct E2EData {
     pub encrypted_signing_key: String,
 }
 
-fn keysign_session_inner(
-    conn: nats::Connection,
+async fn keysign_session_inner(
+    conn: async_nats::Client,
     session: NewEdDSAKeySignSession
 ) -> anyhow::Result<()> {
     let key_id = session.key_id.clone();
     let session_id = session.session_id.clone();
-    let message = session.message.clone();
+    let message = session.network_mode.signing_payload(&session.message, session.hash_mode)?;
     info!("joining EdDSA keysign session key_id: {}", &key_id);
 
     let keyshare = if let Some(email) = &session.email {
@@ -82,7 +132,7 @@ fn keysign_session_inner(
     let threshold = keyshare.threshold;
     let party_index = keyshare.party_index;
 
-    let node = NodeIdentity::load()?;
+    let node = NodeIdentity::cached()?;
     let node_id = node.node_id.to_string();
     let public_key = node.networking_public_key;
     info!("Retrieved node identity");
@@ -101,16 +151,18 @@ fn keysign_session_inner(
         Topic::EphemeralKeyGenEdDSA,
         conn.clone(),
         nats_session.clone()
-    )?;
+    ).await?;
 
     let sign_messenger = NatsBaseMessenger::<KeySignEdDSAAllRounds>::new(
         Topic::KeySignEdDSA,
         conn.clone(),
         nats_session
-    )?;
+    ).await?;
 
-    let join_response = keygen_messenger.wait_for_confirmation(std::time::Duration::from_secs(10))?;
+    let join_response = keygen_messenger
+        .wait_for_confirmation(std::time::Duration::from_secs(10)).await?;
     info!("Got join response");
+    sign_messenger.accept_join_response(&join_response);
 
     let party_count = join_response.party_count;
     let mut all_party_indices = join_response.all_party_indices;
@@ -132,17 +184,24 @@ fn keysign_session_inner(
         all_party_indices: all_party_indices.clone(),
     };
 
-    let ephemeral_keyshare = keygen_client.create_ephemeral_shared_key(&message)?;
+    let ephemeral_keyshare = keygen_client.create_ephemeral_shared_key(&message).await?;
     info!("Successfully created an ephemeral key");
 
-    keygen_client.publish_result(ephemeral_keyshare.shared_key.R.clone())?;
-
-    let sign_peer_messenger = NatsPeerMessenger::from(
-        sign_messenger,
-        party_count,
-        all_party_indices.clone()
+    keygen_client.publish_result(ephemeral_keyshare.shared_key.R.clone()).await?;
+    nonce_ledger::check_and_record(
+        &key_id,
+        session.email.as_deref().unwrap_or_default(),
+        &message,
+        &*ephemeral_keyshare.shared_key.R.to_bytes(true),
+        session.allow_resign
     )?;
 
+    let sign_peer_messenger = with_consented_observers(
+        NatsPeerMessenger::from(sign_messenger, party_count, all_party_indices.clone())?,
+        &key_id,
+        session.email.as_deref()
+    );
+
     let keysign_client = EdDSAKeySignClient {
         peer_messenger: sign_peer_messenger,
         share_params: ShareParams {
@@ -153,22 +212,28 @@ fn keysign_session_inner(
         all_party_indices,
     };
 
-    let signature = keysign_client.create_shared_sig(&message, &ephemeral_keyshare, &keyshare)?;
+    let signature = keysign_client
+        .create_shared_sig(&message, &ephemeral_keyshare, &keyshare).await?;
     let sigma = hex::encode(&*signature.s.to_bytes());
 
     let R = hex::encode(&*signature.R.to_bytes(false));
     let signature = SignatureResult { sigma, R };
-    keysign_client.publish_result(signature)?;
+    keysign_client.publish_result(signature).await?;
     info!("Signature published successfully");
 
-    Ok(())
+    keysign_client.peer_messenger.publish_transcript().await
 }
 
-pub fn handle_new_session_message(app: &App, message: nats::Message) {
+pub fn handle_new_session_message(app: &App, message: IncomingMessage) {
     let parsed_message = match serde_json::from_slice::<NewEdDSAKeySignMessage>(&message.data[..]) {
         Ok(parsed) => parsed,
         Err(err) => {
-            error!("Failed to parse message: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Failed to parse message: {}", err)
+            );
             return;
         }
     };
@@ -179,20 +244,30 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
         parsed_message.message_hmac.is_none() ||
         parsed_message.email.is_none()
     {
-        error!("Missing required security fields: timestamp, message_hmac, or email");
+        session_error::refuse(
+            app,
+            &message,
+            SessionErrorCode::MalformedRequest,
+            "Missing required security fields: timestamp, message_hmac, or email"
+        );
         return;
     }
 
-    let node = match NodeIdentity::load() {
+    let node = match NodeIdentity::cached() {
         Ok(node) => node,
         Err(err) => {
-            error!("Failed to load node identity: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::InternalError,
+                format!("Failed to load node identity: {}", err)
+            );
             return;
         }
     };
 
-    let decrypted_signing_key = match
-        e2e_decrypt(
+    let node_signing_key = match
+        client_e2e_decrypt_secret(
             &parsed_message.encrypted_signing_key,
             &node.e2e_private_key,
             &parsed_message.client_e2e_public_key
@@ -200,15 +275,12 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
     {
         Ok(key) => key,
         Err(err) => {
-            error!("Failed to decrypt signing key: {}", err);
-            return;
-        }
-    };
-
-    let node_signing_key = match String::from_utf8(decrypted_signing_key) {
-        Ok(key) => key,
-        Err(err) => {
-            error!("Failed to convert decrypted signing key to string: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::AuthenticationFailed,
+                format!("Failed to decrypt signing key: {}", err)
+            );
             return;
         }
     };
@@ -220,12 +292,22 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
 
     // Security verification: HMAC then timestamp
     if !verify_hmac(message_hmac, timestamp, &email, &node_signing_key) {
-        error!("HMAC verification failed");
+        session_error::refuse(
+            app,
+            &message,
+            SessionErrorCode::AuthenticationFailed,
+            "HMAC verification failed"
+        );
         return;
     }
 
     if !verify_timestamp(&parsed_message.key_id, timestamp, &email) {
-        error!("Timestamp verification failed");
+        session_error::refuse(
+            app,
+            &message,
+            SessionErrorCode::AuthenticationFailed,
+            "Timestamp verification failed"
+        );
         return;
     }
     info!("Timestamp verified");
@@ -234,35 +316,8 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
     if parsed_message.is_transfer_tx.unwrap_or(false) {
         info!("Initiating ownership transfer");
 
-        let message_str = match String::from_utf8(parsed_message.message.clone()) {
-            Ok(s) => s,
-            Err(err) => {
-                error!("Failed to convert message to string: {}", err);
-                return;
-            }
-        };
-
-        if !message_str.starts_with("Authorizing ownership transfer to ") {
-            error!("Invalid transfer message format: {}", message_str);
-            return;
-        }
-
-        let target_client_key = message_str.replace("Authorizing ownership transfer to ", "");
-
-        let stored_identity = match KeyMetadataStore::get_user_level("new_identity_key", &email) {
-            Ok(identity) => identity,
-            Err(err) => {
-                error!("Failed to retrieve identity using KeyMetadataStore: {}", err);
-                return;
-            }
-        };
-
-        if stored_identity.trim() != target_client_key.trim() {
-            error!(
-                "Transfer target mismatch. Expected: {}, Actual: {}",
-                stored_identity.trim(),
-                target_client_key.trim()
-            );
+        if let Err(err) = check_transfer_target(&parsed_message.message, &email) {
+            session_error::refuse(app, &message, SessionErrorCode::AuthenticationFailed, err);
             return;
         }
 
@@ -270,7 +325,12 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
 
         // Delete the new_identity_key file after successful verification
         if let Err(err) = KeyMetadataStore::remove_user_level("new_identity_key", &email) {
-            error!("Failed to remove new_identity_key: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::InternalError,
+                format!("Failed to remove new_identity_key: {}", err)
+            );
             return;
         }
 
@@ -278,19 +338,46 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
     }
 
     // Validate access key
-    let saved_access_key = match KeyMetadataStore::get(&parsed_message.key_id, "access", &email) {
-        Ok(key) => key,
+    if let Err(err) = check_access_key(&parsed_message.key_id, &email, &node_signing_key) {
+        session_error::refuse(app, &message, SessionErrorCode::AuthenticationFailed, err);
+        return;
+    }
+
+    let request = SigningRequest {
+        messages: vec![parsed_message.message.as_slice()],
+        is_transfer: parsed_message.is_transfer_tx.unwrap_or(false),
+        evm_transaction: None,
+    };
+    if let Err(err) = enforce_signing_policy(&parsed_message.key_id, &email, &request) {
+        session_error::refuse(
+            app,
+            &message,
+            SessionErrorCode::PolicyDenied,
+            format!("Signing request refused by the key's policy: {}", err)
+        );
+        return;
+    }
+    let approval = match
+        request_approval(
+            app,
+            &parsed_message.key_id,
+            &email,
+            &parsed_message.session_id,
+            &request
+        )
+    {
+        Ok(approval) => approval,
         Err(err) => {
-            error!("Failed to load saved access key: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::PolicyDenied,
+                format!("Failed to request approval of the signing request: {}", err)
+            );
             return;
         }
     };
 
-    if node_signing_key != saved_access_key {
-        error!("Access key mismatch: decrypted key does not match saved access key");
-        return;
-    }
-
     // Store the client_e2e_public_key
     if
         let Err(err) = KeyMetadataStore::save_user_level(
@@ -310,94 +397,23 @@ pub fn handle_new_session_message(app: &App, message: nats::Message) {
         session_id: parsed_message.session_id,
         message: parsed_message.message,
         email: Some(email.clone()),
+        hash_mode: parsed_message.hash_mode,
+        network_mode: parsed_message.network_mode,
+        allow_resign: parsed_message.allow_resign,
     };
 
-    // Create a new thread for this signing session
-    info!("Spawning a thread to handle EdDSA signature generation");
-    let thread_name = format!("sign_session_{}", session.session_id);
-    let nc = app.nc.clone();
-    match
-        thread::Builder
-            ::new()
-            .name(thread_name)
-            .spawn(move || sign_session(nc, session))
-    {
-        Ok(_) => info!("Started EdDSA signing thread"),
-        Err(err) => error!("Failed to spawn thread for EdDSA signing: {}", err),
-    };
-}
-
-// Verify that the timestamp is newer than the last one we've seen
-fn verify_timestamp(key_id: &str, new_timestamp: &str, email: &str) -> bool {
-    let timestamp_key = "timestamp";
-    let new_dt = match DateTime::parse_from_rfc3339(new_timestamp) {
-        Ok(dt) => dt.with_timezone(&Utc),
-        Err(err) => {
-            error!("Failed to parse timestamp: {}", err);
-            return false;
-        }
-    };
-
-    // It's fine if this fails - it just means first tx
-    let previous_timestamp_result = KeyMetadataStore::get(key_id, timestamp_key, email);
-
-    match previous_timestamp_result {
-        Ok(prev_timestamp_str) => {
-            let prev_dt = match DateTime::parse_from_rfc3339(&prev_timestamp_str) {
-                Ok(dt) => dt.with_timezone(&Utc),
-                Err(err) => {
-                    error!("Failed to parse stored timestamp: {}", err);
-                    return false;
-                }
-            };
-
-            if new_dt <= prev_dt {
-                error!(
-                    "Timestamp validation failed: provided timestamp ({}) is not newer than stored timestamp ({})",
-                    new_timestamp,
-                    prev_timestamp_str
-                );
-                return false;
+    // Create a new task for this signing session
+    info!("Spawning a task to handle EdDSA signature generation");
+    let nc = app.nc.client().clone();
+    let session_id = session.session_id.clone();
+    session_manager::spawn_session(SessionKind::Signing, &session_id, async move {
+        if let Some(approval) = approval {
+            if let Err(err) = approval.wait().await {
+                error!("{}", err);
+                return Err(err);
             }
         }
-        Err(err) => {
-            info!("No previous timestamp found, likely first transaction: {}", err);
-        }
-    }
-
-    match KeyMetadataStore::save(new_timestamp, key_id, timestamp_key, email, &WriteOpts::Modify) {
-        Ok(_) => true,
-        Err(err) => {
-            error!("Failed to save new timestamp: {}", err);
-            false
-        }
-    }
-}
-
-// HMAC verification using SHA256(timestamp + email) with signing key
-fn verify_hmac(provided_hmac: &str, timestamp: &str, email: &str, signing_key: &str) -> bool {
-    type HmacSha256 = Hmac<Sha256>;
-    let message_input = format!("{}{}", timestamp, email);
-
-    let mut mac = match HmacSha256::new_from_slice(signing_key.as_bytes()) {
-        Ok(m) => m,
-        Err(err) => {
-            error!("Failed to create HMAC instance: {}", err);
-            return false;
-        }
-    };
-
-    mac.update(message_input.as_bytes());
-    let calculated_hmac_bytes = mac.finalize().into_bytes();
-
-    // Use base64 encoding instead of hex to match TypeScript implementation
-    let calculated_hmac = base64::encode(calculated_hmac_bytes);
-
-    if calculated_hmac != provided_hmac {
-        error!("HMAC verification failed: expected {}, got {}", provided_hmac, calculated_hmac);
-        return false;
-    }
-
-    info!("HMAC verified");
-    true
+        sign_session(nc, session).await
+    });
+    info!("Started EdDSA signing task");
 }
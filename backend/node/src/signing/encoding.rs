@@ -0,0 +1,180 @@
+use crate::signing::bls::SignatureResult as BLSSignatureResult;
+use crate::signing::ecdsa::SigningResult;
+use crate::signing::eddsa::SignatureResult;
+use crate::signing::frost::SignatureResult as FrostSignatureResult;
+use crate::signing::sr25519::SignatureResult as Sr25519SignatureResult;
+use crate::signing::SigningResponse;
+use anyhow::{ bail, Result };
+use serde::{ Deserialize, Serialize };
+
+/// Chain specific formats a signature can be returned in
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureEncoding {
+    /// Unencoded r/s/recid or sigma/R, as returned when no encoding is requested
+    Raw,
+    /// ASN.1 DER encoded ECDSA signature (Bitcoin), hex
+    Der,
+    /// 64 byte r || s for ECDSA, R || s for EdDSA, BIP-340 and sr25519 signatures, hex
+    Compact,
+    /// 65 byte r || s || v with v = 27 + recid, 0x prefixed hex
+    Ethereum,
+    /// 64 byte EdDSA signature R || s, base58
+    Solana,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct EncodedSignature {
+    pub encoding: SignatureEncoding,
+    pub signature: String,
+}
+
+impl SigningResponse {
+    pub fn encode(self, encoding: SignatureEncoding) -> Result<SigningResponse> {
+        if encoding == SignatureEncoding::Raw {
+            return Ok(self);
+        }
+        let signature = match &self {
+            SigningResponse::ECDSA(sig) => encode_ecdsa(sig, encoding)?,
+            SigningResponse::EDDSA(sig) => encode_eddsa(sig, encoding)?,
+            SigningResponse::Frost(sig) => encode_frost(sig, encoding)?,
+            SigningResponse::Sr25519(sig) => encode_sr25519(sig, encoding)?,
+            SigningResponse::BLS(sig) => encode_bls(sig, encoding)?,
+            SigningResponse::Encoded(_) => bail!("Signature is already encoded"),
+            SigningResponse::Failed(_) | SigningResponse::DryRun(_) => {
+                return Ok(self);
+            }
+        };
+        Ok(SigningResponse::Encoded(EncodedSignature { encoding, signature }))
+    }
+}
+
+fn encode_ecdsa(sig: &SigningResult, encoding: SignatureEncoding) -> Result<String> {
+    let compact = [hex::decode(&sig.r)?, hex::decode(&sig.s)?].concat();
+    if compact.len() != 64 {
+        bail!("ECDSA signature components must be 32 bytes each");
+    }
+
+    match encoding {
+        SignatureEncoding::Der => {
+            let signature = secp256k1::Signature::from_compact(&compact)?;
+            Ok(hex::encode(&*signature.serialize_der()))
+        }
+        SignatureEncoding::Compact => Ok(hex::encode(compact)),
+        SignatureEncoding::Ethereum => {
+            if sig.recid > 3 {
+                bail!("Invalid recovery id {}", sig.recid);
+            }
+            Ok(format!("0x{}{:02x}", hex::encode(compact), 27 + sig.recid))
+        }
+        _ => bail!("{:?} encoding is not supported for ECDSA signatures", encoding),
+    }
+}
+
+fn encode_eddsa(sig: &SignatureResult, encoding: SignatureEncoding) -> Result<String> {
+    let r = hex::decode(&sig.R)?;
+    // curv serializes scalars big-endian while Ed25519 signatures carry s little-endian
+    let mut s = hex::decode(&sig.sigma)?;
+    s.reverse();
+    if r.len() != 32 || s.len() != 32 {
+        bail!("EdDSA signature components must be 32 bytes each");
+    }
+    let signature = [r, s].concat();
+
+    match encoding {
+        SignatureEncoding::Compact => Ok(hex::encode(signature)),
+        SignatureEncoding::Solana => Ok(bs58::encode(signature).into_string()),
+        _ => bail!("{:?} encoding is not supported for EdDSA signatures", encoding),
+    }
+}
+
+fn encode_frost(sig: &FrostSignatureResult, encoding: SignatureEncoding) -> Result<String> {
+    match encoding {
+        SignatureEncoding::Compact => Ok(sig.signature.clone()),
+        _ => bail!("{:?} encoding is not supported for BIP-340 signatures", encoding),
+    }
+}
+
+fn encode_sr25519(sig: &Sr25519SignatureResult, encoding: SignatureEncoding) -> Result<String> {
+    match encoding {
+        SignatureEncoding::Compact => Ok(sig.signature.clone()),
+        _ => bail!("{:?} encoding is not supported for sr25519 signatures", encoding),
+    }
+}
+
+fn encode_bls(sig: &BLSSignatureResult, encoding: SignatureEncoding) -> Result<String> {
+    match encoding {
+        SignatureEncoding::Compact => Ok(sig.signature.clone()),
+        _ => bail!("{:?} encoding is not supported for BLS signatures", encoding),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6979 secp256k1 signature of sha256("Satoshi Nakamoto") with private key 1
+    fn ecdsa_signature() -> SigningResponse {
+        SigningResponse::ECDSA(SigningResult {
+            r: "934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7e6a4a49860d7a6ab210ee3d8".to_string(),
+            s: "2442ce9d2b916064108014783e923ec36b49743e2ffa1c4496f01a512aafd9e5".to_string(),
+            recid: 1,
+        })
+    }
+
+    // RFC 8032 section 7.1, test 1, with s in curv's big-endian form
+    fn eddsa_signature() -> SigningResponse {
+        SigningResponse::EDDSA(SignatureResult {
+            sigma: "0b107a8e4341516524be5b59f0f55bd26bb4f91c70391ec6ac3ba3901582b85f".to_string(),
+            R: "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155".to_string(),
+        })
+    }
+
+    fn encoded(response: SigningResponse, encoding: SignatureEncoding) -> String {
+        match response.encode(encoding).unwrap() {
+            SigningResponse::Encoded(encoded) => encoded.signature,
+            _ => panic!("Expected an encoded signature"),
+        }
+    }
+
+    #[test]
+    fn encodes_ecdsa_signatures() {
+        assert_eq!(
+            encoded(ecdsa_signature(), SignatureEncoding::Der),
+            "3045022100934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7e6a4a49860d7a6ab210ee3d802202442ce9d2b916064108014783e923ec36b49743e2ffa1c4496f01a512aafd9e5"
+        );
+        assert_eq!(
+            encoded(ecdsa_signature(), SignatureEncoding::Compact),
+            "934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7e6a4a49860d7a6ab210ee3d82442ce9d2b916064108014783e923ec36b49743e2ffa1c4496f01a512aafd9e5"
+        );
+        assert_eq!(
+            encoded(ecdsa_signature(), SignatureEncoding::Ethereum),
+            "0x934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7e6a4a49860d7a6ab210ee3d82442ce9d2b916064108014783e923ec36b49743e2ffa1c4496f01a512aafd9e51c"
+        );
+        assert!(ecdsa_signature().encode(SignatureEncoding::Solana).is_err());
+    }
+
+    #[test]
+    fn encodes_eddsa_signatures() {
+        assert_eq!(
+            encoded(eddsa_signature(), SignatureEncoding::Compact),
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"
+        );
+        assert_eq!(
+            encoded(eddsa_signature(), SignatureEncoding::Solana),
+            "5awYiUvGiDFA33EJjj4TXJG44a5afJc8QjWRpGgQiu6b23jCr7yndW2fmp9ujwqJVe32J456wV3VF78Asb1obnTc"
+        );
+        assert!(eddsa_signature().encode(SignatureEncoding::Der).is_err());
+        assert!(eddsa_signature().encode(SignatureEncoding::Ethereum).is_err());
+    }
+
+    #[test]
+    fn raw_encoding_keeps_response() {
+        assert!(
+            matches!(
+                eddsa_signature().encode(SignatureEncoding::Raw).unwrap(),
+                SigningResponse::EDDSA(_)
+            )
+        );
+    }
+}
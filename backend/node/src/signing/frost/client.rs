@@ -0,0 +1,67 @@
+use crate::communication::nats::PeerMessenger;
+use crate::communication::protocol::{ AllRounds, KeySignFrostAllRounds };
+use crate::signing::frost::protocol::{
+    aggregate,
+    sign_share,
+    verify_share,
+    SigningNonces,
+    SigningPackage,
+    SigningTarget,
+};
+use crate::signing::frost::SignatureResult;
+use crate::storage::Frost;
+use anyhow::{ bail, Result };
+use curv::elliptic::curves::{ Point, Scalar, Secp256k1 };
+use tracing::info;
+
+pub struct FrostKeySignClient<C> {
+    pub peer_messenger: C,
+    pub all_party_indices: Vec<usize>,
+}
+
+impl<C> FrostKeySignClient<C> where C: PeerMessenger<KeySignFrostAllRounds> {
+    /// `record_nonce` is called with the group nonce commitment before the signature share is
+    /// released, see `nonce_ledger`
+    pub async fn create_signature(
+        &self,
+        message: &[u8],
+        keyshare: &Frost,
+        target: &SigningTarget,
+        record_nonce: impl FnOnce(&Point<Secp256k1>) -> Result<()>
+    ) -> Result<Vec<u8>> {
+        let (nonces, commitment) = SigningNonces::generate(keyshare.party_index, message);
+        let commitments = self.peer_messenger.broadcast_and_collect_messages(
+            &<KeySignFrostAllRounds as AllRounds>::BroadcastRound::NonceCommit,
+            commitment
+        ).await?;
+        let package = SigningPackage::new(message, commitments)?;
+        if package.signers() != self.all_party_indices {
+            bail!("Nonce commitments do not match the parties of the session");
+        }
+        info!("Exchanged nonce commitments");
+        record_nonce(&package.group_commitment()?)?;
+
+        let signature_share = sign_share(&package, nonces, keyshare, target)?;
+        let signature_shares: Vec<Scalar<Secp256k1>> =
+            self.peer_messenger.broadcast_and_collect_messages(
+                &<KeySignFrostAllRounds as AllRounds>::BroadcastRound::SignatureShare,
+                signature_share
+            ).await?;
+        for (party_index, share) in self.all_party_indices.iter().zip(&signature_shares) {
+            verify_share(&package, *party_index, share, &keyshare.public_share(*party_index), target)?;
+        }
+        info!("Verified all signature shares");
+
+        let signature = aggregate(&package, &signature_shares, target)?;
+        info!("Full signature generated and verified");
+        Ok(signature)
+    }
+
+    pub async fn publish_result(&self, signature: SignatureResult) -> Result<()> {
+        let _ = self.peer_messenger.broadcast_and_collect_messages(
+            &<KeySignFrostAllRounds as AllRounds>::BroadcastRound::Result,
+            signature
+        ).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,14 @@
+pub mod client;
+pub mod orchestrate;
+pub mod protocol;
+pub mod session;
+
+use serde::{ Deserialize, Serialize };
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SignatureResult {
+    /// 64 byte BIP-340 signature, hex
+    pub signature: String,
+    /// x-only key the signature verifies against (the Taproot output key when tweaked), hex
+    pub public_key: String,
+}
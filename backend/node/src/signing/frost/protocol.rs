@@ -0,0 +1,408 @@
+use crate::storage::Frost;
+use crate::test_seed::seeded_scalar;
+use anyhow::{ anyhow, bail, Result };
+use curv::arithmetic::Converter;
+use curv::elliptic::curves::{ Point, Scalar, Secp256k1 };
+use curv::BigInt;
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+
+const BINDING_FACTOR_TAG: &str = "FROST-secp256k1/rho";
+const CHALLENGE_TAG: &str = "BIP0340/challenge";
+const TAP_TWEAK_TAG: &str = "TapTweak";
+/// Taproot signs 32 byte sighashes
+const MESSAGE_LEN: usize = 32;
+
+/// Nonce pair for a single signing session, never reuse it once its commitment is published
+pub struct SigningNonces {
+    hiding: Scalar<Secp256k1>,
+    binding: Scalar<Secp256k1>,
+}
+
+impl SigningNonces {
+    /// The message is only used to derive reproducible nonces from a deterministic test seed
+    pub fn generate(party_index: usize, message: &[u8]) -> (Self, NonceCommitment) {
+        let label = format!("frost-nonce/{}/{}", party_index, hex::encode(Sha256::digest(message)));
+        let nonces = SigningNonces {
+            hiding: seeded_scalar(&format!("{}/hiding", label)).unwrap_or_else(Scalar::random),
+            binding: seeded_scalar(&format!("{}/binding", label)).unwrap_or_else(Scalar::random),
+        };
+        let commitment = NonceCommitment {
+            party_index,
+            hiding: Point::generator() * &nonces.hiding,
+            binding: Point::generator() * &nonces.binding,
+        };
+        (nonces, commitment)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NonceCommitment {
+    pub party_index: usize,
+    pub hiding: Point<Secp256k1>,
+    pub binding: Point<Secp256k1>,
+}
+
+/// The key a signature verifies against: the group key itself, or the Taproot output key
+/// tweaked from it (BIP-341)
+pub struct SigningTarget {
+    pub public_key: Point<Secp256k1>,
+    /// Whether shares have to be negated to sign for the even-y form of `public_key`
+    negate_shares: bool,
+    /// Tweak contribution added to the aggregated signature, already sign adjusted
+    tweak: Scalar<Secp256k1>,
+}
+
+impl SigningTarget {
+    /// `merkle_root` is `None` for a plain BIP-340 signature under the group key, empty for a
+    /// key-path only Taproot output and the 32 byte script tree root otherwise
+    pub fn new(group_public_key: &Point<Secp256k1>, merkle_root: Option<&[u8]>) -> Result<Self> {
+        let internal_odd = !has_even_y(group_public_key);
+        let merkle_root = match merkle_root {
+            Some(root) => root,
+            None => {
+                return Ok(SigningTarget {
+                    public_key: group_public_key.clone(),
+                    negate_shares: internal_odd,
+                    tweak: Scalar::zero(),
+                });
+            }
+        };
+        if !merkle_root.is_empty() && merkle_root.len() != 32 {
+            bail!("Taproot merkle root must be empty or 32 bytes, got {} bytes", merkle_root.len());
+        }
+
+        let internal_key = negate_point_if(internal_odd, group_public_key.clone());
+        let tweak = scalar_from_hash(
+            &tagged_hash(TAP_TWEAK_TAG, &[x_only(group_public_key), merkle_root.to_vec()].concat())
+        );
+        let public_key = internal_key + Point::generator() * &tweak;
+        let output_odd = !has_even_y(&public_key);
+
+        Ok(SigningTarget {
+            public_key,
+            negate_shares: internal_odd != output_odd,
+            tweak: negate_if(output_odd, tweak),
+        })
+    }
+
+    pub fn x_only_public_key(&self) -> Vec<u8> {
+        x_only(&self.public_key)
+    }
+}
+
+/// Message and nonce commitments of every signer, identical for all parties in a session
+pub struct SigningPackage {
+    pub message: Vec<u8>,
+    pub commitments: Vec<NonceCommitment>,
+}
+
+impl SigningPackage {
+    pub fn new(message: &[u8], mut commitments: Vec<NonceCommitment>) -> Result<Self> {
+        if message.len() != MESSAGE_LEN {
+            bail!("FROST signs {} byte messages, got {} bytes", MESSAGE_LEN, message.len());
+        }
+        commitments.sort_by_key(|c| c.party_index);
+        if commitments.windows(2).any(|pair| pair[0].party_index == pair[1].party_index) {
+            bail!("Received more than one nonce commitment from a party");
+        }
+        Ok(SigningPackage {
+            message: message.to_vec(),
+            commitments,
+        })
+    }
+
+    pub fn signers(&self) -> Vec<usize> {
+        self.commitments
+            .iter()
+            .map(|c| c.party_index)
+            .collect()
+    }
+
+    fn binding_factor(&self, party_index: usize) -> Scalar<Secp256k1> {
+        let mut data = (party_index as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(&self.message);
+        for c in &self.commitments {
+            data.extend_from_slice(&(c.party_index as u32).to_be_bytes());
+            data.extend_from_slice(&c.hiding.to_bytes(true));
+            data.extend_from_slice(&c.binding.to_bytes(true));
+        }
+        scalar_from_hash(&tagged_hash(BINDING_FACTOR_TAG, &data))
+    }
+
+    fn commitment_share(&self, commitment: &NonceCommitment) -> Point<Secp256k1> {
+        &commitment.hiding + &commitment.binding * &self.binding_factor(commitment.party_index)
+    }
+
+    /// Group nonce commitment R, before it is adjusted to have an even y coordinate
+    pub fn group_commitment(&self) -> Result<Point<Secp256k1>> {
+        let group_commitment = self.commitments
+            .iter()
+            .fold(Point::zero(), |sum, c| sum + self.commitment_share(c));
+        if group_commitment.is_zero() {
+            bail!("Group nonce commitment is the point at infinity");
+        }
+        Ok(group_commitment)
+    }
+
+    fn challenge(&self, target: &SigningTarget) -> Result<Scalar<Secp256k1>> {
+        let group_commitment = self.group_commitment()?;
+        Ok(challenge(&x_only(&group_commitment), &target.x_only_public_key(), &self.message))
+    }
+}
+
+/// Computes this party's signature share, consuming its nonces
+pub fn sign_share(
+    package: &SigningPackage,
+    nonces: SigningNonces,
+    keyshare: &Frost,
+    target: &SigningTarget
+) -> Result<Scalar<Secp256k1>> {
+    let party_index = keyshare.party_index;
+    let signers = package.signers();
+    if !signers.contains(&party_index) {
+        bail!("Party {} has no nonce commitment in the signing package", party_index);
+    }
+
+    let group_commitment = package.group_commitment()?;
+    let challenge = package.challenge(target)?;
+    let lambda = lagrange_coefficient(party_index, &signers)?;
+
+    let nonce = negate_if(
+        !has_even_y(&group_commitment),
+        &nonces.hiding + &nonces.binding * &package.binding_factor(party_index)
+    );
+    let share = negate_if(target.negate_shares, keyshare.x_i.clone());
+
+    Ok(nonce + challenge * lambda * share)
+}
+
+/// Checks a signature share against the signer's public share, so a misbehaving party can be named
+pub fn verify_share(
+    package: &SigningPackage,
+    party_index: usize,
+    signature_share: &Scalar<Secp256k1>,
+    public_share: &Point<Secp256k1>,
+    target: &SigningTarget
+) -> Result<()> {
+    let commitment = package.commitments
+        .iter()
+        .find(|c| c.party_index == party_index)
+        .ok_or_else(|| anyhow!("Party {} has no nonce commitment", party_index))?;
+
+    let group_commitment = package.group_commitment()?;
+    let challenge = package.challenge(target)?;
+    let lambda = lagrange_coefficient(party_index, &package.signers())?;
+
+    let nonce_commitment = negate_point_if(
+        !has_even_y(&group_commitment),
+        package.commitment_share(commitment)
+    );
+    let public_share = negate_point_if(target.negate_shares, public_share.clone());
+
+    if Point::generator() * signature_share != nonce_commitment + public_share * (challenge * lambda) {
+        bail!("Invalid signature share from party {}", party_index);
+    }
+    Ok(())
+}
+
+/// Combines the signature shares into a 64 byte BIP-340 signature and verifies it
+pub fn aggregate(
+    package: &SigningPackage,
+    signature_shares: &[Scalar<Secp256k1>],
+    target: &SigningTarget
+) -> Result<Vec<u8>> {
+    let group_commitment = package.group_commitment()?;
+    let challenge = package.challenge(target)?;
+    let s = signature_shares
+        .iter()
+        .fold(challenge * &target.tweak, |sum, share| sum + share);
+
+    let mut signature = x_only(&group_commitment);
+    signature.extend(scalar_to_bytes(&s));
+
+    verify_bip340(&signature, &package.message, &target.x_only_public_key())?;
+    Ok(signature)
+}
+
+pub fn verify_bip340(signature: &[u8], message: &[u8], public_key: &[u8]) -> Result<()> {
+    if signature.len() != 64 || public_key.len() != 32 {
+        bail!("BIP-340 signatures are 64 bytes and public keys 32 bytes");
+    }
+    let point = Point::<Secp256k1>::from_bytes(&[&[0x02u8][..], public_key].concat()).map_err(|_|
+        anyhow!("Public key is not a valid x-only point")
+    )?;
+
+    let s = BigInt::from_bytes(&signature[32..]);
+    if &s >= Scalar::<Secp256k1>::group_order() {
+        bail!("Signature s value exceeds the group order");
+    }
+    let challenge = challenge(&signature[..32], public_key, message);
+    let nonce_commitment =
+        Point::generator() * &Scalar::from_bigint(&s) - point * &challenge;
+
+    if
+        nonce_commitment.is_zero() ||
+        !has_even_y(&nonce_commitment) ||
+        x_only(&nonce_commitment) != signature[..32]
+    {
+        bail!("BIP-340 signature verification failed");
+    }
+    Ok(())
+}
+
+pub fn x_only(point: &Point<Secp256k1>) -> Vec<u8> {
+    point.to_bytes(true)[1..].to_vec()
+}
+
+fn has_even_y(point: &Point<Secp256k1>) -> bool {
+    point.to_bytes(true)[0] == 0x02
+}
+
+fn challenge(nonce_x: &[u8], public_key_x: &[u8], message: &[u8]) -> Scalar<Secp256k1> {
+    scalar_from_hash(&tagged_hash(CHALLENGE_TAG, &[nonce_x, public_key_x, message].concat()))
+}
+
+fn lagrange_coefficient(party_index: usize, signers: &[usize]) -> Result<Scalar<Secp256k1>> {
+    let x_i = index_to_scalar(party_index);
+    let mut numerator = index_to_scalar(1);
+    let mut denominator = index_to_scalar(1);
+    for &j in signers.iter().filter(|&&j| j != party_index) {
+        let x_j = index_to_scalar(j);
+        numerator = numerator * &x_j;
+        denominator = denominator * (x_j - &x_i);
+    }
+    let inverse = denominator
+        .invert()
+        .ok_or_else(|| anyhow!("Signer indices must be distinct and non-zero"))?;
+    Ok(numerator * inverse)
+}
+
+fn index_to_scalar(index: usize) -> Scalar<Secp256k1> {
+    Scalar::from_bigint(&BigInt::from(index as u64))
+}
+
+fn tagged_hash(tag: &str, data: &[u8]) -> Vec<u8> {
+    let tag_hash = Sha256::digest(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(&tag_hash);
+    hasher.update(&tag_hash);
+    hasher.update(data);
+    hasher.finalize().to_vec()
+}
+
+fn scalar_from_hash(hash: &[u8]) -> Scalar<Secp256k1> {
+    Scalar::from_bigint(&BigInt::from_bytes(hash))
+}
+
+fn scalar_to_bytes(scalar: &Scalar<Secp256k1>) -> Vec<u8> {
+    let bytes = scalar.to_bigint().to_bytes();
+    let mut padded = vec![0u8; 32 - bytes.len()];
+    padded.extend(bytes);
+    padded
+}
+
+fn negate_if(negate: bool, scalar: Scalar<Secp256k1>) -> Scalar<Secp256k1> {
+    if negate { Scalar::zero() - scalar } else { scalar }
+}
+
+fn negate_point_if(negate: bool, point: Point<Secp256k1>) -> Point<Secp256k1> {
+    if negate { Point::zero() - point } else { point }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+
+    const THRESHOLD: usize = 2;
+    const PARTY_COUNT: usize = 5;
+
+    // Distributed key generation without the network: every party deals a Feldman VSS
+    fn generate_keyshares() -> Vec<Frost> {
+        let indices = (1..=PARTY_COUNT as u16).collect::<Vec<_>>();
+        let dealings = indices
+            .iter()
+            .map(|_| {
+                VerifiableSS::<Secp256k1>::share_at_indices(
+                    THRESHOLD as u16,
+                    PARTY_COUNT as u16,
+                    &Scalar::random(),
+                    &indices
+                )
+            })
+            .collect::<Vec<_>>();
+        let vss_scheme_vec = dealings
+            .iter()
+            .map(|(vss, _)| vss.clone())
+            .collect::<Vec<_>>();
+        let group_public_key = vss_scheme_vec
+            .iter()
+            .fold(Point::zero(), |sum, vss| sum + &vss.commitments[0]);
+
+        (0..PARTY_COUNT)
+            .map(|i| Frost {
+                threshold: THRESHOLD,
+                party_index: i + 1,
+                x_i: dealings
+                    .iter()
+                    .fold(Scalar::zero(), |sum, (_, shares)| sum + &shares[i]),
+                group_public_key: group_public_key.clone(),
+                vss_scheme_vec: vss_scheme_vec.clone(),
+            })
+            .collect()
+    }
+
+    fn threshold_sign(keyshares: &[Frost], signers: &[usize], merkle_root: Option<&[u8]>) {
+        let message = Sha256::digest(b"taproot sighash").to_vec();
+        let target = SigningTarget::new(&keyshares[0].group_public_key, merkle_root).unwrap();
+
+        let (nonces, commitments): (Vec<_>, Vec<_>) = signers
+            .iter()
+            .map(|&i| SigningNonces::generate(i, &message))
+            .unzip();
+        let package = SigningPackage::new(&message, commitments).unwrap();
+
+        let shares = signers
+            .iter()
+            .zip(nonces)
+            .map(|(&i, nonces)| sign_share(&package, nonces, &keyshares[i - 1], &target).unwrap())
+            .collect::<Vec<_>>();
+        for (&i, share) in signers.iter().zip(&shares) {
+            let public_share = keyshares[0].public_share(i);
+            verify_share(&package, i, share, &public_share, &target).unwrap();
+        }
+
+        let signature = aggregate(&package, &shares, &target).unwrap();
+        verify_bip340(&signature, &message, &target.x_only_public_key()).unwrap();
+    }
+
+    #[test]
+    fn threshold_signatures_verify_under_bip340() {
+        // Repeated so both parities of the group key and nonce commitment are exercised
+        for _ in 0..4 {
+            let keyshares = generate_keyshares();
+            threshold_sign(&keyshares, &[1, 3, 5], None);
+            threshold_sign(&keyshares, &[2, 3, 4], Some(&[]));
+            threshold_sign(&keyshares, &[1, 2, 5], Some(&[7u8; 32]));
+        }
+    }
+
+    #[test]
+    fn verifies_bip340_reference_vector() {
+        // BIP-340 test vector 0
+        let public_key = hex
+            ::decode("f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9")
+            .unwrap();
+        let signature = hex
+            ::decode(
+                "e907831f80848d1069a5371b402410364bdf1c5f8307b0084c55f1ce2dca821525f66a4a85ea8b71e482a74f382d2ce5ebeee8fdb2172f477df4900d310536c0"
+            )
+            .unwrap();
+        let mut message = vec![0u8; 32];
+
+        verify_bip340(&signature, &message, &public_key).unwrap();
+        message[31] = 1;
+        assert!(verify_bip340(&signature, &message, &public_key).is_err());
+    }
+}
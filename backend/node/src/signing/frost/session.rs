@@ -0,0 +1,270 @@
+use crate::audit::{ AuditAction, AuditedRequest };
+use crate::communication::incoming::IncomingMessage;
+use crate::auth::client_e2e_decrypt_secret;
+use crate::communication::nats::{
+    BaseMessenger,
+    NatsBaseMessenger,
+    NatsBaseSession,
+    NatsPeerMessenger,
+};
+use crate::communication::protocol::{ KeySignFrostAllRounds, Topic };
+use crate::node::NodeIdentity;
+use crate::observer::with_consented_observers;
+use crate::signing::frost::client::FrostKeySignClient;
+use crate::signing::frost::protocol::SigningTarget;
+use crate::signing::frost::SignatureResult;
+use crate::signing::nonce_ledger;
+use crate::signing::validation::{
+    check_access_key,
+    check_transfer_target,
+    verify_hmac,
+    verify_timestamp,
+};
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::storage::{ Frost, KeyshareAccessor };
+use crate::App;
+use crate::metrics::SessionKind;
+use crate::session_error::{ self, SessionError, SessionErrorCode };
+use crate::session_manager;
+use anyhow::{ anyhow, bail, Result };
+use curv::elliptic::curves::{ Point, Secp256k1 };
+use serde::{ Deserialize, Serialize };
+use crate::slo;
+use std::time::Instant;
+use tracing::{ error, info, instrument };
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NewFrostKeySignMessage {
+    pub key_id: String,
+    pub session_id: String,
+    pub message: Vec<u8>,
+    pub client_e2e_public_key: String,
+    pub encrypted_signing_key: String,
+    pub is_transfer_tx: Option<bool>,
+    pub timestamp: Option<String>,
+    pub message_hmac: Option<String>,
+    pub email: Option<String>,
+    /// Hex script tree root of the Taproot output being spent, empty for a key-path only output.
+    /// Without it the signature is made under the untweaked group key.
+    #[serde(default)]
+    pub taproot_merkle_root: Option<String>,
+    /// Owner's override for signing a message again with a new nonce, see `nonce_ledger`
+    #[serde(default)]
+    pub allow_resign: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct NewFrostKeySignSession {
+    pub key_id: String,
+    pub session_id: String,
+    pub message: Vec<u8>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub taproot_merkle_root: Option<String>,
+    #[serde(default)]
+    pub allow_resign: bool,
+}
+
+#[instrument(skip_all)]
+async fn sign_session(conn: async_nats::Client, session: NewFrostKeySignSession) {
+    let session_id = session.session_id.clone();
+    let key_id = session.key_id.clone();
+    let audit = AuditedRequest::new(
+        AuditAction::Signing,
+        "frost",
+        &key_id,
+        &session_id,
+        session.email.as_deref()
+    );
+    let email = session.email.clone().unwrap_or_default();
+    let message = session.message.clone();
+    let started = Instant::now();
+    let result = keysign_session_inner(conn, session).await;
+    slo::record_signing("frost", &key_id, started.elapsed(), result.is_ok());
+    audit.record(&result);
+    if result.is_ok() {
+        KeyMetadataStore::record_usage(&key_id, &email, &message);
+    }
+    match result {
+        Ok(()) => info!("Signing completed successfully for session id: {}", session_id),
+        Err(err) => error!("Error in FROST signing: session id: {}, error: {}", session_id, err),
+    }
+}
+
+async fn keysign_session_inner(
+    conn: async_nats::Client,
+    session: NewFrostKeySignSession
+) -> Result<()> {
+    let key_id = session.key_id.clone();
+    info!("joining FROST keysign session key_id: {}", &key_id);
+
+    let keyshare = match &session.email {
+        Some(email) => KeyshareAccessor::<Frost>::read_only_with_email(&key_id, email)?.key,
+        None => KeyshareAccessor::<Frost>::read_only(&key_id)?.key,
+    };
+    info!("Retrieved keyshare");
+
+    let merkle_root = session.taproot_merkle_root.as_deref().map(hex::decode).transpose()?;
+    let target = SigningTarget::new(&keyshare.group_public_key, merkle_root.as_deref())?;
+
+    let node = NodeIdentity::cached()?;
+    let nats_session = NatsBaseSession {
+        session_id: session.session_id.clone(),
+        thread_index: 0,
+        node_id: node.node_id.to_string(),
+        public_key: node.networking_public_key,
+        party_index: keyshare.party_index,
+    };
+
+    let messenger = NatsBaseMessenger::<KeySignFrostAllRounds>::new(
+        Topic::KeySignFrost,
+        conn,
+        nats_session
+    ).await?;
+    let join_response = messenger.wait_for_confirmation(std::time::Duration::from_secs(10)).await?;
+    info!("Got join response");
+
+    let party_count = join_response.party_count;
+    let mut all_party_indices = join_response.all_party_indices;
+    all_party_indices.sort();
+    if all_party_indices.len() <= keyshare.threshold {
+        bail!(
+            "{} signers joined, at least {} are needed",
+            all_party_indices.len(),
+            keyshare.threshold + 1
+        );
+    }
+
+    let peer_messenger = with_consented_observers(
+        NatsPeerMessenger::from(messenger, party_count, all_party_indices.clone())?,
+        &key_id,
+        session.email.as_deref()
+    );
+    let keysign_client = FrostKeySignClient {
+        peer_messenger,
+        all_party_indices,
+    };
+
+    // The same nonce under another tweak leaks the share as well, the ledger is kept by the key
+    // the signature is made under
+    let mut ledger_message = session.message.clone();
+    ledger_message.extend(target.x_only_public_key());
+    let record_nonce = |group_commitment: &Point<Secp256k1>| {
+        nonce_ledger::check_and_record(
+            &key_id,
+            session.email.as_deref().unwrap_or_default(),
+            &ledger_message,
+            &*group_commitment.to_bytes(true),
+            session.allow_resign
+        )
+    };
+    let signature = keysign_client.create_signature(
+        &session.message,
+        &keyshare,
+        &target,
+        record_nonce
+    ).await?;
+    keysign_client.publish_result(SignatureResult {
+        signature: hex::encode(signature),
+        public_key: hex::encode(target.x_only_public_key()),
+    }).await?;
+    info!("Signature published successfully");
+
+    keysign_client.peer_messenger.publish_transcript().await
+}
+
+/// Runs the same checks as the other signing sessions, returning the email the key belongs to
+fn authorize_request(request: &NewFrostKeySignMessage) -> Result<String, SessionError> {
+    let (timestamp, message_hmac, email) = match
+        (&request.timestamp, &request.message_hmac, &request.email)
+    {
+        (Some(timestamp), Some(message_hmac), Some(email)) => (timestamp, message_hmac, email),
+        _ => {
+            return Err(
+                SessionError::new(
+                    SessionErrorCode::MalformedRequest,
+                    "Missing required security fields: timestamp, message_hmac, or email"
+                )
+            );
+        }
+    };
+    let internal = |err: anyhow::Error| SessionError::new(SessionErrorCode::InternalError, err);
+    let unauthenticated = |err: anyhow::Error| {
+        SessionError::new(SessionErrorCode::AuthenticationFailed, err)
+    };
+
+    let node = NodeIdentity::cached().map_err(internal)?;
+    let node_signing_key = client_e2e_decrypt_secret(
+        &request.encrypted_signing_key,
+        &node.e2e_private_key,
+        &request.client_e2e_public_key
+    ).map_err(|err| unauthenticated(anyhow!("Failed to decrypt signing key: {}", err)))?;
+
+    if !verify_hmac(message_hmac, timestamp, email, &node_signing_key) {
+        return Err(unauthenticated(anyhow!("HMAC verification failed")));
+    }
+    if !verify_timestamp(&request.key_id, timestamp, email) {
+        return Err(unauthenticated(anyhow!("Timestamp verification failed")));
+    }
+
+    if request.is_transfer_tx.unwrap_or(false) {
+        info!("Initiating ownership transfer");
+        check_transfer_target(&request.message, email).map_err(unauthenticated)?;
+        KeyMetadataStore::remove_user_level("new_identity_key", email).map_err(internal)?;
+        info!("Successfully removed new_identity_key after ownership verification");
+    }
+
+    check_access_key(&request.key_id, email, &node_signing_key).map_err(unauthenticated)?;
+
+    if
+        let Err(err) = KeyMetadataStore::save_user_level(
+            &request.client_e2e_public_key,
+            "e2e_key",
+            email,
+            &WriteOpts::Modify
+        )
+    {
+        error!("Failed to store client_e2e_public_key: {}", err);
+    }
+
+    Ok(email.clone())
+}
+
+pub fn handle_new_session_message(app: &App, message: IncomingMessage) {
+    let request = match serde_json::from_slice::<NewFrostKeySignMessage>(&message.data[..]) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Failed to parse message: {}", err)
+            );
+            return;
+        }
+    };
+
+    let email = match authorize_request(&request) {
+        Ok(email) => email,
+        Err(err) => {
+            session_error::report(app, &message, err);
+            return;
+        }
+    };
+
+    let session = NewFrostKeySignSession {
+        key_id: request.key_id,
+        session_id: request.session_id,
+        message: request.message,
+        email: Some(email),
+        taproot_merkle_root: request.taproot_merkle_root,
+        allow_resign: request.allow_resign,
+    };
+
+    info!("Spawning a task to handle FROST signature generation");
+    let nc = app.nc.client().clone();
+    let session_id = session.session_id.clone();
+    session_manager::spawn_session(SessionKind::Signing, &session_id, sign_session(nc, session));
+}
@@ -0,0 +1,46 @@
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use sha3::Keccak256;
+
+/// How the node turns the message of a signing request into the bytes that are signed, so clients
+/// can send raw transaction payloads instead of hashing them first
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashMode {
+    /// Signed as given, ECDSA then requires a hash of at most 32 bytes
+    #[default]
+    None,
+    Sha256,
+    /// Ethereum's Keccak-256, not the standardized SHA3-256
+    Keccak256,
+}
+
+impl HashMode {
+    pub fn apply(&self, message: &[u8]) -> Vec<u8> {
+        match self {
+            HashMode::None => message.to_vec(),
+            HashMode::Sha256 => Sha256::digest(message).to_vec(),
+            HashMode::Keccak256 => Keccak256::digest(message).to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_messages_of_any_length() {
+        let payload = vec![7u8; 300];
+        assert_eq!(HashMode::None.apply(&payload), payload);
+        assert_eq!(HashMode::Sha256.apply(&payload).len(), 32);
+        assert_eq!(
+            hex::encode(HashMode::Keccak256.apply(b"")),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+        assert_eq!(
+            hex::encode(HashMode::Sha256.apply(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
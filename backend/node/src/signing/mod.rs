@@ -1,12 +1,30 @@
 use crate::command::{ JsonCommand, MsgContext };
-use anyhow::Result;
+use crate::reputation;
+use crate::tenant::Access;
+use anyhow::{ bail, Result };
+use encoding::{ EncodedSignature, SignatureEncoding };
+use hashing::HashMode;
+use network::NetworkMode;
+use response::{ ResponseVersion, VersionedSigningResponse };
 use serde::{ Deserialize, Serialize };
 use shared::key_info::NodeId;
 
+pub mod batch;
+pub mod bls;
 pub mod ecdsa;
 pub mod eddsa;
+pub mod encoding;
+pub mod frost;
+pub mod hashing;
+pub mod network;
+pub mod nonce_ledger;
+pub mod preflight;
+pub mod presigning;
+pub mod response;
 pub mod sr25519;
 pub mod sr25519_musign;
+pub mod tx_inspector;
+pub mod validation;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct SigningCommand {
@@ -16,17 +34,84 @@ pub struct SigningCommand {
     pub session_id: String,
     pub party_nodes: Vec<NodeId>,
     pub msg: Vec<u8>,
+    /// Returns the signature encoded for the target chain instead of its raw components
+    #[serde(default)]
+    pub encoding: Option<SignatureEncoding>,
+    /// FROST only: hex script tree root of the Taproot output being spent, empty for a key-path
+    /// only output
+    #[serde(default)]
+    pub taproot_merkle_root: Option<String>,
+    /// ECDSA only: presignature from an `OrchestratePresign` command with the same party nodes,
+    /// signs in a single round
+    #[serde(default)]
+    pub presignature_id: Option<String>,
+    /// ECDSA and EdDSA only: hash applied by every node to `msg` before signing it
+    #[serde(default)]
+    pub hash_mode: HashMode,
+    /// EdDSA only: chain the message is a transaction of, the node derives what is signed
+    #[serde(default)]
+    pub network_mode: NetworkMode,
+    /// Format of the response, see `ResponseVersion`
+    #[serde(default)]
+    pub response_version: ResponseVersion,
+    /// EdDSA, FROST and sr25519 only: signs a message again that the key already signed with
+    /// another nonce, see `nonce_ledger`
+    #[serde(default)]
+    pub allow_resign: bool,
+    /// Starts the session even if some of the party nodes are quarantined, see `reputation`
+    #[serde(default)]
+    pub allow_quarantined_peers: bool,
+    /// Only checks whether the signature could be made, answers with a `PreflightReport`
+    #[serde(default)]
+    pub dry_run: bool,
+    /// ECDSA only: BIP-32 path of the child key to sign for, see `keygen::derivation`
+    #[serde(default)]
+    pub derivation_path: Option<String>,
 }
 
 impl JsonCommand for SigningCommand {
-    type Response = SigningResponse;
+    type Response = VersionedSigningResponse;
+
+    /// Every guardian checks the owner's HMAC of the request when the session starts
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
 
     fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
-        match self.kind {
-            Key::ECDSA => ecdsa::orchestrate::orchestrate(self, ctx),
-            Key::EDDSA => eddsa::orchestrate::orchestrate(self, ctx),
-            Key::Sr25519 => { todo!() }
+        if self.dry_run {
+            let report = preflight::dry_run(&ctx.get_app()?, &self);
+            let response = SigningResponse::DryRun(report);
+            return Ok(VersionedSigningResponse::new(self.response_version, self.kind, response));
+        }
+        reputation::check_parties(&self.party_nodes, self.allow_quarantined_peers)?;
+        if self.derivation_path.is_some() {
+            if !matches!(self.kind, Key::ECDSA) {
+                bail!("Only ECDSA keys sign for derived child keys");
+            }
+            if self.presignature_id.is_some() {
+                bail!("Presignatures are made for the parent key, not for its children");
+            }
         }
+        let encoding = self.encoding;
+        let version = self.response_version;
+        let kind = self.kind.clone();
+        let response = match self.kind {
+            Key::ECDSA =>
+                match self.presignature_id.clone() {
+                    Some(presignature_id) =>
+                        presigning::orchestrate::orchestrate_online(self, presignature_id, ctx)?,
+                    None => ecdsa::orchestrate::orchestrate(self, ctx)?,
+                }
+            Key::EDDSA => eddsa::orchestrate::orchestrate(self, ctx)?,
+            Key::Frost => frost::orchestrate::orchestrate(self, ctx)?,
+            Key::Sr25519 => sr25519::orchestrate::orchestrate(self, ctx)?,
+            Key::BLS => bls::orchestrate::orchestrate(self, ctx)?,
+        };
+        let response = match encoding {
+            Some(encoding) => response.encode(encoding)?,
+            None => response,
+        };
+        Ok(VersionedSigningResponse::new(version, kind, response))
     }
 }
 
@@ -36,6 +121,8 @@ pub enum Key {
     ECDSA,
     EDDSA,
     Sr25519,
+    Frost,
+    BLS,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -43,4 +130,12 @@ pub enum Key {
 pub enum SigningResponse {
     ECDSA(ecdsa::SigningResult),
     EDDSA(eddsa::SignatureResult),
+    Frost(frost::SignatureResult),
+    Sr25519(sr25519::SignatureResult),
+    BLS(bls::SignatureResult),
+    Encoded(EncodedSignature),
+    /// ECDSA only: the parties blamed for a failed session
+    Failed(ecdsa::SigningFailure),
+    /// Readiness of a dry run, no signature was made
+    DryRun(preflight::PreflightReport),
 }
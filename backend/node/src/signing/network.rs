@@ -0,0 +1,131 @@
+use crate::signing::hashing::HashMode;
+use anyhow::{ bail, Context, Result };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+
+/// `ENVELOPE_TYPE_TX`, the discriminant of v1 transaction envelopes and signature payloads
+const STELLAR_ENVELOPE_TYPE_TX: [u8; 4] = [0, 0, 0, 2];
+const SOLANA_SIGNATURE_LEN: usize = 64;
+
+/// Chain specific pre-processing of an EdDSA signing request. The message is then the chain's
+/// own transaction encoding and the node derives the bytes the chain expects to be signed.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(tag = "network", rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// Signed as given
+    #[default]
+    Generic,
+    /// The message is the XDR of a v1 `Transaction`, or of an unsigned `TransactionEnvelope`
+    /// holding one. The transaction hash for the given network is signed.
+    Stellar {
+        network_passphrase: String,
+    },
+    /// The message is a serialized transaction, its message without the signature slots is
+    /// signed
+    Solana,
+}
+
+impl NetworkMode {
+    /// Bytes the threshold signature is created over. Chain modes define their own digest, so
+    /// they cannot be combined with a hash mode.
+    pub fn signing_payload(&self, message: &[u8], hash_mode: HashMode) -> Result<Vec<u8>> {
+        if *self != NetworkMode::Generic && hash_mode != HashMode::None {
+            bail!("A hash mode cannot be combined with the {:?} network mode", self);
+        }
+        match self {
+            NetworkMode::Generic => Ok(hash_mode.apply(message)),
+            NetworkMode::Stellar { network_passphrase } =>
+                stellar_transaction_hash(network_passphrase, message),
+            NetworkMode::Solana => solana_message(message).map(<[u8]>::to_vec),
+        }
+    }
+}
+
+fn stellar_transaction_hash(network_passphrase: &str, xdr: &[u8]) -> Result<Vec<u8>> {
+    if network_passphrase.is_empty() {
+        bail!("Stellar signing needs the network passphrase");
+    }
+    let transaction = match xdr.strip_prefix(&STELLAR_ENVELOPE_TYPE_TX[..]) {
+        // Without signatures the envelope ends in the empty signature array
+        Some(envelope) =>
+            envelope
+                .strip_suffix(&[0u8; 4][..])
+                .context("Only unsigned Stellar transaction envelopes can be signed")?,
+        None => xdr,
+    };
+    if transaction.is_empty() || transaction.len() % 4 != 0 {
+        bail!("Stellar transaction XDR has an invalid length of {} bytes", transaction.len());
+    }
+
+    let network_id = Sha256::digest(network_passphrase.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(network_id);
+    hasher.update(STELLAR_ENVELOPE_TYPE_TX);
+    hasher.update(transaction);
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Strips the signature slots in front of the message of a serialized Solana transaction
+fn solana_message(transaction: &[u8]) -> Result<&[u8]> {
+    let (signature_count, offset) = read_compact_u16(transaction)?;
+    let message = transaction
+        .get(offset + signature_count * SOLANA_SIGNATURE_LEN..)
+        .filter(|message| !message.is_empty())
+        .context("Solana transaction is shorter than its signatures")?;
+
+    // Versioned messages start with a prefix byte, legacy ones with the header directly
+    let header = if message[0] & 0x80 != 0 { message.get(1) } else { message.first() };
+    let required_signatures = *header.context("Solana message has no header")? as usize;
+    if required_signatures != signature_count {
+        bail!(
+            "Solana transaction has {} signature slots but its message requires {}",
+            signature_count,
+            required_signatures
+        );
+    }
+    Ok(message)
+}
+
+/// Solana's shortvec length encoding, 7 bits per byte with the high bit marking continuation
+fn read_compact_u16(bytes: &[u8]) -> Result<(usize, usize)> {
+    let mut value = 0usize;
+    for (i, byte) in bytes.iter().take(3).enumerate() {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    bail!("Invalid compact length in Solana transaction")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TESTNET: &str = "Test SDF Network ; September 2015";
+
+    #[test]
+    fn derives_chain_signing_payloads() {
+        let transaction = vec![0u8, 0, 0, 0, 1, 2, 3, 4];
+        let stellar = NetworkMode::Stellar { network_passphrase: TESTNET.to_string() };
+        let hash = stellar.signing_payload(&transaction, HashMode::None).unwrap();
+        let expected = Sha256::digest(
+            &[Sha256::digest(TESTNET.as_bytes()).as_slice(), &[0, 0, 0, 2][..], &transaction[..]]
+                .concat()
+        );
+        assert_eq!(hash, expected.to_vec());
+        let envelope = [&[0u8, 0, 0, 2][..], &transaction[..], &[0, 0, 0, 0][..]].concat();
+        assert_eq!(stellar.signing_payload(&envelope, HashMode::None).unwrap(), hash);
+        let signed = [&envelope[..envelope.len() - 4], &[0, 0, 0, 1][..]].concat();
+        assert!(stellar.signing_payload(&signed, HashMode::None).is_err());
+        assert!(stellar.signing_payload(&transaction, HashMode::Sha256).is_err());
+
+        // One empty signature slot followed by a legacy message requiring one signature
+        let message = vec![1u8, 0, 1, 2, 9, 9];
+        let solana_tx = [&[1u8][..], &[0u8; 64][..], &message[..]].concat();
+        let payload = NetworkMode::Solana.signing_payload(&solana_tx, HashMode::None).unwrap();
+        assert_eq!(payload, message);
+        let missing_slot = [&[0u8][..], &message[..]].concat();
+        assert!(NetworkMode::Solana.signing_payload(&missing_slot, HashMode::None).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+//! Ledger of the nonces Schnorr-family signatures (EdDSA, FROST, sr25519) were made with, by key
+//! and message. A share signing the same message under two different nonces can leak the share
+//! when the nonce contribution of the party repeats, e.g. with deterministic nonces, so a
+//! coordinator replaying a session with other parties' nonces swapped must not get a second
+//! signature.
+
+use crate::session_manager;
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use anyhow::{ bail, Result };
+use sha2::{ Digest, Sha256 };
+use tracing::warn;
+
+const LEDGER_PREFIX: &str = "nonce";
+
+/// Checks the nonce against the one the message was signed with before and records it, before
+/// the party releases its signature share. `allow_resign` is the explicit override of the owner
+/// for signing a message again with a fresh nonce.
+pub fn check_and_record(
+    key_id: &str,
+    email: &str,
+    message: &[u8],
+    nonce: &[u8],
+    allow_resign: bool
+) -> Result<()> {
+    let metadata_type = format!("{}-{}", LEDGER_PREFIX, hex::encode(Sha256::digest(message)));
+    let nonce = hex::encode(nonce);
+    let _lock = session_manager::lock_key(key_id)?;
+    let stored = KeyMetadataStore::get(key_id, &metadata_type, email).ok();
+    if !needs_record(stored.as_deref(), &nonce, allow_resign)? {
+        return Ok(());
+    }
+    if stored.is_some() {
+        warn!("Signing a message again with key {} and a new nonce, allowed by the owner", key_id);
+    }
+    KeyMetadataStore::save(&nonce, key_id, &metadata_type, email, &WriteOpts::Modify)
+}
+
+/// Whether the nonce has to be recorded, fails when the message was signed with another nonce
+fn needs_record(stored: Option<&str>, nonce: &str, allow_resign: bool) -> Result<bool> {
+    match stored {
+        None => Ok(true),
+        Some(stored) if stored == nonce => Ok(false),
+        Some(_) if allow_resign => Ok(true),
+        Some(_) =>
+            bail!(
+                "Message was already signed with this key under a different nonce, refusing to \
+                 sign it again without an explicit override"
+            ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_a_second_nonce_for_the_same_message() {
+        assert!(needs_record(None, "aa", false).unwrap());
+        assert!(!needs_record(Some("aa"), "aa", false).unwrap());
+        assert!(needs_record(Some("aa"), "bb", false).is_err());
+        assert!(needs_record(Some("aa"), "bb", true).unwrap());
+    }
+}
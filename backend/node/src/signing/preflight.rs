@@ -0,0 +1,307 @@
+use crate::auth::client_e2e_decrypt_secret;
+use crate::command::{ JsonCommand, MsgContext };
+use crate::liveness;
+use crate::policy::{ check_signing_policy, SigningRequest };
+use crate::reputation;
+use crate::signing::validation::{
+    check_access_key,
+    check_timestamp,
+    check_transfer_target,
+    verify_hmac,
+};
+use crate::signing::hashing::HashMode;
+use crate::signing::tx_inspector;
+use crate::signing::{ Key, SigningCommand };
+use crate::storage::key_listing::list_keys;
+use crate::storage::{ Frost, KeyInfoStore, KeyshareAccessor, BLS, ECDSA, EDDSA, Sr25519 };
+use crate::App;
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Result };
+use serde::{ Deserialize, Serialize };
+use shared::key_info::{ KeyInfo, NodeId };
+use tracing::warn;
+
+/// GG20 signing operates on 32 byte message hashes
+const MAX_ECDSA_MESSAGE_LEN: usize = 32;
+/// FROST signs Taproot sighashes
+const FROST_MESSAGE_LEN: usize = 32;
+
+/// Runs every check a signing request would go through on this node, without starting the
+/// protocol or consuming any state (the stored timestamp and transfer identity are left as is).
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PreflightSigningCommand {
+    #[serde(flatten)]
+    pub kind: Key,
+    pub key_id: String,
+    pub message: Vec<u8>,
+    pub client_e2e_public_key: String,
+    pub encrypted_signing_key: String,
+    pub is_transfer_tx: Option<bool>,
+    pub timestamp: String,
+    pub message_hmac: String,
+    pub email: String,
+    #[serde(default)]
+    pub hash_mode: HashMode,
+    /// The message is an unsigned Ethereum transaction, decoded for the policy
+    #[serde(default)]
+    pub is_ethereum_tx: Option<bool>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum PreflightStatus {
+    Pass,
+    Fail,
+    Skipped,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PreflightCheck {
+    pub check: String,
+    pub status: PreflightStatus,
+    pub detail: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PreflightReport {
+    pub ready: bool,
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    pub(crate) fn new() -> Self {
+        PreflightReport {
+            ready: true,
+            checks: Vec::new(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, check: &str, result: Result<()>) -> bool {
+        let passed = result.is_ok();
+        self.checks.push(PreflightCheck {
+            check: check.to_string(),
+            status: if passed { PreflightStatus::Pass } else { PreflightStatus::Fail },
+            detail: result.err().map(|err| err.to_string()),
+        });
+        self.ready &= passed;
+        passed
+    }
+
+    pub(crate) fn skip(&mut self, check: &str, reason: &str) {
+        self.checks.push(PreflightCheck {
+            check: check.to_string(),
+            status: PreflightStatus::Skipped,
+            detail: Some(reason.to_string()),
+        });
+    }
+}
+
+impl JsonCommand for PreflightSigningCommand {
+    type Response = PreflightReport;
+
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let app = ctx.get_app()?;
+        let mut report = PreflightReport::new();
+
+        // Nothing else is checked for a request the owner did not authorize, the other checks would
+        // tell anyone which keys, access keys and timestamps the node holds
+        if let Err(err) = self.authorize(&app.node.e2e_private_key) {
+            warn!("Preflight of key {} is not authorized: {:#}", self.key_id, err);
+            report.record("auth", Err(anyhow!("Request is not authorized")));
+            return Ok(report);
+        }
+        report.record("auth", Ok(()));
+
+        report.record("timestamp", check_timestamp(&self.key_id, &self.timestamp, &self.email));
+
+        if self.is_transfer_tx.unwrap_or(false) {
+            report.record("transfer", check_transfer_target(&self.message, &self.email));
+        } else {
+            report.skip("transfer", "Not a transfer transaction");
+        }
+
+        // The policy includes the key's hourly signing limit
+        report.record("policy", self.check_policy());
+        report.record("key_existence", self.check_keyshare());
+        report.record("message", check_message(&self.kind, &self.message, &self.hash_mode));
+
+        match KeyInfoStore::get_key_info(&self.key_id) {
+            Ok(key_info) => report.record("quorum", check_quorum(&app, &key_info)),
+            Err(_) => {
+                report.skip("quorum", "No key info stored on this node");
+                true
+            }
+        };
+
+        Ok(report)
+    }
+}
+
+impl PreflightSigningCommand {
+    /// Decrypts the signing key and checks the HMAC and access key with it, like a signing session
+    fn authorize(&self, e2e_private_key: &str) -> Result<()> {
+        let node_signing_key = client_e2e_decrypt_secret(
+            &self.encrypted_signing_key,
+            e2e_private_key,
+            &self.client_e2e_public_key
+        )?;
+        if !verify_hmac(&self.message_hmac, &self.timestamp, &self.email, &node_signing_key) {
+            bail!("HMAC does not match the timestamp and email");
+        }
+        check_access_key(&self.key_id, &self.email, &node_signing_key)
+    }
+
+    /// Checks the request against the key's signing policy without counting it as a signature
+    fn check_policy(&self) -> Result<()> {
+        let evm_transaction = if self.is_ethereum_tx.unwrap_or(false) {
+            if self.hash_mode != HashMode::Keccak256 {
+                bail!("Ethereum transactions have to be signed with the keccak256 hash mode");
+            }
+            let transaction = tx_inspector
+                ::inspect(&self.message)
+                .map_err(|err| anyhow!("Failed to decode the Ethereum transaction: {}", err))?;
+            Some(transaction)
+        } else {
+            None
+        };
+        let request = SigningRequest {
+            messages: vec![&self.message],
+            is_transfer: self.is_transfer_tx.unwrap_or(false),
+            evm_transaction: evm_transaction.as_ref(),
+        };
+        check_signing_policy(&self.key_id, &self.email, &request)
+    }
+
+    fn check_keyshare(&self) -> Result<()> {
+        let found = match self.kind {
+            Key::ECDSA =>
+                KeyshareAccessor::<ECDSA>::read_only_with_email(&self.key_id, &self.email).is_ok(),
+            Key::EDDSA =>
+                KeyshareAccessor::<EDDSA>::read_only_with_email(&self.key_id, &self.email).is_ok(),
+            Key::Sr25519 =>
+                KeyshareAccessor::<Sr25519>::read_only_with_email(&self.key_id, &self.email).is_ok(),
+            Key::Frost =>
+                KeyshareAccessor::<Frost>::read_only_with_email(&self.key_id, &self.email).is_ok(),
+            Key::BLS =>
+                KeyshareAccessor::<BLS>::read_only_with_email(&self.key_id, &self.email).is_ok(),
+        };
+        if !found {
+            bail!("No {:?} keyshare found for key {}", self.kind, self.key_id);
+        }
+        Ok(())
+    }
+}
+
+fn check_message(kind: &Key, message: &[u8], hash_mode: &HashMode) -> Result<()> {
+    if message.is_empty() {
+        bail!("Message to sign is empty");
+    }
+    let hashed = hash_mode.apply(message);
+    if matches!(kind, Key::ECDSA) && hashed.len() > MAX_ECDSA_MESSAGE_LEN {
+        bail!("ECDSA messages must be hashed to at most {} bytes", MAX_ECDSA_MESSAGE_LEN);
+    }
+    if matches!(kind, Key::Frost) && message.len() != FROST_MESSAGE_LEN {
+        bail!("FROST messages must be {} byte sighashes", FROST_MESSAGE_LEN);
+    }
+    Ok(())
+}
+
+/// The stored threshold is one below the number of signers, see `keygen::ecdsa::client`. Key info
+/// written before the threshold was recorded fails the check instead of being taken for 2-of-3.
+fn required_signers(key_info: &KeyInfo) -> Result<usize> {
+    key_info.threshold
+        .map(|threshold| threshold + 1)
+        .ok_or_else(|| anyhow!("The key info records no threshold"))
+}
+
+/// Enough guardians of the key answer a ping now to sign
+fn check_quorum(app: &App, key_info: &KeyInfo) -> Result<()> {
+    let node_ids = key_info.node_pool
+        .iter()
+        .map(|node| node.node_id.clone())
+        .collect::<Vec<_>>();
+    let unreachable = liveness::unreachable(&app.nc, &app.node.node_id.to_string(), &node_ids);
+    let reachable = node_ids.len() - unreachable.len();
+    let required = required_signers(key_info)?;
+    if reachable < required {
+        bail!(
+            "{} of {} guardians answered, at least {} are needed to sign (no answer from {})",
+            reachable,
+            node_ids.len(),
+            required,
+            unreachable.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Readiness of a `SigningCommand` sent with `dry_run`, checked by the orchestrating node. The
+/// other guardians check the owner's authorization themselves when the session starts, which a
+/// `PreflightSigningCommand` sent to each of them covers.
+pub fn dry_run(app: &App, cmd: &SigningCommand) -> PreflightReport {
+    let mut report = PreflightReport::new();
+    report.record("message", check_message(&cmd.kind, &cmd.msg, &cmd.hash_mode));
+    report.record(
+        "quarantine",
+        reputation::check_parties(&cmd.party_nodes, cmd.allow_quarantined_peers)
+    );
+    report.record(
+        "key_info",
+        KeyInfoStore::get_key_info(&cmd.key_id).and_then(|key_info| {
+            check_signers(&key_info, &cmd.party_nodes)
+        })
+    );
+    let own_node_id = app.node.node_id.to_string();
+    report.record("peers", liveness::probe(&app.nc, &own_node_id, &cmd.party_nodes));
+
+    if !cmd.party_nodes.iter().any(|node_id| node_id.to_string() == own_node_id) {
+        report.skip("key_existence", "This node is not a party of the session");
+        report.skip("policy", "This node is not a party of the session");
+        return report;
+    }
+    let listing = list_keys(None).and_then(|keys| {
+        match keys.into_iter().find(|listing| listing.key_id == cmd.key_id) {
+            Some(listing) => Ok(listing),
+            None => bail!("No keyshare of key {} on this node", cmd.key_id),
+        }
+    });
+    match listing {
+        Ok(listing) => {
+            report.record("key_existence", Ok(()));
+            let request = SigningRequest {
+                messages: vec![&cmd.msg],
+                is_transfer: false,
+                evm_transaction: None,
+            };
+            let email = listing.email.unwrap_or_default();
+            report.record("policy", check_signing_policy(&cmd.key_id, &email, &request));
+        }
+        Err(err) => {
+            report.record("key_existence", Err(err));
+            report.skip("policy", "Requires a keyshare on this node");
+        }
+    }
+    report
+}
+
+/// The parties are guardians of the key and enough of them to sign
+fn check_signers(key_info: &KeyInfo, party_nodes: &[NodeId]) -> Result<()> {
+    let outsiders = party_nodes
+        .iter()
+        .map(|node_id| node_id.to_string())
+        .filter(|node_id| {
+            !key_info.node_pool.iter().any(|node| node.node_id.to_string() == *node_id)
+        })
+        .collect::<Vec<_>>();
+    if !outsiders.is_empty() {
+        bail!("Nodes {} are not guardians of the key", outsiders.join(", "));
+    }
+    let required = required_signers(key_info)?;
+    if party_nodes.len() < required {
+        bail!("{} parties can't sign, the key needs {}", party_nodes.len(), required);
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+//! ECDSA signing split into an offline presigning phase and a single online round. Presignatures
+//! are generated ahead of time for a fixed set of signers and are consumed by the first signature
+//! that uses them.
+//!
+//! This is GG20 with precomputed nonces, not CGGMP21: the offline phase runs the GG20 MtA of
+//! `ecdsa::session`, with the same checks. Every `k_i` ciphertext carries range proofs under the
+//! ring-Pedersen parameters of each signer, and co-signer Paillier keys are checked for their size
+//! and small factors before anything is encrypted to them. Their square-free proofs and the h1, h2, N tilde proofs are
+//! verified when the keys are stored at keygen. The CGGMP21 Paillier-Blum and no-small-factor
+//! proofs are not part of it.
+//!
+//! Moving ECDSA signing to CGGMP21 is unfinished: its presigning rounds and proofs are still to be
+//! implemented. The `KeySignCGGMP` topic of the online round keeps its name for nodes already
+//! deployed.
+
+pub mod online;
+pub mod orchestrate;
+pub mod presign;
+pub mod session;
+
+use crate::command::{ JsonCommand, MsgContext };
+use crate::session_manager;
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::tenant::{ Access, TenantAuth };
+use anyhow::{ bail, Result };
+use curv::elliptic::curves::{ Point, Scalar, Secp256k1 };
+use serde::{ Deserialize, Serialize };
+use shared::key_info::NodeId;
+
+/// One party's share of a presignature. `R` is the public nonce point, `k_i` the party's additive
+/// share of its inverse discrete log and `chi_i` the party's additive share of `k * x`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Presignature {
+    pub presignature_id: String,
+    pub key_id: String,
+    /// Keyshare party indices of the signers, sorted. The shares are only valid for this set.
+    pub signers: Vec<usize>,
+    pub party_index: usize,
+    pub R: Point<Secp256k1>,
+    pub k_i: Scalar<Secp256k1>,
+    pub chi_i: Scalar<Secp256k1>,
+}
+
+impl Presignature {
+    fn metadata_type(presignature_id: &str) -> String {
+        format!("presignature-{}", presignature_id)
+    }
+
+    pub fn save(&self, email: &str) -> Result<()> {
+        KeyMetadataStore::save(
+            &serde_json::to_string(self)?,
+            &self.key_id,
+            &Self::metadata_type(&self.presignature_id),
+            email,
+            &WriteOpts::CreateNewOnly
+        )
+    }
+
+    /// Loads the presignature and deletes it before returning, so it can never be used twice
+    pub fn take(key_id: &str, presignature_id: &str, email: &str) -> Result<Self> {
+        let metadata_type = Self::metadata_type(presignature_id);
+        let _lock = session_manager::lock_key(key_id)?;
+        let presignature = serde_json::from_str::<Self>(
+            &KeyMetadataStore::get(key_id, &metadata_type, email)?
+        )?;
+        KeyMetadataStore::remove(key_id, &metadata_type, email)?;
+
+        if presignature.key_id != key_id || presignature.presignature_id != presignature_id {
+            bail!("Stored presignature does not match key {} and id {}", key_id, presignature_id);
+        }
+        Ok(presignature)
+    }
+}
+
+/// Generates one presignature for `key_id` with the given nodes as signers
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PresignCommand {
+    pub key_id: String,
+    pub session_id: String,
+    pub party_nodes: Vec<NodeId>,
+    /// Account the key belongs to
+    pub email: String,
+    /// The owner's proof for each of `party_nodes`, in the same order
+    pub authorizations: Vec<TenantAuth>,
+}
+
+impl JsonCommand for PresignCommand {
+    type Response = PresignResponse;
+
+    /// Every party checks the owner's proof for it when the session starts
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        orchestrate::orchestrate_presign(self, ctx)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PresignResponse {
+    /// Pass as `presignature_id` of a `SigningCommand` with the same `party_nodes`
+    pub presignature_id: String,
+    /// Compressed public nonce point, hex
+    pub R: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NewPresignSession {
+    pub key_id: String,
+    pub session_id: String,
+    /// The owner's proof for this node, presignatures are spent by signing requests of the key
+    pub authorization: TenantAuth,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PresignResult {
+    pub presignature_id: String,
+    pub R: String,
+}
@@ -0,0 +1,142 @@
+use crate::communication::nats::PeerMessenger;
+use crate::communication::protocol::{ AllRounds, KeySignCGGMPAllRounds };
+use crate::signing::presigning::Presignature;
+use crate::signing::ecdsa::session::check_sig;
+use crate::signing::ecdsa::SigningResult;
+use anyhow::{ anyhow, bail, Result };
+use curv::arithmetic::Converter;
+use curv::elliptic::curves::{ Point, Scalar, Secp256k1 };
+use curv::BigInt;
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::SignatureRecid;
+use tracing::info;
+
+pub struct OnlineSignClient<C> {
+    pub peer_messenger: C,
+    pub all_party_indices: Vec<usize>,
+}
+
+impl<C> OnlineSignClient<C> where C: PeerMessenger<KeySignCGGMPAllRounds> {
+    pub async fn create_signature(
+        &self,
+        presignature: &Presignature,
+        message: &[u8],
+        public_key: &Point<Secp256k1>
+    ) -> Result<SignatureRecid> {
+        if presignature.signers != self.all_party_indices {
+            bail!(
+                "Presignature was generated for signers {:?}, but {:?} joined",
+                presignature.signers,
+                self.all_party_indices
+            );
+        }
+
+        let shares = self.peer_messenger.broadcast_and_collect_messages(
+            &<KeySignCGGMPAllRounds as AllRounds>::BroadcastRound::LocalSig,
+            signature_share(presignature, message)?
+        ).await?;
+        info!("Collected signature shares");
+
+        combine(&presignature.R, &shares, message, public_key)
+    }
+
+    pub async fn publish_result(&self, result: SigningResult) -> Result<()> {
+        let _ = self.peer_messenger.broadcast_and_collect_messages(
+            &<KeySignCGGMPAllRounds as AllRounds>::BroadcastRound::Result,
+            result
+        ).await?;
+        Ok(())
+    }
+}
+
+/// `sigma_i = k_i * m + r * chi_i`, the only value a party reveals in the online round
+pub fn signature_share(presignature: &Presignature, message: &[u8]) -> Result<Scalar<Secp256k1>> {
+    if message.len() > 32 {
+        bail!("Message has {} bytes, ECDSA signs at most 32", message.len());
+    }
+    let m = Scalar::<Secp256k1>::from_bigint(&BigInt::from_bytes(message));
+    let r = x_coordinate_scalar(&presignature.R)?;
+    Ok(&presignature.k_i * &m + &r * &presignature.chi_i)
+}
+
+/// Sums the signature shares into a low-s signature and verifies it against the public key
+#[allow(non_snake_case)]
+pub fn combine(
+    R: &Point<Secp256k1>,
+    shares: &[Scalar<Secp256k1>],
+    message: &[u8],
+    public_key: &Point<Secp256k1>
+) -> Result<SignatureRecid> {
+    let r = x_coordinate_scalar(R)?;
+    let mut s = shares.iter().fold(Scalar::zero(), |sum, share| sum + share);
+
+    let x = R.x_coord().ok_or_else(|| anyhow!("Nonce point is the identity"))?;
+    let mut recid = R.to_bytes(true)[0] - 2;
+    if &x >= Scalar::<Secp256k1>::group_order() {
+        recid |= 2;
+    }
+    let negated = Scalar::zero() - &s;
+    if s.to_bigint() > negated.to_bigint() {
+        s = negated;
+        recid ^= 1;
+    }
+
+    check_sig(&r, &s, &BigInt::from_bytes(message), public_key)?;
+    Ok(SignatureRecid { r, s, recid })
+}
+
+fn x_coordinate_scalar(point: &Point<Secp256k1>) -> Result<Scalar<Secp256k1>> {
+    match point.x_coord() {
+        Some(x) => Ok(Scalar::from_bigint(&x)),
+        None => bail!("Nonce point is the identity"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits `value` into `n` random additive shares
+    fn additive_shares(value: &Scalar<Secp256k1>, n: usize) -> Vec<Scalar<Secp256k1>> {
+        let mut shares = (1..n).map(|_| Scalar::random()).collect::<Vec<_>>();
+        let sum = shares.iter().fold(Scalar::zero(), |sum, share| sum + share);
+        shares.push(value - &sum);
+        shares
+    }
+
+    #[test]
+    fn presignature_shares_combine_to_valid_signature() {
+        let x = Scalar::<Secp256k1>::random();
+        let public_key = Point::generator() * &x;
+        let k = Scalar::<Secp256k1>::random();
+        let R = Point::generator() * k.invert().unwrap();
+
+        let k_shares = additive_shares(&k, 3);
+        let chi_shares = additive_shares(&(&k * &x), 3);
+        let message = [0x5au8; 32];
+
+        let shares = k_shares
+            .into_iter()
+            .zip(chi_shares)
+            .enumerate()
+            .map(|(i, (k_i, chi_i))| {
+                let presignature = Presignature {
+                    presignature_id: "presign".to_string(),
+                    key_id: "key".to_string(),
+                    signers: vec![1, 2, 3],
+                    party_index: i + 1,
+                    R: R.clone(),
+                    k_i,
+                    chi_i,
+                };
+                signature_share(&presignature, &message).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let signature = combine(&R, &shares, &message, &public_key).unwrap();
+        assert!(signature.recid < 4);
+
+        let other_key = Point::generator() * Scalar::<Secp256k1>::random();
+        assert!(combine(&R, &shares, &message, &other_key).is_err());
+        assert!(combine(&R, &shares[1..], &message, &public_key).is_err());
+    }
+}
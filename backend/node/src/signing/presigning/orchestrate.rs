@@ -0,0 +1,141 @@
+use crate::command::MsgContext;
+use crate::communication::blocking;
+use crate::communication::envelope;
+use crate::communication::nats::{ BroadcastMessage, JoinMessage, JoinResponse };
+use crate::signing::ecdsa::{ NewSignSession, SigningResult };
+use crate::signing::presigning::{
+    NewPresignSession,
+    PresignCommand,
+    PresignResponse,
+    PresignResult,
+};
+use crate::signing::{ SigningCommand, SigningResponse };
+use anyhow::{ bail, Context, Result };
+use tracing::{ info, instrument };
+
+#[instrument(skip_all)]
+pub fn orchestrate_presign(cmd: PresignCommand, ctx: MsgContext) -> Result<PresignResponse> {
+    let app = ctx.get_app()?;
+    let nc = app.nc;
+    let session_id = cmd.session_id;
+
+    let party_count = cmd.party_nodes.len();
+    if party_count < 3 {
+        bail!("Not enough nodes in party");
+    }
+    if cmd.authorizations.len() != party_count {
+        bail!("Expected an authorization for each of the {} parties", party_count);
+    }
+    if cmd.authorizations.iter().any(|authorization| authorization.email != cmd.email) {
+        bail!("Every authorization has to be for account {}", cmd.email);
+    }
+
+    let join_sub = nc.subscribe(
+        &format!("network.gridlock.nodes.PresignECDSA.{}.Join", &session_id)
+    )?;
+    let result_sub = nc.subscribe(
+        &format!("network.gridlock.nodes.PresignECDSA.{}.Result", &session_id)
+    )?;
+
+    for (node_id, authorization) in cmd.party_nodes.iter().zip(cmd.authorizations) {
+        let new_session_data = serde_json::to_string(
+            &(NewPresignSession {
+                key_id: cmd.key_id.clone(),
+                session_id: session_id.clone(),
+                authorization,
+            })
+        )?;
+        nc.publish(
+            &format!("network.gridlock.nodes.PresignECDSA.new.{node_id}"),
+            &new_session_data
+        )?;
+    }
+
+    respond_to_joins(&nc, &join_sub, party_count)?;
+    info!("Parties joined to presigning");
+
+    let mut results: Vec<PresignResult> = Vec::new();
+    for _ in 0..party_count {
+        let res = result_sub.next().context("Waiting for presignature results")?;
+        results.push(envelope::decode::<BroadcastMessage<_>>(&res.data)?.message);
+    }
+    if results.iter().any(|r| r.R != results[0].R) {
+        bail!("Parties derived different presignature nonce points");
+    }
+
+    let result = results.swap_remove(0);
+    Ok(PresignResponse {
+        presignature_id: result.presignature_id,
+        R: result.R,
+    })
+}
+
+#[instrument(skip_all)]
+pub fn orchestrate_online(
+    cmd: SigningCommand,
+    presignature_id: String,
+    ctx: MsgContext
+) -> Result<SigningResponse> {
+    let app = ctx.get_app()?;
+    let nc = app.nc;
+    let session_id = cmd.session_id.clone();
+
+    let party_count = cmd.party_nodes.len();
+    if party_count < 3 {
+        bail!("Not enough nodes in party");
+    }
+
+    let join_sub = nc.subscribe(
+        &format!("network.gridlock.nodes.KeySignCGGMP.{}.Join", &session_id)
+    )?;
+    let result_sub = nc.subscribe(
+        &format!("network.gridlock.nodes.KeySignCGGMP.{}.Result", &session_id)
+    )?;
+
+    let new_sign_session_msg = serde_json::to_string(
+        &(NewSignSession {
+            session_id: session_id.clone(),
+            key_id: cmd.key_id,
+            message: cmd.msg,
+            presignature_id: Some(presignature_id),
+            hash_mode: cmd.hash_mode,
+            transaction_summary: None,
+            derivation_path: None,
+        })
+    )?;
+    for node_id in cmd.party_nodes.iter() {
+        nc.publish(
+            &format!("network.gridlock.nodes.keySign.new.{node_id}"),
+            &new_sign_session_msg
+        )?;
+    }
+
+    respond_to_joins(&nc, &join_sub, party_count)?;
+    info!("Parties joined to presigned ecdsa signing");
+
+    let res = result_sub.next().context("Waiting for signature result")?;
+    let sig = envelope::decode::<BroadcastMessage<SigningResult>>(&res.data)?.message;
+    Ok(SigningResponse::ECDSA(sig))
+}
+
+fn respond_to_joins(
+    nc: &blocking::Connection,
+    join_sub: &blocking::Subscription,
+    party_count: usize
+) -> Result<()> {
+    let mut join_msg_vec = Vec::new();
+    for _ in 0..party_count {
+        join_msg_vec.push(join_sub.next().context("Waiting for parties to join")?);
+    }
+
+    let mut joins = Vec::new();
+    for m in join_msg_vec.iter() {
+        joins.push(serde_json::from_slice::<JoinMessage>(&m.data)?);
+    }
+    let join_resp = serde_json::to_string(&JoinResponse::new(&joins))?;
+    for m in join_msg_vec {
+        m.respond(&join_resp).context("Respond to join message for every party")?;
+    }
+    nc.flush()?;
+    Ok(())
+}
@@ -0,0 +1,244 @@
+use crate::communication::nats::PeerMessenger;
+use crate::communication::protocol::{ AllRounds, PresignECDSAAllRounds };
+use crate::signing::presigning::{ Presignature, PresignResult };
+use crate::signing::ecdsa::{ check_signer_paillier_keys, signer_dlog_statements };
+use crate::storage::ECDSA;
+use anyhow::{ anyhow, bail, Result };
+use curv::elliptic::curves::{ Point, Scalar, Secp256k1 };
+use itertools::Itertools;
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::{ Keys, SignKeys };
+use multi_party_ecdsa::utilities::mta::{ MessageA, MessageB };
+use serde::{ Deserialize, Serialize };
+use tracing::{ error, info };
+
+/// Paillier encryption of the party's nonce share `k_i` and the public point of its `gamma_i`
+#[derive(Clone, Serialize, Deserialize)]
+struct NonceCommitment {
+    k_ciphertext: MessageA,
+    g_gamma_i: Point<Secp256k1>,
+}
+
+/// MtA responses to another party's `k_ciphertext`, for `gamma_i` and the weighted key share
+#[derive(Clone, Serialize, Deserialize)]
+struct MtAResponse {
+    gamma: MessageB,
+    w: MessageB,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct DeltaShare {
+    delta_i: Scalar<Secp256k1>,
+    /// `Gamma * k_i`, lets every party check the combined delta before using it
+    big_delta_i: Point<Secp256k1>,
+}
+
+pub struct PresignClient<C> {
+    pub peer_messenger: C,
+    pub all_party_indices: Vec<usize>,
+}
+
+impl<C> PresignClient<C> where C: PeerMessenger<PresignECDSAAllRounds> {
+    /// Runs the offline phase: Paillier MtA for `k * gamma` and `k * x`, then reveals the combined
+    /// `delta = k * gamma` to compute `R = Gamma * delta^-1`
+    pub async fn create_presignature(
+        &self,
+        presignature_id: &str,
+        key_id: &str,
+        keyshare: &ECDSA
+    ) -> Result<Presignature> {
+        let own_position = self.all_party_indices
+            .iter()
+            .position(|&i| i == keyshare.party_index)
+            .ok_or_else(|| anyhow!("Party {} is not part of the session", keyshare.party_index))?;
+        // keyshare party indices start at 1, the GG20 helpers expect them to start at 0
+        let signers = self.all_party_indices
+            .iter()
+            .map(|i| i - 1)
+            .collect::<Vec<_>>();
+        let own_signer = keyshare.party_index - 1;
+        check_signer_paillier_keys(keyshare, &signers)?;
+        let dlog_statements = signer_dlog_statements(keyshare, &signers);
+
+        let sign_keys = SignKeys::create(
+            &keyshare.x_i,
+            &keyshare.vss_scheme_vec[own_signer],
+            own_signer,
+            &signers
+        );
+        // Range proofs of k_i under the ring-Pedersen parameters of every signer, so each of them
+        // can check that k_i is small before multiplying it with its secrets
+        let (k_ciphertext, _) = MessageA::a(
+            &sign_keys.k_i,
+            &keyshare.paillier_key_vec[own_signer],
+            &dlog_statements
+        );
+
+        let commitments: Vec<NonceCommitment> = self.peer_messenger.broadcast_and_collect_messages(
+            &<PresignECDSAAllRounds as AllRounds>::BroadcastRound::Commit,
+            NonceCommitment {
+                k_ciphertext,
+                g_gamma_i: sign_keys.g_gamma_i.clone(),
+            }
+        ).await?;
+        info!("Exchanged nonce commitments");
+
+        let mut outgoing = Vec::new();
+        let mut beta_vec = Vec::new();
+        let mut nu_vec = Vec::new();
+        for (position, &signer) in signers.iter().enumerate() {
+            if position == own_position {
+                continue;
+            }
+            let (gamma, beta, _, _) = MessageB::b(
+                &sign_keys.gamma_i,
+                &keyshare.paillier_key_vec[signer],
+                commitments[position].k_ciphertext.clone(),
+                &dlog_statements
+            ).map_err(|_| anyhow!("MtA range proofs of party {} failed for gamma", signer + 1))?;
+            let (w, nu, _, _) = MessageB::b(
+                &sign_keys.w_i,
+                &keyshare.paillier_key_vec[signer],
+                commitments[position].k_ciphertext.clone(),
+                &dlog_statements
+            ).map_err(|_| anyhow!("MtA range proofs of party {} failed for w", signer + 1))?;
+            outgoing.push(MtAResponse { gamma, w });
+            beta_vec.push(beta);
+            nu_vec.push(nu);
+        }
+
+        let responses = self.peer_messenger.send_p2p_and_collect_messages(
+            &<PresignECDSAAllRounds as AllRounds>::P2PRound::MtA,
+            outgoing
+        ).await?;
+
+        let xi_com_vec = Keys::get_commitments_to_xi(
+            &keyshare.vss_scheme_vec.iter().cloned().map_into().collect::<Vec<_>>()
+        );
+        let mut alpha_vec = Vec::new();
+        let mut mu_vec = Vec::new();
+        let other_positions = (0..signers.len()).filter(|&p| p != own_position);
+        for (position, response) in other_positions.zip(responses) {
+            let signer = signers[position];
+            if response.gamma.b_proof.pk != commitments[position].g_gamma_i {
+                bail!("MtA response of party {} does not match its gamma commitment", signer + 1);
+            }
+            let g_w_j = Keys::update_commitments_to_xi(
+                &xi_com_vec[signer],
+                &keyshare.vss_scheme_vec[signer],
+                signer,
+                &signers
+            );
+            if response.w.b_proof.pk != g_w_j {
+                bail!("MtA response of party {} does not match its key share", signer + 1);
+            }
+
+            let (alpha, _) = response.gamma
+                .verify_proofs_get_alpha(&keyshare.paillier_dk, &sign_keys.k_i)
+                .map_err(|err| anyhow!("Invalid gamma MtA from party {}: {:?}", signer + 1, err))?;
+            let (mu, _) = response.w
+                .verify_proofs_get_alpha(&keyshare.paillier_dk, &sign_keys.k_i)
+                .map_err(|err| anyhow!("Invalid w MtA from party {}: {:?}", signer + 1, err))?;
+            alpha_vec.push(alpha);
+            mu_vec.push(mu);
+        }
+        info!("Completed MtA with all parties");
+
+        let delta_i = sign_keys.phase2_delta_i(&alpha_vec, &beta_vec);
+        let chi_i = sign_keys.phase2_sigma_i(&mu_vec, &nu_vec);
+        let big_gamma = commitments
+            .iter()
+            .fold(Point::zero(), |sum, c| sum + &c.g_gamma_i);
+
+        let delta_shares: Vec<DeltaShare> = self.peer_messenger.broadcast_and_collect_messages(
+            &<PresignECDSAAllRounds as AllRounds>::BroadcastRound::Delta,
+            DeltaShare {
+                delta_i,
+                big_delta_i: &big_gamma * &sign_keys.k_i,
+            }
+        ).await?;
+        let delta = delta_shares
+            .iter()
+            .fold(Scalar::zero(), |sum, d| sum + &d.delta_i);
+        let big_delta = delta_shares
+            .iter()
+            .fold(Point::zero(), |sum, d| sum + &d.big_delta_i);
+        if Point::generator() * &delta != big_delta {
+            error!("Combined delta does not match the parties' nonce shares");
+            bail!("Presignature consistency check failed");
+        }
+
+        let delta_inv = delta.invert().ok_or_else(|| anyhow!("Combined delta is zero"))?;
+        let R = big_gamma * delta_inv;
+        if R.is_zero() {
+            bail!("Presignature nonce point is the identity");
+        }
+
+        Ok(Presignature {
+            presignature_id: presignature_id.to_string(),
+            key_id: key_id.to_string(),
+            signers: self.all_party_indices.clone(),
+            party_index: keyshare.party_index,
+            R,
+            k_i: sign_keys.k_i,
+            chi_i,
+        })
+    }
+
+    pub async fn publish_result(&self, result: PresignResult) -> Result<()> {
+        let results: Vec<PresignResult> = self.peer_messenger.broadcast_and_collect_messages(
+            &<PresignECDSAAllRounds as AllRounds>::BroadcastRound::Result,
+            result.clone()
+        ).await?;
+        if results.iter().any(|r| r.R != result.R) {
+            bail!("Parties derived different presignature nonce points");
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::loopback::LoopbackMessenger;
+    use crate::session_manager;
+    use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+    use curv::BigInt;
+    use paillier::{ EncryptionKey, KeyGeneration, Paillier };
+    use zk_paillier::zkproofs::DLogStatement;
+
+    #[test]
+    fn presigning_refuses_a_co_signer_with_a_crafted_paillier_key() {
+        let (ek, dk) = Paillier::keypair().keys();
+        // Long enough, but with 3 as a factor
+        let crafted_n = &ek.n * BigInt::from(3);
+        let crafted = EncryptionKey { nn: &crafted_n * &crafted_n, n: crafted_n };
+        let statement = DLogStatement { N: ek.n.clone(), g: BigInt::from(4), ni: BigInt::from(9) };
+        let (vss, _) = VerifiableSS::<Secp256k1>::share(1, 3, &Scalar::random());
+        let keyshare = ECDSA {
+            threshold: 1,
+            y_sum: vss.commitments[0].clone(),
+            x_i: Scalar::random(),
+            party_index: 1,
+            public_key_vec: Vec::new(),
+            vss_scheme_vec: vec![vss; 3],
+            paillier_key_vec: vec![ek.clone(), crafted, ek],
+            h1_h2_N_tilde_vec: vec![statement; 3],
+            paillier_dk: dk.into(),
+        };
+
+        let mut messengers = LoopbackMessenger::<PresignECDSAAllRounds>
+            ::network(&[1, 2, 3])
+            .unwrap();
+        let client = PresignClient {
+            peer_messenger: messengers.remove(0),
+            all_party_indices: vec![1, 2, 3],
+        };
+        let err = session_manager
+            ::runtime()
+            .block_on(client.create_presignature("presign", "key", &keyshare))
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("Paillier key of party 2 is refused"));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::approval::ApprovalTicket;
+use crate::audit::{ AuditAction, AuditedRequest };
+use crate::communication::incoming::IncomingMessage;
+use crate::communication::nats::{
+    BaseMessenger,
+    NatsBaseMessenger,
+    NatsBaseSession,
+    NatsPeerMessenger,
+};
+use crate::communication::protocol::{ KeySignCGGMPAllRounds, PresignECDSAAllRounds, Topic };
+use crate::node::NodeIdentity;
+use crate::observer::with_consented_observers;
+use crate::signing::presigning::online::OnlineSignClient;
+use crate::signing::presigning::presign::PresignClient;
+use crate::signing::presigning::{ NewPresignSession, Presignature, PresignResult };
+use crate::signing::ecdsa::session::signature_recid_to_signing_result;
+use crate::signing::ecdsa::NewSignSession;
+use crate::storage::{ KeyshareAccessor, ECDSA };
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::App;
+use crate::metrics::SessionKind;
+use crate::session_error::{ self, SessionErrorCode };
+use crate::session_manager;
+use anyhow::Result;
+use std::time::{ Duration, Instant };
+use crate::slo;
+use tracing::{ error, info, instrument };
+
+pub fn handle_new_session_message(app: &App, message: IncomingMessage) {
+    let session = match serde_json::from_slice::<NewPresignSession>(&message.data[..]) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Failed to parse message: {}", err)
+            );
+            return;
+        }
+    };
+    // The same proof as for a signing request: HMAC, access key and a newer timestamp
+    let authorized = NodeIdentity::cached().and_then(|node| {
+        session.authorization.verify(&[session.key_id.clone()], &node)
+    });
+    if let Err(err) = authorized {
+        session_error::refuse(
+            app,
+            &message,
+            SessionErrorCode::AuthenticationFailed,
+            format!("Presigning is not authorized: {}", err)
+        );
+        return;
+    }
+
+    info!("Spawning a task to handle ECDSA presignature generation");
+    let nc = app.nc.client().clone();
+    let session_id = session.session_id.clone();
+    session_manager::spawn_session(SessionKind::Signing, &session_id, presign_session(nc, session));
+}
+
+#[instrument(skip_all)]
+async fn presign_session(conn: async_nats::Client, session: NewPresignSession) {
+    let session_id = session.session_id.clone();
+    match presign_session_inner(conn, session).await {
+        Ok(()) => info!("Presignature generated for session id: {}", session_id),
+        Err(err) => error!("Error in presigning: session id: {}, error: {}", session_id, err),
+    }
+}
+
+async fn presign_session_inner(conn: async_nats::Client, session: NewPresignSession) -> Result<()> {
+    let email = &session.authorization.email;
+    let keyshare = KeyshareAccessor::<ECDSA>::read_only_with_email(&session.key_id, email)?.key;
+
+    let node = NodeIdentity::cached()?;
+    let nats_session = NatsBaseSession {
+        session_id: session.session_id.clone(),
+        thread_index: 0,
+        node_id: node.node_id.to_string(),
+        public_key: node.networking_public_key,
+        party_index: keyshare.party_index,
+    };
+    let messenger = NatsBaseMessenger::<PresignECDSAAllRounds>::new(
+        Topic::PresignECDSA,
+        conn,
+        nats_session
+    ).await?;
+    let join_response = messenger.wait_for_confirmation(Duration::from_secs(10)).await?;
+
+    let mut all_party_indices = join_response.all_party_indices;
+    all_party_indices.sort();
+    let peer_messenger = with_consented_observers(
+        NatsPeerMessenger::from(messenger, join_response.party_count, all_party_indices.clone())?,
+        &session.key_id,
+        Some(email)
+    );
+    let presign_client = PresignClient {
+        peer_messenger,
+        all_party_indices,
+    };
+
+    let presignature = presign_client.create_presignature(
+        &session.session_id,
+        &session.key_id,
+        &keyshare
+    ).await?;
+    presignature.save(email)?;
+
+    presign_client.publish_result(PresignResult {
+        presignature_id: presignature.presignature_id.clone(),
+        R: hex::encode(&*presignature.R.to_bytes(true)),
+    }).await?;
+    presign_client.peer_messenger.publish_transcript().await
+}
+
+/// Signs with a stored presignature instead of running the interactive GG20 session
+pub fn spawn_online_sign_session(
+    app: &App,
+    session: NewSignSession,
+    presignature_id: String,
+    email: String,
+    approval: Option<ApprovalTicket>
+) {
+    info!("Spawning a task to sign with presignature {}", presignature_id);
+    let nc = app.nc.client().clone();
+    let session_id = session.session_id.clone();
+    session_manager::spawn_session(SessionKind::Signing, &session_id, async move {
+        if let Some(approval) = approval {
+            if let Err(err) = approval.wait().await {
+                error!("{}", err);
+                return;
+            }
+        }
+        online_sign_session(nc, session, presignature_id, email).await
+    });
+}
+
+#[instrument(skip_all)]
+async fn online_sign_session(
+    conn: async_nats::Client,
+    session: NewSignSession,
+    presignature_id: String,
+    email: String
+) {
+    let session_id = session.session_id.clone();
+    let key_id = session.key_id.clone();
+    let audit = AuditedRequest::new(
+        AuditAction::Signing,
+        "ecdsa",
+        &key_id,
+        &session_id,
+        Some(email.as_str())
+    ).with_details(session.transaction_summary.clone());
+    let message = session.message.clone();
+    let started = Instant::now();
+    let result = online_sign_session_inner(conn, session, &presignature_id, &email).await;
+    slo::record_signing("ecdsa", &key_id, started.elapsed(), result.is_ok());
+    audit.record(&result);
+    if result.is_ok() {
+        KeyMetadataStore::record_usage(&key_id, &email, &message);
+    }
+    match result {
+        Ok(()) => info!("Signing completed successfully for session id: {}", session_id),
+        Err(err) => error!("Error in signing: session id: {}, error: {}", session_id, err),
+    }
+}
+
+async fn online_sign_session_inner(
+    conn: async_nats::Client,
+    session: NewSignSession,
+    presignature_id: &str,
+    email: &str
+) -> Result<()> {
+    let keyshare = KeyshareAccessor::<ECDSA>::read_only_with_email(&session.key_id, email)?.key;
+    // Taken before anything else, a presignature must be gone even if this session fails. Taking
+    // it waits for the key lock, which must not block the runtime.
+    let presignature = {
+        let (key_id, presignature_id, email) = (
+            session.key_id.clone(),
+            presignature_id.to_string(),
+            email.to_string(),
+        );
+        tokio::task::spawn_blocking(move || Presignature::take(&key_id, &presignature_id, &email))
+            .await??
+    };
+
+    let node = NodeIdentity::cached()?;
+    let nats_session = NatsBaseSession {
+        session_id: session.session_id.clone(),
+        thread_index: 0,
+        node_id: node.node_id.to_string(),
+        public_key: node.networking_public_key,
+        party_index: keyshare.party_index,
+    };
+    let messenger = NatsBaseMessenger::<KeySignCGGMPAllRounds>::new(
+        Topic::KeySignCGGMP,
+        conn,
+        nats_session
+    ).await?;
+    let join_response = messenger.wait_for_confirmation(Duration::from_secs(10)).await?;
+
+    let mut all_party_indices = join_response.all_party_indices;
+    all_party_indices.sort();
+    let peer_messenger = with_consented_observers(
+        NatsPeerMessenger::from(messenger, join_response.party_count, all_party_indices.clone())?,
+        &session.key_id,
+        Some(email)
+    );
+    let sign_client = OnlineSignClient {
+        peer_messenger,
+        all_party_indices,
+    };
+
+    let message = session.hash_mode.apply(&session.message);
+    let signature = sign_client.create_signature(&presignature, &message, &keyshare.y_sum).await?;
+    sign_client.publish_result(signature_recid_to_signing_result(&signature)).await?;
+    sign_client.peer_messenger.publish_transcript().await
+}
@@ -0,0 +1,107 @@
+use crate::signing::{ Key, SigningResponse };
+use anyhow::{ anyhow, Error };
+use serde::{ Deserialize, Serialize };
+
+/// Latest response format this node can produce
+pub const LATEST_RESPONSE_VERSION: u8 = 2;
+
+/// Response format a client asks for when signing. Clients that don't ask get the first format,
+/// an untagged signature whose shape tells the key type apart. Asking for a version this node
+/// doesn't know fails the request, so clients can fall back to an older one.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum ResponseVersion {
+    /// The bare signature
+    #[default]
+    V1,
+    /// The signature as the payload of a `TaggedSigningResponse`
+    V2,
+}
+
+impl TryFrom<u8> for ResponseVersion {
+    type Error = Error;
+
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(ResponseVersion::V1),
+            2 => Ok(ResponseVersion::V2),
+            _ =>
+                Err(
+                    anyhow!(
+                        "Response version {} is not supported, the latest is {}",
+                        version,
+                        LATEST_RESPONSE_VERSION
+                    )
+                ),
+        }
+    }
+}
+
+impl From<ResponseVersion> for u8 {
+    fn from(version: ResponseVersion) -> Self {
+        match version {
+            ResponseVersion::V1 => 1,
+            ResponseVersion::V2 => 2,
+        }
+    }
+}
+
+/// Signature together with the type of the key that made it
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct TaggedSigningResponse {
+    #[serde(flatten)]
+    pub kind: Key,
+    pub payload: SigningResponse,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum VersionedSigningResponse {
+    Tagged(TaggedSigningResponse),
+    Untagged(SigningResponse),
+}
+
+impl VersionedSigningResponse {
+    pub fn new(version: ResponseVersion, kind: Key, response: SigningResponse) -> Self {
+        match version {
+            ResponseVersion::V1 => VersionedSigningResponse::Untagged(response),
+            ResponseVersion::V2 =>
+                VersionedSigningResponse::Tagged(TaggedSigningResponse {
+                    kind,
+                    payload: response,
+                }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::ecdsa::SigningResult;
+    use serde_json::json;
+
+    #[test]
+    fn tags_the_signature_when_asked_for_version_2() {
+        let signature = SigningResponse::ECDSA(SigningResult {
+            r: "01".to_string(),
+            s: "02".to_string(),
+            recid: 1,
+        });
+        let untagged = VersionedSigningResponse::new(
+            ResponseVersion::V1,
+            Key::ECDSA,
+            signature.clone()
+        );
+        let tagged = VersionedSigningResponse::new(ResponseVersion::V2, Key::ECDSA, signature);
+
+        assert_eq!(
+            serde_json::to_value(untagged).unwrap(),
+            json!({ "r": "01", "s": "02", "recid": 1 })
+        );
+        assert_eq!(
+            serde_json::to_value(tagged).unwrap(),
+            json!({ "key_type": "ECDSA", "payload": { "r": "01", "s": "02", "recid": 1 } })
+        );
+        assert!(serde_json::from_value::<ResponseVersion>(json!(3)).is_err());
+    }
+}
@@ -1,5 +1,9 @@
+pub mod orchestrate;
+pub mod threshold;
+
 use crate::command::{ JsonCommand, MsgContext };
 use crate::storage::{ KeyshareAccessor, Sr25519 };
+use crate::tenant::Access;
 use anyhow::{ bail, Context, Result };
 use schnorrkel::{ ExpansionMode, Keypair, MiniSecretKey, SecretKey };
 use serde::{ Deserialize, Serialize };
@@ -16,6 +20,11 @@ pub struct KeySignCommand {
 impl JsonCommand for KeySignCommand {
     type Response = String;
 
+    /// Every guardian checks the owner's HMAC of the request when the session starts
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
     fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
         match self.key_type.as_str() {
             "sr25519" => sign_for_sr25519(self.key_id, self.message),
@@ -45,3 +54,13 @@ fn sign_for_sr25519(key_id: String, message: Vec<u8>) -> Result<String> {
     let signature = keypair.sign_simple(CTX, &message);
     Ok(hex::encode(signature.to_bytes()))
 }
+
+/// Threshold signature of the party nodes over the message
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SignatureResult {
+    /// 64 byte schnorrkel signature, hex
+    pub signature: String,
+    /// Public key of the key the signature verifies against, hex. Named after the musig sessions
+    /// that preceded threshold signing.
+    pub musig_public_key: String,
+}
@@ -0,0 +1,82 @@
+use crate::command::MsgContext;
+use crate::communication::envelope;
+use crate::communication::nats::{ BroadcastMessage, JoinMessage, JoinResponse };
+use crate::signing::sr25519_musign::{ NewSr25519KeySignSession, ResultMsg };
+use crate::signing::{ SigningCommand, SigningResponse };
+use anyhow::{ bail, Context, Result };
+use tracing::{ error, info, instrument };
+
+/// Drives a threshold signing session over the party nodes, which need to hold more keyshares
+/// than the threshold of the key. Every party node has to join and the result is only returned
+/// once it verifies against the public key of the key.
+#[instrument(skip_all)]
+pub fn orchestrate(cmd: SigningCommand, ctx: MsgContext) -> Result<SigningResponse> {
+    let app = ctx.get_app()?;
+    let nc = app.nc;
+    let session_id = cmd.session_id.clone();
+
+    let party_nodes = cmd.party_nodes;
+    let key_id = cmd.key_id;
+
+    let party_count = party_nodes.len();
+    if party_count < 2 {
+        bail!("Not enough nodes in party");
+    }
+
+    let join_key = format!("network.gridlock.nodes.KeySignSr25519.{}.Join", &session_id);
+    let join_sub = nc.subscribe(&join_key)?;
+
+    let result_key = format!("network.gridlock.nodes.KeySignSr25519.{}.Result", &session_id);
+    let result_sub = nc.subscribe(&result_key)?;
+
+    for (i, node) in party_nodes.iter().enumerate() {
+        let sign_new_key = format!("network.gridlock.nodes.KeySignSr25519.new.{}", node);
+        let key_sign_new_data = serde_json::to_string(
+            &(NewSr25519KeySignSession {
+                key_id: key_id.to_owned(),
+                session_id: session_id.to_owned(),
+                message: cmd.msg.clone(),
+                party_index: i + 1,
+                allow_resign: cmd.allow_resign,
+            })
+        )?;
+        nc.publish(&sign_new_key, key_sign_new_data)?;
+    }
+
+    let mut join_msg_vec = Vec::new();
+    for _ in 0..party_count {
+        let next = join_sub.next().context("Waiting for parties to join")?;
+        join_msg_vec.push(next);
+    }
+
+    if join_msg_vec.len() < party_count {
+        let msg = format!("Not every party joined - party_joined_count: {}", join_msg_vec.len());
+        error!("{}", &msg);
+        bail!(msg);
+    }
+
+    let mut joins = Vec::new();
+    for m in join_msg_vec.iter() {
+        joins.push(serde_json::from_slice::<JoinMessage>(&m.data)?);
+    }
+    let join_resp = JoinResponse::new(&joins);
+    for msg in join_msg_vec {
+        msg.respond(
+            &serde_json::to_string(&join_resp).context("Respond to join message for every party")?
+        )?;
+    }
+    nc.flush()?;
+
+    info!("Parties joined to Sr25519 signing");
+
+    let mut res_vec = Vec::new();
+    for _ in 0..party_count {
+        let res = result_sub.next().context("Waiting for signature results")?;
+        res_vec.push(res);
+    }
+
+    info!("Signature result received");
+
+    let result = envelope::decode::<BroadcastMessage<ResultMsg>>(&res_vec[0].data)?.message;
+    Ok(SigningResponse::Sr25519(result.verify(&cmd.msg)?))
+}
@@ -0,0 +1,173 @@
+use crate::keygen::sr25519::client::{ lagrange_coefficient, to_ristretto_scalar };
+use crate::signing::sr25519_musign::SIGNING_CONTEXT;
+use anyhow::{ anyhow, bail, Error, Result };
+use curv::elliptic::curves::{ Ed25519, Scalar };
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::scalar::Scalar as RistrettoScalar;
+use schnorrkel::context::SigningTranscript;
+use schnorrkel::signing_context;
+use sha2::{ Digest, Sha256 };
+
+/// Nonce of one party for one signing session, committed to before any nonce is revealed so no
+/// party can choose its nonce depending on the others
+pub struct Nonce {
+    secret: RistrettoScalar,
+    pub point: RistrettoPoint,
+}
+
+impl Nonce {
+    pub fn generate() -> Self {
+        let secret = to_ristretto_scalar(&Scalar::<Ed25519>::random());
+        Nonce {
+            secret,
+            point: RISTRETTO_BASEPOINT_POINT * secret,
+        }
+    }
+
+    pub fn commitment(&self) -> String {
+        nonce_commitment(&self.point)
+    }
+}
+
+/// Hex SHA-256 of the compressed nonce point
+pub fn nonce_commitment(point: &RistrettoPoint) -> String {
+    hex::encode(Sha256::digest(point.compress().as_bytes()))
+}
+
+/// Challenge of a schnorrkel signature with the aggregated nonce, the same transcript
+/// `schnorrkel::Keypair::sign` builds, so the result verifies as a plain sr25519 signature
+pub fn challenge(
+    public_key: &RistrettoPoint,
+    nonce: &RistrettoPoint,
+    message: &[u8]
+) -> Result<RistrettoScalar> {
+    // Points go through their encoding, schnorrkel builds on its own curve25519 version
+    let public_key = schnorrkel::PublicKey
+        ::from_bytes(public_key.compress().as_bytes())
+        .map_err(Error::msg)?;
+    let nonce = schnorrkel::PublicKey
+        ::from_bytes(nonce.compress().as_bytes())
+        .map_err(Error::msg)?;
+    let mut transcript = signing_context(SIGNING_CONTEXT).bytes(message);
+    transcript.proto_name(b"Schnorr-sig");
+    transcript.commit_point(b"sign:pk", public_key.as_compressed());
+    transcript.commit_point(b"sign:R", nonce.as_compressed());
+    let challenge = transcript.challenge_scalar(b"sign:c");
+    Ok(RistrettoScalar::from_bytes_mod_order(challenge.to_bytes()))
+}
+
+/// Share of the signature of the party at `index`, weighted with its Lagrange coefficient so the
+/// shares of `signers` add up to the signature
+pub fn partial_signature(
+    nonce: Nonce,
+    x_i: &RistrettoScalar,
+    index: usize,
+    signers: &[usize],
+    challenge: &RistrettoScalar
+) -> RistrettoScalar {
+    nonce.secret + challenge * lagrange_coefficient(0, index, signers) * x_i
+}
+
+/// Checks the signature share of a party against its nonce and public share, so a party that
+/// sends a wrong share is named instead of the session ending with an invalid signature
+pub fn verify_partial_signature(
+    partial: &RistrettoScalar,
+    nonce: &RistrettoPoint,
+    public_share: &RistrettoPoint,
+    index: usize,
+    signers: &[usize],
+    challenge: &RistrettoScalar
+) -> Result<()> {
+    let weight = challenge * lagrange_coefficient(0, index, signers);
+    if RISTRETTO_BASEPOINT_POINT * partial != nonce + public_share * weight {
+        bail!("Signature share of party {} is invalid", index);
+    }
+    Ok(())
+}
+
+/// Public key the public shares of `signers` interpolate to
+pub fn interpolate_public_key(
+    signers: &[usize],
+    public_shares: &[RistrettoPoint]
+) -> RistrettoPoint {
+    signers
+        .iter()
+        .zip(public_shares)
+        .map(|(&index, share)| share * lagrange_coefficient(0, index, signers))
+        .sum()
+}
+
+/// Decodes a hex signature share, which has to be a canonical scalar
+pub fn decode_scalar(encoded: &str) -> Result<RistrettoScalar> {
+    let bytes: [u8; 32] = hex
+        ::decode(encoded)?
+        .try_into()
+        .map_err(|_| anyhow!("Signature share has the wrong length"))?;
+    RistrettoScalar::from_canonical_bytes(bytes).ok_or_else(|| {
+        anyhow!("Signature share is not a canonical scalar")
+    })
+}
+
+/// Encodes the aggregated nonce and signature like schnorrkel, with the marker bit schnorrkel
+/// uses to tell its signatures from ed25519 ones
+pub fn signature(
+    nonce: &RistrettoPoint,
+    partials: &[RistrettoScalar]
+) -> Result<schnorrkel::Signature> {
+    let s: RistrettoScalar = partials.iter().sum();
+    let mut bytes = [0u8; 64];
+    bytes[..32].copy_from_slice(nonce.compress().as_bytes());
+    bytes[32..].copy_from_slice(s.as_bytes());
+    bytes[63] |= 128;
+    schnorrkel::Signature::from_bytes(&bytes).map_err(Error::msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+
+    #[test]
+    fn shares_of_any_quorum_sign_for_the_group_key() {
+        let secret = Scalar::<Ed25519>::random();
+        let (_, shares) = VerifiableSS::<Ed25519>::share_at_indices(1, 3, &secret, &[1, 2, 3]);
+        let public_key = RISTRETTO_BASEPOINT_POINT * to_ristretto_scalar(&secret);
+        let message = b"transfer 1 DOT";
+
+        let signers = vec![1, 3];
+        let x = [to_ristretto_scalar(&shares[0]), to_ristretto_scalar(&shares[2])];
+        let public_shares: Vec<_> = x
+            .iter()
+            .map(|x_i| RISTRETTO_BASEPOINT_POINT * x_i)
+            .collect();
+        assert_eq!(interpolate_public_key(&signers, &public_shares), public_key);
+
+        let nonces = [Nonce::generate(), Nonce::generate()];
+        let points: Vec<_> = nonces
+            .iter()
+            .map(|nonce| nonce.point)
+            .collect();
+        let aggregated: RistrettoPoint = points.iter().sum();
+        let c = challenge(&public_key, &aggregated, message).unwrap();
+        let partials: Vec<_> = nonces
+            .into_iter()
+            .zip(x.iter().zip(&signers))
+            .map(|(nonce, (x_i, &index))| partial_signature(nonce, x_i, index, &signers, &c))
+            .collect();
+        let verify = |partial: &RistrettoScalar, i: usize| {
+            let (nonce, share) = (&points[i], &public_shares[i]);
+            verify_partial_signature(partial, nonce, share, signers[i], &signers, &c)
+        };
+        assert!(verify(&partials[0], 0).is_ok());
+        assert!(verify(&partials[1], 1).is_ok());
+        assert!(verify(&(partials[0] + RistrettoScalar::one()), 0).is_err());
+
+        let signature = signature(&aggregated, &partials).unwrap();
+        let schnorrkel_key = schnorrkel::PublicKey
+            ::from_bytes(public_key.compress().as_bytes())
+            .unwrap();
+        let transcript = signing_context(SIGNING_CONTEXT).bytes(message);
+        assert!(schnorrkel_key.verify(transcript, &signature).is_ok());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::audit::{ AuditAction, AuditedRequest };
+use crate::communication::incoming::IncomingMessage;
 use crate::communication::nats::{
     BaseMessenger,
     NatsBaseMessenger,
@@ -6,18 +8,58 @@ use crate::communication::nats::{
     PeerMessenger,
 };
 use crate::communication::protocol::{ AllRounds, KeySignSr25519AllRounds, Topic };
+use crate::keygen::sr25519::client::{ decode_public_share, to_ristretto_scalar };
 use crate::node::NodeIdentity;
-use crate::storage::{ KeyshareAccessor, Sr25519 };
+use crate::signing::sr25519::threshold::{
+    self,
+    decode_scalar,
+    nonce_commitment,
+    partial_signature,
+    verify_partial_signature,
+    Nonce,
+};
+use crate::signing::nonce_ledger;
+use crate::signing::sr25519::SignatureResult;
+use crate::storage::{ KeyInfoStore, KeyshareAccessor, Sr25519 };
+use crate::storage::key_metadata_store::KeyMetadataStore;
 use crate::App;
-use anyhow::{ anyhow, Context, Error, Result };
-use schnorrkel::{ signing_context, ExpansionMode, Keypair, MiniSecretKey, SecretKey };
+use crate::metrics::SessionKind;
+use crate::session_error::{ self, SessionErrorCode };
+use crate::session_manager;
+use anyhow::{ anyhow, bail, Error, Result };
+use curv::elliptic::curves::{ Ed25519, Scalar };
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::RistrettoPoint;
+use itertools::Itertools;
+use schnorrkel::signing_context;
 use serde::{ Deserialize, Serialize };
-use std::thread;
+use shared::key_info::Key;
+use crate::slo;
+use std::time::Instant;
 use tracing::{ error, info };
 
-fn sign_session(conn: nats::Connection, session: NewSr25519KeySignSession) -> Result<()> {
+/// Signing context of the sr25519 sessions, the transcript of every party has to start with it
+pub const SIGNING_CONTEXT: &[u8] = b"gridlock";
+
+async fn sign_session(conn: async_nats::Client, session: NewSr25519KeySignSession) -> Result<()> {
     let session_id = session.session_id.clone();
-    match keysign_session_inner(conn, session) {
+    let key_id = session.key_id.clone();
+    let audit = AuditedRequest::new(
+        AuditAction::Signing,
+        "sr25519",
+        &key_id,
+        &session_id,
+        None
+    );
+    let message = session.message.clone();
+    let started = Instant::now();
+    let result = keysign_session_inner(conn, session).await;
+    slo::record_signing("sr25519", &key_id, started.elapsed(), result.is_ok());
+    audit.record(&result);
+    if result.is_ok() {
+        KeyMetadataStore::record_usage(&key_id, "", &message);
+    }
+    match result {
         Ok(()) => info!("Signing completed successfully for session id: {}", session_id),
         Err(err) => error!("Error in Sr25519 signing: session id: {}, error: {}", session_id, err),
     }
@@ -25,78 +67,24 @@ fn sign_session(conn: nats::Connection, session: NewSr25519KeySignSession) -> Re
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct NewSr25519KeySignSession {
     pub key_id: String,
     pub session_id: String,
     pub message: Vec<u8>,
     pub party_index: usize,
+    /// Owner's override for signing a message again with a new nonce, see `nonce_ledger`
+    #[serde(default)]
+    pub allow_resign: bool,
 }
 
+/// Hex encoded compressed Ristretto point
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct PublicKey(String);
 
-impl From<schnorrkel::PublicKey> for PublicKey {
-    fn from(f: schnorrkel::PublicKey) -> Self {
-        PublicKey(hex::encode(f.to_bytes()))
-    }
-}
-
-impl From<PublicKey> for schnorrkel::PublicKey {
-    fn from(f: PublicKey) -> Self {
-        let r_bytes = hex::decode(f.0).expect("Hex encoded PublicKey");
-        schnorrkel::PublicKey::from_bytes(&r_bytes).expect("Failed to create public key from bytes")
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Commitment(String);
-
-impl From<schnorrkel::musig::Commitment> for Commitment {
-    fn from(f: schnorrkel::musig::Commitment) -> Self {
-        Commitment(hex::encode(f.0))
-    }
-}
-
-impl From<Commitment> for schnorrkel::musig::Commitment {
-    fn from(f: Commitment) -> Self {
-        let r_bytes = hex::decode(f.0).expect("Hex encoded Commitment");
-        schnorrkel::musig::Commitment(
-            r_bytes.try_into().expect("Wrong length of encoded Commitment")
-        )
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Reveal(String);
-
-impl From<schnorrkel::musig::Reveal> for Reveal {
-    fn from(f: schnorrkel::musig::Reveal) -> Self {
-        Reveal(hex::encode(f.0))
-    }
-}
-
-impl From<Reveal> for schnorrkel::musig::Reveal {
-    fn from(f: Reveal) -> Self {
-        let r_bytes = hex::decode(f.0).expect("Hex encoded Reveal");
-        schnorrkel::musig::Reveal(r_bytes.try_into().expect("Wrong length of encoded Reveal"))
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Cosign(String);
-
-impl From<schnorrkel::musig::Cosignature> for Cosign {
-    fn from(f: schnorrkel::musig::Cosignature) -> Self {
-        Cosign(hex::encode(f.0))
-    }
-}
-
-impl From<Cosign> for schnorrkel::musig::Cosignature {
-    fn from(f: Cosign) -> Self {
-        let r_bytes = hex::decode(f.0).expect("Hex encoded Cosignature");
-        schnorrkel::musig::Cosignature(
-            r_bytes.try_into().expect("Wrong length of encoded Cosignature")
-        )
+impl From<RistrettoPoint> for PublicKey {
+    fn from(f: RistrettoPoint) -> Self {
+        PublicKey(hex::encode(f.compress().as_bytes()))
     }
 }
 
@@ -118,22 +106,25 @@ impl From<Signature> for schnorrkel::Signature {
     }
 }
 
+/// First round: the share index and public share of the party, and the commitment to its nonce
 #[derive(Serialize, Deserialize, Clone)]
 pub struct CommitmentMsg {
+    pub share_index: usize,
+    /// Key the party signs for, every party has to sign for the same one
     pub public_key: PublicKey,
-    pub commitment: Commitment,
+    pub public_share: PublicKey,
+    pub nonce_commitment: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RevealMsg {
-    pub public_key: PublicKey,
-    pub reveal: Reveal,
+    pub nonce: PublicKey,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct CosignMsg {
-    pub public_key: PublicKey,
-    pub cosign: Cosign,
+    /// Hex encoded signature share
+    pub partial_signature: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -142,22 +133,42 @@ pub struct ResultMsg {
     pub sig: Signature,
 }
 
-fn keysign_session_inner(conn: nats::Connection, session: NewSr25519KeySignSession) -> Result<()> {
+impl ResultMsg {
+    /// Checks the signature against the public key it names, the result comes from another node so
+    /// its encoding is not trusted either
+    pub fn verify(self, message: &[u8]) -> Result<SignatureResult> {
+        let public_key = schnorrkel::PublicKey
+            ::from_bytes(&hex::decode(&self.musig_public_key.0)?)
+            .map_err(Error::msg)?;
+        let signature = schnorrkel::Signature
+            ::from_bytes(&hex::decode(&self.sig.0)?)
+            .map_err(Error::msg)?;
+        public_key
+            .verify(signing_context(SIGNING_CONTEXT).bytes(message), &signature)
+            .map_err(|err| anyhow!("Sr25519 signature does not verify: {}", err))?;
+        Ok(SignatureResult {
+            signature: self.sig.0,
+            musig_public_key: self.musig_public_key.0,
+        })
+    }
+}
+
+async fn keysign_session_inner(
+    conn: async_nats::Client,
+    session: NewSr25519KeySignSession
+) -> Result<()> {
     let key_id = session.key_id.clone();
     let session_id = session.session_id.clone();
     let message = session.message.clone();
     info!("joining Sr25519 keysign session key_id: {}", &key_id);
 
     let key = KeyshareAccessor::<Sr25519>::read_only(&key_id)?.key;
-    let keypair = retrieve_keypair(&key)?;
-    let _public_key: PublicKey = keypair.public.into();
-
-    let _threshold = key.threshold;
+    let group_key = group_public_key(&key_id, &key)?;
+    let x_i = to_ristretto_scalar(&key.x_i);
+    let public_share = RISTRETTO_BASEPOINT_POINT * x_i;
     let party_index = session.party_index;
 
-    let node = NodeIdentity::load()?;
-    let node_id = node.node_id.to_string();
-    let public_key = node.networking_public_key;
+    let node = NodeIdentity::cached()?;
     info!("Retrieved node identity");
 
     // We are not currently signing with more than one keyshare per device
@@ -165,8 +176,8 @@ fn keysign_session_inner(conn: nats::Connection, session: NewSr25519KeySignSessi
     let nats_session = NatsBaseSession {
         session_id,
         thread_index,
-        node_id,
-        public_key: public_key.clone(),
+        node_id: node.node_id.to_string(),
+        public_key: node.networking_public_key,
         party_index,
     };
 
@@ -174,9 +185,10 @@ fn keysign_session_inner(conn: nats::Connection, session: NewSr25519KeySignSessi
         Topic::KeySignSr25519,
         conn.clone(),
         nats_session
-    )?;
+    ).await?;
 
-    let join_response = sign_messenger.wait_for_confirmation(std::time::Duration::from_secs(10))?;
+    let join_response = sign_messenger
+        .wait_for_confirmation(std::time::Duration::from_secs(10)).await?;
 
     info!("Got join response");
 
@@ -190,132 +202,148 @@ fn keysign_session_inner(conn: nats::Connection, session: NewSr25519KeySignSessi
         all_party_indices.clone()
     )?;
 
-    let t = signing_context(b"gridlock").bytes(&message);
-
     // Commit stage
-    let mut commit = keypair.musig(t.clone());
+    let nonce = Nonce::generate();
     let commit_msg = CommitmentMsg {
-        public_key: PublicKey(public_key.clone()),
-        commitment: commit.our_commitment().into(),
+        share_index: key.party_index,
+        public_key: group_key.into(),
+        public_share: public_share.into(),
+        nonce_commitment: nonce.commitment(),
     };
-    let other_commit_msgs = sign_peer_messenger.broadcast_and_collect_messages(
+    let commit_msgs = sign_peer_messenger.broadcast_and_collect_messages(
         &<KeySignSr25519AllRounds as AllRounds>::BroadcastRound::Commit,
         commit_msg
-    )?;
-    info!("Other parties commits received - msg count: {}", other_commit_msgs.len());
-    for other_commit_msg in other_commit_msgs {
-        if other_commit_msg.public_key == PublicKey(public_key.clone()) {
-            continue;
-        }
-
-        commit
-            .add_their_commitment(
-                other_commit_msg.public_key.into(),
-                other_commit_msg.commitment.into()
-            )
-            .map_err(Error::msg)?;
+    ).await?;
+    info!("Parties commitments received - msg count: {}", commit_msgs.len());
+    let group_key_msg = PublicKey::from(group_key);
+    if commit_msgs.iter().any(|msg| msg.public_key != group_key_msg) {
+        bail!("Parties are signing for different public keys");
+    }
+    let signers: Vec<usize> = commit_msgs
+        .iter()
+        .map(|msg| msg.share_index)
+        .collect();
+    if signers.iter().unique().count() != signers.len() {
+        bail!("Parties sign with the same keyshare");
+    }
+    if signers.len() <= key.threshold {
+        bail!("{} parties can not sign for a key with threshold {}", signers.len(), key.threshold);
+    }
+    let public_shares = commit_msgs
+        .iter()
+        .map(|msg| decode_public_share(&msg.public_share.0))
+        .collect::<Result<Vec<_>>>()?;
+    // Binds the public shares to the key, the signature shares are checked against them
+    if threshold::interpolate_public_key(&signers, &public_shares) != group_key {
+        bail!("Public shares of the parties do not interpolate to the public key");
     }
     info!("Commit stage passed");
 
     // Reveal stage
-    let mut reveal = commit.reveal_stage();
-    let our_reveal = reveal.our_reveal().clone();
-    let reveal_msg = RevealMsg {
-        public_key: PublicKey(public_key.clone()),
-        reveal: our_reveal.into(),
-    };
-    let other_reveal_msgs = sign_peer_messenger.broadcast_and_collect_messages(
+    let reveal_msgs = sign_peer_messenger.broadcast_and_collect_messages(
         &<KeySignSr25519AllRounds as AllRounds>::BroadcastRound::Reveal,
-        reveal_msg.clone()
-    )?;
-    info!("Other parties reveals received - msg count: {}", other_reveal_msgs.len());
-    for other_reveal_msg in other_reveal_msgs {
-        if other_reveal_msg.public_key.0 == public_key {
-            continue;
-        }
-
-        reveal
-            .add_their_reveal(other_reveal_msg.public_key.into(), other_reveal_msg.reveal.into())
-            .map_err(Error::msg)?;
+        RevealMsg { nonce: nonce.point.into() }
+    ).await?;
+    info!("Parties nonces received - msg count: {}", reveal_msgs.len());
+    if reveal_msgs.len() != commit_msgs.len() {
+        bail!("Received {} nonces for {} commitments", reveal_msgs.len(), commit_msgs.len());
     }
-    let musig_public_key = reveal.public_key();
+    let nonces = reveal_msgs
+        .iter()
+        .zip(&commit_msgs)
+        .map(|(reveal, commit)| {
+            let point = decode_public_share(&reveal.nonce.0)?;
+            if nonce_commitment(&point) != commit.nonce_commitment {
+                bail!("Nonce of party {} does not match its commitment", commit.share_index);
+            }
+            Ok(point)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let aggregated_nonce: RistrettoPoint = nonces.iter().sum();
+    let challenge = threshold::challenge(&group_key, &aggregated_nonce, &message)?;
+    nonce_ledger::check_and_record(
+        &key_id,
+        "",
+        &message,
+        aggregated_nonce.compress().as_bytes(),
+        session.allow_resign
+    )?;
     info!("Reveal stage passed");
 
     // Cosign stage
-    let mut cosign = reveal.cosign_stage();
-    let cosign_msg = CosignMsg {
-        public_key: PublicKey(public_key.clone()),
-        cosign: cosign.our_cosignature().into(),
-    };
-    let other_cosign_msgs = sign_peer_messenger.broadcast_and_collect_messages(
+    let partial = partial_signature(nonce, &x_i, key.party_index, &signers, &challenge);
+    let cosign_msgs = sign_peer_messenger.broadcast_and_collect_messages(
         &<KeySignSr25519AllRounds as AllRounds>::BroadcastRound::Cosign,
-        cosign_msg
-    )?;
-    info!("Other parties cosignatures received - msg count: {}", other_cosign_msgs.len());
-    for other_cosign_msg in other_cosign_msgs {
-        if other_cosign_msg.public_key.0 == public_key {
-            continue;
-        }
-
-        debug_assert_eq!(musig_public_key, cosign.public_key());
-        cosign
-            .add_their_cosignature(
-                other_cosign_msg.public_key.into(),
-                other_cosign_msg.cosign.into()
-            )
-            .map_err(Error::msg)?;
-        debug_assert_eq!(musig_public_key, cosign.public_key());
+        CosignMsg { partial_signature: hex::encode(partial.as_bytes()) }
+    ).await?;
+    info!("Parties signature shares received - msg count: {}", cosign_msgs.len());
+    if cosign_msgs.len() != signers.len() {
+        bail!("Received {} signature shares from {} parties", cosign_msgs.len(), signers.len());
     }
+    let partials = cosign_msgs
+        .iter()
+        .enumerate()
+        .map(|(i, msg)| {
+            let partial = decode_scalar(&msg.partial_signature)?;
+            verify_partial_signature(
+                &partial,
+                &nonces[i],
+                &public_shares[i],
+                signers[i],
+                &signers,
+                &challenge
+            )?;
+            Ok(partial)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let signature = threshold::signature(&aggregated_nonce, &partials)?;
     info!("Cosign stage passed");
 
-    let _signature = cosign.sign().context(anyhow!("Unbalte to git signature from cosignature"))?;
-
     // Result stage
     let result_msg = ResultMsg {
-        musig_public_key: musig_public_key.into(),
-        sig: cosign.sign().expect("Signature generated from cosign").into(),
+        musig_public_key: group_key_msg,
+        sig: signature.into(),
     };
 
     sign_peer_messenger.broadcast_message(
         &<KeySignSr25519AllRounds as AllRounds>::BroadcastRound::Result,
         result_msg
-    )?;
+    ).await?;
 
-    info!("Cosignature result published successfully");
+    info!("Signature result published successfully");
     Ok(())
 }
 
-fn retrieve_keypair(key: &Sr25519) -> Result<Keypair, Error> {
-    let secret = key.secret_key
-        .clone()
-        .context("Can not sign. This user is not the owner of this key")?;
-    let mini_secret: MiniSecretKey = secret.into();
-    info!("Retrieved keyshare");
-
-    let public = mini_secret.expand_to_public(ExpansionMode::Ed25519);
-    let secret: SecretKey = mini_secret.expand(ExpansionMode::Ed25519);
-    Ok(Keypair { public, secret })
+/// Key the shares have to interpolate to. The owner derives it from the secret key, the other
+/// guardians take it from the key info stored at key generation.
+fn group_public_key(key_id: &str, key: &Sr25519) -> Result<RistrettoPoint> {
+    if let Some(secret) = &key.secret_key {
+        let secret: Scalar<Ed25519> = secret.clone().into();
+        return Ok(RISTRETTO_BASEPOINT_POINT * to_ristretto_scalar(&secret));
+    }
+    match KeyInfoStore::get_key_info(key_id)?.kind {
+        Key::Sr25519 { pk } => decode_public_share(&pk),
+        _ => bail!("Key {} is not an sr25519 key", key_id),
+    }
 }
 
-pub fn handle_new_session_message(app: &App, message: nats::Message) {
+pub fn handle_new_session_message(app: &App, message: IncomingMessage) {
     let session = match serde_json::from_slice::<NewSr25519KeySignSession>(&message.data[..]) {
         Ok(s) => s,
         Err(e) => {
-            error!("Incorrect key sign message format: {}", e);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Incorrect key sign message format: {}", e)
+            );
             return;
         }
     };
 
-    let nc = app.nc.clone();
+    let nc = app.nc.client().clone();
     let session_id = session.session_id.clone();
 
-    match
-        thread::Builder
-            ::new()
-            .name(format!("signing_gen_session_{}", &session_id))
-            .spawn(move || sign_session(nc, session))
-    {
-        Ok(_) => info!("Spawned a thread to handle Sr25519 signature generation"),
-        Err(_) => error!("Failed to spawn thread for keysign session {}", &session_id),
-    };
+    session_manager::spawn_session(SessionKind::Signing, &session_id, sign_session(nc, session));
+    info!("Spawned a task to handle Sr25519 signature generation");
 }
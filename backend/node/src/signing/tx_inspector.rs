@@ -0,0 +1,269 @@
+//! Decodes unsigned Ethereum transactions of ECDSA signing requests, so the signing policy can
+//! check where a transaction goes and the audit log records what was signed. Legacy, EIP-2930
+//! and EIP-1559 transactions are decoded, the payload is what Keccak-256 is applied to.
+
+use anyhow::{ bail, Context, Result };
+use serde::{ Deserialize, Serialize };
+
+/// `transfer(address,uint256)` of ERC-20 tokens
+pub const ERC20_TRANSFER_SELECTOR: &str = "0xa9059cbb";
+const ERC20_TRANSFER: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+/// Deepest list nesting of a transaction, access lists are the deepest part at 3 levels
+const MAX_RLP_DEPTH: usize = 4;
+const ADDRESS_LEN: usize = 20;
+const ABI_WORD_LEN: usize = 32;
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct EvmTransaction {
+    /// 0 for legacy transactions, 1 for EIP-2930 and 2 for EIP-1559 ones
+    pub tx_type: u8,
+    /// `None` for legacy transactions signed without EIP-155 replay protection
+    pub chain_id: Option<u64>,
+    pub nonce: u64,
+    /// Lowercase hex address, `None` for contract creations
+    pub to: Option<String>,
+    /// Wei as 0x-prefixed hex
+    pub value: String,
+    pub gas_limit: u64,
+    /// Gas price of legacy and EIP-2930 transactions, max fee per gas of EIP-1559 ones
+    pub max_fee_per_gas: String,
+    /// First four bytes of the calldata, `None` without calldata
+    pub selector: Option<String>,
+    pub calldata_len: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub erc20_transfer: Option<Erc20Transfer>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Erc20Transfer {
+    pub recipient: String,
+    /// Token units as 0x-prefixed hex
+    pub amount: String,
+}
+
+impl EvmTransaction {
+    /// One line description for the logs and the audit log
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "EVM transaction type {} on chain {} to {} of {} wei, gas limit {}",
+            self.tx_type,
+            self.chain_id.map_or("unknown".to_string(), |chain_id| chain_id.to_string()),
+            self.to.as_deref().unwrap_or("a new contract"),
+            self.value,
+            self.gas_limit
+        );
+        if let Some(selector) = &self.selector {
+            summary.push_str(&format!(", calls {} with {} bytes", selector, self.calldata_len));
+        }
+        if let Some(transfer) = &self.erc20_transfer {
+            summary.push_str(
+                &format!(", ERC-20 transfer of {} to {}", transfer.amount, transfer.recipient)
+            );
+        }
+        summary
+    }
+}
+
+/// Decodes the payload of a signing request flagged as an Ethereum transaction
+pub fn inspect(payload: &[u8]) -> Result<EvmTransaction> {
+    let (tx_type, encoded) = match payload.first() {
+        Some(&tx_type) if tx_type == 1 || tx_type == 2 => (tx_type, &payload[1..]),
+        Some(&prefix) if prefix >= 0xc0 => (0, payload),
+        _ => bail!("Payload is not an unsigned Ethereum transaction"),
+    };
+    let (item, rest) = decode_item(encoded, 0)?;
+    if !rest.is_empty() {
+        bail!("Ethereum transaction is followed by {} more bytes", rest.len());
+    }
+    let fields = match item {
+        Rlp::List(fields) => fields,
+        Rlp::Bytes(_) => bail!("Ethereum transaction is not an RLP list"),
+    };
+
+    // Fields from the nonce on, after the chain id of typed transactions
+    let (chain_id, fields) = match (tx_type, fields.len()) {
+        (0, 6) => (None, &fields[..]),
+        // EIP-155 appends the chain id and two empty fields to the signed legacy fields
+        (0, 9) => (Some(uint(&fields[6])?), &fields[..6]),
+        (1, 8) | (2, 9) => (Some(uint(&fields[0])?), &fields[1..]),
+        _ => bail!("Type {} transaction has {} fields", tx_type, fields.len()),
+    };
+    // EIP-1559 has the priority fee in front of the max fee
+    let fields = if tx_type == 2 { &fields[1..] } else { fields };
+    let to = match bytes(&fields[3])? {
+        [] => None,
+        address if address.len() == ADDRESS_LEN => Some(hex_address(address)),
+        address => bail!("Destination of {} bytes is not an address", address.len()),
+    };
+    let calldata = bytes(&fields[5])?;
+
+    Ok(EvmTransaction {
+        tx_type,
+        chain_id,
+        nonce: uint(&fields[0])?,
+        to,
+        value: quantity(bytes(&fields[4])?),
+        gas_limit: uint(&fields[2])?,
+        max_fee_per_gas: quantity(bytes(&fields[1])?),
+        selector: calldata.get(..4).map(|selector| format!("0x{}", hex::encode(selector))),
+        calldata_len: calldata.len(),
+        erc20_transfer: erc20_transfer(calldata),
+    })
+}
+
+/// Arguments of a `transfer` call, `None` for other calls or malformed arguments
+fn erc20_transfer(calldata: &[u8]) -> Option<Erc20Transfer> {
+    let arguments = calldata.strip_prefix(&ERC20_TRANSFER[..])?;
+    if arguments.len() != 2 * ABI_WORD_LEN {
+        return None;
+    }
+    let (recipient, amount) = arguments.split_at(ABI_WORD_LEN);
+    let (padding, recipient) = recipient.split_at(ABI_WORD_LEN - ADDRESS_LEN);
+    if padding.iter().any(|byte| *byte != 0) {
+        return None;
+    }
+    Some(Erc20Transfer {
+        recipient: hex_address(recipient),
+        amount: quantity(amount),
+    })
+}
+
+enum Rlp<'a> {
+    Bytes(&'a [u8]),
+    List(Vec<Rlp<'a>>),
+}
+
+fn decode_item(input: &[u8], depth: usize) -> Result<(Rlp, &[u8])> {
+    let prefix = *input.first().context("RLP item is missing")?;
+    let (item, rest) = match prefix {
+        // A single byte below 0x80 is its own encoding
+        0x00..=0x7f => {
+            return Ok((Rlp::Bytes(&input[..1]), &input[1..]));
+        }
+        0x80..=0xb7 => split(&input[1..], (prefix - 0x80) as usize)?,
+        0xb8..=0xbf => {
+            let (len, rest) = long_length(&input[1..], (prefix - 0xb7) as usize)?;
+            split(rest, len)?
+        }
+        0xc0..=0xf7 => {
+            let (payload, rest) = split(&input[1..], (prefix - 0xc0) as usize)?;
+            return Ok((Rlp::List(decode_list(payload, depth + 1)?), rest));
+        }
+        0xf8..=0xff => {
+            let (len, rest) = long_length(&input[1..], (prefix - 0xf7) as usize)?;
+            let (payload, rest) = split(rest, len)?;
+            return Ok((Rlp::List(decode_list(payload, depth + 1)?), rest));
+        }
+    };
+    Ok((Rlp::Bytes(item), rest))
+}
+
+fn decode_list(mut payload: &[u8], depth: usize) -> Result<Vec<Rlp>> {
+    if depth > MAX_RLP_DEPTH {
+        bail!("RLP lists are nested deeper than an Ethereum transaction");
+    }
+    let mut items = Vec::new();
+    while !payload.is_empty() {
+        let (item, rest) = decode_item(payload, depth)?;
+        items.push(item);
+        payload = rest;
+    }
+    Ok(items)
+}
+
+/// Big endian length of a long string or list, in `len_of_len` bytes
+fn long_length(input: &[u8], len_of_len: usize) -> Result<(usize, &[u8])> {
+    let (len, rest) = split(input, len_of_len)?;
+    if len.len() > 8 || len.first() == Some(&0) {
+        bail!("Invalid RLP length");
+    }
+    let len = len.iter().fold(0u64, |value, byte| (value << 8) | (*byte as u64));
+    Ok((usize::try_from(len)?, rest))
+}
+
+fn split(input: &[u8], len: usize) -> Result<(&[u8], &[u8])> {
+    if input.len() < len {
+        bail!("RLP item of {} bytes is cut off after {} bytes", len, input.len());
+    }
+    Ok(input.split_at(len))
+}
+
+fn bytes<'a>(item: &'a Rlp) -> Result<&'a [u8]> {
+    match item {
+        Rlp::Bytes(bytes) => Ok(bytes),
+        Rlp::List(_) => bail!("Expected a value in the transaction, found a list"),
+    }
+}
+
+fn uint(item: &Rlp) -> Result<u64> {
+    let value = bytes(item)?;
+    if value.len() > 8 || value.first() == Some(&0) {
+        bail!("Invalid integer in the transaction");
+    }
+    Ok(value.iter().fold(0u64, |sum, byte| (sum << 8) | (*byte as u64)))
+}
+
+/// 0x-prefixed hex without leading zeros, like Ethereum's JSON-RPC quantities
+fn quantity(value: &[u8]) -> String {
+    let digits = hex::encode(value);
+    let digits = digits.trim_start_matches('0');
+    if digits.is_empty() { "0x0".to_string() } else { format!("0x{}", digits) }
+}
+
+fn hex_address(address: &[u8]) -> String {
+    format!("0x{}", hex::encode(address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_legacy_and_eip1559_transactions() {
+        // Signing data of the EIP-155 example transaction
+        let legacy = hex
+            ::decode(
+                "ec098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400\
+                 0080018080"
+            )
+            .unwrap();
+        let transaction = inspect(&legacy).unwrap();
+        assert_eq!(transaction.tx_type, 0);
+        assert_eq!(transaction.chain_id, Some(1));
+        assert_eq!(transaction.nonce, 9);
+        assert_eq!(transaction.to.as_deref(), Some("0x3535353535353535353535353535353535353535"));
+        assert_eq!(transaction.value, "0xde0b6b3a7640000");
+        assert_eq!(transaction.gas_limit, 21000);
+        assert_eq!(transaction.max_fee_per_gas, "0x4a817c800");
+        assert_eq!(transaction.selector, None);
+
+        let recipient = [0x11u8; ADDRESS_LEN];
+        let calldata = [
+            &ERC20_TRANSFER[..],
+            &[0u8; ABI_WORD_LEN - ADDRESS_LEN],
+            &recipient,
+            &[0u8; ABI_WORD_LEN - 1],
+            &[100],
+        ].concat();
+        let fields = [
+            &[0x01, 0x80, 0x01, 0x02, 0x82, 0xea, 0x60, 0x94][..],
+            &[0x22; ADDRESS_LEN],
+            &[0x80, 0xb8, calldata.len() as u8],
+            &calldata,
+            &[0xc0],
+        ].concat();
+        let eip1559 = [&[0x02, 0xf8, fields.len() as u8][..], &fields].concat();
+        let transaction = inspect(&eip1559).unwrap();
+        assert_eq!(transaction.tx_type, 2);
+        assert_eq!(transaction.max_fee_per_gas, "0x2");
+        assert_eq!(transaction.gas_limit, 60000);
+        assert_eq!(transaction.selector.as_deref(), Some(ERC20_TRANSFER_SELECTOR));
+        assert_eq!(
+            transaction.erc20_transfer,
+            Some(Erc20Transfer { recipient: hex_address(&recipient), amount: "0x64".to_string() })
+        );
+
+        assert!(inspect(&eip1559[..eip1559.len() - 1]).is_err());
+        assert!(inspect(b"hello").is_err());
+    }
+}
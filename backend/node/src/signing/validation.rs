@@ -0,0 +1,169 @@
+use crate::session_manager;
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use anyhow::{ anyhow, bail, Result };
+use base64;
+use chrono::{ DateTime, Utc };
+use hmac::{ Hmac, Mac, NewMac };
+use sha2::Sha256;
+use tracing::{ error, info };
+
+pub(crate) const TIMESTAMP_KEY: &str = "timestamp";
+const TRANSFER_PREFIX: &str = "Authorizing ownership transfer to ";
+
+// Check that the timestamp is newer than the last one we've seen, without recording it
+pub fn check_timestamp(key_id: &str, new_timestamp: &str, email: &str) -> Result<()> {
+    let new_dt = DateTime::parse_from_rfc3339(new_timestamp)
+        .map_err(|err| anyhow!("Failed to parse timestamp: {}", err))?
+        .with_timezone(&Utc);
+
+    // It's fine if this fails - it just means first tx
+    match KeyMetadataStore::get(key_id, TIMESTAMP_KEY, email) {
+        Ok(prev_timestamp_str) => {
+            let prev_dt = DateTime::parse_from_rfc3339(&prev_timestamp_str)
+                .map_err(|err| anyhow!("Failed to parse stored timestamp: {}", err))?
+                .with_timezone(&Utc);
+
+            if new_dt <= prev_dt {
+                bail!(
+                    "Provided timestamp ({}) is not newer than the last request",
+                    new_timestamp
+                );
+            }
+        }
+        Err(err) => {
+            info!("No previous timestamp found, likely first transaction: {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Timestamp of the last request recorded for the key, `None` before the first one
+pub fn last_authorized_timestamp(key_id: &str, email: &str) -> Option<DateTime<Utc>> {
+    let timestamp = KeyMetadataStore::get(key_id, TIMESTAMP_KEY, email).ok()?;
+    DateTime::parse_from_rfc3339(&timestamp)
+        .ok()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+}
+
+// Verify that the timestamp is newer than the last one we've seen and record it
+pub fn verify_timestamp(key_id: &str, new_timestamp: &str, email: &str) -> bool {
+    // Concurrent requests for the key could otherwise both pass the check with the same timestamp
+    let _lock = match session_manager::lock_key(key_id) {
+        Ok(lock) => lock,
+        Err(err) => {
+            error!("Timestamp validation failed: {}", err);
+            return false;
+        }
+    };
+    if let Err(err) = check_timestamp(key_id, new_timestamp, email) {
+        error!("Timestamp validation failed: {}", err);
+        return false;
+    }
+
+    match KeyMetadataStore::save(new_timestamp, key_id, TIMESTAMP_KEY, email, &WriteOpts::Modify) {
+        Ok(_) => true,
+        Err(err) => {
+            error!("Failed to save new timestamp: {}", err);
+            false
+        }
+    }
+}
+
+// HMAC verification using SHA256(timestamp + email) with signing key
+pub fn verify_hmac(provided_hmac: &str, timestamp: &str, email: &str, signing_key: &str) -> bool {
+    verify_hmac_input(provided_hmac, &format!("{}{}", timestamp, email), signing_key)
+}
+
+// HMAC verification of an arbitrary input, for requests that bind more than timestamp and email
+pub fn verify_hmac_input(provided_hmac: &str, message_input: &str, signing_key: &str) -> bool {
+    type HmacSha256 = Hmac<Sha256>;
+
+    let mut mac = match HmacSha256::new_from_slice(signing_key.as_bytes()) {
+        Ok(m) => m,
+        Err(err) => {
+            error!("Failed to create HMAC instance: {}", err);
+            return false;
+        }
+    };
+
+    mac.update(message_input.as_bytes());
+
+    // Base64 instead of hex to match the TypeScript implementation
+    let provided_hmac = match base64::decode(provided_hmac) {
+        Ok(provided_hmac) => provided_hmac,
+        Err(_) => {
+            error!("HMAC verification failed: the HMAC is not base64");
+            return false;
+        }
+    };
+    // Compared in constant time, neither HMAC is logged
+    if mac.verify(&provided_hmac).is_err() {
+        error!("HMAC verification failed");
+        return false;
+    }
+
+    info!("HMAC verified");
+    true
+}
+
+// Identity an ownership transfer message hands the key to
+pub fn transfer_destination(message: &[u8]) -> Result<String> {
+    let message_str = String::from_utf8(message.to_vec()).map_err(|err|
+        anyhow!("Failed to convert message to string: {}", err)
+    )?;
+
+    if !message_str.starts_with(TRANSFER_PREFIX) {
+        bail!("Invalid transfer message format: {}", message_str);
+    }
+
+    Ok(message_str.replace(TRANSFER_PREFIX, ""))
+}
+
+// Check that a transfer message targets the identity the user registered, without consuming it
+pub fn check_transfer_target(message: &[u8], email: &str) -> Result<()> {
+    let target_client_key = transfer_destination(message)?;
+    let stored_identity = KeyMetadataStore::get_user_level("new_identity_key", email).map_err(
+        |err| anyhow!("Failed to retrieve identity using KeyMetadataStore: {}", err)
+    )?;
+
+    if stored_identity.trim() != target_client_key.trim() {
+        bail!(
+            "Transfer target {} is not the identity registered for the account",
+            target_client_key.trim()
+        );
+    }
+    Ok(())
+}
+
+pub fn check_access_key(key_id: &str, email: &str, node_signing_key: &str) -> Result<()> {
+    let saved_access_key = KeyMetadataStore::get(key_id, "access", email).map_err(|err|
+        anyhow!("Failed to load saved access key: {}", err)
+    )?;
+
+    if node_signing_key != saved_access_key {
+        bail!("Access key mismatch: decrypted key does not match saved access key");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hmac_of(input: &str, key: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(input.as_bytes());
+        base64::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn verifies_only_the_matching_hmac() {
+        let hmac = hmac_of("2024-01-01T00:00:00Zowner@example.com", "signing-key");
+
+        assert!(verify_hmac(&hmac, "2024-01-01T00:00:00Z", "owner@example.com", "signing-key"));
+        assert!(!verify_hmac(&hmac, "2024-01-01T00:00:01Z", "owner@example.com", "signing-key"));
+        assert!(!verify_hmac(&hmac, "2024-01-01T00:00:00Z", "owner@example.com", "other-key"));
+        assert!(!verify_hmac_input("not base64!", "input", "signing-key"));
+    }
+}
@@ -0,0 +1,267 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::config::{ Config, ConfigProvider };
+use crate::metrics::{ self, SIGNING_LATENCY_BUCKETS };
+use crate::tenant::Access;
+use anyhow::{ bail, Result };
+use chrono::{ NaiveDate, Utc };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{ env, fs };
+use tracing::warn;
+
+/// Number of pools keys are spread over, bounds the label cardinality of the SLO metrics
+const KEY_POOLS_VAR: &str = "SLO_KEY_POOLS";
+const DEFAULT_KEY_POOLS: u64 = 8;
+const SLO_DIRECTORY: &str = "slo";
+const MONTH_FORMAT: &str = "%Y-%m";
+
+/// Serializes updates of the monthly ledger between session threads
+static LEDGER_LOCK: Mutex<()> = Mutex::new(());
+
+/// Pool of a key for metric labels. Keys are hashed into a fixed number of pools, so the labels
+/// stay bounded and reveal nothing about the key ids a partner node holds.
+pub fn key_pool(key_id: &str) -> String {
+    let pools = match env::var(KEY_POOLS_VAR).map(|pools| pools.parse::<u64>()) {
+        Ok(Ok(pools)) if pools > 0 => pools,
+        Ok(_) => {
+            warn!("Ignoring invalid {}, using {} pools", KEY_POOLS_VAR, DEFAULT_KEY_POOLS);
+            DEFAULT_KEY_POOLS
+        }
+        Err(_) => DEFAULT_KEY_POOLS,
+    };
+    let digest = Sha256::digest(key_id.as_bytes());
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    format!("pool-{:02}", u64::from_be_bytes(prefix) % pools)
+}
+
+/// Records a finished signing session in the metrics and in the ledger of the current month
+pub fn record_signing(scheme: &str, key_id: &str, duration: Duration, succeeded: bool) {
+    let pool = key_pool(key_id);
+    metrics::record_key_signing(scheme, &pool, duration, succeeded);
+    update_ledger(&pool, |pool| pool.add_signing(duration, succeeded));
+}
+
+/// Records a finished keyshare recovery in the metrics and in the ledger of the current month
+pub fn record_recovery(key_id: &str, succeeded: bool) {
+    let pool = key_pool(key_id);
+    metrics::record_key_recovery(&pool, succeeded);
+    update_ledger(&pool, |pool| pool.add_recovery(succeeded));
+}
+
+fn update_ledger(pool: &str, update: impl FnOnce(&mut PoolLedger)) {
+    let _guard = LEDGER_LOCK.lock().unwrap();
+    let month = Utc::now().format(MONTH_FORMAT).to_string();
+    let updated = MonthlyLedger::load(&month).and_then(|mut ledger| {
+        update(ledger.pools.entry(pool.to_string()).or_default());
+        ledger.save(&month)
+    });
+    if let Err(err) = updated {
+        warn!("Failed to update the SLO ledger of {}: {}", month, err);
+    }
+}
+
+/// Counts of one key pool over a month. Latencies are kept in the buckets of the signing latency
+/// histogram so the ledger stays small however many sessions a month has.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+struct PoolLedger {
+    signings_succeeded: u64,
+    signings_failed: u64,
+    /// Sessions per latency bucket, the last entry counts sessions slower than every bucket
+    signing_latency_buckets: Vec<u64>,
+    recoveries_succeeded: u64,
+    recoveries_failed: u64,
+}
+
+impl PoolLedger {
+    fn add_signing(&mut self, duration: Duration, succeeded: bool) {
+        if succeeded {
+            self.signings_succeeded += 1;
+        } else {
+            self.signings_failed += 1;
+        }
+        self.signing_latency_buckets.resize(SIGNING_LATENCY_BUCKETS.len() + 1, 0);
+        let bucket = SIGNING_LATENCY_BUCKETS
+            .iter()
+            .position(|bound| duration.as_secs_f64() <= *bound)
+            .unwrap_or(SIGNING_LATENCY_BUCKETS.len());
+        self.signing_latency_buckets[bucket] += 1;
+    }
+
+    fn add_recovery(&mut self, succeeded: bool) {
+        if succeeded {
+            self.recoveries_succeeded += 1;
+        } else {
+            self.recoveries_failed += 1;
+        }
+    }
+
+    /// Upper bound of the bucket holding the 95th percentile, none when no session was recorded
+    /// or when it is slower than every bucket
+    fn p95_latency_secs(&self) -> Option<f64> {
+        let total: u64 = self.signing_latency_buckets.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let target = (total * 95).div_ceil(100);
+        let mut seen = 0;
+        for (bucket, count) in self.signing_latency_buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return SIGNING_LATENCY_BUCKETS.get(bucket).copied();
+            }
+        }
+        None
+    }
+
+    fn report(&self, pool: &str) -> PoolReport {
+        let signings = self.signings_succeeded + self.signings_failed;
+        PoolReport {
+            pool: pool.to_string(),
+            signings,
+            signing_success_rate: if signings == 0 {
+                None
+            } else {
+                Some((self.signings_succeeded as f64) / (signings as f64))
+            },
+            p95_signing_latency_secs: self.p95_latency_secs(),
+            recoveries_succeeded: self.recoveries_succeeded,
+            recoveries_failed: self.recoveries_failed,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct MonthlyLedger {
+    pools: BTreeMap<String, PoolLedger>,
+}
+
+impl MonthlyLedger {
+    fn load(month: &str) -> Result<Self> {
+        let path = ledger_path(month);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn save(&self, month: &str) -> Result<()> {
+        let path = ledger_path(month);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+fn ledger_path(month: &str) -> PathBuf {
+    let mut path = Config::get_gridlock_directory();
+    path.push(SLO_DIRECTORY);
+    path.push(format!("{}.json", month));
+    path
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct PoolReport {
+    pub pool: String,
+    pub signings: u64,
+    /// Share of signing sessions that succeeded, none without sessions
+    pub signing_success_rate: Option<f64>,
+    /// Upper bound of the latency bucket holding the 95th percentile
+    pub p95_signing_latency_secs: Option<f64>,
+    pub recoveries_succeeded: u64,
+    pub recoveries_failed: u64,
+}
+
+/// Monthly summary of the signing and recovery service levels of this node, per key pool
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SloReport {
+    pub month: String,
+    pub pools: Vec<PoolReport>,
+    pub total: PoolReport,
+}
+
+/// Returns the SLO report of a month given as `YYYY-MM`, the current month by default
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetSLOReportCommand {
+    #[serde(default)]
+    pub month: Option<String>,
+}
+
+impl JsonCommand for GetSLOReportCommand {
+    type Response = SloReport;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let month = match self.month {
+            Some(month) => {
+                if NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d").is_err() {
+                    bail!("Invalid month {:?}, expected YYYY-MM", month);
+                }
+                month
+            }
+            None => Utc::now().format(MONTH_FORMAT).to_string(),
+        };
+        let ledger = {
+            let _guard = LEDGER_LOCK.lock().unwrap();
+            MonthlyLedger::load(&month)?
+        };
+        Ok(build_report(month, &ledger))
+    }
+}
+
+fn build_report(month: String, ledger: &MonthlyLedger) -> SloReport {
+    let mut total = PoolLedger::default();
+    for pool in ledger.pools.values() {
+        total.signings_succeeded += pool.signings_succeeded;
+        total.signings_failed += pool.signings_failed;
+        total.recoveries_succeeded += pool.recoveries_succeeded;
+        total.recoveries_failed += pool.recoveries_failed;
+        total.signing_latency_buckets.resize(pool.signing_latency_buckets.len(), 0);
+        for (bucket, count) in pool.signing_latency_buckets.iter().enumerate() {
+            total.signing_latency_buckets[bucket] += count;
+        }
+    }
+    SloReport {
+        month,
+        pools: ledger.pools
+            .iter()
+            .map(|(name, pool)| pool.report(name))
+            .collect(),
+        total: total.report("all"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_pools_into_a_report() {
+        let mut ledger = MonthlyLedger::default();
+        let pool = ledger.pools.entry(key_pool("key-1")).or_default();
+        for _ in 0..19 {
+            pool.add_signing(Duration::from_millis(800), true);
+        }
+        pool.add_signing(Duration::from_secs(40), false);
+        pool.add_recovery(true);
+
+        let report = build_report("2026-10".to_string(), &ledger);
+        assert_eq!(report.pools.len(), 1);
+        assert_eq!(report.total.signings, 20);
+        assert_eq!(report.total.signing_success_rate, Some(0.95));
+        assert_eq!(report.total.p95_signing_latency_secs, Some(1.0));
+        assert_eq!(report.total.recoveries_succeeded, 1);
+        assert_eq!(key_pool("key-1"), key_pool("key-1"));
+        assert!(key_pool("key-1").starts_with("pool-"));
+    }
+}
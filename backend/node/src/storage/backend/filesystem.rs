@@ -0,0 +1,96 @@
+use super::{ StorageBackend, StorageItem };
+use crate::storage::fs::WriteOpts;
+use anyhow::{ bail, Result };
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{ Path, PathBuf };
+
+/// Files below the storage directory, the layout every node used before backends were pluggable
+pub struct FileSystemBackend {
+    root: PathBuf,
+}
+
+impl FileSystemBackend {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub fn item_path(&self, item: &StorageItem) -> PathBuf {
+        self.root.join(item.path())
+    }
+
+    /// Walks only the directories that can contain paths starting with `prefix`
+    fn collect_files(&self, directory: &Path, prefix: &str, paths: &mut Vec<String>) -> Result<()> {
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(());
+            }
+            Err(err) => {
+                return Err(err.into());
+            }
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let relative = match path.strip_prefix(&self.root)?.to_str() {
+                Some(relative) => relative.to_string(),
+                None => {
+                    continue;
+                }
+            };
+            if path.is_dir() {
+                if relative.starts_with(prefix) || prefix.starts_with(&format!("{}/", relative)) {
+                    self.collect_files(&path, prefix, paths)?;
+                }
+            } else if relative.starts_with(prefix) {
+                paths.push(relative);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for FileSystemBackend {
+    fn name(&self) -> &'static str {
+        "filesystem"
+    }
+
+    fn read(&self, item: &StorageItem) -> Result<Option<String>> {
+        match fs::read_to_string(self.item_path(item)) {
+            Ok(content) => Ok(Some(content)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&self, item: &StorageItem, content: &str, write_access: &WriteOpts) -> Result<()> {
+        let path = self.item_path(item);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if write_access == &WriteOpts::CreateNewOnly && path.exists() {
+            bail!("Tried to write {} that already exists", item.path());
+        }
+        fs::write(path, content)?;
+        Ok(())
+    }
+
+    fn remove(&self, item: &StorageItem) -> Result<bool> {
+        match fs::remove_file(self.item_path(item)) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut paths = Vec::new();
+        self.collect_files(&self.root, prefix, &mut paths)?;
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn exists(&self, item: &StorageItem) -> Result<bool> {
+        Ok(self.item_path(item).exists())
+    }
+}
@@ -0,0 +1,76 @@
+use super::{ StorageBackend, StorageItem };
+use crate::storage::fs::WriteOpts;
+use anyhow::{ anyhow, bail, Result };
+use std::collections::BTreeMap;
+use std::sync::{ Mutex, MutexGuard };
+
+/// Items kept only for the life of the process, for temporary guardians such as a browser tab
+/// taking part in a recovery ceremony, which has no filesystem and must not leave shares behind
+#[derive(Default)]
+pub struct MemoryBackend {
+    items: Mutex<BTreeMap<String, String>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn items(&self) -> Result<MutexGuard<BTreeMap<String, String>>> {
+        self.items.lock().map_err(|_| anyhow!("Memory storage is poisoned"))
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn read(&self, item: &StorageItem) -> Result<Option<String>> {
+        Ok(self.items()?.get(&item.path()).cloned())
+    }
+
+    fn write(&self, item: &StorageItem, content: &str, write_access: &WriteOpts) -> Result<()> {
+        let mut items = self.items()?;
+        let path = item.path();
+        if write_access == &WriteOpts::CreateNewOnly && items.contains_key(&path) {
+            bail!("Tried to write {} that already exists", path);
+        }
+        items.insert(path, content.to_string());
+        Ok(())
+    }
+
+    fn remove(&self, item: &StorageItem) -> Result<bool> {
+        Ok(self.items()?.remove(&item.path()).is_some())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(
+            self
+                .items()?
+                .range(prefix.to_string()..)
+                .take_while(|(path, _)| path.starts_with(prefix))
+                .map(|(path, _)| path.clone())
+                .collect()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_items_by_path_until_removed() {
+        let backend = MemoryBackend::new();
+        let info = StorageItem::KeyInfo { key_id: "key" };
+        let keyfile = StorageItem::Keyfile { key_id: "key", index: 1, email: None };
+        backend.write(&info, "{}", &WriteOpts::CreateNewOnly).unwrap();
+        backend.write(&keyfile, "share", &WriteOpts::CreateNewOnly).unwrap();
+        assert!(backend.write(&keyfile, "other", &WriteOpts::CreateNewOnly).is_err());
+        assert_eq!(backend.list("keys--").unwrap(), vec![keyfile.path()]);
+        assert!(backend.remove(&keyfile).unwrap());
+        assert_eq!(backend.read(&keyfile).unwrap(), None);
+        assert_eq!(backend.read(&info).unwrap().as_deref(), Some("{}"));
+    }
+}
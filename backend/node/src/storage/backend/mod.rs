@@ -0,0 +1,282 @@
+#[cfg(not(target_arch = "wasm32"))]
+mod filesystem;
+mod memory;
+#[cfg(feature = "s3-storage")]
+mod s3;
+#[cfg(feature = "sqlite-storage")]
+mod sqlite;
+
+use crate::config::{ Config, ConfigProvider };
+use crate::storage::fs::WriteOpts;
+use anyhow::{ bail, Result };
+#[cfg(not(target_arch = "wasm32"))]
+pub use filesystem::FileSystemBackend;
+pub use memory::MemoryBackend;
+use std::env;
+use std::sync::OnceLock;
+
+/// Selects the backend: "filesystem" (default), "memory", "sqlite" or "s3". Browser builds have no
+/// filesystem and default to "memory".
+const BACKEND_VAR: &str = "STORAGE_BACKEND";
+
+/// Something the node persists. Every backend stores an item under its `path`, which is the
+/// location of the file relative to the storage directory in the filesystem layout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageItem<'a> {
+    Keyfile {
+        key_id: &'a str,
+        index: usize,
+        /// Keyfiles of an account live in the account directory, others in the flat layout
+        email: Option<&'a str>,
+    },
+    /// Checksum of a keyfile, stored beside it
+    KeyshareIntegrity {
+        key_id: &'a str,
+        index: usize,
+        email: Option<&'a str>,
+    },
+    KeyInfo {
+        key_id: &'a str,
+    },
+    /// Protocol version the key was generated with
+    KeyProtocol {
+        key_id: &'a str,
+    },
+    KeyMetadata {
+        key_id: &'a str,
+        metadata_type: &'a str,
+        email: &'a str,
+    },
+    UserMetadata {
+        metadata_type: &'a str,
+        email: &'a str,
+    },
+    /// Metadata of the node itself, not tied to a key or an account
+    NodeMetadata {
+        metadata_type: &'a str,
+    },
+}
+
+impl<'a> StorageItem<'a> {
+    pub fn path(&self) -> String {
+        match *self {
+            StorageItem::Keyfile { key_id, index, email: None } =>
+                match index {
+                    0 => format!("keys--{}.json", key_id),
+                    _ => format!("keys--{}--{}.json", key_id, index),
+                }
+            StorageItem::Keyfile { key_id, index, email: Some(email) } =>
+                match index {
+                    0 => format!("accounts/{}/keys/{}/keyshare-{}.json", email, key_id, key_id),
+                    _ =>
+                        format!(
+                            "accounts/{}/keys/{}/keyshare-{}-{}.json",
+                            email,
+                            key_id,
+                            key_id,
+                            index
+                        ),
+                }
+            StorageItem::KeyshareIntegrity { key_id, index, email } =>
+                format!("{}.integrity", (StorageItem::Keyfile { key_id, index, email }).path()),
+            StorageItem::KeyInfo { key_id } => format!("info--{}.json", key_id),
+            StorageItem::KeyProtocol { key_id } => format!("protocol--{}.json", key_id),
+            // The access key is shared by every key of the account
+            StorageItem::KeyMetadata { metadata_type: "access", email, .. } =>
+                format!("accounts/{}/access_key", email),
+            StorageItem::KeyMetadata { key_id, metadata_type: "keys", email } =>
+                (StorageItem::Keyfile { key_id, index: 0, email: Some(email) }).path(),
+            StorageItem::KeyMetadata { key_id, metadata_type, email } =>
+                format!("accounts/{}/keys/{}/{}-{}", email, key_id, metadata_type, key_id),
+            StorageItem::UserMetadata { metadata_type, email } =>
+                format!("accounts/{}/{}", email, metadata_type),
+            StorageItem::NodeMetadata { metadata_type } => format!("node/{}", metadata_type),
+        }
+    }
+
+    /// The item stored under `path`, the inverse of `path`. Paths that are not plain relative
+    /// paths, or name nothing the node stores, give `None`.
+    pub fn from_path(path: &'a str) -> Option<Self> {
+        if path.split('/').any(|component| matches!(component, "" | "." | "..")) {
+            return None;
+        }
+        if let Some(keyfile) = path.strip_suffix(".integrity") {
+            return match StorageItem::from_path(keyfile)? {
+                StorageItem::Keyfile { key_id, index, email } =>
+                    Some(StorageItem::KeyshareIntegrity { key_id, index, email }),
+                _ => None,
+            };
+        }
+        if let Some(metadata_type) = path.strip_prefix("node/") {
+            return (!metadata_type.contains('/')).then_some(StorageItem::NodeMetadata {
+                metadata_type,
+            });
+        }
+        if let Some(account_path) = path.strip_prefix("accounts/") {
+            return Self::from_account_path(account_path);
+        }
+        if path.contains('/') {
+            return None;
+        }
+        let file = path.strip_suffix(".json")?;
+        if let Some(key_id) = file.strip_prefix("info--") {
+            return Some(StorageItem::KeyInfo { key_id });
+        }
+        if let Some(key_id) = file.strip_prefix("protocol--") {
+            return Some(StorageItem::KeyProtocol { key_id });
+        }
+        let file = file.strip_prefix("keys--")?;
+        let (key_id, index) = file
+            .rsplit_once("--")
+            .and_then(|(key_id, index)| Some((key_id, index.parse().ok()?)))
+            .unwrap_or((file, 0));
+        Some(StorageItem::Keyfile { key_id, index, email: None })
+    }
+
+    fn from_account_path(path: &'a str) -> Option<Self> {
+        let mut components = path.split('/');
+        match (components.next()?, components.next()?, components.next(), components.next()) {
+            (email, "access_key", None, None) =>
+                Some(StorageItem::KeyMetadata { key_id: "", metadata_type: "access", email }),
+            (email, metadata_type, None, None) =>
+                Some(StorageItem::UserMetadata { metadata_type, email }),
+            (email, "keys", Some(key_id), Some(file)) if components.next().is_none() => {
+                let keyshare = file
+                    .strip_prefix("keyshare-")
+                    .and_then(|rest| rest.strip_prefix(key_id))
+                    .and_then(|rest| rest.strip_suffix(".json"));
+                if let Some(rest) = keyshare {
+                    let index = match rest {
+                        "" => Some(0),
+                        _ => rest.strip_prefix('-').and_then(|index| index.parse().ok()),
+                    };
+                    if let Some(index) = index {
+                        return Some(StorageItem::Keyfile { key_id, index, email: Some(email) });
+                    }
+                }
+                let metadata_type = file.strip_suffix(key_id)?.strip_suffix('-')?;
+                Some(StorageItem::KeyMetadata { key_id, metadata_type, email })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Where keyfiles, key info and key metadata are persisted. The node identity and the files of
+/// background jobs stay in the storage directory whatever the backend.
+pub trait StorageBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Contents of the item, `None` if it was never written or has been removed
+    fn read(&self, item: &StorageItem) -> Result<Option<String>>;
+
+    fn write(&self, item: &StorageItem, content: &str, write_access: &WriteOpts) -> Result<()>;
+
+    /// Removes the item, returns whether it existed
+    fn remove(&self, item: &StorageItem) -> Result<bool>;
+
+    /// Paths of every stored item starting with `prefix`
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    fn exists(&self, item: &StorageItem) -> Result<bool> {
+        Ok(self.read(item)?.is_some())
+    }
+}
+
+/// The backend selected with `STORAGE_BACKEND`, created on first use
+pub fn storage_backend() -> Result<&'static dyn StorageBackend> {
+    static BACKEND: OnceLock<Box<dyn StorageBackend>> = OnceLock::new();
+    if let Some(backend) = BACKEND.get() {
+        return Ok(backend.as_ref());
+    }
+    let backend = backend_from_env()?;
+    Ok(BACKEND.get_or_init(|| backend).as_ref())
+}
+
+fn backend_from_env() -> Result<Box<dyn StorageBackend>> {
+    let kind = env::var(BACKEND_VAR).unwrap_or_default();
+    match kind.as_str() {
+        #[cfg(not(target_arch = "wasm32"))]
+        "" | "filesystem" => {
+            Ok(Box::new(FileSystemBackend::new(Config::get_gridlock_directory())))
+        }
+        #[cfg(target_arch = "wasm32")]
+        "" => Ok(Box::new(MemoryBackend::new())),
+        "memory" => Ok(Box::new(MemoryBackend::new())),
+        #[cfg(feature = "sqlite-storage")]
+        "sqlite" => Ok(Box::new(sqlite::SqliteBackend::from_env()?)),
+        #[cfg(feature = "s3-storage")]
+        "s3" => Ok(Box::new(s3::S3Backend::from_env()?)),
+        #[allow(unreachable_patterns)]
+        "sqlite" | "s3" =>
+            bail!("Storage backend {} needs a node built with the {}-storage feature", kind, kind),
+        _ => bail!("Unknown storage backend {}", kind),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn items_keep_the_filesystem_layout() {
+        let key_id = "1b2359cf";
+        let email = "user@example.com";
+        let flat = StorageItem::Keyfile { key_id, index: 2, email: None };
+        assert_eq!(flat.path(), "keys--1b2359cf--2.json");
+        let account = StorageItem::Keyfile { key_id, index: 0, email: Some(email) };
+        assert_eq!(
+            account.path(),
+            "accounts/user@example.com/keys/1b2359cf/keyshare-1b2359cf.json"
+        );
+        assert_eq!(
+            (StorageItem::KeyMetadata { key_id, metadata_type: "keys", email }).path(),
+            account.path()
+        );
+        assert_eq!(
+            (StorageItem::KeyMetadata { key_id, metadata_type: "access", email }).path(),
+            "accounts/user@example.com/access_key"
+        );
+        assert_eq!(
+            (StorageItem::KeyMetadata { key_id, metadata_type: "observers", email }).path(),
+            "accounts/user@example.com/keys/1b2359cf/observers-1b2359cf"
+        );
+        assert_eq!(
+            (StorageItem::KeyshareIntegrity { key_id, index: 2, email: None }).path(),
+            "keys--1b2359cf--2.json.integrity"
+        );
+        assert_eq!((StorageItem::KeyInfo { key_id }).path(), "info--1b2359cf.json");
+        assert_eq!((StorageItem::KeyProtocol { key_id }).path(), "protocol--1b2359cf.json");
+        assert_eq!(
+            (StorageItem::NodeMetadata { metadata_type: "peer_reputation" }).path(),
+            "node/peer_reputation"
+        );
+    }
+
+    #[test]
+    fn items_are_found_from_their_path() {
+        let key_id = "1b2359cf";
+        let email = Some("user@example.com");
+        let items = [
+            StorageItem::Keyfile { key_id, index: 0, email: None },
+            StorageItem::Keyfile { key_id, index: 2, email: None },
+            StorageItem::Keyfile { key_id, index: 0, email },
+            StorageItem::Keyfile { key_id, index: 2, email },
+            StorageItem::KeyshareIntegrity { key_id, index: 2, email },
+            StorageItem::KeyInfo { key_id },
+            StorageItem::KeyProtocol { key_id },
+            StorageItem::KeyMetadata { key_id: "", metadata_type: "access", email: "a@b.c" },
+            StorageItem::KeyMetadata { key_id, metadata_type: "timestamp", email: "a@b.c" },
+            StorageItem::UserMetadata { metadata_type: "e2e_key", email: "a@b.c" },
+            StorageItem::NodeMetadata { metadata_type: "peer_reputation" },
+        ];
+        for item in items {
+            assert_eq!(StorageItem::from_path(&item.path()), Some(item));
+        }
+        assert_eq!(StorageItem::from_path("node.json"), None);
+        assert_eq!(StorageItem::from_path("/etc/passwd"), None);
+        assert_eq!(StorageItem::from_path("accounts/../node.json"), None);
+        assert_eq!(StorageItem::from_path("accounts/a@b.c/keys/../keyshare-...json"), None);
+        assert_eq!(StorageItem::from_path("info--../x.json"), None);
+    }
+}
@@ -0,0 +1,101 @@
+use super::{ StorageBackend, StorageItem };
+use crate::storage::fs::WriteOpts;
+use anyhow::{ anyhow, bail, Context, Result };
+use s3::bucket::Bucket;
+use s3::creds::Credentials;
+use s3::region::Region;
+use std::env;
+
+const BUCKET_VAR: &str = "STORAGE_S3_BUCKET";
+const REGION_VAR: &str = "STORAGE_S3_REGION";
+/// Endpoint of an S3 compatible object store, AWS is used when unset
+const ENDPOINT_VAR: &str = "STORAGE_S3_ENDPOINT";
+/// Prefix of every object the node writes, so several nodes can share a bucket
+const PREFIX_VAR: &str = "STORAGE_S3_PREFIX";
+
+/// Every item as an object named by its path. Credentials are read like the AWS CLI does, from
+/// `AWS_ACCESS_KEY_ID` / `AWS_SECRET_ACCESS_KEY` or the shared credentials file.
+pub struct S3Backend {
+    bucket: Bucket,
+    prefix: String,
+}
+
+impl S3Backend {
+    pub fn from_env() -> Result<Self> {
+        let name = env::var(BUCKET_VAR).with_context(|| format!("{} is not set", BUCKET_VAR))?;
+        let region_name = env::var(REGION_VAR).unwrap_or_else(|_| "us-east-1".to_string());
+        let bucket = match env::var(ENDPOINT_VAR) {
+            Ok(endpoint) if !endpoint.is_empty() => {
+                let region = Region::Custom { region: region_name, endpoint };
+                // Self-hosted object stores rarely resolve bucket subdomains
+                Bucket::new(&name, region, Credentials::default()?)?.with_path_style()
+            }
+            _ => {
+                let region = region_name
+                    .parse::<Region>()
+                    .map_err(|err| anyhow!("Invalid S3 region: {}", err))?;
+                Bucket::new(&name, region, Credentials::default()?)?
+            }
+        };
+        let prefix = env::var(PREFIX_VAR).unwrap_or_default();
+        Ok(Self { bucket, prefix })
+    }
+
+    fn object_name(&self, path: &str) -> String {
+        format!("{}{}", self.prefix, path)
+    }
+}
+
+impl StorageBackend for S3Backend {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    fn read(&self, item: &StorageItem) -> Result<Option<String>> {
+        let response = self.bucket.get_object(self.object_name(&item.path()))?;
+        match response.status_code() {
+            200 => Ok(Some(String::from_utf8(response.bytes().to_vec())?)),
+            404 => Ok(None),
+            status => bail!("Reading {} from S3 failed with status {}", item.path(), status),
+        }
+    }
+
+    fn write(&self, item: &StorageItem, content: &str, write_access: &WriteOpts) -> Result<()> {
+        let object_name = self.object_name(&item.path());
+        if write_access == &WriteOpts::CreateNewOnly {
+            let (_, status) = self.bucket.head_object(&object_name)?;
+            if status != 404 {
+                bail!("Tried to write {} that already exists", item.path());
+            }
+        }
+        let response = self.bucket.put_object(&object_name, content.as_bytes())?;
+        if response.status_code() != 200 {
+            bail!("Writing {} to S3 failed with status {}", item.path(), response.status_code());
+        }
+        Ok(())
+    }
+
+    fn remove(&self, item: &StorageItem) -> Result<bool> {
+        let object_name = self.object_name(&item.path());
+        let (_, status) = self.bucket.head_object(&object_name)?;
+        if status == 404 {
+            return Ok(false);
+        }
+        let response = self.bucket.delete_object(&object_name)?;
+        match response.status_code() {
+            200 | 204 => Ok(true),
+            status => bail!("Removing {} from S3 failed with status {}", item.path(), status),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut paths = self.bucket
+            .list(self.object_name(prefix), None)?
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .filter_map(|object| object.key.strip_prefix(&self.prefix).map(str::to_string))
+            .collect::<Vec<String>>();
+        paths.sort();
+        Ok(paths)
+    }
+}
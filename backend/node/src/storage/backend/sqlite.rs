@@ -0,0 +1,115 @@
+use super::{ StorageBackend, StorageItem };
+use crate::config::{ Config, ConfigProvider };
+use crate::storage::fs::WriteOpts;
+use anyhow::{ anyhow, bail, Result };
+use rusqlite::{ params, Connection, OptionalExtension };
+use std::env;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Database file, `storage.sqlite3` in the storage directory by default
+const PATH_VAR: &str = "STORAGE_SQLITE_PATH";
+
+/// Every item as a row keyed by its path, in a single database file
+pub struct SqliteBackend {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteBackend {
+    pub fn from_env() -> Result<Self> {
+        let path = match env::var(PATH_VAR) {
+            Ok(path) if !path.is_empty() => PathBuf::from(path),
+            _ => Config::get_gridlock_directory().join("storage.sqlite3"),
+        };
+        Self::new(Connection::open(path)?)
+    }
+
+    pub fn new(connection: Connection) -> Result<Self> {
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS storage_items (
+                path TEXT PRIMARY KEY,
+                content TEXT NOT NULL
+            )",
+            []
+        )?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    fn connection(&self) -> Result<std::sync::MutexGuard<Connection>> {
+        self.connection.lock().map_err(|_| anyhow!("SQLite storage connection is poisoned"))
+    }
+}
+
+impl StorageBackend for SqliteBackend {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn read(&self, item: &StorageItem) -> Result<Option<String>> {
+        let content = self
+            .connection()?
+            .query_row(
+                "SELECT content FROM storage_items WHERE path = ?1",
+                params![item.path()],
+                |row| row.get(0)
+            )
+            .optional()?;
+        Ok(content)
+    }
+
+    fn write(&self, item: &StorageItem, content: &str, write_access: &WriteOpts) -> Result<()> {
+        let statement = match write_access {
+            WriteOpts::CreateNewOnly =>
+                "INSERT OR IGNORE INTO storage_items (path, content) VALUES (?1, ?2)",
+            WriteOpts::Modify =>
+                "INSERT OR REPLACE INTO storage_items (path, content) VALUES (?1, ?2)",
+        };
+        let written = self.connection()?.execute(statement, params![item.path(), content])?;
+        if written == 0 {
+            bail!("Tried to write {} that already exists", item.path());
+        }
+        Ok(())
+    }
+
+    fn remove(&self, item: &StorageItem) -> Result<bool> {
+        let removed = self
+            .connection()?
+            .execute("DELETE FROM storage_items WHERE path = ?1", params![item.path()])?;
+        Ok(removed > 0)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(
+            "SELECT path FROM storage_items WHERE substr(path, 1, length(?1)) = ?1 ORDER BY path"
+        )?;
+        let paths = statement
+            .query_map(params![prefix], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_items_by_path() {
+        let backend = SqliteBackend::new(Connection::open_in_memory().unwrap()).unwrap();
+        let info = StorageItem::KeyInfo { key_id: "key" };
+        let keyfile = StorageItem::Keyfile { key_id: "key", index: 0, email: None };
+        assert_eq!(backend.read(&info).unwrap(), None);
+
+        backend.write(&info, "{}", &WriteOpts::CreateNewOnly).unwrap();
+        backend.write(&keyfile, "share", &WriteOpts::CreateNewOnly).unwrap();
+        assert!(backend.write(&info, "{\"a\":1}", &WriteOpts::CreateNewOnly).is_err());
+        backend.write(&info, "{\"a\":1}", &WriteOpts::Modify).unwrap();
+        assert_eq!(backend.read(&info).unwrap().as_deref(), Some("{\"a\":1}"));
+        assert_eq!(backend.list("keys--").unwrap(), vec![keyfile.path()]);
+
+        assert!(backend.remove(&keyfile).unwrap());
+        assert!(!backend.remove(&keyfile).unwrap());
+        assert!(backend.list("keys--").unwrap().is_empty());
+    }
+}
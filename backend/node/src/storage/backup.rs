@@ -0,0 +1,362 @@
+use super::backend::{ storage_backend, StorageItem, WriteOpts };
+use super::key_store::Keystore;
+use super::keyshare_check::verify_keyshare_plaintext;
+use super::keyshare_integrity::KeyshareIntegrity;
+use super::storage_key::StorageKeyring;
+use crate::command::{ JsonCommand, MsgContext };
+use crate::encryption::{ aes_decrypt, aes_encrypt, get_secure_random_bytes, AES_KEY_BYTES_LEN };
+use anyhow::{ anyhow, bail, Context, Result };
+use chrono::{ DateTime, Utc };
+use serde::{ Deserialize, Serialize };
+use crate::node::NodeIdentity;
+use crate::signing::validation::TIMESTAMP_KEY;
+use crate::tenant::{ self, Access, TenantAuth };
+use shared::recovery::EncryptedData;
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use tracing::info;
+
+const BUNDLE_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const MIN_PASSPHRASE_LEN: usize = 12;
+
+/// Passphrase protected copy of keyshare and key metadata files, readable on any node
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BackupBundle {
+    pub version: u8,
+    /// Hex encoded argon2 salt of the passphrase key
+    pub salt: String,
+    pub data: EncryptedData,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupContents {
+    created_at: DateTime<Utc>,
+    files: Vec<BackupFile>,
+}
+
+/// One file relative to the gridlock directory. Contents are kept decrypted so the bundle does
+/// not depend on the storage key of the node it was taken on.
+#[derive(Serialize, Deserialize)]
+struct BackupFile {
+    path: String,
+    contents: String,
+    /// Whether the file was encrypted with the storage key and has to be again on restore
+    encrypted: bool,
+}
+
+fn passphrase_key(passphrase: &str, salt: &[u8]) -> Result<Vec<u8>> {
+    let key = argon2
+        ::hash_raw(passphrase.as_bytes(), salt, &argon2::Config::default())
+        .map_err(|err| anyhow!("Failed to derive the backup key: {}", err))?;
+    if key.len() != AES_KEY_BYTES_LEN {
+        bail!("Derived backup key has length {}", key.len());
+    }
+    Ok(key)
+}
+
+/// Files of the account for one key, or every key of the account when no key is given. Checksums
+/// are left out, they are recomputed from the restored shares.
+fn files_to_back_up(email: &str, key_id: Option<&str>) -> Result<Vec<String>> {
+    let backend = storage_backend()?;
+    let wanted = |stored: &str| !key_id.is_some_and(|key_id| key_id != stored);
+    let mut files = Vec::new();
+    let mut key_ids = BTreeSet::new();
+    for path in backend.list(&format!("accounts/{}/", email))? {
+        let keep = match StorageItem::from_path(&path) {
+            // Authorizes a restore onto a node that doesn't know the account yet
+            Some(StorageItem::KeyMetadata { metadata_type: "access", .. }) => true,
+            Some(StorageItem::Keyfile { key_id, .. }) if wanted(key_id) => {
+                key_ids.insert(key_id.to_string());
+                true
+            }
+            Some(StorageItem::KeyMetadata { key_id, .. }) => wanted(key_id),
+            Some(StorageItem::UserMetadata { .. }) => key_id.is_none(),
+            _ => false,
+        };
+        if keep {
+            files.push(path);
+        }
+    }
+    for key_id in &key_ids {
+        for item in [StorageItem::KeyInfo { key_id }, StorageItem::KeyProtocol { key_id }] {
+            if backend.exists(&item)? {
+                files.push(item.path());
+            }
+        }
+    }
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Only items of the account, and the key info of its restored keys, are restored. Keys in the
+/// flat layout belong to no account and the node's own metadata is never part of a backup.
+fn restore_item<'a>(
+    path: &'a str,
+    email: &str,
+    key_ids: &BTreeSet<&str>
+) -> Result<StorageItem<'a>> {
+    let item = StorageItem::from_path(path);
+    let allowed = match item {
+        | Some(StorageItem::Keyfile { email: Some(account), .. })
+        | Some(StorageItem::KeyMetadata { email: account, .. })
+        | Some(StorageItem::UserMetadata { email: account, .. }) => account == email,
+        Some(StorageItem::KeyInfo { key_id }) | Some(StorageItem::KeyProtocol { key_id }) =>
+            key_ids.contains(key_id),
+        _ => false,
+    };
+    match item {
+        Some(item) if allowed => Ok(item),
+        _ => bail!("Backup contains a file outside of the key storage of {}: {:?}", email, path),
+    }
+}
+
+/// Exports one or all keyshares of an account with their key metadata, encrypted with a key
+/// derived from the passphrase
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupShareCommand {
+    #[serde(default)]
+    pub key_id: Option<String>,
+    pub passphrase: String,
+    /// Proof of the account whose keys are exported
+    pub authorization: TenantAuth,
+}
+
+impl Debug for BackupShareCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("BackupShareCommand")
+            .field("key_id", &self.key_id)
+            .field("authorization", &self.authorization)
+            .finish()
+    }
+}
+
+impl JsonCommand for BackupShareCommand {
+    type Response = BackupBundle;
+
+    fn access(&self) -> Access<'_> {
+        Access::Account {
+            auth: Some(&self.authorization),
+            key_ids: self.key_id.iter().cloned().collect(),
+        }
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        create_backup(&self.authorization.email, self.key_id.as_deref(), &self.passphrase)
+    }
+}
+
+/// Bundles the keys of an account. Callers check the account first, the administration CLI
+/// runs it directly on the node's own machine.
+pub fn create_backup(email: &str, key_id: Option<&str>, passphrase: &str) -> Result<BackupBundle> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        bail!("Backup passphrase has to be at least {} characters", MIN_PASSPHRASE_LEN);
+    }
+    let keyring = StorageKeyring::load()?;
+    let backend = storage_backend()?;
+    let paths = files_to_back_up(email, key_id)?;
+    let is_keyfile = |path: &String| {
+        matches!(StorageItem::from_path(path), Some(StorageItem::Keyfile { .. }))
+    };
+    if !paths.iter().any(is_keyfile) {
+        match key_id {
+            Some(key_id) => bail!("No keyshare of {} found for key {}", email, key_id),
+            None => bail!("No keyshares of {} found to back up", email),
+        }
+    }
+
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        let item = StorageItem::from_path(&path).ok_or_else(|| anyhow!("{} is unknown", path))?;
+        let contents = backend.read(&item)?.ok_or_else(|| anyhow!("{} was removed", path))?;
+        let encrypted = StorageKeyring::is_encrypted(&contents);
+        let contents = match encrypted {
+            true => String::from_utf8(keyring.open(&contents)?)?,
+            false => contents,
+        };
+        files.push(BackupFile { path, contents, encrypted });
+    }
+    info!("Backing up {} files of {}", files.len(), email);
+
+    let salt = get_secure_random_bytes(SALT_LEN);
+    let contents = BackupContents { created_at: Utc::now(), files };
+    let data = aes_encrypt(&serde_json::to_vec(&contents)?, &passphrase_key(passphrase, &salt)?)?;
+    Ok(BackupBundle {
+        version: BUNDLE_VERSION,
+        salt: hex::encode(salt),
+        data,
+    })
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RestoreShareResponse {
+    pub restored: Vec<String>,
+    /// Files that already exist on the node and were left alone
+    pub skipped: Vec<String>,
+}
+
+/// Validates a backup bundle and writes the files of the proven account back, keyshares are
+/// checked against their VSS commitments before anything is written
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestoreShareCommand {
+    pub bundle: BackupBundle,
+    pub passphrase: String,
+    /// Replace files that already exist instead of skipping them
+    #[serde(default)]
+    pub overwrite: bool,
+    /// Proof of the account the backup is restored for. On a node that doesn't know the account
+    /// yet it is checked against the access key in the backup.
+    pub authorization: TenantAuth,
+}
+
+impl Debug for RestoreShareCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RestoreShareCommand")
+            .field("version", &self.bundle.version)
+            .field("overwrite", &self.overwrite)
+            .field("authorization", &self.authorization)
+            .finish()
+    }
+}
+
+impl JsonCommand for RestoreShareCommand {
+    type Response = RestoreShareResponse;
+
+    /// The access key may only be in the backup, the proof is checked against it
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let contents = open_bundle(&self.bundle, &self.passphrase)?;
+        let email = self.authorization.email.as_str();
+        let key_ids: BTreeSet<&str> = contents.files
+            .iter()
+            .filter_map(|file| {
+                match StorageItem::from_path(&file.path)? {
+                    StorageItem::Keyfile { key_id, email: Some(account), .. } if account == email =>
+                        Some(key_id),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        let mut planned = Vec::with_capacity(contents.files.len());
+        let mut restored_access_key = None;
+        for file in &contents.files {
+            let item = restore_item(&file.path, email, &key_ids)?;
+            match item {
+                StorageItem::Keyfile { .. } => {
+                    verify_keyshare_plaintext(&file.contents).with_context(||
+                        format!("Keyshare {} in the backup is invalid", file.path)
+                    )?;
+                }
+                StorageItem::KeyMetadata { metadata_type: "access", .. } => {
+                    restored_access_key = Some(file.contents.as_str());
+                }
+                // An old timestamp would let requests the node already refused be replayed
+                StorageItem::KeyMetadata { metadata_type: TIMESTAMP_KEY, .. } => {
+                    continue;
+                }
+                _ => {}
+            }
+            planned.push((item, file));
+        }
+
+        let key_ids: Vec<String> = key_ids.iter().map(|key_id| key_id.to_string()).collect();
+        let node = NodeIdentity::cached()?;
+        let _scope = self.authorization.verify_restore(&key_ids, &node, restored_access_key)?;
+        tenant::ensure_account(email)?;
+
+        let keyring = StorageKeyring::load()?;
+        let backend = storage_backend()?;
+        let mut response = RestoreShareResponse { restored: Vec::new(), skipped: Vec::new() };
+        for (item, file) in planned {
+            if backend.exists(&item)? && !self.overwrite {
+                response.skipped.push(file.path.clone());
+                continue;
+            }
+            let stored = if file.encrypted {
+                keyring.seal(file.contents.as_bytes())?
+            } else {
+                file.contents.clone()
+            };
+            backend.write(&item, &stored, &WriteOpts::Modify)?;
+            if let StorageItem::Keyfile { key_id, index, email } = item {
+                let format = Keystore::deserialize_key(&file.contents)?.name();
+                KeyshareIntegrity::new(&file.contents, format).save(key_id, index, email)?;
+            }
+            response.restored.push(file.path.clone());
+        }
+        info!(
+            "Restored {} files of {} from a backup taken at {}, skipped {}",
+            response.restored.len(),
+            email,
+            contents.created_at,
+            response.skipped.len()
+        );
+        Ok(response)
+    }
+}
+
+fn open_bundle(bundle: &BackupBundle, passphrase: &str) -> Result<BackupContents> {
+    if bundle.version != BUNDLE_VERSION {
+        bail!("Unsupported backup version {}", bundle.version);
+    }
+    let salt = hex::decode(&bundle.salt).context("Backup salt is not hex")?;
+    let plaintext = aes_decrypt(&bundle.data, &passphrase_key(passphrase, &salt)?).map_err(|_|
+        anyhow!("Backup could not be decrypted, the passphrase may be wrong")
+    )?;
+    serde_json::from_slice(&plaintext).context("Backup contents are malformed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_only_files_of_the_account() {
+        let email = "user@example.com";
+        let key_ids = BTreeSet::from(["a"]);
+        let restorable = [
+            "accounts/user@example.com/keys/a/keyshare-a.json",
+            "accounts/user@example.com/keys/a/signing_policy-a",
+            "accounts/user@example.com/access_key",
+            "info--a.json",
+        ];
+        for path in restorable {
+            assert!(restore_item(path, email, &key_ids).is_ok(), "{}", path);
+        }
+        let refused = [
+            "accounts/other@example.com/keys/a/keyshare-a.json",
+            "accounts/other@example.com/access_key",
+            "info--b.json",
+            "keys--a.json",
+            "node/peer_reputation",
+            "node.json",
+            "../keys--abc.json",
+            "/etc/passwd",
+            "accounts/../node.json",
+        ];
+        for path in refused {
+            assert!(restore_item(path, email, &key_ids).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn bundles_open_only_with_their_passphrase() {
+        let salt = get_secure_random_bytes(SALT_LEN);
+        let contents = BackupContents { created_at: Utc::now(), files: Vec::new() };
+        let key = passphrase_key("correct horse battery", &salt).unwrap();
+        let bundle = BackupBundle {
+            version: BUNDLE_VERSION,
+            salt: hex::encode(salt),
+            data: aes_encrypt(&serde_json::to_vec(&contents).unwrap(), &key).unwrap(),
+        };
+        assert!(open_bundle(&bundle, "correct horse battery").is_ok());
+        assert!(open_bundle(&bundle, "wrong horse battery").is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use super::backend::storage_backend;
+use super::fs::{ FileSystem, WriteOpts };
+use super::key_metadata_store::KeyMetadataStore;
+use crate::auth::client_e2e_decrypt_secret;
+use crate::command::{ JsonCommand, MsgContext };
+use crate::config::{ Config, ConfigProvider };
+use crate::encryption::{ fill_secure_random, get_secure_random_bytes };
+use crate::node::NodeIdentity;
+use crate::signing::validation::{ check_access_key, verify_hmac_input, verify_timestamp };
+use crate::tenant::Access;
+use anyhow::{ anyhow, bail, Context, Result };
+use chrono::{ DateTime, Duration, Utc };
+use serde::{ Deserialize, Serialize };
+use std::fmt::Debug;
+use std::fs::{ self, OpenOptions };
+use std::io::Write;
+use std::path::{ Component, Path, PathBuf };
+use tracing::{ info, warn };
+
+const PENDING_DELETION_KEY: &str = "pending_deletion";
+const CONFIRMATION_WINDOW_MINUTES: i64 = 5;
+const NONCE_LEN: usize = 32;
+
+/// Deletion requested for a key, kept next to the keyshare until it is confirmed or expires
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PendingKeyDeletion {
+    pub key_id: String,
+    /// Hex nonce the confirmation has to sign
+    pub nonce: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl PendingKeyDeletion {
+    fn new(key_id: &str, now: DateTime<Utc>) -> Self {
+        Self {
+            key_id: key_id.to_string(),
+            nonce: hex::encode(get_secure_random_bytes(NONCE_LEN)),
+            expires_at: now + Duration::minutes(CONFIRMATION_WINDOW_MINUTES),
+        }
+    }
+
+    fn check(&self, key_id: &str, nonce: &str, now: DateTime<Utc>) -> Result<()> {
+        if self.key_id != key_id || self.nonce != nonce {
+            bail!("Confirmation does not match the pending deletion of key {}", key_id);
+        }
+        if now > self.expires_at {
+            bail!("Deletion of key {} expired at {}, request it again", key_id, self.expires_at);
+        }
+        Ok(())
+    }
+}
+
+/// First phase of deleting a keyshare. Nothing is removed, the node only records the request
+/// and returns the nonce a `ConfirmDeleteKeyCommand` has to sign.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DeleteKeyCommand {
+    pub key_id: String,
+    pub email: String,
+}
+
+impl JsonCommand for DeleteKeyCommand {
+    type Response = PendingKeyDeletion;
+
+    /// Nothing is removed before `ConfirmDeleteKeyCommand` proves the account
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        check_path_component(&self.key_id, "key id")?;
+        check_path_component(&self.email, "email")?;
+        if !FileSystem::keyfile_exists_with_email(&self.key_id, 0, &self.email)? {
+            bail!("Key {} is not stored for {}", self.key_id, self.email);
+        }
+
+        let pending = PendingKeyDeletion::new(&self.key_id, Utc::now());
+        KeyMetadataStore::save(
+            &serde_json::to_string(&pending)?,
+            &self.key_id,
+            PENDING_DELETION_KEY,
+            &self.email,
+            &WriteOpts::Modify
+        )?;
+        info!("Deletion of key {} requested, awaiting confirmation", self.key_id);
+        Ok(pending)
+    }
+}
+
+/// Second phase of deleting a keyshare. Authorized like a signing request with the access key of
+/// the account, the HMAC additionally covers the nonce of the pending deletion.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfirmDeleteKeyCommand {
+    pub key_id: String,
+    pub email: String,
+    pub nonce: String,
+    pub timestamp: String,
+    /// Base64 HMAC-SHA256 of `timestamp + email + nonce` with the access key
+    pub message_hmac: String,
+    pub client_e2e_public_key: String,
+    pub encrypted_signing_key: String,
+}
+
+impl Debug for ConfirmDeleteKeyCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ConfirmDeleteKeyCommand")
+            .field("key_id", &self.key_id)
+            .field("email", &self.email)
+            .field("timestamp", &self.timestamp)
+            .finish()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct KeyDeletionResponse {
+    pub key_id: String,
+    pub erased_files: usize,
+}
+
+impl JsonCommand for ConfirmDeleteKeyCommand {
+    type Response = KeyDeletionResponse;
+
+    fn access(&self) -> Access<'_> {
+        Access::Checked
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        check_path_component(&self.key_id, "key id")?;
+        check_path_component(&self.email, "email")?;
+        let pending: PendingKeyDeletion = serde_json::from_str(
+            &KeyMetadataStore::get(&self.key_id, PENDING_DELETION_KEY, &self.email).map_err(
+                |_| anyhow!("No deletion is pending for key {}", self.key_id)
+            )?
+        )?;
+        pending.check(&self.key_id, &self.nonce, Utc::now())?;
+
+        let node = NodeIdentity::cached()?;
+        let node_signing_key = client_e2e_decrypt_secret(
+            &self.encrypted_signing_key,
+            &node.e2e_private_key,
+            &self.client_e2e_public_key
+        )?;
+        let message_input = format!("{}{}{}", self.timestamp, self.email, self.nonce);
+        if !verify_hmac_input(&self.message_hmac, &message_input, &node_signing_key) {
+            bail!("HMAC verification failed");
+        }
+        if !verify_timestamp(&self.key_id, &self.timestamp, &self.email) {
+            bail!("Timestamp verification failed");
+        }
+        check_access_key(&self.key_id, &self.email, &node_signing_key)?;
+
+        let erased_files = erase_key(&self.key_id, &self.email)?;
+        info!("Erased key {} ({} files)", self.key_id, erased_files);
+        Ok(KeyDeletionResponse { key_id: self.key_id, erased_files })
+    }
+}
+
+/// Key ids and emails become directory names, so they have to be a single plain path component
+fn check_path_component(value: &str, name: &str) -> Result<()> {
+    let mut components = Path::new(value).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => bail!("Invalid {} {:?}", name, value),
+    }
+}
+
+/// Erases the key directory of the account and the key info and protocol record of the key. The
+/// account wide access key is kept, other keys of the account still use it.
+fn erase_key(key_id: &str, email: &str) -> Result<usize> {
+    let backend = storage_backend()?;
+    if backend.name() != "filesystem" {
+        bail!("Keys can only be erased from the filesystem backend, not from {}", backend.name());
+    }
+    let key_directory = FileSystem::key_directory_with_email(key_id, email);
+    let mut files = fs
+        ::read_dir(&key_directory)
+        .with_context(|| format!("Failed to read {}", key_directory.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    files.retain(|path| path.is_file());
+    let info_path = Config::get_key_info_storage_path(key_id);
+    if info_path.exists() {
+        files.push(info_path);
+    }
+    let protocol_path = FileSystem::key_protocol_path(key_id);
+    if protocol_path.exists() {
+        files.push(protocol_path);
+    }
+
+    for path in &files {
+        secure_erase_file(path)?;
+    }
+    if let Err(err) = fs::remove_dir_all(&key_directory) {
+        warn!("Failed to remove key directory {}: {}", key_directory.display(), err);
+    }
+    Ok(files.len())
+}
+
+/// Overwrites the file with random bytes and flushes it to disk before unlinking it
+fn secure_erase_file(path: &Path) -> Result<()> {
+    let len = fs::metadata(path)?.len() as usize;
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let mut noise = vec![0u8; len];
+    fill_secure_random(&mut noise);
+    file.write_all(&noise)?;
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirmation_has_to_match_an_unexpired_request() {
+        let now = Utc::now();
+        let pending = PendingKeyDeletion::new("key", now);
+        assert!(pending.check("key", &pending.nonce, now).is_ok());
+        assert!(pending.check("other", &pending.nonce, now).is_err());
+        assert!(pending.check("key", "00", now).is_err());
+        let late = now + Duration::minutes(CONFIRMATION_WINDOW_MINUTES + 1);
+        assert!(pending.check("key", &pending.nonce, late).is_err());
+
+        assert!(check_path_component("user@example.com", "email").is_ok());
+        assert!(check_path_component("../other", "email").is_err());
+        assert!(check_path_component("a/b", "key id").is_err());
+        assert!(check_path_component("", "key id").is_err());
+    }
+}
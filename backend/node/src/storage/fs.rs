@@ -1,4 +1,5 @@
 use crate::config::{ Config, ConfigProvider };
+use crate::storage::backend::{ storage_backend, StorageItem };
 use anyhow::{ anyhow, bail, Result };
 use glob::glob;
 use regex::Regex;
@@ -22,14 +23,8 @@ impl FileSystem {
         content: &str,
         write_access: &WriteOpts
     ) -> Result<()> {
-        let filepath = Config::get_key_storage_path(key_id, index);
-
-        if write_access == &WriteOpts::CreateNewOnly && filepath.exists() {
-            bail!("Tried to write to a keyfile that already exists");
-        }
-
-        fs::write(filepath, content)?;
-        Ok(())
+        let item = StorageItem::Keyfile { key_id, index, email: None };
+        storage_backend()?.write(&item, content, write_access)
     }
 
     pub fn add_keyfile_with_email(
@@ -39,72 +34,69 @@ impl FileSystem {
         content: &str,
         write_access: &WriteOpts
     ) -> Result<()> {
-        // Ensure the account directory exists
-        Self::ensure_account_directory_exists(email, Some(key_id))?;
-
-        // Create path for the keyfile in the user's directory
-        let mut filepath = Config::get_gridlock_directory();
-        filepath.push("accounts");
-        filepath.push(email);
-        filepath.push("keys");
-        filepath.push(key_id);
-
-        if index == 0 {
-            filepath.push(format!("keyshare-{}.json", key_id));
-        } else {
-            filepath.push(format!("keyshare-{}-{}.json", key_id, index));
-        }
+        let item = StorageItem::Keyfile { key_id, index, email: Some(email) };
+        storage_backend()?.write(&item, content, write_access)
+    }
 
-        if write_access == &WriteOpts::CreateNewOnly && filepath.exists() {
-            bail!("Tried to write to a keyfile that already exists");
-        }
+    pub fn add_key_info_file(key_id: &str, content: &str, write_access: &WriteOpts) -> Result<()> {
+        storage_backend()?.write(&StorageItem::KeyInfo { key_id }, content, write_access)
+    }
 
-        fs::write(filepath, content)?;
-        Ok(())
+    pub fn read_keyfile(key_id: &str, index: usize) -> Result<String> {
+        let item = StorageItem::Keyfile { key_id, index, email: None };
+        storage_backend()?
+            .read(&item)?
+            .ok_or_else(|| anyhow!("Keyfile not found for key_id: {}, index: {}", key_id, index))
     }
 
-    pub fn add_key_info_file(key_id: &str, content: &str, write_access: &WriteOpts) -> Result<()> {
-        let filepath = Config::get_key_info_storage_path(key_id);
+    pub fn read_keyfile_with_email(key_id: &str, index: usize, email: &str) -> Result<String> {
+        let item = StorageItem::Keyfile { key_id, index, email: Some(email) };
+        storage_backend()?
+            .read(&item)?
+            .ok_or_else(|| {
+                anyhow!(
+                    "Keyfile not found for key_id: {}, index: {}, email: {}",
+                    key_id,
+                    index,
+                    email
+                )
+            })
+    }
 
-        if write_access == &WriteOpts::CreateNewOnly && filepath.exists() {
-            bail!("Tried to write key info that already exists");
+    /// Whether the keyfile is stored in the account directory of the email or in the flat layout
+    pub fn keyfile_exists(key_id: &str, index: usize, email: Option<&str>) -> Result<bool> {
+        if let Some(email) = email {
+            if Self::keyfile_exists_with_email(key_id, index, email)? {
+                return Ok(true);
+            }
         }
-
-        fs::write(filepath, content)?;
-        Ok(())
+        storage_backend()?.exists(&StorageItem::Keyfile { key_id, index, email: None })
     }
 
-    pub fn read_keyfile(key_id: &str, index: usize) -> Result<String> {
-        let filename = Config::get_key_storage_path(key_id, index);
-        let kf = fs::read_to_string(filename)?;
-        Ok(kf)
+    pub fn keyfile_exists_with_email(key_id: &str, index: usize, email: &str) -> Result<bool> {
+        storage_backend()?.exists(&StorageItem::Keyfile { key_id, index, email: Some(email) })
     }
 
-    pub fn find_keyfile_with_email(key_id: &str, index: usize, email: &str) -> Result<PathBuf> {
-        // Build path for the keyfile in the account directory
+    /// Directory holding the keyshare and key metadata of one key of an account, only used by the
+    /// filesystem backend
+    pub fn key_directory_with_email(key_id: &str, email: &str) -> PathBuf {
         let mut filepath = Config::get_gridlock_directory();
         filepath.push("accounts");
         filepath.push(email);
         filepath.push("keys");
         filepath.push(key_id);
+        filepath
+    }
 
-        if index == 0 {
-            filepath.push(format!("keyshare-{}.json", key_id));
-        } else {
-            filepath.push(format!("keyshare-{}-{}.json", key_id, index));
-        }
-
-        if !filepath.exists() {
-            bail!("Keyfile not found for key_id: {}, index: {}, email: {}", key_id, index, email);
-        }
-
-        Ok(filepath)
+    /// File recording the protocol version of a key, only used by the filesystem backend
+    pub fn key_protocol_path(key_id: &str) -> PathBuf {
+        Config::get_gridlock_directory().join((StorageItem::KeyProtocol { key_id }).path())
     }
 
     pub fn read_key_info_file(key_id: &str) -> Result<String> {
-        let filename = Config::get_key_info_storage_path(key_id);
-        let kf = fs::read_to_string(filename)?;
-        Ok(kf)
+        storage_backend()?
+            .read(&StorageItem::KeyInfo { key_id })?
+            .ok_or_else(|| anyhow!("Key info not found for key_id: {}", key_id))
     }
 
     pub fn save_node_identity(node_params: &str) -> Result<()> {
@@ -122,8 +114,11 @@ impl FileSystem {
     }
 
     pub fn find_all_key_ids() -> Result<Vec<String>> {
-        let keyfiles = Self::find_all_key_files()?;
-        let key_ids = keyfiles.iter().filter_map(Self::file_path_to_key_id).collect();
+        let key_ids = storage_backend()?
+            .list("keys--")?
+            .into_iter()
+            .filter_map(|path| Self::file_path_to_key_id(&PathBuf::from(path)))
+            .collect();
         Ok(key_ids)
     }
 
@@ -142,6 +137,37 @@ impl FileSystem {
         Ok(results)
     }
 
+    /// Every keyshare file in the storage directory, both in the flat layout and in account
+    /// directories. Items of other storage backends are not files and are not included.
+    pub fn find_all_keyshare_files() -> Result<Vec<PathBuf>> {
+        let mut keyshare_files = Self::find_all_key_files()?;
+        let mut search_path = Config::get_gridlock_directory();
+        search_path.push("accounts/*/keys/*/keyshare-*.json");
+        let search_term = search_path.to_str().ok_or(anyhow!("Could not create search"))?;
+        keyshare_files.extend(glob(search_term)?.filter_map(Result::ok));
+        Ok(keyshare_files)
+    }
+
+    /// Every key and user level metadata file in the account directories, keyshares excluded
+    pub fn find_all_metadata_files() -> Result<Vec<PathBuf>> {
+        let mut metadata_files = Vec::new();
+        for pattern in ["accounts/*/*", "accounts/*/keys/*/*"] {
+            let mut search_path = Config::get_gridlock_directory();
+            search_path.push(pattern);
+            let search_term = search_path.to_str().ok_or(anyhow!("Could not create search"))?;
+            metadata_files.extend(
+                glob(search_term)?
+                    .filter_map(Result::ok)
+                    .filter(|path| path.is_file())
+                    .filter(|path| {
+                        let file_name = path.file_name().and_then(|name| name.to_str());
+                        !file_name.is_some_and(|name| name.starts_with("keyshare-"))
+                    })
+            );
+        }
+        Ok(metadata_files)
+    }
+
     fn file_path_to_key_id(filepath: &PathBuf) -> Option<String> {
         let re = Regex::new(r"keys--(.*).json$").ok()?;
         filepath
@@ -151,54 +177,6 @@ impl FileSystem {
             .map(|key_id| String::from(key_id.as_str()))
     }
 
-    // Helper function to get the key metadata file path
-    fn get_key_metadata_file_path(key_id: &str, metadata_type: &str, email: &str) -> PathBuf {
-        // Build the path based on the structure
-        let mut filepath = Config::get_gridlock_directory();
-        filepath.push("accounts");
-        filepath.push(email);
-
-        if metadata_type == "access" {
-            // access_key is stored directly in the email folder
-            filepath.push("access_key");
-        } else {
-            // Other types go in the keys/keyId directory
-            filepath.push("keys");
-            filepath.push(key_id);
-
-            // Format the filename based on the metadata type
-            if metadata_type == "keys" {
-                filepath.push(format!("keyshare-{}.json", key_id));
-            } else {
-                filepath.push(format!("{}-{}", metadata_type, key_id));
-            }
-        }
-
-        filepath
-    }
-
-    // Helper to ensure account directory structure exists
-    fn ensure_account_directory_exists(email: &str, key_id: Option<&str>) -> Result<()> {
-        let mut filepath = Config::get_gridlock_directory();
-        filepath.push("accounts");
-        fs::create_dir_all(&filepath)?;
-
-        filepath.push(email);
-        fs::create_dir_all(&filepath)?;
-
-        // Create the keys directory
-        filepath.push("keys");
-        fs::create_dir_all(&filepath)?;
-
-        // If a key_id is provided, create the key-specific directory
-        if let Some(key_id) = key_id {
-            filepath.push(key_id);
-            fs::create_dir_all(&filepath)?;
-        }
-
-        Ok(())
-    }
-
     pub fn add_key_metadata_file(
         key_id: &str,
         metadata_type: &str,
@@ -206,19 +184,8 @@ impl FileSystem {
         email: &str,
         write_access: &WriteOpts
     ) -> Result<()> {
-        let filepath = Self::get_key_metadata_file_path(key_id, metadata_type, email);
-
-        // Ensure the directory exists
-        if let Some(parent) = filepath.parent() {
-            fs::create_dir_all(parent)?;
-        }
-
-        if write_access == &WriteOpts::CreateNewOnly && filepath.exists() {
-            bail!("Tried to write key metadata that already exists");
-        }
-
-        fs::write(filepath, content)?;
-        Ok(())
+        let item = StorageItem::KeyMetadata { key_id, metadata_type, email };
+        storage_backend()?.write(&item, content, write_access)
     }
 
     pub fn read_key_metadata_file(
@@ -226,28 +193,23 @@ impl FileSystem {
         metadata_type: &str,
         email: &str
     ) -> Result<String> {
-        let filepath = Self::get_key_metadata_file_path(key_id, metadata_type, email);
-
-        if !filepath.exists() {
-            bail!("Metadata file not found for key_id: {}, type: {}", key_id, metadata_type);
-        }
-
-        let content = fs::read_to_string(filepath)?;
-        Ok(content)
+        let item = StorageItem::KeyMetadata { key_id, metadata_type, email };
+        storage_backend()?
+            .read(&item)?
+            .ok_or_else(|| {
+                anyhow!("Metadata file not found for key_id: {}, type: {}", key_id, metadata_type)
+            })
     }
 
     pub fn remove_key_metadata_file(key_id: &str, metadata_type: &str, email: &str) -> Result<()> {
-        let filepath = Self::get_key_metadata_file_path(key_id, metadata_type, email);
-
-        if !filepath.exists() {
+        let item = StorageItem::KeyMetadata { key_id, metadata_type, email };
+        if !storage_backend()?.remove(&item)? {
             bail!(
                 "Key metadata file does not exist for id `{}` and type `{}`",
                 key_id,
                 metadata_type
             );
         }
-
-        fs::remove_file(filepath)?;
         Ok(())
     }
 
@@ -255,18 +217,6 @@ impl FileSystem {
         Ok(Config::get_gridlock_directory())
     }
 
-    // Get the file path for user metadata
-    fn get_user_metadata_file_path(metadata_type: &str, email: &str) -> PathBuf {
-        let mut filepath = Config::get_gridlock_directory();
-        filepath.push("accounts");
-        filepath.push(email);
-
-        // Add the metadata type as the filename
-        filepath.push(metadata_type);
-
-        filepath
-    }
-
     // Function to add user metadata files
     pub fn add_user_metadata_file(
         metadata_type: &str,
@@ -274,44 +224,38 @@ impl FileSystem {
         email: &str,
         write_access: &WriteOpts
     ) -> Result<()> {
-        let filepath = Self::get_user_metadata_file_path(metadata_type, email);
-
-        // Ensure the directory exists
-        if let Some(parent) = filepath.parent() {
-            fs::create_dir_all(parent)?;
-        }
-
-        if write_access == &WriteOpts::CreateNewOnly && filepath.exists() {
-            bail!("Tried to write user metadata that already exists");
-        }
-
-        fs::write(filepath, content)?;
-        Ok(())
+        let item = StorageItem::UserMetadata { metadata_type, email };
+        storage_backend()?.write(&item, content, write_access)
     }
 
     // Function to read user metadata files
     pub fn read_user_metadata_file(metadata_type: &str, email: &str) -> Result<String> {
-        let filepath = Self::get_user_metadata_file_path(metadata_type, email);
-
-        if !filepath.exists() {
-            bail!("User metadata file not found for type: {}", metadata_type);
-        }
-
-        let content = fs::read_to_string(filepath)?;
-        Ok(content)
+        storage_backend()?
+            .read(&StorageItem::UserMetadata { metadata_type, email })?
+            .ok_or_else(|| anyhow!("User metadata file not found for type: {}", metadata_type))
     }
 
     // Function to remove user metadata files
     pub fn remove_user_metadata_file(metadata_type: &str, email: &str) -> Result<()> {
-        let filepath = Self::get_user_metadata_file_path(metadata_type, email);
-
-        if !filepath.exists() {
+        if !storage_backend()?.remove(&(StorageItem::UserMetadata { metadata_type, email }))? {
             bail!("User metadata file does not exist for type: {}", metadata_type);
         }
-
-        fs::remove_file(filepath)?;
         Ok(())
     }
+
+    pub fn add_node_metadata_file(
+        metadata_type: &str,
+        content: &str,
+        write_access: &WriteOpts
+    ) -> Result<()> {
+        let item = StorageItem::NodeMetadata { metadata_type };
+        storage_backend()?.write(&item, content, write_access)
+    }
+
+    /// Contents of node metadata, `None` if it was never written
+    pub fn read_node_metadata_file(metadata_type: &str) -> Result<Option<String>> {
+        storage_backend()?.read(&StorageItem::NodeMetadata { metadata_type })
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,230 @@
+use crate::command::{ JsonCommand, MsgContext };
+use crate::config::{ Config, ConfigProvider };
+use crate::signing::validation::last_authorized_timestamp;
+use crate::storage::backend::{ storage_backend, StorageItem };
+use crate::storage::key_protocol::{ KeyProtocol, KeyProtocolStore };
+use crate::storage::key_store::KeyshareFormat;
+use crate::storage::{ CurrentKeyshareFormat, KeyInfoStore, KeyshareAccessor };
+use crate::storage::{ Frost, Sr25519, BLS, ECDSA, EDDSA };
+use crate::tenant::{ Access, TenantAuth };
+use anyhow::{ bail, Result };
+use chrono::{ DateTime, Utc };
+use serde::{ Deserialize, Serialize };
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::fs;
+use tracing::warn;
+
+/// Every key the node holds a share of, in the flat layout and in account directories
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ListKeysCommand {
+    /// Required on multi user nodes, only the keys of the proven account are listed then
+    #[serde(default)]
+    pub authorization: Option<TenantAuth>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct KeyListing {
+    pub key_id: String,
+    /// Account the key is stored for, `None` for keys in the flat layout
+    pub email: Option<String>,
+    pub protocol: KeyProtocol,
+    pub curve: String,
+    /// Party index of the primary share
+    pub party_index: usize,
+    pub threshold: usize,
+    /// Shares of the key held by this node, more than one for keys with extra shares
+    pub share_count: usize,
+    /// Only known for keys in the filesystem backend
+    pub created_at: Option<DateTime<Utc>>,
+    /// Timestamp of the last signing request the owner authorized
+    pub last_signed_at: Option<DateTime<Utc>>,
+    pub has_key_info: bool,
+}
+
+impl JsonCommand for ListKeysCommand {
+    type Response = Vec<KeyListing>;
+
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: self.authorization.as_ref(), key_ids: Vec::new() }
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        list_keys(ctx.tenant().as_deref())
+    }
+}
+
+/// Lists the keys of an account, or every key without one. Keys whose share can't be read are
+/// skipped with a warning.
+pub fn list_keys(tenant: Option<&str>) -> Result<Vec<KeyListing>> {
+    let mut listings = Vec::new();
+    for ((key_id, email), share_count) in stored_keyshares()? {
+        if tenant.is_some() && email.as_deref() != tenant {
+            continue;
+        }
+        match key_listing(&key_id, email.as_deref(), share_count) {
+            Ok(listing) => listings.push(listing),
+            Err(err) => warn!("Skipping key {} in the key list: {}", key_id, err),
+        }
+    }
+    Ok(listings)
+}
+
+/// Number of keyshares stored for every key, by key id and account
+fn stored_keyshares() -> Result<BTreeMap<(String, Option<String>), usize>> {
+    let mut keyshares = BTreeMap::new();
+    for path in stored_keyshare_paths()? {
+        if let Some(key) = parse_keyshare_path(&path) {
+            *keyshares.entry(key).or_insert(0) += 1;
+        }
+    }
+    Ok(keyshares)
+}
+
+/// Accounts holding a keyshare of the key, empty for keys only stored in the flat layout
+pub(crate) fn key_accounts(key_id: &str) -> Result<Vec<String>> {
+    let accounts = stored_keyshares()?
+        .into_keys()
+        .filter(|(stored_key_id, _)| stored_key_id == key_id)
+        .filter_map(|(_, email)| email)
+        .collect();
+    Ok(accounts)
+}
+
+/// Paths of every keyshare in the storage backend, see `StorageItem::path`
+pub(crate) fn stored_keyshare_paths() -> Result<Vec<String>> {
+    let backend = storage_backend()?;
+    let paths = backend
+        .list("keys--")?
+        .into_iter()
+        .chain(backend.list("accounts/")?)
+        .filter(|path| parse_keyshare_file(path).is_some())
+        .collect();
+    Ok(paths)
+}
+
+/// Key id and account of a keyshare path, see `StorageItem::path`
+pub(crate) fn parse_keyshare_path(path: &str) -> Option<(String, Option<String>)> {
+    parse_keyshare_file(path).map(|(key_id, _, email)| (key_id, email))
+}
+
+/// Key id, share index and account of a keyshare path
+pub(crate) fn parse_keyshare_file(path: &str) -> Option<(String, usize, Option<String>)> {
+    match StorageItem::from_path(path)? {
+        StorageItem::Keyfile { key_id, index, email } =>
+            Some((key_id.to_string(), index, email.map(str::to_string))),
+        _ => None,
+    }
+}
+
+fn key_listing(key_id: &str, email: Option<&str>, share_count: usize) -> Result<KeyListing> {
+    let (protocol, party_index, threshold) = share_params(key_id, email)?;
+    Ok(KeyListing {
+        key_id: key_id.to_string(),
+        email: email.map(str::to_string),
+        protocol,
+        curve: curve(protocol).to_string(),
+        party_index,
+        threshold,
+        share_count,
+        created_at: created_at(key_id, email),
+        last_signed_at: email.and_then(|email| last_authorized_timestamp(key_id, email)),
+        has_key_info: KeyInfoStore::get_key_info(key_id).is_ok(),
+    })
+}
+
+/// Protocol, party index and threshold of the primary share. Keys generated before protocols
+/// were recorded are read as each format in turn.
+fn share_params(key_id: &str, email: Option<&str>) -> Result<(KeyProtocol, usize, usize)> {
+    let protocols = match KeyProtocolStore::get(key_id)? {
+        Some(version) => vec![version.protocol],
+        None =>
+            vec![
+                KeyProtocol::GG2020,
+                KeyProtocol::EdDSA,
+                KeyProtocol::Frost,
+                KeyProtocol::BLS,
+                KeyProtocol::Sr25519
+            ],
+    };
+    let mut errors = Vec::new();
+    for protocol in protocols {
+        let params = match protocol {
+            KeyProtocol::GG2020 => read::<ECDSA>(key_id, email, |k| (k.party_index, k.threshold)),
+            KeyProtocol::EdDSA => read::<EDDSA>(key_id, email, |k| (k.party_index, k.threshold)),
+            KeyProtocol::Sr25519 => {
+                read::<Sr25519>(key_id, email, |k| (k.party_index, k.threshold))
+            }
+            KeyProtocol::Frost => read::<Frost>(key_id, email, |k| (k.party_index, k.threshold)),
+            KeyProtocol::BLS => read::<BLS>(key_id, email, |k| (k.party_index, k.threshold)),
+        };
+        match params {
+            Ok((party_index, threshold)) => {
+                return Ok((protocol, party_index, threshold));
+            }
+            Err(err) => errors.push(format!("{}: {}", protocol, err)),
+        }
+    }
+    bail!("Could not read the keyshare: {}", errors.join(", "))
+}
+
+fn read<K>(
+    key_id: &str,
+    email: Option<&str>,
+    params: fn(&K) -> (usize, usize)
+) -> Result<(usize, usize)>
+    where K: CurrentKeyshareFormat, <K as TryFrom<KeyshareFormat>>::Error: Display
+{
+    let accessor = match email {
+        Some(email) => KeyshareAccessor::<K>::read_only_with_email(key_id, email)?,
+        None => KeyshareAccessor::<K>::read_only(key_id)?,
+    };
+    Ok(params(&accessor.key))
+}
+
+fn curve(protocol: KeyProtocol) -> &'static str {
+    match protocol {
+        KeyProtocol::GG2020 | KeyProtocol::Frost => "secp256k1",
+        KeyProtocol::EdDSA => "ed25519",
+        KeyProtocol::Sr25519 => "ristretto255",
+        KeyProtocol::BLS => "bls12-381",
+    }
+}
+
+/// Creation time of the primary keyshare file, other backends don't keep one
+fn created_at(key_id: &str, email: Option<&str>) -> Option<DateTime<Utc>> {
+    if storage_backend().ok()?.name() != "filesystem" {
+        return None;
+    }
+    let item = StorageItem::Keyfile { key_id, index: 0, email };
+    let metadata = fs::metadata(Config::get_gridlock_directory().join(item.path())).ok()?;
+    let created = metadata.created().or_else(|_| metadata.modified()).ok()?;
+    Some(created.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keyshare_paths_of_both_layouts() {
+        let key_id = "0f64e0eb-ed88-454c-97d9-ad112a5ac267";
+        let email = "alice@example.com";
+        for index in [0, 2] {
+            for email in [None, Some(email)] {
+                let path = (StorageItem::Keyfile { key_id, index, email }).path();
+                assert_eq!(
+                    parse_keyshare_file(&path),
+                    Some((key_id.to_string(), index, email.map(str::to_string)))
+                );
+            }
+        }
+        let metadata = StorageItem::KeyMetadata { key_id, metadata_type: "timestamp", email };
+        assert_eq!(parse_keyshare_path(&metadata.path()), None);
+        assert_eq!(parse_keyshare_path(&format!("accounts/{}/access_key", email)), None);
+        let integrity = StorageItem::KeyshareIntegrity { key_id, index: 2, email: Some(email) };
+        assert_eq!(parse_keyshare_path(&integrity.path()), None);
+    }
+}
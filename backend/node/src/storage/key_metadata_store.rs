@@ -1,8 +1,43 @@
+use crate::storage::backend::{ storage_backend, StorageItem };
 use crate::storage::fs::{ FileSystem, WriteOpts };
+use crate::storage::storage_key::StorageKeyring;
+use crate::session_manager;
 use anyhow::Result;
+use chrono::{ DateTime, Utc };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use tracing::{ info, warn };
+
+const USAGE_KEY: &str = "usage";
+/// Bytes of the message hash kept with the usage record, enough to tell signatures apart
+const MESSAGE_HASH_PREFIX_LEN: usize = 8;
+
+/// How often and how recently this guardian co-signed with a key
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct KeyUsage {
+    pub signature_count: u64,
+    pub last_signed_at: DateTime<Utc>,
+    /// Hex prefix of the SHA-256 of the last signed message
+    pub last_message_hash_prefix: String,
+}
+
+impl KeyUsage {
+    fn record(previous: Option<KeyUsage>, message: &[u8], signed_at: DateTime<Utc>) -> Self {
+        KeyUsage {
+            signature_count: previous.map_or(0, |usage| usage.signature_count) + 1,
+            last_signed_at: signed_at,
+            last_message_hash_prefix: hex::encode(
+                &Sha256::digest(message)[..MESSAGE_HASH_PREFIX_LEN]
+            ),
+        }
+    }
+}
 
 /// Store for key-related metadata that isn't a KeyInfo object
 /// Handles string-based data like access tokens, recovery codes, emails, etc.
+///
+/// Metadata is encrypted at rest with the node storage key used for keyshares. Files written in
+/// plaintext before are encrypted the first time they are read.
 pub struct KeyMetadataStore;
 
 impl KeyMetadataStore {
@@ -14,12 +49,22 @@ impl KeyMetadataStore {
         email: &str,
         write_access: &WriteOpts
     ) -> Result<()> {
-        FileSystem::add_key_metadata_file(key_id, metadata_type, content, email, write_access)
+        let sealed = StorageKeyring::load()?.seal(content.as_bytes())?;
+        FileSystem::add_key_metadata_file(key_id, metadata_type, &sealed, email, write_access)
     }
 
     /// Get key-specific metadata
     pub fn get(key_id: &str, metadata_type: &str, email: &str) -> Result<String> {
-        FileSystem::read_key_metadata_file(key_id, metadata_type, email)
+        let stored = FileSystem::read_key_metadata_file(key_id, metadata_type, email)?;
+        let (content, plaintext) = open(&StorageKeyring::load()?, &stored)?;
+        if plaintext {
+            info!("Encrypting plaintext {} metadata of key {}", metadata_type, key_id);
+            let migrated = Self::save(&content, key_id, metadata_type, email, &WriteOpts::Modify);
+            if let Err(err) = migrated {
+                warn!("Failed to encrypt {} metadata of key {}: {}", metadata_type, key_id, err);
+            }
+        }
+        Ok(content)
     }
 
     /// Remove key-specific metadata
@@ -27,6 +72,29 @@ impl KeyMetadataStore {
         FileSystem::remove_key_metadata_file(key_id, metadata_type, email)
     }
 
+    /// Usage record of a key, `None` before its first signature
+    pub fn get_usage(key_id: &str, email: &str) -> Result<Option<KeyUsage>> {
+        let item = StorageItem::KeyMetadata { key_id, metadata_type: USAGE_KEY, email };
+        if !storage_backend()?.exists(&item)? {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&Self::get(key_id, USAGE_KEY, email)?)?))
+    }
+
+    /// Counts a successful signature with the key. A failure to write is logged, the signature
+    /// was already produced.
+    pub fn record_usage(key_id: &str, email: &str, message: &[u8]) {
+        let recorded = session_manager::lock_key(key_id).and_then(|_lock| {
+            let previous = Self::get_usage(key_id, email)?;
+            let usage = KeyUsage::record(previous, message, Utc::now());
+            let content = serde_json::to_string(&usage)?;
+            Self::save(&content, key_id, USAGE_KEY, email, &WriteOpts::Modify)
+        });
+        if let Err(err) = recorded {
+            warn!("Failed to record the usage of key {}: {}", key_id, err);
+        }
+    }
+
     /// Save user metadata
     pub fn save_user_level(
         content: &str,
@@ -34,16 +102,76 @@ impl KeyMetadataStore {
         email: &str,
         write_access: &WriteOpts
     ) -> Result<()> {
-        FileSystem::add_user_metadata_file(metadata_type, content, email, write_access)
+        let sealed = StorageKeyring::load()?.seal(content.as_bytes())?;
+        FileSystem::add_user_metadata_file(metadata_type, &sealed, email, write_access)
     }
 
     /// Get user metadata
     pub fn get_user_level(metadata_type: &str, email: &str) -> Result<String> {
-        FileSystem::read_user_metadata_file(metadata_type, email)
+        let stored = FileSystem::read_user_metadata_file(metadata_type, email)?;
+        let (content, plaintext) = open(&StorageKeyring::load()?, &stored)?;
+        if plaintext {
+            info!("Encrypting plaintext {} user metadata", metadata_type);
+            let modify = &WriteOpts::Modify;
+            if let Err(err) = Self::save_user_level(&content, metadata_type, email, modify) {
+                warn!("Failed to encrypt {} user metadata: {}", metadata_type, err);
+            }
+        }
+        Ok(content)
     }
 
     /// Remove user metadata
     pub fn remove_user_level(metadata_type: &str, email: &str) -> Result<()> {
         FileSystem::remove_user_metadata_file(metadata_type, email)
     }
+
+    /// Save metadata of the node itself
+    pub fn save_node_level(
+        content: &str,
+        metadata_type: &str,
+        write_access: &WriteOpts
+    ) -> Result<()> {
+        let sealed = StorageKeyring::load()?.seal(content.as_bytes())?;
+        FileSystem::add_node_metadata_file(metadata_type, &sealed, write_access)
+    }
+
+    /// Get metadata of the node itself, `None` if it was never saved
+    pub fn get_node_level(metadata_type: &str) -> Result<Option<String>> {
+        match FileSystem::read_node_metadata_file(metadata_type)? {
+            Some(stored) => Ok(Some(open(&StorageKeyring::load()?, &stored)?.0)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Contents of a metadata file and whether it was still stored in plaintext
+fn open(keyring: &StorageKeyring, stored: &str) -> Result<(String, bool)> {
+    if !StorageKeyring::is_encrypted(stored) {
+        return Ok((stored.to_string(), true));
+    }
+    Ok((String::from_utf8(keyring.open(stored)?)?, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::storage_key::StorageKey;
+
+    #[test]
+    fn opens_sealed_and_legacy_plaintext_metadata() {
+        let keyring = StorageKeyring::new(StorageKey::from_key_bytes(vec![7; 32]), vec![]);
+        let sealed = keyring.seal(b"client-e2e-public-key").unwrap();
+        assert!(!sealed.contains("client-e2e-public-key"));
+        assert_eq!(open(&keyring, &sealed).unwrap(), ("client-e2e-public-key".to_string(), false));
+        assert_eq!(open(&keyring, "1700000000").unwrap(), ("1700000000".to_string(), true));
+    }
+
+    #[test]
+    fn counts_signatures_and_keeps_the_last_one() {
+        let first = KeyUsage::record(None, b"first", Utc::now());
+        let second = KeyUsage::record(Some(first.clone()), b"second", Utc::now());
+        assert_eq!((first.signature_count, second.signature_count), (1, 2));
+        assert_eq!(second.last_message_hash_prefix.len(), MESSAGE_HASH_PREFIX_LEN * 2);
+        assert_ne!(first.last_message_hash_prefix, second.last_message_hash_prefix);
+    }
 }
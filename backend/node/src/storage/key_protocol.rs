@@ -0,0 +1,149 @@
+use crate::storage::backend::{ storage_backend, StorageItem };
+use crate::storage::fs::WriteOpts;
+use anyhow::{ Context, Result };
+use derive_more::Display;
+use serde::{ Deserialize, Serialize };
+use std::error::Error;
+use std::fmt;
+
+/// Threshold protocol a keyshare was generated with. Shares of different protocols can't take
+/// part in the same signing or recovery session even when their curve matches.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Display, PartialEq, Eq)]
+pub enum KeyProtocol {
+    /// ECDSA over secp256k1, GG18 keygen with the GG2020 signing flow
+    GG2020,
+    /// Threshold EdDSA over ed25519
+    EdDSA,
+    Sr25519,
+    /// FROST Schnorr over secp256k1
+    Frost,
+    BLS,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ProtocolVersion {
+    pub protocol: KeyProtocol,
+    pub version: u32,
+}
+
+impl ProtocolVersion {
+    pub const GG2020_V1: ProtocolVersion = ProtocolVersion::v1(KeyProtocol::GG2020);
+    pub const EDDSA_V1: ProtocolVersion = ProtocolVersion::v1(KeyProtocol::EdDSA);
+    pub const SR25519_V1: ProtocolVersion = ProtocolVersion::v1(KeyProtocol::Sr25519);
+    pub const FROST_V1: ProtocolVersion = ProtocolVersion::v1(KeyProtocol::Frost);
+    pub const BLS_V1: ProtocolVersion = ProtocolVersion::v1(KeyProtocol::BLS);
+
+    const fn v1(protocol: KeyProtocol) -> Self {
+        ProtocolVersion { protocol, version: 1 }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} v{}", self.protocol, self.version)
+    }
+}
+
+/// A session asked for a protocol or version other than the one the key was generated with
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProtocolMismatch {
+    pub key_id: String,
+    pub stored: ProtocolVersion,
+    pub requested: ProtocolVersion,
+}
+
+impl fmt::Display for ProtocolMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Key {} was generated with {}, the session runs {}",
+            self.key_id,
+            self.stored,
+            self.requested
+        )
+    }
+}
+
+impl Error for ProtocolMismatch {}
+
+/// What a session declares about the key it uses: the protocol its route runs, if the route is
+/// tied to one, and the exact version, if the sender states it
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SessionProtocol {
+    pub protocol: Option<KeyProtocol>,
+    pub version: Option<ProtocolVersion>,
+}
+
+impl SessionProtocol {
+    /// Compares the declaration against what the key was generated with
+    pub fn check(&self, key_id: &str, stored: ProtocolVersion) -> Result<(), ProtocolMismatch> {
+        let requested = match (self.version, self.protocol) {
+            (Some(version), _) => version,
+            (None, Some(protocol)) => ProtocolVersion { protocol, version: stored.version },
+            (None, None) => {
+                return Ok(());
+            }
+        };
+        let protocol_differs = self.protocol.is_some_and(|protocol| protocol != stored.protocol);
+        if requested != stored || protocol_differs {
+            return Err(ProtocolMismatch {
+                key_id: key_id.to_string(),
+                stored,
+                requested,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Protocol version of each key, written whenever one of its keyshares is saved
+pub struct KeyProtocolStore;
+
+impl KeyProtocolStore {
+    pub fn save(key_id: &str, protocol: &ProtocolVersion) -> Result<()> {
+        let contents = serde_json::to_string(protocol)?;
+        let item = StorageItem::KeyProtocol { key_id };
+        storage_backend()?.write(&item, &contents, &WriteOpts::Modify)
+    }
+
+    /// `None` for keys generated before protocol versions were recorded
+    pub fn get(key_id: &str) -> Result<Option<ProtocolVersion>> {
+        match storage_backend()?.read(&StorageItem::KeyProtocol { key_id })? {
+            Some(contents) => {
+                Ok(Some(serde_json::from_str(&contents).context("Deserialize key protocol")?))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_sessions_of_another_protocol_or_version() {
+        let key_id = "1b2359cf";
+        let stored = ProtocolVersion::GG2020_V1;
+        let route_only = SessionProtocol { protocol: Some(KeyProtocol::GG2020), version: None };
+        assert!(route_only.check(key_id, stored).is_ok());
+        assert!(SessionProtocol::default().check(key_id, stored).is_ok());
+
+        let other_route = SessionProtocol { protocol: Some(KeyProtocol::Frost), version: None };
+        assert_eq!(
+            other_route.check(key_id, stored).unwrap_err().requested,
+            ProtocolVersion::FROST_V1
+        );
+
+        let newer = SessionProtocol {
+            protocol: Some(KeyProtocol::GG2020),
+            version: Some(ProtocolVersion { protocol: KeyProtocol::GG2020, version: 2 }),
+        };
+        let err = newer.check(key_id, stored).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Key 1b2359cf was generated with GG2020 v1, the session runs GG2020 v2"
+        );
+    }
+}
@@ -1,20 +1,22 @@
 use super::fs::{ FileSystem, WriteOpts };
+use super::keyshare_integrity::KeyshareIntegrity;
 use crate::recovery::RecoveryCalculator;
 use anyhow::Result;
 use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
-use curv::elliptic::curves::{ Ed25519, Point, Scalar, Secp256k1 };
+use curv::elliptic::curves::{ Bls12_381_1, Ed25519, Point, Scalar, Secp256k1 };
 use curv::BigInt;
 use itertools::Itertools;
 use paillier::{ DecryptionKey, EncryptionKey };
 use serde::{ de::DeserializeOwned, Deserialize, Serialize };
-use shared::recovery::EncryptedData;
 use std::convert::TryFrom;
-use std::fs;
+use zeroize::Zeroizing;
 use zk_paillier::zkproofs::DLogStatement;
 
-use crate::encryption::{ aes_decrypt, aes_encrypt, AES_KEY_BYTES_LEN };
+use crate::storage::key_protocol::ProtocolVersion;
+use crate::storage::storage_key::StorageKeyring;
 use crate::storage::wrappers::{
     SchnorrkelSecretKey,
+    WDecryptionKey,
     WDLogStatement,
     WEcSharedKeys,
     WEdKeys,
@@ -24,15 +26,35 @@ use crate::storage::wrappers::{
     WVerifiableSS,
 };
 
-const TEMP_ENCRYPTION_KEY: &[u8; AES_KEY_BYTES_LEN] = b"65hjkt23scdfbfh8789kj2isdv870m84";
-
 //Marker trait to make sure we save keyfiles in most up to date format
-pub trait CurrentKeyshareFormat: Serialize + DeserializeOwned + TryFrom<KeyshareFormat> {}
+pub trait CurrentKeyshareFormat: Serialize + DeserializeOwned + TryFrom<KeyshareFormat> {
+    /// Protocol version shares in this format are generated with
+    const PROTOCOL: ProtocolVersion;
+    /// Name of the format recorded with the checksum of saved shares
+    const FORMAT: &'static str;
+}
 
 // Note that if CurrentKeyshareFormat is updated from EdDSA_V2, it will be necessary to update the TryFrom method to allow converting from TwoFractorAuth to new EdDSA format (this is necessary for regeneration of 2fa).
-impl CurrentKeyshareFormat for ECDSA_V4 {}
-impl CurrentKeyshareFormat for EdDSA_V3 {}
-impl CurrentKeyshareFormat for Sr25519 {}
+impl CurrentKeyshareFormat for ECDSA_V4 {
+    const PROTOCOL: ProtocolVersion = ProtocolVersion::GG2020_V1;
+    const FORMAT: &'static str = "ECDSA_V4";
+}
+impl CurrentKeyshareFormat for EdDSA_V3 {
+    const PROTOCOL: ProtocolVersion = ProtocolVersion::EDDSA_V1;
+    const FORMAT: &'static str = "EdDSA_V3";
+}
+impl CurrentKeyshareFormat for Sr25519 {
+    const PROTOCOL: ProtocolVersion = ProtocolVersion::SR25519_V1;
+    const FORMAT: &'static str = "Sr25519";
+}
+impl CurrentKeyshareFormat for Frost {
+    const PROTOCOL: ProtocolVersion = ProtocolVersion::FROST_V1;
+    const FORMAT: &'static str = "Frost";
+}
+impl CurrentKeyshareFormat for BLS_V1 {
+    const PROTOCOL: ProtocolVersion = ProtocolVersion::BLS_V1;
+    const FORMAT: &'static str = "BLS_V1";
+}
 
 impl TryFrom<KeyshareFormat> for ECDSA_V4 {
     type Error = &'static str;
@@ -52,7 +74,7 @@ impl TryFrom<KeyshareFormat> for ECDSA_V4 {
                     paillier_key_vec: ecdsa_v1v2.paillier_key_vector,
                     y_sum: ecdsa_v1v2.y_sum.into(),
                     public_key_vec: ecdsa_v1v2.public_key_vec.into_iter().map_into().collect(),
-                    paillier_dk: ecdsa_v1v2.party_keys.dk,
+                    paillier_dk: ecdsa_v1v2.party_keys.dk.into(),
                 }),
             KeyshareFormat::ECDSA_V3(ecdsa_v3) =>
                 Ok(Self {
@@ -64,13 +86,15 @@ impl TryFrom<KeyshareFormat> for ECDSA_V4 {
                     vss_scheme_vec: ecdsa_v3.vss_scheme_vec.into_iter().map_into().collect(),
                     paillier_key_vec: ecdsa_v3.paillier_key_vec.into_iter().map_into().collect(),
                     h1_h2_N_tilde_vec: ecdsa_v3.h1_h2_N_tilde_vec.into_iter().map_into().collect(),
-                    paillier_dk: ecdsa_v3.paillier_dk,
+                    paillier_dk: ecdsa_v3.paillier_dk.into(),
                 }),
             KeyshareFormat::ECDSA_V4(ecdsa_v4) => Ok(ecdsa_v4),
             | KeyshareFormat::EdDSA_V1(_)
             | KeyshareFormat::EdDSA_V2(_)
             | KeyshareFormat::EdDSA_V3(_)
-            | KeyshareFormat::Sr25519(_) => {
+            | KeyshareFormat::Sr25519(_)
+            | KeyshareFormat::Frost(_)
+            | KeyshareFormat::BLS_V1(_) => {
                 Err("The key file contained a different key type, expecting ECDSA")
             }
         }
@@ -86,7 +110,9 @@ impl TryFrom<KeyshareFormat> for EdDSA_V3 {
             | KeyshareFormat::EdDSA_V2(_)
             | KeyshareFormat::ECDSA_V1V2(_)
             | KeyshareFormat::ECDSA_V3(_)
-            | KeyshareFormat::ECDSA_V4(_) => {
+            | KeyshareFormat::ECDSA_V4(_)
+            | KeyshareFormat::Frost(_)
+            | KeyshareFormat::BLS_V1(_) => {
                 Err("The key file contained a different key type, expecting EdDSA")
             }
             KeyshareFormat::EdDSA_V3(eddsa_v2) => Ok(eddsa_v2),
@@ -134,7 +160,7 @@ pub struct ECDSA_V4 {
     pub vss_scheme_vec: Vec<VerifiableSS<Secp256k1>>,
     pub paillier_key_vec: Vec<EncryptionKey>,
     pub h1_h2_N_tilde_vec: Vec<DLogStatement>,
-    pub paillier_dk: DecryptionKey,
+    pub paillier_dk: WDecryptionKey,
 }
 
 #[allow(non_camel_case_types)]
@@ -165,6 +191,28 @@ pub struct ECDSA_V1V2 {
     pub h1_h2_N_tilde_vec: Vec<WDLogStatement>,
 }
 
+impl TryFrom<KeyshareFormat> for Frost {
+    type Error = &'static str;
+
+    fn try_from(kf: KeyshareFormat) -> Result<Self, Self::Error> {
+        match kf {
+            KeyshareFormat::Frost(frost) => Ok(frost),
+            _ => Err("The key file contained a different key type, expecting FROST"),
+        }
+    }
+}
+
+impl TryFrom<KeyshareFormat> for BLS_V1 {
+    type Error = &'static str;
+
+    fn try_from(kf: KeyshareFormat) -> Result<Self, Self::Error> {
+        match kf {
+            KeyshareFormat::BLS_V1(bls) => Ok(bls),
+            _ => Err("The key file contained a different key type, expecting BLS"),
+        }
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct EdDSA_V3 {
@@ -206,6 +254,24 @@ pub enum KeyshareFormat {
     EdDSA_V2(EdDSA_V2),
     EdDSA_V3(EdDSA_V3),
     Sr25519(Sr25519),
+    Frost(Frost),
+    BLS_V1(BLS_V1),
+}
+
+impl KeyshareFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyshareFormat::ECDSA_V1V2(_) => "ECDSA_V1V2",
+            KeyshareFormat::ECDSA_V3(_) => "ECDSA_V3",
+            KeyshareFormat::ECDSA_V4(_) => "ECDSA_V4",
+            KeyshareFormat::EdDSA_V1(_) => "EdDSA_V1",
+            KeyshareFormat::EdDSA_V2(_) => "EdDSA_V2",
+            KeyshareFormat::EdDSA_V3(_) => "EdDSA_V3",
+            KeyshareFormat::Sr25519(_) => "Sr25519",
+            KeyshareFormat::Frost(_) => "Frost",
+            KeyshareFormat::BLS_V1(_) => "BLS_V1",
+        }
+    }
 }
 
 pub struct Keystore;
@@ -217,12 +283,11 @@ impl Keystore {
         index: usize,
         write_access: &WriteOpts
     ) -> Result<()> {
-        let mut contents = serde_json::to_string(keyshare)?;
-
-        let encrypted_data = aes_encrypt(contents.as_bytes(), TEMP_ENCRYPTION_KEY)?;
-        contents = serde_json::to_string(&encrypted_data)?;
+        let plaintext = Zeroizing::new(serde_json::to_string(keyshare)?);
+        let contents = StorageKeyring::load()?.seal(plaintext.as_bytes())?;
 
-        FileSystem::add_keyfile(key_id, index, &contents, write_access)
+        FileSystem::add_keyfile(key_id, index, &contents, write_access)?;
+        KeyshareIntegrity::new(&plaintext, T::FORMAT).save(key_id, index, None)
     }
 
     pub fn encrypt_and_save_key_with_email<T: CurrentKeyshareFormat>(
@@ -232,12 +297,11 @@ impl Keystore {
         email: &str,
         write_access: &WriteOpts
     ) -> Result<()> {
-        let mut contents = serde_json::to_string(keyshare)?;
+        let plaintext = Zeroizing::new(serde_json::to_string(keyshare)?);
+        let contents = StorageKeyring::load()?.seal(plaintext.as_bytes())?;
 
-        let encrypted_data = aes_encrypt(contents.as_bytes(), TEMP_ENCRYPTION_KEY)?;
-        contents = serde_json::to_string(&encrypted_data)?;
-
-        FileSystem::add_keyfile_with_email(key_id, index, email, &contents, write_access)
+        FileSystem::add_keyfile_with_email(key_id, index, email, &contents, write_access)?;
+        KeyshareIntegrity::new(&plaintext, T::FORMAT).save(key_id, index, Some(email))
     }
 
     pub fn save_key<T: CurrentKeyshareFormat>(
@@ -245,9 +309,10 @@ impl Keystore {
         key_id: &str,
         write_access: &WriteOpts
     ) -> Result<()> {
-        let contents = serde_json::to_string(keyshare)?;
+        let contents = Zeroizing::new(serde_json::to_string(keyshare)?);
 
-        FileSystem::add_keyfile(key_id, 0, &contents, write_access)
+        FileSystem::add_keyfile(key_id, 0, &contents, write_access)?;
+        KeyshareIntegrity::new(&contents, T::FORMAT).save(key_id, 0, None)
     }
 
     pub fn save_key_with_email<T: CurrentKeyshareFormat>(
@@ -256,19 +321,19 @@ impl Keystore {
         email: &str,
         write_access: &WriteOpts
     ) -> Result<()> {
-        let contents = serde_json::to_string(keyshare)?;
+        let contents = Zeroizing::new(serde_json::to_string(keyshare)?);
 
-        FileSystem::add_keyfile_with_email(key_id, 0, email, &contents, write_access)
+        FileSystem::add_keyfile_with_email(key_id, 0, email, &contents, write_access)?;
+        KeyshareIntegrity::new(&contents, T::FORMAT).save(key_id, 0, Some(email))
     }
 
     pub fn get_key(key_id: &str) -> Result<KeyshareFormat> {
-        let data = FileSystem::read_keyfile(key_id, 0)?;
+        let data = Zeroizing::new(FileSystem::read_keyfile(key_id, 0)?);
         Self::deserialize_key(&data)
     }
 
     pub fn get_key_with_email(key_id: &str, email: &str) -> Result<KeyshareFormat> {
-        let file_path = FileSystem::find_keyfile_with_email(key_id, 0, email)?;
-        let data = fs::read_to_string(file_path)?;
+        let data = Zeroizing::new(FileSystem::read_keyfile_with_email(key_id, 0, email)?);
         Self::deserialize_key(&data)
     }
 
@@ -285,7 +350,7 @@ impl Keystore {
     // This function should not need changing; if new keyshare formats are added they should be added directly to the KeyshareFormat enum.
     // This is just a weird case for ECDSA v1 as it was serialized in a non json standard way, so deserializer doesn't understand how to
     // deserialize it as an untagged KeyshareFormat variant.
-    fn deserialize_key(data: &str) -> Result<KeyshareFormat> {
+    pub(crate) fn deserialize_key(data: &str) -> Result<KeyshareFormat> {
         let ks = match serde_json::from_str::<KeyshareFormat>(data) {
             Ok(ks) => ks,
             Err(_) => {
@@ -296,19 +361,23 @@ impl Keystore {
         Ok(ks)
     }
 
-    fn decrypt_keyfile_to_string(key_id: &str) -> Result<String> {
+    fn decrypt_keyfile_to_string(key_id: &str) -> Result<Zeroizing<String>> {
         let contents = FileSystem::read_keyfile(key_id, 0)?;
-        let data = serde_json::from_str::<EncryptedData>(&contents)?;
-        let decrypted = aes_decrypt(&data, TEMP_ENCRYPTION_KEY)?;
-        Ok(String::from_utf8(decrypted)?)
+        Self::open_keyfile(&contents)
     }
 
-    fn decrypt_keyfile_to_string_with_email(key_id: &str, email: &str) -> Result<String> {
-        let file_path = FileSystem::find_keyfile_with_email(key_id, 0, email)?;
-        let contents = fs::read_to_string(file_path)?;
-        let data = serde_json::from_str::<EncryptedData>(&contents)?;
-        let decrypted = aes_decrypt(&data, TEMP_ENCRYPTION_KEY)?;
-        Ok(String::from_utf8(decrypted)?)
+    fn decrypt_keyfile_to_string_with_email(
+        key_id: &str,
+        email: &str
+    ) -> Result<Zeroizing<String>> {
+        let contents = FileSystem::read_keyfile_with_email(key_id, 0, email)?;
+        Self::open_keyfile(&contents)
+    }
+
+    /// Decrypts a keyfile, the plaintext is wiped when the returned string is dropped
+    fn open_keyfile(contents: &str) -> Result<Zeroizing<String>> {
+        let decrypted = Zeroizing::new(StorageKeyring::load()?.open(contents)?);
+        Ok(Zeroizing::new(std::str::from_utf8(&decrypted)?.to_string()))
     }
 }
 
@@ -323,6 +392,48 @@ pub struct Sr25519 {
     pub vss_scheme: WVerifiableSS<Ed25519>,
 }
 
+/// Keyshare for FROST threshold Schnorr signatures over secp256k1 (BIP-340)
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Frost {
+    pub threshold: usize,
+    pub party_index: usize,
+    pub x_i: Scalar<Secp256k1>,
+    pub group_public_key: Point<Secp256k1>,
+    pub vss_scheme_vec: Vec<VerifiableSS<Secp256k1>>,
+}
+
+impl Frost {
+    /// Public counterpart of the share held by `party_index`
+    pub fn public_share(&self, party_index: usize) -> Point<Secp256k1> {
+        self.vss_scheme_vec
+            .iter()
+            .fold(Point::zero(), |sum, vss| sum + vss.get_point_commitment(party_index as u16))
+    }
+}
+
+/// Keyshare for BLS12-381 threshold signatures, the public key and its commitments live in G1
+/// as in the Ethereum consensus ciphersuite
+#[allow(non_camel_case_types)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BLS_V1 {
+    pub threshold: usize,
+    pub party_index: usize,
+    pub x_i: Scalar<Bls12_381_1>,
+    pub public_key: Point<Bls12_381_1>,
+    pub vss_scheme_vec: Vec<VerifiableSS<Bls12_381_1>>,
+}
+
+impl BLS_V1 {
+    /// Public counterpart of the share held by `party_index`
+    pub fn public_share(&self, party_index: usize) -> Point<Bls12_381_1> {
+        self.vss_scheme_vec
+            .iter()
+            .fold(Point::zero(), |sum, vss| sum + vss.get_point_commitment(party_index as u16))
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ECKeysV1V2 {
     u_i: WScalar<Secp256k1>,
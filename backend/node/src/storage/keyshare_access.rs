@@ -1,5 +1,7 @@
-use super::fs::WriteOpts;
+use super::fs::{ FileSystem, WriteOpts };
+use crate::storage::key_protocol::KeyProtocolStore;
 use crate::storage::key_store::{ CurrentKeyshareFormat, KeyshareFormat, Keystore };
+use crate::tenant;
 
 use anyhow::{ anyhow, bail, Result };
 use std::convert::TryFrom;
@@ -52,6 +54,13 @@ impl<K> KeyshareAccessor<K>
         access_opts: AccessOpts,
         write_access: Option<WriteOpts>
     ) -> Result<Self> {
+        // A command acting for an account only reads the keyshares in the account directory
+        if let Some(email) = tenant::current() {
+            if !FileSystem::keyfile_exists_with_email(key_id, 0, &email)? {
+                bail!("Key {} is not stored for account {}", key_id, email);
+            }
+            return Self::accessor_with_opts_and_email(key_id, access_opts, write_access, &email);
+        }
         let key_format = (match access_opts {
             AccessOpts::Standard => Keystore::get_key(key_id),
             AccessOpts::FromEncrypted => Keystore::get_encrypted_key(key_id),
@@ -73,6 +82,7 @@ impl<K> KeyshareAccessor<K>
         write_access: Option<WriteOpts>,
         email: &str
     ) -> Result<Self> {
+        tenant::ensure_account(email)?;
         let key_format = (match access_opts {
             AccessOpts::Standard => Keystore::get_key_with_email(key_id, email),
             AccessOpts::FromEncrypted => Keystore::get_encrypted_key_with_email(key_id, email),
@@ -151,7 +161,17 @@ impl KeyshareSaver {
         self
     }
 
+    pub fn email(&self) -> Option<&str> {
+        self.email.as_deref()
+    }
+
+    /// Saves the keyshare and records the protocol version it was generated with
     pub fn save_key<K: CurrentKeyshareFormat>(&self, keyshare: &K) -> Result<()> {
+        self.save_keyshare(keyshare)?;
+        KeyProtocolStore::save(&self.key_id, &K::PROTOCOL)
+    }
+
+    fn save_keyshare<K: CurrentKeyshareFormat>(&self, keyshare: &K) -> Result<()> {
         match self.encryption {
             EncryptionOpts::None => {
                 if let Some(email) = &self.email {
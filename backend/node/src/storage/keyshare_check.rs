@@ -0,0 +1,291 @@
+use super::backend::{ storage_backend, StorageItem };
+use super::key_listing::{ parse_keyshare_file, stored_keyshare_paths };
+use super::key_store::{ EdDSA_V3, Frost, KeyshareFormat, Keystore, BLS_V1, ECDSA_V4 };
+use super::keyshare_integrity::KeyshareIntegrity;
+use super::storage_key::StorageKeyring;
+use crate::command::{ JsonCommand, MsgContext };
+use crate::recovery::RecoveryCalculator;
+use crate::tenant::{ Access, TenantAuth };
+use anyhow::{ anyhow, bail, Context, Result };
+use chrono::{ DateTime, Utc };
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::{ Curve, Point, Scalar };
+use rand::seq::SliceRandom;
+use serde::{ Deserialize, Serialize };
+use std::convert::TryFrom;
+use std::env;
+use tracing::{ error, info, warn };
+use zeroize::Zeroizing;
+
+/// `checksums` (the default), `all`, a number of randomly chosen keyshares, or `off`
+const STARTUP_CHECK_VAR: &str = "KEYSHARE_STARTUP_CHECK";
+
+#[derive(Debug, PartialEq)]
+pub enum StartupCheck {
+    Off,
+    /// Every keyshare is compared against its recorded checksum, VSS commitments are not checked
+    Checksums,
+    Sample(usize),
+    All,
+}
+
+impl StartupCheck {
+    pub fn from_env() -> Result<Self> {
+        match env::var(STARTUP_CHECK_VAR) {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(StartupCheck::Checksums),
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self> {
+        match value.trim() {
+            "" | "off" => Ok(StartupCheck::Off),
+            "checksums" => Ok(StartupCheck::Checksums),
+            "all" => Ok(StartupCheck::All),
+            count =>
+                count
+                    .parse()
+                    .map(StartupCheck::Sample)
+                    .map_err(|_| {
+                        anyhow!(
+                            "{} must be \"checksums\", \"all\", \"off\" or a number",
+                            STARTUP_CHECK_VAR
+                        )
+                    }),
+        }
+    }
+}
+
+/// Checks that the configured keyshares can be read, decrypted, match the checksum recorded when
+/// they were saved and, unless only checksums are checked, are consistent with their VSS
+/// commitments. Meant to run before the node subscribes to signing traffic. Failures are reported,
+/// not fatal: a share saved without its checksum by an interrupted write still signs, and
+/// `VerifyKeysharesCommand` tells which shares need restoring.
+pub fn verify_keyshares_on_startup() -> Result<()> {
+    let check = StartupCheck::from_env()?;
+    if check == StartupCheck::Off {
+        return Ok(());
+    }
+    let mut keyshare_files = stored_keyshare_paths()?;
+    if let StartupCheck::Sample(count) = check {
+        keyshare_files.shuffle(&mut rand::thread_rng());
+        keyshare_files.truncate(count);
+    }
+
+    let keyring = StorageKeyring::load()?;
+    let verify_vss = check != StartupCheck::Checksums;
+    let mut unrecorded = 0;
+    let failed = keyshare_files
+        .iter()
+        .filter(|path| {
+            match verify_keyshare_file(path, &keyring, verify_vss) {
+                Ok(integrity) => {
+                    if integrity.is_none() {
+                        unrecorded += 1;
+                    }
+                    false
+                }
+                Err(err) => {
+                    error!("Keyshare {} failed verification: {:#}", path, err);
+                    true
+                }
+            }
+        })
+        .count();
+    if failed > 0 {
+        error!("{} of {} checked keyshares failed verification", failed, keyshare_files.len());
+    }
+    if unrecorded > 0 {
+        warn!("{} keyshares were saved before checksums were recorded", unrecorded);
+    }
+    info!("Verified {} keyshares", keyshare_files.len() - failed);
+    Ok(())
+}
+
+/// Checks the keyshare stored under `path` against its checksum and, with `verify_vss`, its VSS
+/// commitments. Returns the checksum, `None` if none was recorded for the share.
+fn verify_keyshare_file(
+    path: &str,
+    keyring: &StorageKeyring,
+    verify_vss: bool
+) -> Result<Option<KeyshareIntegrity>> {
+    let (key_id, index, email) = parse_keyshare_file(path).ok_or_else(||
+        anyhow!("Not a keyshare path")
+    )?;
+    let email = email.as_deref();
+    let contents = storage_backend()?
+        .read(&(StorageItem::Keyfile { key_id: &key_id, index, email }))?
+        .ok_or_else(|| anyhow!("Keyshare was removed"))?;
+    let plaintext = if StorageKeyring::is_encrypted(&contents) {
+        let decrypted = Zeroizing::new(
+            keyring.open(&contents).context("Failed to decrypt, the file may be truncated")?
+        );
+        Zeroizing::new(std::str::from_utf8(&decrypted)?.to_string())
+    } else {
+        Zeroizing::new(contents)
+    };
+    let integrity = KeyshareIntegrity::read(&key_id, index, email)?;
+    if let Some(integrity) = &integrity {
+        integrity.check(&plaintext)?;
+    }
+    let keyshare = Keystore::deserialize_key(&plaintext).context("Keyshare can't be parsed")?;
+    if verify_vss {
+        verify_keyshare(keyshare)?;
+    }
+    Ok(integrity)
+}
+
+/// Checks every keyshare of the node, or of one key, and reports the health of each. Keyshares
+/// are compared against their checksum and VSS commitments, the node keeps running whatever the
+/// outcome.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct VerifyKeysharesCommand {
+    #[serde(default)]
+    pub key_id: Option<String>,
+    /// Required on multi user nodes, only the keyshares of the proven account are checked then
+    #[serde(default)]
+    pub authorization: Option<TenantAuth>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyshareStatus {
+    Healthy,
+    /// Valid, but saved before checksums were recorded
+    NoChecksum,
+    /// Unreadable, truncated or changed since it was saved
+    Corrupted,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct KeyshareHealth {
+    pub key_id: String,
+    /// Account the keyshare is stored for, `None` for keyshares in the flat layout
+    pub email: Option<String>,
+    pub file: String,
+    pub status: KeyshareStatus,
+    /// Why the keyshare is corrupted
+    pub error: Option<String>,
+    /// Format and save time recorded with the checksum
+    pub format: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl JsonCommand for VerifyKeysharesCommand {
+    type Response = Vec<KeyshareHealth>;
+
+    fn access(&self) -> Access<'_> {
+        Access::Account {
+            auth: self.authorization.as_ref(),
+            key_ids: self.key_id.iter().cloned().collect(),
+        }
+    }
+
+    fn execute_message(self, ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let tenant = ctx.tenant();
+        let keyring = StorageKeyring::load()?;
+
+        let mut report = Vec::new();
+        for file in stored_keyshare_paths()? {
+            let Some((key_id, _, email)) = parse_keyshare_file(&file) else {
+                continue;
+            };
+            if self.key_id.as_ref().is_some_and(|wanted| wanted != &key_id) {
+                continue;
+            }
+            if tenant.is_some() && email != tenant {
+                continue;
+            }
+            let verified = verify_keyshare_file(&file, &keyring, true);
+            let mut health = KeyshareHealth {
+                key_id,
+                email,
+                file,
+                status: KeyshareStatus::Healthy,
+                error: None,
+                format: None,
+                created_at: None,
+            };
+            match verified {
+                Ok(Some(integrity)) => {
+                    health.format = Some(integrity.format);
+                    health.created_at = Some(integrity.created_at);
+                }
+                Ok(None) => {
+                    health.status = KeyshareStatus::NoChecksum;
+                }
+                Err(err) => {
+                    warn!("Keyshare {} failed verification: {:#}", health.file, err);
+                    health.status = KeyshareStatus::Corrupted;
+                    health.error = Some(format!("{:#}", err));
+                }
+            }
+            report.push(health);
+        }
+        Ok(report)
+    }
+}
+
+/// Checks a decrypted keyshare file against its VSS commitments
+pub(crate) fn verify_keyshare_plaintext(plaintext: &str) -> Result<()> {
+    verify_keyshare(Keystore::deserialize_key(plaintext)?)
+}
+
+fn verify_keyshare(keyshare: KeyshareFormat) -> Result<()> {
+    match keyshare {
+        | KeyshareFormat::ECDSA_V1V2(_)
+        | KeyshareFormat::ECDSA_V3(_)
+        | KeyshareFormat::ECDSA_V4(_) => {
+            let ecdsa = ECDSA_V4::try_from(keyshare).map_err(|err| anyhow!("{}", err))?;
+            verify_share(&ecdsa.x_i, &ecdsa.vss_scheme_vec, ecdsa.party_index, &ecdsa.y_sum)
+        }
+        KeyshareFormat::EdDSA_V3(_) | KeyshareFormat::Sr25519(_) => {
+            let eddsa = EdDSA_V3::try_from(keyshare).map_err(|err| anyhow!("{}", err))?;
+            verify_share(&eddsa.x_i, &eddsa.vss_scheme_vec, eddsa.party_index, &eddsa.y_sum)
+        }
+        KeyshareFormat::Frost(_) => {
+            let frost = Frost::try_from(keyshare).map_err(|err| anyhow!("{}", err))?;
+            let public_key = &frost.group_public_key;
+            verify_share(&frost.x_i, &frost.vss_scheme_vec, frost.party_index, public_key)
+        }
+        KeyshareFormat::BLS_V1(_) => {
+            let bls = BLS_V1::try_from(keyshare).map_err(|err| anyhow!("{}", err))?;
+            verify_share(&bls.x_i, &bls.vss_scheme_vec, bls.party_index, &bls.public_key)
+        }
+        // Old EdDSA formats are not converted to the current one, parsing them is all we can check
+        KeyshareFormat::EdDSA_V1(_) | KeyshareFormat::EdDSA_V2(_) => Ok(()),
+    }
+}
+
+fn verify_share<C: Curve>(
+    x_i: &Scalar<C>,
+    vss_scheme_vec: &[VerifiableSS<C>],
+    party_index: usize,
+    public_key: &Point<C>
+) -> Result<()> {
+    if vss_scheme_vec.is_empty() {
+        bail!("Keyshare has no VSS commitments");
+    }
+    RecoveryCalculator::<C>
+        ::validate_recovered_share(x_i, vss_scheme_vec, party_index)
+        .map_err(|_| anyhow!("Secret share does not match its VSS commitments"))?;
+    if &RecoveryCalculator::<C>::calculate_y_sum_from_vss_vec(vss_scheme_vec)? != public_key {
+        bail!("Public key does not match the VSS commitments");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_check_settings() {
+        assert_eq!(StartupCheck::parse("").unwrap(), StartupCheck::Off);
+        assert_eq!(StartupCheck::parse("all").unwrap(), StartupCheck::All);
+        assert_eq!(StartupCheck::parse("checksums").unwrap(), StartupCheck::Checksums);
+        assert_eq!(StartupCheck::parse("25").unwrap(), StartupCheck::Sample(25));
+        assert!(StartupCheck::parse("some").is_err());
+    }
+}
@@ -1,13 +1,16 @@
 use crate::storage::fs::FileSystem;
-use crate::storage::{ KeyshareAccessor, ECDSA, EDDSA };
+use crate::storage::{ Frost, KeyInfoStore, KeyshareAccessor, BLS, ECDSA, EDDSA };
 use anyhow::{ bail, Result };
 use serde::{ Deserialize, Serialize };
+use shared::key_info::KeyMetadata;
 use tracing::error;
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct KeyshareIndex {
     pub key_id: String,
     pub index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<KeyMetadata>,
 }
 
 pub fn get_all_keyshare_indices() -> Result<Vec<KeyshareIndex>> {
@@ -27,6 +30,10 @@ fn get_all_keyshare_indices_by_key_id(key_ids: &[String]) -> Result<Vec<Keyshare
         .map(|(key_id, index)| KeyshareIndex {
             key_id: key_id.clone(),
             index: index.unwrap(),
+            metadata: KeyInfoStore::get_key_info(key_id)
+                .ok()
+                .and_then(|key_info| key_info.metadata)
+                .map(|signed| signed.metadata),
         })
         .collect();
     Ok(results)
@@ -38,15 +45,25 @@ fn get_keyshare_index(key_id: &str) -> Result<usize> {
         Err(err1) =>
             match KeyshareAccessor::<EDDSA>::read_only(key_id) {
                 Ok(ka) => Ok(ka.key.party_index),
-                Err(err2) => {
-                    let err_msg = format!(
-                        "Could not decrypt key file to expected format: {}, {}",
-                        err1,
-                        err2
-                    );
-                    error!("{}", &err_msg);
-                    bail!("{}", &err_msg)
-                }
+                Err(err2) =>
+                    match KeyshareAccessor::<Frost>::read_only(key_id) {
+                        Ok(ka) => Ok(ka.key.party_index),
+                        Err(err3) =>
+                            match KeyshareAccessor::<BLS>::read_only(key_id) {
+                                Ok(ka) => Ok(ka.key.party_index),
+                                Err(err4) => {
+                                    let err_msg = format!(
+                                        "Could not decrypt key file to expected format: {}, {}, {}, {}",
+                                        err1,
+                                        err2,
+                                        err3,
+                                        err4
+                                    );
+                                    error!("{}", &err_msg);
+                                    bail!("{}", &err_msg)
+                                }
+                            }
+                    }
             }
     }
 }
@@ -0,0 +1,74 @@
+use crate::storage::backend::{ storage_backend, StorageItem };
+use crate::storage::fs::WriteOpts;
+use anyhow::{ bail, Context, Result };
+use chrono::{ DateTime, Utc };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+
+/// Checksum of a keyshare, written beside the keyfile whenever the share is saved. It is taken
+/// over the plaintext, so re-encrypting the keyfile under a new storage key keeps it valid.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KeyshareIntegrity {
+    /// Hex encoded SHA-256 of the plaintext keyshare
+    pub sha256: String,
+    /// Keyshare format the share was saved in, e.g. "ECDSA_V4"
+    pub format: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl KeyshareIntegrity {
+    pub fn new(plaintext: &str, format: &str) -> Self {
+        KeyshareIntegrity {
+            sha256: checksum(plaintext),
+            format: format.to_string(),
+            created_at: Utc::now(),
+        }
+    }
+
+    pub fn save(&self, key_id: &str, index: usize, email: Option<&str>) -> Result<()> {
+        let item = StorageItem::KeyshareIntegrity { key_id, index, email };
+        let contents = serde_json::to_string(self)?;
+        storage_backend()?.write(&item, &contents, &WriteOpts::Modify)
+    }
+
+    /// Checksum recorded for the keyfile, `None` for shares saved before checksums were
+    pub fn read(key_id: &str, index: usize, email: Option<&str>) -> Result<Option<Self>> {
+        let item = StorageItem::KeyshareIntegrity { key_id, index, email };
+        let Some(contents) = storage_backend()?.read(&item)? else {
+            return Ok(None);
+        };
+        let integrity = serde_json
+            ::from_str(&contents)
+            .with_context(|| format!("Checksum {} is corrupted", item.path()))?;
+        Ok(Some(integrity))
+    }
+
+    /// Fails if the plaintext is not the one the checksum was taken of
+    pub fn check(&self, plaintext: &str) -> Result<()> {
+        if checksum(plaintext) != self.sha256 {
+            bail!(
+                "Keyshare does not match the checksum recorded when it was saved on {}",
+                self.created_at
+            );
+        }
+        Ok(())
+    }
+}
+
+fn checksum(plaintext: &str) -> String {
+    hex::encode(Sha256::digest(plaintext.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_changed_keyshares() {
+        let integrity = KeyshareIntegrity::new(r#"{"x_i":"01"}"#, "Frost");
+        assert!(integrity.check(r#"{"x_i":"01"}"#).is_ok());
+        assert!(integrity.check(r#"{"x_i":"02"}"#).is_err());
+        assert!(integrity.check(r#"{"x_i":"0"#).is_err());
+    }
+}
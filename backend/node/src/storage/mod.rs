@@ -1,16 +1,27 @@
+pub mod backend;
+pub mod backup;
+pub mod deletion;
 pub mod fs;
 mod key_info_store;
+pub mod key_listing;
 mod key_store;
 mod keyshare_access;
+pub mod keyshare_check;
+mod keyshare_integrity;
+pub mod reencryption;
+pub mod storage_key;
 pub mod keyshare_index_info;
 mod wrappers;
 pub mod key_metadata_store;
+pub mod key_protocol;
 
 pub use key_info_store::*;
-pub use key_store::CurrentKeyshareFormat;
+pub use key_store::{ CurrentKeyshareFormat, KeyshareFormat };
 pub use key_store::EdDSA_V3 as EDDSA;
 pub use key_store::ECDSA_V4 as ECDSA;
 pub use key_store::Sr25519;
+pub use key_store::Frost;
+pub use key_store::BLS_V1 as BLS;
 pub use key_store::Keystore;
 pub use keyshare_access::{ KeyshareAccessor, KeyshareSaver };
 pub use wrappers::SchnorrkelSecretKey;
@@ -0,0 +1,226 @@
+use super::fs::FileSystem;
+use super::storage_key::StorageKeyring;
+use crate::command::{ JsonCommand, MsgContext };
+use crate::config::{ Config, ConfigProvider };
+use crate::tenant::Access;
+use anyhow::{ anyhow, Result };
+use serde::{ Deserialize, Serialize };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Mutex, OnceLock };
+use std::time::Duration;
+use std::{ fs, thread };
+use tracing::{ error, info, warn };
+
+const CHECKPOINT_FILE: &str = "reencryption.json";
+/// Pause between files so the job never competes with signing sessions for the disk
+const FILE_PAUSE: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Progress of moving every encrypted file to one storage key, saved after each file so an
+/// interrupted job continues where it stopped
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReencryptionStatus {
+    pub key_fingerprint: String,
+    pub state: JobState,
+    pub total_files: usize,
+    pub next_index: usize,
+    pub reencrypted: usize,
+    pub failed_files: Vec<String>,
+}
+
+impl ReencryptionStatus {
+    fn new(key_fingerprint: &str, total_files: usize) -> Self {
+        Self {
+            key_fingerprint: key_fingerprint.to_string(),
+            state: JobState::Running,
+            total_files,
+            next_index: 0,
+            reencrypted: 0,
+            failed_files: Vec::new(),
+        }
+    }
+
+    fn load_checkpoint() -> Result<Option<Self>> {
+        let path = checkpoint_path();
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+    }
+
+    fn save_checkpoint(&self) -> Result<()> {
+        fs::write(checkpoint_path(), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+fn checkpoint_path() -> PathBuf {
+    let mut path = Config::get_gridlock_directory();
+    path.push(CHECKPOINT_FILE);
+    path
+}
+
+fn current_status() -> &'static Mutex<Option<ReencryptionStatus>> {
+    static STATUS: OnceLock<Mutex<Option<ReencryptionStatus>>> = OnceLock::new();
+    STATUS.get_or_init(|| Mutex::new(None))
+}
+
+fn publish_status(status: &ReencryptionStatus) {
+    if let Err(err) = status.save_checkpoint() {
+        warn!("Failed to save the re-encryption checkpoint: {}", err);
+    }
+    *current_status().lock().unwrap() = Some(status.clone());
+}
+
+/// Every file that may hold data encrypted with the storage key, in a stable order so a
+/// checkpointed position stays meaningful between runs
+fn encrypted_file_candidates() -> Result<Vec<PathBuf>> {
+    let mut files = FileSystem::find_all_keyshare_files()?;
+    files.extend(FileSystem::find_all_metadata_files()?);
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Starts re-encrypting keyshares and metadata that are not under the current storage key on a
+/// background thread. A checkpoint left by an interrupted job for the same key is resumed, one
+/// for an older key is discarded since files are checked against the current key anyway.
+pub fn spawn_reencryption_job() -> Result<()> {
+    let keyring = StorageKeyring::load()?;
+    let files = encrypted_file_candidates()?;
+    let checkpoint = ReencryptionStatus::load_checkpoint()
+        .unwrap_or_else(|err| {
+            warn!("Ignoring unreadable re-encryption checkpoint: {}", err);
+            None
+        })
+        .filter(|checkpoint| {
+            checkpoint.key_fingerprint == keyring.current_fingerprint() &&
+                checkpoint.state == JobState::Running
+        });
+    let status = match checkpoint {
+        Some(checkpoint) => {
+            info!("Resuming storage re-encryption at file {}", checkpoint.next_index);
+            ReencryptionStatus { total_files: files.len(), ..checkpoint }
+        }
+        None => ReencryptionStatus::new(keyring.current_fingerprint(), files.len()),
+    };
+
+    thread::Builder
+        ::new()
+        .name("storage-reencryption".to_string())
+        .spawn(move || run_job(keyring, files, status))?;
+    Ok(())
+}
+
+fn run_job(keyring: StorageKeyring, files: Vec<PathBuf>, mut status: ReencryptionStatus) {
+    publish_status(&status);
+    while let Some(path) = files.get(status.next_index) {
+        match reencrypt_file(path, &keyring) {
+            Ok(true) => {
+                status.reencrypted += 1;
+            }
+            Ok(false) => {}
+            Err(err) => {
+                error!("Failed to re-encrypt {}: {}", path.display(), err);
+                status.failed_files.push(path.display().to_string());
+            }
+        }
+        status.next_index += 1;
+        publish_status(&status);
+        thread::sleep(FILE_PAUSE);
+    }
+
+    status.state = if status.failed_files.is_empty() {
+        JobState::Completed
+    } else {
+        JobState::Failed
+    };
+    publish_status(&status);
+    info!(
+        "Storage re-encryption finished: {} of {} files re-encrypted, {} failed",
+        status.reencrypted,
+        status.total_files,
+        status.failed_files.len()
+    );
+}
+
+/// Moves one file to the current storage key, returns whether it had to be rewritten. Plaintext
+/// files and files already under the current key are left alone.
+pub fn reencrypt_file(path: &Path, keyring: &StorageKeyring) -> Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let contents = fs::read_to_string(path)?;
+    if !StorageKeyring::is_encrypted(&contents) || keyring.is_current(&contents) {
+        return Ok(false);
+    }
+    let plaintext = keyring
+        .open(&contents)
+        .map_err(|err| anyhow!("Failed to decrypt {}: {}", path.display(), err))?;
+    let sealed = keyring.seal(&plaintext)?;
+
+    // A session may have saved the file in the meantime, with the current key already
+    if fs::read_to_string(path)? != contents {
+        return Ok(false);
+    }
+    // Write next to the original and rename so an interrupted run never loses a file
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, sealed)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(true)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GetReencryptionStatusCommand {}
+
+impl JsonCommand for GetReencryptionStatusCommand {
+    type Response = Option<ReencryptionStatus>;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        match current_status().lock().unwrap().clone() {
+            Some(status) => Ok(Some(status)),
+            None => ReencryptionStatus::load_checkpoint(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::AES_KEY_BYTES_LEN;
+    use crate::storage::storage_key::StorageKey;
+
+    #[test]
+    fn moves_files_to_the_current_key_once() {
+        let dir = std::env::temp_dir().join(format!("reencryption-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keyshare-test.json");
+
+        let old_key = StorageKey::from_key_bytes(vec![1; AES_KEY_BYTES_LEN]);
+        let new_key = StorageKey::from_key_bytes(vec![2; AES_KEY_BYTES_LEN]);
+        let old = StorageKeyring::new(old_key, vec![]);
+        fs::write(&path, old.seal(b"keyshare").unwrap()).unwrap();
+
+        let old_key = StorageKey::from_key_bytes(vec![1; AES_KEY_BYTES_LEN]);
+        let rotated = StorageKeyring::new(new_key, vec![old_key]);
+        assert!(reencrypt_file(&path, &rotated).unwrap());
+        assert!(!reencrypt_file(&path, &rotated).unwrap());
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(rotated.is_current(&contents));
+        assert_eq!(rotated.open(&contents).unwrap(), b"keyshare");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use crate::encryption::{ aes_decrypt, aes_encrypt, AES_KEY_BYTES_LEN };
+use crate::node::NodeIdentity;
+use anyhow::{ anyhow, bail, Result };
+use ed25519_dalek::Digest;
+use sha2::Sha256;
+use shared::recovery::EncryptedData;
+use std::env;
+use zeroize::Zeroizing;
+
+/// Optional passphrase the keyshare storage key is derived from instead of the node identity
+const PASSPHRASE_VAR: &str = "KEYSHARE_STORAGE_PASSPHRASE";
+/// Comma separated passphrases that were in use before the current one, only used for reading
+const PREVIOUS_PASSPHRASES_VAR: &str = "KEYSHARE_STORAGE_PREVIOUS_PASSPHRASES";
+
+/// Key every encrypted keyshare was written with before storage keys were derived per node
+const LEGACY_ENCRYPTION_KEY: &[u8; AES_KEY_BYTES_LEN] = b"65hjkt23scdfbfh8789kj2isdv870m84";
+
+const IDENTITY_KDF_CONTEXT: &[u8] = b"gridlock-keyshare-storage-v1";
+const PASSPHRASE_SALT_CONTEXT: &[u8] = b"gridlock-keyshare-storage-salt-v1";
+const FINGERPRINT_CONTEXT: &[u8] = b"gridlock-keyshare-storage-fingerprint";
+
+pub struct StorageKey {
+    key: Zeroizing<Vec<u8>>,
+    fingerprint: String,
+}
+
+impl StorageKey {
+    pub(crate) fn from_key_bytes(key: Vec<u8>) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(FINGERPRINT_CONTEXT);
+        hasher.update(&key);
+        let fingerprint = hex::encode(&hasher.finalize()[..8]);
+        Self { key: Zeroizing::new(key), fingerprint }
+    }
+
+    /// Key derived from the node's private networking key, unique to every node
+    pub fn from_identity(node: &NodeIdentity) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(IDENTITY_KDF_CONTEXT);
+        hasher.update(node.networking_private_key.as_bytes());
+        Self::from_key_bytes(hasher.finalize().to_vec())
+    }
+
+    /// Key stretched from an operator passphrase, salted with the node id so equal passphrases on
+    /// different nodes still give different keys
+    pub fn from_passphrase(passphrase: &str, node: &NodeIdentity) -> Result<Self> {
+        let mut hasher = Sha256::new();
+        hasher.update(PASSPHRASE_SALT_CONTEXT);
+        hasher.update(node.node_id.as_bytes());
+        let salt = hasher.finalize();
+        let key = argon2
+            ::hash_raw(passphrase.as_bytes(), &salt[..16], &argon2::Config::default())
+            .map_err(|err| anyhow!("Failed to derive the keyshare storage key: {}", err))?;
+        if key.len() != AES_KEY_BYTES_LEN {
+            bail!("Derived keyshare storage key has length {}", key.len());
+        }
+        Ok(Self::from_key_bytes(key))
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+}
+
+/// The key new keyshares are encrypted with plus every key older files may still be encrypted with
+pub struct StorageKeyring {
+    current: StorageKey,
+    previous: Vec<StorageKey>,
+}
+
+impl StorageKeyring {
+    pub fn new(current: StorageKey, previous: Vec<StorageKey>) -> Self {
+        Self { current, previous }
+    }
+
+    /// Uses the configured passphrase if there is one, otherwise the node identity. The identity
+    /// key stays readable after switching to a passphrase so existing files can be re-encrypted.
+    pub fn load() -> Result<Self> {
+        let node = NodeIdentity::cached()?;
+        let mut previous = Vec::new();
+        if let Ok(passphrases) = env::var(PREVIOUS_PASSPHRASES_VAR) {
+            for passphrase in passphrases.split(',').filter(|p| !p.is_empty()) {
+                previous.push(StorageKey::from_passphrase(passphrase, &node)?);
+            }
+        }
+
+        let current = match env::var(PASSPHRASE_VAR) {
+            Ok(passphrase) if !passphrase.is_empty() => {
+                previous.push(StorageKey::from_identity(&node));
+                StorageKey::from_passphrase(&passphrase, &node)?
+            }
+            _ => StorageKey::from_identity(&node),
+        };
+        Ok(Self::new(current, previous))
+    }
+
+    pub fn current_fingerprint(&self) -> &str {
+        &self.current.fingerprint
+    }
+
+    /// Encrypts with the current key, naming it in the key id hint of the data
+    pub fn seal(&self, plaintext: &[u8]) -> Result<String> {
+        let encrypted = aes_encrypt(plaintext, &self.current.key)?;
+        Ok(serde_json::to_string(&encrypted.with_key_id(&self.current.fingerprint))?)
+    }
+
+    /// Decrypts a keyshare written with any known storage key, data without a key id hint was
+    /// written with the legacy key
+    pub fn open(&self, contents: &str) -> Result<Vec<u8>> {
+        let encrypted = serde_json::from_str::<EncryptedData>(contents)?;
+        match &encrypted.key_id {
+            None => aes_decrypt(&encrypted, LEGACY_ENCRYPTION_KEY),
+            Some(key_id) => aes_decrypt(&encrypted, &self.find_key(key_id)?.key),
+        }
+    }
+
+    fn find_key(&self, fingerprint: &str) -> Result<&StorageKey> {
+        std::iter
+            ::once(&self.current)
+            .chain(self.previous.iter())
+            .find(|key| key.fingerprint == fingerprint)
+            .ok_or_else(|| {
+                anyhow!("Keyshare was encrypted with unknown storage key {}", fingerprint)
+            })
+    }
+
+    /// Whether the contents are encrypted at all, plaintext keyshares are kept next to encrypted ones
+    pub fn is_encrypted(contents: &str) -> bool {
+        serde_json::from_str::<EncryptedData>(contents).is_ok()
+    }
+
+    /// Whether the contents are encrypted with the current key and cipher, anything else gets
+    /// re-encrypted by the background job in `storage::reencryption`
+    pub fn is_current(&self, contents: &str) -> bool {
+        match serde_json::from_str::<EncryptedData>(contents) {
+            Ok(encrypted) =>
+                encrypted.has_current_cipher() &&
+                    encrypted.key_id.as_deref() == Some(self.current.fingerprint.as_str()),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> StorageKey {
+        StorageKey::from_key_bytes(vec![byte; AES_KEY_BYTES_LEN])
+    }
+
+    #[test]
+    fn reads_rotated_and_legacy_keyshares() {
+        let old = StorageKeyring::new(key(1), vec![]);
+        let sealed_with_old = old.seal(b"keyshare").unwrap();
+        let legacy = serde_json
+            ::to_string(&aes_encrypt(b"legacy keyshare", LEGACY_ENCRYPTION_KEY).unwrap())
+            .unwrap();
+
+        let rotated = StorageKeyring::new(key(2), vec![key(1)]);
+        assert_eq!(rotated.open(&sealed_with_old).unwrap(), b"keyshare");
+        assert!(!rotated.is_current(&sealed_with_old));
+        assert!(rotated.is_current(&rotated.seal(b"keyshare").unwrap()));
+
+        assert!(StorageKeyring::is_encrypted(&legacy));
+        assert!(!rotated.is_current(&legacy));
+        assert_eq!(rotated.open(&legacy).unwrap(), b"legacy keyshare");
+    }
+
+    #[test]
+    fn refuses_unknown_storage_keys() {
+        let sealed = StorageKeyring::new(key(1), vec![]).seal(b"keyshare").unwrap();
+        assert!(StorageKeyring::new(key(2), vec![]).open(&sealed).is_err());
+        assert!(!StorageKeyring::is_encrypted("{\"threshold\":2}"));
+    }
+}
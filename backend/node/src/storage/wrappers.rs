@@ -9,11 +9,13 @@ use derive_more::{ Deref, DerefMut, From, Into };
 use itertools::Itertools;
 use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::SharedKeys as EcSharedKeys;
 use multi_party_eddsa::protocols::thresholdsig::SharedKeys;
+use paillier::DecryptionKey;
 use schnorrkel::{ ExpansionMode, MiniSecretKey, SecretKey };
 use serde::de::{ DeserializeOwned, Error, MapAccess, SeqAccess, Visitor };
 use serde::ser::SerializeStruct;
 use serde::{ Deserialize, Deserializer, Serialize, Serializer };
 use std::fmt;
+use zeroize::Zeroize;
 use zk_paillier::zkproofs::DLogStatement;
 
 #[derive(Deref, DerefMut, From, Into, Debug, Clone)]
@@ -328,6 +330,18 @@ impl<'de> Deserialize<'de> for WBigInt {
     }
 }
 
+/// Paillier decryption key whose primes are wiped when it is dropped
+#[derive(Deref, From, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct WDecryptionKey(DecryptionKey);
+
+impl Drop for WDecryptionKey {
+    fn drop(&mut self) {
+        self.0.p.zeroize();
+        self.0.q.zeroize();
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WEdSharedKeys {
     pub y: WPoint<Ed25519>,
@@ -401,6 +415,12 @@ impl SchnorrkelSecretKey {
     }
 }
 
+impl Drop for SchnorrkelSecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
 impl From<Scalar<Ed25519>> for SchnorrkelSecretKey {
     fn from(value: Scalar<Ed25519>) -> Self {
         let hex = hex::encode(value.to_bytes().to_vec());
@@ -410,7 +430,7 @@ impl From<Scalar<Ed25519>> for SchnorrkelSecretKey {
 
 impl From<SchnorrkelSecretKey> for Scalar<Ed25519> {
     fn from(value: SchnorrkelSecretKey) -> Self {
-        let bytes = hex::decode(value.0).expect("hex decoded SchnorrkelSecretKey");
+        let bytes = hex::decode(&value.0).expect("hex decoded SchnorrkelSecretKey");
         Scalar::<Ed25519>::from_bytes(&bytes).expect("scalar constructed from bytes")
     }
 }
@@ -423,7 +443,7 @@ impl From<MiniSecretKey> for SchnorrkelSecretKey {
 
 impl From<SchnorrkelSecretKey> for MiniSecretKey {
     fn from(value: SchnorrkelSecretKey) -> Self {
-        let bytes = hex::decode(value.0).expect("hex decoded SchnorrkelSecretKey");
+        let bytes = hex::decode(&value.0).expect("hex decoded SchnorrkelSecretKey");
         MiniSecretKey::from_bytes(&bytes).expect("MiniSecretKey created from bytes)")
     }
 }
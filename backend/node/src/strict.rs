@@ -0,0 +1,175 @@
+use crate::command::{ CommandType, JsonCommand, MsgContext, TaggedCommandType };
+use crate::keygen;
+use crate::recovery::recovery_session::NewKeyShareRecoverySession;
+use crate::signing;
+use crate::tenant::Access;
+use crate::user_recovery::{ confirm::ConfirmRecoverySession, session::NewUserRecoverySession };
+use crate::{ route_message, MessageRoute };
+use anyhow::{ anyhow, bail, Result };
+use serde::de::DeserializeOwned;
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+
+/// Deserializes an inbound message, refusing fields the message type does not know.
+///
+/// Most message types deny unknown fields through serde, which doesn't support this for types
+/// that flatten the key type into the message. Their top level fields are checked here by
+/// serializing the parsed message again: a field of the input that doesn't come back was ignored.
+pub fn from_slice<T>(data: &[u8]) -> Result<T> where T: DeserializeOwned + Serialize {
+    from_value(serde_json::from_slice(data)?)
+}
+
+pub fn from_value<T>(value: Value) -> Result<T> where T: DeserializeOwned + Serialize {
+    let parsed: T = serde_json::from_value(value.clone())?;
+    ensure_no_unknown_fields(&value, &parsed)?;
+    Ok(parsed)
+}
+
+fn ensure_no_unknown_fields<T: Serialize>(input: &Value, parsed: &T) -> Result<()> {
+    let (input, known) = match (input, serde_json::to_value(parsed)?) {
+        (Value::Object(input), Value::Object(known)) => (input, known),
+        _ => {
+            return Ok(());
+        }
+    };
+    // Fields left out when serializing because they are empty may still be sent
+    let unknown = input
+        .iter()
+        .find(|(field, value)| !is_empty(value) && !known.contains_key(field.as_str()));
+    match unknown {
+        Some((field, _)) => bail!("unknown field `{}`", field),
+        None => Ok(()),
+    }
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Array(items) => items.is_empty(),
+        Value::Object(fields) => fields.is_empty(),
+        _ => false,
+    }
+}
+
+/// Checks a command or session message against the schema of the message type, without acting on
+/// it. The type is taken from the subject the payload would be sent on, a payload without subject
+/// is checked as a command.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ValidatePayloadCommand {
+    pub payload: Value,
+    #[serde(default)]
+    pub subject: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct PayloadValidation {
+    pub valid: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ValidatePayloadCommand {
+    fn validate(self) -> Result<()> {
+        let route = match &self.subject {
+            Some(subject) => {
+                route_message(subject).ok_or_else(|| anyhow!("Unknown subject {}", subject))?
+            }
+            None => MessageRoute::Command,
+        };
+        let payload = self.payload;
+        match route {
+            MessageRoute::KeyGenECDSA => check::<keygen::ecdsa::NewKeyGenMessage>(payload),
+            | MessageRoute::KeyGenEdDSA
+            | MessageRoute::KeyGenFrost
+            | MessageRoute::KeyGenSr25519
+            | MessageRoute::KeyGenBLS => {
+                check::<keygen::eddsa::session::NewKeyGenMessage>(payload)
+            }
+            MessageRoute::KeySignECDSA => check::<signing::ecdsa::NewSignMessage>(payload),
+            MessageRoute::KeySignEdDSA => {
+                check::<signing::eddsa::session::NewEdDSAKeySignMessage>(payload)
+            }
+            MessageRoute::KeySignSr25519 => {
+                check::<signing::sr25519_musign::NewSr25519KeySignSession>(payload)
+            }
+            MessageRoute::KeySignFrost => {
+                check::<signing::frost::session::NewFrostKeySignMessage>(payload)
+            }
+            MessageRoute::KeySignBLS => {
+                check::<signing::bls::session::NewBLSKeySignSession>(payload)
+            }
+            MessageRoute::PresignECDSA => check::<signing::presigning::NewPresignSession>(payload),
+            MessageRoute::KeyShareRecovery => check::<NewKeyShareRecoverySession>(payload),
+            MessageRoute::UserRecovery => check::<NewUserRecoverySession>(payload),
+            MessageRoute::UserRecoveryConfirm => check::<ConfirmRecoverySession>(payload),
+            // Commands are dispatched like `process_request` does, tagged ones first
+            MessageRoute::Command =>
+                check::<TaggedCommandType>(payload.clone()).or_else(|tagged_err| {
+                    if payload.get("cmd").is_some() {
+                        return Err(tagged_err);
+                    }
+                    check::<CommandType>(payload)
+                }),
+        }
+    }
+}
+
+fn check<T>(payload: Value) -> Result<()> where T: DeserializeOwned + Serialize {
+    from_value::<T>(payload).map(|_| ())
+}
+
+impl JsonCommand for ValidatePayloadCommand {
+    type Response = PayloadValidation;
+
+    fn access(&self) -> Access<'_> {
+        Access::Node
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        Ok(match self.validate() {
+            Ok(()) => PayloadValidation { valid: true, error: None },
+            Err(err) => PayloadValidation { valid: false, error: Some(err.to_string()) },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn refuses_unknown_fields_of_flattened_and_plain_messages() {
+        let command = json!({
+            "cmd": "RevokeRecoverySession",
+            "session_id": "session-1",
+        });
+        let validate = |payload: Value, subject: Option<&str>| {
+            (ValidatePayloadCommand { payload, subject: subject.map(str::to_string) })
+                .execute_message(MsgContext::FFI)
+                .unwrap()
+        };
+        assert!(validate(command.clone(), None).valid);
+
+        let mut misspelled = command;
+        misspelled["node_idz"] = json!(["node-1"]);
+        assert!(!validate(misspelled, None).valid);
+
+        // Serde can't deny unknown fields next to the flattened key type
+        let mut signing = json!({
+            "cmd": "OrchestrateSigning",
+            "key_type": "EDDSA",
+            "key_id": "key-1",
+            "session_id": "session-2",
+            "party_nodes": [],
+            "msg": [104, 105],
+        });
+        assert!(validate(signing.clone(), None).valid);
+        signing["hash_mod"] = json!("Sha256");
+        assert!(!validate(signing, None).valid);
+
+        let unknown_subject = validate(json!({}), Some("network.gridlock.nodes.unknown"));
+        assert!(!unknown_subject.valid);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::auth::client_e2e_decrypt_secret;
+use crate::node::NodeIdentity;
+use crate::signing::validation::{ check_access_key, verify_hmac_input, verify_timestamp };
+use crate::storage::backend::{ storage_backend, StorageItem };
+use crate::storage::fs::FileSystem;
+use anyhow::{ bail, Result };
+use serde::{ Deserialize, Serialize };
+use std::cell::RefCell;
+use std::env;
+use std::fmt::Debug;
+use std::path::{ Component, Path };
+
+/// Set on nodes holding keys of several accounts: commands reading keys then have to prove the
+/// account they act for
+const MULTI_USER_VAR: &str = "MULTI_USER_NODE";
+
+thread_local! {
+    static CURRENT_TENANT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Proof that the caller holds the access key of an account, the same fields a signing request
+/// carries. The HMAC covers the key ids so a captured request can't be used for other keys.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TenantAuth {
+    pub email: String,
+    pub timestamp: String,
+    /// Base64 HMAC-SHA256 of `timestamp + email + key ids joined by ","` with the access key
+    pub message_hmac: String,
+    pub client_e2e_public_key: String,
+    pub encrypted_signing_key: String,
+}
+
+impl Debug for TenantAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TenantAuth")
+            .field("email", &self.email)
+            .field("timestamp", &self.timestamp)
+            .finish()
+    }
+}
+
+impl TenantAuth {
+    /// Verifies the proof for the keys and confines storage access on this thread to the account
+    /// until the returned scope is dropped
+    pub fn verify(&self, key_ids: &[String], node: &NodeIdentity) -> Result<TenantScope> {
+        self.verify_with(key_ids, node, |key_id, node_signing_key| {
+            check_access_key(key_id, &self.email, node_signing_key)
+        })
+    }
+
+    /// Verifies the proof for keys restored from a backup. A node that doesn't know the account
+    /// yet takes the access key from the backup, which has to be the one the request proves.
+    pub fn verify_restore(
+        &self,
+        key_ids: &[String],
+        node: &NodeIdentity,
+        restored_access_key: Option<&str>
+    ) -> Result<TenantScope> {
+        self.verify_with(key_ids, node, |key_id, node_signing_key| {
+            if restored_access_key.is_some_and(|restored| restored != node_signing_key) {
+                bail!("The backup holds another access key than the one the request proves");
+            }
+            let email = self.email.as_str();
+            let access = StorageItem::KeyMetadata { key_id, metadata_type: "access", email };
+            match storage_backend()?.exists(&access)? {
+                true => check_access_key(key_id, &self.email, node_signing_key),
+                false if restored_access_key.is_some() => Ok(()),
+                false => bail!("Account {} has no access key on this node", self.email),
+            }
+        })
+    }
+
+    fn verify_with(
+        &self,
+        key_ids: &[String],
+        node: &NodeIdentity,
+        check_access: impl Fn(&str, &str) -> Result<()>
+    ) -> Result<TenantScope> {
+        check_email(&self.email)?;
+        let node_signing_key = client_e2e_decrypt_secret(
+            &self.encrypted_signing_key,
+            &node.e2e_private_key,
+            &self.client_e2e_public_key
+        )?;
+        let message_input = format!("{}{}{}", self.timestamp, self.email, key_ids.join(","));
+        if !verify_hmac_input(&self.message_hmac, &message_input, &node_signing_key) {
+            bail!("HMAC verification failed");
+        }
+        for key_id in key_ids {
+            check_access(key_id, &node_signing_key)?;
+        }
+        // Commands about the whole account prove it with the access key every key of the account
+        // shares
+        if key_ids.is_empty() {
+            check_access("", &node_signing_key)?;
+        }
+        // Timestamps are only recorded once every key is authorized
+        for key_id in key_ids {
+            if !verify_timestamp(key_id, &self.timestamp, &self.email) {
+                bail!("Timestamp verification failed for key {}", key_id);
+            }
+        }
+        TenantScope::enter(&self.email)
+    }
+}
+
+/// What a command has to prove about the account it acts for. Every command declares it with
+/// `JsonCommand::access`, the dispatcher checks it before the command runs.
+pub enum Access<'a> {
+    /// Reads nothing of an account, e.g. the node's health, identity or configuration
+    Node,
+    /// Reads keys of the account the proof is for. Single user nodes also run it without one.
+    Account {
+        auth: Option<&'a TenantAuth>,
+        key_ids: Vec<String>,
+    },
+    /// Checks its caller itself, e.g. a request carrying the owner's HMAC of its own payload or
+    /// a grant signed by the operator
+    Checked,
+    /// Sent by other guardians during a ceremony over a key, not by an account holder
+    Peer,
+}
+
+/// Verifies the proof a command carries. Single user nodes accept commands without one, on multi
+/// user nodes every command reading keys has to carry it.
+pub fn authorize(
+    auth: Option<&TenantAuth>,
+    key_ids: &[String],
+    node: &NodeIdentity
+) -> Result<Option<TenantScope>> {
+    match auth {
+        Some(auth) => auth.verify(key_ids, node).map(Some),
+        None if is_multi_user() => {
+            bail!("This node holds keys of several accounts, the request has to prove its account")
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn is_multi_user() -> bool {
+    env::var(MULTI_USER_VAR).is_ok_and(|value| value == "true")
+}
+
+/// Account storage on this thread is confined to, restores the previous one when dropped. The
+/// dispatcher holds it while a command runs as a backstop for storage reads, commands take the
+/// account from their `MsgContext`.
+pub struct TenantScope {
+    previous: Option<String>,
+}
+
+impl TenantScope {
+    pub fn enter(email: &str) -> Result<Self> {
+        check_email(email)?;
+        let previous = CURRENT_TENANT.with(|current| current.replace(Some(email.to_string())));
+        Ok(Self { previous })
+    }
+}
+
+impl Drop for TenantScope {
+    fn drop(&mut self) {
+        CURRENT_TENANT.with(|current| current.replace(self.previous.take()));
+    }
+}
+
+/// Account of the command running on this thread, `None` outside of a tenant scope
+pub fn current() -> Option<String> {
+    CURRENT_TENANT.with(|current| current.borrow().clone())
+}
+
+/// Refuses access to the directory of another account than the one in scope
+pub fn ensure_account(email: &str) -> Result<()> {
+    match current() {
+        Some(tenant) if tenant != email => {
+            bail!("Account {} can't access keys of another account", tenant)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Refuses keys that are not stored under the account in scope. Keys in the flat layout belong
+/// to no account and are only accessible outside of a tenant scope.
+pub fn ensure_key_access(key_id: &str) -> Result<()> {
+    match current() {
+        Some(tenant) if !FileSystem::keyfile_exists_with_email(key_id, 0, &tenant)? => {
+            bail!("Key {} is not stored for account {}", key_id, tenant)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Emails become directory names, so they have to be a single plain path component
+fn check_email(email: &str) -> Result<()> {
+    let mut components = Path::new(email).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => bail!("Invalid email {:?}", email),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_confine_the_thread_to_one_account() {
+        assert!(TenantScope::enter("../other").is_err());
+        assert_eq!(current(), None);
+        {
+            let _scope = TenantScope::enter("alice@example.com").unwrap();
+            assert!(ensure_account("alice@example.com").is_ok());
+            assert!(ensure_account("bob@example.com").is_err());
+            {
+                let _nested = TenantScope::enter("bob@example.com").unwrap();
+                assert_eq!(current().as_deref(), Some("bob@example.com"));
+            }
+            assert_eq!(current().as_deref(), Some("alice@example.com"));
+        }
+        assert_eq!(current(), None);
+        assert!(ensure_account("bob@example.com").is_ok());
+    }
+}
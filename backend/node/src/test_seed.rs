@@ -0,0 +1,96 @@
+use cfg_if::cfg_if;
+use curv::arithmetic::Converter;
+use curv::elliptic::curves::{ Curve, Scalar };
+use curv::BigInt;
+use sha2::{ Digest, Sha512 };
+
+/// Hex seed the node side randomness of key generation and signing is derived from. Only read
+/// when the node is built with the `deterministic-seeds` feature, which is meant for integration
+/// tests and must never be enabled in a build that holds real keys.
+///
+/// The seed covers the ceremony entropy keygen secrets are derived from and the FROST and EdDSA
+/// signing nonces. Randomness the protocol libraries draw themselves is not covered: VSS
+/// polynomials and commitment blinds, Paillier keys and the GG20 signing nonces. Tests have to
+/// compare public keys and verify signatures instead of expecting the same transcript.
+const SEED_VAR: &str = "TEST_CEREMONY_SEED";
+
+cfg_if! {
+    if #[cfg(feature = "deterministic-seeds")] {
+        use anyhow::{ anyhow, Result };
+        use std::env;
+        use std::sync::Once;
+        use tracing::warn;
+
+        static WARNING: Once = Once::new();
+
+        /// Refuses a seed that is not hex, called once when the node starts
+        pub fn check_configured() -> Result<()> {
+            match env::var(SEED_VAR) {
+                Ok(seed) => {
+                    hex::decode(seed.trim()).map_err(|err| {
+                        anyhow!("{} is not hex: {}", SEED_VAR, err)
+                    })?;
+                    Ok(())
+                }
+                Err(_) => Ok(()),
+            }
+        }
+
+        /// Bytes derived from the test seed and `label`, `None` when no seed is configured
+        pub fn seeded_bytes(label: &str, len: usize) -> Option<Vec<u8>> {
+            // A seed that is not hex was refused by `check_configured` when the node started
+            let seed = hex::decode(env::var(SEED_VAR).ok()?.trim()).ok()?;
+            WARNING.call_once(|| {
+                warn!("Deterministic test seed in use, keys generated by this node are not secret")
+            });
+            Some(expand(&seed, label, len))
+        }
+    } else {
+        /// Nothing to check, deterministic seeds are only available with `deterministic-seeds`
+        pub fn check_configured() -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        /// Always `None`, deterministic seeds are only available with `deterministic-seeds`
+        pub fn seeded_bytes(_label: &str, _len: usize) -> Option<Vec<u8>> {
+            None
+        }
+    }
+}
+
+pub fn seeded_scalar<C: Curve>(label: &str) -> Option<Scalar<C>> {
+    seeded_bytes(label, 64).map(|bytes| Scalar::from_bigint(&BigInt::from_bytes(&bytes)))
+}
+
+/// SHA-512 in counter mode, labels are length prefixed so no label is a prefix of another
+fn expand(seed: &[u8], label: &str, len: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(len);
+    let mut counter = 0u32;
+    while output.len() < len {
+        let mut hasher = Sha512::new();
+        hasher.update(seed);
+        hasher.update((label.len() as u64).to_be_bytes());
+        hasher.update(label.as_bytes());
+        hasher.update(counter.to_be_bytes());
+        output.extend_from_slice(&hasher.finalize());
+        counter += 1;
+    }
+    output.truncate(len);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expansion_is_reproducible_and_label_separated() {
+        let seed = [42u8; 32];
+        let bytes = expand(&seed, "frost-nonce/1", 100);
+        assert_eq!(bytes.len(), 100);
+        assert_eq!(bytes, expand(&seed, "frost-nonce/1", 100));
+        assert_eq!(&bytes[..32], &expand(&seed, "frost-nonce/1", 32)[..]);
+        assert_ne!(bytes, expand(&seed, "frost-nonce/2", 100));
+        assert_ne!(bytes, expand(&[43u8; 32], "frost-nonce/1", 100));
+    }
+}
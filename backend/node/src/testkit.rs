@@ -0,0 +1,358 @@
+//! Helpers for multi-party integration tests: a NATS server and a pool of guardian nodes on it,
+//! driven through the same commands the communication hub sends.
+//!
+//! Node identity and storage are process wide, so every node of the pool is a `guardian-node`
+//! process with its own storage directory rather than an `App` in the test process.
+
+use crate::auth::e2e_decrypt;
+use crate::communication::blocking::Connection;
+use crate::eject::EncryptedEjectInfo;
+use crate::session_manager;
+use anyhow::{ anyhow, bail, Context, Result };
+use serde_json::{ json, Value };
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{ Child, Command, Stdio };
+use std::time::Duration;
+use tracing::{ info, warn };
+use uuid::Uuid;
+
+/// URL of a running NATS server to use instead of starting one in docker
+const NATS_URL_VAR: &str = "TESTKIT_NATS_URL";
+/// Path of the `guardian-node` binary the pool runs
+const NODE_BIN_VAR: &str = "TESTKIT_NODE_BIN";
+const DEFAULT_NODE_BIN: &str = "target/debug/guardian-node";
+const NATS_IMAGE: &str = "nats:2";
+const NATS_USER: &str = "testkit";
+const NATS_PASSWORD: &str = "testkit";
+
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Orchestrated sessions reply once every party is done
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// NATS server the pool runs against, a docker container stopped on drop unless
+/// `TESTKIT_NATS_URL` names one
+pub struct NatsServer {
+    pub url: String,
+    container: Option<String>,
+}
+
+impl NatsServer {
+    pub fn start() -> Result<Self> {
+        if let Ok(url) = env::var(NATS_URL_VAR) {
+            return Ok(NatsServer { url, container: None });
+        }
+        let container = docker(
+            &[
+                "run",
+                "-d",
+                "--rm",
+                "-p",
+                "127.0.0.1::4222",
+                NATS_IMAGE,
+                "--user",
+                NATS_USER,
+                "--pass",
+                NATS_PASSWORD,
+            ]
+        )?;
+        // Stops the container if its port can't be found
+        let mut server = NatsServer { url: String::new(), container: Some(container.clone()) };
+        let address = docker(&["port", &container, "4222"])?;
+        let address = address
+            .lines()
+            .next()
+            .ok_or_else(|| anyhow!("NATS container {} publishes no port", container))?;
+        info!("Started NATS container {} on {}", container, address);
+        server.url = format!("nats://{}", address);
+        Ok(server)
+    }
+
+    pub fn connect(&self) -> Result<Connection> {
+        let deadline = std::time::Instant::now() + READY_TIMEOUT;
+        loop {
+            let options = async_nats::ConnectOptions::with_user_and_password(
+                NATS_USER.to_string(),
+                NATS_PASSWORD.to_string()
+            );
+            match session_manager::runtime().block_on(options.connect(self.url.as_str())) {
+                Ok(client) => {
+                    return Ok(Connection::new(client));
+                }
+                // The container accepts connections a moment after it starts
+                Err(_) if std::time::Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(200));
+                }
+                Err(err) => bail!("Failed to connect to NATS at {}: {}", self.url, err),
+            }
+        }
+    }
+}
+
+impl Drop for NatsServer {
+    fn drop(&mut self) {
+        if let Some(container) = &self.container {
+            if let Err(err) = docker(&["stop", container]) {
+                warn!("Failed to stop NATS container {}: {}", container, err);
+            }
+        }
+    }
+}
+
+fn docker(args: &[&str]) -> Result<String> {
+    let output = Command::new("docker").args(args).output().context("Failed to run docker")?;
+    if !output.status.success() {
+        bail!("docker {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Node of the pool, killed and its storage removed on drop
+pub struct TestNode {
+    pub node_id: String,
+    pub networking_public_key: String,
+    pub e2e_public_key: String,
+    pub storage_dir: PathBuf,
+    process: Child,
+}
+
+impl Drop for TestNode {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.storage_dir);
+    }
+}
+
+pub struct NodePool {
+    pub nodes: Vec<TestNode>,
+    nc: Connection,
+    nats_url: String,
+}
+
+impl NodePool {
+    /// Starts `count` nodes on the server and waits until each of them is ready
+    pub fn spawn(count: usize, nats: &NatsServer) -> Result<Self> {
+        let mut pool = NodePool {
+            nodes: Vec::new(),
+            nc: nats.connect()?,
+            nats_url: nats.url.clone(),
+        };
+        for _ in 0..count {
+            let node = pool.spawn_node()?;
+            pool.nodes.push(node);
+        }
+        Ok(pool)
+    }
+
+    /// Starts a node outside the pool, e.g. to replace a lost guardian in a recovery
+    pub fn spawn_node(&self) -> Result<TestNode> {
+        let storage_dir = env::temp_dir().join(format!("guardian-testkit-{}", Uuid::new_v4()));
+        fs::create_dir_all(&storage_dir)?;
+        let bin = env::var(NODE_BIN_VAR).unwrap_or_else(|_| DEFAULT_NODE_BIN.to_string());
+        // Nodes announce themselves with their id on the ready subject once they are listening
+        let ready = self.nc.subscribe("network.gridlock.nodes.ready.*")?;
+        let process = Command::new(&bin)
+            .env("STORAGE_DIR", &storage_dir)
+            .env("NATS_NETWORK", &self.nats_url)
+            .env("NATS_USER", NATS_USER)
+            .env("NATS_PASSWORD", NATS_PASSWORD)
+            .env("HTTP_STATUS_ADDR", format!("127.0.0.1:{}", free_port()?))
+            .stdout(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to start {}", bin))?;
+        let mut node = TestNode {
+            node_id: String::new(),
+            networking_public_key: String::new(),
+            e2e_public_key: String::new(),
+            storage_dir,
+            process,
+        };
+        let message = ready
+            .next_timeout(READY_TIMEOUT)
+            .map_err(|_| anyhow!("Node {} did not get ready in time", bin))?;
+        node.node_id = String::from_utf8(message.data)?;
+
+        let info = self.command_to(&node.node_id, &json!({ "cmd": "GetNodeInfo" }))?;
+        node.networking_public_key = string_field(&info, "networking_public_key")?;
+        node.e2e_public_key = string_field(&info, "e2e_public_key")?;
+        info!("Node {} is ready", node.node_id);
+        Ok(node)
+    }
+
+    pub fn node_ids(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .map(|node| node.node_id.clone())
+            .collect()
+    }
+
+    /// Sends a command to a node and returns its response, failing on an error response
+    pub fn command_to(&self, node_id: &str, command: &Value) -> Result<Value> {
+        let subject = format!("network.gridlock.nodes.Message.new.{}", node_id);
+        let reply = self.nc
+            .request_timeout(&subject, serde_json::to_vec(command)?, COMMAND_TIMEOUT)
+            .with_context(|| format!("No response from node {}", node_id))?;
+        let response = String::from_utf8(reply.data)?;
+        if let Some(err) = response.strip_prefix("ERROR: ") {
+            bail!("Node {} failed the command: {}", node_id, err);
+        }
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    /// Sends a command to the first node of the pool, which orchestrates the session
+    pub fn command(&self, command: &Value) -> Result<Value> {
+        let orchestrator = self.nodes
+            .first()
+            .ok_or_else(|| anyhow!("The pool has no nodes"))?;
+        self.command_to(&orchestrator.node_id, command)
+    }
+
+    /// Generates a key shared by every node of the pool
+    pub fn keygen(&self, key_type: &str, key_id: &str) -> Result<Value> {
+        self.command(
+            &json!({
+                "cmd": "OrchestrateKeyGen",
+                "key_type": key_type,
+                "key_id": key_id,
+                "session_id": Uuid::new_v4().to_string(),
+                "party_nodes": self.node_ids(),
+            })
+        )
+    }
+
+    pub fn sign(&self, key_type: &str, key_id: &str, message: &[u8]) -> Result<Value> {
+        self.command(
+            &json!({
+                "cmd": "OrchestrateSigning",
+                "key_type": key_type,
+                "key_id": key_id,
+                "session_id": Uuid::new_v4().to_string(),
+                "party_nodes": self.node_ids(),
+                "msg": message,
+            })
+        )
+    }
+
+    /// Replaces the node at `lost` with `replacement`, the remaining nodes regenerate its share.
+    /// The replacement takes the place of the lost node in the pool.
+    pub fn recover(
+        &mut self,
+        key_type: &str,
+        key_id: &str,
+        email: &str,
+        lost: usize,
+        replacement: TestNode
+    ) -> Result<Value> {
+        let old_node_id = self.nodes[lost].node_id.clone();
+        let helpers: Vec<String> = self
+            .node_ids()
+            .into_iter()
+            .filter(|node_id| *node_id != old_node_id)
+            .collect();
+        let orchestrator = helpers
+            .first()
+            .ok_or_else(|| anyhow!("No node is left to recover the share"))?
+            .clone();
+        let response = self.command_to(
+            &orchestrator,
+            &json!({
+                "cmd": "OrchestrateRecovery",
+                "key_type": key_type,
+                "key_id": key_id,
+                "session_id": Uuid::new_v4().to_string(),
+                "new_node_id": replacement.node_id,
+                "new_node_public_key": replacement.networking_public_key,
+                "old_node_id": old_node_id,
+                "party_nodes": helpers,
+                "email": email,
+            })
+        )?;
+        self.nodes[lost] = replacement;
+        Ok(response)
+    }
+
+    /// Collects the shares of every node and reconstructs the keys from them on the first node.
+    /// `authorize` builds the `TenantAuth` for the keys a node accepts from the client, once per
+    /// command as every proof needs a newer timestamp. Shares and keys come encrypted to the client
+    /// e2e key, the keys are returned decrypted by key id.
+    pub fn eject(
+        &self,
+        key_ids: &[&str],
+        client_e2e_private_key: &str,
+        authorize: impl Fn(&TestNode) -> Result<Value>
+    ) -> Result<Value> {
+        let eject_info = self.nodes
+            .iter()
+            .map(|node| {
+                let command = json!({
+                    "key_ids_to_eject": key_ids,
+                    "authorization": authorize(node)?,
+                });
+                let response = self.command_to(&node.node_id, &command)?;
+                let encrypted: EncryptedEjectInfo = serde_json::from_value(response)?;
+                let eject_info = e2e_decrypt(
+                    &encrypted.encrypted_eject_info,
+                    client_e2e_private_key,
+                    &encrypted.node_e2e_public_key
+                )?;
+                Ok(serde_json::from_slice::<Value>(&eject_info)?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let orchestrator = self.nodes
+            .first()
+            .ok_or_else(|| anyhow!("The pool has no nodes"))?;
+        let response = self.command_to(
+            &orchestrator.node_id,
+            &json!({
+                "key_ids": key_ids,
+                "eject_info": eject_info,
+                "authorization": authorize(orchestrator)?,
+            })
+        )?;
+        let mut keys = serde_json::Map::new();
+        for result in response.as_array().ok_or_else(|| anyhow!("Expected a list of keys"))? {
+            let key = e2e_decrypt(
+                &string_field(result, "key")?,
+                client_e2e_private_key,
+                &string_field(result, "node_e2e_public_key")?
+            )?;
+            keys.insert(string_field(result, "key_id")?, Value::String(String::from_utf8(key)?));
+        }
+        Ok(Value::Object(keys))
+    }
+}
+
+fn string_field(value: &Value, field: &str) -> Result<String> {
+    value
+        .get(field)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Response has no field {}", field))
+}
+
+fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs docker and a built guardian-node binary"]
+    fn keygen_sign_and_recover_with_three_nodes() {
+        let nats = NatsServer::start().unwrap();
+        let mut pool = NodePool::spawn(3, &nats).unwrap();
+        let key_id = Uuid::new_v4().to_string();
+
+        pool.keygen("EDDSA", &key_id).unwrap();
+        pool.sign("EDDSA", &key_id, b"hello").unwrap();
+
+        let replacement = pool.spawn_node().unwrap();
+        pool.recover("EDDSA", &key_id, "owner@example.com", 2, replacement).unwrap();
+        pool.sign("EDDSA", &key_id, b"hello again").unwrap();
+    }
+}
@@ -1,25 +1,40 @@
-use crate::auth::e2e_decrypt;
+use crate::auth::client_e2e_decrypt;
+use crate::communication::blocking;
 use crate::node::NodeIdentity;
 use crate::storage::fs::WriteOpts;
 use crate::storage::key_metadata_store::KeyMetadataStore;
-use anyhow::Result;
-use nats::Message;
+use crate::user_recovery::verifier::{ configured_verifiers, PendingRecovery };
+use anyhow::{ anyhow, Result };
+use crate::communication::incoming::IncomingMessage;
+use crate::session_error::{ self, SessionErrorCode };
 use serde::{ Deserialize, Serialize };
+use std::collections::BTreeMap;
 use std::thread;
 use tracing::{ error, info };
+use zeroize::Zeroizing;
 
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ConfirmRecoverySession {
     pub key_id: String,
     pub client_e2e_public_key: String,
     pub encrypted_recovery_confirmation: String,
     pub email: Option<String>,
+    /// RFC 3339 time of the request, checked against the replay window, see `replay`
+    #[serde(default)]
+    pub timestamp: Option<String>,
+    /// HMAC of timestamp and email made with the recovery key sent with the recovery request
+    #[serde(default)]
+    pub message_hmac: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 struct RecoveryConfirmationData {
     recovery_challenge: String,
     client_identity_public_key: String,
+    /// Codes of the second factors this node requires, by factor name
+    #[serde(default)]
+    second_factor_codes: BTreeMap<String, String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -29,11 +44,16 @@ pub struct RecoveryConfirmation {
     pub error: Option<String>,
 }
 
-pub fn handle_new_session_message(app: &crate::App, message: Message) {
+pub fn handle_new_session_message(app: &crate::App, message: IncomingMessage) {
     let confirmation = match serde_json::from_slice::<ConfirmRecoverySession>(&message.data[..]) {
         Ok(confirmation) => confirmation,
         Err(err) => {
-            error!("Incorrect recovery confirmation message format: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Incorrect recovery confirmation message format: {}", err)
+            );
             return;
         }
     };
@@ -51,10 +71,10 @@ pub fn handle_new_session_message(app: &crate::App, message: Message) {
 }
 
 fn confirm_recovery_session(
-    _conn: nats::Connection,
+    _conn: blocking::Connection,
     confirmation: ConfirmRecoverySession
 ) -> Result<()> {
-    let node = match NodeIdentity::load() {
+    let node = match NodeIdentity::cached() {
         Ok(node) => node,
         Err(err) => {
             error!("Failed to load node identity: {}", err);
@@ -76,11 +96,13 @@ fn confirm_recovery_session(
     };
 
     // Decrypt and validate the recovery confirmation
-    let decrypted_confirmation = e2e_decrypt(
-        &confirmation.encrypted_recovery_confirmation,
-        &node.e2e_private_key,
-        &confirmation.client_e2e_public_key
-    )?;
+    let decrypted_confirmation = Zeroizing::new(
+        client_e2e_decrypt(
+            &confirmation.encrypted_recovery_confirmation,
+            &node.e2e_private_key,
+            &confirmation.client_e2e_public_key
+        )?
+    );
 
     let recovery_data: RecoveryConfirmationData = match
         serde_json::from_slice(&decrypted_confirmation)
@@ -106,6 +128,18 @@ fn confirm_recovery_session(
         return Ok(());
     }
 
+    // Verify the second factors before anything of the recovery is released
+    let recovery = PendingRecovery {
+        email: &recovery_email,
+        key_id: &confirmation.key_id,
+    };
+    for verifier in configured_verifiers()? {
+        if let Err(err) = verifier.verify(&recovery, &recovery_data.second_factor_codes) {
+            error!("Recovery {} verification failed: {}", verifier.name(), err);
+            return Err(anyhow!("{} verification failed: {}", verifier.name(), err));
+        }
+    }
+
     // Store client's E2E public key for future communication
     if
         let Err(e) = KeyMetadataStore::save_user_level(
@@ -1,5 +1,6 @@
 pub mod session;
 pub mod confirm;
+pub mod verifier;
 
 pub use session::{
     NewUserRecoverySession,
@@ -1,20 +1,30 @@
-use crate::auth::{ e2e_decrypt, e2e_encrypt };
+use crate::auth::{ client_e2e_decrypt_secret, e2e_encrypt };
+use crate::communication::blocking;
 use crate::node::NodeIdentity;
 use crate::storage::fs::WriteOpts;
 use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::user_recovery::verifier::{ configured_verifiers, PendingRecovery };
 use crate::App;
-use nats::Message;
+use crate::communication::incoming::IncomingMessage;
+use crate::session_error::{ self, SessionErrorCode };
 use serde::{ Deserialize, Serialize };
 use std::thread;
 use tracing::{ error, info };
 use uuid::Uuid;
 
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct NewUserRecoverySession {
     pub key_id: String,
     pub client_e2e_public_key: String,
     pub encrypted_recovery_key: String,
     pub email: Option<String>,
+    /// RFC 3339 time of the request, checked against the replay window, see `replay`
+    #[serde(default)]
+    pub timestamp: Option<String>,
+    /// HMAC of timestamp and email made with the recovery key
+    #[serde(default)]
+    pub message_hmac: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -23,11 +33,16 @@ pub struct E2EData {
     pub encrypted_recovery_key: String,
 }
 
-pub fn handle_new_session_message(app: &App, message: Message) {
+pub fn handle_new_session_message(app: &App, message: IncomingMessage) {
     let session = match serde_json::from_slice::<NewUserRecoverySession>(&message.data[..]) {
         Ok(session) => session,
         Err(err) => {
-            error!("Incorrect user recovery message format: {}", err);
+            session_error::refuse(
+                app,
+                &message,
+                SessionErrorCode::MalformedRequest,
+                format!("Incorrect user recovery message format: {}", err)
+            );
             return;
         }
     };
@@ -45,10 +60,10 @@ pub fn handle_new_session_message(app: &App, message: Message) {
 }
 
 fn recovery_session(
-    _conn: nats::Connection,
+    _conn: blocking::Connection,
     session: NewUserRecoverySession
 ) -> anyhow::Result<()> {
-    let node = match NodeIdentity::load() {
+    let node = match NodeIdentity::cached() {
         Ok(node) => node,
         Err(err) => {
             error!("Failed to load node identity: {}", err);
@@ -69,15 +84,15 @@ fn recovery_session(
         return Err(err.into());
     };
 
+    let verifiers = configured_verifiers()?;
+
     // Decrypt and store the recovery key
-    let decrypted_recovery_key = e2e_decrypt(
+    let recovery_key_str = client_e2e_decrypt_secret(
         &session.encrypted_recovery_key,
         &node.e2e_private_key,
         &session.client_e2e_public_key
     )?;
 
-    let recovery_key_str = String::from_utf8(decrypted_recovery_key)?;
-
     // Store client's E2E public key for future communication
     if
         let Err(e) = KeyMetadataStore::save_user_level(
@@ -109,7 +124,7 @@ fn recovery_session(
         serde_json::json!({
         "guardian_node_id": node.node_id,
         "recovery_challenge": recovery_challenge,
-        "recovery_key": recovery_key_str,
+        "recovery_key": recovery_key_str.as_str(),
     });
 
     let encrypted_bundle = match
@@ -139,18 +154,16 @@ fn recovery_session(
         return Ok(());
     }
 
-    // Send recovery email with encrypted challenge bundle
-    match send_recovery_email(&recovery_email, &encrypted_bundle) {
-        Ok(()) => info!("Recovery email sent to {} with encrypted bundle", recovery_email),
-        Err(err) => {
-            error!("Failed to send recovery email: {}", err);
+    // Send recovery email with encrypted challenge bundle, and the codes of further factors
+    let recovery = PendingRecovery {
+        email: &recovery_email,
+        key_id: &session.key_id,
+    };
+    for verifier in &verifiers {
+        if let Err(err) = verifier.start(&recovery, &encrypted_bundle) {
+            error!("Failed to start {} verification: {}", verifier.name(), err);
             return Err(err);
         }
     }
     Ok(())
 }
-
-fn send_recovery_email(email: &str, encrypted_bundle: &str) -> anyhow::Result<()> {
-    info!("(Dummy) Sending email to {}: recovery challenge: {}", email, encrypted_bundle);
-    Ok(())
-}
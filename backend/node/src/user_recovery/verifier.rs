@@ -0,0 +1,332 @@
+//! Factors a user proves before this node confirms their recovery. The challenge always reaches
+//! the user by email; partner guardians may require further factors with `RECOVERY_SECOND_FACTORS`
+//! before the access key of the account is released to the new device.
+
+use crate::auth::e2e_encrypt;
+use crate::command::{ JsonCommand, MsgContext };
+use crate::encryption::get_secure_random_bytes;
+use crate::node::NodeIdentity;
+use crate::storage::fs::WriteOpts;
+use crate::storage::key_metadata_store::KeyMetadataStore;
+use crate::tenant::{ Access, TenantAuth };
+use anyhow::{ anyhow, bail, Context, Result };
+use chrono::{ DateTime, Duration, Utc };
+use hmac::{ Hmac, Mac, NewMac };
+use rand::Rng;
+use serde::{ Deserialize, Serialize };
+use serde_json::json;
+use sha1::Sha1;
+use std::collections::BTreeMap;
+use std::env;
+use tracing::info;
+
+const SECOND_FACTORS_VAR: &str = "RECOVERY_SECOND_FACTORS";
+const EMAIL_WEBHOOK_VAR: &str = "RECOVERY_EMAIL_WEBHOOK_URL";
+const SMS_WEBHOOK_VAR: &str = "RECOVERY_SMS_WEBHOOK_URL";
+const WEBHOOK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+const TOTP_SECRET_KEY: &str = "totp_secret";
+const TOTP_SECRET_BYTES: usize = 20;
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Codes of the previous and next step are accepted for clock drift
+const TOTP_ALLOWED_DRIFT: i64 = 1;
+
+const SMS_PHONE_KEY: &str = "sms_phone";
+const SMS_CODE_KEY: &str = "sms_code";
+const SMS_CODE_MINUTES: i64 = 10;
+
+/// Recovery of an account waiting for its factors
+pub struct PendingRecovery<'a> {
+    pub email: &'a str,
+    pub key_id: &'a str,
+}
+
+pub trait RecoveryVerifier {
+    /// Name of the factor, the key of its code in the confirmation
+    fn name(&self) -> &'static str;
+
+    /// Called once the recovery challenge is stored, e.g. to send the user a code
+    fn start(&self, recovery: &PendingRecovery, encrypted_bundle: &str) -> Result<()>;
+
+    /// Called with the codes of the confirmation before the recovery is confirmed
+    fn verify(&self, recovery: &PendingRecovery, codes: &BTreeMap<String, String>) -> Result<()>;
+}
+
+/// Verifiers of every recovery on this node, the email link first
+pub fn configured_verifiers() -> Result<Vec<Box<dyn RecoveryVerifier>>> {
+    let mut verifiers: Vec<Box<dyn RecoveryVerifier>> = vec![Box::new(EmailLinkVerifier)];
+    let factors = env::var(SECOND_FACTORS_VAR).unwrap_or_default();
+    for factor in factors.split(',').map(str::trim).filter(|factor| !factor.is_empty()) {
+        match factor {
+            "totp" => verifiers.push(Box::new(TotpVerifier)),
+            "sms" => {
+                let webhook_url = env
+                    ::var(SMS_WEBHOOK_VAR)
+                    .with_context(|| format!("{} is required for sms recovery", SMS_WEBHOOK_VAR))?;
+                verifiers.push(Box::new(SmsWebhookVerifier { webhook_url }));
+            }
+            _ => bail!("Unknown recovery factor {} in {}", factor, SECOND_FACTORS_VAR),
+        }
+    }
+    Ok(verifiers)
+}
+
+fn post_webhook(url: &str, body: &serde_json::Value) -> Result<()> {
+    ureq::post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .set("Content-Type", "application/json")
+        .send_string(&body.to_string())
+        .map_err(|err| anyhow!("Webhook {} failed: {}", url, err))?;
+    Ok(())
+}
+
+/// Compares codes without leaking the position of the first difference
+fn codes_match(expected: &str, provided: &str) -> bool {
+    expected.len() == provided.len() &&
+        expected
+            .bytes()
+            .zip(provided.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn code_of<'a>(codes: &'a BTreeMap<String, String>, factor: &str) -> Result<&'a str> {
+    match codes.get(factor) {
+        Some(code) => Ok(code.trim()),
+        None => bail!("The confirmation carries no {} code", factor),
+    }
+}
+
+/// Emails the link with the encrypted challenge bundle, which proves the email address: the
+/// challenge is checked by the confirmation itself. Without a webhook the email is only logged.
+pub struct EmailLinkVerifier;
+
+impl RecoveryVerifier for EmailLinkVerifier {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn start(&self, recovery: &PendingRecovery, encrypted_bundle: &str) -> Result<()> {
+        match env::var(EMAIL_WEBHOOK_VAR) {
+            Ok(url) => {
+                let body =
+                    json!({
+                    "email": recovery.email,
+                    "key_id": recovery.key_id,
+                    "encrypted_bundle": encrypted_bundle,
+                });
+                post_webhook(&url, &body)?;
+                info!("Recovery email sent to {} with encrypted bundle", recovery.email);
+            }
+            Err(_) => {
+                info!(
+                    "(Dummy) Sending email to {}: recovery challenge: {}",
+                    recovery.email,
+                    encrypted_bundle
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn verify(&self, _recovery: &PendingRecovery, _codes: &BTreeMap<String, String>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Time-based one-time password of RFC 6238 from an authenticator app, with the secret the user
+/// enrolled on this node
+pub struct TotpVerifier;
+
+fn totp_code(secret: &[u8], counter: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!("{:0width$}", truncated % (10u32).pow(TOTP_DIGITS), width = TOTP_DIGITS as usize)
+}
+
+impl RecoveryVerifier for TotpVerifier {
+    fn name(&self) -> &'static str {
+        "totp"
+    }
+
+    fn start(&self, _recovery: &PendingRecovery, _encrypted_bundle: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn verify(&self, recovery: &PendingRecovery, codes: &BTreeMap<String, String>) -> Result<()> {
+        let secret = KeyMetadataStore::get_user_level(TOTP_SECRET_KEY, recovery.email).map_err(
+            |_| anyhow!("No authenticator is enrolled for {}", recovery.email)
+        )?;
+        let secret = base32
+            ::decode(base32::Alphabet::RFC4648 { padding: false }, &secret)
+            .ok_or_else(|| anyhow!("Stored authenticator secret is not base32"))?;
+        let code = code_of(codes, self.name())?;
+        let step = Utc::now().timestamp() / TOTP_STEP_SECONDS;
+        let valid = (-TOTP_ALLOWED_DRIFT..=TOTP_ALLOWED_DRIFT).any(|drift| {
+            codes_match(&totp_code(&secret, (step + drift) as u64), code)
+        });
+        if !valid {
+            bail!("Authenticator code is not valid");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SmsCode {
+    code: String,
+    expires: DateTime<Utc>,
+}
+
+/// Code sent by SMS to the phone number the user enrolled, through a webhook of the partner's SMS
+/// gateway
+pub struct SmsWebhookVerifier {
+    webhook_url: String,
+}
+
+impl RecoveryVerifier for SmsWebhookVerifier {
+    fn name(&self) -> &'static str {
+        "sms"
+    }
+
+    fn start(&self, recovery: &PendingRecovery, _encrypted_bundle: &str) -> Result<()> {
+        let phone_number = KeyMetadataStore::get_user_level(SMS_PHONE_KEY, recovery.email).map_err(
+            |_| anyhow!("No phone number is enrolled for {}", recovery.email)
+        )?;
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        let stored = SmsCode {
+            code: code.clone(),
+            expires: Utc::now() + Duration::minutes(SMS_CODE_MINUTES),
+        };
+        KeyMetadataStore::save_user_level(
+            &serde_json::to_string(&stored)?,
+            SMS_CODE_KEY,
+            recovery.email,
+            &WriteOpts::Modify
+        )?;
+        post_webhook(
+            &self.webhook_url,
+            &json!({
+                "phone_number": phone_number,
+                "message": format!("Your Gridlock recovery code is {}", code),
+            })
+        )?;
+        info!("Recovery code sent by SMS for {}", recovery.email);
+        Ok(())
+    }
+
+    fn verify(&self, recovery: &PendingRecovery, codes: &BTreeMap<String, String>) -> Result<()> {
+        let stored = KeyMetadataStore::get_user_level(SMS_CODE_KEY, recovery.email).map_err(|_|
+            anyhow!("No SMS code was sent to {}", recovery.email)
+        )?;
+        // A code is good for a single attempt, a wrong guess needs a new recovery session
+        KeyMetadataStore::remove_user_level(SMS_CODE_KEY, recovery.email)?;
+        let stored: SmsCode = serde_json::from_str(&stored)?;
+        if Utc::now() > stored.expires {
+            bail!("SMS code expired");
+        }
+        if !codes_match(&stored.code, code_of(codes, self.name())?) {
+            bail!("SMS code is not valid");
+        }
+        Ok(())
+    }
+}
+
+/// Enrolls a second factor of the account for later recoveries, from a device holding its
+/// access key
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct EnrollRecoveryFactorCommand {
+    pub authorization: TenantAuth,
+    pub factor: RecoveryFactor,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind")]
+pub enum RecoveryFactor {
+    /// The node generates the secret of an authenticator app
+    Totp,
+    Sms {
+        phone_number: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EnrolledRecoveryFactor {
+    /// `otpauth://` URI of an enrolled authenticator, encrypted to the client e2e key
+    pub encrypted_totp_uri: Option<String>,
+    pub node_e2e_public_key: String,
+}
+
+impl JsonCommand for EnrollRecoveryFactorCommand {
+    type Response = EnrolledRecoveryFactor;
+
+    fn access(&self) -> Access<'_> {
+        Access::Account { auth: Some(&self.authorization), key_ids: Vec::new() }
+    }
+
+    fn execute_message(self, _ctx: MsgContext) -> Result<Self::Response> where Self: Sized {
+        let node = NodeIdentity::cached()?;
+        let email = &self.authorization.email;
+        let encrypted_totp_uri = match &self.factor {
+            RecoveryFactor::Totp => {
+                let secret = base32::encode(
+                    base32::Alphabet::RFC4648 { padding: false },
+                    &get_secure_random_bytes(TOTP_SECRET_BYTES)
+                );
+                KeyMetadataStore::save_user_level(
+                    &secret,
+                    TOTP_SECRET_KEY,
+                    email,
+                    &WriteOpts::Modify
+                )?;
+                let uri = format!(
+                    "otpauth://totp/Gridlock:{}?secret={}&issuer=Gridlock&digits={}&period={}",
+                    email,
+                    secret,
+                    TOTP_DIGITS,
+                    TOTP_STEP_SECONDS
+                );
+                let client_key = &self.authorization.client_e2e_public_key;
+                Some(e2e_encrypt(uri.as_bytes(), client_key, &node.e2e_private_key)?)
+            }
+            RecoveryFactor::Sms { phone_number } => {
+                KeyMetadataStore::save_user_level(
+                    phone_number,
+                    SMS_PHONE_KEY,
+                    email,
+                    &WriteOpts::Modify
+                )?;
+                None
+            }
+        };
+        info!("Enrolled a recovery factor for {}", email);
+        Ok(EnrolledRecoveryFactor {
+            encrypted_totp_uri,
+            node_e2e_public_key: node.e2e_public_key,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totp_codes_match_rfc_6238() {
+        let secret = b"12345678901234567890";
+        assert_eq!(totp_code(secret, 59 / 30), "287082");
+        assert_eq!(totp_code(secret, 1111111109 / 30), "081804");
+        assert!(codes_match("081804", "081804"));
+        assert!(!codes_match("081804", "081805"));
+        assert!(!codes_match("081804", "81804"));
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::{ bail, Context, Result };
+use node::communication::file_transport::{
+    from_chunks,
+    to_chunks,
+    FileTransport,
+    DEFAULT_CHUNK_LEN,
+};
+use node::session_manager;
+use node::signing::eddsa::airgap::{ sign, OfflineSigningRequest };
+use std::fs;
+use std::io::{ self, Read };
+use std::path::PathBuf;
+
+const USAGE: &str =
+    "Usage:
+  guardian-node airgap sign-eddsa <request.json> <inbox> <outbox>
+  guardian-node airgap chunk <message file> [chunk length]
+  guardian-node airgap assemble <message file>  (chunk lines on stdin)";
+
+/// `guardian-node airgap ...` runs a guardian that never connects to NATS. Round messages are
+/// written to the outbox and read from the inbox, the operator carries them between the parties,
+/// as files or as QR codes of their chunks.
+pub fn run(args: &[String]) -> Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["sign-eddsa", request, inbox, outbox] => sign_eddsa(request, inbox, outbox),
+        ["chunk", file] => chunk(file, DEFAULT_CHUNK_LEN),
+        ["chunk", file, chunk_len] => chunk(file, chunk_len.parse().context("Chunk length")?),
+        ["assemble", file] => assemble(file),
+        _ => bail!("{}", USAGE),
+    }
+}
+
+fn sign_eddsa(request: &str, inbox: &str, outbox: &str) -> Result<()> {
+    let request: OfflineSigningRequest = serde_json
+        ::from_slice(&fs::read(request)?)
+        .context("Invalid signing request")?;
+    let node = node::start_offline()?;
+    eprintln!(
+        "Guardian {} signs in session {}, carry the files of its outbox {} to the other parties \
+         and theirs to its inbox {}",
+        node.node_id,
+        request.session_id,
+        outbox,
+        inbox
+    );
+    let transport = FileTransport {
+        inbox: PathBuf::from(inbox),
+        outbox: PathBuf::from(outbox),
+    };
+    let signature = session_manager::runtime().block_on(sign(request, transport))?;
+    println!("{}", serde_json::to_string(&signature)?);
+    Ok(())
+}
+
+fn chunk(file: &str, chunk_len: usize) -> Result<()> {
+    let data = fs::read(file).with_context(|| format!("Failed to read {}", file))?;
+    for chunk in to_chunks(&data, chunk_len) {
+        println!("{}", chunk);
+    }
+    Ok(())
+}
+
+fn assemble(file: &str) -> Result<()> {
+    let mut chunks = String::new();
+    io::stdin().read_to_string(&mut chunks)?;
+    fs::write(file, from_chunks(chunks.lines())?)?;
+    Ok(())
+}
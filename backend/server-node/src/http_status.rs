@@ -0,0 +1,105 @@
+use anyhow::{ anyhow, Result };
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{ Json, Router };
+use node::metrics;
+use node::storage::fs::FileSystem;
+use node::NATS_CONNECTED;
+use serde::Serialize;
+use std::net::{ SocketAddr, TcpListener };
+use std::sync::atomic::Ordering;
+use std::{ env, thread };
+use tracing::{ error, info };
+
+/// Address to serve the health endpoints on, e.g. `0.0.0.0:8080`. Without it no HTTP server runs.
+const HTTP_STATUS_ADDR_VAR: &str = "HTTP_STATUS_ADDR";
+
+#[derive(Clone)]
+struct StatusState {
+    node_id: String,
+}
+
+#[derive(Serialize)]
+struct NodeStatus {
+    node_id: String,
+    nats_connected: bool,
+    keyshare_count: Option<usize>,
+    version: &'static str,
+}
+
+/// Starts the optional HTTP server with `/healthz`, `/readyz`, `/status` and the Prometheus
+/// `/metrics` on its own thread. The listener is bound before returning so a bad address fails
+/// the node start.
+pub fn spawn_status_server(node_id: String) -> Result<()> {
+    let addr: SocketAddr = match env::var(HTTP_STATUS_ADDR_VAR) {
+        Ok(addr) if !addr.is_empty() => {
+            addr.parse().map_err(|err| anyhow!("Invalid {}: {}", HTTP_STATUS_ADDR_VAR, err))?
+        }
+        _ => {
+            return Ok(());
+        }
+    };
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Builder
+        ::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()?;
+
+    thread::Builder
+        ::new()
+        .name("http-status".to_string())
+        .spawn(move || {
+            runtime.block_on(async move {
+                let router = Router::new()
+                    .route("/healthz", get(healthz))
+                    .route("/readyz", get(readyz))
+                    .route("/status", get(status))
+                    .route("/metrics", get(prometheus_metrics))
+                    .with_state(StatusState { node_id });
+                let served = match axum::Server::from_tcp(listener) {
+                    Ok(server) => server.serve(router.into_make_service()).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = served {
+                    error!("HTTP status server stopped: {}", err);
+                }
+            })
+        })?;
+    info!("Serving health endpoints on http://{}", addr);
+    Ok(())
+}
+
+/// Liveness: the process is up and serving requests
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness: the node can take part in sessions, which needs the NATS connection
+async fn readyz() -> (StatusCode, &'static str) {
+    if NATS_CONNECTED.load(Ordering::Relaxed) {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "NATS disconnected")
+    }
+}
+
+async fn prometheus_metrics() -> (StatusCode, String) {
+    match metrics::gather() {
+        Ok(text) => (StatusCode::OK, text),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    }
+}
+
+async fn status(State(state): State<StatusState>) -> Json<NodeStatus> {
+    Json(NodeStatus {
+        node_id: state.node_id,
+        nats_connected: NATS_CONNECTED.load(Ordering::Relaxed),
+        keyshare_count: FileSystem::find_all_keyshare_files()
+            .map(|files| files.len())
+            .ok(),
+        version: env!("CARGO_PKG_VERSION"),
+    })
+}
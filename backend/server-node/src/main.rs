@@ -1,21 +1,65 @@
-use anyhow::{ bail, Result };
-use nats::Subscription;
+use anyhow::{ anyhow, bail, Result };
+use async_nats::jetstream::consumer::pull;
+use async_nats::{ Message, Subscriber };
+use futures::StreamExt;
+use node::cli;
+use node::communication::incoming::IncomingMessage;
+use node::communication::jetstream::{ handle_durable_message, is_durable, JetStreamConfig };
+use node::communication::leaf_node::shutdown_leaf_node;
+use node::communication::permissions::node_permissions_json;
+use node::communication::queue_groups::{ dispatch_queued_message, WorkerConfig };
 use node::{
     handle_message,
+    route_message,
     start,
     start_sending_ready_as_cancellable_task_on_thread,
+    session_manager,
     App,
-    NATS_CONNECTED,
 };
-use std::sync::atomic::{ AtomicBool, Ordering };
-use std::sync::{ mpsc, Arc };
+use std::env;
+use std::future;
+use std::sync::mpsc;
 use std::time::Duration;
+use tokio::signal::unix::{ signal, SignalKind };
 use tracing::{ error, warn, info };
 
+mod airgap;
+mod http_status;
+
 const READY_MSG_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
 
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 fn main() {
+    // `server-node nats-permissions [--orchestrator]` prints the subjects to grant the node's NATS
+    // user and exits without connecting
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("nats-permissions") {
+        let orchestrator = args.iter().any(|arg| arg == "--orchestrator");
+        match node_permissions_json(orchestrator) {
+            Ok(permissions) => println!("{permissions}"),
+            Err(err) => {
+                eprintln!("Failed to generate NATS permissions: {err:?}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // Local administration, e.g. `server-node keys list`, runs without joining the network
+    if args.first().is_some_and(|command| cli::COMMANDS.contains(&command.as_str())) {
+        if let Err(err) = cli::run(&args) {
+            eprintln!("{err:?}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("airgap") {
+        if let Err(err) = airgap::run(&args[1..]) {
+            eprintln!("{err:?}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let app = match start() {
         Ok(setup) => setup,
         Err(err) => {
@@ -25,6 +69,11 @@ fn main() {
         }
     };
 
+    if let Err(err) = http_status::spawn_status_server(app.node.node_id.to_string()) {
+        error!("Failed to start the HTTP status server: {err:?}");
+        std::process::exit(1);
+    }
+
     let (_tx, rx) = mpsc::channel();
     let _ = start_sending_ready_as_cancellable_task_on_thread(
         app.nc.clone(),
@@ -36,40 +85,71 @@ fn main() {
     match message_loop(app) {
         Ok(_) => {
             info!("Node shutting down gracefully");
+            shutdown_leaf_node();
             std::process::exit(0);
         }
         Err(e) => {
             error!("Node encountered an error: {}", e);
+            shutdown_leaf_node();
             std::process::exit(1);
         }
     }
 }
 
 #[cfg(any(target_os = "linux", target_os = "macos"))]
-fn message_loop(mut app: App) -> Result<()> {
-    let mut subscription = subscribe(&app)?;
+fn message_loop(app: App) -> Result<()> {
+    session_manager::runtime().block_on(run_message_loop(app))
+}
 
-    let has_terminate = Arc::new(AtomicBool::new(false));
-    signal_hook::flag
-        ::register(signal_hook::SIGTERM, Arc::clone(&has_terminate))
-        .expect("SIGTERM Signal hook registered");
+async fn run_message_loop(app: App) -> Result<()> {
+    let worker = WorkerConfig::from_env();
+    if let Some(worker) = &worker {
+        info!(
+            "Running as instance {} of queue group \"{}\"",
+            worker.instance_id,
+            worker.queue_group
+        );
+    }
+    // Subscriptions are made on the async client, which resubscribes them after a reconnect
+    let mut subscriptions = subscribe(&app, &worker).await?;
+    // Keygen and recovery requests come from the stream instead when JetStream is configured
+    let jetstream = JetStreamConfig::from_env();
+    let mut durable = match &jetstream {
+        Some(jetstream) => {
+            Some(jetstream.consume(app.nc.client(), &app.node.node_id.to_string()).await?)
+        }
+        None => None,
+    };
+    let mut terminate = signal(SignalKind::terminate())?;
 
-    while !has_terminate.load(Ordering::Relaxed) {
-        // Timeout is necessary to check whether SIGTERM called
-        match subscription.next_timeout(Duration::from_millis(1000)) {
-            Ok(msg) => {
-                handle_message(&app, msg);
+    loop {
+        tokio::select! {
+            biased;
+            _ = terminate.recv() => break,
+            // Messages forwarded by sibling instances belong to sessions this instance owns
+            Some(msg) = next_direct(&mut subscriptions.direct) => {
+                handle_message(&app, IncomingMessage::from(msg));
+            }
+            Some(msg) = next_durable(&mut durable) => {
+                let result = match msg {
+                    Ok(msg) => handle_durable_message(&app, worker.as_ref(), msg).await,
+                    Err(err) => Err(anyhow!(err)),
+                };
+                if let Err(err) = result {
+                    warn!("Failed to process a JetStream message: {}", err);
+                }
             }
-            Err(_e) => {
-                if !NATS_CONNECTED.load(Ordering::Relaxed) {
-                    match app.try_reconnect() {
-                        Ok(_) => {
-                            subscription = subscribe(&app)?;
-                        }
-                        Err(e) => {
-                            warn!("Couldn't reconnect to NATs - {}", e);
-                        }
-                    }
+            msg = subscriptions.main.next() => {
+                let msg = match msg {
+                    Some(msg) => IncomingMessage::from(msg),
+                    None => bail!("Subscription to the node's subjects was closed"),
+                };
+                if jetstream.is_some() && is_durable(route_message(&msg.subject)) {
+                    continue;
+                }
+                match &worker {
+                    Some(worker) => dispatch_queued_message(&app, worker, msg),
+                    None => handle_message(&app, msg),
                 }
             }
         }
@@ -77,9 +157,57 @@ fn message_loop(mut app: App) -> Result<()> {
     Ok(())
 }
 
-fn subscribe(app: &App) -> Result<Subscription> {
-    let subject = format!("network.gridlock.nodes.*.new.{}", &app.node.node_id);
-    match app.nc.subscribe(&subject) {
+async fn next_direct(direct: &mut Option<Subscriber>) -> Option<Message> {
+    match direct {
+        Some(subscription) => subscription.next().await,
+        None => future::pending().await,
+    }
+}
+
+async fn next_durable(
+    durable: &mut Option<pull::Stream>
+) -> Option<<pull::Stream as futures::Stream>::Item> {
+    match durable {
+        Some(messages) => messages.next().await,
+        None => future::pending().await,
+    }
+}
+
+struct Subscriptions {
+    main: Subscriber,
+    direct: Option<Subscriber>,
+}
+
+async fn subscribe(app: &App, worker: &Option<WorkerConfig>) -> Result<Subscriptions> {
+    let node_id = app.node.node_id.to_string();
+    let subject = WorkerConfig::shared_subject(&node_id);
+    match worker {
+        None =>
+            Ok(Subscriptions {
+                main: subscribe_subject(app, &subject, None).await?,
+                direct: None,
+            }),
+        Some(worker) =>
+            Ok(Subscriptions {
+                main: subscribe_subject(app, &subject, Some(&worker.queue_group)).await?,
+                direct: Some(
+                    subscribe_subject(app, &worker.instance_subject(&node_id), None).await?
+                ),
+            }),
+    }
+}
+
+async fn subscribe_subject(
+    app: &App,
+    subject: &str,
+    queue_group: Option<&str>
+) -> Result<Subscriber> {
+    let client = app.nc.client();
+    let result = match queue_group {
+        Some(group) => client.queue_subscribe(subject.to_string(), group.to_string()).await,
+        None => client.subscribe(subject.to_string()).await,
+    };
+    match result {
         Ok(sub) => Ok(sub),
         Err(err) => { bail!("Failed to subscribe to subject \"{}\" :{}", subject, err) }
     }
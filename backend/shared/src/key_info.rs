@@ -17,6 +17,60 @@ pub struct KeyInfo {
     #[serde(flatten)]
     pub kind: Key,
     pub node_pool: Vec<NodeInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<SignedKeyMetadata>,
+    /// Threshold the key was generated with, `None` for keys from before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<usize>,
+}
+
+/// Descriptive data attached to a key at keygen, shared by every guardian of the key
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct KeyMetadata {
+    pub display_name: Option<String>,
+    pub chain: Option<String>,
+    pub wallet_descriptor: Option<String>,
+}
+
+/// Domain separation of the signed encoding of key metadata
+const KEY_METADATA_CONTEXT: &[u8] = b"gridlock-key-metadata-v1";
+
+impl KeyMetadata {
+    /// Encoding the owner signs: the context, the key id and every field in declaration order,
+    /// each field as a presence byte followed by its big endian u32 length and its bytes. Unlike
+    /// JSON it does not depend on the serializer, and the metadata of one key can't be replayed
+    /// onto another.
+    pub fn signing_payload(&self, key_id: &str) -> Vec<u8> {
+        let mut payload = KEY_METADATA_CONTEXT.to_vec();
+        let fields = [
+            Some(key_id),
+            self.display_name.as_deref(),
+            self.chain.as_deref(),
+            self.wallet_descriptor.as_deref(),
+        ];
+        for field in fields {
+            match field {
+                Some(value) => {
+                    payload.push(1);
+                    payload.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                    payload.extend_from_slice(value.as_bytes());
+                }
+                None => payload.push(0),
+            }
+        }
+        payload
+    }
+}
+
+/// Key metadata together with the owner's ed25519 signature over its `signing_payload`
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct SignedKeyMetadata {
+    pub metadata: KeyMetadata,
+    /// Base64 encoded ed25519 public key of the signer, the key whose X25519 form is the client
+    /// e2e key of the key owner
+    pub signer_public_key: String,
+    /// Base64 encoded ed25519 signature
+    pub signature: String,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -60,6 +114,14 @@ pub enum Key {
     Sr25519 {
         pk: String,
     },
+    /// Threshold Schnorr key for BIP-340 / Taproot signing, compressed group key hex
+    Frost {
+        y_sum: String,
+    },
+    /// BLS12-381 threshold key, compressed G1 public key hex
+    BLS {
+        public_key: String,
+    },
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
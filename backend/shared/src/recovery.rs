@@ -3,6 +3,7 @@ use kzen_paillier::EncryptionKey;
 use serde::{ Deserialize, Serialize };
 use std::collections::HashMap;
 use std::fmt::Debug;
+use zk_paillier::zkproofs::NiCorrectKeyProof;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ReceiveRecoveryPackages {
@@ -17,6 +18,9 @@ pub struct ReceiveRecoveryPackages {
 pub struct UpdatePaillierKeysCommand {
     pub key_id: String,
     pub new_eks: Vec<EncryptionKey>,
+    /// Proofs that the moduli of `new_eks` are square-free, in the same order
+    #[serde(default)]
+    pub new_ek_proofs: Vec<NiCorrectKeyProof>,
 }
 
 impl Debug for UpdatePaillierKeysCommand {
@@ -31,6 +35,9 @@ pub struct UpdateSinglePaillierKeyCommand {
     pub key_id: String,
     pub new_ek: EncryptionKey,
     pub index: usize,
+    /// Proof that the modulus of `new_ek` is square-free
+    #[serde(default)]
+    pub new_ek_proof: Option<NiCorrectKeyProof>,
 }
 
 impl Debug for UpdateSinglePaillierKeyCommand {
@@ -47,12 +54,55 @@ pub enum Key {
     ECDSA,
     EDDSA,
     Sr25519,
+    BLS,
 }
 
 #[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct EncryptedData {
     pub aead_pack: Vec<u8>,
     pub nonce: Vec<u8>,
+    /// Envelope format the encryption key was derived with, absent for the legacy format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u8>,
+    /// Cipher the data was encrypted with, absent data predates the field and is AES-256-GCM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<CipherAlgorithm>,
+    /// Identifies which of several possible keys the data was encrypted with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+}
+
+impl EncryptedData {
+    pub fn with_key_id(mut self, key_id: &str) -> Self {
+        self.key_id = Some(key_id.to_string());
+        self
+    }
+
+    /// Whether the data uses the cipher new data is written with, anything else should be
+    /// re-encrypted when it is next written
+    pub fn has_current_cipher(&self) -> bool {
+        self.algorithm == Some(CipherAlgorithm::CURRENT)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CipherAlgorithm {
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    /// Written by a newer node, kept so the data can still be parsed and reported
+    #[serde(other)]
+    Unsupported,
+}
+
+impl CipherAlgorithm {
+    pub const CURRENT: CipherAlgorithm = CipherAlgorithm::Aes256Gcm;
+
+    pub fn nonce_len(&self) -> Option<usize> {
+        match self {
+            CipherAlgorithm::Aes256Gcm => Some(12),
+            CipherAlgorithm::Unsupported => None,
+        }
+    }
 }
 
 impl Debug for EncryptedData {
@@ -69,6 +119,9 @@ pub struct RecoveryPackageInfo {
     pub peers: Vec<usize>,
     pub public_keys: PublicKeysEnum,
     pub encrypted_packages: Vec<EncryptedData>,
+    /// The share is a ghost share at a new index, kept encrypted beside the target's own share
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ghost: bool,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]